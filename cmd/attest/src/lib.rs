@@ -0,0 +1,224 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility attest`
+//!
+//! `humility attest` retrieves attestation data from the root of trust:
+//! either directly (when attached to the RoT, via its `Attest` interface)
+//! or by way of the SP (when attached to the SP, via its `SpRot`
+//! interface).  By default, the certificate chain and the measurement log
+//! are retrieved and decoded:
+//!
+//! ```console
+//! % humility attest
+//! humility: attached via ST-Link V3
+//! humility: retrieving attestation data via SpRot
+//! certificate chain (2 certificates):
+//!     [0] subject: CN=alias, O=Oxide Computer Company
+//!          issuer: CN=device-id, O=Oxide Computer Company
+//!          serial: 00
+//!     [1] subject: CN=device-id, O=Oxide Computer Company
+//!          issuer: CN=platform-id, O=Oxide Computer Company
+//!          serial: 01
+//! measurement log (2 measurements):
+//!     [0] sha3-256 9e2c4c6f0d2d8b2a...
+//!     [1] sha3-256 3f1a20b7c6f80e4d...
+//! ```
+//!
+//! To display only the certificate chain, use `-c` (`--certs`); to display
+//! it as PEM (e.g., for consumption by other tools), add `-p` (`--pem`).
+//! To display only the measurement log, use `-m` (`--log`).
+//!
+//! To obtain an attestation (a signature over the measurement log and a
+//! nonce), use `-a` (`--attest`).  A random nonce is used unless one is
+//! specified (as 32 bytes of hex) with `-n` (`--nonce`):
+//!
+//! ```console
+//! % humility attest --attest
+//! humility: attached via ST-Link V3
+//! humility: retrieving attestation data via SpRot
+//! nonce: 5b2b0c6a...
+//! attestation: ed25519 8c0d2e55...
+//! ```
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::attest::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+
+#[derive(Parser, Debug)]
+#[clap(name = "attest", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct AttestArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// retrieve the certificate chain
+    #[clap(long, short)]
+    certs: bool,
+
+    /// display certificates as PEM
+    #[clap(long, short, requires = "certs")]
+    pem: bool,
+
+    /// retrieve the measurement log
+    #[clap(long = "log", short = 'm')]
+    log: bool,
+
+    /// obtain an attestation over the measurement log and a nonce
+    #[clap(long, short)]
+    attest: bool,
+
+    /// nonce for attestation, as hex
+    #[clap(long, short, value_name = "nonce", requires = "attest")]
+    nonce: Option<String>,
+}
+
+fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_nonce(nonce: &str) -> Result<Vec<u8>> {
+    let nonce = nonce.trim_start_matches("0x");
+
+    if nonce.len() != NONCE_SIZE * 2 {
+        bail!(
+            "nonce must be {} bytes ({} hex digits)",
+            NONCE_SIZE,
+            NONCE_SIZE * 2
+        );
+    }
+
+    (0..nonce.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&nonce[i..i + 2], 16)?))
+        .collect()
+}
+
+fn certs(
+    attest: &mut Attest,
+    core: &mut dyn Core,
+    subargs: &AttestArgs,
+) -> Result<()> {
+    let chain = attest.cert_chain(core)?;
+
+    if subargs.pem {
+        for cert in &chain {
+            print!("{}", pem(cert));
+        }
+
+        return Ok(());
+    }
+
+    println!("certificate chain ({} certificates):", chain.len());
+
+    for (ndx, cert) in chain.iter().enumerate() {
+        let label = format!("[{}]", ndx);
+
+        match decode_cert(cert) {
+            Ok(c) => {
+                println!("    {:>3} subject: {}", label, c.subject);
+                println!("    {:>3}  issuer: {}", "", c.issuer);
+                println!("    {:>3}  serial: {}", "", hex(&c.serial));
+            }
+            Err(err) => {
+                println!(
+                    "    {:>3} <{} bytes; failed to decode: {}>",
+                    label,
+                    cert.len(),
+                    err
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn log(attest: &mut Attest, core: &mut dyn Core) -> Result<()> {
+    let log = decode_log(&attest.log(core)?)?;
+
+    println!("measurement log ({} measurements):", log.len());
+
+    for (ndx, measurement) in log.iter().enumerate() {
+        match measurement {
+            Measurement::Sha3_256(digest) => {
+                println!("    [{}] sha3-256 {}", ndx, hex(digest));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn attestation(
+    attest: &mut Attest,
+    core: &mut dyn Core,
+    subargs: &AttestArgs,
+) -> Result<()> {
+    let nonce = match &subargs.nonce {
+        Some(nonce) => parse_nonce(nonce)?,
+        None => (0..NONCE_SIZE).map(|_| rand::random::<u8>()).collect(),
+    };
+
+    let raw = attest.attest(core, &nonce)?;
+
+    println!("nonce: {}", hex(&nonce));
+
+    match decode_attestation(&raw)? {
+        Attestation::Ed25519(sig) => {
+            println!("attestation: ed25519 {}", hex(&sig));
+        }
+    }
+
+    Ok(())
+}
+
+fn attestcmd(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = AttestArgs::try_parse_from(subargs)?;
+    let mut attest = Attest::new(hubris, core, subargs.timeout)?;
+
+    humility::msg!("retrieving attestation data via {}", attest.interface());
+
+    let all = !subargs.certs && !subargs.log && !subargs.attest;
+
+    if subargs.certs || all {
+        certs(&mut attest, core, &subargs)?;
+    }
+
+    if subargs.log || all {
+        log(&mut attest, core)?;
+    }
+
+    if subargs.attest {
+        attestation(&mut attest, core, &subargs)?;
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "attest",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: attestcmd,
+        },
+        AttestArgs::command(),
+    )
+}
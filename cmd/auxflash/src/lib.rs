@@ -0,0 +1,391 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility auxflash`
+//!
+//! `humility auxflash` verifies the contents of auxiliary flash against the
+//! auxiliary flash image carried in the archive (`img/auxi.tlvc`), by way
+//! of the task that implements the `AuxFlash` Idol interface.  Auxiliary
+//! flash is divided into slots, each of which holds a copy of the image
+//! along with its checksum (a `CHCK` chunk); a slot is current if its
+//! checksum matches that of the archive's image:
+//!
+//! ```console
+//! % humility auxflash
+//! humility: attached via ST-Link V3
+//! SLOT STATUS  DETAIL
+//!    0 current
+//!    1 current
+//!    2 stale   checksum 4d071127.. differs from 9b3c05f2..
+//!    3 invalid MissingChck
+//! humility auxflash failed: 2 of 4 slots are not current
+//! ```
+//!
+//! To verify a single slot, use `-s` (`--slot`).  To reprogram the slots that
+//! are not current with the archive's image, use `-p` (`--program`); each
+//! slot is erased, written, and then verified:
+//!
+//! ```console
+//! % humility auxflash --program
+//! humility: attached via ST-Link V3
+//! ...
+//! humility: programming slot 2 with 131172 bytes
+//! humility: writing [##############################] 128KB/128KB
+//! humility: programming slot 3 with 131172 bytes
+//! humility: writing [##############################] 128KB/128KB
+//! humility: programmed and verified 2 slots
+//! ```
+//!
+//! If the target's hiffy task can unpack frames, the image is sent as
+//! checksummed and (where it helps) compressed frames, and the ratio of
+//! bytes sent to bytes written is reported for each slot.
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::idol::{IdolArgument, IdolOperation};
+use humility_cmd::output::{Cell, Column, Table};
+use humility_cmd::progress::Progress;
+use humility_cmd::transfer::Transfer;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+
+#[derive(Parser, Debug)]
+#[clap(name = "auxflash", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct AuxflashArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "15000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// verify (or program) only the specified slot
+    #[clap(long, short, value_name = "slot",
+        parse(try_from_str = parse_int::parse),
+    )]
+    slot: Option<u32>,
+
+    /// reprogram slots that are not current
+    #[clap(long, short)]
+    program: bool,
+}
+
+const INTERFACE: &str = "AuxFlash";
+
+//
+// The size of an auxiliary flash sector; slot sizes are reported by the
+// server in sectors.
+//
+const SECTOR_SIZE: usize = 64 * 1024;
+
+//
+// The maximum size of each write; writes are further limited by the size of
+// the HIF data.
+//
+const WRITE_SIZE: usize = 2048;
+
+///
+/// Finds the checksum in an auxiliary flash image, which is a TLV-C file
+/// consisting of an `AUXI` chunk that contains the checksum (`CHCK`) and
+/// the data (`AUXD`).  Each chunk has a 12-byte header (tag, length and
+/// header checksum); its body is padded to a multiple of 4 bytes and
+/// followed by a body checksum.
+///
+fn checksum(image: &[u8]) -> Result<Vec<u8>> {
+    fn chunks(buf: &[u8]) -> Result<Vec<(&[u8], &[u8])>> {
+        let mut rval = vec![];
+        let mut offs = 0;
+
+        while offs < buf.len() {
+            if offs + 12 > buf.len() {
+                bail!("truncated chunk header at offset {}", offs);
+            }
+
+            let tag = &buf[offs..offs + 4];
+            let len =
+                u32::from_le_bytes(buf[offs + 4..offs + 8].try_into().unwrap())
+                    as usize;
+            let body = offs + 12;
+
+            if body + len > buf.len() {
+                bail!("truncated chunk body at offset {}", offs);
+            }
+
+            rval.push((tag, &buf[body..body + len]));
+            offs = body + ((len + 3) & !3) + 4;
+        }
+
+        Ok(rval)
+    }
+
+    for (tag, body) in chunks(image)? {
+        if tag != b"AUXI" {
+            continue;
+        }
+
+        for (tag, body) in chunks(body)? {
+            if tag == b"CHCK" {
+                return Ok(body.to_vec());
+            }
+        }
+    }
+
+    bail!("auxiliary flash image has no checksum")
+}
+
+fn hex(checksum: &[u8]) -> String {
+    checksum.iter().take(4).map(|b| format!("{:02x}", b)).collect()
+}
+
+struct AuxFlash<'a> {
+    hubris: &'a HubrisArchive,
+    context: HiffyContext<'a>,
+    funcs: HiffyFunctions,
+}
+
+enum Status {
+    Current,
+    Stale(Vec<u8>),
+    Invalid(String),
+}
+
+impl<'a> AuxFlash<'a> {
+    fn new(
+        hubris: &'a HubrisArchive,
+        core: &mut dyn Core,
+        timeout: u32,
+    ) -> Result<Self> {
+        let mut context = HiffyContext::new(hubris, core, timeout)?;
+        let funcs = context.functions()?;
+
+        Ok(Self { hubris, context, funcs })
+    }
+
+    fn op(&self, name: &str) -> Result<IdolOperation<'a>> {
+        IdolOperation::new(self.hubris, INTERFACE, name, None)
+    }
+
+    ///
+    /// Calls the specified operation, returning its (raw) reply or the name
+    /// of the error.  If `lease` is specified, that many bytes of the HIF
+    /// data are lent to the operation; if `data` is specified, it is written
+    /// to the HIF data before the call (otherwise, the HIF data is assumed to
+    /// have been staged).
+    ///
+    fn call(
+        &mut self,
+        core: &mut dyn Core,
+        name: &str,
+        args: &[(&str, IdolArgument)],
+        lease: Option<u32>,
+        data: Option<&[u8]>,
+    ) -> Result<std::result::Result<Vec<u8>, String>> {
+        let op = self.op(name)?;
+        let leases = (lease, None);
+
+        self.context.idol_call(core, &self.funcs, &op, args, leases, data)
+    }
+
+    fn call_ok(
+        &mut self,
+        core: &mut dyn Core,
+        name: &str,
+        args: &[(&str, IdolArgument)],
+        lease: Option<u32>,
+        data: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        match self.call(core, name, args, lease, data)? {
+            Ok(val) => Ok(val),
+            Err(e) => bail!("{} failed: {}", name, e),
+        }
+    }
+
+    fn word(&mut self, core: &mut dyn Core, name: &str) -> Result<u32> {
+        let val = self.call_ok(core, name, &[], None, None)?;
+
+        match val.len() {
+            4 => Ok(u32::from_le_bytes(val[..].try_into()?)),
+            len => bail!("unexpected {} reply length {}", name, len),
+        }
+    }
+
+    fn status(
+        &mut self,
+        core: &mut dyn Core,
+        slot: u32,
+        expected: &[u8],
+    ) -> Result<Status> {
+        let args = [("slot", IdolArgument::Scalar(slot as u64))];
+
+        Ok(match self.call(core, "read_slot_chck", &args, None, None)? {
+            Ok(chck) if chck == expected => Status::Current,
+            Ok(chck) => Status::Stale(chck),
+            Err(e) => Status::Invalid(e),
+        })
+    }
+
+    fn program(
+        &mut self,
+        core: &mut dyn Core,
+        args: &Args,
+        slot: u32,
+        image: &[u8],
+    ) -> Result<()> {
+        let slot_arg = ("slot", IdolArgument::Scalar(slot as u64));
+
+        let progress = Progress::new(
+            args,
+            &format!("programming slot {} with {} bytes", slot, image.len()),
+            None,
+        );
+
+        let erase = progress.child("erasing", None, false);
+        self.call_ok(core, "erase_slot", &[slot_arg], None, None)?;
+        erase.finish();
+
+        let mut transfer = Transfer::new(&self.funcs);
+        let frames = transfer.frames(&self.context, image, WRITE_SIZE, 1)?;
+
+        let mut write =
+            progress.child("writing", Some(image.len() as u64), true);
+
+        for frame in &frames {
+            let args = [
+                ("slot", IdolArgument::Scalar(slot as u64)),
+                ("offset", IdolArgument::Scalar(frame.offset as u64)),
+            ];
+
+            let data = transfer.stage(&mut self.context, core, frame)?;
+            let lease = Some(frame.len as u32);
+
+            self.call_ok(core, "write_slot_with_offset", &args, lease, data)?;
+            write.set_position((frame.offset + frame.len) as u64);
+        }
+
+        write.finish();
+        progress.finish();
+
+        let stats = transfer.stats();
+
+        if transfer.framed() {
+            humility::msg!(
+                "sent {} bytes in {} frames ({:.1}% of {} bytes, {} resent)",
+                stats.sent,
+                stats.frames,
+                stats.sent as f64 * 100.0 / stats.bytes.max(1) as f64,
+                stats.bytes,
+                stats.retries
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn auxflash(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = AuxflashArgs::try_parse_from(subargs)?;
+
+    let image = match hubris.auxflash_data()? {
+        Some(image) => image,
+        None => bail!("archive does not contain an auxiliary flash image"),
+    };
+
+    let expected = checksum(&image)?;
+
+    let mut aux = AuxFlash::new(hubris, core, subargs.timeout)?;
+    let nslots = aux.word(core, "slot_count")?;
+    let slot_size = aux.word(core, "slot_sector_count")? as usize * SECTOR_SIZE;
+
+    if image.len() > slot_size {
+        bail!(
+            "auxiliary flash image ({} bytes) exceeds slot size ({} bytes)",
+            image.len(),
+            slot_size
+        );
+    }
+
+    let slots = match subargs.slot {
+        Some(slot) if slot >= nslots => {
+            bail!("slot {} is invalid; there are {} slots", slot, nslots)
+        }
+        Some(slot) => vec![slot],
+        None => (0..nslots).collect(),
+    };
+
+    let mut table = Table::new(
+        args.format,
+        vec![
+            Column::new("slot", 4),
+            Column::new("status", 7),
+            Column::new("detail", 0),
+        ],
+    );
+
+    let mut outdated = vec![];
+
+    for slot in &slots {
+        let (status, detail) = match aux.status(core, *slot, &expected)? {
+            Status::Current => ("current", Cell::None),
+            Status::Stale(chck) => (
+                "stale",
+                format!(
+                    "checksum {}.. differs from {}..",
+                    hex(&chck),
+                    hex(&expected)
+                )
+                .into(),
+            ),
+            Status::Invalid(e) => ("invalid", e.into()),
+        };
+
+        if status != "current" {
+            outdated.push(*slot);
+        }
+
+        table.row(vec![(*slot).into(), status.into(), detail])?;
+    }
+
+    if outdated.is_empty() {
+        humility::msg!("all {} slots are current", slots.len());
+        return Ok(());
+    }
+
+    if !subargs.program {
+        bail!("{} of {} slots are not current", outdated.len(), slots.len());
+    }
+
+    for slot in &outdated {
+        aux.program(core, args, *slot, &image)?;
+
+        if !matches!(aux.status(core, *slot, &expected)?, Status::Current) {
+            bail!("slot {} failed to verify after programming", slot);
+        }
+    }
+
+    humility::msg!("programmed and verified {} slots", outdated.len());
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "auxflash",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: auxflash,
+        },
+        AuxflashArgs::command(),
+    )
+}
@@ -0,0 +1,362 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility bench`
+//!
+//! `humility bench` measures the performance of the path between Humility
+//! and the target:  the round-trip latency of an (empty) HIF program, and
+//! the throughput of memory reads and writes at various block sizes.  This
+//! is useful to quantify a particular probe and host, and to catch
+//! performance regressions in the debug transport:
+//!
+//! ```console
+//! % humility bench
+//! humility: attached via ST-Link V3
+//! hiffy round-trip (100 iterations):
+//!          min      avg      max
+//!      2.210ms  2.731ms  5.120ms
+//!
+//! memory (100 iterations per size):
+//!         SIZE         READ        WRITE
+//!            4    4.26KB/s     4.18KB/s
+//!           64   63.80KB/s    60.12KB/s
+//!          256  215.33KB/s   197.52KB/s
+//!         1024  520.11KB/s   455.87KB/s
+//!         2048  641.49KB/s   533.02KB/s
+//! ```
+//!
+//! Memory is read from and written to the HIF data buffer, so the largest
+//! block size is bounded by its size.  The number of iterations can be
+//! specified with `-n` (`--iterations`).
+//!
+//! To additionally measure the time taken by an I2C transaction, specify a
+//! device (as one would to `humility i2c`) and optionally a register.  The
+//! time for a single transaction is determined by comparing the time taken
+//! by a HIF program that performs several transactions to that taken by an
+//! empty one, thereby factoring out the HIF overhead:
+//!
+//! ```console
+//! % humility bench -b mid -d 0x24 -r 0x99
+//! ...
+//! i2c (I2C3, port H, dev 0x24, register 0x99; 100 iterations):
+//!          min      avg      max
+//!      0.204ms  0.221ms  0.318ms
+//! ```
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::i2c::I2cArgs;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[clap(name = "bench", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct BenchArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// number of iterations of each measurement
+    #[clap(
+        long, short = 'n', default_value = "100", value_name = "iterations",
+        parse(try_from_str = parse_int::parse)
+    )]
+    iterations: u32,
+
+    /// specifies an I2C bus by name
+    #[clap(long, short, value_name = "bus",
+        conflicts_with_all = &["port", "controller"]
+    )]
+    bus: Option<String>,
+
+    /// specifies an I2C controller
+    #[clap(long, short, value_name = "controller",
+        parse(try_from_str = parse_int::parse),
+    )]
+    controller: Option<u8>,
+
+    /// specifies an I2C controller port
+    #[clap(long, short, value_name = "port")]
+    port: Option<String>,
+
+    /// specifies I2C multiplexer and segment
+    #[clap(long, short, value_name = "mux:segment")]
+    mux: Option<String>,
+
+    /// specifies an I2C device address
+    #[clap(long, short, value_name = "address")]
+    device: Option<String>,
+
+    /// specifies an I2C register to read
+    #[clap(long, short, value_name = "register", requires = "device",
+        parse(try_from_str = parse_int::parse),
+    )]
+    register: Option<u8>,
+}
+
+//
+// The number of I2C transactions in each HIF program that measures I2C
+// transaction time; this is bounded by the size of the return stack.
+//
+const I2C_TRANSACTIONS: usize = 8;
+
+const MEMORY_SIZES: &[usize] = &[4, 64, 256, 1024, 2048, 4096, 16384];
+
+struct Stats {
+    min: Duration,
+    max: Duration,
+    total: Duration,
+    count: u32,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            min: Duration::MAX,
+            max: Duration::ZERO,
+            total: Duration::ZERO,
+            count: 0,
+        }
+    }
+
+    fn add(&mut self, d: Duration) {
+        self.min = self.min.min(d);
+        self.max = self.max.max(d);
+        self.total += d;
+        self.count += 1;
+    }
+
+    fn avg(&self) -> Duration {
+        self.total / self.count.max(1)
+    }
+
+    fn print(&self) {
+        let ms = |d: Duration| format!("{:.3}ms", d.as_secs_f64() * 1000.0);
+
+        println!("{:>12} {:>8} {:>8}", "min", "avg", "max");
+        println!(
+            "{:>12} {:>8} {:>8}",
+            ms(self.min),
+            ms(self.avg()),
+            ms(self.max)
+        );
+    }
+}
+
+fn rate(bytes: usize, elapsed: Duration) -> String {
+    let rate = bytes as f64 / elapsed.as_secs_f64();
+
+    if rate >= 1_000_000.0 {
+        format!("{:.2}MB/s", rate / 1_000_000.0)
+    } else {
+        format!("{:.2}KB/s", rate / 1_000.0)
+    }
+}
+
+///
+/// Runs a HIF program, polling for completion as quickly as we can rather
+/// than at the leisurely pace of [`HiffyContext::run`], and returns the
+/// time taken along with the results.
+///
+fn timed(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    ops: &[Op],
+) -> Result<(Duration, Vec<Result<Vec<u8>, u32>>)> {
+    let started = Instant::now();
+
+    context.start(core, ops, None)?;
+
+    while !context.done(core)? {}
+
+    let elapsed = started.elapsed();
+
+    Ok((elapsed, context.results(core)?))
+}
+
+fn bench_hiffy(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    iterations: u32,
+) -> Result<Stats> {
+    let mut stats = Stats::new();
+
+    for _ in 0..iterations {
+        let (elapsed, _) = timed(core, context, &[Op::Done])?;
+        stats.add(elapsed);
+    }
+
+    Ok(stats)
+}
+
+fn bench_memory(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    iterations: u32,
+) -> Result<()> {
+    let buffer = hubris.lookup_variable("HIFFY_DATA")?;
+
+    println!("memory ({} iterations per size):", iterations);
+    println!("{:>12} {:>12} {:>12}", "SIZE", "READ", "WRITE");
+
+    for &size in MEMORY_SIZES.iter().filter(|&&s| s <= buffer.size) {
+        let mut buf = vec![0u8; size];
+
+        let started = Instant::now();
+
+        for _ in 0..iterations {
+            core.read_8(buffer.addr, &mut buf)?;
+        }
+
+        let read = started.elapsed();
+
+        //
+        // We write back what we read, leaving the buffer as we found it.
+        //
+        let started = Instant::now();
+
+        for _ in 0..iterations {
+            core.write_8(buffer.addr, &buf)?;
+        }
+
+        let write = started.elapsed();
+        let total = size * iterations as usize;
+
+        println!(
+            "{:>12} {:>12} {:>12}",
+            size,
+            rate(total, read),
+            rate(total, write)
+        );
+    }
+
+    Ok(())
+}
+
+fn bench_i2c(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    subargs: &BenchArgs,
+) -> Result<()> {
+    let hargs = I2cArgs::parse(
+        hubris,
+        &subargs.bus,
+        subargs.controller,
+        &subargs.port,
+        &subargs.mux,
+        &subargs.device,
+    )?;
+
+    let address = match hargs.address {
+        Some(address) => address,
+        None => bail!("must specify an I2C device"),
+    };
+
+    let funcs = context.functions()?;
+    let i2c_read = funcs.get("I2cRead", 7)?;
+
+    let mut ops = vec![Op::Push(hargs.controller), Op::Push(hargs.port.index)];
+
+    if let Some(mux) = hargs.mux {
+        ops.push(Op::Push(mux.0));
+        ops.push(Op::Push(mux.1));
+    } else {
+        ops.push(Op::PushNone);
+        ops.push(Op::PushNone);
+    }
+
+    ops.push(Op::Push(address));
+
+    match subargs.register {
+        Some(register) => ops.push(Op::Push(register)),
+        None => ops.push(Op::PushNone),
+    }
+
+    ops.push(Op::Push(1));
+
+    for _ in 0..I2C_TRANSACTIONS {
+        ops.push(Op::Call(i2c_read.id));
+    }
+
+    ops.push(Op::Done);
+
+    let mut stats = Stats::new();
+
+    for _ in 0..subargs.iterations {
+        let (empty, _) = timed(core, context, &[Op::Done])?;
+        let (elapsed, results) = timed(core, context, &ops)?;
+
+        if let Some(Err(code)) = results.iter().find(|r| r.is_err()) {
+            return Err(i2c_read.error(*code).context("I2C read failed"));
+        }
+
+        stats.add(elapsed.saturating_sub(empty) / I2C_TRANSACTIONS as u32);
+    }
+
+    match subargs.register {
+        Some(register) => println!(
+            "i2c ({}, register 0x{:02x}; {} iterations):",
+            hargs, register, subargs.iterations
+        ),
+        None => {
+            println!("i2c ({}; {} iterations):", hargs, subargs.iterations)
+        }
+    }
+
+    stats.print();
+
+    Ok(())
+}
+
+fn bench(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = BenchArgs::try_parse_from(subargs)?;
+
+    if subargs.iterations == 0 {
+        bail!("iterations must be non-zero");
+    }
+
+    let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+
+    let stats = bench_hiffy(core, &mut context, subargs.iterations)?;
+    println!("hiffy round-trip ({} iterations):", subargs.iterations);
+    stats.print();
+    println!();
+
+    bench_memory(hubris, core, subargs.iterations)?;
+
+    if subargs.device.is_some() {
+        println!();
+        bench_i2c(hubris, core, &mut context, &subargs)?;
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "bench",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: bench,
+        },
+        BenchArgs::command(),
+    )
+}
@@ -0,0 +1,832 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility break`
+//!
+//! `humility break` manages hardware breakpoints (via the Flash Patch and
+//! Breakpoint unit) and watchpoints (via the DWT).  Without arguments, the
+//! breakpoints and watchpoints that are set are listed:
+//!
+//! ```console
+//! % humility break
+//! humility: attached via ST-Link V3
+//! TYPE  NDX ADDR       SIZE ACCESS SYMBOL
+//! break   0 0x08027f44    - -      task_net::main+0x4
+//! watch   0 0x20006b10    4 write  task_net::RX_COUNT
+//! ```
+//!
+//! To set a breakpoint, specify a function (by name or by address); to set
+//! a watchpoint, use `-w` (`--watch`) and specify a variable (by name or by
+//! address).  By default, a watchpoint covers the entire variable and
+//! triggers on any access; use `-s` (`--size`) to specify the size of the
+//! watched region (which must be a naturally aligned power of two) and `-a`
+//! (`--access`) to trigger only on a `read` or a `write`:
+//!
+//! ```console
+//! % humility break -w RX_COUNT -a write
+//! humility: attached via ST-Link V3
+//! humility: watchpoint 0 set on task_net::RX_COUNT (0x20006b10, 4 bytes)
+//! ```
+//!
+//! To clear a breakpoint or watchpoint, use `-c` (`--clear`) with the
+//! symbol or address; to clear all of them, use `--clear-all`.
+//!
+//! Hitting a breakpoint or watchpoint halts the target.  To wait for the
+//! target to halt (resuming it first if it is halted), use `-W` (`--wait`),
+//! optionally with a timeout in milliseconds (`-T`).  When the target
+//! halts, the reason is displayed along with the current task and its
+//! stack:
+//!
+//! ```console
+//! % humility break --wait
+//! humility: attached via ST-Link V3
+//! humility: waiting for target to halt
+//! humility: halted on watchpoint 0 (write to task_net::RX_COUNT)
+//! humility: PC is 0x08028a1e in task_net::bsp::rx_packet+0x3a (net)
+//!    |
+//!    +--->  0x20006a60 0x08028a1e task_net::bsp::rx_packet
+//!           0x20006aa0 0x08027fd2 task_net::main
+//!           0x20006ac0 0x0802711e main
+//! ```
+//!
+//! The target is left halted; use `-r` (`--resume`) to resume it.  Note that
+//! a halted target does not service its watchdog, and that breakpoints and
+//! watchpoints remain set until cleared (or until the target is reset).
+//!
+//! To capture the state of the target when it halts -- e.g., to catch a
+//! rare memory corruption unattended -- use `--capture` with `--wait`,
+//! specifying a directory.  When the target halts, a directory named for
+//! the time of the halt is created within it, containing the stop report
+//! (`report.txt`), the contents of every ring buffer (`ringbufs.txt`) and a
+//! dump (`hubris.core`).  To then resume the target and wait for it to halt
+//! again (indefinitely, or until the timeout), add `--rearm`:
+//!
+//! ```console
+//! % humility break -w RX_COUNT -a write --wait --capture halts --rearm
+//! humility: attached via ST-Link V3
+//! humility: watchpoint 0 set on task_net::RX_COUNT (0x20006b10, 4 bytes)
+//! humility: waiting for target to halt
+//! humility: halted on watchpoint 0 (write to task_net::RX_COUNT)
+//! ...
+//! humility: dumping to halts/halt-2026-10-17T153012Z/hubris.core
+//! humility: dumped 1.12MB in 24 seconds
+//! humility: captured halt to halts/halt-2026-10-17T153012Z
+//! humility: waiting for target to halt
+//! ```
+//!
+//! To catch panics and faults as they happen (rather than discovering them
+//! after the fact from restart counts), use `--panics`, which sets
+//! breakpoints on the entry points of task panics, task faults and kernel
+//! panics as found in the archive.  When one of these is hit with `--wait`,
+//! the task that panicked is displayed along with its panic message and its
+//! stack; with `--capture`, the panic is captured as above:
+//!
+//! ```console
+//! % humility break --panics --wait --capture panics --rearm
+//! humility: attached via ST-Link V3
+//! humility: breakpoint 0 set at sys_panic_stub (0x08004f60)
+//! humility: breakpoint 1 set at kernel::arch::arm_m::handle_fault (0x08001a3c)
+//! humility: breakpoint 2 set at kernel::fail::die (0x08000e14)
+//! humility: waiting for target to halt
+//! humility: halted on task panic or fault
+//! humility: PC is 0x08004f60 in sys_panic_stub (spi_driver)
+//! humility: task spi_driver panicked: panicked at 'bad xfer', src/main.rs:93
+//!    |
+//!    +--->  0x20005f88 0x08004f60 sys_panic_stub
+//!           0x20005f90 0x08004f4c userlib::sys_panic
+//!           0x20005fa8 0x08004e22 userlib::task_slot::panic
+//!           0x20005fc0 0x080041f6 task_spi::main
+//! humility: dumping to panics/halt-2026-10-17T162244Z/hubris.core
+//! ...
+//! ```
+//!
+//! Note that a task is caught at the entry to the kernel's fault handler,
+//! before its fault has been recorded; once the target is resumed, the
+//! fault can be seen with `humility tasks`.
+//!
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{ArgEnum, CommandFactory, Parser};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::kernel::KernelState;
+use humility_cmd::output::{Cell, Column, Table};
+use humility_cmd::reflect::Format;
+use humility_cmd::ringbuf;
+use humility_cmd::stack::StackPrinter;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::*;
+use humility_cortex::dwt::*;
+use humility_cortex::fpb::*;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+    Rw,
+}
+
+impl From<Access> for DWTAccess {
+    fn from(access: Access) -> Self {
+        match access {
+            Access::Read => DWTAccess::Read,
+            Access::Write => DWTAccess::Write,
+            Access::Rw => DWTAccess::ReadWrite,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "break", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct BreakArgs {
+    /// set a watchpoint on a variable rather than a breakpoint
+    #[clap(long, short, requires = "target")]
+    watch: bool,
+
+    /// access that triggers the watchpoint
+    #[clap(long, short, arg_enum, default_value = "rw", requires = "watch")]
+    access: Access,
+
+    /// size of the watched region, in bytes
+    #[clap(
+        long, short, value_name = "bytes", requires = "watch",
+        parse(try_from_str = parse_int::parse)
+    )]
+    size: Option<u32>,
+
+    /// clear the breakpoint or watchpoint on the specified symbol or address
+    #[clap(long, short, requires = "target", conflicts_with = "watch")]
+    clear: bool,
+
+    /// clear all breakpoints and watchpoints
+    #[clap(long, conflicts_with_all = &["target", "clear"])]
+    clear_all: bool,
+
+    /// set breakpoints on the entry points of task panics and faults (and
+    /// of kernel panics)
+    #[clap(long, conflicts_with_all = &["target", "clear-all"])]
+    panics: bool,
+
+    /// resume the target if it is halted
+    #[clap(long, short)]
+    resume: bool,
+
+    /// wait for the target to halt, and display where it stopped
+    #[clap(long = "wait", short = 'W')]
+    wait: bool,
+
+    /// time to wait for the target to halt, in milliseconds
+    #[clap(
+        long, short = 'T', value_name = "timeout_ms", requires = "wait",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: Option<u64>,
+
+    /// when the target halts, capture its state to a timestamped directory
+    /// within the specified directory
+    #[clap(long, value_name = "directory", requires = "wait")]
+    capture: Option<String>,
+
+    /// after capturing, resume the target and wait for it to halt again
+    #[clap(long, requires = "capture")]
+    rearm: bool,
+
+    /// function or variable (by name or address)
+    target: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Catch {
+    Panic,
+    Fault,
+    KernelPanic,
+}
+
+///
+/// Functions on which we set breakpoints to catch panics and faults.  For
+/// each kind of catch, only the first of these that is found is used:  the
+/// panic entry points take the message as a pointer (in R0) and a length
+/// (in R1).
+///
+const CATCH_POINTS: &[(&str, Catch)] = &[
+    ("sys_panic_stub", Catch::Panic),
+    ("userlib::sys_panic", Catch::Panic),
+    ("kernel::arch::arm_m::handle_fault", Catch::Fault),
+    ("kernel::fail::die", Catch::KernelPanic),
+];
+
+fn catch_points(hubris: &HubrisArchive) -> Vec<(u32, Catch)> {
+    let mut rval: Vec<(u32, Catch)> = vec![];
+
+    for (name, catch) in CATCH_POINTS {
+        if rval.iter().any(|(_, c)| c == catch) {
+            continue;
+        }
+
+        if let Some((_, addr, _)) = hubris.lookup_functions(name).first() {
+            rval.push((*addr, *catch));
+        }
+    }
+
+    rval
+}
+
+fn access_name(access: DWTAccess) -> &'static str {
+    match access {
+        DWTAccess::Read => "read",
+        DWTAccess::Write => "write",
+        DWTAccess::ReadWrite => "rw",
+    }
+}
+
+///
+/// Returns a description of an instruction address in terms of the function
+/// that contains it.
+///
+fn instr_name(hubris: &HubrisArchive, addr: u32) -> String {
+    match hubris.instr_sym(addr) {
+        Some((sym, base)) if base == addr => sym.to_string(),
+        Some((sym, base)) => format!("{}+0x{:x}", sym, addr - base),
+        None => format!("0x{:08x}", addr),
+    }
+}
+
+///
+/// Returns a description of a data address in terms of the variable that
+/// contains it.
+///
+fn data_name(hubris: &HubrisArchive, addr: u32) -> String {
+    let found = hubris
+        .qualified_variables()
+        .find(|(_, v)| addr >= v.addr && addr < v.addr + v.size as u32);
+
+    match found {
+        Some((name, v)) if v.addr == addr => name.to_string(),
+        Some((name, v)) => format!("{}+0x{:x}", name, addr - v.addr),
+        None => format!("0x{:08x}", addr),
+    }
+}
+
+fn function(hubris: &HubrisArchive, name: &str) -> Result<u32> {
+    if let Ok(addr) = parse_int::parse::<u32>(name) {
+        if hubris.instr_mod(addr).is_none() {
+            bail!("0x{:x} is not an instruction address", addr);
+        }
+
+        return Ok(addr);
+    }
+
+    let found = hubris.lookup_functions(name);
+
+    match found.len() {
+        0 => bail!("no function matches \"{}\"", name),
+        1 => Ok(found[0].1),
+        _ => {
+            for (sym, addr, _) in &found {
+                humility::msg!(
+                    "0x{:08x} {} ({})",
+                    addr,
+                    sym,
+                    hubris.instr_mod(*addr).unwrap_or("?")
+                );
+            }
+
+            bail!("\"{}\" matches multiple functions; specify by address", name)
+        }
+    }
+}
+
+fn variable(hubris: &HubrisArchive, name: &str) -> Result<(u32, usize)> {
+    if let Ok(addr) = parse_int::parse::<u32>(name) {
+        return Ok((addr, 4));
+    }
+
+    let variables: Vec<&HubrisVariable> = match hubris.lookup_variables(name) {
+        Ok(variables) => variables.iter().collect(),
+        Err(_) => hubris
+            .qualified_variables()
+            .filter(|(n, _)| *n == name)
+            .map(|(_, v)| v)
+            .collect(),
+    };
+
+    match variables.len() {
+        0 => bail!("variable {} not found (use \"readvar -l\" to list)", name),
+        1 => Ok((variables[0].addr, variables[0].size)),
+        _ => {
+            for v in &variables {
+                humility::msg!(
+                    "0x{:08x} {}",
+                    v.addr,
+                    data_name(hubris, v.addr)
+                );
+            }
+
+            bail!("\"{}\" matches multiple variables; specify by address", name)
+        }
+    }
+}
+
+fn breakpoints(core: &mut dyn Core) -> Result<Vec<(u32, u32)>> {
+    let mut rval = vec![];
+
+    for ndx in 0..FP_CTRL::read(core)?.num_code() {
+        if let Some(addr) = fpb_addr(core, ndx)? {
+            rval.push((ndx, addr));
+        }
+    }
+
+    Ok(rval)
+}
+
+fn watchpoints(core: &mut dyn Core) -> Result<Vec<(u32, DWTWatchpoint)>> {
+    let mut rval = vec![];
+
+    for ndx in 0..DWT_CTRL::read(core)?.num_comparators() {
+        if let Some(watch) = dwt_watch_read(core, ndx)? {
+            rval.push((ndx, watch));
+        }
+    }
+
+    Ok(rval)
+}
+
+fn set_breakpoint(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    addr: u32,
+) -> Result<()> {
+    let set = breakpoints(core)?;
+
+    if let Some((ndx, _)) = set.iter().find(|(_, a)| *a == addr) {
+        humility::msg!("breakpoint {} already set at 0x{:x}", ndx, addr);
+        return Ok(());
+    }
+
+    let ndx = match (0..FP_CTRL::read(core)?.num_code())
+        .find(|ndx| set.iter().all(|(n, _)| n != ndx))
+    {
+        Some(ndx) => ndx,
+        None => bail!("all {} breakpoints are in use", set.len()),
+    };
+
+    fpb_set(core, ndx, addr)?;
+    fpb_enable(core)?;
+
+    humility::msg!(
+        "breakpoint {} set at {} (0x{:x})",
+        ndx,
+        instr_name(hubris, addr),
+        addr
+    );
+
+    Ok(())
+}
+
+fn set_watchpoint(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &BreakArgs,
+    addr: u32,
+    size: usize,
+) -> Result<()> {
+    let size = match subargs.size {
+        Some(size) => size,
+        None if (size as u32).is_power_of_two() => size as u32,
+        None => {
+            bail!("variable is {} bytes; specify a size with --size", size)
+        }
+    };
+
+    let set = watchpoints(core)?;
+
+    let ndx = match (0..DWT_CTRL::read(core)?.num_comparators())
+        .find(|ndx| set.iter().all(|(n, _)| n != ndx))
+    {
+        Some(ndx) => ndx,
+        None => bail!("all {} watchpoints are in use", set.len()),
+    };
+
+    //
+    // The DWT comparators are only enabled when tracing is enabled.
+    //
+    let mut demcr = DEMCR::read(core)?;
+    demcr.set_trcena(true);
+    demcr.write(core)?;
+
+    dwt_watch_set(core, ndx, addr, size, subargs.access.into())?;
+
+    humility::msg!(
+        "watchpoint {} set on {} (0x{:x}, {} bytes)",
+        ndx,
+        data_name(hubris, addr),
+        addr,
+        size
+    );
+
+    Ok(())
+}
+
+fn clear(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    target: &str,
+) -> Result<()> {
+    let mut cleared = false;
+
+    if let Ok(addr) = function(hubris, target) {
+        for (ndx, _) in breakpoints(core)?.iter().filter(|(_, a)| *a == addr) {
+            fpb_clear(core, *ndx)?;
+            humility::msg!("breakpoint {} cleared", ndx);
+            cleared = true;
+        }
+    }
+
+    if let Ok((addr, _)) = variable(hubris, target) {
+        for (ndx, _) in
+            watchpoints(core)?.iter().filter(|(_, w)| w.addr == addr)
+        {
+            dwt_watch_clear(core, *ndx)?;
+            humility::msg!("watchpoint {} cleared", ndx);
+            cleared = true;
+        }
+    }
+
+    if !cleared {
+        bail!("no breakpoint or watchpoint is set on {}", target);
+    }
+
+    Ok(())
+}
+
+fn clear_all(core: &mut dyn Core) -> Result<()> {
+    for (ndx, _) in breakpoints(core)? {
+        fpb_clear(core, ndx)?;
+    }
+
+    for (ndx, _) in watchpoints(core)? {
+        dwt_watch_clear(core, ndx)?;
+    }
+
+    humility::msg!("cleared all breakpoints and watchpoints");
+    Ok(())
+}
+
+fn list(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+) -> Result<()> {
+    let mut table = Table::new(
+        args.format,
+        vec![
+            Column::new("type", 5),
+            Column::new("ndx", 3).right(),
+            Column::new("addr", 10),
+            Column::new("size", 4).right(),
+            Column::new("access", 6),
+            Column::new("symbol", 30),
+        ],
+    );
+
+    for (ndx, addr) in breakpoints(core)? {
+        table.row(vec![
+            "break".into(),
+            ndx.into(),
+            Cell::Hex(addr as u64, 8),
+            Cell::None,
+            Cell::None,
+            instr_name(hubris, addr).into(),
+        ])?;
+    }
+
+    for (ndx, watch) in watchpoints(core)? {
+        table.row(vec![
+            "watch".into(),
+            ndx.into(),
+            Cell::Hex(watch.addr as u64, 8),
+            watch.size.into(),
+            access_name(watch.access).into(),
+            data_name(hubris, watch.addr).into(),
+        ])?;
+    }
+
+    Ok(())
+}
+
+fn set_catch_points(hubris: &HubrisArchive, core: &mut dyn Core) -> Result<()> {
+    let points = catch_points(hubris);
+
+    if points.is_empty() {
+        bail!("no panic or fault entry points found in archive");
+    }
+
+    for (addr, _) in points {
+        set_breakpoint(hubris, core, addr)?;
+    }
+
+    Ok(())
+}
+
+///
+/// Reads the message of a panic from a task halted at a panic entry point.
+///
+fn panic_message(core: &mut dyn Core) -> Result<String> {
+    let base = core.read_reg(ARMRegister::R0)?;
+    let len = core.read_reg(ARMRegister::R1)?.min(255) as usize;
+    let mut buf = vec![0; len];
+
+    core.read_8(base, &mut buf)?;
+
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+///
+/// Resumes a halted target.  If the target is halted on a breakpoint, we
+/// must step over it with the breakpoint cleared, lest we immediately halt
+/// again.
+///
+fn resume(core: &mut dyn Core) -> Result<()> {
+    let pc = core.read_reg(ARMRegister::PC)?;
+
+    for (ndx, addr) in breakpoints(core)? {
+        if addr == pc {
+            let saved = fpb_read(core, ndx)?;
+            fpb_clear(core, ndx)?;
+            core.step()?;
+            fpb_write(core, ndx, saved)?;
+        }
+    }
+
+    core.halt()?;
+    core.run()
+}
+
+///
+/// Reports why the target halted, returning the report as text.
+///
+fn report(hubris: &HubrisArchive, core: &mut dyn Core) -> Result<String> {
+    let dfsr = DFSR::read(core)?;
+    let pc = core.read_reg(ARMRegister::PC)?;
+    let mut text = vec![];
+
+    let caught = catch_points(hubris)
+        .into_iter()
+        .find(|(addr, _)| *addr == pc)
+        .map(|(_, catch)| catch);
+
+    let mut msg = |line: String| {
+        humility::msg!("{}", line);
+        text.push(line);
+    };
+
+    if dfsr.watchpoint() {
+        for (ndx, watch) in watchpoints(core)?.iter().filter(|(_, w)| w.matched)
+        {
+            msg(format!(
+                "halted on watchpoint {} ({} of {})",
+                ndx,
+                access_name(watch.access),
+                data_name(hubris, watch.addr)
+            ));
+        }
+    } else if dfsr.breakpoint() && caught == Some(Catch::KernelPanic) {
+        msg("halted on kernel panic".to_string());
+    } else if dfsr.breakpoint() && caught.is_some() {
+        msg("halted on task panic or fault".to_string());
+    } else if dfsr.breakpoint() {
+        msg("halted on breakpoint".to_string());
+    } else if dfsr.vector_catch() {
+        msg("halted on vector catch".to_string());
+    } else {
+        msg("halted".to_string());
+    }
+
+    //
+    // The DFSR bits are write-one-to-clear; clear the ones we've seen.
+    //
+    dfsr.write(core)?;
+
+    let module = hubris.instr_mod(pc).unwrap_or("?");
+
+    msg(format!(
+        "PC is 0x{:08x} in {} ({})",
+        pc,
+        instr_name(hubris, pc),
+        module
+    ));
+
+    let kernel = KernelState::read(hubris, core)?;
+    let current = kernel
+        .current
+        .and_then(|ndx| kernel.tasks.iter().find(|t| t.index == ndx));
+
+    let task = match current {
+        Some(task) => task,
+        None => return Ok(text.join("\n") + "\n"),
+    };
+
+    match caught {
+        Some(Catch::Panic) => msg(format!(
+            "task {} panicked: {}",
+            task.name,
+            panic_message(core)?
+        )),
+        Some(Catch::Fault) => msg(format!("task {} faulted", task.name)),
+        _ if module == "kernel" => {
+            msg(format!("current task is {}", task.name))
+        }
+        _ => {}
+    }
+
+    let t = HubrisTask::Task(task.index);
+    let regs = hubris.registers(core, t)?;
+
+    match hubris.stack(core, t, task.desc.initial_stack, &regs) {
+        Ok(stack) => {
+            StackPrinter::default().print(hubris, &stack);
+
+            for frame in &stack {
+                let pc = frame.registers.get(&ARMRegister::PC).unwrap();
+
+                text.push(match frame.sym {
+                    Some(sym) => format!(
+                        "0x{:08x} 0x{:08x} {}",
+                        frame.cfa, pc, sym.demangled_name
+                    ),
+                    None => format!("0x{:08x} 0x{:08x}", frame.cfa, pc),
+                });
+            }
+        }
+        Err(e) => msg(format!("stack unwind failed: {:?}", e)),
+    }
+
+    Ok(text.join("\n") + "\n")
+}
+
+///
+/// Captures the state of a halted target -- its stop report, its ring
+/// buffers and a dump -- to a new timestamped directory within `dir`.
+///
+fn capture(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    dir: &str,
+    report: &str,
+) -> Result<()> {
+    let stamp = humantime::format_rfc3339_seconds(SystemTime::now())
+        .to_string()
+        .replace(':', "");
+
+    let path = Path::new(dir).join(format!("halt-{}", stamp));
+
+    fs::create_dir_all(&path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+
+    fs::write(path.join("report.txt"), report)?;
+
+    let mut ringbufs = String::new();
+    let fmt = HubrisPrintFormat { hex: true, ..HubrisPrintFormat::default() };
+
+    for v in ringbuf::ringbufs(hubris)? {
+        writeln!(ringbufs, "{} ({}):", v.name, v.task)?;
+
+        let ringbuf = match v.read(hubris, core) {
+            Ok(ringbuf) => ringbuf,
+            Err(e) => {
+                writeln!(ringbufs, "    failed to read: {}\n", e)?;
+                continue;
+            }
+        };
+
+        for (slot, entry) in ringbuf::entries(&ringbuf) {
+            let mut payload = vec![];
+            entry.payload.format(hubris, fmt, &mut payload)?;
+
+            writeln!(
+                ringbufs,
+                "{:4} {:4} {:8} {:8} {}",
+                slot,
+                entry.line,
+                entry.generation,
+                entry.count,
+                String::from_utf8_lossy(&payload)
+            )?;
+        }
+
+        writeln!(ringbufs)?;
+    }
+
+    fs::write(path.join("ringbufs.txt"), ringbufs)?;
+
+    let dumpfile = path.join("hubris.core").to_string_lossy().to_string();
+    let options = HubrisDumpOptions::default();
+
+    hubris.dump(core, Some(dumpfile.as_str()), &options)?;
+
+    humility::msg!("captured halt to {}", path.display());
+
+    Ok(())
+}
+
+fn wait(core: &mut dyn Core, timeout: Option<u64>) -> Result<()> {
+    let started = Instant::now();
+
+    humility::msg!("waiting for target to halt");
+
+    while !DHCSR::read(core)?.halted() {
+        if let Some(timeout) = timeout {
+            if started.elapsed() > Duration::from_millis(timeout) {
+                bail!("timed out waiting for target to halt");
+            }
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    //
+    // Account for the halt so that subsequent operations don't resume the
+    // target out from under us.
+    //
+    core.halt()
+}
+
+fn breakcmd(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = BreakArgs::try_parse_from(subargs)?;
+
+    if subargs.clear_all {
+        clear_all(core)?;
+    } else if subargs.panics {
+        set_catch_points(hubris, core)?;
+    } else if let Some(target) = &subargs.target {
+        if subargs.clear {
+            clear(hubris, core, target)?;
+        } else if subargs.watch {
+            let (addr, size) = variable(hubris, target)?;
+            set_watchpoint(hubris, core, &subargs, addr, size)?;
+        } else {
+            let addr = function(hubris, target)?;
+            set_breakpoint(hubris, core, addr)?;
+        }
+    } else if !subargs.resume && !subargs.wait {
+        list(hubris, core, args)?;
+    }
+
+    let halted = DHCSR::read(core)?.halted();
+
+    if halted && (subargs.resume || subargs.wait) {
+        resume(core)?;
+    } else if halted {
+        let pc = core.read_reg(ARMRegister::PC)?;
+
+        humility::msg!(
+            "target is halted at {}; use --resume to resume",
+            instr_name(hubris, pc)
+        );
+    }
+
+    if subargs.wait {
+        loop {
+            wait(core, subargs.timeout)?;
+            let text = report(hubris, core)?;
+
+            if let Some(dir) = &subargs.capture {
+                capture(hubris, core, dir, &text)?;
+            }
+
+            if !subargs.rearm {
+                break;
+            }
+
+            resume(core)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "break",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: breakcmd,
+        },
+        BreakArgs::command(),
+    )
+}
@@ -0,0 +1,115 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility compat`
+//!
+//! `humility compat` checks the compatibility of an archive with a target
+//! or (more usefully) a dump.  Humility ordinarily refuses to use an
+//! archive whose image ID does not match the target; `humility compat`
+//! reports whether the image ID matches and, for each task (and the
+//! kernel), whether the task's text on the target matches its text in the
+//! archive:
+//!
+//! ```console
+//! % humility -d hubris.core.7 -a build-gimlet-b-old.zip compat
+//! humility: attached to dump
+//! humility: image ID does not match: image ID in archive ([5f, 2a, ...])
+//! does not equal ID in RAM at 0x8000300 ([a3, 19, ...])
+//! TASK                 ARCHIVE            TARGET             STATUS
+//! kernel               0x6b1d0e8e7a3c2f55 0x6b1d0e8e7a3c2f55 match
+//! jefe                 0x0c52b5f1d9e2a4a7 0x0c52b5f1d9e2a4a7 match
+//! net                  0x3fa1c0d7b2e85190 0x9e27c5a8d0f3b146 MISMATCH
+//! ...
+//! humility compat failed: 1 task does not match archive
+//! ```
+//!
+//! A task whose text is not present on the target (e.g., because the dump
+//! does not include flash) is reported as `unavailable`.  The command fails
+//! if any task's text does not match.  To nonetheless use a mismatched
+//! archive with a dump, specify the global `--allow-mismatch` option:
+//! Humility will then warn about each task whose text differs (and whose
+//! symbols are therefore unreliable) rather than refusing to proceed.
+//!
+
+use anyhow::Result;
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::error::ErrorKind;
+use humility_cmd::output::{Cell, Column, Table};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+
+#[derive(Parser, Debug)]
+#[clap(name = "compat", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct CompatArgs {}
+
+fn compat(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let _subargs = CompatArgs::try_parse_from(subargs)?;
+
+    match hubris.validate(core, HubrisValidate::ArchiveMatch) {
+        Ok(_) => humility::msg!("image ID matches"),
+        Err(err) => humility::msg!("image ID does not match: {}", err),
+    }
+
+    let mut table = Table::new(
+        args.format,
+        vec![
+            Column::new("task", 20),
+            Column::new("archive", 18),
+            Column::new("target", 18),
+            Column::new("status", 6),
+        ],
+    );
+
+    let mut mismatched = 0;
+
+    for check in hubris.check_text(core) {
+        let (target, status) = match &check.target {
+            Ok(hash) if *hash == check.archive => {
+                (Cell::Hex(*hash, 16), "match")
+            }
+            Ok(hash) => {
+                mismatched += 1;
+                (Cell::Hex(*hash, 16), "MISMATCH")
+            }
+            Err(_) => (Cell::None, "unavailable"),
+        };
+
+        table.row(vec![
+            check.name.as_str().into(),
+            Cell::Hex(check.archive, 16),
+            target,
+            status.into(),
+        ])?;
+    }
+
+    if mismatched > 0 {
+        return Err(ErrorKind::ArchiveMismatch.error(format!(
+            "{} task{} not match archive",
+            mismatched,
+            if mismatched == 1 { " does" } else { "s do" }
+        )));
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "compat",
+            archive: Archive::Required,
+            attach: Attach::Any,
+            validate: Validate::None,
+            run: compat,
+        },
+        CompatArgs::command(),
+    )
+}
@@ -0,0 +1,362 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility counters`
+//!
+//! `humility counters` reads and displays event counters, as created via
+//! the `counters!` macro in the Hubris `counters` crate.  Each counted event
+//! is displayed along with the task that counts it; by default, only events
+//! that have occurred are shown (use `-a` to display all of them):
+//!
+//! ```console
+//! % humility counters
+//! humility: attached via ST-Link
+//! TASK            COUNTER                             COUNT
+//! i2c_driver      Reset                                   3
+//! i2c_driver      Read.Timeout                           17
+//! net             RxPacket                            20419
+//! ```
+//!
+//! If an argument is provided, only counters in a task (or with a name)
+//! containing the argument as a substring are displayed.  The counts read
+//! from a live system are saved (keyed by image ID); to display how each
+//! count has changed since the last invocation against the same image, use
+//! `-d` (`--diff`).  A count that is lower than it was previously (e.g.,
+//! because its task has restarted) is shown as having changed by its
+//! current value:
+//!
+//! ```console
+//! % humility counters --diff
+//! humility: attached via ST-Link
+//! TASK            COUNTER                             COUNT      DELTA
+//! i2c_driver      Read.Timeout                           19          2
+//! net             RxPacket                            20587        168
+//! ```
+//!
+//! To continue to display counts as they change, use `-f` (`--follow`),
+//! optionally specifying the polling interval in milliseconds with `-i`
+//! (`--interval`); each change is displayed with its rate (in events per
+//! second) over the interval.
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::counters::{self, CountersVariable};
+use humility_cmd::output::{Cell, Column, Table};
+use humility_cmd::style::Severity;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[clap(name = "counters", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct CountersArgs {
+    /// list counters variables
+    #[clap(long, short)]
+    list: bool,
+
+    /// display all counters, including those that are zero or unchanged
+    #[clap(long, short, conflicts_with = "list")]
+    all: bool,
+
+    /// display the change in each counter since the last invocation
+    #[clap(long, short, conflicts_with_all = &["list", "follow"])]
+    diff: bool,
+
+    /// continue to display counters as they change
+    #[clap(long, short, conflicts_with = "list")]
+    follow: bool,
+
+    /// interval between reads when following, in milliseconds
+    #[clap(
+        long, short, default_value = "1000", value_name = "ms",
+        requires = "follow", parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
+    /// display only counters in a task or variable containing this
+    name: Option<String>,
+}
+
+//
+// Counts are keyed by task and then by counter.
+//
+type Counts = BTreeMap<(String, String), u64>;
+
+fn read(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    variables: &[CountersVariable],
+) -> Result<Counts> {
+    let mut rval = Counts::new();
+
+    //
+    // If a task has more than one counters variable, we qualify each of its
+    // counters with the name of its variable.
+    //
+    let qualify = |v: &CountersVariable| {
+        variables.iter().filter(|other| other.task == v.task).count() > 1
+    };
+
+    if !core.is_dump() {
+        core.halt()?;
+    }
+
+    let results =
+        variables.iter().map(|v| (v, v.read(hubris, core))).collect::<Vec<_>>();
+
+    if !core.is_dump() {
+        core.run()?;
+    }
+
+    for (v, result) in results {
+        let counts = match result {
+            Ok(counts) => counts,
+            Err(e) => {
+                humility::msg!("counters {} in {}: {}", v.name, v.task, e);
+                continue;
+            }
+        };
+
+        for (path, count) in counts {
+            let name =
+                if qualify(v) { format!("{}.{}", v.name, path) } else { path };
+
+            rval.insert((v.task.to_string(), name), count);
+        }
+    }
+
+    Ok(rval)
+}
+
+///
+/// The change in a count, given its previous value.  A count that has gone
+/// down has been reset (e.g., by its task restarting), so the change is its
+/// current value.
+///
+fn delta(prev: Option<u64>, count: u64) -> u64 {
+    match prev {
+        Some(prev) if prev <= count => count - prev,
+        _ => count,
+    }
+}
+
+///
+/// Returns the cell for a change in a count, highlighting any change.
+///
+fn changed(change: u64) -> Cell {
+    if change != 0 {
+        Cell::from(change).styled(Severity::Changed)
+    } else {
+        change.into()
+    }
+}
+
+///
+/// Returns the path of the file in which counts are saved between
+/// invocations, keyed by the image ID of the archive.
+///
+fn saved(hubris: &HubrisArchive) -> Option<PathBuf> {
+    let id: String =
+        hubris.image_id()?.iter().map(|b| format!("{:02x}", b)).collect();
+
+    Some(std::env::temp_dir().join(format!("humility-counters-{}.json", id)))
+}
+
+fn load(hubris: &HubrisArchive) -> Option<Counts> {
+    let contents = std::fs::read_to_string(saved(hubris)?).ok()?;
+    let map: BTreeMap<String, u64> = serde_json::from_str(&contents).ok()?;
+
+    Some(
+        map.into_iter()
+            .filter_map(|(key, count)| {
+                let (task, name) = key.split_once('/')?;
+                Some(((task.to_string(), name.to_string()), count))
+            })
+            .collect(),
+    )
+}
+
+fn save(hubris: &HubrisArchive, counts: &Counts) -> Result<()> {
+    let path = match saved(hubris) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let map: BTreeMap<String, u64> = counts
+        .iter()
+        .map(|((task, name), count)| (format!("{}/{}", task, name), *count))
+        .collect();
+
+    std::fs::write(path, serde_json::to_string(&map)?)?;
+
+    Ok(())
+}
+
+fn counterscmd(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = CountersArgs::try_parse_from(subargs)?;
+
+    if subargs.follow && core.is_dump() {
+        bail!("cannot follow counters in a dump");
+    }
+
+    let variables = counters::counters(hubris)?
+        .into_iter()
+        .filter(|v| match &subargs.name {
+            Some(name) => v.name.contains(name) || v.task.contains(name),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+
+    if variables.is_empty() {
+        match subargs.name {
+            Some(name) => {
+                bail!("no counters contain \"{}\" (-l to list)", name)
+            }
+            None => bail!("no counters found"),
+        }
+    }
+
+    if subargs.list {
+        let mut table = Table::new(
+            args.format,
+            vec![
+                Column::new("task", 18),
+                Column::new("variable", 30),
+                Column::new("addr", 10),
+                Column::new("size", 6),
+            ],
+        );
+
+        for v in variables {
+            table.row(vec![
+                v.task.into(),
+                v.name.into(),
+                Cell::Hex(v.variable.addr as u64, 8),
+                Cell::Size(v.variable.size as u64),
+            ])?;
+        }
+
+        return Ok(());
+    }
+
+    let mut prev = if subargs.diff {
+        let prev = load(hubris);
+
+        if prev.is_none() {
+            humility::msg!("no previous counts for this image");
+        }
+
+        prev
+    } else {
+        None
+    };
+
+    let mut columns = vec![
+        Column::new("task", 15),
+        Column::new("counter", 30),
+        Column::new("count", 10),
+    ];
+
+    if subargs.diff || subargs.follow {
+        columns.push(Column::new("delta", 10));
+    }
+
+    if subargs.follow {
+        columns.insert(0, Column::new("time", 10));
+        columns.push(Column::new("rate", 10));
+    }
+
+    let mut table = Table::new(args.format, columns);
+    let interval = Duration::from_millis(subargs.interval);
+    let started = Instant::now();
+    let mut last = started;
+
+    loop {
+        let counts = read(hubris, core, &variables)?;
+        let now = Instant::now();
+        let elapsed = now.duration_since(last).as_secs_f64();
+
+        for ((task, name), count) in &counts {
+            let key = (task.clone(), name.clone());
+            let before = prev.as_ref().and_then(|p| p.get(&key).copied());
+            let change = delta(before, *count);
+
+            let shown = match &prev {
+                _ if subargs.all => true,
+                Some(_) => change != 0,
+                None => *count != 0,
+            };
+
+            if !shown {
+                continue;
+            }
+
+            let mut row: Vec<Cell> = vec![
+                task.as_str().into(),
+                name.as_str().into(),
+                (*count).into(),
+            ];
+
+            if subargs.follow {
+                row.insert(0, Cell::Float(started.elapsed().as_secs_f64()));
+
+                //
+                // On our first read, we have nothing to compare against.
+                //
+                match &prev {
+                    Some(_) => {
+                        row.push(changed(change));
+                        row.push(Cell::Float(change as f64 / elapsed));
+                    }
+                    None => {
+                        row.push(Cell::None);
+                        row.push(Cell::None);
+                    }
+                }
+            } else if subargs.diff {
+                row.push(changed(change));
+            }
+
+            table.row(row)?;
+        }
+
+        if !core.is_dump() {
+            save(hubris, &counts)?;
+        }
+
+        if !subargs.follow {
+            break;
+        }
+
+        prev = Some(counts);
+        last = now;
+        thread::sleep(interval);
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "counters",
+            archive: Archive::Required,
+            attach: Attach::Any,
+            validate: Validate::Match,
+            run: counterscmd,
+        },
+        CountersArgs::command(),
+    )
+}
@@ -383,8 +383,8 @@ impl Graph {
 }
 
 struct Dashboard<'a> {
-    hubris: &'a HubrisArchive,
     context: HiffyContext<'a>,
+    idol: idol::IdolCache<'a>,
     ops: Vec<Op>,
     graphs: Vec<Graph>,
     current: usize,
@@ -402,19 +402,23 @@ impl<'a> Dashboard<'a> {
         subargs: &DashboardArgs,
     ) -> Result<Dashboard<'a>> {
         let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+        let mut idol = idol::IdolCache::new(hubris);
         let mut ops = vec![];
 
-        let temps = sensor_ops(hubris, &mut context, &mut ops, |s| {
-            s.kind == HubrisSensorKind::Temperature
-        })?;
+        let temps =
+            sensor_ops(hubris, &mut idol, &mut context, &mut ops, |s| {
+                s.kind == HubrisSensorKind::Temperature
+            })?;
 
-        let fans = sensor_ops(hubris, &mut context, &mut ops, |s| {
-            s.kind == HubrisSensorKind::Speed
-        })?;
+        let fans =
+            sensor_ops(hubris, &mut idol, &mut context, &mut ops, |s| {
+                s.kind == HubrisSensorKind::Speed
+            })?;
 
-        let current = sensor_ops(hubris, &mut context, &mut ops, |s| {
-            s.kind == HubrisSensorKind::Current
-        })?;
+        let current =
+            sensor_ops(hubris, &mut idol, &mut context, &mut ops, |s| {
+                s.kind == HubrisSensorKind::Current
+            })?;
 
         ops.push(Op::Done);
 
@@ -435,8 +439,8 @@ impl<'a> Dashboard<'a> {
         ];
 
         Ok(Dashboard {
-            hubris,
             context,
+            idol,
             ops,
             graphs,
             current: 0,
@@ -565,25 +569,25 @@ impl<'a> Dashboard<'a> {
     fn enter(&mut self) {}
 
     fn set_a0(&mut self, core: &mut dyn Core) -> Result<()> {
-        let ops = power_ops(self.hubris, &mut self.context, "A0")?;
+        let ops = power_ops(&mut self.idol, &mut self.context, "A0")?;
         self.enqueue_work(core, ops)?;
         Ok(())
     }
 
     fn set_a2(&mut self, core: &mut dyn Core) -> Result<()> {
-        let ops = power_ops(self.hubris, &mut self.context, "A2")?;
+        let ops = power_ops(&mut self.idol, &mut self.context, "A2")?;
         self.enqueue_work(core, ops)?;
         Ok(())
     }
 
     fn fans_on(&mut self, core: &mut dyn Core) -> Result<()> {
-        let ops = fan_ops(self.hubris, &mut self.context, true)?;
+        let ops = fan_ops(&mut self.idol, &mut self.context, true)?;
         self.enqueue_work(core, ops)?;
         Ok(())
     }
 
     fn fans_off(&mut self, core: &mut dyn Core) -> Result<()> {
-        let ops = fan_ops(self.hubris, &mut self.context, false)?;
+        let ops = fan_ops(&mut self.idol, &mut self.context, false)?;
         self.enqueue_work(core, ops)?;
         Ok(())
     }
@@ -594,7 +598,7 @@ impl<'a> Dashboard<'a> {
         index: usize,
         pwm: u8,
     ) -> Result<()> {
-        let ops = pwm_ops(self.hubris, &mut self.context, index, pwm)?;
+        let ops = pwm_ops(&mut self.idol, &mut self.context, index, pwm)?;
         self.enqueue_work(core, ops)?;
         Ok(())
     }
@@ -706,13 +710,14 @@ pub fn init() -> (Command, ClapCommand<'static>) {
 
 fn sensor_ops(
     hubris: &HubrisArchive,
+    cache: &mut idol::IdolCache,
     context: &mut HiffyContext,
     ops: &mut Vec<Op>,
     capture: impl Fn(&HubrisSensor) -> bool,
 ) -> Result<Vec<String>> {
     let mut sensors = vec![];
     let funcs = context.functions()?;
-    let op = idol::IdolOperation::new(hubris, "Sensor", "get", None)?;
+    let op = cache.get("Sensor", "get", None)?;
 
     let ok = hubris.lookup_basetype(op.ok)?;
 
@@ -724,14 +729,15 @@ fn sensor_ops(
         bail!("expected return value of read_sensor() to be an f32");
     }
 
+    let mut payload = op.template(&[("id", idol::IdolArgument::Scalar(0))])?;
+
     for (i, s) in hubris.manifest.sensors.iter().enumerate() {
         if !capture(s) {
             continue;
         }
 
-        let payload =
-            op.payload(&[("id", idol::IdolArgument::Scalar(i as u64))])?;
-        context.idol_call_ops(&funcs, &op, &payload, ops)?;
+        payload.set("id", i as u64)?;
+        context.idol_call_ops(&funcs, &op, payload.as_slice(), ops)?;
         sensors.push(s.name.clone());
     }
 
@@ -739,13 +745,13 @@ fn sensor_ops(
 }
 
 fn power_ops(
-    hubris: &HubrisArchive,
+    cache: &mut idol::IdolCache,
     context: &mut HiffyContext,
     state: &str,
 ) -> Result<Vec<Op>> {
     let mut ops = vec![];
     let funcs = context.functions()?;
-    let op = idol::IdolOperation::new(hubris, "Sequencer", "set_state", None)?;
+    let op = cache.get("Sequencer", "set_state", None)?;
 
     let payload =
         op.payload(&[("state", idol::IdolArgument::String(state))])?;
@@ -756,14 +762,13 @@ fn power_ops(
 }
 
 fn fan_ops(
-    hubris: &HubrisArchive,
+    cache: &mut idol::IdolCache,
     context: &mut HiffyContext,
     on: bool,
 ) -> Result<Vec<Op>> {
     let mut ops = vec![];
     let funcs = context.functions()?;
-    let op = idol::IdolOperation::new(
-        hubris,
+    let op = cache.get(
         "Sequencer",
         if on { "fans_on" } else { "fans_off" },
         None,
@@ -777,14 +782,14 @@ fn fan_ops(
 }
 
 fn pwm_ops(
-    hubris: &HubrisArchive,
+    cache: &mut idol::IdolCache,
     context: &mut HiffyContext,
     index: usize,
     pwm: u8,
 ) -> Result<Vec<Op>> {
     let mut ops = vec![];
     let funcs = context.functions()?;
-    let op = idol::IdolOperation::new(hubris, "Thermal", "set_fan_pwm", None)?;
+    let op = cache.get("Thermal", "set_fan_pwm", None)?;
 
     let payload = op.payload(&[
         ("index", idol::IdolArgument::Scalar(index as u64)),
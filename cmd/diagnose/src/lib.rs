@@ -347,7 +347,7 @@ fn diagnose(
 
     if !subargs.no_dump {
         section("Generating Coredump");
-        let rval = hubris.dump(core, None);
+        let rval = hubris.dump(core, None, &HubrisDumpOptions::default());
         if let Err(e) = rval {
             println!("Coredump failed: {}", e);
         }
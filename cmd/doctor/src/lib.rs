@@ -0,0 +1,521 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility doctor`
+//!
+//! `humility doctor` checks each layer that Humility depends upon, in the
+//! order in which they depend on one another: the archive, attaching via
+//! the probe, identifying the target, the target running the image in the
+//! archive, the kernel having booted, the HIF execution facility (the
+//! `hiffy` task) answering, the sensor task being present, and each I2C
+//! controller in the archive being reachable.  Each check either passes,
+//! fails, or is skipped (because a check it depends on failed, or because
+//! it does not apply to the archive); a failed check is accompanied by a
+//! hint as to how to remedy it:
+//!
+//! ```console
+//! % humility doctor
+//! CHECK                STATUS DETAIL
+//! archive              pass   gimlet-c (build-gimlet-c.zip)
+//! probe                pass   via ST-Link V3
+//! target               pass   ARM Cortex-M7 (STMicroelectronics)
+//! archive match        pass   image ID 5f1f4ef1a1b9e3a8
+//! booted               pass   kernel running, 24 tasks, none faulted
+//! hiffy                fail   HIF versions appear uninitialized; has the hiffy task not yet run?
+//! sensors              pass   sensor task present, 48 sensors
+//! i2c mid              skip   requires hiffy
+//! i2c front            skip   requires hiffy
+//!
+//! hiffy: the hiffy task may be faulted, starved or absent; check its state with "humility tasks hiffy"
+//! humility doctor failed: 1 check failed
+//! ```
+//!
+//! With `--format json` or `--format csv`, each check is emitted with its
+//! hint.  `humility doctor` exits with an error if any check fails.
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::kernel::KernelState;
+use humility_cmd::output::{Cell, Column, OutputFormat, Table};
+use humility_cmd::{Archive, Args, Command};
+use humility_cortex::debug::corename;
+use humility_cortex::scs::CoreInfo;
+
+#[derive(Parser, Debug)]
+#[clap(name = "doctor", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct DoctorArgs {
+    /// sets timeout for HIF operations
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Status {
+    Pass,
+    Fail,
+    Skip,
+}
+
+impl Status {
+    fn name(&self) -> &'static str {
+        match self {
+            Status::Pass => "pass",
+            Status::Fail => "fail",
+            Status::Skip => "skip",
+        }
+    }
+}
+
+struct Check {
+    name: String,
+    status: Status,
+    detail: String,
+    hint: Option<&'static str>,
+}
+
+struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    fn pass(&mut self, name: &str, detail: String) {
+        self.checks.push(Check {
+            name: name.to_string(),
+            status: Status::Pass,
+            detail,
+            hint: None,
+        });
+    }
+
+    fn fail(&mut self, name: &str, detail: String, hint: &'static str) {
+        self.checks.push(Check {
+            name: name.to_string(),
+            status: Status::Fail,
+            detail,
+            hint: Some(hint),
+        });
+    }
+
+    fn skip(&mut self, name: &str, detail: &str) {
+        self.checks.push(Check {
+            name: name.to_string(),
+            status: Status::Skip,
+            detail: detail.to_string(),
+            hint: None,
+        });
+    }
+
+    ///
+    /// Records the outcome of a check, returning true if it passed.
+    ///
+    fn record(
+        &mut self,
+        name: &str,
+        result: Result<String>,
+        hint: &'static str,
+    ) -> bool {
+        match result {
+            Ok(detail) => {
+                self.pass(name, detail);
+                true
+            }
+            Err(err) => {
+                self.fail(name, format!("{}", err), hint);
+                false
+            }
+        }
+    }
+
+    fn print(&self, format: OutputFormat) -> Result<()> {
+        let mut columns = vec![
+            Column::new("check", 20),
+            Column::new("status", 6),
+            Column::new("detail", 0),
+        ];
+
+        if format != OutputFormat::Table {
+            columns.push(Column::new("hint", 0));
+        }
+
+        let mut table = Table::new(format, columns);
+
+        for check in &self.checks {
+            let mut row = vec![
+                Cell::from(check.name.as_str()),
+                Cell::from(check.status.name()),
+                Cell::from(check.detail.as_str()),
+            ];
+
+            if format != OutputFormat::Table {
+                row.push(Cell::from(check.hint));
+            }
+
+            table.row(row)?;
+        }
+
+        if format == OutputFormat::Table {
+            let hints: Vec<_> = self
+                .checks
+                .iter()
+                .filter_map(|c| c.hint.map(|hint| (&c.name, hint)))
+                .collect();
+
+            if !hints.is_empty() {
+                println!();
+            }
+
+            for (name, hint) in hints {
+                println!("{}: {}", name, hint);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn failed(&self) -> usize {
+        self.checks.iter().filter(|c| c.status == Status::Fail).count()
+    }
+}
+
+fn target(core: &mut dyn Core) -> Result<String> {
+    let coreinfo = CoreInfo::read(core)?;
+
+    Ok(match coreinfo.manufacturer.get() {
+        Some(manufacturer) => {
+            format!("ARM {} ({})", corename(coreinfo.part), manufacturer)
+        }
+        None => format!("ARM {}", corename(coreinfo.part)),
+    })
+}
+
+fn archive_match(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+) -> Result<String> {
+    hubris.validate(core, HubrisValidate::ArchiveMatch)?;
+
+    Ok(match hubris.image_id() {
+        Some(id) => format!(
+            "image ID {}",
+            id.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+        ),
+        None => "archive matches".to_string(),
+    })
+}
+
+fn booted(hubris: &HubrisArchive, core: &mut dyn Core) -> Result<String> {
+    hubris.validate(core, HubrisValidate::Booted)?;
+
+    let state = KernelState::read(hubris, core)?;
+    let faulted: Vec<_> = state
+        .tasks
+        .iter()
+        .filter(|t| t.is_faulted())
+        .map(|t| t.name.as_str())
+        .collect();
+
+    if faulted.is_empty() {
+        Ok(format!("kernel running, {} tasks, none faulted", state.tasks.len()))
+    } else {
+        bail!("faulted tasks: {}", faulted.join(", "));
+    }
+}
+
+fn hiffy<'a>(
+    hubris: &'a HubrisArchive,
+    core: &mut dyn Core,
+    timeout: u32,
+) -> Result<HiffyContext<'a>> {
+    if hubris.lookup_task("hiffy").is_none() {
+        bail!("no hiffy task in archive");
+    }
+
+    let mut context = HiffyContext::new(hubris, core, timeout)?;
+
+    //
+    // The simplest program that the hiffy task can execute; success means
+    // that the task is running and answering requests.
+    //
+    context.run(core, &[Op::Done], None)?;
+
+    Ok(context)
+}
+
+fn sensors(hubris: &HubrisArchive) -> Result<String> {
+    let sensors = hubris.manifest.sensors.len();
+
+    match hubris.lookup_task("sensor") {
+        Some(_) => Ok(format!("sensor task present, {} sensors", sensors)),
+        None => bail!("{} sensors in archive, but no sensor task", sensors),
+    }
+}
+
+///
+/// Determines if an I2C controller is reachable by reading from a device
+/// that the archive expects on it.  A device not responding is no
+/// indictment of the controller; any other error is.
+///
+fn i2c(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    bus: &HubrisI2cBus,
+) -> Result<Option<String>> {
+    let device = match hubris.manifest.i2c_devices.iter().find(|d| {
+        d.controller == bus.controller
+            && d.port.index == bus.port.index
+            && d.mux.is_none()
+            && !d.removable
+    }) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+
+    let funcs = context.functions()?;
+    let func = funcs.get("I2cRead", 7)?;
+
+    let ops = vec![
+        Op::Push(bus.controller),
+        Op::Push(bus.port.index),
+        Op::PushNone,
+        Op::PushNone,
+        Op::Push(device.address),
+        Op::PushNone,
+        Op::Push(1),
+        Op::Call(func.id),
+        Op::Done,
+    ];
+
+    let results = context.run(core, &ops, None)?;
+
+    match results.first() {
+        Some(Ok(_)) => Ok(Some(format!(
+            "{} at 0x{:02x} responded",
+            device.device, device.address
+        ))),
+        Some(Err(code)) => {
+            let err = func.error(*code);
+
+            if err.kind == HiffyErrorKind::NoDevice {
+                Ok(Some(format!(
+                    "controller reachable; {} at 0x{:02x} did not respond",
+                    device.device, device.address
+                )))
+            } else {
+                Err(err.context(format!("read of {} failed", device.device)))
+            }
+        }
+        None => bail!("no result from read of {}", device.device),
+    }
+}
+
+fn bus_name(bus: &HubrisI2cBus) -> String {
+    match &bus.name {
+        Some(name) => format!("i2c {}", name),
+        None => format!("i2c I2C{}{}", bus.controller, bus.port.name),
+    }
+}
+
+fn doctor(
+    hubris: &mut HubrisArchive,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = DoctorArgs::try_parse_from(subargs)?;
+
+    if args.dump.is_some() {
+        bail!("must be run against a live system");
+    }
+
+    let hubris: &HubrisArchive = hubris;
+    let buses: Vec<_> =
+        hubris.manifest.i2c_buses.iter().filter(|b| !b.target).collect();
+    let mut report = Report { checks: vec![] };
+
+    let loaded = hubris.loaded();
+
+    if loaded {
+        let name = hubris.manifest.name.as_deref().unwrap_or("<unnamed>");
+
+        match &args.archive {
+            Some(archive) => {
+                report.pass("archive", format!("{} ({})", name, archive))
+            }
+            None => report.pass("archive", name.to_string()),
+        }
+    } else {
+        report.fail(
+            "archive",
+            "no archive specified".to_string(),
+            "specify an archive with -a or HUMILITY_ARCHIVE (or a target \
+            with --target)",
+        );
+    }
+
+    let probe = args.probe.as_deref().unwrap_or("auto");
+
+    let mut attached = match humility::core::attach(probe, hubris) {
+        Ok(attached) => {
+            report.pass("probe", attached.description.clone());
+            Some(attached)
+        }
+        Err(err) => {
+            report.fail(
+                "probe",
+                format!("{}", err),
+                "check that the probe is plugged in and not in use by \
+                another debugger; \"humility probe\" lists attached probes",
+            );
+            None
+        }
+    };
+
+    let core = attached.as_mut().map(|a| a.core.as_mut());
+
+    let mut core = match core {
+        Some(core) => {
+            let ok = report.record(
+                "target",
+                target(core),
+                "check that the target is powered and that the probe is \
+                connected to its debug header",
+            );
+
+            if ok {
+                Some(core)
+            } else {
+                None
+            }
+        }
+        None => {
+            report.skip("target", "requires probe");
+            None
+        }
+    };
+
+    let matched = match (core.as_deref_mut(), loaded) {
+        (Some(core), true) => report.record(
+            "archive match",
+            archive_match(hubris, core),
+            "the target is running a different image; specify the archive \
+            for that image or flash this one with \"humility flash\"",
+        ),
+        (None, true) => {
+            report.skip("archive match", "requires target");
+            false
+        }
+        (_, false) => {
+            report.skip("archive match", "requires archive");
+            false
+        }
+    };
+
+    let booted = match core.as_deref_mut() {
+        Some(core) if matched => report.record(
+            "booted",
+            booted(hubris, core),
+            "the kernel has not booted or a task has faulted; see \
+            \"humility tasks\" for details",
+        ),
+        _ => {
+            report.skip("booted", "requires archive match");
+            false
+        }
+    };
+
+    if !booted {
+        report.skip("hiffy", "requires booted target");
+        report.skip("sensors", "requires booted target");
+
+        for bus in &buses {
+            report.skip(&bus_name(bus), "requires booted target");
+        }
+    } else {
+        //
+        // We know that we have a core if the target is booted.
+        //
+        let core = core.unwrap();
+
+        let context = match hiffy(hubris, core, subargs.timeout) {
+            Ok(context) => {
+                report.pass("hiffy", "HIF execution facility answering".into());
+                Some(context)
+            }
+            Err(err) => {
+                report.fail(
+                    "hiffy",
+                    format!("{}", err),
+                    "the hiffy task may be faulted, starved or absent; check \
+                    its state with \"humility tasks hiffy\"",
+                );
+                None
+            }
+        };
+
+        if hubris.manifest.sensors.is_empty() {
+            report.skip("sensors", "no sensors in archive");
+        } else {
+            report.record(
+                "sensors",
+                sensors(hubris),
+                "the archive defines sensors, but has no sensor task to \
+                serve them; check the image's configuration",
+            );
+        }
+
+        match context {
+            Some(mut context) => {
+                for bus in &buses {
+                    let name = bus_name(bus);
+
+                    match i2c(hubris, core, &mut context, bus) {
+                        Ok(Some(detail)) => report.pass(&name, detail),
+                        Ok(None) => {
+                            report.skip(&name, "no devices to read on bus")
+                        }
+                        Err(err) => report.fail(
+                            &name,
+                            format!("{}", err),
+                            "the bus may be locked or the controller \
+                            misconfigured; scan it with \"humility i2c -s\"",
+                        ),
+                    }
+                }
+            }
+            None => {
+                for bus in &buses {
+                    report.skip(&bus_name(bus), "requires hiffy");
+                }
+            }
+        }
+    }
+
+    report.print(args.format)?;
+
+    match report.failed() {
+        0 => Ok(()),
+        1 => bail!("1 check failed"),
+        n => bail!("{} checks failed", n),
+    }
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Unattached {
+            name: "doctor",
+            archive: Archive::Optional,
+            run: doctor,
+        },
+        DoctorArgs::command(),
+    )
+}
@@ -47,8 +47,31 @@
 //!  9 20000558 idle                 0 Healthy(Runnable)          <-
 //! ```
 //!
+//! In addition to all RAM, a dump contains the system registers, the
+//! registers of each task, and the entire Hubris archive (including the
+//! application manifest); a dump therefore describes itself and does not
+//! require the archive to be present.  Device memory (e.g., peripheral
+//! registers) is not dumped by default, as reading it can have side
+//! effects.  To include specific peripherals in the dump, use `-p`
+//! (`--peripheral`), specifying either the name of the peripheral or its
+//! address, optionally followed by a size (which defaults to 1 KiB):
+//!
+//! ```console
+//! % humility dump -p gpioa,i2c4:0x400
+//! humility: attached via ST-Link
+//! humility: core halted
+//! humility: dumping to hubris.core.2
+//! humility: dumped 1.12MB in 24 seconds
+//! humility: core resumed
+//! ```
+//!
+//! To compress the dump with gzip, use `-z` (`--compress`).  Compressed
+//! dumps can be used anywhere an uncompressed dump can be.  To convert a
+//! dump into a core file that can be used with standard tools like GDB, see
+//! `humility elfcore`.
+//!
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::core::Core;
@@ -58,9 +81,50 @@ use humility_cmd::{Archive, Args, Attach, Command, Validate};
 #[derive(Parser, Debug)]
 #[clap(name = "dump", about = env!("CARGO_PKG_DESCRIPTION"))]
 struct DumpArgs {
+    /// peripherals to include in the dump, as a name or an address,
+    /// optionally followed by a size
+    #[clap(
+        long,
+        short,
+        value_name = "peripheral[:size]",
+        use_value_delimiter = true
+    )]
+    peripheral: Vec<String>,
+
+    /// compress the dump
+    #[clap(long = "compress", short = 'z')]
+    compress: bool,
+
     dumpfile: Option<String>,
 }
 
+//
+// The size of memory to dump for a peripheral if no size is specified.
+//
+const PERIPHERAL_SIZE: u32 = 0x400;
+
+fn parse_peripheral(hubris: &HubrisArchive, spec: &str) -> Result<(u32, u32)> {
+    let (name, size) = match spec.split_once(':') {
+        Some((name, size)) => (
+            name,
+            parse_int::parse::<u32>(size)
+                .with_context(|| format!("invalid size in \"{}\"", spec))?,
+        ),
+        None => (spec, PERIPHERAL_SIZE),
+    };
+
+    let base = match parse_int::parse::<u32>(name) {
+        Ok(addr) => addr,
+        Err(_) => hubris.lookup_peripheral(name)?,
+    };
+
+    if size == 0 {
+        bail!("peripheral \"{}\" has zero size", spec);
+    }
+
+    Ok((base, size))
+}
+
 fn dumpcmd(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
@@ -69,10 +133,19 @@ fn dumpcmd(
 ) -> Result<()> {
     let subargs = DumpArgs::try_parse_from(subargs)?;
 
+    let options = HubrisDumpOptions {
+        device: subargs
+            .peripheral
+            .iter()
+            .map(|p| parse_peripheral(hubris, p))
+            .collect::<Result<Vec<_>>>()?,
+        compress: subargs.compress,
+    };
+
     let _info = core.halt()?;
     humility::msg!("core halted");
 
-    let rval = hubris.dump(core, subargs.dumpfile.as_deref());
+    let rval = hubris.dump(core, subargs.dumpfile.as_deref(), &options);
 
     core.run()?;
     humility::msg!("core resumed");
@@ -0,0 +1,333 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility eeprom`
+//!
+//! `humility eeprom` reads, decodes and writes AT24-style I<sup>2</sup>C
+//! EEPROMs, including those that contain FRU ID data.  To list the EEPROMs
+//! in the manifest, use `-l` (`--list`):
+//!
+//! ```console
+//! % humility eeprom -l
+//! humility: attached via ST-Link V3
+//! C P  MUX ADDR DEVICE        SIZE PAGE DESCRIPTION
+//! 2 F  -   0x50 at24csw080    1024   16 Local VPD
+//! ```
+//!
+//! The EEPROM is specified as it would be to `humility i2c` (e.g., by
+//! bus, controller, port and address -- or by device name if it is
+//! unique).  By default, the contents of the EEPROM are dumped:
+//!
+//! ```console
+//! % humility eeprom -d at24csw080
+//! humility: attached via ST-Link V3
+//!              \/  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f
+//! 0x00000000 | 01 00 00 01 00 00 00 fe 01 08 19 d0 4d ce 4f 78 | ............M.Ox
+//! ...
+//! ```
+//!
+//! To decode the contents, use `-D` (`--decode`).  Both the IPMI FRU
+//! information format and Oxide barcode strings are understood:
+//!
+//! ```console
+//! % humility eeprom -d at24csw080 -D
+//! humility: attached via ST-Link V3
+//! IPMI FRU (format version 1):
+//!     board.mfg_date       = 2022-03-01 08:15
+//!     board.manufacturer   = "Oxide"
+//!     board.product        = "Gimlet"
+//!     board.serial         = "BRM42220016"
+//!     board.part           = "913-0000019"
+//!     board.file_id        = ""
+//! ```
+//!
+//! FRU fields can be updated with `-s` (`--set`), naming the field as it is
+//! displayed when decoded; the FRU image is then reencoded (with its area
+//! lengths and checksums updated) and written.  An entire image can
+//! instead be written from a file with `-w` (`--write`), optionally at an
+//! offset specified with `-o` (`--offset`).  Writes are broken into writes
+//! of the EEPROM's page size, and the contents are read back and verified
+//! afterwards.  To see what would be written without writing it, use `-n`
+//! (`--dry-run`):
+//!
+//! ```console
+//! % humility eeprom -d at24csw080 -s board.serial=BRM42220017
+//! humility: attached via ST-Link V3
+//! humility: wrote 72 bytes at offset 0x0 in 5 pages; verified
+//! ```
+//!
+//! The page size and overall size are known for common parts; for other
+//! parts, they must be specified with `--page-size` and `--size`.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::eeprom::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::i2c::I2cArgs;
+use humility_cmd::{Archive, Args, Attach, Command, Dumper, Validate};
+use std::fs;
+
+#[derive(Parser, Debug)]
+#[clap(name = "eeprom", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct EepromArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// list EEPROMs in the manifest
+    #[clap(long, short, conflicts_with_all = &["write", "set", "decode"])]
+    list: bool,
+
+    /// specifies an I2C bus by name
+    #[clap(long, short, value_name = "bus",
+        conflicts_with_all = &["port", "controller"]
+    )]
+    bus: Option<String>,
+
+    /// specifies an I2C controller
+    #[clap(long, short, value_name = "controller",
+        parse(try_from_str = parse_int::parse),
+    )]
+    controller: Option<u8>,
+
+    /// specifies an I2C controller port
+    #[clap(long, short, value_name = "port")]
+    port: Option<String>,
+
+    /// specifies I2C multiplexer and segment
+    #[clap(long, short, value_name = "mux:segment")]
+    mux: Option<String>,
+
+    /// specifies an I2C device address or name
+    #[clap(long, short, value_name = "device")]
+    device: Option<String>,
+
+    /// size of the EEPROM, in bytes
+    #[clap(long, value_name = "bytes",
+        parse(try_from_str = parse_int::parse),
+    )]
+    size: Option<usize>,
+
+    /// page size of the EEPROM, in bytes
+    #[clap(long, value_name = "bytes",
+        parse(try_from_str = parse_int::parse),
+    )]
+    page_size: Option<usize>,
+
+    /// decode the contents of the EEPROM
+    #[clap(long, short = 'D')]
+    decode: bool,
+
+    /// save the contents of the EEPROM to the specified file
+    #[clap(long, short = 'S', value_name = "filename",
+        conflicts_with_all = &["write", "set"]
+    )]
+    save: Option<String>,
+
+    /// write the contents of the specified file to the EEPROM
+    #[clap(long, short, value_name = "filename", conflicts_with = "set")]
+    write: Option<String>,
+
+    /// offset at which to write the file
+    #[clap(long, short, value_name = "offset", requires = "write",
+        parse(try_from_str = parse_int::parse),
+    )]
+    offset: Option<usize>,
+
+    /// set a FRU field, e.g. board.serial=BRM42220017
+    #[clap(long, short, value_name = "field=value")]
+    set: Vec<String>,
+
+    /// show what would be written rather than writing it
+    #[clap(long = "dry-run", short = 'n')]
+    dryrun: bool,
+}
+
+fn list(hubris: &HubrisArchive) {
+    println!(
+        "{:1} {:2} {:3} {:4} {:13} {:>5} {:>4} DESCRIPTION",
+        "C", "P", "MUX", "ADDR", "DEVICE", "SIZE", "PAGE"
+    );
+
+    for device in hubris.manifest.i2c_devices.iter().filter(|d| is_eeprom(d)) {
+        let mux = match (device.mux, device.segment) {
+            (Some(m), Some(s)) => format!("{}:{}", m, s),
+            (None, None) => "-".to_string(),
+            (_, _) => "?:?".to_string(),
+        };
+
+        let (size, page) = match lookup_geometry(&device.device) {
+            Some(g) => (g.size.to_string(), g.page.to_string()),
+            None => ("-".to_string(), "-".to_string()),
+        };
+
+        println!(
+            "{:1} {:2} {:3} 0x{:02x} {:13} {:>5} {:>4} {}",
+            device.controller,
+            device.port.name,
+            mux,
+            device.address,
+            device.device,
+            size,
+            page,
+            device.description
+        );
+    }
+}
+
+fn print_barcode(offset: usize, barcode: &str) {
+    println!("Oxide barcode at offset 0x{:x}:", offset);
+
+    let fields: Vec<&str> = barcode.split(':').collect();
+
+    match fields.as_slice() {
+        [version, part, rev, serial] => {
+            println!("    {:20} = {}", "version", &version[2..]);
+            println!("    {:20} = {}", "part", part);
+            println!("    {:20} = {}", "revision", rev);
+            println!("    {:20} = {}", "serial", serial);
+        }
+        _ => println!("    {:20} = {:?}", "barcode", barcode),
+    }
+}
+
+fn decode(buf: &[u8]) {
+    let mut found = false;
+
+    match Fru::parse(buf) {
+        Ok(fru) => {
+            fru.print();
+            found = true;
+        }
+        Err(err) if buf[0] == 0x01 => {
+            humility::msg!("invalid IPMI FRU: {}", err);
+        }
+        Err(_) => {}
+    }
+
+    for (offset, barcode) in barcodes(buf) {
+        print_barcode(offset, &barcode);
+        found = true;
+    }
+
+    if !found {
+        humility::msg!("contents are not in a recognized format");
+    }
+}
+
+fn eeprom(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = EepromArgs::try_parse_from(subargs)?;
+
+    if subargs.list {
+        list(hubris);
+        return Ok(());
+    }
+
+    let hargs = I2cArgs::parse(
+        hubris,
+        &subargs.bus,
+        subargs.controller,
+        &subargs.port,
+        &subargs.mux,
+        &subargs.device,
+    )?;
+
+    let geometry = geometry(&hargs, subargs.size, subargs.page_size)?;
+
+    let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+    let funcs = context.functions()?;
+    let mut eeprom = Eeprom::new(context, &funcs, &hargs, geometry)?;
+
+    let contents = eeprom.read(core)?;
+
+    let (offset, data) = if let Some(filename) = &subargs.write {
+        let data = fs::read(filename)
+            .with_context(|| format!("failed to read {}", filename))?;
+        (subargs.offset.unwrap_or(0), data)
+    } else if !subargs.set.is_empty() {
+        let mut fru = Fru::parse(&contents)
+            .context("can only set fields on a valid IPMI FRU")?;
+
+        for set in &subargs.set {
+            let (field, value) = set.split_once('=').ok_or_else(|| {
+                anyhow!("expected field=value, found {}", set)
+            })?;
+
+            fru.set(field, value)?;
+        }
+
+        (0, fru.encode()?)
+    } else {
+        if let Some(filename) = &subargs.save {
+            fs::write(filename, &contents)?;
+            humility::msg!("saved {} bytes to {}", contents.len(), filename);
+        } else if subargs.decode {
+            decode(&contents);
+        } else {
+            Dumper::new().dump(&contents, 0);
+        }
+
+        return Ok(());
+    };
+
+    if offset + data.len() > geometry.size {
+        bail!(
+            "write of {} bytes at offset 0x{:x} exceeds EEPROM size of {}",
+            data.len(),
+            offset,
+            geometry.size
+        );
+    }
+
+    if subargs.dryrun {
+        humility::msg!(
+            "would write {} bytes at offset 0x{:x}",
+            data.len(),
+            offset
+        );
+        Dumper::new().dump(&data, offset as u32);
+        return Ok(());
+    }
+
+    if contents[offset..offset + data.len()] == data[..] {
+        humility::msg!("contents are unchanged; not writing");
+        return Ok(());
+    }
+
+    let pages = eeprom.write_verified(core, offset, &data)?;
+
+    humility::msg!(
+        "wrote {} bytes at offset 0x{:x} in {} pages; verified",
+        data.len(),
+        offset,
+        pages
+    );
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "eeprom",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: eeprom,
+        },
+        EepromArgs::command(),
+    )
+}
@@ -0,0 +1,407 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility elfcore`
+//!
+//! Hubris dumps are ELF core files, but the registers that they contain are
+//! recorded in Hubris-specific notes that only Humility understands.
+//! `humility elfcore` converts a dump into a standard ELF core file that can
+//! be consumed by tools like GDB and LLDB.  Each task is presented as a
+//! thread (with a thread ID of one more than its task index, as with
+//! `humility gdb --listen`), with the task that was running at the time of
+//! the dump as the first thread.  The kernel and task ELF objects are
+//! extracted from the archive alongside the core file, along with a GDB
+//! script that loads their symbols and the core:
+//!
+//! ```console
+//! % humility -d hubris.core.4 elfcore
+//! humility: attached to dump
+//! humility: writing core to hubris.core.4.elfcore
+//! humility: wrote 10 threads and 12 segments (1179648 bytes)
+//! humility: symbols extracted to hubris.core.4.elfcore.symbols
+//! humility: to debug, run:
+//! humility:   arm-none-eabi-gdb -q -x hubris.core.4.elfcore.gdb
+//! ```
+//!
+//! The mapping of thread IDs to tasks is displayed with `-v` (`--verbose`)
+//! and is recorded in the GDB script.  Note that GDB only reads the
+//! registers of an ARM core file when the OS ABI is `GNU/Linux`; the
+//! generated script sets this accordingly.  The name of the core file may
+//! be specified with `-o` (`--output`); existing files will not be
+//! overwritten.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use goblin::elf::program_header::{PF_R, PF_W, PF_X, PT_LOAD, PT_NOTE};
+use goblin::elf::Elf;
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::kernel::KernelState;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use scroll::Pwrite;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Parser, Debug)]
+#[clap(name = "elfcore", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct ElfcoreArgs {
+    /// name of the ELF core file to write
+    #[clap(long, short, value_name = "filename")]
+    output: Option<String>,
+
+    /// display the mapping of thread IDs to tasks
+    #[clap(long, short)]
+    verbose: bool,
+}
+
+//
+// Note types and layouts of the Linux ARM core file notes, which are what
+// standard tools expect to find.
+//
+const NT_NAME: &[u8] = b"CORE\0";
+const NT_PRSTATUS: u32 = 1;
+const NT_PRPSINFO: u32 = 3;
+
+const PRSTATUS_SIZE: usize = 148;
+const PRSTATUS_PID: usize = 24;
+const PRSTATUS_REGS: usize = 72;
+
+const PRPSINFO_SIZE: usize = 124;
+const PRPSINFO_PID: usize = 12;
+const PRPSINFO_FNAME: usize = 28;
+const PRPSINFO_PSARGS: usize = 44;
+
+const EF_ARM_EABI_VER5: u32 = 0x0500_0000;
+
+/// Registers in the order in which they appear in `NT_PRSTATUS`
+const PRSTATUS_REGISTERS: [ARMRegister; 17] = [
+    ARMRegister::R0,
+    ARMRegister::R1,
+    ARMRegister::R2,
+    ARMRegister::R3,
+    ARMRegister::R4,
+    ARMRegister::R5,
+    ARMRegister::R6,
+    ARMRegister::R7,
+    ARMRegister::R8,
+    ARMRegister::R9,
+    ARMRegister::R10,
+    ARMRegister::R11,
+    ARMRegister::R12,
+    ARMRegister::SP,
+    ARMRegister::LR,
+    ARMRegister::PC,
+    ARMRegister::PSR,
+];
+
+struct Thread {
+    lwp: u32,
+    name: String,
+    regs: BTreeMap<ARMRegister, u32>,
+}
+
+fn pad(len: usize) -> usize {
+    (4 - (len & 0b11)) & 0b11
+}
+
+fn note(ntype: u32, desc: &[u8]) -> Vec<u8> {
+    let mut rval = vec![];
+
+    rval.extend_from_slice(&(NT_NAME.len() as u32).to_le_bytes());
+    rval.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    rval.extend_from_slice(&ntype.to_le_bytes());
+    rval.extend_from_slice(NT_NAME);
+    rval.resize(rval.len() + pad(NT_NAME.len()), 0);
+    rval.extend_from_slice(desc);
+    rval.resize(rval.len() + pad(desc.len()), 0);
+
+    rval
+}
+
+fn prstatus(thread: &Thread) -> Vec<u8> {
+    let mut desc = vec![0u8; PRSTATUS_SIZE];
+    let pid = PRSTATUS_PID;
+
+    desc[pid..pid + 4].copy_from_slice(&thread.lwp.to_le_bytes());
+
+    for (i, reg) in PRSTATUS_REGISTERS.iter().enumerate() {
+        let val = thread.regs.get(reg).copied().unwrap_or(0);
+        let offs = PRSTATUS_REGS + i * 4;
+        desc[offs..offs + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    note(NT_PRSTATUS, &desc)
+}
+
+fn prpsinfo(lwp: u32, name: &str) -> Vec<u8> {
+    let mut desc = vec![0u8; PRPSINFO_SIZE];
+    let pid = PRPSINFO_PID;
+
+    desc[pid..pid + 4].copy_from_slice(&lwp.to_le_bytes());
+
+    let fname = b"hubris";
+    desc[PRPSINFO_FNAME..PRPSINFO_FNAME + fname.len()].copy_from_slice(fname);
+
+    let psargs = name.as_bytes();
+    let len = psargs.len().min(PRPSINFO_SIZE - PRPSINFO_PSARGS - 1);
+    desc[PRPSINFO_PSARGS..PRPSINFO_PSARGS + len]
+        .copy_from_slice(&psargs[..len]);
+
+    note(NT_PRPSINFO, &desc)
+}
+
+///
+/// Returns a thread for each task whose registers can be determined, with
+/// the task that was running at the time of the dump first.
+///
+fn threads(hubris: &HubrisArchive, core: &mut dyn Core) -> Result<Vec<Thread>> {
+    let current = KernelState::read(hubris, core)?.current;
+    let mut rval = vec![];
+
+    for i in 0..hubris.ntasks() as u32 {
+        let task = HubrisTask::Task(i);
+        let name = hubris.lookup_module(task)?.name.clone();
+
+        match hubris.registers(core, task) {
+            Ok(regs) => rval.push(Thread { lwp: i + 1, name, regs }),
+            Err(e) => {
+                humility::msg!("failed to read registers for {}: {}", name, e);
+            }
+        }
+    }
+
+    if let Some(current) = current {
+        if let Some(pos) = rval.iter().position(|t| t.lwp == current + 1) {
+            let thread = rval.remove(pos);
+            rval.insert(0, thread);
+        }
+    }
+
+    Ok(rval)
+}
+
+///
+/// Writes a GDB script that loads the symbols of the kernel and every task
+/// and then the core, recording the mapping of threads to tasks.
+///
+fn script(
+    hubris: &HubrisArchive,
+    dump: &str,
+    output: &str,
+    symbols: &Path,
+    threads: &[Thread],
+) -> Result<String> {
+    let filename = format!("{}.gdb", output);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&filename)
+        .with_context(|| format!("failed to create {}", filename))?;
+
+    writeln!(file, "#\n# Generated by humility elfcore from {}\n#", dump)?;
+    writeln!(file, "# Threads:\n#")?;
+
+    for thread in threads {
+        writeln!(file, "#   {:>3} {}", thread.lwp, thread.name)?;
+    }
+
+    writeln!(file, "#\nset osabi GNU/Linux")?;
+    writeln!(file, "file {}", symbols.join("kernel").display())?;
+
+    for i in 0..hubris.ntasks() as u32 {
+        let module = hubris.lookup_module(HubrisTask::Task(i))?;
+        let path = symbols.join(&module.name);
+
+        if path.exists() {
+            writeln!(file, "add-symbol-file {}", path.display())?;
+        }
+    }
+
+    writeln!(file, "core-file {}", output)?;
+
+    Ok(filename)
+}
+
+fn elfcore(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = ElfcoreArgs::try_parse_from(subargs)?;
+
+    let dump = match &args.dump {
+        Some(dump) => dump,
+        None => bail!("must be run against a dump"),
+    };
+
+    let output = match &subargs.output {
+        Some(output) => output.clone(),
+        None => format!("{}.elfcore", dump.trim_end_matches(".gz")),
+    };
+
+    let contents = read_dump(dump)?;
+    let elf = Elf::parse(&contents).map_err(|e| {
+        anyhow!("failed to parse {} as an ELF file: {}", dump, e)
+    })?;
+
+    //
+    // Our segments are precisely those of the dump; we mark them according
+    // to the region (if any) that they correspond to.
+    //
+    let regions = hubris.regions(core)?;
+
+    let segs = elf
+        .program_headers
+        .iter()
+        .filter(|phdr| phdr.p_type == PT_LOAD)
+        .map(|phdr| {
+            let base = phdr.p_vaddr as u32;
+            let flags = match regions.get(&base) {
+                Some(region) => {
+                    (if region.attr.read { PF_R } else { 0 })
+                        | (if region.attr.write { PF_W } else { 0 })
+                        | (if region.attr.execute { PF_X } else { 0 })
+                }
+                None => PF_R,
+            };
+
+            let offs = phdr.p_offset as usize;
+            let size = phdr.p_filesz as usize;
+
+            match contents.get(offs..offs + size) {
+                Some(data) => Ok((base, flags, data)),
+                None => bail!("segment at 0x{:x} is truncated", base),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let threads = threads(hubris, core)?;
+
+    if threads.is_empty() {
+        bail!("could not determine the registers of any task");
+    }
+
+    let mut notes = prpsinfo(threads[0].lwp, &threads[0].name);
+
+    for thread in &threads {
+        notes.extend(prstatus(thread));
+    }
+
+    let ctx = goblin::container::Ctx::new(
+        goblin::container::Container::Little,
+        goblin::container::Endian::Little,
+    );
+
+    let mut header = goblin::elf::header::Header::new(ctx);
+    header.e_machine = goblin::elf::header::EM_ARM;
+    header.e_type = goblin::elf::header::ET_CORE;
+    header.e_flags = EF_ARM_EABI_VER5;
+    header.e_phoff = header.e_ehsize as u64;
+    header.e_phnum = (1 + segs.len()) as u16;
+
+    let mut offset =
+        header.e_phoff as u32 + (header.e_phentsize * header.e_phnum) as u32;
+
+    let mut buf = vec![0u8; offset as usize];
+    buf.pwrite_with(header, 0, ctx)?;
+
+    let mut phdrs = vec![goblin::elf32::program_header::ProgramHeader {
+        p_type: PT_NOTE,
+        p_flags: PF_R,
+        p_offset: offset,
+        p_filesz: notes.len() as u32,
+        ..Default::default()
+    }];
+
+    offset += notes.len() as u32;
+
+    for (base, flags, data) in &segs {
+        let size = data.len() as u32;
+
+        phdrs.push(goblin::elf32::program_header::ProgramHeader {
+            p_type: PT_LOAD,
+            p_flags: *flags,
+            p_offset: offset,
+            p_vaddr: *base,
+            p_paddr: *base,
+            p_filesz: size,
+            p_memsz: size,
+            p_align: 4,
+        });
+
+        offset += size + pad(data.len()) as u32;
+    }
+
+    let mut phoff = header.e_phoff as usize;
+
+    for phdr in phdrs {
+        buf.pwrite_with(phdr, phoff, ctx.le)?;
+        phoff += goblin::elf32::program_header::SIZEOF_PHDR;
+    }
+
+    buf.extend(notes);
+
+    let mut total = 0;
+
+    for (_, _, data) in &segs {
+        buf.extend_from_slice(data);
+        buf.resize(buf.len() + pad(data.len()), 0);
+        total += data.len();
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&output)
+        .with_context(|| format!("failed to create {}", output))?;
+
+    humility::msg!("writing core to {}", output);
+    file.write_all(&buf)?;
+
+    humility::msg!(
+        "wrote {} threads and {} segments ({} bytes)",
+        threads.len(),
+        segs.len(),
+        total
+    );
+
+    let symbols = format!("{}.symbols", output);
+    fs::create_dir_all(&symbols)?;
+    hubris.extract_elfs_to(Path::new(&symbols))?;
+    humility::msg!("symbols extracted to {}", symbols);
+
+    let script = script(hubris, dump, &output, Path::new(&symbols), &threads)?;
+
+    if subargs.verbose {
+        humility::msg!("{:>4} TASK", "LWP");
+
+        for thread in &threads {
+            humility::msg!("{:>4} {}", thread.lwp, thread.name);
+        }
+    }
+
+    humility::msg!("to debug, run:");
+    humility::msg!("  arm-none-eabi-gdb -q -x {}", script);
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "elfcore",
+            archive: Archive::Required,
+            attach: Attach::DumpOnly,
+            validate: Validate::Booted,
+            run: elfcore,
+        },
+        ElfcoreArgs::command(),
+    )
+}
@@ -0,0 +1,125 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility eval`
+//!
+//! `humility eval` evaluates an expression over static variables, reading
+//! target memory as needed.  Variables (specified by their name or by their
+//! fully qualified name) may be indexed if arrays (`VAR[3]`), may have
+//! their members accessed if structures (`VAR.member`, or `VAR.0` for tuple
+//! members), and may be dereferenced if pointers (`*VAR`).  Member access
+//! and indexing implicitly dereference pointers, and look through the
+//! active variant of an enum (e.g., an `Option`).  If the expression
+//! denotes a value in memory, the value is displayed by its type along
+//! with its address:
+//!
+//! ```console
+//! % humility eval 'TASK_TABLE_BASE[3].state'
+//! humility: attached via ST-Link V3
+//! TASK_TABLE_BASE[3].state (0x200005a8: kern::task::TaskState) = Healthy(
+//!         InRecv(None)
+//!     )
+//! ```
+//!
+//! Integer values (including pointers) can be combined with integer
+//! literals using the arithmetic and bitwise operators of Rust (`+`, `-`,
+//! `*`, `/`, `%`, `&`, `|`, `^`, `<<`, `>>` and `~` for bitwise negation),
+//! and the address of a value can be taken with `&`.  Structures that wrap
+//! a single value (e.g., `NonNull` or a newtype) are looked through as
+//! needed.  The result of an integer expression is displayed in both hex
+//! and decimal:
+//!
+//! ```console
+//! % humility eval 'CURRENT_TASK_PTR.generation + 1'
+//! humility: attached via ST-Link V3
+//! CURRENT_TASK_PTR.generation + 1 = 0x4 (4)
+//! ```
+//!
+//! A dereferenced integer is treated as the address of a 32-bit word.  By
+//! default, values are displayed in hex; to display them in decimal, use
+//! `-d` (`--decimal`).
+//!
+
+use anyhow::Result;
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::eval::{Eval, Evaluator};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+
+#[derive(Parser, Debug)]
+#[clap(name = "eval", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct EvalArgs {
+    /// values in decimal instead of hex
+    #[clap(long, short)]
+    decimal: bool,
+
+    /// expression to evaluate
+    expression: String,
+}
+
+fn evaluate(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &EvalArgs,
+) -> Result<()> {
+    let expr = &subargs.expression;
+
+    let mut evaluator = Evaluator::new(hubris, core, expr)?;
+
+    match evaluator.evaluate()? {
+        Eval::Int(val) => {
+            println!("{} = 0x{:x} ({})", expr, val, val);
+        }
+        Eval::Place { addr, goff } => {
+            let ty = hubris.lookup_type(goff)?;
+            let buf = evaluator.read(addr, ty.size(hubris)?)?;
+
+            let fmt = HubrisPrintFormat {
+                newline: true,
+                hex: !subargs.decimal,
+                ..HubrisPrintFormat::default()
+            };
+
+            println!(
+                "{} (0x{:08x}: {}) = {}",
+                expr,
+                addr,
+                ty.name(hubris)?,
+                hubris.printfmt(&buf, goff, &fmt)?
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn eval(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = EvalArgs::try_parse_from(subargs)?;
+
+    core.halt()?;
+    let rval = evaluate(hubris, core, &subargs);
+    core.run()?;
+
+    rval
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "eval",
+            archive: Archive::Required,
+            attach: Attach::Any,
+            validate: Validate::Match,
+            run: eval,
+        },
+        EvalArgs::command(),
+    )
+}
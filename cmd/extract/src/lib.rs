@@ -119,9 +119,27 @@
 //!
 //! ```console
 //! % humility -d ./hubris.core.79 extract toml
-//! humility extract failed: "toml" matches multiple files: app.toml, stm32h7.toml
+//! humility extract failed: "toml" matches multiple files: app.toml, stm32h7.toml ("--directory" to extract all)
 //! ```
 //!
+//! The file may also be a glob pattern (that is, containing `*`, `?` or
+//! `[`), in which case it is matched against the entire path of each file
+//! in the archive.  To extract every matching file, specify a directory
+//! with the `--directory` (`-d`) option; each file is extracted to its path
+//! within the archive, relative to that directory:
+//!
+//! ```console
+//! % humility -a /path/to/my/hubris-archive.zip extract -d out 'elf/*'
+//! humility: extracting elf/task/jefe to out/elf/task/jefe
+//! humility: extracting elf/task/sys to out/elf/task/sys
+//! ...
+//! humility: extracting elf/kernel to out/elf/kernel
+//! ```
+//!
+//! A pattern may also be used with `--list` to list only the matching
+//! files.  To extract the ELF object of a particular task, use the `--task`
+//! (`-t`) option, e.g. `humility extract -t ping -o ping.elf`.
+//!
 //! To redirect output to a particular file, use the `--output` (`-o`) option.
 //!
 //! To dump the entire archive, leave the file unspecified.  (Note that
@@ -129,14 +147,15 @@
 //! to prevent accidental blasts of binary content to the console.)
 //!
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::hubris::HubrisArchive;
+use humility_cmd::output::{Column, Table};
 use humility_cmd::{Args, Command};
-use std::fs::File;
-use std::io::Cursor;
-use std::io::{self, Read, Write};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
 
 #[derive(Parser, Debug)]
 #[clap(name = "extract", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -146,90 +165,137 @@ struct ExtractArgs {
     list: bool,
 
     /// file for output
-    #[clap(long, short)]
+    #[clap(long, short, conflicts_with_all = &["list", "directory"])]
     output: Option<String>,
 
-    /// Optional file to extract
+    /// directory into which to extract matching files
+    #[clap(long, short, value_name = "directory", conflicts_with = "list")]
+    directory: Option<String>,
+
+    /// extract the ELF object for the specified task
+    #[clap(long, short, value_name = "task", conflicts_with = "file")]
+    task: Option<String>,
+
+    /// Optional file (or glob pattern) to extract
     file: Option<String>,
 }
 
+//
+// Returns the files in the archive that match the specified file, which is
+// either a glob pattern or a substring.
+//
+fn matching(files: &[(String, u64)], file: &str) -> Result<Vec<(String, u64)>> {
+    let matches = if file.contains(|c| matches!(c, '*' | '?' | '[')) {
+        let pattern = glob::Pattern::new(file)
+            .with_context(|| format!("invalid pattern \"{}\"", file))?;
+
+        files
+            .iter()
+            .filter(|(name, _)| pattern.matches(name))
+            .cloned()
+            .collect()
+    } else {
+        files.iter().filter(|(name, _)| name.contains(file)).cloned().collect()
+    };
+
+    Ok(matches)
+}
+
 fn extract(
     hubris: &mut HubrisArchive,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
-    let archive = hubris.archive();
     let subargs = ExtractArgs::try_parse_from(subargs)?;
+    let files = hubris.archive_files()?;
+
+    let file = match (&subargs.task, &subargs.file) {
+        (Some(task), _) => Some(format!("elf/task/{}", task)),
+        (None, file) => file.clone(),
+    };
 
     if subargs.list {
-        let cursor = Cursor::new(archive);
-        let mut archive = zip::ZipArchive::new(cursor)?;
+        let listed = match &file {
+            Some(file) => matching(&files, file)?,
+            None => files,
+        };
 
-        println!("{:>12} NAME", "SIZE");
+        let mut table = Table::new(
+            args.format,
+            vec![Column::new("size", 12).right(), Column::new("name", 4)],
+        );
 
-        for i in 0..archive.len() {
-            let file = archive.by_index(i)?;
-            println!("{:12} {}", file.size(), file.name());
+        for (name, size) in &listed {
+            table.row(vec![(*size).into(), name.as_str().into()])?;
         }
 
         return Ok(());
     }
 
-    let buffer = if let Some(ref filename) = subargs.file {
-        let cursor = Cursor::new(archive);
-        let mut archive = zip::ZipArchive::new(cursor)?;
-        let mut found = vec![];
-
-        for i in 0..archive.len() {
-            let file = archive.by_index(i)?;
-
-            if file.name().contains(filename) {
-                found.push((i, file.name().to_string()));
+    let filename = match file {
+        Some(filename) => filename,
+        None => {
+            //
+            // As a precaution against naive use, we force an output file to
+            // be specified if the entire archive is to be written.
+            //
+            match subargs.output {
+                Some(output) => fs::write(output, hubris.archive())?,
+                None => bail!(
+                    "must specify output file name to extract entire archive"
+                ),
             }
-        }
 
-        if found.is_empty() {
-            bail!(
-                "\"{}\" doesn't match any files (\"--list\" to list)",
-                filename
-            );
+            return Ok(());
         }
+    };
+
+    let found = matching(&files, &filename)?;
+
+    if found.is_empty() {
+        bail!("\"{}\" doesn't match any files (\"--list\" to list)", filename);
+    }
+
+    if let Some(directory) = &subargs.directory {
+        //
+        // When extracting into a directory, we extract every match, keeping
+        // its path within the archive.
+        //
+        for (name, _) in &found {
+            let target = Path::new(directory).join(name);
+
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
 
-        if found.len() > 1 {
-            bail!(
-                "\"{}\" matches multiple files: {}",
-                filename,
-                found
-                    .iter()
-                    .map(|(_, name)| name.to_string())
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            );
+            humility::msg!("extracting {} to {}", name, target.display());
+            fs::write(&target, hubris.archive_file(name)?)?;
         }
 
-        humility::msg!("extracting {} to stdout", found[0].1);
+        return Ok(());
+    }
 
-        let mut file = archive.by_index(found[0].0)?;
+    if found.len() > 1 {
+        bail!(
+            "\"{}\" matches multiple files: {} \
+            (\"--directory\" to extract all)",
+            filename,
+            found
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
 
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        buffer
-    } else {
-        archive.to_vec()
-    };
+    let name = &found[0].0;
+    let buffer = hubris.archive_file(name)?;
 
     if let Some(output) = subargs.output {
-        let mut ofile = File::create(output)?;
-        ofile.write_all(&buffer)?;
+        humility::msg!("extracting {} to {}", name, output);
+        fs::write(output, &buffer)?;
     } else {
-        //
-        // As a precaution against naive use, we force an output file to be
-        // specified if the entire archive is to be written.
-        //
-        if subargs.file.is_none() {
-            bail!("must specify output file name to extract entire archive");
-        }
-
+        humility::msg!("extracting {} to stdout", name);
         io::stdout().write_all(&buffer)?;
     }
 
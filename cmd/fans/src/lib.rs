@@ -0,0 +1,628 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility fans`
+//!
+//! `humility fans` displays and controls the fans described in the Hubris
+//! application description.  Fans are the speed sensors in the manifest;
+//! their speed (in RPM) is read from the `sensor` task via its `Sensor` Idol
+//! interface, and -- for fan controllers that Humility knows about (currently
+//! the MAX31790) -- their PWM duty cycle is read directly from the
+//! controller.  To list the fans, use `-l` (`--list`):
+//!
+//! ```console
+//! % humility fans --list
+//! humility: attached via ST-Link V3
+//! ID C P  MUX ADDR DEVICE        NAME
+//!  0 4 F  -   0x20 max31790      ESE_fan0
+//!  1 4 F  -   0x20 max31790      ESE_fan1
+//!  2 4 F  -   0x20 max31790      ESE_fan2
+//!  3 4 F  -   0x20 max31790      ESE_fan3
+//! ```
+//!
+//! With no arguments, the speed and duty cycle of each fan is displayed:
+//!
+//! ```console
+//! % humility fans
+//! humility: attached via ST-Link V3
+//! ID NAME                  RPM   PWM
+//!  0 ESE_fan0          4871.23   40%
+//!  1 ESE_fan1          4903.49   40%
+//!  2 ESE_fan2          4829.81   40%
+//!  3 ESE_fan3          4862.60   40%
+//! ```
+//!
+//! To watch the tachometer feedback, use `-w` (`--watch`), optionally with
+//! a number of seconds to watch via `-c` (`--count`).  Fans can be
+//! constrained with `-f` (`--fans`), specifying either fan IDs or names.
+//! A sample that fails while watching is handled as specified by
+//! `--on-error` (as with `humility sensors`); by default, it is retried once.
+//!
+//! To override the duty cycle of one or more fans, specify a percentage
+//! with `-p` (`--pwm`).  This requires the `Thermal` Idol interface; if the
+//! `thermal` task is in automatic mode, it is first put into manual mode.
+//! After the override has been applied, the tachometer feedback is watched
+//! (for `--count` seconds, or until Control-C is pressed) -- after which the
+//! thermal task is returned to automatic mode (or, if it was already in
+//! manual mode, the fans are returned to their prior duty cycles):
+//!
+//! ```console
+//! % humility fans --pwm 80 --fans 0,1
+//! humility: attached via ST-Link V3
+//! humility: thermal task in Auto mode; switching to manual mode
+//! humility: set PWM to 80% on ESE_fan0, ESE_fan1; ^C to restore
+//!     ESE_FAN0     ESE_FAN1     ESE_FAN2     ESE_FAN3
+//!      4871.23      4903.49      4829.81      4862.60
+//!      6312.08      6355.12      4830.44      4861.98
+//!      8107.55      8164.20      4829.02      4862.31
+//!      8511.93      8570.37      4830.77      4862.14
+//! ^Chumility: restoring thermal task to automatic mode
+//! ```
+//!
+//! To leave the override in place when exiting, use `-k` (`--keep`).
+//!
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::idol::{IdolArgument, IdolOperation};
+use humility_cmd::retry::RetryPolicy;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+#[clap(name = "fans", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct FansArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// list all fans
+    #[clap(long, short, conflicts_with_all = &["watch", "pwm"])]
+    list: bool,
+
+    /// restrict to the specified fans, by ID or name
+    #[clap(long, short, value_name = "fan", use_value_delimiter = true)]
+    fans: Option<Vec<String>>,
+
+    /// watch fan speeds, printing them every second
+    #[clap(long, short)]
+    watch: bool,
+
+    /// number of seconds to watch
+    #[clap(
+        long, short, value_name = "seconds",
+        parse(try_from_str = parse_int::parse)
+    )]
+    count: Option<u32>,
+
+    /// override fan duty cycle, as a percentage
+    #[clap(
+        long, short, value_name = "percent",
+        parse(try_from_str = parse_int::parse)
+    )]
+    pwm: Option<u8>,
+
+    /// leave the PWM override in place on exit
+    #[clap(long, short, requires = "pwm")]
+    keep: bool,
+
+    /// when watching, what to do when a sample fails:  fail, retry[:N],
+    /// backoff[:N] or continue
+    #[clap(long, value_name = "policy", default_value = "retry:1")]
+    on_error: String,
+}
+
+struct Fan<'a> {
+    /// index of the fan, as understood by the thermal task
+    index: usize,
+
+    /// index of the corresponding speed sensor
+    sensor: usize,
+
+    name: &'a str,
+    device: &'a HubrisI2cDevice,
+
+    /// PWM channel on the fan controller, if we know how to read it
+    channel: Option<u8>,
+}
+
+//
+// MAX31790 PWMOUT duty cycle registers:  one 16-bit register per channel,
+// with the 9-bit duty cycle left-justified.
+//
+const MAX31790_PWMOUT: u8 = 0x30;
+
+fn all_fans(hubris: &HubrisArchive) -> Vec<Fan> {
+    let mut rval: Vec<Fan> = vec![];
+
+    for (ndx, s) in hubris.manifest.sensors.iter().enumerate() {
+        if s.kind != HubrisSensorKind::Speed {
+            continue;
+        }
+
+        let device = &hubris.manifest.i2c_devices[s.device];
+
+        //
+        // The thermal task indexes its fans in the order of the speed
+        // sensors; within a controller, the channel is the order of the
+        // sensor among that device's speed sensors.
+        //
+        let channel = match device.device.as_str() {
+            "max31790" => Some(
+                rval.iter()
+                    .filter(|f| {
+                        hubris.manifest.sensors[f.sensor].device == s.device
+                    })
+                    .count() as u8,
+            ),
+            _ => None,
+        };
+
+        rval.push(Fan {
+            index: rval.len(),
+            sensor: ndx,
+            name: &s.name,
+            device,
+            channel,
+        });
+    }
+
+    rval
+}
+
+impl<'a> Fan<'a> {
+    fn matches(&self, s: &str) -> bool {
+        self.name == s || parse_int::parse::<usize>(s) == Ok(self.index)
+    }
+}
+
+fn select<'a>(
+    hubris: &'a HubrisArchive,
+    subargs: &FansArgs,
+) -> Result<Vec<Fan<'a>>> {
+    let all = all_fans(hubris);
+
+    if all.is_empty() {
+        bail!("no fans found");
+    }
+
+    let selected = match subargs.fans {
+        Some(ref selected) => selected,
+        None => return Ok(all),
+    };
+
+    for s in selected {
+        if !all.iter().any(|f| f.matches(s)) {
+            bail!("unrecognized fan {} (use \"fans -l\" to list)", s);
+        }
+    }
+
+    Ok(all
+        .into_iter()
+        .filter(|f| selected.iter().any(|s| f.matches(s)))
+        .collect())
+}
+
+fn list(fans: &[Fan]) {
+    println!(
+        "{:2} {:1} {:2} {:3} {:4} {:13} NAME",
+        "ID", "C", "P", "MUX", "ADDR", "DEVICE"
+    );
+
+    for fan in fans {
+        let device = fan.device;
+
+        let mux = match (device.mux, device.segment) {
+            (Some(m), Some(s)) => format!("{}:{}", m, s),
+            (None, None) => "-".to_string(),
+            (_, _) => "?:?".to_string(),
+        };
+
+        println!(
+            "{:2} {:1} {:2} {:3} 0x{:02x} {:13} {}",
+            fan.index,
+            device.controller,
+            device.port.name,
+            mux,
+            device.address,
+            device.device,
+            fan.name,
+        );
+    }
+}
+
+///
+/// Reads the speed and (where possible) the duty cycle of the specified
+/// fans, returning them in the same order.
+///
+fn read(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    fans: &[Fan],
+) -> Result<Vec<(Option<f32>, Option<u8>)>> {
+    let funcs = context.functions()?;
+    let i2c_read = funcs.get("I2cRead", 7)?;
+    let op = IdolOperation::new(hubris, "Sensor", "get", None)
+        .context("is the 'sensor' task present?")?;
+    let mut ops = vec![];
+
+    let mut payload = op.template(&[("id", IdolArgument::Scalar(0))])?;
+
+    for fan in fans {
+        payload.set("id", fan.sensor as u64)?;
+        context.idol_call_ops(&funcs, &op, payload.as_slice(), &mut ops)?;
+    }
+
+    for fan in fans {
+        let channel = match fan.channel {
+            Some(channel) => channel,
+            None => continue,
+        };
+
+        let device = fan.device;
+
+        ops.push(Op::Push(device.controller));
+        ops.push(Op::Push(device.port.index));
+
+        match (device.mux, device.segment) {
+            (Some(mux), Some(segment)) => {
+                ops.push(Op::Push(mux));
+                ops.push(Op::Push(segment));
+            }
+            _ => {
+                ops.push(Op::PushNone);
+                ops.push(Op::PushNone);
+            }
+        }
+
+        ops.push(Op::Push(device.address));
+        ops.push(Op::Push(MAX31790_PWMOUT + channel * 2));
+        ops.push(Op::Push(2));
+        ops.push(Op::Call(i2c_read.id));
+        ops.push(Op::DropN(7));
+    }
+
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+    let mut pwms = results[fans.len()..].iter();
+    let mut rval = vec![];
+
+    for (ndx, fan) in fans.iter().enumerate() {
+        let rpm = match results[ndx] {
+            Ok(ref val) if val.len() >= 4 => {
+                Some(f32::from_le_bytes(val[0..4].try_into()?))
+            }
+            _ => None,
+        };
+
+        let pwm = match fan.channel {
+            Some(_) => match pwms.next() {
+                Some(Ok(val)) if val.len() == 2 => {
+                    let duty =
+                        (u32::from(val[0]) << 1) | (u32::from(val[1]) >> 7);
+                    Some(((duty * 100 + 255) / 511) as u8)
+                }
+                _ => None,
+            },
+            None => None,
+        };
+
+        rval.push((rpm, pwm));
+    }
+
+    Ok(rval)
+}
+
+///
+/// Makes a single call to the `Thermal` interface, returning `None` if the
+/// operation is not present in the archive.
+///
+fn thermal(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    operation: &str,
+    args: &[(&str, IdolArgument)],
+) -> Result<Option<Result<String, String>>> {
+    let op = match IdolOperation::new(hubris, "Thermal", operation, None) {
+        Ok(op) => op,
+        Err(_) => return Ok(None),
+    };
+
+    let funcs = context.functions()?;
+    let payload = op.payload(args)?;
+    let mut ops = vec![];
+
+    context.idol_call_ops(&funcs, &op, &payload, &mut ops)?;
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+
+    Ok(Some(match results.get(0) {
+        Some(Ok(val)) => {
+            let variant = match hubris.lookup_enum(op.ok) {
+                Ok(e) if !val.is_empty() => e.lookup_variant(val[0].into()),
+                _ => None,
+            };
+
+            match variant {
+                Some(variant) => Ok(variant.name.clone()),
+                None => Ok(String::new()),
+            }
+        }
+        Some(Err(e)) => {
+            match op.error.and_then(|error| error.lookup_variant(*e as u64)) {
+                Some(variant) => Err(variant.name.clone()),
+                None => Err(format!("Err(0x{:x})", e)),
+            }
+        }
+        None => bail!("missing result for Thermal.{}", operation),
+    }))
+}
+
+fn set_pwm(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    fan: &Fan,
+    pwm: u8,
+) -> Result<()> {
+    let args = [
+        ("index", IdolArgument::Scalar(fan.index as u64)),
+        ("pwm", IdolArgument::Scalar(pwm as u64)),
+    ];
+
+    match thermal(hubris, core, context, "set_fan_pwm", &args)? {
+        Some(Ok(_)) => Ok(()),
+        Some(Err(err)) => bail!("failed to set PWM on {}: {}", fan.name, err),
+        None => bail!("Thermal interface not found; is 'thermal' present?"),
+    }
+}
+
+fn watch(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    fans: &[Fan],
+    count: Option<u32>,
+    policy: &RetryPolicy,
+    stop: &AtomicBool,
+) -> Result<()> {
+    for fan in fans {
+        print!(" {:>12}", fan.name.to_uppercase());
+    }
+
+    println!();
+
+    let mut seconds = 0;
+
+    while !stop.load(Ordering::SeqCst) {
+        let sample =
+            policy.sample("sample", || read(hubris, core, context, fans))?;
+
+        let sample = match sample {
+            Ok(sample) => sample,
+            Err(err) => {
+                humility::msg!("sample missed: {}", err);
+                vec![(None, None); fans.len()]
+            }
+        };
+
+        for (rpm, _) in sample {
+            match rpm {
+                Some(rpm) => print!(" {:>12.2}", rpm),
+                None => print!(" {:>12}", "-"),
+            }
+        }
+
+        println!();
+        seconds += 1;
+
+        if let Some(count) = count {
+            if seconds >= count {
+                break;
+            }
+        }
+
+        thread::sleep(Duration::from_millis(1000));
+    }
+
+    Ok(())
+}
+
+fn override_pwm(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    subargs: &FansArgs,
+    pwm: u8,
+) -> Result<()> {
+    if pwm > 100 {
+        bail!("PWM must be a percentage between 0 and 100");
+    }
+
+    let policy: RetryPolicy = subargs.on_error.parse()?;
+    let selected = select(hubris, subargs)?;
+    let all = all_fans(hubris);
+
+    //
+    // Before we change anything, record the current duty cycles and the
+    // mode of the thermal task, so we can put things back the way we found
+    // them.
+    //
+    let prior = read(hubris, core, context, &all)?;
+    let mode = thermal(hubris, core, context, "get_mode", &[])?;
+    let auto = matches!(mode, Some(Ok(ref mode)) if mode == "Auto");
+
+    if auto {
+        humility::msg!("thermal task in Auto mode; switching to manual mode");
+
+        let args = [("initial_pwm", IdolArgument::Scalar(pwm as u64))];
+
+        match thermal(hubris, core, context, "set_mode_manual", &args)? {
+            Some(Ok(_)) => {}
+            Some(Err(err)) => bail!("failed to set manual mode: {}", err),
+            None => bail!(
+                "thermal task is in Auto mode but cannot be set to \
+                manual mode"
+            ),
+        }
+
+        //
+        // Entering manual mode sets every fan to the initial PWM; put the
+        // fans that we weren't asked to override back where they were.
+        //
+        for (fan, (_, prior)) in all.iter().zip(prior.iter()) {
+            if selected.iter().any(|f| f.index == fan.index) {
+                continue;
+            }
+
+            if let Some(prior) = prior {
+                set_pwm(hubris, core, context, fan, *prior)?;
+            }
+        }
+    }
+
+    for fan in &selected {
+        set_pwm(hubris, core, context, fan, pwm)?;
+    }
+
+    let names: Vec<&str> = selected.iter().map(|f| f.name).collect();
+
+    if subargs.keep {
+        humility::msg!("set PWM to {}% on {}", pwm, names.join(", "));
+        return Ok(());
+    }
+
+    humility::msg!(
+        "set PWM to {}% on {}; ^C to restore",
+        pwm,
+        names.join(", ")
+    );
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let s = stop.clone();
+
+    ctrlc::set_handler(move || {
+        s.store(true, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    //
+    // Whatever happens while watching, we want to restore the fans.
+    //
+    let watched =
+        watch(hubris, core, context, &all, subargs.count, &policy, &stop);
+
+    if auto {
+        humility::msg!("restoring thermal task to automatic mode");
+
+        match thermal(hubris, core, context, "set_mode_auto", &[])? {
+            Some(Ok(_)) => {}
+            Some(Err(err)) => bail!("failed to restore Auto mode: {}", err),
+            None => bail!("couldn't restore Auto mode"),
+        }
+    } else {
+        humility::msg!("restoring prior PWM settings");
+
+        for fan in &selected {
+            match prior[fan.index].1 {
+                Some(prior) => set_pwm(hubris, core, context, fan, prior)?,
+                None => {
+                    humility::msg!(
+                        "prior PWM on {} is unknown; not restored",
+                        fan.name
+                    );
+                }
+            }
+        }
+    }
+
+    watched
+}
+
+fn fans(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = FansArgs::try_parse_from(subargs)?;
+
+    if subargs.list {
+        list(&select(hubris, &subargs)?);
+        return Ok(());
+    }
+
+    let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+
+    if let Some(pwm) = subargs.pwm {
+        return override_pwm(hubris, core, &mut context, &subargs, pwm);
+    }
+
+    let fans = select(hubris, &subargs)?;
+
+    if subargs.watch {
+        let policy: RetryPolicy = subargs.on_error.parse()?;
+        let stop = AtomicBool::new(false);
+
+        return watch(
+            hubris,
+            core,
+            &mut context,
+            &fans,
+            subargs.count,
+            &policy,
+            &stop,
+        );
+    }
+
+    println!("{:2} {:16} {:>8} {:>5}", "ID", "NAME", "RPM", "PWM");
+
+    for (fan, (rpm, pwm)) in
+        fans.iter().zip(read(hubris, core, &mut context, &fans)?)
+    {
+        println!(
+            "{:2} {:16} {:>8} {:>5}",
+            fan.index,
+            fan.name,
+            match rpm {
+                Some(rpm) => format!("{:.2}", rpm),
+                None => "-".to_string(),
+            },
+            match pwm {
+                Some(pwm) => format!("{}%", pwm),
+                None => "-".to_string(),
+            }
+        );
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "fans",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: fans,
+        },
+        FansArgs::command(),
+    )
+}
@@ -0,0 +1,439 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility fault`
+//!
+//! `humility fault` explains why a task has faulted.  It combines the fault
+//! that Hubris has recorded for the task with the fault status registers of
+//! the system control block (CFSR, HFSR, MMFAR and BFAR), symbolizes the
+//! faulting PC and LR, and offers a probable cause:
+//!
+//! ```console
+//! % humility fault pong
+//! humility: attached via ST-Link
+//!        task: pong (#7, generation 3)
+//!       fault: bus fault (precise: 0x60000000) in task code
+//!          PC: 0x08026e42 task_pong::main+0x32
+//!          LR: 0x08026e17 task_pong::main+0x7
+//!        CFSR: 0x00008200 (bus_addr_valid, bus_precise_data)
+//!        HFSR: 0x00000000
+//!        BFAR: 0x60000000
+//!
+//! precise bus fault on data access to 0x60000000 from task_pong::main
+//!
+//! probable cause: 0x60000000 is not in any memory region; this is likely an
+//! access to an invalid address or to a peripheral that has not been enabled
+//! ```
+//!
+//! Note that the system control block registers reflect the most recent
+//! fault taken by the system, which is not necessarily the fault taken by
+//! the specified task; they are only shown if they are consistent with the
+//! task's fault.  To also display the task's stack backtrace, use `-s`
+//! (`--stack`).
+//!
+
+use anyhow::{anyhow, bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::doppel::{FaultInfo, FaultSource, TaskState};
+use humility_cmd::kernel::{
+    describe_fault, describe_source, KernelState, KernelTask,
+};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::{BFAR, CFSR, HFSR, MMFAR};
+use std::collections::BTreeMap;
+
+#[derive(Parser, Debug)]
+#[clap(name = "fault", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct FaultArgs {
+    /// show stack backtrace
+    #[clap(long, short)]
+    stack: bool,
+
+    /// show line number information with stack backtrace
+    #[clap(long, short, requires = "stack")]
+    line: bool,
+
+    /// task to explain
+    task: String,
+}
+
+struct FaultStatus {
+    cfsr: CFSR,
+    hfsr: HFSR,
+    mmfar: MMFAR,
+    bfar: BFAR,
+}
+
+impl FaultStatus {
+    fn read(core: &mut dyn Core) -> Result<Self> {
+        Ok(Self {
+            cfsr: CFSR::read(core)?,
+            hfsr: HFSR::read(core)?,
+            mmfar: MMFAR::read(core)?,
+            bfar: BFAR::read(core)?,
+        })
+    }
+
+    fn cfsr_flags(&self) -> Vec<&'static str> {
+        let c = &self.cfsr;
+
+        [
+            (c.usage_divide_by_zero(), "usage_divide_by_zero"),
+            (c.usage_unaligned(), "usage_unaligned"),
+            (c.usage_no_coprocessor(), "usage_no_coprocessor"),
+            (c.usage_invalid_pc(), "usage_invalid_pc"),
+            (c.usage_invalid_state(), "usage_invalid_state"),
+            (c.usage_undefined_instr(), "usage_undefined_instr"),
+            (c.bus_addr_valid(), "bus_addr_valid"),
+            (c.bus_lazy_fp(), "bus_lazy_fp"),
+            (c.bus_exception_entry(), "bus_exception_entry"),
+            (c.bus_exception_return(), "bus_exception_return"),
+            (c.bus_imprecise_data(), "bus_imprecise_data"),
+            (c.bus_precise_data(), "bus_precise_data"),
+            (c.bus_instr_prefetch(), "bus_instr_prefetch"),
+            (c.mem_addr_valid(), "mem_addr_valid"),
+            (c.mem_lazy_fp(), "mem_lazy_fp"),
+            (c.mem_exception_entry(), "mem_exception_entry"),
+            (c.mem_exception_return(), "mem_exception_return"),
+            (c.mem_data_access(), "mem_data_access"),
+            (c.mem_instr_access(), "mem_instr_access"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect()
+    }
+
+    ///
+    /// Determines if the status registers are consistent with the specified
+    /// Hubris fault; if they aren't, they likely reflect some other fault.
+    ///
+    fn consistent(&self, fault: FaultInfo) -> bool {
+        let c = &self.cfsr;
+
+        match fault {
+            FaultInfo::MemoryAccess { address, source: FaultSource::User } => {
+                match address {
+                    Some(addr) => {
+                        c.mem_addr_valid() && self.mmfar.address() == addr
+                    }
+                    None => c.mem_data_access() || c.mem_instr_access(),
+                }
+            }
+            FaultInfo::BusError { address, .. } => match address {
+                Some(addr) => c.bus_addr_valid() && self.bfar.address() == addr,
+                None => c.bus_imprecise_data() || c.bus_instr_prefetch(),
+            },
+            FaultInfo::DivideByZero => c.usage_divide_by_zero(),
+            FaultInfo::IllegalInstruction => c.usage_undefined_instr(),
+            FaultInfo::IllegalText => c.mem_instr_access(),
+            FaultInfo::InvalidOperation(cfsr) => u32::from(*c) == cfsr,
+            _ => false,
+        }
+    }
+}
+
+fn symbolize(hubris: &HubrisArchive, addr: u32) -> String {
+    //
+    // The low bit of a code address denotes Thumb state; strip it before
+    // looking up the symbol.
+    //
+    match hubris.instr_sym(addr & !1) {
+        Some((name, base)) if addr & !1 > base => {
+            format!("{}+0x{:x}", name, (addr & !1) - base)
+        }
+        Some((name, _)) => name.to_string(),
+        None => "<unknown>".to_string(),
+    }
+}
+
+fn function(hubris: &HubrisArchive, addr: u32) -> String {
+    match hubris.instr_sym(addr & !1) {
+        Some((name, _)) => name.to_string(),
+        None => format!("0x{:x}", addr),
+    }
+}
+
+///
+/// Offers a probable cause for the fault, based on the fault itself and on
+/// the address (if any) that was being accessed.
+///
+fn probable_cause(
+    hubris: &HubrisArchive,
+    regions: &BTreeMap<u32, HubrisRegion>,
+    task: &KernelTask,
+    fault: FaultInfo,
+) -> String {
+    let region = |addr: u32| {
+        regions.values().find(|r| addr >= r.base && addr < r.base + r.size)
+    };
+
+    let owner = |r: &HubrisRegion| {
+        r.tasks
+            .iter()
+            .map(|t| match hubris.lookup_module(*t) {
+                Ok(m) => m.name.clone(),
+                Err(_) => format!("{:?}", t),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let bad_address = |addr: u32| {
+        if addr < 0x1000 {
+            return format!(
+                "0x{:x} is near zero; this is likely a null pointer \
+                dereference",
+                addr
+            );
+        }
+
+        match region(addr) {
+            Some(r) if !r.tasks.contains(&HubrisTask::Task(task.index)) => {
+                format!(
+                    "0x{:x} is in memory belonging to {}; a task may only \
+                    access its own regions (is a lease or a peripheral \
+                    missing from the task's configuration?)",
+                    addr,
+                    owner(r)
+                )
+            }
+            Some(r) if r.attr.device => format!(
+                "0x{:x} is in a device region; this is likely an access \
+                to a peripheral that has not been enabled (clocked and \
+                brought out of reset)",
+                addr
+            ),
+            Some(_) => format!(
+                "0x{:x} is in one of the task's own regions; this is likely \
+                an access of the wrong size or alignment, or a write to \
+                read-only memory",
+                addr
+            ),
+            None => format!(
+                "0x{:x} is not in any memory region; this is likely an \
+                access to an invalid address or to a peripheral that has \
+                not been enabled",
+                addr
+            ),
+        }
+    };
+
+    match fault {
+        FaultInfo::MemoryAccess { address: Some(addr), .. }
+        | FaultInfo::BusError { address: Some(addr), .. } => {
+            if addr < task.desc.initial_stack
+                && task.desc.initial_stack - addr < 0x10000
+                && region(addr).is_none()
+            {
+                format!(
+                    "0x{:x} is just below the task's stack; this is likely \
+                    a stack overflow (consider increasing the task's \
+                    stacksize)",
+                    addr
+                )
+            } else {
+                bad_address(addr)
+            }
+        }
+        FaultInfo::MemoryAccess { address: None, .. } => {
+            "the faulting address was not recorded; this is likely an \
+            access outside of the task's regions"
+                .to_string()
+        }
+        FaultInfo::BusError { address: None, .. } => {
+            "imprecise bus faults are reported after the faulting \
+            instruction, so the PC is not reliable; this is likely a \
+            buffered write to an invalid address or to a peripheral that \
+            has not been enabled"
+                .to_string()
+        }
+        FaultInfo::StackOverflow { .. } => {
+            "the task has exhausted its stack; consider increasing its \
+            stacksize or reducing its stack usage"
+                .to_string()
+        }
+        FaultInfo::DivideByZero => {
+            "an integer division by zero with divide-by-zero trapping \
+            enabled"
+                .to_string()
+        }
+        FaultInfo::IllegalText | FaultInfo::IllegalInstruction => {
+            "control was transferred to memory that does not contain valid \
+            code; this is likely a corrupted function pointer or return \
+            address (e.g., from a stack corruption)"
+                .to_string()
+        }
+        FaultInfo::InvalidOperation(_) => {
+            "an unexpected processor fault; see the CFSR flags above"
+                .to_string()
+        }
+        FaultInfo::SyscallUsage(_) => {
+            "the kernel rejected the arguments to a syscall; this is likely \
+            a bug in the task or in a library that it uses"
+                .to_string()
+        }
+        FaultInfo::Panic => "the task explicitly panicked".to_string(),
+        FaultInfo::Injected(_) => {
+            "the fault was injected by another task (often the supervisor, \
+            e.g. via \"humility jefe -f\")"
+                .to_string()
+        }
+        FaultInfo::FromServer(..) => {
+            "a server faulted the task for sending it a bad message; this \
+            is likely a mismatch between client and server"
+                .to_string()
+        }
+    }
+}
+
+fn fault(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = FaultArgs::try_parse_from(subargs)?;
+
+    core.halt()?;
+
+    let rval = explain(hubris, core, &subargs);
+
+    core.run()?;
+
+    rval
+}
+
+#[rustfmt::skip::macros(println)]
+fn explain(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &FaultArgs,
+) -> Result<()> {
+    let kernel = KernelState::read(hubris, core)?;
+
+    let task = kernel
+        .lookup_task(&subargs.task)
+        .ok_or_else(|| anyhow!("\"{}\" is not a valid task", subargs.task))?;
+
+    let fault = match task.task.state {
+        TaskState::Faulted { fault, .. } => fault,
+        TaskState::Healthy(state) => {
+            bail!("task {} has not faulted (state is {:?})", task.name, state);
+        }
+    };
+
+    let t = HubrisTask::Task(task.index);
+    let regs = hubris.registers(core, t)?;
+    let regions = hubris.regions(core)?;
+
+    //
+    // The fault status registers are device memory that won't be present
+    // in a dump unless explicitly included, so we don't fail if we can't
+    // read them.
+    //
+    let status = FaultStatus::read(core).ok().filter(|s| s.consistent(fault));
+
+    println!("{:>12}: {} (#{}, generation {})", "task", task.name, task.index,
+        u32::from(task.task.generation));
+    println!("{:>12}: {}", "fault", describe_fault(hubris, core, task, fault));
+
+    let pc = regs.get(&ARMRegister::PC).copied();
+    let lr = regs.get(&ARMRegister::LR).copied();
+
+    for (name, val) in [("PC", pc), ("LR", lr)] {
+        if let Some(val) = val {
+            println!("{:>12}: 0x{:08x} {}", name, val, symbolize(hubris, val));
+        }
+    }
+
+    if let Some(status) = &status {
+        println!("{:>12}: 0x{:08x} ({})", "CFSR", u32::from(status.cfsr),
+            status.cfsr_flags().join(", "));
+        println!("{:>12}: 0x{:08x}", "HFSR", u32::from(status.hfsr));
+
+        if status.cfsr.mem_addr_valid() {
+            println!("{:>12}: 0x{:08x}", "MMFAR", status.mmfar.address());
+        }
+
+        if status.cfsr.bus_addr_valid() {
+            println!("{:>12}: 0x{:08x}", "BFAR", status.bfar.address());
+        }
+    }
+
+    println!();
+
+    let from = match pc {
+        Some(pc) => format!(" from {}", function(hubris, pc)),
+        None => String::new(),
+    };
+
+    match fault {
+        FaultInfo::MemoryAccess { address, source } => {
+            println!("{} memory management fault {} on access to {}{}",
+                if address.is_some() { "precise" } else { "imprecise" },
+                describe_source(source),
+                address.map_or("an unknown address".to_string(),
+                    |a| format!("0x{:x}", a)),
+                from);
+        }
+        FaultInfo::BusError { address, source } => {
+            //
+            // A fault on instruction fetch is precise, but doesn't record
+            // the faulting address in BFAR.
+            //
+            let fetch =
+                status.as_ref().map_or(false, |s| s.cfsr.bus_instr_prefetch());
+            let precise = address.is_some() || fetch;
+
+            println!("{} bus fault {} on {} to {}{}",
+                if precise { "precise" } else { "imprecise" },
+                describe_source(source),
+                if fetch { "instruction fetch" } else { "data access" },
+                address.map_or("an unknown address".to_string(),
+                    |a| format!("0x{:x}", a)),
+                from);
+        }
+        _ => {
+            println!("{}{}", describe_fault(hubris, core, task, fault), from);
+        }
+    }
+
+    println!("\nprobable cause: {}",
+        probable_cause(hubris, &regions, task, fault));
+
+    if subargs.stack {
+        let printer = humility_cmd::stack::StackPrinter {
+            indent: 3,
+            line: subargs.line,
+            additional: false,
+        };
+
+        println!();
+
+        match hubris.stack(core, t, task.desc.initial_stack, &regs) {
+            Ok(stack) => printer.print(hubris, &stack),
+            Err(e) => println!("   stack unwind failed: {:?} ", e),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "fault",
+            archive: Archive::Required,
+            attach: Attach::Any,
+            validate: Validate::Booted,
+            run: fault,
+        },
+        FaultArgs::command(),
+    )
+}
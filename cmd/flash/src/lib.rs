@@ -85,13 +85,8 @@ fn flashcmd(
     // We need to attach to (1) confirm that we're plugged into something
     // and (2) extract serial information.
     //
-    let probe = match &args.probe {
-        Some(p) => p,
-        None => "auto",
-    };
-
     let serial = {
-        let mut c = humility::core::attach(probe, hubris)?;
+        let mut c = humility_cmd::attach_live(args, hubris)?;
         let core = c.as_mut();
 
         //
@@ -0,0 +1,802 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility fpgaflash`
+//!
+//! `humility fpgaflash` reads, verifies and programs the SPI flash parts
+//! from which FPGAs load their configuration, via the SPI task in Hubris.
+//! The flash is named by its SPI device in the application TOML via
+//! `--device` (`-D`); if it isn't specified and exactly one SPI device has
+//! "flash" in its name, that device is used.
+//!
+//! With no other options, the flash part is identified, and the header of
+//! the bitstream that it contains (if any) is decoded.  Both Xilinx
+//! bitstream headers and Lattice bitstream comments are understood:
+//!
+//! ```console
+//! % humility fpgaflash
+//! humility: attached via ST-Link V3
+//! humility: fpga_flash is device 0 of spi3_driver
+//!    flash => Micron (0x20), ID 0x20ba18, 16384 KiB
+//!   offset => 0x0
+//!   format => Xilinx
+//!   design => sidecar_mainboard_controller
+//!  user ID => 0xffffffff
+//!  version => 2021.2
+//!     part => 7a35tcsg324
+//!     date => 2022/03/31
+//!     time => 14:02:57
+//!   length => 2192012 bytes
+//! ```
+//!
+//! To read the contents of the flash to a file, use `--read` (`-r`),
+//! optionally specifying the number of bytes via `--nbytes` (`-n`); to
+//! compare the flash with a file, use `--verify` (`-V`); to program a file
+//! into the flash, use `--program` (`-P`).  Programming erases the 64 KiB
+//! blocks to be written, writes the file, and verifies the result.  Each of
+//! these operates at the offset specified via `--offset` (`-o`), which
+//! defaults to 0:
+//!
+//! ```console
+//! % humility fpgaflash --program ./sidecar_mainboard_controller.bit
+//! humility: attached via ST-Link V3
+//! humility: fpga_flash is device 0 of spi3_driver
+//! humility: file has Xilinx header for sidecar_mainboard_controller
+//! humility: programming 2192124 bytes at offset 0x0
+//! humility: programmed and verified 2192124 bytes in 94 seconds
+//! ```
+//!
+//! Only parts that use 3-byte addresses (that is, parts of 16 MiB or
+//! smaller) are supported.
+//!
+
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::progress::Progress;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cmd_spi::spi_device;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{ArgGroup, CommandFactory, Parser};
+use hif::*;
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "fpgaflash", about = env!("CARGO_PKG_DESCRIPTION"),
+    group = ArgGroup::new("action").multiple(false)
+)]
+struct FpgaflashArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// SPI peripheral on which to operate
+    #[clap(long, short, value_name = "peripheral")]
+    peripheral: Option<u8>,
+
+    /// SPI device (by name or index) of the flash
+    #[clap(long, short = 'D', value_name = "device")]
+    device: Option<String>,
+
+    /// offset in flash at which to operate
+    #[clap(
+        long, short, default_value = "0", value_name = "offset",
+        parse(try_from_str = parse_int::parse)
+    )]
+    offset: u32,
+
+    /// read the flash into the specified file
+    #[clap(long, short, value_name = "filename", group = "action")]
+    read: Option<String>,
+
+    /// number of bytes to read (defaults to the remainder of the flash)
+    #[clap(
+        long, short, value_name = "nbytes", requires = "read",
+        parse(try_from_str = parse_int::parse)
+    )]
+    nbytes: Option<u32>,
+
+    /// compare the flash with the specified file
+    #[clap(long, short = 'V', value_name = "filename", group = "action")]
+    verify: Option<String>,
+
+    /// program the specified file into the flash
+    #[clap(long, short = 'P', value_name = "filename", group = "action")]
+    program: Option<String>,
+}
+
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_READ: u8 = 0x03;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_READ_ID: u8 = 0x9f;
+const CMD_BLOCK_ERASE: u8 = 0xd8;
+
+const STATUS_WIP: u8 = 0x01;
+
+const PAGE_SIZE: u32 = 256;
+const BLOCK_SIZE: u32 = 64 * 1024;
+
+/// Number of bytes we read to find a bitstream header
+const HEADER_SIZE: u32 = 1024;
+
+/// Largest flash that can be addressed with 3-byte addresses
+const MAX_CAPACITY: u32 = 16 * 1024 * 1024;
+
+/// Time we are willing to wait for a block erase or page program
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MANUFACTURERS: &[(u8, &str)] = &[
+    (0x01, "Cypress"),
+    (0x1f, "Adesto"),
+    (0x20, "Micron"),
+    (0x9d, "ISSI"),
+    (0xbf, "Microchip"),
+    (0xc2, "Macronix"),
+    (0xef, "Winbond"),
+];
+
+struct Flash<'a> {
+    context: HiffyContext<'a>,
+    spi_read: HiffyFunction,
+    spi_write: HiffyFunction,
+    task: u32,
+    device: u8,
+    chunk: u32,
+}
+
+impl<'a> Flash<'a> {
+    fn new(
+        hubris: &'a HubrisArchive,
+        core: &mut dyn Core,
+        subargs: &FpgaflashArgs,
+    ) -> Result<Self> {
+        let (task, device, name) = flash_device(hubris, subargs)?;
+
+        let module = &hubris.lookup_module(task)?.name;
+
+        match name {
+            Some(name) => {
+                humility::msg!("{} is device {} of {}", name, device, module)
+            }
+            None => humility::msg!("flash is device {} of {}", device, module),
+        }
+
+        let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+        let funcs = context.functions()?;
+        let spi_read = funcs.get("SpiRead", 4)?.clone();
+        let spi_write = funcs.get("SpiWrite", 3)?.clone();
+
+        if context.data_size() < (4 + PAGE_SIZE) as usize {
+            bail!("HIF data buffer is too small to program a page");
+        }
+
+        //
+        // Our reads return the bytes clocked out while sending the command
+        // and address in addition to the data, and we need to leave room on
+        // the return stack for the result itself.
+        //
+        let chunk = (context.rstack_size() as u32).saturating_sub(64)
+            & !(PAGE_SIZE - 1);
+
+        if chunk == 0 {
+            bail!("HIF return stack is too small to read a page");
+        }
+
+        let task = match task {
+            HubrisTask::Task(task) => task,
+            HubrisTask::Kernel => bail!("SPI task cannot be the kernel"),
+        };
+
+        Ok(Self { context, spi_read, spi_write, task, device, chunk })
+    }
+
+    /// Sends the specified command and returns the `nbytes` that follow it
+    fn exchange(
+        &mut self,
+        core: &mut dyn Core,
+        cmd: &[u8],
+        nbytes: usize,
+    ) -> Result<Vec<u8>> {
+        let ops = [
+            Op::Push32(self.task),
+            Op::Push(self.device),
+            Op::Push32(cmd.len() as u32),
+            Op::Push32((cmd.len() + nbytes) as u32),
+            Op::Call(self.spi_read.id),
+            Op::Done,
+        ];
+
+        let results = self.context.run(core, &ops, Some(cmd))?;
+
+        match &results[0] {
+            Ok(buf) if buf.len() == cmd.len() + nbytes => {
+                Ok(buf[cmd.len()..].to_vec())
+            }
+            Ok(buf) => {
+                bail!(
+                    "short read of command {:#x}: expected {} bytes, \
+                    found {}",
+                    cmd[0],
+                    cmd.len() + nbytes,
+                    buf.len()
+                );
+            }
+            Err(err) => Err(self
+                .spi_read
+                .error(*err)
+                .context(format!("command {:#x} failed", cmd[0]))),
+        }
+    }
+
+    fn send(&mut self, core: &mut dyn Core, cmd: &[u8]) -> Result<()> {
+        let ops = [
+            Op::Push32(self.task),
+            Op::Push(self.device),
+            Op::Push32(cmd.len() as u32),
+            Op::Call(self.spi_write.id),
+            Op::Done,
+        ];
+
+        let results = self.context.run(core, &ops, Some(cmd))?;
+
+        match &results[0] {
+            Ok(_) => Ok(()),
+            Err(err) => Err(self
+                .spi_write
+                .error(*err)
+                .context(format!("command {:#x} failed", cmd[0]))),
+        }
+    }
+
+    fn id(&mut self, core: &mut dyn Core) -> Result<[u8; 3]> {
+        let id = self.exchange(core, &[CMD_READ_ID], 3)?;
+        Ok([id[0], id[1], id[2]])
+    }
+
+    fn wait(&mut self, core: &mut dyn Core, addr: u32) -> Result<()> {
+        let started = Instant::now();
+
+        loop {
+            let status = self.exchange(core, &[CMD_READ_STATUS], 1)?[0];
+
+            if status & STATUS_WIP == 0 {
+                return Ok(());
+            }
+
+            if started.elapsed() > BUSY_TIMEOUT {
+                bail!("timed out waiting for write at {:#x}", addr);
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        core: &mut dyn Core,
+        addr: u32,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        for (ndx, chunk) in buf.chunks_mut(self.chunk as usize).enumerate() {
+            let a = addr + ndx as u32 * self.chunk;
+            let data =
+                self.exchange(core, &command(CMD_READ, a), chunk.len())?;
+            chunk.copy_from_slice(&data);
+        }
+
+        Ok(())
+    }
+
+    fn erase(&mut self, core: &mut dyn Core, addr: u32) -> Result<()> {
+        self.send(core, &[CMD_WRITE_ENABLE])?;
+        self.send(core, &command(CMD_BLOCK_ERASE, addr))?;
+        self.wait(core, addr)
+    }
+
+    fn program(
+        &mut self,
+        core: &mut dyn Core,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut cmd = command(CMD_PAGE_PROGRAM, addr).to_vec();
+        cmd.extend_from_slice(data);
+
+        self.send(core, &[CMD_WRITE_ENABLE])?;
+        self.send(core, &cmd)?;
+        self.wait(core, addr)
+    }
+
+    ///
+    /// Identifies the flash part, returning its capacity.
+    ///
+    fn identify(&mut self, core: &mut dyn Core) -> Result<(String, u32)> {
+        let id = self.id(core)?;
+
+        if id == [0, 0, 0] || id == [0xff, 0xff, 0xff] {
+            bail!("flash did not respond to read ID (found {:x?})", id);
+        }
+
+        let manufacturer = MANUFACTURERS
+            .iter()
+            .find(|(m, _)| *m == id[0])
+            .map_or("unknown manufacturer", |(_, name)| name);
+
+        //
+        // By convention, the last byte of the JEDEC ID is the base-2
+        // logarithm of the capacity.
+        //
+        let capacity = match id[2] {
+            16..=24 => 1u32 << id[2],
+            25..=31 => bail!(
+                "flash ID {:02x}{:02x}{:02x} indicates a part larger \
+                than 16 MiB, which is not supported",
+                id[0],
+                id[1],
+                id[2]
+            ),
+            _ => bail!(
+                "flash ID {:02x}{:02x}{:02x} has unrecognized capacity",
+                id[0],
+                id[1],
+                id[2]
+            ),
+        };
+
+        Ok((
+            format!(
+                "{} ({:#x}), ID {:#08x}, {} KiB",
+                manufacturer,
+                id[0],
+                u32::from_be_bytes([0, id[0], id[1], id[2]]),
+                capacity / 1024
+            ),
+            capacity,
+        ))
+    }
+}
+
+fn command(cmd: u8, addr: u32) -> [u8; 4] {
+    let a = addr.to_be_bytes();
+    [cmd, a[1], a[2], a[3]]
+}
+
+///
+/// Determines the SPI device of the flash:  if it hasn't been specified,
+/// we look for a sole device in the manifest with "flash" in its name.
+///
+fn flash_device<'a>(
+    hubris: &'a HubrisArchive,
+    subargs: &FpgaflashArgs,
+) -> Result<(HubrisTask, u8, Option<&'a str>)> {
+    let devices = &hubris.manifest.spi_devices;
+
+    if let Some(ref device) = subargs.device {
+        let (task, index) =
+            spi_device(hubris, subargs.peripheral, Some(device))?;
+        let name = devices.iter().find(|d| &d.name == device);
+        return Ok((task, index, name.map(|d| d.name.as_str())));
+    }
+
+    if devices.is_empty() {
+        let (task, index) = spi_device(hubris, subargs.peripheral, None)?;
+        return Ok((task, index, None));
+    }
+
+    let flashes = devices
+        .iter()
+        .filter(|d| d.name.to_lowercase().contains("flash"))
+        .filter(|d| subargs.peripheral.map_or(true, |p| p == d.controller))
+        .collect::<Vec<_>>();
+
+    match flashes.len() {
+        1 => {
+            let d = flashes[0];
+            let task = humility_cmd_spi::spi_task(hubris, Some(d.controller))?;
+            Ok((task, d.index, Some(d.name.as_str())))
+        }
+        _ => bail!(
+            "{}; specify the device with -D (devices: {})",
+            if flashes.is_empty() {
+                "no flash device found"
+            } else {
+                "more than one flash device found"
+            },
+            devices
+                .iter()
+                .map(|d| d.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+struct Bitstream {
+    format: &'static str,
+    fields: Vec<(String, String)>,
+}
+
+impl Bitstream {
+    fn design(&self) -> Option<&str> {
+        self.fields.iter().find(|(k, _)| k == "design").map(|(_, v)| v.as_str())
+    }
+
+    fn print(&self) {
+        println!("{:>8} => {}", "format", self.format);
+
+        for (key, value) in &self.fields {
+            println!("{:>8} => {}", key, value);
+        }
+    }
+}
+
+///
+/// Decodes a Xilinx bitstream header:  a fixed preamble followed by fields
+/// for the design, the part, and the date and time of the build, each of
+/// which is a key byte followed by a big-endian length and a NUL-terminated
+/// string -- and then the length of the bitstream that follows.
+///
+fn xilinx(buf: &[u8]) -> Option<Bitstream> {
+    const PREAMBLE: &[u8] = &[
+        0x00, 0x09, 0x0f, 0xf0, 0x0f, 0xf0, 0x0f, 0xf0, 0x0f, 0xf0, 0x00, 0x00,
+        0x01,
+    ];
+
+    if !buf.starts_with(PREAMBLE) {
+        return None;
+    }
+
+    let mut fields = vec![];
+    let mut pos = PREAMBLE.len();
+
+    let string = |bytes: &[u8]| {
+        String::from_utf8_lossy(bytes).trim_end_matches('\0').to_string()
+    };
+
+    loop {
+        let key = *buf.get(pos)?;
+
+        if key == b'e' {
+            let len = buf.get(pos + 1..pos + 5)?;
+            let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]);
+            fields.push(("length".to_string(), format!("{} bytes", len)));
+            break;
+        }
+
+        let len = buf.get(pos + 1..pos + 3)?;
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        let value = string(buf.get(pos + 3..pos + 3 + len)?);
+        pos += 3 + len;
+
+        match key {
+            b'a' => {
+                //
+                // The design name is followed by semicolon-separated
+                // properties like the user ID and tool version.
+                //
+                let mut props = value.split(';');
+                let design = props.next().unwrap_or_default();
+                fields.push(("design".to_string(), design.to_string()));
+
+                for prop in props {
+                    let (k, v) = match prop.split_once('=') {
+                        Some(("UserID", v)) => ("user ID", v.to_lowercase()),
+                        Some(("Version", v)) => ("version", v.to_string()),
+                        Some((k, v)) => (k, v.to_string()),
+                        None => ("property", prop.to_string()),
+                    };
+
+                    fields.push((k.to_string(), v));
+                }
+            }
+            b'b' => fields.push(("part".to_string(), value)),
+            b'c' => fields.push(("date".to_string(), value)),
+            b'd' => fields.push(("time".to_string(), value)),
+            _ => return None,
+        }
+    }
+
+    Some(Bitstream { format: "Xilinx", fields })
+}
+
+///
+/// Decodes a Lattice bitstream comment:  0xff 0x00 followed by
+/// NUL-terminated strings (some of which are of the form "Key: value"),
+/// terminated by 0xff.
+///
+fn lattice(buf: &[u8]) -> Option<Bitstream> {
+    if !buf.starts_with(&[0xff, 0x00]) {
+        return None;
+    }
+
+    let end = buf[2..].iter().position(|&b| b == 0xff)? + 2;
+    let mut fields = vec![];
+
+    for comment in buf[2..end].split(|&b| b == 0) {
+        let comment = String::from_utf8_lossy(comment);
+        let comment = comment.trim();
+
+        if comment.is_empty() {
+            continue;
+        }
+
+        let field = match comment.split_once(": ") {
+            Some((k, v)) if k.eq_ignore_ascii_case("design name") => {
+                ("design".to_string(), v.trim().to_string())
+            }
+            Some((k, v)) => (k.to_lowercase(), v.trim().to_string()),
+            None => ("comment".to_string(), comment.to_string()),
+        };
+
+        fields.push(field);
+    }
+
+    Some(Bitstream { format: "Lattice", fields })
+}
+
+fn bitstream(buf: &[u8]) -> Option<Bitstream> {
+    xilinx(buf).or_else(|| lattice(buf))
+}
+
+fn check_range(offset: u32, len: u32, capacity: u32) -> Result<()> {
+    match offset.checked_add(len) {
+        Some(end) if end <= capacity => Ok(()),
+        _ => bail!(
+            "{} bytes at offset {:#x} exceeds flash capacity of {:#x}",
+            len,
+            offset,
+            capacity
+        ),
+    }
+}
+
+fn read_flash(
+    flash: &mut Flash,
+    core: &mut dyn Core,
+    progress: &Progress,
+    offset: u32,
+    len: u32,
+    what: &str,
+) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len as usize];
+    let mut read = progress.child(what, Some(len as u64), true);
+    let chunk = flash.chunk as usize * 4;
+
+    for (ndx, c) in buf.chunks_mut(chunk).enumerate() {
+        flash.read(core, offset + (ndx * chunk) as u32, c)?;
+        read.inc(c.len() as u64);
+    }
+
+    read.finish();
+    Ok(buf)
+}
+
+///
+/// Compares the flash to the specified contents, returning the number of
+/// bytes that differ and the offset of the first.
+///
+fn compare(
+    flash: &mut Flash,
+    core: &mut dyn Core,
+    progress: &Progress,
+    offset: u32,
+    contents: &[u8],
+) -> Result<Option<(usize, u32)>> {
+    let len = contents.len() as u32;
+    let found = read_flash(flash, core, progress, offset, len, "verifying")?;
+
+    let differ = found
+        .iter()
+        .zip(contents.iter())
+        .enumerate()
+        .filter(|(_, (f, c))| f != c)
+        .map(|(ndx, _)| ndx)
+        .collect::<Vec<_>>();
+
+    Ok(differ.first().map(|first| (differ.len(), offset + *first as u32)))
+}
+
+fn load(filename: &str, offset: u32, capacity: u32) -> Result<Vec<u8>> {
+    let contents = fs::read(filename)
+        .with_context(|| format!("failed to read {}", filename))?;
+
+    if contents.is_empty() {
+        bail!("{} is empty", filename);
+    }
+
+    if contents.len() > MAX_CAPACITY as usize {
+        bail!("{} is larger than 16 MiB", filename);
+    }
+
+    check_range(offset, contents.len() as u32, capacity)?;
+
+    match bitstream(&contents) {
+        Some(b) => humility::msg!(
+            "file has {} header for {}",
+            b.format,
+            b.design().unwrap_or("unnamed design")
+        ),
+        None => humility::msg!("file has no recognized bitstream header"),
+    }
+
+    Ok(contents)
+}
+
+fn fpgaflash(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = FpgaflashArgs::try_parse_from(subargs)?;
+    let mut flash = Flash::new(hubris, core, &subargs)?;
+    let (part, capacity) = flash.identify(core)?;
+    let offset = subargs.offset;
+
+    if let Some(ref filename) = subargs.read {
+        let len = match subargs.nbytes {
+            Some(nbytes) => nbytes,
+            None => capacity.saturating_sub(offset),
+        };
+
+        check_range(offset, len, capacity)?;
+
+        let progress = Progress::new(
+            args,
+            &format!("reading {} bytes at offset {:#x}", len, offset),
+            None,
+        );
+
+        let contents =
+            read_flash(&mut flash, core, &progress, offset, len, "reading")?;
+        progress.finish();
+
+        fs::write(filename, &contents)
+            .with_context(|| format!("failed to write {}", filename))?;
+
+        humility::msg!("read {} bytes into {}", len, filename);
+        return Ok(());
+    }
+
+    if let Some(ref filename) = subargs.verify {
+        let contents = load(filename, offset, capacity)?;
+
+        let progress = Progress::new(
+            args,
+            &format!(
+                "verifying {} bytes at offset {:#x}",
+                contents.len(),
+                offset
+            ),
+            None,
+        );
+
+        let differ = compare(&mut flash, core, &progress, offset, &contents)?;
+        progress.finish();
+
+        match differ {
+            None => {
+                humility::msg!(
+                    "verified {} bytes at offset {:#x}",
+                    contents.len(),
+                    offset
+                );
+                return Ok(());
+            }
+            Some((n, first)) => bail!(
+                "{} byte{} differ{} from {}, starting at {:#x}",
+                n,
+                if n != 1 { "s" } else { "" },
+                if n == 1 { "s" } else { "" },
+                filename,
+                first
+            ),
+        }
+    }
+
+    if let Some(ref filename) = subargs.program {
+        if offset % BLOCK_SIZE != 0 {
+            bail!("offset must be a multiple of {:#x}", BLOCK_SIZE);
+        }
+
+        let contents = load(filename, offset, capacity)?;
+        let len = contents.len() as u32;
+        let started = Instant::now();
+
+        let progress = Progress::new(
+            args,
+            &format!("programming {} bytes at offset {:#x}", len, offset),
+            None,
+        );
+
+        let blocks = (len + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        let mut erase =
+            progress.child("erasing", Some((blocks * BLOCK_SIZE) as u64), true);
+
+        for block in 0..blocks {
+            flash.erase(core, offset + block * BLOCK_SIZE)?;
+            erase.inc(BLOCK_SIZE as u64);
+        }
+
+        erase.finish();
+
+        let mut write = progress.child("writing", Some(len as u64), true);
+
+        for (ndx, page) in contents.chunks(PAGE_SIZE as usize).enumerate() {
+            //
+            // Erased pages are all ones; there is no need to program a page
+            // that would remain so.
+            //
+            if page.iter().any(|&b| b != 0xff) {
+                flash.program(core, offset + ndx as u32 * PAGE_SIZE, page)?;
+            }
+
+            write.inc(page.len() as u64);
+        }
+
+        write.finish();
+
+        let differ = compare(&mut flash, core, &progress, offset, &contents)?;
+        progress.finish();
+
+        if let Some((n, first)) = differ {
+            bail!(
+                "verification failed: {} bytes differ, starting at {:#x}",
+                n,
+                first
+            );
+        }
+
+        humility::msg!(
+            "programmed and verified {} bytes in {} seconds",
+            len,
+            started.elapsed().as_secs()
+        );
+
+        return Ok(());
+    }
+
+    println!("{:>8} => {}", "flash", part);
+    println!("{:>8} => {:#x}", "offset", offset);
+
+    let progress = Progress::new(args, "reading header", None);
+    let header = read_flash(
+        &mut flash,
+        core,
+        &progress,
+        offset,
+        HEADER_SIZE,
+        "reading",
+    )?;
+    progress.finish();
+
+    match bitstream(&header) {
+        Some(bitstream) => bitstream.print(),
+        None if header.iter().all(|&b| b == 0xff) => {
+            println!("{:>8} => none (erased)", "format");
+        }
+        None => println!("{:>8} => unrecognized", "format"),
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "fpgaflash",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: fpgaflash,
+        },
+        FpgaflashArgs::command(),
+    )
+}
@@ -16,13 +16,63 @@
 //! because `humility gdb` connects to it multiple times (once to check the
 //! app id, then again to run the console).
 //!
+//! Alternatively, `humility gdb --listen <port>` attaches to the target via
+//! Humility's own probe support and serves the GDB remote protocol on the
+//! specified port, obviating the need for OpenOCD or pyOCD.  The target is
+//! halted when GDB connects, and the target description and memory map
+//! (with the archive's flash regions marked read-only, so that GDB uses
+//! hardware breakpoints within them) are provided to GDB.  Breakpoints and
+//! watchpoints are implemented with the FPB and DWT, respectively.  The
+//! final ELF is extracted from the archive, and the command to connect to
+//! the server is displayed:
+//!
+//! ```console
+//! % humility gdb --listen 2331
+//! humility: attached via ST-Link V3
+//! humility: listening on port 2331; to connect, run:
+//! humility:   arm-none-eabi-gdb /tmp/.tmpWm0Ejq/final.elf -ex "target remote :2331"
+//! humility: connection from 127.0.0.1:53912
+//! ```
+//!
+//! Each Hubris task is presented to GDB as a thread (with a thread ID of one
+//! more than its task index), with the task that was running when the
+//! target halted as the current thread.  The registers of other tasks are
+//! taken from their saved state, allowing their stacks to be examined with
+//! `info threads`, `thread` and `backtrace`:
+//!
+//! ```console
+//! (gdb) info threads
+//!   Id   Target Id                                  Frame
+//!   1    Thread 1 "jefe (in recv)"                  userlib::sys_recv_stub ()
+//!   2    Thread 2 "net (in recv)"                   userlib::sys_recv_stub ()
+//! ...
+//! * 9    Thread 9 "idle (running)"                  0x08023c3e in main ()
+//! ```
+//!
+//! The registers of tasks other than the current one cannot be modified.
+//! When GDB detaches, any breakpoints and watchpoints that it set are
+//! cleared, the target is resumed, and the server awaits another
+//! connection.
+//!
 
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
 
+use humility::arch::ARMRegister;
+use humility::core::Core;
 use humility::hubris::*;
-use humility_cmd::{Archive, Args, Command as HumilityCmd};
+use humility_cmd::doppel::{SchedState, TaskId, TaskState};
+use humility_cmd::kernel::{describe_fault, KernelState, KernelTask};
+use humility_cmd::{Archive, Args, Attach, Command as HumilityCmd, Validate};
+use humility_cortex::debug::*;
+use humility_cortex::dwt::*;
+use humility_cortex::fpb::*;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::{Command as ClapCommand, CommandFactory, Parser};
 
 #[derive(Parser, Debug)]
@@ -45,6 +95,866 @@ struct GdbArgs {
     /// specifies the probe serial number to use with OpenOCD
     #[clap(long, requires = "run_openocd")]
     serial: Option<String>,
+
+    /// serve the GDB remote protocol on the specified port
+    #[clap(
+        long, value_name = "port", conflicts_with_all = &["load", "run_openocd"]
+    )]
+    listen: Option<u16>,
+}
+
+//
+// The registers that we present to GDB, in the order of their GDB register
+// numbers (and therefore the order in which they appear in the `g` packet).
+//
+const REGISTERS: [(ARMRegister, &str, &str); 19] = [
+    (ARMRegister::R0, "r0", "uint32"),
+    (ARMRegister::R1, "r1", "uint32"),
+    (ARMRegister::R2, "r2", "uint32"),
+    (ARMRegister::R3, "r3", "uint32"),
+    (ARMRegister::R4, "r4", "uint32"),
+    (ARMRegister::R5, "r5", "uint32"),
+    (ARMRegister::R6, "r6", "uint32"),
+    (ARMRegister::R7, "r7", "uint32"),
+    (ARMRegister::R8, "r8", "uint32"),
+    (ARMRegister::R9, "r9", "uint32"),
+    (ARMRegister::R10, "r10", "uint32"),
+    (ARMRegister::R11, "r11", "uint32"),
+    (ARMRegister::R12, "r12", "uint32"),
+    (ARMRegister::SP, "sp", "data_ptr"),
+    (ARMRegister::LR, "lr", "code_ptr"),
+    (ARMRegister::PC, "pc", "code_ptr"),
+    (ARMRegister::PSR, "xpsr", "uint32"),
+    (ARMRegister::MSP, "msp", "data_ptr"),
+    (ARMRegister::PSP, "psp", "data_ptr"),
+];
+
+fn target_xml() -> String {
+    let reg = |(_, name, kind): &(ARMRegister, &str, &str)| {
+        format!(
+            "    <reg name=\"{}\" bitsize=\"32\" type=\"{}\"/>\n",
+            name, kind
+        )
+    };
+
+    let (core, system) = REGISTERS.split_at(17);
+
+    format!(
+        "<?xml version=\"1.0\"?>\n\
+        <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+        <target version=\"1.0\">\n\
+        <architecture>arm</architecture>\n\
+        <feature name=\"org.gnu.gdb.arm.m-profile\">\n{}</feature>\n\
+        <feature name=\"org.gnu.gdb.arm.m-system\">\n{}</feature>\n\
+        </target>\n",
+        core.iter().map(reg).collect::<String>(),
+        system.iter().map(reg).collect::<String>(),
+    )
+}
+
+///
+/// Generates the memory map from the target's regions.  Non-writable
+/// executable regions (i.e., flash) are marked as ROM, which causes GDB to
+/// use hardware breakpoints within them; everything else is marked as RAM,
+/// lest GDB refuse to access memory outside of the map.
+///
+fn memory_map(hubris: &HubrisArchive, core: &mut dyn Core) -> Result<String> {
+    let mut map = String::from(
+        "<?xml version=\"1.0\"?>\n\
+        <!DOCTYPE memory-map PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map \
+        V1.0//EN\" \"http://sourceware.org/gdb/gdb-memory-map.dtd\">\n\
+        <memory-map>\n",
+    );
+
+    let mut next = 0u64;
+
+    let region = |map: &mut String, kind: &str, base: u64, size: u64| {
+        map.push_str(&format!(
+            "  <memory type=\"{}\" start=\"0x{:x}\" length=\"0x{:x}\"/>\n",
+            kind, base, size
+        ));
+    };
+
+    for r in hubris.regions(core)?.values() {
+        if r.attr.write || !r.attr.execute || r.size == 0 {
+            continue;
+        }
+
+        let (base, size) = (r.base as u64, r.size as u64);
+
+        if base < next {
+            continue;
+        }
+
+        if base > next {
+            region(&mut map, "ram", next, base - next);
+        }
+
+        region(&mut map, "rom", base, size);
+        next = base + size;
+    }
+
+    if next < 1 << 32 {
+        region(&mut map, "ram", next, (1 << 32) - next);
+    }
+
+    map.push_str("</memory-map>\n");
+    Ok(map)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        bail!("odd-length hex string");
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Result<u32> {
+    u32::from_str_radix(s, 16).map_err(|_| anyhow!("bad number \"{}\"", s))
+}
+
+///
+/// A packet (or interrupt) received from GDB
+///
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+struct Connection {
+    stream: TcpStream,
+    ack: bool,
+}
+
+impl Connection {
+    fn byte(&mut self) -> Result<Option<u8>> {
+        let mut buf = [0u8; 1];
+
+        match self.stream.read(&mut buf) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(buf[0])),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    ///
+    /// Receives the next packet, returning None if GDB has disconnected.
+    ///
+    fn recv(&mut self) -> Result<Option<Packet>> {
+        loop {
+            match self.byte()? {
+                None => return Ok(None),
+                Some(0x03) => return Ok(Some(Packet::Interrupt)),
+                Some(b'$') => break,
+                Some(_) => continue,
+            }
+        }
+
+        let mut data = vec![];
+
+        loop {
+            match self.byte()? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(b) => data.push(b),
+            }
+        }
+
+        let mut checksum = [0u8; 2];
+
+        for c in checksum.iter_mut() {
+            match self.byte()? {
+                None => return Ok(None),
+                Some(b) => *c = b,
+            }
+        }
+
+        let sum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        let expected = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|c| u8::from_str_radix(c, 16).ok());
+
+        if self.ack {
+            if expected != Some(sum) {
+                self.stream.write_all(b"-")?;
+                return self.recv();
+            }
+
+            self.stream.write_all(b"+")?;
+        }
+
+        Ok(Some(Packet::Command(String::from_utf8_lossy(&data).to_string())))
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, sum);
+
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+
+            if !self.ack {
+                return Ok(());
+            }
+
+            match self.byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    ///
+    /// Checks for an interrupt from GDB without blocking.
+    ///
+    fn interrupted(&mut self) -> Result<bool> {
+        self.stream.set_read_timeout(Some(Duration::from_millis(10)))?;
+
+        let rval = match self.byte() {
+            Ok(Some(0x03)) => Ok(true),
+            Ok(None) => Err(anyhow!("connection closed")),
+            Ok(Some(_)) => Ok(false),
+            Err(e) => match e.downcast_ref::<std::io::Error>() {
+                Some(e)
+                    if e.kind() == ErrorKind::WouldBlock
+                        || e.kind() == ErrorKind::TimedOut =>
+                {
+                    Ok(false)
+                }
+                _ => Err(e),
+            },
+        };
+
+        self.stream.set_read_timeout(None)?;
+        rval
+    }
+}
+
+///
+/// Escapes the binary data in a `qXfer` response.
+///
+fn escape(data: &str) -> String {
+    let mut rval = String::new();
+
+    for c in data.chars() {
+        match c {
+            '#' | '$' | '}' | '*' => {
+                rval.push('}');
+                rval.push((c as u8 ^ 0x20) as char);
+            }
+            _ => rval.push(c),
+        }
+    }
+
+    rval
+}
+
+///
+/// Responds to a `qXfer` read of the specified object, given the offset and
+/// length requested.
+///
+fn xfer(object: &str, args: &str) -> Result<String> {
+    let (offset, length) = match args.split_once(',') {
+        Some((o, l)) => (parse_hex(o)? as usize, parse_hex(l)? as usize),
+        None => bail!("bad qXfer arguments"),
+    };
+
+    if offset >= object.len() {
+        return Ok("l".to_string());
+    }
+
+    let end = (offset + length).min(object.len());
+    let prefix = if end == object.len() { "l" } else { "m" };
+
+    Ok(format!("{}{}", prefix, escape(&object[offset..end])))
+}
+
+/// A breakpoint or watchpoint that GDB has set
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Point {
+    Break(u32),
+    Watch(u32),
+}
+
+struct Server<'a> {
+    hubris: &'a HubrisArchive,
+    target: String,
+    memory: String,
+    points: Vec<(Point, u32)>,
+
+    /// The kernel state, as read when the target last halted
+    kernel: Option<KernelState>,
+
+    /// The task selected by GDB for register operations, if not current
+    thread: Option<u32>,
+}
+
+//
+// GDB thread IDs must be positive, so a task's thread ID is its index plus
+// one.
+//
+fn thread_id(ndx: u32) -> u32 {
+    ndx + 1
+}
+
+fn describe(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    task: &KernelTask,
+    current: bool,
+) -> String {
+    let name = |id: TaskId| {
+        hubris.task_name(id.index() as usize).unwrap_or("?").to_string()
+    };
+
+    match task.task.state {
+        TaskState::Faulted { fault, .. } => {
+            format!("faulted: {}", describe_fault(hubris, core, task, fault))
+        }
+        TaskState::Healthy(SchedState::Stopped) => "stopped".to_string(),
+        TaskState::Healthy(SchedState::Runnable) if current => {
+            "running".to_string()
+        }
+        TaskState::Healthy(SchedState::Runnable) => "ready".to_string(),
+        TaskState::Healthy(SchedState::InSend(id)) => {
+            format!("in send to {}", name(id))
+        }
+        TaskState::Healthy(SchedState::InReply(id)) => {
+            format!("in reply from {}", name(id))
+        }
+        TaskState::Healthy(SchedState::InRecv(None)) => "in recv".to_string(),
+        TaskState::Healthy(SchedState::InRecv(Some(id))) => {
+            format!("in recv from {}", name(id))
+        }
+    }
+}
+
+impl<'a> Server<'a> {
+    ///
+    /// Reads the kernel state after the target has halted.  If this fails
+    /// (e.g., because the kernel has not yet booted), tasks will not be
+    /// presented as threads.
+    ///
+    fn refresh(&mut self, core: &mut dyn Core) {
+        self.thread = None;
+        self.kernel = match KernelState::read(self.hubris, core) {
+            Ok(kernel) => Some(kernel),
+            Err(e) => {
+                log::warn!("failed to read kernel state: {:?}", e);
+                None
+            }
+        };
+    }
+
+    fn current(&self) -> Option<u32> {
+        self.kernel.as_ref().and_then(|k| k.current)
+    }
+
+    ///
+    /// Returns the stop reply for the specified signal, indicating the
+    /// current task as the thread that stopped.
+    ///
+    fn reply(&self, signal: u8, extra: &str) -> String {
+        match self.current() {
+            Some(ndx) => {
+                format!("T{:02x}{}thread:{:x};", signal, extra, thread_id(ndx))
+            }
+            None if extra.is_empty() => format!("S{:02x}", signal),
+            None => format!("T{:02x}{}", signal, extra),
+        }
+    }
+
+    fn task(&self, id: &str) -> Result<u32> {
+        let id = parse_hex(id)?;
+
+        match &self.kernel {
+            Some(kernel) if id > 0 && id <= kernel.tasks.len() as u32 => {
+                Ok(id - 1)
+            }
+            _ => bail!("no such thread {:x}", id),
+        }
+    }
+
+    ///
+    /// Returns the saved registers of the selected task, or None if the
+    /// registers should be read from the core (that is, if the selected
+    /// task is the current one).
+    ///
+    fn saved(
+        &self,
+        core: &mut dyn Core,
+    ) -> Result<Option<BTreeMap<ARMRegister, u32>>> {
+        match self.thread {
+            Some(ndx) if Some(ndx) != self.current() => {
+                Ok(Some(self.hubris.registers(core, HubrisTask::Task(ndx))?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn read_register(
+        &self,
+        core: &mut dyn Core,
+        saved: &Option<BTreeMap<ARMRegister, u32>>,
+        reg: ARMRegister,
+    ) -> String {
+        let val = match saved {
+            Some(regs) => regs.get(&reg).copied(),
+            None => core.read_reg(reg).ok(),
+        };
+
+        match val {
+            Some(val) => hex(&val.to_le_bytes()),
+            None => "xxxxxxxx".to_string(),
+        }
+    }
+
+    fn read_registers(&self, core: &mut dyn Core) -> Result<String> {
+        let saved = self.saved(core)?;
+
+        Ok(REGISTERS
+            .iter()
+            .map(|(reg, _, _)| self.read_register(core, &saved, *reg))
+            .collect())
+    }
+
+    fn writable(&self) -> Result<()> {
+        match self.thread {
+            Some(ndx) if Some(ndx) != self.current() => {
+                bail!("cannot write registers of a task that isn't running")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn write_registers(&self, core: &mut dyn Core, data: &str) -> Result<()> {
+        self.writable()?;
+        let bytes = unhex(data)?;
+
+        for ((reg, _, _), val) in REGISTERS.iter().zip(bytes.chunks_exact(4)) {
+            core.write_reg(*reg, u32::from_le_bytes(val.try_into()?))?;
+        }
+
+        Ok(())
+    }
+
+    fn register(ndx: &str) -> Result<ARMRegister> {
+        match REGISTERS.get(parse_hex(ndx)? as usize) {
+            Some((reg, _, _)) => Ok(*reg),
+            None => bail!("bad register {}", ndx),
+        }
+    }
+
+    fn read_memory(&self, core: &mut dyn Core, args: &str) -> Result<String> {
+        let (addr, len) = match args.split_once(',') {
+            Some((a, l)) => (parse_hex(a)?, parse_hex(l)?),
+            None => bail!("bad memory read"),
+        };
+
+        let mut buf = vec![0u8; len as usize];
+        core.read_8(addr, &mut buf)?;
+
+        Ok(hex(&buf))
+    }
+
+    fn write_memory(&self, core: &mut dyn Core, args: &str) -> Result<()> {
+        let (addr, data) = match args.split_once(':') {
+            Some((spec, data)) => match spec.split_once(',') {
+                Some((a, _)) => (parse_hex(a)?, unhex(data)?),
+                None => bail!("bad memory write"),
+            },
+            None => bail!("bad memory write"),
+        };
+
+        core.write_8(addr, &data)
+    }
+
+    ///
+    /// Sets or clears a breakpoint (types 0 and 1) or watchpoint (types 2
+    /// through 4) as specified by a `Z` or `z` packet.
+    ///
+    fn point(
+        &mut self,
+        core: &mut dyn Core,
+        args: &str,
+        set: bool,
+    ) -> Result<()> {
+        let fields = args.split(',').collect::<Vec<_>>();
+
+        if fields.len() < 3 {
+            bail!("bad breakpoint specification");
+        }
+
+        let addr = parse_hex(fields[1])?;
+        let kind = parse_hex(fields[2])?;
+
+        let point = match fields[0] {
+            "0" | "1" => Point::Break(addr),
+            "2" | "3" | "4" => Point::Watch(addr),
+            _ => bail!("unsupported breakpoint type {}", fields[0]),
+        };
+
+        if !set {
+            if let Some(pos) = self.points.iter().position(|(p, _)| *p == point)
+            {
+                let (point, ndx) = self.points.remove(pos);
+
+                match point {
+                    Point::Break(_) => fpb_clear(core, ndx)?,
+                    Point::Watch(_) => dwt_watch_clear(core, ndx)?,
+                }
+            }
+
+            return Ok(());
+        }
+
+        let kind_of = std::mem::discriminant(&point);
+
+        let used = self
+            .points
+            .iter()
+            .filter(|(p, _)| std::mem::discriminant(p) == kind_of)
+            .map(|(_, ndx)| *ndx)
+            .collect::<Vec<_>>();
+
+        match point {
+            Point::Break(_) => {
+                let ndx = (0..FP_CTRL::read(core)?.num_code())
+                    .find(|ndx| !used.contains(ndx))
+                    .ok_or_else(|| anyhow!("out of breakpoints"))?;
+
+                fpb_set(core, ndx, addr)?;
+                self.points.push((point, ndx));
+            }
+            Point::Watch(_) => {
+                let ndx = (0..DWT_CTRL::read(core)?.num_comparators())
+                    .find(|ndx| !used.contains(ndx))
+                    .ok_or_else(|| anyhow!("out of watchpoints"))?;
+
+                let access = match fields[0] {
+                    "2" => DWTAccess::Write,
+                    "3" => DWTAccess::Read,
+                    _ => DWTAccess::ReadWrite,
+                };
+
+                dwt_watch_set(core, ndx, addr, kind, access)?;
+                self.points.push((point, ndx));
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Clears any breakpoints and watchpoints that GDB left set.
+    ///
+    fn cleanup(&mut self, core: &mut dyn Core) -> Result<()> {
+        for (point, ndx) in self.points.drain(..) {
+            match point {
+                Point::Break(_) => fpb_clear(core, ndx)?,
+                Point::Watch(_) => dwt_watch_clear(core, ndx)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Returns the stop reply for a halted target.
+    ///
+    fn stopped(&mut self, core: &mut dyn Core) -> Result<String> {
+        let dfsr = DFSR::read(core)?;
+
+        //
+        // The DFSR bits are write-one-to-clear; clear the ones we've seen.
+        //
+        dfsr.write(core)?;
+        self.refresh(core);
+
+        if dfsr.watchpoint() {
+            for (point, ndx) in &self.points {
+                if let Point::Watch(_) = point {
+                    if let Some(w) = dwt_watch_read(core, *ndx)? {
+                        if w.matched {
+                            let kind = match w.access {
+                                DWTAccess::Write => "watch",
+                                DWTAccess::Read => "rwatch",
+                                DWTAccess::ReadWrite => "awatch",
+                            };
+
+                            let extra = format!("{}:{:x};", kind, w.addr);
+                            return Ok(self.reply(5, &extra));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(self.reply(5, ""))
+    }
+
+    ///
+    /// Resumes the target and waits for it to halt (or for GDB to
+    /// interrupt it).
+    ///
+    fn resume(
+        &mut self,
+        conn: &mut Connection,
+        core: &mut dyn Core,
+    ) -> Result<String> {
+        core.run()?;
+        let halted = Self::wait(conn, core);
+        core.halt()?;
+
+        if halted? {
+            self.stopped(core)
+        } else {
+            self.refresh(core);
+            Ok(self.reply(2, ""))
+        }
+    }
+
+    ///
+    /// Waits for the target to halt, returning false if GDB interrupted it.
+    ///
+    fn wait(conn: &mut Connection, core: &mut dyn Core) -> Result<bool> {
+        loop {
+            if DHCSR::read(core)?.halted() {
+                return Ok(true);
+            }
+
+            if conn.interrupted()? {
+                return Ok(false);
+            }
+        }
+    }
+
+    fn handle(
+        &mut self,
+        conn: &mut Connection,
+        core: &mut dyn Core,
+        cmd: &str,
+    ) -> Result<Option<String>> {
+        let rval = if cmd == "?" {
+            self.reply(5, "")
+        } else if cmd.starts_with("qSupported") {
+            "PacketSize=4000;qXfer:features:read+;qXfer:memory-map:read+;\
+            QStartNoAckMode+"
+                .to_string()
+        } else if cmd == "QStartNoAckMode" {
+            conn.send("OK")?;
+            conn.ack = false;
+            return Ok(None);
+        } else if let Some(args) =
+            cmd.strip_prefix("qXfer:features:read:target.xml:")
+        {
+            xfer(&self.target, args)?
+        } else if let Some(args) = cmd.strip_prefix("qXfer:memory-map:read::") {
+            xfer(&self.memory, args)?
+        } else if cmd == "qAttached" {
+            "1".to_string()
+        } else if cmd == "g" {
+            self.read_registers(core)?
+        } else if let Some(data) = cmd.strip_prefix('G') {
+            self.write_registers(core, data)?;
+            "OK".to_string()
+        } else if let Some(ndx) = cmd.strip_prefix('p') {
+            let saved = self.saved(core)?;
+            self.read_register(core, &saved, Self::register(ndx)?)
+        } else if let Some(args) = cmd.strip_prefix('P') {
+            self.writable()?;
+
+            match args.split_once('=') {
+                Some((ndx, val)) => {
+                    let bytes = unhex(val)?;
+                    let val = u32::from_le_bytes(bytes[..].try_into()?);
+                    core.write_reg(Self::register(ndx)?, val)?;
+                    "OK".to_string()
+                }
+                None => bail!("bad register write"),
+            }
+        } else if let Some(args) = cmd.strip_prefix('m') {
+            self.read_memory(core, args)?
+        } else if let Some(args) = cmd.strip_prefix('M') {
+            self.write_memory(core, args)?;
+            "OK".to_string()
+        } else if let Some(args) = cmd.strip_prefix('Z') {
+            self.point(core, args, true)?;
+            "OK".to_string()
+        } else if let Some(args) = cmd.strip_prefix('z') {
+            self.point(core, args, false)?;
+            "OK".to_string()
+        } else if cmd.starts_with('c') {
+            self.resume(conn, core)?
+        } else if cmd.starts_with('s') {
+            core.step()?;
+            self.stopped(core)?
+        } else if let Some(id) = cmd.strip_prefix("Hg") {
+            self.thread = match id {
+                "0" | "-1" => None,
+                id => Some(self.task(id)?),
+            };
+
+            "OK".to_string()
+        } else if cmd.starts_with('H') {
+            "OK".to_string()
+        } else if cmd == "qfThreadInfo" {
+            match &self.kernel {
+                Some(kernel) => format!(
+                    "m{}",
+                    kernel
+                        .tasks
+                        .iter()
+                        .map(|t| format!("{:x}", thread_id(t.index)))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+                None => "l".to_string(),
+            }
+        } else if cmd == "qsThreadInfo" {
+            "l".to_string()
+        } else if cmd == "qC" {
+            match self.current() {
+                Some(ndx) => format!("QC{:x}", thread_id(ndx)),
+                None => String::new(),
+            }
+        } else if let Some(id) = cmd.strip_prefix('T') {
+            self.task(id)?;
+            "OK".to_string()
+        } else if let Some(id) = cmd.strip_prefix("qThreadExtraInfo,") {
+            let ndx = self.task(id)?;
+            let current = self.current() == Some(ndx);
+            let kernel = self.kernel.as_ref().unwrap();
+            let task = &kernel.tasks[ndx as usize];
+
+            let info = format!(
+                "{} ({})",
+                task.name,
+                describe(self.hubris, core, task, current)
+            );
+
+            hex(info.as_bytes())
+        } else {
+            //
+            // An empty response indicates an unsupported packet.
+            //
+            String::new()
+        };
+
+        Ok(Some(rval))
+    }
+
+    fn serve(
+        &mut self,
+        conn: &mut Connection,
+        core: &mut dyn Core,
+    ) -> Result<()> {
+        loop {
+            let cmd = match conn.recv()? {
+                Some(Packet::Command(cmd)) => cmd,
+                Some(Packet::Interrupt) => {
+                    conn.send(&self.reply(2, ""))?;
+                    continue;
+                }
+                None => return Ok(()),
+            };
+
+            if cmd.starts_with('D') {
+                conn.send("OK")?;
+                return Ok(());
+            }
+
+            if cmd == "k" {
+                return Ok(());
+            }
+
+            let reply = match self.handle(conn, core, &cmd) {
+                Ok(reply) => reply,
+                Err(e) => {
+                    log::warn!("{}: {:?}", cmd, e);
+                    Some("E01".to_string())
+                }
+            };
+
+            if let Some(reply) = reply {
+                conn.send(&reply)?;
+            }
+        }
+    }
+}
+
+fn listen(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    port: u16,
+) -> Result<()> {
+    let work_dir = tempfile::tempdir()?;
+    let elf = work_dir.path().join("final.elf");
+    hubris.extract_file_to("img/final.elf", &elf)?;
+
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .with_context(|| format!("failed to listen on port {}", port))?;
+
+    humility::msg!("listening on port {}; to connect, run:", port);
+    humility::msg!(
+        "  arm-none-eabi-gdb {} -ex \"target remote :{}\"",
+        elf.display(),
+        port
+    );
+
+    //
+    // Breakpoints are only taken with the FPB enabled, and watchpoints with
+    // tracing enabled.
+    //
+    fpb_enable(core)?;
+
+    let mut demcr = DEMCR::read(core)?;
+    demcr.set_trcena(true);
+    demcr.write(core)?;
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        humility::msg!("connection from {}", stream.peer_addr()?);
+
+        core.halt()?;
+
+        let mut server = Server {
+            hubris,
+            kernel: None,
+            thread: None,
+            target: target_xml(),
+            memory: memory_map(hubris, core)?,
+            points: vec![],
+        };
+
+        server.refresh(core);
+
+        let mut conn = Connection { stream, ack: true };
+        let rval = server.serve(&mut conn, core);
+
+        server.cleanup(core)?;
+        core.run()?;
+
+        match rval {
+            Ok(_) => humility::msg!("connection closed"),
+            Err(e) => humility::msg!("connection failed: {}", e),
+        }
+
+        //
+        // Give the target a moment before accepting another connection.
+        //
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    Ok(())
 }
 
 fn gdb(
@@ -52,12 +962,22 @@ fn gdb(
     args: &Args,
     subargs: &[String],
 ) -> Result<()> {
+    let subargs = GdbArgs::try_parse_from(subargs)?;
+
+    if let Some(port) = subargs.listen {
+        return humility_cmd::attach(
+            hubris,
+            args,
+            Attach::LiveOnly,
+            Validate::Match,
+            |hubris, core| listen(hubris, core, port),
+        );
+    }
+
     if args.probe.is_some() {
         bail!("Cannot specify --probe with `gdb` subcommand");
     }
 
-    let subargs = GdbArgs::try_parse_from(subargs)?;
-
     let work_dir = tempfile::tempdir()?;
     let name = match &hubris.manifest.name {
         Some(name) => name,
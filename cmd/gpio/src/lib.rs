@@ -91,6 +91,42 @@
 //! % humility gpio -c Output:PushPull:High:None:AF0 -p A:5
 //! ```
 //!
+//! Alternatively, only the fields of interest need be specified, in any
+//! order; unspecified fields default to `PushPull`, `Low`, `None` and `AF0`.
+//! If an alternate function is specified without a mode, the mode is
+//! assumed to be `Alternate`.  For example, to configure pin 14 on port B
+//! as an input with a pull-up:
+//!
+//! ```console
+//! % humility gpio -c Input:Up -p B:14
+//! humility: attached via ST-Link V3
+//! [Ok([])]
+//! ```
+//!
+//! ### Named pins
+//!
+//! If the archive defines a pin map (in the `[config.gpio.pins]` section
+//! of the application TOML), pins may be specified by name rather than by
+//! port and pin number -- and inputs will be displayed with their names.
+//! To list the named pins, use `--list` (`-l`):
+//!
+//! ```console
+//! % humility gpio --list
+//! humility: attached via ST-Link V3
+//!   PIN NAME                 DESCRIPTION
+//!  B:14 SP_PRSNT_L           Sled presence detect (active low)
+//!   E:1 STRAP_BOOT0          Boot strap
+//!  G:12 FAN_PWR_EN           Fan power enable
+//! % humility gpio --input --pins SP_PRSNT_L,B:0,STRAP_BOOT0
+//! humility: attached via ST-Link V3
+//! B:14 = 0   SP_PRSNT_L
+//! B:0  = 1
+//! E:1  = 0   STRAP_BOOT0
+//! % humility gpio --set --pins FAN_PWR_EN
+//! humility: attached via ST-Link V3
+//! [Ok([])]
+//! ```
+//!
 
 use humility::core::Core;
 use humility::hubris::*;
@@ -146,9 +182,126 @@ struct GpioArgs {
     #[clap(long, short, requires = "pins")]
     configure: Option<String>,
 
-    /// specifies GPIO pins on which to operate
+    /// specifies GPIO pins on which to operate, by port and pin number or
+    /// by name
     #[clap(long, short, value_name = "pins", use_value_delimiter = true)]
     pins: Option<Vec<String>>,
+
+    /// lists the named pins in the archive's pin map
+    #[clap(
+        long, short,
+        conflicts_with_all = &["input", "toggle", "set", "reset", "configure"]
+    )]
+    list: bool,
+}
+
+struct GpioPin {
+    port: u16,
+    pin: Option<u8>,
+    portname: String,
+    name: Option<String>,
+}
+
+fn list(hubris: &HubrisArchive) -> Result<()> {
+    let pins = &hubris.manifest.gpio_pins;
+
+    if pins.is_empty() {
+        bail!("archive does not contain a GPIO pin map");
+    }
+
+    println!("{:>5} {:20} DESCRIPTION", "PIN", "NAME");
+
+    for pin in pins {
+        println!(
+            "{:>5} {:20} {}",
+            format!("{}:{}", pin.port, pin.pin),
+            pin.name,
+            pin.description.as_deref().unwrap_or("-")
+        );
+    }
+
+    Ok(())
+}
+
+///
+/// Parses a configuration specification.  This is either a full 5-tuple, or
+/// any subset of its fields in any order, with each field identified by the
+/// variant that it names.
+///
+fn configure_args(
+    hubris: &HubrisArchive,
+    func: &HiffyFunction,
+    configure: &str,
+) -> Result<Vec<u16>> {
+    let params: Vec<&str> = configure.split(':').collect();
+    let args = ["Mode", "OutputType", "Speed", "Pull", "Alternate"];
+
+    if params.len() == args.len() {
+        let mut rval = vec![];
+
+        for i in 0..args.len() {
+            rval.push(func.lookup_argument(
+                hubris,
+                args[i],
+                2 + i,
+                params[i],
+            )?);
+        }
+
+        return Ok(rval);
+    }
+
+    let mut values: Vec<Option<&str>> =
+        vec![None, Some("PushPull"), Some("Low"), Some("None"), Some("AF0")];
+    let mut specified = [false; 5];
+
+    for &param in &params {
+        let mut found = false;
+
+        for i in 0..args.len() {
+            let variants = func.argument_variants(hubris, 2 + i)?;
+
+            if variants.iter().any(|(name, _)| name == param) {
+                if specified[i] {
+                    bail!("{} specified more than once", args[i]);
+                }
+
+                values[i] = Some(param);
+                specified[i] = true;
+                found = true;
+                break;
+            }
+        }
+
+        if !found {
+            bail!(
+                "invalid configuration \"{}\"; expected {} (or a subset)",
+                param,
+                args.join(":")
+            );
+        }
+    }
+
+    if values[0].is_none() {
+        if specified[4] {
+            values[0] = Some("Alternate");
+        } else {
+            bail!("a Mode must be specified");
+        }
+    }
+
+    let mut rval = vec![];
+
+    for i in 0..args.len() {
+        rval.push(func.lookup_argument(
+            hubris,
+            args[i],
+            2 + i,
+            values[i].unwrap(),
+        )?);
+    }
+
+    Ok(rval)
 }
 
 fn gpio(
@@ -158,6 +311,11 @@ fn gpio(
     subargs: &[String],
 ) -> Result<()> {
     let subargs = GpioArgs::try_parse_from(subargs)?;
+
+    if subargs.list {
+        return list(hubris);
+    }
+
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
     let funcs = context.functions()?;
 
@@ -175,51 +333,65 @@ fn gpio(
     } else if subargs.reset {
         gpio_reset.id
     } else if let Some(ref configure) = subargs.configure {
-        let params: Vec<&str> = configure.split(':').collect();
-        let args = ["Mode", "OutputType", "Speed", "Pull", "Alternate"];
-
-        if params.len() != args.len() {
-            bail!("expected {}", args.join(":"));
-        }
-
-        for i in 0..args.len() {
-            configure_args.push(gpio_configure.lookup_argument(
-                hubris,
-                args[i],
-                2 + i,
-                params[i],
-            )?);
-        }
-
+        configure_args =
+            self::configure_args(hubris, gpio_configure, configure)?;
         gpio_configure.id
     } else if subargs.input {
         gpio_input.id
     } else {
         bail!(
             "expected one of configure, set, \
-            reset, toggle, or input to be specified"
+            reset, toggle, input, or list to be specified"
         );
     };
 
-    let mut args: Vec<(u16, Option<u8>, String)> = vec![];
+    let mut args: Vec<GpioPin> = vec![];
 
     if let Some(ref pins) = subargs.pins {
         for pin in pins {
-            let p: Vec<&str> = pin.split(':').collect();
+            //
+            // A pin is either a port and pin number, or a name from the pin
+            // map -- in which case we resolve it to its port and pin number.
+            //
+            let (portname, pin, name) = match hubris.lookup_gpio_pin(pin) {
+                Some(p) => (p.port.as_str(), p.pin, Some(p.name.clone())),
+                None => {
+                    let p: Vec<&str> = pin.split(':').collect();
 
-            if p.len() != 2 {
-                bail!("expected both a port and a pin number");
-            }
+                    if p.len() != 2 {
+                        bail!(
+                            "expected either a pin name or both a port \
+                            and a pin number"
+                        );
+                    }
 
-            let port = gpio_toggle.lookup_argument(hubris, "port", 0, p[0])?;
-            let pin = match parse_int::parse::<u8>(p[1]) {
-                Ok(pin) if pin < 16 => pin,
-                _ => {
-                    bail!("invalid pin {}", p[1]);
+                    let pin = match parse_int::parse::<u8>(p[1]) {
+                        Ok(pin) if pin < 16 => pin,
+                        _ => {
+                            bail!("invalid pin {}", p[1]);
+                        }
+                    };
+
+                    let name = hubris
+                        .manifest
+                        .gpio_pins
+                        .iter()
+                        .find(|m| m.port == p[0] && m.pin == pin)
+                        .map(|m| m.name.clone());
+
+                    (p[0], pin, name)
                 }
             };
 
-            args.push((port, Some(pin), p[0].to_string()));
+            let port =
+                gpio_toggle.lookup_argument(hubris, "port", 0, portname)?;
+
+            args.push(GpioPin {
+                port,
+                pin: Some(pin),
+                portname: portname.to_string(),
+                name,
+            });
         }
     }
 
@@ -230,19 +402,24 @@ fn gpio(
             let variants = gpio_input.argument_variants(hubris, 0)?;
 
             for v in &variants {
-                args.push((v.1, None, v.0.clone()));
+                args.push(GpioPin {
+                    port: v.1,
+                    pin: None,
+                    portname: v.0.clone(),
+                    name: None,
+                });
             }
         }
 
         for arg in &args {
-            ops.push(Op::Push16(arg.0));
+            ops.push(Op::Push16(arg.port));
             ops.push(Op::Call(target));
             ops.push(Op::DropN(1));
         }
     } else if subargs.configure.is_some() {
         for arg in &args {
-            ops.push(Op::Push16(arg.0));
-            ops.push(Op::Push(arg.1.unwrap()));
+            ops.push(Op::Push16(arg.port));
+            ops.push(Op::Push(arg.pin.unwrap()));
 
             for configure_arg in &configure_args {
                 ops.push(Op::Push16(*configure_arg));
@@ -253,8 +430,8 @@ fn gpio(
         }
     } else {
         for arg in &args {
-            ops.push(Op::Push16(arg.0));
-            ops.push(Op::Push(arg.1.unwrap()));
+            ops.push(Op::Push16(arg.port));
+            ops.push(Op::Push(arg.pin.unwrap()));
             ops.push(Op::Call(target));
             ops.push(Op::DropN(2));
         }
@@ -268,26 +445,29 @@ fn gpio(
         let mut header = false;
 
         for (ndx, arg) in args.iter().enumerate() {
-            match arg.1 {
+            match arg.pin {
                 Some(pin) => {
-                    println!(
-                        "{}:{:<2} = {}",
-                        arg.2,
-                        pin,
-                        match results[ndx] {
-                            Err(code) => {
-                                gpio_input.strerror(code)
-                            }
-                            Ok(ref val) => {
-                                let arr: &[u8; 2] = val[0..2].try_into()?;
-                                let v = u16::from_le_bytes(*arr);
-                                format!(
-                                    "{}",
-                                    if v & (1 << pin) != 0 { 1 } else { 0 }
-                                )
-                            }
+                    let val = match results[ndx] {
+                        Err(code) => gpio_input.strerror(code),
+                        Ok(ref val) => {
+                            let arr: &[u8; 2] = val[0..2].try_into()?;
+                            let v = u16::from_le_bytes(*arr);
+                            format!(
+                                "{}",
+                                if v & (1 << pin) != 0 { 1 } else { 0 }
+                            )
                         }
-                    );
+                    };
+
+                    match arg.name {
+                        Some(ref name) => println!(
+                            "{}:{:<2} = {:<3} {}",
+                            arg.portname, pin, val, name
+                        ),
+                        None => {
+                            println!("{}:{:<2} = {}", arg.portname, pin, val)
+                        }
+                    }
                 }
 
                 None => {
@@ -310,7 +490,7 @@ fn gpio(
                         header = true;
                     }
 
-                    print!("Port {} ", arg.2);
+                    print!("Port {} ", arg.portname);
                     match results[ndx] {
                         Err(code) => {
                             println!("{}", gpio_input.strerror(code))
@@ -2,13 +2,48 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{anyhow, bail, Result};
+//! ## `humility hash`
+//!
+//! `humility hash` exercises the HASH block via HIF, computing the SHA-256
+//! digest of data supplied as a string (`--string`), as hex bytes (`--hex`)
+//! or as a file (`--file`); see `humility hash --help` for the sequences of
+//! `--init`, `--update` and `--finalize` that are supported.
+//!
+//! To hash target memory, give its address via `--memory` (`-m`) and its
+//! length via `--nbytes` (`-n`).  If the target's HIF has a `HashMemory`
+//! function, the memory is hashed on the target, and only the digest is
+//! read over the debug link -- which makes verifying large flash regions
+//! much faster than reading them.  Otherwise (or if `--host` is specified),
+//! the memory is read and hashed by Humility:
+//!
+//! ```console
+//! % humility hash --memory 0x08000000 --nbytes 0x100000
+//! humility: attached via ST-Link V3
+//! humility: hashed 1048576 bytes at 0x08000000 on target
+//! 851e43bd44a2c3d30e5f3acadc9240c12d9f1c610dba34761e8c47ba82d1daea
+//! ```
+//!
+//! If data is supplied along with `--memory`, the digest of the memory is
+//! compared with that of the data (and `--nbytes` defaults to the length of
+//! the data):
+//!
+//! ```console
+//! % humility hash --memory 0x08000000 --file ./kernel.bin
+//! humility: attached via ST-Link V3
+//! humility: hashed 64512 bytes at 0x08000000 on target
+//! 6923dd1bc0460082c5d55a831908c24a282860b7f1cd6c2b79cf1bc8857c639c
+//! humility: memory at 0x08000000 matches ./kernel.bin
+//! ```
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{ArgGroup, CommandFactory, Parser};
 
 use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
+use humility_cmd::progress::Progress;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 use sha2::{Digest, Sha256};
 use std::fs::File;
@@ -16,8 +51,6 @@ use std::io::Read;
 
 use hif::*;
 
-use indicatif::{ProgressBar, ProgressStyle};
-
 extern crate log;
 
 #[derive(Parser, Debug)]
@@ -99,12 +132,103 @@ struct HashArgs {
     /// enable long test
     #[clap(long, short)]
     long: bool,
+
+    /// hash target memory at the specified address
+    #[clap(
+        long, short, value_name = "address", group = "command",
+        parse(try_from_str = parse_int::parse)
+    )]
+    memory: Option<u32>,
+
+    /// number of bytes of target memory to hash
+    #[clap(
+        long, short, value_name = "nbytes", requires = "memory",
+        parse(try_from_str = parse_int::parse)
+    )]
+    nbytes: Option<u32>,
+
+    /// hash target memory on the host, even if the target can hash it
+    #[clap(long, requires = "memory")]
+    host: bool,
+}
+
+/// Size of the reads when hashing target memory on the host
+const HOST_CHUNK: u32 = 64 * 1024;
+
+///
+/// Hashes the specified target memory:  on the target if its HIF has a
+/// `HashMemory` function (and we haven't been told otherwise), or on the
+/// host by reading the memory.
+///
+fn hash_memory(
+    args: &Args,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    funcs: &HiffyFunctions,
+    addr: u32,
+    nbytes: u32,
+    host: bool,
+) -> Result<Vec<u8>> {
+    if !host && funcs.0.contains_key("HashMemory") {
+        let hash_memory = funcs.get("HashMemory", 2)?;
+
+        let ops = [
+            Op::Push32(addr),
+            Op::Push32(nbytes),
+            Op::Call(hash_memory.id),
+            Op::Done,
+        ];
+
+        let results = context.run(core, &ops, None)?;
+
+        return match &results[0] {
+            Ok(buf) => {
+                humility::msg!(
+                    "hashed {} bytes at {:#010x} on target",
+                    nbytes,
+                    addr
+                );
+                Ok(buf.clone())
+            }
+            Err(err) => Err(hash_memory
+                .error(*err)
+                .context(format!("failed to hash memory at {:#x}", addr))),
+        };
+    }
+
+    if !host {
+        humility::msg!("target cannot hash memory; reading it instead");
+    }
+
+    let mut progress = Progress::bytes(args, "reading", nbytes as u64);
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; HOST_CHUNK as usize];
+    let mut offset = 0;
+
+    while offset < nbytes {
+        let len = (nbytes - offset).min(HOST_CHUNK);
+        let chunk = &mut buf[..len as usize];
+
+        core.read_8(addr + offset, chunk).with_context(|| {
+            format!("failed to read memory at {:#x}", addr + offset)
+        })?;
+
+        hasher.update(chunk);
+        offset += len;
+        progress.set_position(offset as u64);
+    }
+
+    progress.finish();
+    humility::msg!("hashed {} bytes at {:#010x} on host", nbytes, addr);
+
+    Ok(hasher.finalize().to_vec())
 }
 
 fn hash(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = HashArgs::try_parse_from(subargs)?;
@@ -116,7 +240,7 @@ fn hash(
     // Fetch the supplied data if any.
     let mut data = Vec::new();
     let data = if subargs.file.is_some() {
-        let filename = subargs.file.unwrap();
+        let filename = subargs.file.clone().unwrap();
         let mut file = File::open(&filename)?;
         if let Err(err) = file.read_to_end(&mut data) {
             bail!("Cannot read file \"{}\": {}", filename, err);
@@ -140,6 +264,56 @@ fn hash(
         None
     };
 
+    if let Some(addr) = subargs.memory {
+        if subargs.init {
+            bail!("--init is not used with --memory");
+        }
+
+        let nbytes = match (subargs.nbytes, data) {
+            (Some(nbytes), Some(data)) if nbytes as usize != data.len() => {
+                bail!(
+                    "{} bytes specified, but {} bytes of data supplied",
+                    nbytes,
+                    data.len()
+                );
+            }
+            (Some(nbytes), _) => nbytes,
+            (None, Some(data)) => data.len() as u32,
+            (None, None) => bail!("must specify number of bytes to hash"),
+        };
+
+        if addr.checked_add(nbytes).is_none() {
+            bail!("{} bytes at {:#x} exceeds address space", nbytes, addr);
+        }
+
+        let digest = hash_memory(
+            args,
+            core,
+            &mut context,
+            &funcs,
+            addr,
+            nbytes,
+            subargs.host,
+        )?;
+
+        print_hash(&digest);
+
+        if let Some(data) = data {
+            let what = match subargs.file {
+                Some(ref filename) => filename.as_str(),
+                None => "supplied data",
+            };
+
+            if Sha256::digest(data).as_slice() != digest.as_slice() {
+                bail!("memory at {:#x} does not match {}", addr, what);
+            }
+
+            humility::msg!("memory at {:#x} matches {}", addr, what);
+        }
+
+        return Ok(());
+    }
+
     if !subargs.digest && !subargs.update && data.is_some() {
         return Err(anyhow!("data supplied and not used"));
     }
@@ -186,18 +360,15 @@ fn hash(
                         scratch_size
                     ));
                 }
-                // TODO: suppress progress bar with --quiet/--noverbose option
                 // Execution can take a long time in some cases (30+ minutes)
-                let bar = ProgressBar::new(data.len() as u64);
-                bar.set_style(ProgressStyle::default_bar().template(
-                    "humility: Hashing [{bar:30}] {bytes}/{total_bytes}",
-                ));
+                let mut progress =
+                    Progress::bytes(args, "hashing", data.len() as u64);
                 // On first iteration, --digest won't have the Init already pushed.
                 if subargs.digest {
                     ops.push(Op::Call(funcs.get("HashInit", 0)?.id));
                 }
                 for index in (0..data.len()).step_by(scratch_size) {
-                    bar.set_position(index as u64);
+                    progress.set_position(index as u64);
                     let nbytes = if index + scratch_size > data.len() {
                         data.len() - index
                     } else {
@@ -218,7 +389,7 @@ fn hash(
                     }
                     ops.clear();
                 }
-                bar.finish_and_clear();
+                progress.finish();
                 if subargs.digest {
                     ops.push(Op::Call(funcs.get("HashFinalize", 0)?.id));
                     ops.push(Op::Done);
@@ -419,11 +590,7 @@ fn hash(
     ];
     let limit = 1_000_000;
 
-    let bar = ProgressBar::new(limit as u64);
-    bar.set_style(
-        ProgressStyle::default_bar()
-            .template("humility: Hashing [{bar:30}] {bytes}/{total_bytes}"),
-    );
+    let mut progress = Progress::bytes(args, "hashing", limit as u64);
 
     let mut hasher = Sha256::new();
 
@@ -442,9 +609,9 @@ fn hash(
             Some(&block[0..nbytes]),
         )?;
         count += nbytes;
-        bar.set_position(count as u64);
+        progress.set_position(count as u64);
     }
-    bar.finish_and_clear();
+    progress.finish();
 
     let lib_sum = hasher.finalize();
     let result =
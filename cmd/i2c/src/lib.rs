@@ -90,24 +90,133 @@
 //! Controller I2C3, device 0x48, register 0x4 = 0x1f
 //! ```
 //!
+//! Some devices (e.g., larger EEPROMs) have a 16-bit register pointer; to
+//! read or write such a register, specify it via `-A` (`--register16`)
+//! instead of `-r`.  The register is sent big-endian and, on a read, is
+//! followed by a repeated start rather than a stop:
+//!
+//! ```console
+//! % humility i2c -b front -d 0x50 -A 0x1f0 -n 4
+//! humility: attached via ST-Link V3
+//! Controller I2C2, device 0x50, register 0x01f0 =
+//!              \/  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f
+//! 0x00000000 | 4f 58 43 31                                     | OXC1
+//! ```
+//!
+//! More generally, `-X` (`--write-read`) writes arbitrary bytes and then
+//! reads (with a repeated start) the number of bytes specified by `-n`:
+//!
+//! ```console
+//! % humility i2c -b front -d 0x50 -X 0x01,0xf0 -n 2
+//! humility: attached via ST-Link V3
+//! Controller I2C2, device 0x50, write-then-read = 0x4f 0x58
+//! ```
+//!
+//! Both require a version of hiffy that supports the `I2cWriteRead`
+//! function.
+//!
+//! For SMBus devices, `-B` (`--block`) performs a block read or block write,
+//! in which the data is preceded by its byte count.  Devices that mandate
+//! Packet Error Checking (as many hot-swap controllers do) can be accessed
+//! by specifying `-P` (`--pec`):  on a write, a PEC is computed and appended
+//! to the payload; on a read, the PEC is read from the device and checked,
+//! with a mismatch resulting in an error:
+//!
+//! ```console
+//! % humility i2c -b mid -d 0x10 -r 0x99 -B -P
+//! humility: attached via ST-Link V3
+//! Controller I2C2, device 0x10, register 0x99 =
+//!              \/  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f
+//! 0x00000000 | 41 44 49                                        | ADI
+//! ```
+//!
+//! When scanning a bus for devices, `-i` (`--identify`) additionally
+//! attempts to identify each device found.  This reads the PMBus
+//! identification commands (`MFR_ID`, `MFR_MODEL` and `IC_DEVICE_ID`), the
+//! manufacturer and device ID registers common to many sensors, and (at SPD
+//! addresses) the memory type -- and compares the devices found with those
+//! that the archive expects on the bus, flagging any that are unexpected or
+//! missing.  (As with `-S`, reading these registers may have side-effects on
+//! some devices.)  For example:
+//!
+//! ```console
+//! % humility i2c -s -b mid -i
+//! humility: attached via ST-Link V3
+//! ...
+//! Device identification on I2C2, port B:
+//!
+//! ADDR STATUS     DEVICE        IDENTIFICATION
+//! 0x10 ok         adm1272       MFR_ID="ADI", MFR_MODEL="ADM1272-1"
+//! 0x48 ok         tmp117        0xfe="TI", 0xff=[0x01, 0x17]
+//! 0x4a missing    tmp117        -
+//! 0x67 unexpected -             MFR_ID="LTC", MFR_MODEL="LTC4282"
+//! ```
+//!
+//! To distinguish a marginal bus from a misbehaving device (or driver), use
+//! `--stats` to report the latency of each transaction, the number of times
+//! it was retried, and the number of NACKs and arbitration losses it
+//! encountered.  This requires a version of the `hiffy` task that provides
+//! the `I2cStats` function, which returns (and clears) statistics that the
+//! target records for each transaction.  When scanning a bus, transactions
+//! that were simply NACK'd (i.e., for which there is no device) are not
+//! displayed:
+//!
+//! ```console
+//! % humility i2c -b mid -d 0x48 -r 0 -n 2 --stats
+//! humility: attached via ST-Link V3
+//! Controller I2C2, device 0x48, register 0x0 = 0x0c 0x80
+//! ADDR LATENCY (us) RETRIES NACKS ARBLOST
+//! 0x48          412       2     2       0
+//! humility: 1 transactions: latency min/mean/max 412/412/412us; 2 retries, 2 NACKs, 0 arbitration losses
+//! ```
+//!
+//! To emit results as JSON or CSV, use the global `--format` option.  Scans
+//! are emitted as a row for each address (or register) with its status and
+//! any value read; other operations are emitted as a single row.
+//!
+//! To reproduce a device-level problem on another board, the I2C
+//! transactions performed by any command can be captured to a file with the
+//! global `--capture-i2c` option, and later replayed in the same order with
+//! `--replay`.  Each transaction is performed on the same controller, port
+//! and mux segment; device addresses can be remapped with `--remap` (which
+//! may be specified more than once).  Each replayed transaction is
+//! displayed along with whether its outcome matched that of the capture:
+//!
+//! ```console
+//! % humility --capture-i2c tmp117.json i2c -b mid -d 0x48 -r 0xfe -n 2
+//! humility: attached via ST-Link V3
+//! Controller I2C2, device 0x48, register 0xfe = 0x54 0x49
+//! humility: wrote 1 I2C transactions to tmp117.json
+//! % humility i2c --replay tmp117.json --remap 0x48=0x49
+//! humility: attached via ST-Link V3
+//! #    OPERATION  C P ADDR REG  STATUS       MATCH VALUE
+//! 0    read       2 B 0x49 0xfe Ok           true  0x54 0x49
+//! humility: replayed 1 transaction; all matched the capture
+//! ```
+//!
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use hif::*;
 use humility::core::Core;
 use humility::hubris::*;
+use humility_cmd::capture::{self, Operation, Outcome, Transaction};
+use humility_cmd::error::ErrorKind;
 use humility_cmd::hiffy::*;
+use humility_cmd::i2c::{I2cStats, I2cTransactionStats, SmbusOptions};
+use humility_cmd::output::{Cell, Column, OutputFormat, Table};
+use humility_cmd::style::Severity;
 use humility_cmd::{Archive, Args, Attach, Command, Dumper, Validate};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::time::Instant;
 
+use humility_cmd::progress::Progress;
 use indicatif::{HumanBytes, HumanDuration};
-use indicatif::{ProgressBar, ProgressStyle};
 
 #[derive(Parser, Debug, Default)]
 #[clap(name = "i2c", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -124,6 +233,11 @@ pub struct I2cArgs {
     #[clap(long, short, conflicts_with = "register")]
     scan: bool,
 
+    /// after scanning a controller for devices, attempt to identify each
+    /// device found and compare the results to the devices in the archive
+    #[clap(long, short, requires = "scan", conflicts_with = "device")]
+    identify: bool,
+
     /// scan a controller for devices at a particular register, which may
     /// have side-effects on unsporting devices
     #[clap(long, short = 'S', value_name = "register",
@@ -160,14 +274,29 @@ pub struct I2cArgs {
     )]
     register: Option<u8>,
 
+    /// specifies a 16-bit register, sent big-endian (e.g., for devices
+    /// with a 16-bit pointer)
+    #[clap(long, short = 'A', value_name = "register",
+        conflicts_with_all = &["register", "scan", "scanreg", "block", "pec"],
+        parse(try_from_str = parse_int::parse),
+    )]
+    register16: Option<u16>,
+
     /// indicates a raw operation
-    #[clap(long, short = 'R', conflicts_with = "register")]
+    #[clap(
+        long, short = 'R', conflicts_with_all = &["register", "register16"]
+    )]
     raw: bool,
 
-    /// read block
-    #[clap(long, short = 'B', conflicts_with_all = &["write", "nbytes"])]
+    /// read or write an SMBus block (in which the data is preceded by its
+    /// byte count)
+    #[clap(long, short = 'B', conflicts_with_all = &["nbytes", "writeraw"])]
     block: bool,
 
+    /// use SMBus Packet Error Checking on the transaction
+    #[clap(long, short = 'P', conflicts_with = "writeraw")]
+    pec: bool,
+
     /// specifies write value
     #[clap(long, short, value_name = "bytes")]
     write: Option<String>,
@@ -188,16 +317,50 @@ pub struct I2cArgs {
     )]
     nbytes: Option<u8>,
 
+    /// write the specified bytes and then, after a repeated start, read
+    /// the number of bytes specified by -n (or 1)
+    #[clap(long, short = 'X', value_name = "bytes",
+        conflicts_with_all = &[
+            "write", "writeraw", "raw", "register", "register16", "scan",
+            "scanreg", "block", "pec"
+        ],
+    )]
+    write_read: Option<String>,
+
     /// flash the specified file, assuming two byte addressing
     #[clap(long, short,
         conflicts_with_all = &[
             "write", "raw", "nbytes", "register", "scan",
-            "writeraw"
+            "writeraw", "register16", "write_read"
         ],
         value_name = "filename",
         requires = "device",
     )]
     flash: Option<String>,
+
+    /// replay the I2C transactions in the specified capture file
+    #[clap(long, value_name = "filename",
+        conflicts_with_all = &[
+            "scan", "scanreg", "bus", "controller", "port", "mux", "device",
+            "register", "register16", "raw", "block", "pec", "write",
+            "writeraw", "nbytes", "write_read", "flash"
+        ],
+    )]
+    replay: Option<String>,
+
+    /// when replaying, remap a device address (e.g., 0x48=0x49)
+    #[clap(
+        long,
+        value_name = "from=to",
+        requires = "replay",
+        multiple_occurrences = true
+    )]
+    remap: Vec<String>,
+
+    /// report the latency, retries, NACKs and arbitration losses of each
+    /// transaction
+    #[clap(long, conflicts_with_all = &["flash", "replay", "identify"])]
+    stats: bool,
 }
 
 fn i2c_done(
@@ -370,12 +533,18 @@ fn i2c_done(
             }
         }
     } else {
+        let target = match (subargs.register, subargs.register16) {
+            (Some(register), _) => format!("register 0x{:x}", register),
+            (None, Some(register)) => format!("register 0x{:04x}", register),
+            (None, None) => "write-then-read".to_string(),
+        };
+
         print!(
-            "Controller I2C{}, device 0x{:x}, {}register 0x{:x} = ",
+            "Controller I2C{}, device 0x{:x}, {}{} = ",
             hargs.controller,
             hargs.address.unwrap(),
             if subargs.writeraw { "raw write to " } else { "" },
-            subargs.register.unwrap()
+            target,
         );
 
         if results.is_empty() {
@@ -390,15 +559,15 @@ fn i2c_done(
                     Dumper::new().dump(val, 0);
                 }
 
-                Ok(val) => match subargs.nbytes {
-                    Some(n) if n > 2 => {
+                Ok(val) => match val.len() {
+                    n if n > 2 => {
                         println!();
                         Dumper::new().dump(val, 0);
                     }
-                    Some(2) => {
+                    2 => {
                         println!("0x{:02x} 0x{:02x}", val[0], val[1])
                     }
-                    Some(1) => {
+                    1 => {
                         println!("0x{:02x}", val[0])
                     }
                     _ => {
@@ -422,30 +591,662 @@ fn i2c_done(
     Ok(())
 }
 
+///
+/// Emits the results of an I2C operation as rows, for output formats other
+/// than a table.
+///
+fn i2c_rows(
+    format: OutputFormat,
+    subargs: &I2cArgs,
+    hargs: &humility_cmd::i2c::I2cArgs,
+    results: &[Result<Vec<u8>, u32>],
+    func: &HiffyFunction,
+) -> Result<()> {
+    let status = |ndx: usize| match results.get(ndx) {
+        None => Cell::from("TimedOut"),
+        Some(Ok(_)) => Cell::from("Ok"),
+        Some(Err(err)) => Cell::from(func.strerror(*err)),
+    };
+
+    let value = |ndx: usize| match results.get(ndx) {
+        Some(Ok(val)) => Cell::Bytes(val.clone()),
+        _ => Cell::None,
+    };
+
+    if subargs.scan || subargs.scanreg.is_some() {
+        let (what, count) = match subargs.device {
+            None => ("address", 128),
+            Some(_) => ("register", 256),
+        };
+
+        let mut table = Table::new(
+            format,
+            vec![
+                Column::new(what, 4),
+                Column::new("status", 12),
+                Column::new("value", 0),
+            ],
+        );
+
+        for i in 0..count {
+            table.row(vec![Cell::Hex(i as u64, 2), status(i), value(i)])?;
+        }
+    } else {
+        let mut table = Table::new(
+            format,
+            vec![
+                Column::new("controller", 2),
+                Column::new("port", 2),
+                Column::new("address", 4),
+                Column::new("register", 4),
+                Column::new("status", 12),
+                Column::new("value", 0),
+            ],
+        );
+
+        table.row(vec![
+            hargs.controller.into(),
+            hargs.port.name.as_str().into(),
+            hargs.address.map(|a| Cell::Hex(a.into(), 2)).into(),
+            match (subargs.register, subargs.register16) {
+                (Some(r), _) => Cell::Hex(r.into(), 2),
+                (None, Some(r)) => Cell::Hex(r.into(), 4),
+                (None, None) => Cell::None,
+            },
+            status(0),
+            value(0),
+        ])?;
+    }
+
+    Ok(())
+}
+
+///
+/// Registers that we read to identify a device, along with the number of
+/// bytes to read (or `None` for an SMBus block read):  the PMBus `MFR_ID`,
+/// `MFR_MODEL` and `IC_DEVICE_ID` commands, the manufacturer and device
+/// ID registers found on many sensors, and the SPD byte that denotes the
+/// memory type (which is only read at SPD addresses).
+///
+const IDENTIFY_PROBES: &[(&str, u8, Option<u8>)] = &[
+    ("MFR_ID", 0x99, None),
+    ("MFR_MODEL", 0x9a, None),
+    ("IC_DEVICE_ID", 0xad, None),
+    ("0xfe", 0xfe, Some(2)),
+    ("0xff", 0xff, Some(2)),
+];
+
+const SPD_ADDRESSES: std::ops::RangeInclusive<u8> = 0x50..=0x57;
+
+fn i2c_stats(
+    format: OutputFormat,
+    subargs: &I2cArgs,
+    stats: &I2cStats,
+) -> Result<()> {
+    let scan = subargs.device.is_none();
+    let mut table = Table::new(
+        format,
+        vec![
+            Column::new("addr", 4),
+            Column::new("latency", 12).heading("LATENCY (us)").right(),
+            Column::new("retries", 7).right(),
+            Column::new("nacks", 5).right(),
+            Column::new("arblost", 7).right(),
+        ],
+    );
+
+    for t in &stats.transactions {
+        //
+        // When scanning a bus, a NACK usually just means that there is no
+        // device at the address; we only display such a transaction if it
+        // was retried or lost arbitration.
+        //
+        if scan && t.nacks != 0 && t.retries == 0 && t.arblost == 0 {
+            continue;
+        }
+
+        let count = |n: u8| -> Cell {
+            if n == 0 {
+                n.into()
+            } else {
+                Cell::from(n).styled(Severity::Warning)
+            }
+        };
+
+        table.row(vec![
+            Cell::Hex(t.address as u64, 2),
+            t.latency.into(),
+            count(t.retries),
+            count(t.nacks),
+            count(t.arblost),
+        ])?;
+    }
+
+    let all = &stats.transactions;
+
+    if let Some(max) = all.iter().map(|t| t.latency).max() {
+        let min = all.iter().map(|t| t.latency).min().unwrap();
+        let sum = all.iter().map(|t| t.latency as u64).sum::<u64>();
+        let total = |f: fn(&I2cTransactionStats) -> u8| {
+            all.iter().map(|t| f(t) as u32).sum::<u32>()
+        };
+
+        humility::msg!(
+            "{} transactions: latency min/mean/max {}/{}/{}us; \
+            {} retries, {} NACKs, {} arbitration losses",
+            all.len(),
+            min,
+            sum / all.len() as u64,
+            max,
+            total(|t| t.retries),
+            total(|t| t.nacks),
+            total(|t| t.arblost),
+        );
+    }
+
+    if stats.dropped != 0 {
+        humility::msg!(
+            "{} transactions were not recorded by the target",
+            stats.dropped
+        );
+    }
+
+    Ok(())
+}
+
+fn spd_memory_type(val: u8) -> Option<&'static str> {
+    match val {
+        0x0b => Some("DDR3"),
+        0x0c => Some("DDR4"),
+        0x0e => Some("DDR4E"),
+        0x0f => Some("LPDDR3"),
+        0x10 => Some("LPDDR4"),
+        0x11 => Some("LPDDR4X"),
+        0x12 => Some("DDR5"),
+        0x13 => Some("LPDDR5"),
+        _ => None,
+    }
+}
+
+fn fingerprint(address: u8, results: &[Result<Vec<u8>, u32>]) -> String {
+    let printable = |val: &[u8]| {
+        !val.is_empty()
+            && val.iter().all(|&c| c.is_ascii_graphic() || c == b' ')
+    };
+
+    let mut rval = vec![];
+    let mut pmbus = false;
+
+    for ((name, _, _), result) in IDENTIFY_PROBES.iter().zip(results) {
+        let val = match result {
+            Ok(val) if !val.is_empty() && val.iter().any(|&c| c != 0xff) => val,
+            _ => continue,
+        };
+
+        //
+        // The generic ID registers are only of interest if the device
+        // doesn't identify itself via PMBus.
+        //
+        if name.starts_with("0x") && pmbus {
+            continue;
+        }
+
+        pmbus |= !name.starts_with("0x");
+
+        if printable(val) {
+            let val = String::from_utf8_lossy(val);
+            rval.push(format!("{}=\"{}\"", name, val));
+        } else {
+            let val = val
+                .iter()
+                .map(|b| format!("0x{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(", ");
+            rval.push(format!("{}=[{}]", name, val));
+        }
+    }
+
+    if SPD_ADDRESSES.contains(&address) {
+        if let Some(Ok(val)) = results.get(IDENTIFY_PROBES.len()) {
+            if let Some(kind) = val.first().and_then(|&v| spd_memory_type(v)) {
+                rval.insert(0, format!("SPD {}", kind));
+            }
+        }
+    }
+
+    if rval.is_empty() {
+        "-".to_string()
+    } else {
+        rval.join(", ")
+    }
+}
+
+///
+/// Attempts to identify each device found by a scan, and compares the
+/// devices found to the devices that the archive expects on the scanned bus
+/// (and segment).
+///
+fn identify(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    hargs: &humility_cmd::i2c::I2cArgs,
+    results: &[Result<Vec<u8>, u32>],
+    func: &HiffyFunction,
+    format: OutputFormat,
+) -> Result<()> {
+    let found = results
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| r.is_ok())
+        .map(|(addr, _)| addr as u8)
+        .collect::<Vec<_>>();
+
+    let mut expected = BTreeMap::new();
+
+    for d in &hubris.manifest.i2c_devices {
+        let mux = match (d.mux, d.segment) {
+            (Some(m), Some(s)) => Some((m, s)),
+            _ => None,
+        };
+
+        if d.controller == hargs.controller
+            && d.port.index == hargs.port.index
+            && mux == hargs.mux
+        {
+            expected.entry(d.address).or_insert_with(Vec::new).push(d);
+        }
+    }
+
+    let mut fingerprints = BTreeMap::new();
+
+    //
+    // We identify each device with its own HIF program to keep the results
+    // of the (potentially large) block reads from overflowing our stack.
+    //
+    for &address in &found {
+        let mut ops = vec![];
+        hargs.push_bus(&mut ops);
+        ops.push(Op::Push(address));
+
+        let mut probes = IDENTIFY_PROBES
+            .iter()
+            .map(|(_, r, n)| (*r, *n))
+            .collect::<Vec<_>>();
+
+        if SPD_ADDRESSES.contains(&address) {
+            probes.push((0x02, Some(1)));
+        }
+
+        for (register, nbytes) in probes {
+            ops.push(Op::Push(register));
+
+            match nbytes {
+                Some(nbytes) => ops.push(Op::Push(nbytes)),
+                None => ops.push(Op::PushNone),
+            }
+
+            ops.push(Op::Call(func.id));
+            ops.push(Op::DropN(2));
+        }
+
+        ops.push(Op::Done);
+
+        let results = context.run(core, ops.as_slice(), None)?;
+        fingerprints.insert(address, fingerprint(address, &results));
+    }
+
+    let addresses =
+        found.iter().chain(expected.keys()).copied().collect::<BTreeSet<_>>();
+
+    if format == OutputFormat::Table {
+        println!("\nDevice identification on {}:\n", hargs);
+    }
+
+    let mut table = Table::new(
+        format,
+        vec![
+            Column::new("addr", 4),
+            Column::new("status", 10),
+            Column::new("device", 13),
+            Column::new("identification", 0),
+        ],
+    );
+
+    for address in addresses {
+        let devices = expected.get(&address);
+        let present = found.contains(&address);
+
+        let status = match (present, devices) {
+            (true, Some(_)) => "ok",
+            (true, None) => "unexpected",
+            (false, Some(d)) if d.iter().all(|d| d.removable) => "absent",
+            (false, _) => "missing",
+        };
+
+        let device = match devices {
+            Some(d) => d
+                .iter()
+                .map(|d| d.device.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+            None => "-".to_string(),
+        };
+
+        let id = fingerprints.get(&address).map(|s| s.as_str());
+
+        table.row(vec![
+            Cell::Hex(address.into(), 2),
+            status.into(),
+            device.into(),
+            id.into(),
+        ])?;
+    }
+
+    Ok(())
+}
+
+///
+/// Performs an SMBus block read with the specified operations (which must
+/// leave the I2C controller, port, mux, segment, device and register on the
+/// stack), returning the size of the block.
+///
+fn block_size(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    ops: &[Op],
+    func: &HiffyFunction,
+) -> Result<usize> {
+    let mut ops = ops.to_vec();
+
+    ops.push(Op::PushNone);
+    ops.push(Op::Call(func.id));
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+
+    match results.get(0) {
+        Some(Ok(val)) => Ok(val.len()),
+        Some(Err(err)) => Err(ErrorKind::DeviceNak
+            .error(format!("block read failed: {}", func.strerror(*err)))),
+        None => bail!("block read timed out"),
+    }
+}
+
+fn parse_bytes(bytes: &str) -> Result<Vec<u8>> {
+    let mut rval = vec![];
+
+    for byte in bytes.split(',') {
+        if let Ok(val) = parse_int::parse::<u8>(byte) {
+            rval.push(val);
+        } else {
+            bail!("invalid byte {}", byte)
+        }
+    }
+
+    Ok(rval)
+}
+
+//
+// The number of transactions that we replay in a single HIF program.
+//
+const REPLAY_BATCH: usize = 8;
+
+fn replay_ops(
+    hubris: &HubrisArchive,
+    funcs: &HiffyFunctions,
+    t: &Transaction,
+    address: u8,
+    ops: &mut Vec<Op>,
+) -> Result<()> {
+    let port = hubris.lookup_i2c_port(t.controller, &t.port)?;
+
+    ops.push(Op::Push(t.controller));
+    ops.push(Op::Push(port.index));
+
+    match (t.mux, t.segment) {
+        (Some(mux), Some(segment)) => {
+            ops.push(Op::Push(mux));
+            ops.push(Op::Push(segment));
+        }
+        _ => {
+            ops.push(Op::PushNone);
+            ops.push(Op::PushNone);
+        }
+    }
+
+    ops.push(Op::Push(address));
+
+    match t.operation {
+        Operation::Read => {
+            let func = funcs.get("I2cRead", 7)?;
+
+            match t.register {
+                Some(register) => ops.push(Op::Push(register)),
+                None => ops.push(Op::PushNone),
+            }
+
+            match t.nbytes {
+                Some(nbytes) => ops.push(Op::Push(nbytes)),
+                None => ops.push(Op::PushNone),
+            }
+
+            ops.push(Op::Call(func.id));
+            ops.push(Op::DropN(7));
+        }
+        Operation::Write => {
+            let func = funcs.get("I2cWrite", 8)?;
+
+            match t.register {
+                Some(register) => ops.push(Op::Push(register)),
+                None => ops.push(Op::PushNone),
+            }
+
+            for byte in &t.write {
+                ops.push(Op::Push(*byte));
+            }
+
+            ops.push(Op::Push32(t.write.len() as u32));
+            ops.push(Op::Call(func.id));
+            ops.push(Op::DropN(t.write.len() as u8 + 7));
+        }
+        Operation::WriteRead => {
+            let func = funcs.get("I2cWriteRead", 8).context(
+                "replay requires a version of hiffy that supports \
+                I2cWriteRead",
+            )?;
+
+            humility_cmd::i2c::write_read_ops(
+                func,
+                &t.write,
+                t.nbytes.unwrap_or(1),
+                ops,
+            )?;
+
+            ops.push(Op::DropN(5));
+        }
+    }
+
+    Ok(())
+}
+
+fn replay(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    format: OutputFormat,
+    subargs: &I2cArgs,
+) -> Result<()> {
+    let filename = subargs.replay.as_ref().unwrap();
+    let capture = capture::load(filename)?;
+
+    let mut remap = HashMap::new();
+
+    for r in &subargs.remap {
+        let (from, to) = match r.split_once('=') {
+            Some((from, to)) => {
+                (parse_int::parse::<u8>(from), parse_int::parse::<u8>(to))
+            }
+            None => bail!("remapping must be of the form from=to"),
+        };
+
+        match (from, to) {
+            (Ok(from), Ok(to)) => {
+                remap.insert(from, to);
+            }
+            _ => bail!("invalid address remapping \"{}\"", r),
+        }
+    }
+
+    if let (Some(image), Some(name)) = (&capture.image, &hubris.manifest.name) {
+        if image != name {
+            humility::msg!("replaying capture from {} on {}", image, name);
+        }
+    }
+
+    let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+    let funcs = context.functions()?;
+
+    let mut table = Table::new(
+        format,
+        vec![
+            Column::new("#", 4),
+            Column::new("operation", 10),
+            Column::new("controller", 1).heading("C"),
+            Column::new("port", 1).heading("P"),
+            Column::new("address", 4).heading("ADDR"),
+            Column::new("register", 4).heading("REG"),
+            Column::new("status", 12),
+            Column::new("match", 5),
+            Column::new("value", 0),
+        ],
+    );
+
+    let mut mismatched = 0;
+
+    for (batch, transactions) in
+        capture.transactions.chunks(REPLAY_BATCH).enumerate()
+    {
+        let mut ops = vec![];
+        let mut addresses = vec![];
+
+        for t in transactions {
+            let address = *remap.get(&t.address).unwrap_or(&t.address);
+            replay_ops(hubris, &funcs, t, address, &mut ops)?;
+            addresses.push(address);
+        }
+
+        ops.push(Op::Done);
+
+        let results = context.run(core, ops.as_slice(), None)?;
+
+        for (i, t) in transactions.iter().enumerate() {
+            let outcome = match results.get(i) {
+                Some(Ok(val)) => Outcome::Ok(val.clone()),
+                Some(Err(code)) => {
+                    let name = match t.operation {
+                        Operation::Read => "I2cRead",
+                        Operation::Write => "I2cWrite",
+                        Operation::WriteRead => "I2cWriteRead",
+                    };
+
+                    Outcome::Err(funcs.0[name].strerror(*code))
+                }
+                None => bail!("missing result for transaction"),
+            };
+
+            let matched = outcome == t.outcome;
+
+            if !matched {
+                mismatched += 1;
+            }
+
+            let (status, value) = match outcome {
+                Outcome::Ok(val) => (Cell::from("Ok"), Cell::Bytes(val)),
+                Outcome::Err(err) => (Cell::from(err), Cell::None),
+            };
+
+            table.row(vec![
+                Cell::Unsigned((batch * REPLAY_BATCH + i) as u64),
+                Cell::from(match t.operation {
+                    Operation::Read => "read",
+                    Operation::Write => "write",
+                    Operation::WriteRead => "write-read",
+                }),
+                t.controller.into(),
+                t.port.as_str().into(),
+                Cell::Hex(addresses[i].into(), 2),
+                t.register.map(|r| Cell::Hex(r.into(), 2)).into(),
+                status,
+                Cell::Bool(matched),
+                value,
+            ])?;
+        }
+    }
+
+    let n = capture.transactions.len();
+
+    if mismatched == 0 {
+        humility::msg!(
+            "replayed {} transaction{}; all matched the capture",
+            n,
+            if n == 1 { "" } else { "s" }
+        );
+    } else {
+        humility::msg!(
+            "replayed {} transaction{}; {} did not match the capture",
+            n,
+            if n == 1 { "" } else { "s" },
+            mismatched
+        );
+    }
+
+    Ok(())
+}
+
 fn i2c(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = I2cArgs::try_parse_from(subargs)?;
 
+    if subargs.replay.is_some() {
+        return replay(hubris, core, args.format, &subargs);
+    }
+
     if !subargs.scan
         && subargs.scanreg.is_none()
         && subargs.register.is_none()
+        && subargs.register16.is_none()
+        && subargs.write_read.is_none()
         && !subargs.raw
         && subargs.flash.is_none()
     {
         bail!(
-            "must indicate a scan (-s/-S), specify a register (-r), \
-            indicate raw (-R) or flash (-f)"
+            "must indicate a scan (-s/-S), specify a register (-r/-A), \
+            indicate raw (-R), write-then-read (-X), flash (-f) or replay"
         );
     }
 
+    //
+    // A read of a 16-bit register (like an arbitrary write-then-read) is a
+    // write of the register followed by a read after a repeated start.
+    //
+    let write_read = match (&subargs.write_read, subargs.register16) {
+        (Some(bytes), _) => Some(parse_bytes(bytes)?),
+        (None, Some(register)) if subargs.write.is_none() => {
+            Some(register.to_be_bytes().to_vec())
+        }
+        _ => None,
+    };
+
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
 
-    let (fname, args) = if subargs.flash.is_some() {
+    let (fname, nargs) = if subargs.flash.is_some() {
         ("I2cBulkWrite", 8)
+    } else if write_read.is_some() {
+        ("I2cWriteRead", 8)
     } else {
         match (subargs.write.is_some(), subargs.writeraw) {
             (true, _) | (false, true) => ("I2cWrite", 8),
@@ -454,7 +1255,32 @@ fn i2c(
     };
 
     let funcs = context.functions()?;
-    let func = funcs.get(fname, args)?;
+
+    let func = if write_read.is_some() {
+        funcs.get(fname, nargs).context(
+            "write-then-read requires a version of hiffy that supports \
+            I2cWriteRead",
+        )?
+    } else {
+        funcs.get(fname, nargs)?
+    };
+
+    //
+    // Statistics accumulate on the target until they are retrieved; we
+    // retrieve (and discard) them before our operation so that we report
+    // only on its transactions.
+    //
+    let stats = if subargs.stats {
+        let stats = funcs.get("I2cStats", 0).context(
+            "transaction statistics require a version of hiffy that \
+            supports I2cStats",
+        )?;
+
+        context.run(core, &[Op::Call(stats.id), Op::Done], None)?;
+        Some(stats)
+    } else {
+        None
+    };
 
     let hargs = humility_cmd::i2c::I2cArgs::parse(
         hubris,
@@ -465,17 +1291,8 @@ fn i2c(
         &subargs.device,
     )?;
 
-    let mut ops = vec![Op::Push(hargs.controller)];
-
-    ops.push(Op::Push(hargs.port.index));
-
-    if let Some(mux) = hargs.mux {
-        ops.push(Op::Push(mux.0));
-        ops.push(Op::Push(mux.1));
-    } else {
-        ops.push(Op::PushNone);
-        ops.push(Op::PushNone);
-    }
+    let mut ops = vec![];
+    hargs.push_bus(&mut ops);
 
     if let Some(filename) = subargs.flash {
         ops.push(Op::Push(hargs.address.unwrap()));
@@ -513,12 +1330,7 @@ fn i2c(
         let sleep = funcs.get("Sleep", 1)?;
 
         let started = Instant::now();
-        let bar = ProgressBar::new(filelen as u64);
-        bar.set_style(
-            ProgressStyle::default_bar().template(
-                "humility: flashing [{bar:30}] {bytes}/{total_bytes}",
-            ),
-        );
+        let mut progress = Progress::bytes(args, "flashing", filelen as u64);
 
         let base = ops;
 
@@ -587,16 +1399,14 @@ fn i2c(
 
             let results = context.run(core, ops.as_slice(), Some(&buf))?;
 
-            bar.set_position(offset.into());
+            progress.set_position(offset.into());
 
             for (i, item) in results.into_iter().enumerate() {
                 if let Err(err) = item {
-                    bail!(
-                        "failed to write block {} at offset {}: {}",
-                        i,
-                        offset,
-                        func.strerror(err)
-                    );
+                    return Err(func.error(err).context(format!(
+                        "failed to write block {} at offset {}",
+                        i, offset
+                    )));
                 }
             }
 
@@ -605,7 +1415,7 @@ fn i2c(
             }
         }
 
-        bar.finish_and_clear();
+        progress.finish();
 
         humility::msg!(
             "flashed {} in {}",
@@ -616,30 +1426,48 @@ fn i2c(
         return Ok(());
     }
 
+    let smbus = SmbusOptions { block: subargs.block, pec: subargs.pec };
+
+    if (subargs.scan || subargs.scanreg.is_some()) && subargs.pec {
+        bail!("PEC cannot be used with a scan");
+    }
+
     if !subargs.scan && subargs.scanreg.is_none() {
-        if let Some(address) = hargs.address {
-            ops.push(Op::Push(address));
-        } else {
-            bail!("expected device");
-        }
+        let address = match hargs.address {
+            Some(address) => address,
+            None => bail!("expected device"),
+        };
+
+        ops.push(Op::Push(address));
 
-        if let Some(ref write) = subargs.write {
+        if let Some(ref write) = write_read {
+            let nbytes = subargs.nbytes.unwrap_or(1);
+            humility_cmd::i2c::write_read_ops(func, write, nbytes, &mut ops)?;
+        } else if let Some(ref write) = subargs.write {
             if let Some(register) = subargs.register {
                 ops.push(Op::Push(register));
             } else {
                 ops.push(Op::PushNone);
             }
 
-            let bytes: Vec<&str> = write.split(',').collect();
-            let mut arr = vec![];
-
-            for byte in &bytes {
-                if let Ok(val) = parse_int::parse::<u8>(byte) {
-                    arr.push(val);
-                } else {
-                    bail!("invalid byte {}", byte)
+            //
+            // A 16-bit register is written as the first two bytes of the
+            // payload.
+            //
+            let arr = match subargs.register16 {
+                Some(register) => {
+                    let mut arr = register.to_be_bytes().to_vec();
+                    arr.extend(parse_bytes(write)?);
+                    arr
                 }
-            }
+                None => parse_bytes(write)?,
+            };
+
+            //
+            // For a block write, the data must be preceded by its length --
+            // and with PEC, followed by a PEC covering the transaction.
+            //
+            let arr = smbus.write_payload(address, subargs.register, &arr)?;
 
             for item in &arr {
                 ops.push(Op::Push(*item));
@@ -661,16 +1489,26 @@ fn i2c(
                 ops.push(Op::PushNone);
             }
 
-            if let Some(nbytes) = subargs.nbytes {
-                ops.push(Op::Push(nbytes));
+            if subargs.block && subargs.pec {
+                //
+                // A block read on the target doesn't read the PEC that
+                // follows the block, so we first do a block read to learn
+                // the size of the block, and then read the count, the block
+                // and the PEC as a fixed-size read.
+                //
+                let nbytes = block_size(core, &mut context, &ops, func)?;
+                ops.push(Op::Push(smbus.read_size(nbytes) as u8));
             } else if subargs.block {
                 ops.push(Op::PushNone);
             } else {
-                ops.push(Op::Push(1));
+                let nbytes = subargs.nbytes.unwrap_or(1) as usize;
+                ops.push(Op::Push(smbus.read_size(nbytes) as u8));
             }
         }
 
-        ops.push(Op::Call(func.id));
+        if write_read.is_none() {
+            ops.push(Op::Call(func.id));
+        }
     } else if let Some(address) = hargs.address {
         ops.push(Op::Push(address));
         ops.push(Op::Push(0));
@@ -705,9 +1543,46 @@ fn i2c(
 
     ops.push(Op::Done);
 
-    let results = context.run(core, ops.as_slice(), None)?;
+    let mut results = context.run(core, ops.as_slice(), None)?;
 
-    i2c_done(&subargs, &hargs, &results, func)?;
+    //
+    // If we have performed a read with PEC, check (and strip) the PEC.
+    //
+    if subargs.pec && subargs.write.is_none() {
+        if let Some(Ok(val)) = results.get(0) {
+            let address = hargs.address.unwrap();
+            let data = smbus
+                .read_data(address, subargs.register, val)
+                .with_context(|| format!("bad read {:x?}", val))?;
+
+            results[0] = Ok(data);
+        }
+    }
+
+    if args.format == OutputFormat::Table {
+        i2c_done(&subargs, &hargs, &results, func)?;
+    } else {
+        i2c_rows(args.format, &subargs, &hargs, &results, func)?;
+    }
+
+    if subargs.identify {
+        let format = args.format;
+        identify(hubris, core, &mut context, &hargs, &results, func, format)?;
+    }
+
+    if let Some(stats) = stats {
+        let ops = [Op::Call(stats.id), Op::Done];
+
+        let stats = match context.run(core, &ops, None)?.get(0) {
+            Some(Ok(val)) => I2cStats::from_bytes(val)?,
+            Some(Err(err)) => {
+                return Err(stats.error(*err).context("failed to get stats"))
+            }
+            None => bail!("no statistics returned"),
+        };
+
+        i2c_stats(args.format, &subargs, &stats)?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,249 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility ipc`
+//!
+//! `humility ipc` displays the IPC relationships between blocked tasks:
+//! for each task that is blocked on another task -- be it waiting to send
+//! to it, waiting for a reply from it, or in a closed receive from it --
+//! the task on which it is blocked is shown, along with the chain of tasks
+//! that follows from it:
+//!
+//! ```console
+//! % humility ipc
+//! humility: attached via ST-Link V3
+//! ID TASK                 WAIT  ON                   CHAIN
+//!  8 ping                 send  pong/gen0            ping -> pong (FAULT)
+//! 12 sensor               reply i2c_driver/gen0      sensor -> i2c_driver (recv)
+//! 13 thermal              send  sensor/gen0          thermal -> sensor -> i2c_driver (recv)
+//! ```
+//!
+//! A chain ends at a task that is not itself blocked on another task; the
+//! state of that task is shown in parentheses.  The task at the end of a
+//! chain is where to look to understand why the tasks in the chain are
+//! stuck:  a task that is waiting for a reply from a task that is in
+//! receive, for example, sent a message that was received but never replied
+//! to.  A task that is blocked on a generation of a task other than the
+//! task's current generation is flagged as `stale`.
+//!
+//! If the chain starting at a task returns to a task already in the chain,
+//! the tasks in the cycle are deadlocked, and each such cycle is reported:
+//!
+//! ```console
+//! % humility ipc
+//! humility: attached via ST-Link V3
+//! ID TASK                 WAIT  ON                   CHAIN
+//!  4 spi_driver           send  net/gen0             spi_driver -> net -> spi_driver (DEADLOCK)
+//!  5 net                  reply spi_driver/gen0      net -> spi_driver -> net (DEADLOCK)
+//! humility: deadlock: spi_driver -> net -> spi_driver
+//! ```
+//!
+//! To see only the chain for a single task, specify the task.
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::doppel::{SchedState, TaskId, TaskState};
+use humility_cmd::kernel::KernelState;
+use humility_cmd::output::{Column, Table};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Parser, Debug)]
+#[clap(name = "ipc", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct IpcArgs {
+    /// single task to display
+    task: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Wait {
+    Send,
+    Reply,
+    Recv,
+}
+
+impl Wait {
+    fn name(&self) -> &'static str {
+        match self {
+            Wait::Send => "send",
+            Wait::Reply => "reply",
+            Wait::Recv => "recv",
+        }
+    }
+}
+
+//
+// Returns what a task is blocked on, if it is blocked on another task.
+//
+fn blocked_on(state: TaskState) -> Option<(Wait, TaskId)> {
+    match state {
+        TaskState::Healthy(SchedState::InSend(tid))
+            if tid != TaskId::KERNEL =>
+        {
+            Some((Wait::Send, tid))
+        }
+        TaskState::Healthy(SchedState::InReply(tid)) => {
+            Some((Wait::Reply, tid))
+        }
+        TaskState::Healthy(SchedState::InRecv(Some(tid))) => {
+            Some((Wait::Recv, tid))
+        }
+        _ => None,
+    }
+}
+
+//
+// A terse description of the state of a task at the end of a chain.
+//
+fn terminal(state: TaskState, current: bool) -> &'static str {
+    match state {
+        TaskState::Faulted { .. } => "FAULT",
+        TaskState::Healthy(SchedState::Stopped) => "not started",
+        TaskState::Healthy(SchedState::Runnable) if current => "RUNNING",
+        TaskState::Healthy(SchedState::Runnable) => "ready",
+        TaskState::Healthy(SchedState::InSend(_)) => "HALT",
+        TaskState::Healthy(_) => "recv",
+    }
+}
+
+fn ipc(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = IpcArgs::try_parse_from(subargs)?;
+
+    core.halt()?;
+    let kernel = KernelState::read(hubris, core);
+    core.run()?;
+    let kernel = kernel?;
+
+    if let Some(ref task) = subargs.task {
+        if kernel.lookup_task(task).is_none() {
+            bail!("\"{}\" is not a valid task", task);
+        }
+    }
+
+    let edges = kernel
+        .tasks
+        .iter()
+        .filter_map(|t| blocked_on(t.task.state).map(|e| (t.index, e)))
+        .collect::<BTreeMap<_, _>>();
+
+    let name = |ndx: u32| {
+        kernel
+            .tasks
+            .get(ndx as usize)
+            .map_or_else(|| format!("unknown#{}", ndx), |t| t.name.clone())
+    };
+
+    let mut table = Table::new(
+        args.format,
+        vec![
+            Column::new("id", 2).right(),
+            Column::new("task", 15),
+            Column::new("wait", 5),
+            Column::new("on", 15),
+            Column::new("chain", 5),
+        ],
+    );
+
+    let mut cycles = BTreeSet::new();
+
+    for (&ndx, &(wait, tid)) in &edges {
+        let task = &kernel.tasks[ndx as usize];
+
+        if let Some(ref name) = subargs.task {
+            if *name != task.name {
+                continue;
+            }
+        }
+
+        //
+        // Follow the chain until we reach a task that isn't blocked on
+        // another, or until we revisit a task.
+        //
+        let mut path = vec![ndx];
+        let mut next = tid.index() as u32;
+
+        let end = loop {
+            if let Some(pos) = path.iter().position(|&p| p == next) {
+                //
+                // We normalize each cycle to start at its lowest task index
+                // so that we report it only once.
+                //
+                let mut cycle = path[pos..].to_vec();
+                let min = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap();
+                cycle.rotate_left(min);
+                cycles.insert(cycle);
+
+                path.push(next);
+                break "DEADLOCK".to_string();
+            }
+
+            path.push(next);
+
+            let target = match kernel.tasks.get(next as usize) {
+                Some(target) => target,
+                None => break "unknown".to_string(),
+            };
+
+            match edges.get(&next) {
+                Some((_, tid)) => next = tid.index() as u32,
+                None => {
+                    let current = kernel.current == Some(next);
+                    break terminal(target.task.state, current).to_string();
+                }
+            }
+        };
+
+        let on =
+            format!("{}/gen{}", name(tid.index() as u32), tid.generation());
+
+        let stale = kernel.tasks.get(tid.index()).map_or(false, |t| {
+            u32::from(t.task.generation) != tid.generation() as u32
+        });
+
+        let chain =
+            path.iter().map(|&p| name(p)).collect::<Vec<_>>().join(" -> ");
+
+        table.row(vec![
+            ndx.into(),
+            task.name.as_str().into(),
+            wait.name().into(),
+            if stale { format!("{} (stale)", on) } else { on }.into(),
+            format!("{} ({})", chain, end).into(),
+        ])?;
+    }
+
+    for cycle in &cycles {
+        let mut names = cycle.iter().map(|&p| name(p)).collect::<Vec<_>>();
+        names.push(name(cycle[0]));
+        humility::msg!("deadlock: {}", names.join(" -> "));
+    }
+
+    if edges.is_empty() {
+        humility::msg!("no tasks are blocked on other tasks");
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "ipc",
+            archive: Archive::Required,
+            attach: Attach::Any,
+            validate: Validate::Booted,
+            run: ipc,
+        },
+        IpcArgs::command(),
+    )
+}
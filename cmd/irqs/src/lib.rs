@@ -0,0 +1,220 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility irqs`
+//!
+//! `humility irqs` displays the state of interrupts as seen by the NVIC,
+//! along with the task (if any) to which the kernel routes each interrupt.
+//! For each interrupt, its name (from the peripheral definitions in the
+//! archive), whether it is enabled, pending and/or active, its raw priority
+//! and its owning task and notification mask are displayed:
+//!
+//! ```console
+//! % humility irqs
+//! humility: attached via ST-Link V3
+//!  IRQ NAME                   STATE  PRI TASK               NOTIFICATION
+//!   31 i2c1.event             E---  0x00 i2c_driver         0x00000001
+//!   32 i2c1.error             E---  0x00 i2c_driver         0x00000001
+//!   37 usart1.irq             EPA-  0x00 usart_driver       0x00000001
+//!   61 eth.irq                E---  0x00 net                0x00000001
+//! ```
+//!
+//! The `STATE` column denotes enabled (`E`), pending (`P`) and active (`A`);
+//! an interrupt that is pending or active but not owned by any task is
+//! flagged with `!`.  By default, only interrupts that are enabled, pending,
+//! active or owned by a task are shown; to show all interrupts, use `-a`
+//! (`--all`).
+//!
+//! If the firmware counts interrupts (in an array of `u32` named
+//! `IRQ_COUNTS`, indexed by IRQ number), the count for each interrupt is
+//! also displayed.  To additionally determine the rate of each interrupt,
+//! use `-i` (`--interval`) to specify an interval (in milliseconds) over
+//! which to measure it; this is useful for finding interrupt storms:
+//!
+//! ```console
+//! % humility irqs -i 1000
+//! humility: attached via ST-Link V3
+//!  IRQ NAME                   STATE  PRI TASK               NOTIFICATION      COUNT   RATE/s
+//!   31 i2c1.event             E---  0x00 i2c_driver         0x00000001      1892231  40182.0
+//!   ...
+//! ```
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::kernel::KernelState;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::nvic::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[clap(name = "irqs", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct IrqsArgs {
+    /// show all interrupts, not just those that are in use
+    #[clap(long, short)]
+    all: bool,
+
+    /// measure interrupt rates over the specified interval
+    #[clap(
+        long, short, value_name = "ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    interval: Option<u64>,
+}
+
+fn counts(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+) -> Result<Option<Vec<u32>>> {
+    let var = match hubris.lookup_variable("IRQ_COUNTS") {
+        Ok(var) => var,
+        Err(_) => return Ok(None),
+    };
+
+    if var.size % 4 != 0 {
+        bail!("IRQ_COUNTS has unexpected size {}", var.size);
+    }
+
+    let mut buf = vec![0u8; var.size];
+    core.read_8(var.addr, &mut buf)?;
+
+    Ok(Some(
+        buf.chunks_exact(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect(),
+    ))
+}
+
+fn irqs(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = IrqsArgs::try_parse_from(subargs)?;
+
+    core.halt()?;
+    let kernel = KernelState::read(hubris, core);
+    core.run()?;
+    let kernel = kernel?;
+
+    let owners = kernel.irq_owners();
+
+    let nvic = nvic_read(core)?;
+    let before = counts(hubris, core)?;
+
+    let rates = match (subargs.interval, &before) {
+        (Some(_), None) => {
+            bail!("firmware does not count interrupts; cannot measure rates")
+        }
+        (Some(interval), Some(before)) => {
+            let started = Instant::now();
+            thread::sleep(Duration::from_millis(interval));
+
+            let after = counts(hubris, core)?.unwrap();
+            let elapsed = started.elapsed().as_secs_f64();
+
+            Some(
+                before
+                    .iter()
+                    .zip(after.iter())
+                    .map(|(b, a)| a.wrapping_sub(*b) as f64 / elapsed)
+                    .collect::<Vec<_>>(),
+            )
+        }
+        (None, _) => None,
+    };
+
+    print!(
+        "{:>4} {:22} {:5} {:>4} {:18} {:12}",
+        "IRQ", "NAME", "STATE", "PRI", "TASK", "NOTIFICATION"
+    );
+
+    if before.is_some() {
+        print!(" {:>10}", "COUNT");
+    }
+
+    if rates.is_some() {
+        print!(" {:>8}", "RATE/s");
+    }
+
+    println!();
+
+    for irq in &nvic {
+        let owner = owners.get(&irq.irq);
+        let count = before.as_ref().and_then(|c| c.get(irq.irq as usize));
+
+        if !subargs.all
+            && !irq.enabled
+            && !irq.pending
+            && !irq.active
+            && owner.is_none()
+            && count.map_or(true, |&c| c == 0)
+        {
+            continue;
+        }
+
+        let state = format!(
+            "{}{}{}{}",
+            if irq.enabled { "E" } else { "-" },
+            if irq.pending { "P" } else { "-" },
+            if irq.active { "A" } else { "-" },
+            if (irq.pending || irq.active) && owner.is_none() {
+                "!"
+            } else {
+                "-"
+            }
+        );
+
+        let name = hubris
+            .manifest
+            .irq_names
+            .get(&irq.irq)
+            .map(|n| n.as_str())
+            .unwrap_or("-");
+
+        let (task, mask) = match owner {
+            Some((ndx, mask)) => (
+                kernel.tasks[*ndx as usize].name.as_str(),
+                format!("0x{:08x}", mask),
+            ),
+            None => ("-", "-".to_string()),
+        };
+
+        print!(
+            "{:>4} {:22} {:5} 0x{:02x} {:18} {:12}",
+            irq.irq, name, state, irq.priority, task, mask
+        );
+
+        if let Some(count) = count {
+            print!(" {:>10}", count);
+        }
+
+        if let Some(rate) = rates.as_ref().and_then(|r| r.get(irq.irq as usize))
+        {
+            print!(" {:>8.1}", rate);
+        }
+
+        println!();
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "irqs",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: irqs,
+        },
+        IrqsArgs::command(),
+    )
+}
@@ -31,6 +31,81 @@
 //! Task #7 Divide-by-zero
 //! ```
 //!
+//! Tasks that use deferred formatting send the index of an interned format
+//! string followed by the values of its arguments rather than a formatted
+//! message; the format strings are contained in the archive, and messages
+//! can be reconstructed by specifying the stimulus port that carries them
+//! with `--deferred`.  If more than one task has interned format strings,
+//! the task must also be specified with `--task`:
+//!
+//! ```console
+//! % humility itm -ea --deferred 1
+//! humility: attached via ST-Link
+//! humility: core halted
+//! humility: core resumed
+//! humility: ITM synchronization packet found at offset 6
+//! 1042 INFO  sequencer: powered on in 12 ms
+//! 1187 WARN  rail V3P3_SYS: output voltage 3.401 out of range
+//! ```
+//!
+//! Each message consists of the index of its format string (as a
+//! little-endian `u16`), the arguments of the task's timestamp format (if
+//! any), and then the arguments of the message.  Parameters are of the form
+//! `{=type}`, `{N=type}` or `{=type:hint}`, where `type` is an integer or
+//! floating point type, `bool`, `str`, `[u8]`, `[u8; N]`, `istr` (an
+//! interned string) or `?` (a value with its own format string), and `hint`
+//! is one of `x`, `X`, `#x`, `#X`, `b` or `#b`.
+//!
+//! To correlate the phases of a test running on the host with what the
+//! target emits, the host can inject markers into the ITM stream:  the
+//! debugger writes a marker's text to a stimulus port (port 29 by
+//! convention) exactly as the target would, and it appears in the stream
+//! in sequence with the target's own output.  To decode markers, specify
+//! their port with `--markers`; to inject a marker, specify its text with
+//! `--mark`.  Because the probe cannot generally be shared with a running
+//! `humility itm -a`, markers can instead be injected by the ingesting
+//! Humility itself:  with `--markers-from`, each line appended to the
+//! specified file (e.g., by a test harness) is injected as a marker.
+//! Markers are displayed with the time at which they were received:
+//!
+//! ```console
+//! % humility itm -ea --markers 29 --markers-from /tmp/markers &
+//! % echo "test 7 begins" >> /tmp/markers
+//! ...
+//! --- 12.416821s: test 7 begins ---
+//! Task #7 Divide-by-zero
+//! ```
+//!
+//! Markers are also decoded by `humility trace`.
+//!
+//! On boards on which neither SWO nor the parallel trace port is routed,
+//! ITM can instead be captured into an on-chip trace buffer -- either an
+//! Embedded Trace Buffer (ETB) or a Trace Memory Controller configured as
+//! an Embedded Trace FIFO (ETF) -- and read out over the debug port.  To
+//! start capture into the buffer, use `--buffer` along with `--enable`; to
+//! stop capture and decode the buffer's contents, use `--drain`.  The
+//! buffer is circular:  if more trace has been captured than the buffer can
+//! hold, the oldest trace is lost (and a message indicates as much).  The
+//! raw contents of the buffer can be saved with `--save` for later decoding
+//! with `--ingest`:
+//!
+//! ```console
+//! % humility itm -e --buffer
+//! humility: attached via ST-Link
+//! humility: core halted
+//! humility: ITM capturing into TMC at 0x5c014000
+//! humility: core resumed
+//! % humility itm --drain --save /tmp/trace.bin
+//! humility: attached via ST-Link
+//! humility: core halted
+//! humility: drained 4096 bytes from TMC at 0x5c014000
+//! humility: trace buffer wrapped; oldest trace has been lost
+//! humility: raw trace saved to /tmp/trace.bin
+//! humility: ITM synchronization packet found at offset 18
+//! Task #7 Divide-by-zero
+//! humility: core resumed
+//! ```
+//!
 
 use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
@@ -38,11 +113,13 @@ use clap::{CommandFactory, Parser};
 use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::attach_live;
+use humility_cmd::deferred::{self, DeferredDecoder};
 use humility_cmd::{Archive, Args, Command};
 use humility_cortex::debug::*;
 use humility_cortex::dwt::*;
 use humility_cortex::itm::*;
 use humility_cortex::scs::*;
+use humility_cortex::tmc::*;
 use humility_cortex::tpiu::*;
 use std::fs::File;
 use std::io::Read;
@@ -84,6 +161,78 @@ struct ItmArgs {
         parse(try_from_str = parse_int::parse),
     )]
     clockscaler: Option<u16>,
+    /// decode deferred-format messages on the specified stimulus port
+    #[clap(long, value_name = "port",
+        parse(try_from_str = parse_int::parse),
+    )]
+    deferred: Option<u8>,
+    /// task whose format strings are used for deferred-format messages
+    #[clap(long, value_name = "task", requires = "deferred")]
+    task: Option<String>,
+    /// decode markers on the specified stimulus port
+    #[clap(long, value_name = "port",
+        parse(try_from_str = parse_int::parse),
+    )]
+    markers: Option<u8>,
+    /// inject a marker on the marker port
+    #[clap(
+        long,
+        value_name = "text",
+        requires = "markers",
+        conflicts_with = "ingest"
+    )]
+    mark: Option<String>,
+    /// inject each line appended to the specified file as a marker
+    #[clap(long, value_name = "file", requires_all = &["markers", "attach"])]
+    markers_from: Option<String>,
+    /// capture into the on-chip trace buffer (ETF/ETB) rather than via SWO
+    #[clap(
+        long,
+        requires = "enable",
+        conflicts_with_all = &["attach", "clockscaler"]
+    )]
+    buffer: bool,
+    /// stop capture into the on-chip trace buffer and decode its contents
+    #[clap(
+        long,
+        conflicts_with_all = &["probe", "enable", "disable", "ingest", "attach"]
+    )]
+    drain: bool,
+    /// save the raw contents of the drained trace buffer to a file
+    #[clap(long, value_name = "filename", requires = "drain")]
+    save: Option<String>,
+}
+
+fn decoder<'a>(
+    hubris: &'a HubrisArchive,
+    subargs: &ItmArgs,
+) -> Result<Option<DeferredDecoder<'a>>> {
+    if subargs.deferred.is_none() {
+        return Ok(None);
+    }
+
+    if !hubris.loaded() {
+        bail!("must provide an archive to decode deferred-format messages");
+    }
+
+    let task = deferred::task(hubris, subargs.task.as_deref())?;
+
+    Ok(Some(DeferredDecoder::new(hubris, task)))
+}
+
+fn decode(decoder: &mut DeferredDecoder, payload: &[u8]) {
+    for message in decoder.push(payload) {
+        match message {
+            Ok(message) => println!("{}", message),
+            Err(err) => humility::msg!("{}", err),
+        }
+    }
+}
+
+fn marker(markers: &mut ITMMarkers, packet: &ITMPacket, payload: &[u8]) {
+    for marker in markers.push(payload) {
+        println!("--- {:.6}s: {} ---", packet.time, marker);
+    }
 }
 
 fn itmcmd_probe(core: &mut dyn Core, coreinfo: &CoreInfo) -> Result<()> {
@@ -110,6 +259,15 @@ fn itmcmd_probe(core: &mut dyn Core, coreinfo: &CoreInfo) -> Result<()> {
         _ => {}
     }
 
+    if let Some(buffer) = TraceBuffer::find(coreinfo) {
+        humility::msg!(
+            "trace buffer: {:?} at {:#x}, {} bytes",
+            buffer.kind,
+            buffer.base,
+            buffer.size(core)?
+        );
+    }
+
     Ok(())
 }
 
@@ -138,12 +296,33 @@ fn itmcmd_disable(core: &mut dyn Core) -> Result<()> {
     Ok(())
 }
 
-fn itmcmd_ingest(subargs: &ItmArgs, filename: &str) -> Result<()> {
-    let file = File::open(filename)?;
-    let traceid = if subargs.bypass { None } else { Some(subargs.traceid) };
+//
+// Decodes ITM data from the specified source, printing the output of
+// stimulus ports (and any markers and deferred-format messages).
+//
+fn itmcmd_process(
+    hubris: &HubrisArchive,
+    subargs: &ItmArgs,
+    traceid: Option<u8>,
+    readnext: impl FnMut() -> Result<Option<(u8, f64)>>,
+) -> Result<()> {
+    let mut decoder = decoder(hubris, subargs)?;
+    let mut markers = ITMMarkers::default();
 
     let process = |packet: &ITMPacket| -> Result<()> {
-        if let ITMPayload::Instrumentation { payload, .. } = &packet.payload {
+        if let ITMPayload::Instrumentation { payload, port } = &packet.payload {
+            if Some(*port as u8) == subargs.markers {
+                marker(&mut markers, packet, payload);
+                return Ok(());
+            }
+
+            if let Some(decoder) = decoder.as_mut() {
+                if Some(*port as u8) == subargs.deferred {
+                    decode(decoder, payload);
+                    return Ok(());
+                }
+            }
+
             for p in payload {
                 print!("{}", *p as char);
             }
@@ -152,6 +331,17 @@ fn itmcmd_ingest(subargs: &ItmArgs, filename: &str) -> Result<()> {
         Ok(())
     };
 
+    itm_ingest(traceid, readnext, process)
+}
+
+fn itmcmd_ingest(
+    hubris: &HubrisArchive,
+    subargs: &ItmArgs,
+    filename: &str,
+) -> Result<()> {
+    let file = File::open(filename)?;
+    let traceid = if subargs.bypass { None } else { Some(subargs.traceid) };
+
     let mut rdr = csv::Reader::from_reader(file);
 
     match rdr.headers() {
@@ -159,18 +349,14 @@ fn itmcmd_ingest(subargs: &ItmArgs, filename: &str) -> Result<()> {
             type SaleaeTraceRecord = (f64, u8, Option<String>, Option<String>);
             let mut iter = rdr.deserialize();
 
-            itm_ingest(
-                traceid,
-                || {
-                    if let Some(line) = iter.next() {
-                        let record: SaleaeTraceRecord = line?;
-                        Ok(Some((record.1, record.0)))
-                    } else {
-                        Ok(None)
-                    }
-                },
-                process,
-            )
+            itmcmd_process(hubris, subargs, traceid, || {
+                if let Some(line) = iter.next() {
+                    let record: SaleaeTraceRecord = line?;
+                    Ok(Some((record.1, record.0)))
+                } else {
+                    Ok(None)
+                }
+            })
         }
         Err(_) => {
             humility::msg!("not a Saleae trace file; assuming raw input");
@@ -178,32 +364,77 @@ fn itmcmd_ingest(subargs: &ItmArgs, filename: &str) -> Result<()> {
             let mut file = File::open(filename)?;
             let mut buffer = [0; 1];
 
-            itm_ingest(
-                traceid,
-                || {
-                    let nbytes = file.read(&mut buffer)?;
-
-                    match nbytes {
-                        1 => Ok(Some((buffer[0], 0.0))),
-                        0 => Ok(None),
-                        _ => {
-                            panic!("illegal read");
-                        }
+            itmcmd_process(hubris, subargs, traceid, || {
+                let nbytes = file.read(&mut buffer)?;
+
+                match nbytes {
+                    1 => Ok(Some((buffer[0], 0.0))),
+                    0 => Ok(None),
+                    _ => {
+                        panic!("illegal read");
                     }
-                },
-                process,
-            )
+                }
+            })
         }
     }
 }
 
+fn itmcmd_drain(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    coreinfo: &CoreInfo,
+    subargs: &ItmArgs,
+) -> Result<()> {
+    let buffer = match TraceBuffer::find(coreinfo) {
+        Some(buffer) => buffer,
+        None => bail!("no on-chip trace buffer (ETF/ETB) found"),
+    };
+
+    buffer.stop(core)?;
+    let contents = buffer.drain(core)?;
+
+    humility::msg!(
+        "drained {} bytes from {:?} at {:#x}",
+        contents.data.len(),
+        buffer.kind,
+        buffer.base
+    );
+
+    if contents.wrapped {
+        humility::msg!("trace buffer wrapped; oldest trace has been lost");
+    }
+
+    if let Some(filename) = &subargs.save {
+        std::fs::write(filename, &contents.data)
+            .with_context(|| format!("failed to write {}", filename))?;
+        humility::msg!("raw trace saved to {}", filename);
+    }
+
+    let mut data = contents.data.iter();
+
+    itmcmd_process(hubris, subargs, Some(subargs.traceid), || {
+        Ok(data.next().map(|datum| (*datum, 0.0)))
+    })
+}
+
 fn itmcmd_ingest_attached(
+    hubris: &HubrisArchive,
     core: &mut dyn Core,
     coreinfo: &CoreInfo,
     subargs: &ItmArgs,
 ) -> Result<()> {
     let mut bytes: Vec<u8> = vec![];
     let mut ndx = 0;
+    let mut decoder = decoder(hubris, subargs)?;
+    let mut markers = ITMMarkers::default();
+
+    let mut file = match &subargs.markers_from {
+        Some(filename) => Some(
+            ITMMarkerFile::open(filename)
+                .with_context(|| format!("failed to open {}", filename))?,
+        ),
+        None => None,
+    };
 
     let traceid = if coreinfo.address(CoreSightComponent::SWO).is_some() {
         None
@@ -217,6 +448,10 @@ fn itmcmd_ingest_attached(
         traceid,
         || {
             while ndx == bytes.len() {
+                if let (Some(file), Some(port)) = (&mut file, subargs.markers) {
+                    file.inject(core, port)?;
+                }
+
                 bytes = core.read_swv()?;
                 ndx = 0;
             }
@@ -227,6 +462,18 @@ fn itmcmd_ingest_attached(
             if let ITMPayload::Instrumentation { payload, port } =
                 &packet.payload
             {
+                if Some(*port as u8) == subargs.markers {
+                    marker(&mut markers, packet, payload);
+                    return Ok(());
+                }
+
+                if let Some(decoder) = decoder.as_mut() {
+                    if Some(*port as u8) == subargs.deferred {
+                        decode(decoder, payload);
+                        return Ok(());
+                    }
+                }
+
                 if *port > 1 {
                     println!("{:x?}", payload);
                     return Ok(());
@@ -257,7 +504,7 @@ fn itmcmd(
     }
 
     if let Some(ingest) = &subargs.ingest {
-        match itmcmd_ingest(subargs, ingest) {
+        match itmcmd_ingest(hubris, subargs, ingest) {
             Err(e) => {
                 bail!("failed to ingest {}: {}", ingest, e);
             }
@@ -293,35 +540,83 @@ fn itmcmd(
         }
 
         //
-        // By default, we enable all logging (ports 0-7).
+        // By default, we enable all logging (ports 0-7), along with the ports
+        // carrying deferred-format messages and markers (if any).
         //
-        let stim = 0x0000_000f;
-        let clockscaler = match subargs.clockscaler {
-            Some(value) => value,
-            None => {
-                if !hubris.loaded() {
+        let mut stim = 0x0000_000f;
+
+        for port in [subargs.deferred, subargs.markers].iter().flatten() {
+            if *port >= 32 {
+                bail!("invalid stimulus port {}", port);
+            }
+
+            stim |= 1 << port;
+        }
+
+        if subargs.buffer {
+            let buffer = match TraceBuffer::find(&coreinfo) {
+                Some(buffer) => buffer,
+                None => {
                     core.run()?;
-                    bail!("must provide an archive");
+                    bail!("no on-chip trace buffer (ETF/ETB) found");
                 }
+            };
+
+            rval = itm_enable_buffer(core, &coreinfo, &buffer, traceid, stim);
 
-                swoscaler(hubris, core).with_context(|| {
-                    "CPU frequency cannot be determined; the clock scaler \
-                    must be set manually. To determine the clock scaler, \
-                    take the CPU frequency in megahertz divide by 2, and \
-                    subtract 1 (e.g., 400 MHz yields a clock scaler of 199), \
-                    and specify via \"-c\" (e.g. \"-c 199\")"
-                })?
+            if rval.is_ok() {
+                humility::msg!(
+                    "ITM capturing into {:?} at {:#x}",
+                    buffer.kind,
+                    buffer.base
+                );
             }
-        };
+        } else {
+            let clockscaler = match subargs.clockscaler {
+                Some(value) => value,
+                None => {
+                    if !hubris.loaded() {
+                        core.run()?;
+                        bail!("must provide an archive");
+                    }
+
+                    swoscaler(hubris, core).with_context(|| {
+                        "CPU frequency cannot be determined; the clock \
+                        scaler must be set manually. To determine the clock \
+                        scaler, take the CPU frequency in megahertz divide \
+                        by 2, and subtract 1 (e.g., 400 MHz yields a clock \
+                        scaler of 199), and specify via \"-c\" \
+                        (e.g. \"-c 199\")"
+                    })?
+                }
+            };
 
-        rval = itm_enable_explicit(core, &coreinfo, clockscaler, traceid, stim);
+            rval = itm_enable_explicit(
+                core,
+                &coreinfo,
+                clockscaler,
+                traceid,
+                stim,
+            );
+        }
+    }
+
+    if subargs.drain {
+        rval = itmcmd_drain(hubris, core, &coreinfo, subargs);
     }
 
     core.run()?;
     humility::msg!("core resumed");
 
+    if let (Some(text), Some(port)) = (&subargs.mark, subargs.markers) {
+        if rval.is_ok() {
+            itm_marker_write(core, port, text)?;
+            humility::msg!("injected marker \"{}\" on port {}", text, port);
+        }
+    }
+
     if rval.is_ok() && subargs.attach {
-        match itmcmd_ingest_attached(core, &coreinfo, subargs) {
+        match itmcmd_ingest_attached(hubris, core, &coreinfo, subargs) {
             Err(e) => {
                 bail!("failed to ingest from attached device: {}", e);
             }
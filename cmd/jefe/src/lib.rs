@@ -78,7 +78,44 @@
 //! To restart a task that has had a fault injected, again use the `-r` flag to
 //! change its disposition back to restart.
 //!
-//! Finally, to start a task that is not started by default, use the `-s` flag.
+//! To restart a task outright, use the `-R` flag, which injects a fault and
+//! then changes the disposition back to restart -- so `jefe` restarts the
+//! task immediately (and the task's generation is incremented):
+//!
+//! ```console
+//! % humility jefe -R pong
+//! humility: attached via ST-Link
+//! restart pong? [y/N] y
+//! humility: successfully restarted pong
+//! ```
+//!
+//! Because injecting a fault and restarting a task are disruptive, they must
+//! be confirmed interactively unless `-y` (`--yes`) is specified.
+//!
+//! To start a task that is not started by default, use the `-s` flag.
+//!
+//! To see what `jefe` knows about each task, use the `-l` (`--list`) flag.
+//! For each task, this shows the number of times that the task has been
+//! restarted (that is, its generation, which wraps at 256), its restart
+//! disposition within `jefe`, and -- if the task is faulted -- the fault:
+//!
+//! ```console
+//! % humility jefe -l
+//! humility: attached via ST-Link
+//! ID TASK                 GEN DISPOSITION FAULT
+//!  0 jefe                   0 -           -
+//!  1 rcc_driver             0 Restart     -
+//! ...
+//!  7 pong                   0 Hold        killed by jefe/gen0
+//!  8 ping                 121 Restart     -
+//! ```
+//!
+//! The disposition is decoded from the archive's definition of `jefe`'s
+//! per-task state; if `jefe` in the archive has no such state, the
+//! disposition is not shown.  Because the kernel does not retain a fault
+//! once a task is restarted, the fault is only shown for tasks that are
+//! faulted (e.g., because they are being held).  `-l` may also be used on a
+//! dump.
 //!
 
 use anyhow::{anyhow, bail, Result};
@@ -86,8 +123,12 @@ use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::core::Core;
 use humility::hubris::*;
+use humility_cmd::doppel::{FaultInfo, TaskState};
 use humility_cmd::jefe::{send_request, JefeRequest};
-use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cmd::kernel::KernelState;
+use humility_cmd::output::{Column, Table};
+use humility_cmd::reflect::{self, Value};
+use humility_cmd::{confirm, Archive, Args, Attach, Command, Validate};
 use std::num::NonZeroU32;
 
 #[derive(Parser, Debug)]
@@ -104,6 +145,13 @@ struct JefeArgs {
     #[clap(long, short, conflicts_with_all = &["start", "release", "hold"])]
     fault: bool,
 
+    /// restart the specified task
+    #[clap(
+        long, short = 'R',
+        conflicts_with_all = &["fault", "start", "release", "hold"]
+    )]
+    restart: bool,
+
     /// start the specified task
     #[clap(long, short, conflicts_with_all = &["release", "hold"])]
     start: bool,
@@ -116,18 +164,183 @@ struct JefeArgs {
     #[clap(long, short)]
     release: bool,
 
-    task: String,
+    /// do not prompt for confirmation of a fault or restart
+    #[clap(long, short)]
+    yes: bool,
+
+    /// list restart counts, dispositions and faults of all tasks
+    #[clap(
+        long, short,
+        conflicts_with_all = &["fault", "restart", "start", "hold", "release"]
+    )]
+    list: bool,
+
+    #[clap(required_unless_present = "list", conflicts_with = "list")]
+    task: Option<String>,
+}
+
+//
+// Locates jefe's per-task state, if it has any: a variable in the
+// supervisor that is an array of structures (one per task), each having a
+// `disposition` member.
+//
+fn state_table(hubris: &HubrisArchive) -> Option<&HubrisVariable> {
+    let supervisor = HubrisTask::Task(0);
+
+    hubris
+        .qualified_variables()
+        .map(|(_, v)| v)
+        .filter(|v| HubrisTask::from(v.goff) == supervisor)
+        .find(|v| {
+            hubris
+                .lookup_array(v.goff)
+                .and_then(|a| hubris.lookup_struct(a.goff))
+                .and_then(|s| s.lookup_member("disposition"))
+                .is_ok()
+        })
+}
+
+fn dispositions(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    variable: &HubrisVariable,
+) -> Result<Vec<String>> {
+    let mut buf = vec![0u8; variable.size];
+    core.read_8(variable.addr, &mut buf)?;
+
+    let value = reflect::load_value(
+        hubris,
+        &buf,
+        hubris.lookup_type(variable.goff)?,
+        0,
+    )?;
+
+    value
+        .as_array()?
+        .iter()
+        .map(|elem| {
+            Ok(elem.as_struct()?["disposition"].as_enum()?.disc().to_string())
+        })
+        .collect()
+}
+
+fn describe_fault(fault: FaultInfo) -> String {
+    let at = |address: Option<u32>| match address {
+        Some(address) => format!(" at 0x{:x}", address),
+        None => String::new(),
+    };
+
+    match fault {
+        FaultInfo::MemoryAccess { address, .. } => {
+            format!("memory fault{}", at(address))
+        }
+        FaultInfo::StackOverflow { address } => {
+            format!("stack overflow{}", at(Some(address)))
+        }
+        FaultInfo::BusError { address, .. } => {
+            format!("bus error{}", at(address))
+        }
+        FaultInfo::DivideByZero => "divide by zero".to_string(),
+        FaultInfo::IllegalText => "illegal text".to_string(),
+        FaultInfo::IllegalInstruction => "illegal instruction".to_string(),
+        FaultInfo::InvalidOperation(code) => {
+            format!("invalid operation 0x{:x}", code)
+        }
+        FaultInfo::SyscallUsage(err) => format!("syscall usage: {:?}", err),
+        FaultInfo::Panic => "panicked".to_string(),
+        FaultInfo::Injected(id) => format!("killed by {}", id),
+        FaultInfo::FromServer(id, reason) => {
+            format!("fault from {}: {:?}", id, reason)
+        }
+    }
+}
+
+fn list(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+) -> Result<()> {
+    let table = state_table(hubris);
+
+    core.halt()?;
+
+    let state = KernelState::read(hubris, core).and_then(|kernel| {
+        let dispositions = match table {
+            Some(variable) => Some(dispositions(hubris, core, variable)?),
+            None => None,
+        };
+
+        Ok((kernel, dispositions))
+    });
+
+    core.run()?;
+
+    let (kernel, dispositions) = state?;
+
+    if dispositions.is_none() {
+        humility::msg!(
+            "jefe does not have per-task state; not showing dispositions"
+        );
+    }
+
+    let mut out = Table::new(
+        args.format,
+        vec![
+            Column::new("id", 2).right(),
+            Column::new("task", 15),
+            Column::new("gen", 8).right(),
+            Column::new("disposition", 11),
+            Column::new("fault", 5),
+        ],
+    );
+
+    for ktask in &kernel.tasks {
+        let ndx = ktask.index as usize;
+
+        //
+        // jefe does not have a disposition for itself.
+        //
+        let disposition = match &dispositions {
+            Some(d) if ndx != 0 => d.get(ndx).map(|d| d.as_str()),
+            _ => None,
+        };
+
+        let fault = match ktask.task.state {
+            TaskState::Faulted { fault, .. } => Some(describe_fault(fault)),
+            TaskState::Healthy(_) => None,
+        };
+
+        out.row(vec![
+            ktask.index.into(),
+            ktask.name.as_str().into(),
+            u32::from(ktask.task.generation).into(),
+            disposition.into(),
+            fault.into(),
+        ])?;
+    }
+
+    Ok(())
 }
 
 fn jefe(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = JefeArgs::try_parse_from(subargs)?;
 
-    let request = if subargs.fault {
+    if subargs.list {
+        return list(hubris, core, args);
+    }
+
+    if core.is_dump() {
+        bail!("cannot change disposition of a task in a dump");
+    }
+
+    let name = subargs.task.as_ref().unwrap();
+
+    let request = if subargs.fault || subargs.restart {
         JefeRequest::Fault
     } else if subargs.start {
         JefeRequest::Start
@@ -136,12 +349,14 @@ fn jefe(
     } else if subargs.release {
         JefeRequest::Release
     } else {
-        bail!("one of fault, start, hold, or release must be specified");
+        bail!(
+            "one of fault, restart, start, hold, or release must be specified"
+        );
     };
 
     let task = hubris
-        .lookup_task(&subargs.task)
-        .ok_or_else(|| anyhow!("couldn't find task {}", subargs.task))?;
+        .lookup_task(name)
+        .ok_or_else(|| anyhow!("couldn't find task {}", name))?;
 
     let id = match task {
         HubrisTask::Kernel => {
@@ -156,9 +371,27 @@ fn jefe(
         }
     };
 
+    if subargs.fault || subargs.restart {
+        let prompt = format!(
+            "{} {}?",
+            if subargs.restart { "restart" } else { "fault" },
+            name
+        );
+
+        if !subargs.yes && !confirm(&prompt)? {
+            bail!("operation not confirmed");
+        }
+    }
+
     send_request(hubris, core, request, id, subargs.timeout)?;
 
-    humility::msg!("successfully changed disposition for {}", subargs.task);
+    if subargs.restart {
+        send_request(hubris, core, JefeRequest::Release, id, subargs.timeout)?;
+        humility::msg!("successfully restarted {}", name);
+        return Ok(());
+    }
+
+    humility::msg!("successfully changed disposition for {}", name);
 
     Ok(())
 }
@@ -168,7 +401,7 @@ pub fn init() -> (Command, ClapCommand<'static>) {
         Command::Attached {
             name: "jefe",
             archive: Archive::Required,
-            attach: Attach::LiveOnly,
+            attach: Attach::Any,
             validate: Validate::Booted,
             run: jefe,
         },
@@ -0,0 +1,806 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility log`
+//!
+//! `humility log` merges the logging available on a target into a single
+//! stream, with each record tagged by the task that logged it:  entries in
+//! ring buffers, lines written to RTT (SEGGER Real-Time Transfer) channels,
+//! task faults as recorded by the kernel (including panic messages), and
+//! events in the kernel's event log.  Each record is stamped with the
+//! kernel's time (in ticks) at which it was observed:
+//!
+//! ```console
+//! % humility log
+//! humility: attached via ST-Link
+//! TICKS      TASK            LEVEL SOURCE  MESSAGE
+//! 1873024    i2c_driver      DEBUG ringbuf __RINGBUF:211 Reset(I2C2)
+//! 1873024    net             DEBUG ringbuf __RINGBUF:134 Read(IADR5, 0x4000)
+//! 1873024    thermal         INFO  rtt     fan 0 set to 40%
+//! 1873024    pong            ERROR fault   panic: not ready
+//! ```
+//!
+//! Ring buffer entries carry no timestamp of their own; within a single
+//! read, they are ordered by generation and then by position in their
+//! buffer, as with `humility ringbuf --merge`.  Ring buffer entries are
+//! logged at level `debug`, RTT output at level `info`, and faults at level
+//! `error`.  To display only records from particular tasks, use `--task`
+//! (which may be repeated); to display only records at or above a particular
+//! level, use `--level`:
+//!
+//! ```console
+//! % humility log --task pong --level error
+//! ```
+//!
+//! If the kernel was built with event logging, events from the kernel's
+//! event log (e.g., task faults and IPC errors) are also included, with
+//! their event codes symbolized.  Kernel events are stamped with the time at
+//! which they occurred rather than the time at which they were observed;
+//! events that indicate a fault are logged at level `error`, those that
+//! indicate an error at level `warn`, and all others at level `info`:
+//!
+//! ```console
+//! % humility log --task pong
+//! humility: attached via ST-Link
+//! TICKS      TASK            LEVEL SOURCE  MESSAGE
+//! 1872998    pong            WARN  klog    IpcError(BadSender)
+//! 1873024    pong            ERROR fault   panic: not ready
+//! ```
+//!
+//! If more events have been logged than the event log can hold, the oldest
+//! are lost (and a message indicates as much).
+//!
+//! To continue to display new records as they are logged on a live system,
+//! use `-f` (`--follow`), optionally specifying the polling interval in
+//! milliseconds with `-i` (`--interval`).  When following, ITM stimulus
+//! ports can also be ingested with `--itm` (ITM must already be enabled,
+//! e.g. via `humility itm -e`); output on port 0 is attributed to the
+//! kernel.  Deferred-format messages on an ITM stimulus port can be decoded
+//! by specifying the port with `--deferred` (see `humility itm` for
+//! details); their level is that of the message.
+//!
+//! To display the host's wall-clock time of each record rather than the
+//! kernel's time, use `-w` (`--wallclock`).  The kernel's time is sampled
+//! against the host's clock to fit a model of the target's clock (its offset
+//! and, when following for long enough to measure it, its drift relative to
+//! the host), by which kernel time is translated to wall-clock time:
+//!
+//! ```console
+//! % humility log -w --task thermal
+//! humility: attached via ST-Link
+//! humility: synchronized with target clock to within 0.4ms
+//! TIME                     TASK            LEVEL SOURCE  MESSAGE
+//! 2022-06-14T18:02:11.482Z thermal         INFO  rtt     fan 0 set to 40%
+//! ```
+//!
+//! When following, a reset of the target (as indicated by the kernel's
+//! system time going backwards) is noted in the log, and records from the
+//! new boot are displayed in full.
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{ArgEnum, CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::clock::{rfc3339, ClockSync};
+use humility_cmd::deferred::{self, DeferredDecoder};
+use humility_cmd::doppel::{RingbufEntry, TaskId, TaskState};
+use humility_cmd::kernel::{describe_fault, Heartbeat, KernelState, Reset};
+use humility_cmd::klog;
+use humility_cmd::output::{Column, Table};
+use humility_cmd::reflect::Format;
+use humility_cmd::ringbuf::{self, RingbufVariable};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::itm::*;
+use humility_cortex::scs::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::thread;
+use std::time::{Duration, Instant};
+
+//
+// The maximum number of RTT up buffers that we will read per control block;
+// anything beyond this is almost certainly a corrupt control block.
+//
+const RTT_MAX_BUFFERS: u32 = 16;
+const RTT_ID: &[u8] = b"SEGGER RTT";
+
+//
+// The number of samples of the target's clock that we take to synchronize
+// with it initially; when following, we take another sample with each poll.
+//
+const CLOCK_SAMPLES: usize = 16;
+
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+
+    fn from_tag(tag: &str) -> Self {
+        match tag {
+            "trace" => Level::Trace,
+            "debug" => Level::Debug,
+            "warn" => Level::Warn,
+            "error" => Level::Error,
+            _ => Level::Info,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "log", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct LogArgs {
+    /// continue to display new records as they are logged
+    #[clap(long, short)]
+    follow: bool,
+
+    /// interval between reads when following, in milliseconds
+    #[clap(
+        long, short, default_value = "1000", value_name = "ms",
+        requires = "follow", parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
+    /// display host wall-clock time instead of ticks
+    #[clap(long, short)]
+    wallclock: bool,
+
+    /// display only records from the specified task
+    #[clap(long, short, value_name = "task", multiple_occurrences = true)]
+    task: Vec<String>,
+
+    /// display only records at or above the specified level
+    #[clap(
+        long,
+        short,
+        arg_enum,
+        default_value = "trace",
+        value_name = "level"
+    )]
+    level: Level,
+
+    /// also ingest ITM stimulus ports
+    #[clap(long, requires = "follow")]
+    itm: bool,
+
+    /// sets ITM trace identifier
+    #[clap(
+        long, default_value = "0x3a", value_name = "identifier",
+        requires = "itm", parse(try_from_str = parse_int::parse)
+    )]
+    traceid: u8,
+
+    /// decode deferred-format messages on the specified ITM stimulus port
+    #[clap(
+        long, value_name = "port", requires = "itm",
+        parse(try_from_str = parse_int::parse)
+    )]
+    deferred: Option<u32>,
+}
+
+struct Record {
+    ticks: Option<u64>,
+    task: String,
+    level: Level,
+    source: &'static str,
+    message: String,
+}
+
+struct Log {
+    table: Table,
+    tasks: Vec<String>,
+    level: Level,
+    ticks: u64,
+    heartbeat: Heartbeat,
+    clock: Option<ClockSync>,
+}
+
+impl Log {
+    fn emit(
+        &mut self,
+        task: &str,
+        level: Level,
+        source: &str,
+        message: &str,
+    ) -> Result<()> {
+        self.emit_at(self.ticks, task, level, source, message)
+    }
+
+    fn emit_at(
+        &mut self,
+        ticks: u64,
+        task: &str,
+        level: Level,
+        source: &str,
+        message: &str,
+    ) -> Result<()> {
+        if level < self.level {
+            return Ok(());
+        }
+
+        if !self.tasks.is_empty() && !self.tasks.iter().any(|t| t == task) {
+            return Ok(());
+        }
+
+        let time = match self.clock.as_ref().and_then(ClockSync::model) {
+            Some(model) => rfc3339(model.wallclock(ticks)).into(),
+            None => ticks.into(),
+        };
+
+        self.table.row(vec![
+            time,
+            task.into(),
+            level.name().into(),
+            source.into(),
+            message.into(),
+        ])
+    }
+}
+
+///
+/// What we have already seen of the target, such that we emit only new
+/// records when following.
+///
+#[derive(Default)]
+struct Seen {
+    /// Generation and count by ring buffer and slot
+    entries: HashMap<(usize, usize), (u16, u32)>,
+    /// Ring buffers that we have failed to read
+    unreadable: HashSet<usize>,
+    /// Faulted tasks by index and generation
+    faults: HashSet<(u32, u32)>,
+    /// Task and partial line by address of RTT buffer descriptor
+    rtt: HashMap<u32, (String, String)>,
+    /// Number of kernel events logged
+    klog: u32,
+}
+
+fn ringbuf_message(
+    hubris: &HubrisArchive,
+    v: &RingbufVariable,
+    entry: &RingbufEntry,
+) -> Result<String> {
+    let fmt = HubrisPrintFormat { hex: true, ..HubrisPrintFormat::default() };
+    let mut dumped = vec![];
+    entry.payload.format(hubris, fmt, &mut dumped)?;
+
+    let name = v.name.rsplit("::").next().unwrap_or(v.name);
+    let payload = String::from_utf8(dumped)?;
+
+    Ok(match entry.count {
+        1 => format!("{}:{} {}", name, entry.line, payload),
+        count => format!("{}:{} {} (x{})", name, entry.line, payload, count),
+    })
+}
+
+fn read_ringbufs(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    ringbufs: &[RingbufVariable],
+    seen: &mut Seen,
+    records: &mut Vec<Record>,
+) -> Result<()> {
+    let mut rows = vec![];
+
+    for (ndx, v) in ringbufs.iter().enumerate() {
+        let ringbuf = match v.read(hubris, core) {
+            Ok(ringbuf) => ringbuf,
+            Err(e) => {
+                if seen.unreadable.insert(ndx) {
+                    humility::msg!(
+                        "ring buffer {} in {}: {}",
+                        v.name,
+                        v.task,
+                        e
+                    );
+                }
+                continue;
+            }
+        };
+
+        for (seq, (slot, entry)) in
+            ringbuf::entries(&ringbuf).into_iter().enumerate()
+        {
+            let val = (entry.generation, entry.count);
+
+            if seen.entries.insert((ndx, slot), val) != Some(val) {
+                let message = ringbuf_message(hubris, v, entry)?;
+                rows.push(((entry.generation, seq), ndx, message));
+            }
+        }
+    }
+
+    rows.sort_by_key(|row| (row.0, row.1));
+
+    for (_, ndx, message) in rows {
+        records.push(Record {
+            ticks: None,
+            task: ringbufs[ndx].task.to_string(),
+            level: Level::Debug,
+            source: "ringbuf",
+            message,
+        });
+    }
+
+    Ok(())
+}
+
+///
+/// Reads any RTT up buffers, consuming their contents on a live target.
+/// Lines are accumulated across reads; a partial line is emitted only once
+/// it is complete.
+///
+fn read_rtt(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    seen: &mut Seen,
+    records: &mut Vec<Record>,
+) -> Result<()> {
+    let variables = match hubris.lookup_variables("_SEGGER_RTT") {
+        Ok(variables) => variables,
+        Err(_) => return Ok(()),
+    };
+
+    for v in variables {
+        let task = &hubris.lookup_module(HubrisTask::from(v.goff))?.name;
+        let mut header = [0u8; 24];
+        core.read_8(v.addr, &mut header)?;
+
+        //
+        // If the control block hasn't been initialized, the task hasn't yet
+        // used RTT.
+        //
+        if !header.starts_with(RTT_ID) {
+            continue;
+        }
+
+        let nbufs = u32::from_le_bytes(header[16..20].try_into().unwrap());
+
+        for i in 0..nbufs.min(RTT_MAX_BUFFERS) {
+            let desc = v.addr + 24 + i * 24;
+            let mut buf = [0u8; 24];
+            core.read_8(desc, &mut buf)?;
+
+            let word = |offs: usize| {
+                u32::from_le_bytes(buf[offs..offs + 4].try_into().unwrap())
+            };
+
+            let (base, size, wr, rd) = (word(4), word(8), word(12), word(16));
+
+            if base == 0 || wr >= size || rd >= size || wr == rd {
+                continue;
+            }
+
+            let ranges = if wr > rd {
+                vec![(rd, wr)]
+            } else {
+                vec![(rd, size), (0, wr)]
+            };
+
+            let mut data = vec![];
+
+            for (from, to) in ranges.into_iter().filter(|(f, t)| f < t) {
+                let mut chunk = vec![0u8; (to - from) as usize];
+                core.read_8(base + from, &mut chunk)?;
+                data.extend_from_slice(&chunk);
+            }
+
+            if !core.is_dump() {
+                core.write_word_32(desc + 16, wr)?;
+            }
+
+            let (_, line) = seen
+                .rtt
+                .entry(desc)
+                .or_insert_with(|| (task.clone(), String::new()));
+
+            line.push_str(&String::from_utf8_lossy(&data));
+
+            while let Some(pos) = line.find('\n') {
+                let message: String = line.drain(..=pos).collect();
+
+                records.push(Record {
+                    ticks: None,
+                    task: task.clone(),
+                    level: Level::Info,
+                    source: "rtt",
+                    message: message.trim_end().to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Reads any new events from the kernel's event log.  Events that indicate
+/// a fault are logged at level `error`; those that indicate an error (e.g.,
+/// an IPC error) at level `warn`; and all others at level `info`.
+///
+fn read_klog(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    kernel: &KernelState,
+    seen: &mut Seen,
+    records: &mut Vec<Record>,
+) -> Result<()> {
+    let variable = match klog::klog(hubris) {
+        Some(variable) => variable,
+        None => return Ok(()),
+    };
+
+    let (events, count) = klog::read(hubris, core, variable, seen.klog)?;
+
+    if let Some(first) = events.first() {
+        if first.seq > seen.klog {
+            humility::msg!(
+                "{} kernel events lost to wrapping of the event log",
+                first.seq - seen.klog
+            );
+        }
+    }
+
+    seen.klog = count;
+
+    for event in events {
+        let task = if event.task == TaskId::KERNEL {
+            "kernel".to_string()
+        } else {
+            match kernel
+                .tasks
+                .iter()
+                .find(|t| t.index as usize == event.task.index())
+            {
+                Some(task) => task.name.clone(),
+                None => event.task.to_string(),
+            }
+        };
+
+        let name = event.name();
+
+        let level = if name.contains("Fault") {
+            Level::Error
+        } else if name.contains("Error") {
+            Level::Warn
+        } else {
+            Level::Info
+        };
+
+        records.push(Record {
+            ticks: Some(event.ticks),
+            task,
+            level,
+            source: "klog",
+            message: event.describe(hubris)?,
+        });
+    }
+
+    Ok(())
+}
+
+fn read_faults(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    kernel: &KernelState,
+    seen: &mut Seen,
+    records: &mut Vec<Record>,
+) {
+    for task in &kernel.tasks {
+        if let TaskState::Faulted { fault, .. } = task.task.state {
+            let generation = u32::from(task.task.generation);
+
+            if seen.faults.insert((task.index, generation)) {
+                records.push(Record {
+                    ticks: None,
+                    task: task.name.clone(),
+                    level: Level::Error,
+                    source: "fault",
+                    message: describe_fault(hubris, core, task, fault),
+                });
+            }
+        }
+    }
+}
+
+fn read(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    ringbufs: &[RingbufVariable],
+    seen: &mut Seen,
+    heartbeat: &mut Heartbeat,
+) -> Result<(u64, Option<Reset>, Vec<Record>)> {
+    let kernel = KernelState::read(hubris, core)?;
+    let mut records = vec![];
+
+    //
+    // If the target has reset, what we have seen is of a previous boot:  ring
+    // buffers and task generations start anew, and must not be mistaken for
+    // entries and faults that we have already emitted.
+    //
+    let reset = heartbeat.beat(kernel.ticks);
+
+    if reset.is_some() {
+        let unreadable = std::mem::take(&mut seen.unreadable);
+        *seen = Seen { unreadable, ..Seen::default() };
+    }
+
+    read_ringbufs(hubris, core, ringbufs, seen, &mut records)?;
+    read_rtt(hubris, core, seen, &mut records)?;
+    read_klog(hubris, core, &kernel, seen, &mut records)?;
+    read_faults(hubris, core, &kernel, seen, &mut records);
+
+    Ok((kernel.ticks, reset, records))
+}
+
+///
+/// Reads all sources, emitting any new records.  We halt once to read
+/// everything, giving as consistent a view across sources as we can.
+///
+fn poll(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    ringbufs: &[RingbufVariable],
+    seen: &mut Seen,
+    log: &mut Log,
+) -> Result<()> {
+    core.halt()?;
+    let rval = read(hubris, core, ringbufs, seen, &mut log.heartbeat);
+    core.run()?;
+
+    let (ticks, reset, records) = rval?;
+    log.ticks = ticks;
+
+    //
+    // We sample the clock with the target running, as the kernel's tick
+    // may not advance while the core is halted.
+    //
+    if let Some(clock) = &mut log.clock {
+        clock.sample(hubris, core)?;
+    }
+
+    if let Some(reset) = reset {
+        log.table.note(&reset.to_string())?;
+    }
+
+    for r in records {
+        let ticks = r.ticks.unwrap_or(log.ticks);
+        log.emit_at(ticks, &r.task, r.level, r.source, &r.message)?;
+    }
+
+    Ok(())
+}
+
+struct Itm<'a> {
+    deferred: Option<(u32, DeferredDecoder<'a>, String)>,
+    lines: HashMap<u32, String>,
+}
+
+impl<'a> Itm<'a> {
+    fn ingest(
+        &mut self,
+        log: &mut Log,
+        port: u32,
+        payload: &[u8],
+    ) -> Result<()> {
+        if let Some((deferred, decoder, task)) = &mut self.deferred {
+            if port == *deferred {
+                for message in decoder.push(payload) {
+                    match message {
+                        Ok(m) => {
+                            let level =
+                                m.level().map_or(Level::Info, Level::from_tag);
+
+                            let message = match &m.timestamp {
+                                Some(ts) => format!("{} {}", ts, m.message),
+                                None => m.message.clone(),
+                            };
+
+                            log.emit(task, level, "itm", &message)?;
+                        }
+                        Err(e) => humility::msg!("{}", e),
+                    }
+                }
+
+                return Ok(());
+            }
+        }
+
+        let task = match port {
+            0 => "kernel".to_string(),
+            _ => format!("itm{}", port),
+        };
+
+        let line = self.lines.entry(port).or_default();
+        line.push_str(&String::from_utf8_lossy(payload));
+
+        while let Some(pos) = line.find('\n') {
+            let message: String = line.drain(..=pos).collect();
+            log.emit(&task, Level::Info, "itm", message.trim_end())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn logcmd(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = LogArgs::try_parse_from(subargs)?;
+
+    if subargs.follow && core.is_dump() {
+        bail!("cannot follow a dump");
+    }
+
+    for task in &subargs.task {
+        if task != "kernel" && hubris.lookup_task(task).is_none() {
+            bail!("unknown task \"{}\"", task);
+        }
+    }
+
+    let ringbufs = ringbuf::ringbufs(hubris)?;
+
+    let clock = if subargs.wallclock {
+        if core.is_dump() {
+            bail!("cannot synchronize with the clock of a dump");
+        }
+
+        let clock = ClockSync::sync(hubris, core, CLOCK_SAMPLES)?;
+
+        if let Some(model) = clock.model() {
+            humility::msg!(
+                "synchronized with target clock to within {:.1}ms",
+                model.uncertainty * 1000.0
+            );
+        }
+
+        Some(clock)
+    } else {
+        None
+    };
+
+    let time = if clock.is_some() {
+        Column::new("time", 24)
+    } else {
+        Column::new("ticks", 10).left()
+    };
+
+    let mut log = Log {
+        table: Table::new(
+            args.format,
+            vec![
+                time,
+                Column::new("task", 15),
+                Column::new("level", 5),
+                Column::new("source", 7),
+                Column::new("message", 0),
+            ],
+        ),
+        tasks: subargs.task.clone(),
+        level: subargs.level,
+        ticks: 0,
+        heartbeat: Heartbeat::default(),
+        clock,
+    };
+
+    let mut seen = Seen::default();
+    let interval = Duration::from_millis(subargs.interval);
+
+    poll(hubris, core, &ringbufs, &mut seen, &mut log)?;
+
+    if !subargs.follow {
+        //
+        // We won't be back, so emit any partial RTT lines.
+        //
+        for (task, line) in seen.rtt.values().filter(|(_, l)| !l.is_empty()) {
+            log.emit(task, Level::Info, "rtt", line)?;
+        }
+
+        return Ok(());
+    }
+
+    if !subargs.itm {
+        loop {
+            thread::sleep(interval);
+            poll(hubris, core, &ringbufs, &mut seen, &mut log)?;
+        }
+    }
+
+    let deferred = match subargs.deferred {
+        Some(port) => {
+            let task = deferred::task(hubris, None)?;
+            let name = hubris.lookup_module(task)?.name.clone();
+            Some((port, DeferredDecoder::new(hubris, task), name))
+        }
+        None => None,
+    };
+
+    let mut itm = Itm { deferred, lines: HashMap::new() };
+
+    let coreinfo = CoreInfo::read(core)?;
+
+    let traceid = if coreinfo.address(CoreSightComponent::SWO).is_some() {
+        None
+    } else {
+        Some(subargs.traceid)
+    };
+
+    core.init_swv()?;
+
+    //
+    // ITM data arrives as we ingest it, so we poll the other sources from
+    // within the ingest loop whenever the interval has elapsed.  Both the
+    // poll and the ingestion of ITM packets emit records, so the log is
+    // shared between them.
+    //
+    let log = RefCell::new(log);
+    let mut bytes: Vec<u8> = vec![];
+    let mut ndx = 0;
+    let start = Instant::now();
+    let mut deadline = start + interval;
+
+    itm_ingest(
+        traceid,
+        || {
+            while ndx == bytes.len() {
+                if Instant::now() >= deadline {
+                    let log = &mut log.borrow_mut();
+                    poll(hubris, core, &ringbufs, &mut seen, log)?;
+                    deadline = Instant::now() + interval;
+                }
+
+                bytes = core.read_swv()?;
+                ndx = 0;
+
+                if bytes.is_empty() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+
+            ndx += 1;
+            Ok(Some((bytes[ndx - 1], start.elapsed().as_secs_f64())))
+        },
+        |packet| {
+            if let ITMPayload::Instrumentation { payload, port } =
+                &packet.payload
+            {
+                itm.ingest(&mut log.borrow_mut(), *port, payload)?;
+            }
+
+            Ok(())
+        },
+    )
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "log",
+            archive: Archive::Required,
+            attach: Attach::Any,
+            validate: Validate::Match,
+            run: logcmd,
+        },
+        LogArgs::command(),
+    )
+}
@@ -55,7 +55,7 @@ fn manifestcmd(
     _args: &Args,
     _subargs: &[String],
 ) -> Result<()> {
-    hubris.manifest()?;
+    hubris.manifest(&mut std::io::stdout())?;
     Ok(())
 }
 
@@ -33,58 +33,426 @@
 //! ```console
 //! % humility -a ~/hubris/target/demo/dist/build-demo.zip map
 //! humility: attached via OpenOCD
-//! DESC       LOW          HIGH          SIZE ATTR  ID TASK
-//! 0x08004864 0x08010000 - 0x08017fff   32KiB r-x--  0 jefe
-//! 0x08004884 0x08018000 - 0x08019fff    8KiB r-x--  1 rcc_driver
-//! 0x080048a4 0x0801c000 - 0x0801ffff   16KiB r-x--  2 usart_driver
-//! 0x080048c4 0x08020000 - 0x08023fff   16KiB r-x--  3 user_leds
-//! 0x080048e4 0x08024000 - 0x08025fff    8KiB r-x--  4 ping
-//! 0x08004904 0x08026000 - 0x08027fff    8KiB r-x--  5 pong
-//! 0x08004924 0x08028000 - 0x080280ff     256 r-x--  6 idle
-//! 0x08004944 0x0802a000 - 0x0802bfff    8KiB r-x--  7 oh_no
-//! 0x08004964 0x0802c000 - 0x0802dfff    8KiB r-x--  8 oh_no2
-//! 0x08004874 0x20001000 - 0x200013ff    1KiB rwx--  0 jefe
-//! 0x08004894 0x20001400 - 0x200017ff    1KiB rwx--  1 rcc_driver
-//! 0x080048b4 0x20001800 - 0x20001bff    1KiB rwx--  2 usart_driver
-//! 0x080048d4 0x20001c00 - 0x20001fff    1KiB rwx--  3 user_leds
-//! 0x080048f4 0x20002000 - 0x200021ff     512 rwx--  4 ping
-//! 0x08004914 0x20002400 - 0x200027ff    1KiB rwx--  5 pong
-//! 0x08004934 0x20002800 - 0x200028ff     256 rwx--  6 idle
-//! 0x08004954 0x20002900 - 0x200029ff     256 rwx--  7 oh_no
-//! 0x08004974 0x20002a00 - 0x20002aff     256 rwx--  8 oh_no2
-//! 0x08004824 0x40004400 - 0x400047ff    1KiB rw-d-  2 usart_driver
-//! 0x08004844 0x40020000 - 0x400203ff    1KiB rw-d-  2 usart_driver
-//! 0x08004854 0x40020c00 - 0x40020fff    1KiB rw-d-  3 user_leds
-//! 0x08004834 0x40023800 - 0x40023bff    1KiB rw-d-  1 rcc_driver
+//! DESC       LOW        HIGH          SIZE ATTR  ID TASK
+//! 0x08004864 0x08010000 0x08017fff   32KiB r-x--  0 jefe
+//! 0x08004884 0x08018000 0x08019fff    8KiB r-x--  1 rcc_driver
+//! 0x080048a4 0x0801c000 0x0801ffff   16KiB r-x--  2 usart_driver
+//! 0x080048c4 0x08020000 0x08023fff   16KiB r-x--  3 user_leds
+//! 0x080048e4 0x08024000 0x08025fff    8KiB r-x--  4 ping
+//! 0x08004904 0x08026000 0x08027fff    8KiB r-x--  5 pong
+//! 0x08004924 0x08028000 0x080280ff     256 r-x--  6 idle
+//! 0x08004944 0x0802a000 0x0802bfff    8KiB r-x--  7 oh_no
+//! 0x08004964 0x0802c000 0x0802dfff    8KiB r-x--  8 oh_no2
+//! 0x08004874 0x20001000 0x200013ff    1KiB rwx--  0 jefe
+//! 0x08004894 0x20001400 0x200017ff    1KiB rwx--  1 rcc_driver
+//! 0x080048b4 0x20001800 0x20001bff    1KiB rwx--  2 usart_driver
+//! 0x080048d4 0x20001c00 0x20001fff    1KiB rwx--  3 user_leds
+//! 0x080048f4 0x20002000 0x200021ff     512 rwx--  4 ping
+//! 0x08004914 0x20002400 0x200027ff    1KiB rwx--  5 pong
+//! 0x08004934 0x20002800 0x200028ff     256 rwx--  6 idle
+//! 0x08004954 0x20002900 0x200029ff     256 rwx--  7 oh_no
+//! 0x08004974 0x20002a00 0x20002aff     256 rwx--  8 oh_no2
+//! 0x08004824 0x40004400 0x400047ff    1KiB rw-d-  2 usart_driver
+//! 0x08004844 0x40020000 0x400203ff    1KiB rw-d-  2 usart_driver
+//! 0x08004854 0x40020c00 0x40020fff    1KiB rw-d-  3 user_leds
+//! 0x08004834 0x40023800 0x40023bff    1KiB rw-d-  1 rcc_driver
 //! ```
 //!
 //! (In this case, task 7, `oh_no`, has overflowed its stack -- which
 //! we can see from the `map` output has been sized to only 256 bytes.)
+//!
+//! To emit the map as JSON or CSV (e.g., for consumption by other tools),
+//! use the global `--format` option (e.g., `humility --format json map`).
+//!
+//! To verify the memory regions of each task against what the archive
+//! declares, use `--verify`.  Each task's loadable segments must be
+//! contained within a region of the task with compatible attributes, and
+//! each peripheral that the task uses must be contained within a device
+//! region of the task.  On a live target, the regions programmed into the
+//! MPU (which are those of the task that was most recently run) are also
+//! verified against that task's region table.  Any divergence is displayed,
+//! and results in an error:
+//!
+//! ```console
+//! % humility map --verify
+//! humility: attached via ST-Link V3
+//! TASK            LOW        HIGH       ATTR  PROBLEM
+//! i2c_driver      0x40005400 0x400057ff rw-d- missing region for i2c1
+//! spi_driver      0x08040000 0x08047fff r-x-- MPU region has attributes rw---
+//! humility: MPU holds regions for spi_driver
+//! Error: 2 divergences found
+//! ```
+//!
+//! A task's region table is also read from a dump, so `--verify` can be
+//! used on a dump (though the MPU itself is not verified).
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::core::Core;
 use humility::hubris::*;
+use humility_cmd::kernel::KernelState;
+use humility_cmd::output::{Cell, Column, Table};
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::collections::BTreeMap;
 
 #[derive(Parser, Debug)]
 #[clap(name = "map", about = env!("CARGO_PKG_DESCRIPTION"))]
-struct MapArgs {}
+struct MapArgs {
+    /// verify task regions against the archive and the MPU
+    #[clap(long)]
+    verify: bool,
+}
+
+//
+// MPU registers, which are at the same addresses on ARMv7-M (PMSAv7) and
+// ARMv8-M (PMSAv8) -- though with different layouts.
+//
+const MPU_TYPE: u32 = 0xe000_ed90;
+const MPU_CTRL: u32 = 0xe000_ed94;
+const MPU_RNR: u32 = 0xe000_ed98;
+const MPU_RBAR: u32 = 0xe000_ed9c;
+const MPU_RASR: u32 = 0xe000_eda0;
+
+fn attrs(attr: &HubrisRegionAttr) -> String {
+    format!(
+        "{}{}{}{}{}",
+        if attr.read { "r" } else { "-" },
+        if attr.write { "w" } else { "-" },
+        if attr.execute { "x" } else { "-" },
+        if attr.device { "d" } else { "-" },
+        if attr.dma { "m" } else { "-" },
+    )
+}
+
+/// A region as programmed into the MPU, with access as seen by a task
+#[derive(Debug)]
+struct MpuRegion {
+    base: u32,
+    size: u64,
+    attr: HubrisRegionAttr,
+}
+
+///
+/// Reads the enabled regions from the MPU, returning `None` if the MPU is
+/// disabled.  The core must be halted, as the region number register is
+/// modified (and restored) in the process.
+///
+fn mpu_regions(
+    core: &mut dyn Core,
+    v8: bool,
+) -> Result<Option<Vec<MpuRegion>>> {
+    if core.read_word_32(MPU_CTRL)? & 1 == 0 {
+        return Ok(None);
+    }
+
+    let nregions = (core.read_word_32(MPU_TYPE)? >> 8) & 0xff;
+    let rnr = core.read_word_32(MPU_RNR)?;
+    let mut rval = vec![];
+
+    for i in 0..nregions {
+        core.write_word_32(MPU_RNR, i)?;
+        let rbar = core.read_word_32(MPU_RBAR)?;
+        let rasr = core.read_word_32(MPU_RASR)?;
+
+        if rasr & 1 == 0 {
+            continue;
+        }
+
+        let base = rbar & !0x1f;
+
+        //
+        // We are interested in access from unprivileged code (that is, from
+        // tasks), so we decode the access permissions accordingly.
+        //
+        let (size, read, write, execute) = if v8 {
+            let limit = (rasr & !0x1f) | 0x1f;
+            let ap = (rbar >> 1) & 0b11;
+            (
+                limit.wrapping_sub(base) as u64 + 1,
+                ap & 0b01 != 0,
+                ap == 0b01,
+                rbar & 1 == 0,
+            )
+        } else {
+            let ap = (rasr >> 24) & 0b111;
+            (
+                1u64 << (((rasr >> 1) & 0x1f) + 1),
+                matches!(ap, 0b010 | 0b011 | 0b110 | 0b111),
+                ap == 0b011,
+                rasr & (1 << 28) == 0,
+            )
+        };
+
+        rval.push(MpuRegion {
+            base,
+            size,
+            attr: HubrisRegionAttr {
+                read,
+                write,
+                execute,
+                device: false,
+                dma: false,
+            },
+        });
+    }
+
+    core.write_word_32(MPU_RNR, rnr)?;
+
+    Ok(Some(rval))
+}
+
+fn rwx(attr: &HubrisRegionAttr) -> (bool, bool, bool) {
+    (attr.read, attr.write, attr.execute)
+}
+
+/// A divergence between a task's regions and what is expected of them
+struct Divergence<'a> {
+    task: &'a str,
+    base: u32,
+    size: u64,
+    attr: HubrisRegionAttr,
+    problem: String,
+}
+
+///
+/// Verifies that each task's region table covers its loadable segments and
+/// the peripherals that it uses.
+///
+fn verify_tasks<'a>(
+    hubris: &'a HubrisArchive,
+    regions: &BTreeMap<u32, HubrisRegion>,
+    divergences: &mut Vec<Divergence<'a>>,
+) -> Result<()> {
+    let device = HubrisRegionAttr {
+        read: true,
+        write: true,
+        execute: false,
+        device: true,
+        dma: false,
+    };
+
+    for i in 0..hubris.ntasks() as u32 {
+        let task = HubrisTask::Task(i);
+        let name = &hubris.lookup_module(task)?.name;
+
+        let mine = regions
+            .values()
+            .filter(|r| r.tasks.contains(&task))
+            .collect::<Vec<_>>();
+
+        let containing = |base: u32, size: u32| {
+            mine.iter().find(|r| {
+                r.base <= base
+                    && base as u64 + size as u64
+                        <= r.base as u64 + r.mapsize as u64
+            })
+        };
+
+        for seg in hubris.loaded_regions(task) {
+            if seg.size == 0 {
+                continue;
+            }
+
+            let problem = match containing(seg.base, seg.size) {
+                None => "missing region for loaded segment".to_string(),
+                Some(r)
+                    if (seg.attr.read && !r.attr.read)
+                        || (seg.attr.write && !r.attr.write)
+                        || (seg.attr.execute && !r.attr.execute) =>
+                {
+                    format!("region has attributes {}", attrs(&r.attr))
+                }
+                Some(_) => continue,
+            };
+
+            divergences.push(Divergence {
+                task: name,
+                base: seg.base,
+                size: seg.size.into(),
+                attr: seg.attr,
+                problem,
+            });
+        }
+
+        let peripherals = hubris.manifest.task_peripherals.get(name);
+
+        for (periph, base, size) in peripherals.into_iter().flatten() {
+            let problem = match containing(*base, *size) {
+                None => format!("missing region for {}", periph),
+                Some(r) if !r.attr.device || !r.attr.read || !r.attr.write => {
+                    format!(
+                        "region for {} has attributes {}",
+                        periph,
+                        attrs(&r.attr)
+                    )
+                }
+                Some(_) => continue,
+            };
+
+            divergences.push(Divergence {
+                task: name,
+                base: *base,
+                size: (*size).into(),
+                attr: device,
+                problem,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Reads the index of the task that was most recently run along with the
+/// regions programmed into the MPU, which should be that task's.
+///
+fn mpu_task(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+) -> Result<(Option<u32>, Option<Vec<MpuRegion>>)> {
+    let v8 = matches!(
+        hubris.manifest.target.as_deref(),
+        Some(target) if target.starts_with("thumbv8m")
+    );
+
+    let current = KernelState::read(hubris, core)?.current;
+
+    Ok((current, mpu_regions(core, v8)?))
+}
+
+///
+/// Verifies the regions programmed into the MPU against the region table of
+/// the task that was most recently run, returning the name of that task.
+///
+fn verify_mpu<'a>(
+    hubris: &'a HubrisArchive,
+    core: &mut dyn Core,
+    regions: &BTreeMap<u32, HubrisRegion>,
+    divergences: &mut Vec<Divergence<'a>>,
+) -> Result<Option<&'a str>> {
+    core.halt()?;
+    let rval = mpu_task(hubris, core);
+    core.run()?;
+
+    let (ndx, mpu) = match rval? {
+        (_, None) => bail!("MPU is disabled"),
+        (None, _) => {
+            humility::msg!("could not determine task in MPU");
+            return Ok(None);
+        }
+        (Some(ndx), Some(mpu)) => (ndx, mpu),
+    };
+
+    let task = HubrisTask::Task(ndx);
+    let name = &hubris.lookup_module(task)?.name;
+
+    for r in regions.values().filter(|r| r.tasks.contains(&task)) {
+        let size = r.mapsize as u64;
+
+        let problem = match mpu.iter().find(|m| m.base == r.base) {
+            None => "missing MPU region".to_string(),
+            Some(m) if m.size != size => {
+                format!("MPU region has size {}", m.size)
+            }
+            Some(m) if rwx(&m.attr) != rwx(&r.attr) => {
+                format!("MPU region has attributes {}", attrs(&m.attr))
+            }
+            Some(_) => continue,
+        };
+
+        divergences.push(Divergence {
+            task: name,
+            base: r.base,
+            size,
+            attr: r.attr,
+            problem,
+        });
+    }
+
+    Ok(Some(name))
+}
+
+fn verify(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    regions: &BTreeMap<u32, HubrisRegion>,
+) -> Result<()> {
+    let mut divergences = vec![];
+
+    verify_tasks(hubris, regions, &mut divergences)?;
+
+    let current = if core.is_dump() {
+        None
+    } else {
+        verify_mpu(hubris, core, regions, &mut divergences)?
+    };
+
+    if !divergences.is_empty() {
+        let mut table = Table::new(
+            args.format,
+            vec![
+                Column::new("task", 15),
+                Column::new("low", 10),
+                Column::new("high", 10),
+                Column::new("attr", 5),
+                Column::new("problem", 0),
+            ],
+        );
+
+        for d in &divergences {
+            table.row(vec![
+                d.task.into(),
+                Cell::Hex(d.base.into(), 8),
+                Cell::Hex(d.base as u64 + d.size - 1, 8),
+                attrs(&d.attr).into(),
+                d.problem.as_str().into(),
+            ])?;
+        }
+    }
+
+    if let Some(name) = current {
+        humility::msg!("MPU holds regions for {}", name);
+    }
+
+    match divergences.len() {
+        0 => {
+            humility::msg!("regions of {} tasks verified", hubris.ntasks());
+            Ok(())
+        }
+        1 => bail!("1 divergence found"),
+        n => bail!("{} divergences found", n),
+    }
+}
 
 fn mapcmd(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
-    _subargs: &[String],
+    args: &Args,
+    subargs: &[String],
 ) -> Result<()> {
+    let subargs = MapArgs::try_parse_from(subargs)?;
+
     core.op_start()?;
     let regions = hubris.regions(core)?;
     core.op_done()?;
 
-    println!(
-        "{:10} {:10}   {:10} {:>7} {:5} {:2} TASK",
-        "DESC", "LOW", "HIGH", "SIZE", "ATTR", "ID",
+    if subargs.verify {
+        return verify(hubris, core, args, &regions);
+    }
+
+    let mut table = Table::new(
+        args.format,
+        vec![
+            Column::new("desc", 10),
+            Column::new("low", 10),
+            Column::new("high", 10).separator(" - "),
+            Column::new("size", 7).right(),
+            Column::new("attr", 5),
+            Column::new("id", 2).left(),
+            Column::new("task", 0),
+        ],
     );
 
     for (_, region) in regions.iter() {
@@ -98,35 +466,30 @@ fn mapcmd(
             names.join(", ")
         };
 
-        println!(
-            "{:10} 0x{:08x} - 0x{:08x} {:>7} {}{}{}{}{} {:2} {}",
-            match region.daddr {
-                Some(daddr) => format!("0x{:08x}", daddr),
-                None => "-".to_owned(),
-            },
-            region.base,
-            region.base + region.mapsize - 1,
-            if region.mapsize >= 1024 {
-                format!("{}KiB", region.mapsize >> 10)
-            } else {
-                format!("{}", region.mapsize)
-            },
-            if region.attr.read { "r" } else { "-" },
-            if region.attr.write { "w" } else { "-" },
-            if region.attr.execute { "x" } else { "-" },
-            if region.attr.device { "d" } else { "-" },
-            if region.attr.dma { "m" } else { "-" },
-            region.tasks[0].id(),
-            if region.attr.device {
-                if let Some(p) = hubris.lookup_peripheral_byaddr(region.base) {
-                    format!("[{}] {}", p, name)
-                } else {
-                    format!("[??] {}", name)
-                }
+        let attr = attrs(&region.attr);
+
+        let task = if region.attr.device {
+            if let Some(p) = hubris.lookup_peripheral_byaddr(region.base) {
+                format!("[{}] {}", p, name)
             } else {
-                name.to_string()
+                format!("[??] {}", name)
             }
-        );
+        } else {
+            name
+        };
+
+        table.row(vec![
+            region.daddr.map(|daddr| Cell::Hex(daddr.into(), 8)).into(),
+            Cell::Hex(region.base.into(), 8),
+            Cell::Hex((region.base + region.mapsize - 1).into(), 8),
+            Cell::Size(region.mapsize.into()),
+            attr.into(),
+            match region.tasks[0] {
+                HubrisTask::Task(id) => id.into(),
+                HubrisTask::Kernel => Cell::None,
+            },
+            task.into(),
+        ])?;
     }
 
     Ok(())
@@ -0,0 +1,701 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility mfg`
+//!
+//! `humility mfg` runs a manufacturing test plan:  a TOML file that
+//! declares a sequence of steps, each of which performs a check against
+//! the target and either passes or fails.  Each step is a `[[step]]` table
+//! with an optional `name` and a `check` that denotes its kind:
+//!
+//! - `i2c`: checks that the I2C devices that match `device` (a device
+//!   type or name from the archive, optionally restricted to the bus named
+//!   by `bus`) are present, i.e. acknowledge a read
+//!
+//! - `sensor`: checks that the value of the sensor named by `sensor` is
+//!   within `min` and `max` (either of which may be omitted)
+//!
+//! - `gpio`: drives the pin specified by `output` high and then low, and
+//!   checks that the pin specified by `input` follows it (or, if `invert`
+//!   is true, does the opposite) after `settle_ms` milliseconds (10 by
+//!   default); pins may be specified by name or as `port:pin`
+//!
+//! - `version`: checks that the image on the target matches the archive,
+//!   and that the archive's `version` and `gitrev` (if specified) are as
+//!   expected; if `op` names an Idol operation that takes no arguments
+//!   (e.g., `SpRot.rot_version`), its reply must contain `expect`
+//!
+//! For example:
+//!
+//! ```toml
+//! name = "gimlet board test"
+//!
+//! [[step]]
+//! name = "temperature sensors present"
+//! check = "i2c"
+//! device = "tmp117"
+//!
+//! [[step]]
+//! check = "sensor"
+//! sensor = "Southwest"
+//! min = 15.0
+//! max = 45.0
+//!
+//! [[step]]
+//! name = "sequencer loopback"
+//! check = "gpio"
+//! output = "SP_TO_SEQ_TEST"
+//! input = "SEQ_TO_SP_TEST"
+//!
+//! [[step]]
+//! check = "version"
+//! version = "1.0.2"
+//! ```
+//!
+//! Steps are run in order, and a report is emitted with the result of
+//! each:
+//!
+//! ```console
+//! % humility mfg gimlet.toml
+//! humility: attached via ST-Link V3
+//! humility: running gimlet board test (4 steps)
+//! STEP RESULT NAME                          DETAIL
+//! 0    PASS   temperature sensors present   8 of 8 devices present
+//! 1    PASS   sensor Southwest              31.25
+//! 2    FAIL   sequencer loopback            input stuck at 0
+//! 3    PASS   version                       image matches; version 1.0.2
+//! humility mfg failed: 1 of 4 steps failed
+//! ```
+//!
+//! The report is subject to the global `--format` option, allowing it to be
+//! consumed as JSON or CSV.  Each step also generates a log of what it did
+//! (e.g., the result for each device checked); the logs are emitted to
+//! standard error with `-v` (`--verbose`), and written to a file with `-l`
+//! (`--log`).  To stop at the first failed step, use `-x`
+//! (`--stop-on-failure`).  The command fails if any step fails.
+//!
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::i2c::I2cArgs;
+use humility_cmd::idol::{IdolArgument, IdolOperation};
+use humility_cmd::output::{Cell, Column, Table};
+use humility_cmd::style::Severity;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use serde::Deserialize;
+use std::fs;
+use std::io::Write;
+
+#[derive(Parser, Debug)]
+#[clap(name = "mfg", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct MfgArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// emit the log of each step to standard error
+    #[clap(long, short)]
+    verbose: bool,
+
+    /// write the log of each step to the specified file
+    #[clap(long, short, value_name = "file")]
+    log: Option<String>,
+
+    /// stop at the first step that fails
+    #[clap(long = "stop-on-failure", short = 'x')]
+    stop: bool,
+
+    /// test plan to run
+    plan: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "check", rename_all = "lowercase")]
+enum Check {
+    I2c {
+        device: String,
+        bus: Option<String>,
+    },
+    Sensor {
+        sensor: String,
+        min: Option<f32>,
+        max: Option<f32>,
+    },
+    Gpio {
+        output: String,
+        input: String,
+        #[serde(default)]
+        invert: bool,
+        #[serde(default = "Check::settle_ms")]
+        settle_ms: u8,
+    },
+    Version {
+        version: Option<String>,
+        gitrev: Option<String>,
+        op: Option<String>,
+        expect: Option<String>,
+    },
+}
+
+impl Check {
+    fn settle_ms() -> u8 {
+        10
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Step {
+    name: Option<String>,
+    #[serde(flatten)]
+    check: Check,
+}
+
+impl Step {
+    fn name(&self) -> String {
+        match (&self.name, &self.check) {
+            (Some(name), _) => name.clone(),
+            (None, Check::I2c { device, .. }) => format!("i2c {}", device),
+            (None, Check::Sensor { sensor, .. }) => {
+                format!("sensor {}", sensor)
+            }
+            (None, Check::Gpio { output, input, .. }) => {
+                format!("gpio {} -> {}", output, input)
+            }
+            (None, Check::Version { .. }) => "version".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TestPlan {
+    name: Option<String>,
+    #[serde(default)]
+    step: Vec<Step>,
+}
+
+///
+/// The outcome of a step:  whether it passed, a summary, and the log of
+/// what it did.
+///
+#[derive(Debug, Default)]
+struct Outcome {
+    pass: bool,
+    detail: String,
+    log: Vec<String>,
+}
+
+struct Runner<'a> {
+    hubris: &'a HubrisArchive,
+    context: HiffyContext<'a>,
+}
+
+impl<'a> Runner<'a> {
+    fn i2c(
+        &mut self,
+        core: &mut dyn Core,
+        device: &str,
+        bus: &Option<String>,
+    ) -> Result<Outcome> {
+        let hubris = self.hubris;
+        let funcs = self.context.functions()?;
+        let func = funcs.get("I2cRead", 7)?;
+
+        let bus = match bus {
+            Some(bus) => Some(hubris.lookup_i2c_bus(bus)?),
+            None => None,
+        };
+
+        let devices = hubris
+            .manifest
+            .i2c_devices
+            .iter()
+            .filter(|d| d.device == device || d.name.as_deref() == Some(device))
+            .filter(|d| match bus {
+                Some(b) => {
+                    d.controller == b.controller && d.port.name == b.port.name
+                }
+                None => true,
+            })
+            .collect::<Vec<_>>();
+
+        if devices.is_empty() {
+            bail!("no devices in archive match \"{}\"", device);
+        }
+
+        let mut ops = vec![];
+
+        for d in &devices {
+            let hargs = I2cArgs::from_device(d);
+            hargs.push_bus(&mut ops);
+            ops.push(Op::Push(d.address));
+            ops.push(Op::PushNone);
+            ops.push(Op::Push(1));
+            ops.push(Op::Call(func.id));
+            ops.push(Op::DropN(7));
+        }
+
+        ops.push(Op::Done);
+
+        let results = self.context.run(core, ops.as_slice(), None)?;
+        let mut outcome = Outcome::default();
+        let mut present = 0;
+
+        for (d, result) in devices.iter().zip(&results) {
+            let hargs = I2cArgs::from_device(d);
+
+            match result {
+                Ok(_) => {
+                    present += 1;
+                    outcome
+                        .log
+                        .push(format!("{} ({}): present", d.device, hargs));
+                }
+                Err(code) => outcome.log.push(format!(
+                    "{} ({}): {}",
+                    d.device,
+                    hargs,
+                    func.strerror(*code)
+                )),
+            }
+        }
+
+        outcome.pass = present == devices.len();
+        outcome.detail =
+            format!("{} of {} devices present", present, devices.len());
+
+        Ok(outcome)
+    }
+
+    fn sensor(
+        &mut self,
+        core: &mut dyn Core,
+        sensor: &str,
+        min: Option<f32>,
+        max: Option<f32>,
+    ) -> Result<Outcome> {
+        let hubris = self.hubris;
+
+        let id = hubris
+            .manifest
+            .sensors
+            .iter()
+            .position(|s| s.name == sensor)
+            .with_context(|| format!("no sensor named \"{}\"", sensor))?;
+
+        let s = &hubris.manifest.sensors[id];
+        let funcs = self.context.functions()?;
+        let op = IdolOperation::new(hubris, "Sensor", "get", None)
+            .context("is the 'sensor' task present?")?;
+        let payload = op.payload(&[("id", IdolArgument::Scalar(id as u64))])?;
+        let mut ops = vec![];
+
+        self.context.idol_call_ops(&funcs, &op, &payload, &mut ops)?;
+        ops.push(Op::Done);
+
+        let results = self.context.run(core, ops.as_slice(), None)?;
+        let mut outcome = Outcome::default();
+
+        let value = match results.get(0) {
+            Some(Ok(val)) if val.len() >= 4 => {
+                f32::from_le_bytes(val[0..4].try_into().unwrap())
+            }
+            Some(Err(e)) => {
+                let err = op
+                    .error
+                    .and_then(|err| err.lookup_variant(*e as u64))
+                    .map(|v| v.name.clone())
+                    .unwrap_or_else(|| format!("Err(0x{:x})", e));
+
+                outcome.detail = err;
+                outcome.log.push(format!(
+                    "{} ({}): {}",
+                    s.name,
+                    s.kind.to_string(),
+                    outcome.detail
+                ));
+                return Ok(outcome);
+            }
+            _ => bail!("bad reply from Sensor.get"),
+        };
+
+        outcome.pass = min.map_or(true, |min| value >= min)
+            && max.map_or(true, |max| value <= max);
+
+        outcome.detail = format!("{:.2}", value);
+
+        outcome.log.push(format!(
+            "{} ({}): {:.2} (expected {} to {})",
+            s.name,
+            s.kind.to_string(),
+            value,
+            min.map_or("-".to_string(), |v| v.to_string()),
+            max.map_or("-".to_string(), |v| v.to_string()),
+        ));
+
+        Ok(outcome)
+    }
+
+    ///
+    /// Resolves a pin specified by name or as `port:pin` to its port name
+    /// and pin number.
+    ///
+    fn pin<'p>(&self, pin: &'p str) -> Result<(&'p str, u8)>
+    where
+        'a: 'p,
+    {
+        if let Some(p) = self.hubris.lookup_gpio_pin(pin) {
+            return Ok((p.port.as_str(), p.pin));
+        }
+
+        match pin.split_once(':') {
+            Some((port, pin)) => match parse_int::parse::<u8>(pin) {
+                Ok(pin) if pin < 16 => Ok((port, pin)),
+                _ => bail!("invalid pin {}", pin),
+            },
+            None => bail!("\"{}\" is neither a pin name nor port:pin", pin),
+        }
+    }
+
+    fn gpio(
+        &mut self,
+        core: &mut dyn Core,
+        output: &str,
+        input: &str,
+        invert: bool,
+        settle_ms: u8,
+    ) -> Result<Outcome> {
+        let hubris = self.hubris;
+        let funcs = self.context.functions()?;
+        let gpio_set = funcs.get("GpioSet", 2)?;
+        let gpio_reset = funcs.get("GpioReset", 2)?;
+        let gpio_input = funcs.get("GpioInput", 1)?;
+        let sleep = funcs.get("Sleep", 1)?;
+
+        let (oport, opin) = self.pin(output)?;
+        let (iport, ipin) = self.pin(input)?;
+        let oport = gpio_set.lookup_argument(hubris, "port", 0, oport)?;
+        let iport = gpio_input.lookup_argument(hubris, "port", 0, iport)?;
+
+        let mut ops = vec![];
+
+        for drive in [gpio_set, gpio_reset] {
+            ops.push(Op::Push16(oport));
+            ops.push(Op::Push(opin));
+            ops.push(Op::Call(drive.id));
+            ops.push(Op::DropN(2));
+
+            ops.push(Op::Push(settle_ms));
+            ops.push(Op::Call(sleep.id));
+            ops.push(Op::Drop);
+
+            ops.push(Op::Push16(iport));
+            ops.push(Op::Call(gpio_input.id));
+            ops.push(Op::Drop);
+        }
+
+        ops.push(Op::Done);
+
+        let results = self.context.run(core, ops.as_slice(), None)?;
+
+        //
+        // Our results are interleaved:  the result of driving the output,
+        // the result of sleeping, and the result of reading the input.
+        //
+        let mut outcome = Outcome::default();
+        let mut levels = vec![];
+
+        for (driven, chunk) in [1, 0].iter().zip(results.chunks(3)) {
+            if let Err(code) = &chunk[0] {
+                bail!(
+                    "failed to drive {}: {}",
+                    output,
+                    gpio_set.strerror(*code)
+                );
+            }
+
+            let level = match chunk.get(2) {
+                Some(Ok(val)) if val.len() >= 2 => {
+                    let v = u16::from_le_bytes(val[0..2].try_into().unwrap());
+                    if v & (1 << ipin) != 0 {
+                        1
+                    } else {
+                        0
+                    }
+                }
+                Some(Err(code)) => {
+                    bail!(
+                        "failed to read {}: {}",
+                        input,
+                        gpio_input.strerror(*code)
+                    )
+                }
+                _ => bail!("bad reply from GpioInput"),
+            };
+
+            outcome.log.push(format!(
+                "drove {} to {}; read {} as {}",
+                output, driven, input, level
+            ));
+
+            levels.push(level);
+        }
+
+        let expected = if invert { [0, 1] } else { [1, 0] };
+        outcome.pass = levels == expected;
+
+        outcome.detail = match levels.as_slice() {
+            _ if outcome.pass => "input follows output".to_string(),
+            [a, b] if a == b => format!("input stuck at {}", a),
+            _ => "input has wrong polarity".to_string(),
+        };
+
+        Ok(outcome)
+    }
+
+    fn version(
+        &mut self,
+        core: &mut dyn Core,
+        version: &Option<String>,
+        gitrev: &Option<String>,
+        op: &Option<String>,
+        expect: &Option<String>,
+    ) -> Result<Outcome> {
+        let hubris = self.hubris;
+        let mut outcome = Outcome { pass: true, ..Default::default() };
+        let mut detail = vec![];
+
+        match hubris.validate(core, HubrisValidate::ArchiveMatch) {
+            Ok(_) => {
+                outcome.log.push("image ID matches archive".to_string());
+                detail.push("image matches".to_string());
+            }
+            Err(err) => {
+                outcome.log.push(format!("{}", err));
+                detail.push("image mismatch".to_string());
+                outcome.pass = false;
+            }
+        }
+
+        let manifest = &hubris.manifest;
+
+        for (what, expected, actual) in [
+            ("version", version, &manifest.version),
+            ("gitrev", gitrev, &manifest.gitrev),
+        ] {
+            let expected = match expected {
+                Some(expected) => expected,
+                None => continue,
+            };
+
+            let actual = actual.as_deref().unwrap_or("<unknown>");
+
+            outcome.log.push(format!(
+                "{} is {} (expected {})",
+                what, actual, expected
+            ));
+
+            if actual == expected {
+                detail.push(format!("{} {}", what, actual));
+            } else {
+                detail.push(format!("{} {} != {}", what, actual, expected));
+                outcome.pass = false;
+            }
+        }
+
+        if let Some(op) = op {
+            let (iface, name) = match op.split_once('.') {
+                Some(split) => split,
+                None => bail!("op must be of the form Interface.operation"),
+            };
+
+            let idol = IdolOperation::new(hubris, iface, name, None)?;
+            let funcs = self.context.functions()?;
+            let mut ops = vec![];
+
+            self.context.idol_call_ops(&funcs, &idol, &[], &mut ops)?;
+            ops.push(Op::Done);
+
+            let results = self.context.run(core, ops.as_slice(), None)?;
+            let fmt = HubrisPrintFormat::default();
+
+            let reply = match results.get(0) {
+                Some(Ok(val)) => hubris.printfmt(val, idol.ok, &fmt)?,
+                Some(Err(e)) => bail!("{} failed: Err(0x{:x})", op, e),
+                None => bail!("missing result for {}", op),
+            };
+
+            outcome.log.push(format!("{} returned {}", op, reply));
+
+            if let Some(expect) = expect {
+                if reply.contains(expect.as_str()) {
+                    detail.push(format!("{} ok", op));
+                } else {
+                    detail.push(format!("{} mismatch", op));
+                    outcome.pass = false;
+                }
+            }
+        }
+
+        outcome.detail = detail.join("; ");
+
+        Ok(outcome)
+    }
+
+    fn run(&mut self, core: &mut dyn Core, step: &Step) -> Result<Outcome> {
+        match &step.check {
+            Check::I2c { device, bus } => self.i2c(core, device, bus),
+            Check::Sensor { sensor, min, max } => {
+                self.sensor(core, sensor, *min, *max)
+            }
+            Check::Gpio { output, input, invert, settle_ms } => {
+                self.gpio(core, output, input, *invert, *settle_ms)
+            }
+            Check::Version { version, gitrev, op, expect } => {
+                self.version(core, version, gitrev, op, expect)
+            }
+        }
+    }
+}
+
+fn mfg(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = MfgArgs::try_parse_from(subargs)?;
+
+    let contents = fs::read_to_string(&subargs.plan)
+        .with_context(|| format!("failed to read {}", subargs.plan))?;
+
+    let plan: TestPlan = toml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", subargs.plan))?;
+
+    if plan.step.is_empty() {
+        bail!("{}: no steps specified", subargs.plan);
+    }
+
+    let mut log = match &subargs.log {
+        Some(filename) => Some(
+            fs::File::create(filename)
+                .with_context(|| format!("failed to create {}", filename))?,
+        ),
+        None => None,
+    };
+
+    let context = HiffyContext::new(hubris, core, subargs.timeout)?;
+    let mut runner = Runner { hubris, context };
+
+    humility::msg!(
+        "running {} ({} step{})",
+        plan.name.as_deref().unwrap_or(&subargs.plan),
+        plan.step.len(),
+        if plan.step.len() == 1 { "" } else { "s" }
+    );
+
+    let mut table = Table::new(
+        args.format,
+        vec![
+            Column::new("step", 4),
+            Column::new("result", 6),
+            Column::new("name", 30),
+            Column::new("detail", 0),
+        ],
+    );
+
+    let mut failed = 0;
+    let mut ran = 0;
+
+    for (ndx, step) in plan.step.iter().enumerate() {
+        let name = step.name();
+
+        let outcome = match runner.run(core, step) {
+            Ok(outcome) => outcome,
+            Err(err) => Outcome {
+                pass: false,
+                detail: format!("error: {}", err),
+                log: vec![format!("{:?}", err)],
+            },
+        };
+
+        for line in &outcome.log {
+            if subargs.verbose {
+                humility::msg!("[{}] {}", ndx, line);
+            }
+
+            if let Some(ref mut log) = log {
+                writeln!(log, "[{}] {}: {}", ndx, name, line)?;
+            }
+        }
+
+        let result = if outcome.pass {
+            Cell::from("PASS").styled(Severity::Ok)
+        } else {
+            Cell::from("FAIL").styled(Severity::Error)
+        };
+
+        if let Some(ref mut log) = log {
+            writeln!(
+                log,
+                "[{}] {}: {}",
+                ndx,
+                name,
+                if outcome.pass { "PASS" } else { "FAIL" }
+            )?;
+        }
+
+        table.row(vec![
+            Cell::from(ndx as u64),
+            result,
+            name.into(),
+            outcome.detail.into(),
+        ])?;
+
+        ran += 1;
+
+        if !outcome.pass {
+            failed += 1;
+
+            if subargs.stop {
+                break;
+            }
+        }
+    }
+
+    if ran < plan.step.len() {
+        humility::msg!(
+            "stopped after step {}; {} steps not run",
+            ran - 1,
+            plan.step.len() - ran
+        );
+    }
+
+    if failed > 0 {
+        bail!("{} of {} steps failed", failed, plan.step.len());
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "mfg",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: mfg,
+        },
+        MfgArgs::command(),
+    )
+}
@@ -0,0 +1,627 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility monitor`
+//!
+//! `humility monitor` provides a captive, continuously updating display of
+//! the state of a running system:  the state and generation of each task,
+//! the values of sensors, faults and restarts of tasks (and resets of the
+//! target) as they are observed, and the most recent entries of ring
+//! buffers.  The target is polled at the interval specified with `-i`
+//! (`--interval`, in milliseconds; defaults to 1000).  To exit, press `q`.
+//!
+//! By default, the display consists of tasks, temperature sensors, events
+//! and ring buffers.  To configure the display, specify a layout file with
+//! `-l` (`--layout`).  A layout is a TOML file consisting of panes to be
+//! displayed from top to bottom, each with a `kind` of `tasks`, `sensors`,
+//! `events` or `ringbuf`, an optional `title`, and an optional `height`
+//! (relative to the other panes; defaults to 1).  A `sensors` pane may
+//! specify the `sensors` to display by name (defaulting to all temperature
+//! sensors), and a `ringbuf` pane may specify `ringbufs` to display (by
+//! substring; defaulting to all of them):
+//!
+//! ```toml
+//! [[pane]]
+//! kind = "tasks"
+//! height = 2
+//!
+//! [[pane]]
+//! kind = "sensors"
+//! sensors = ["Southwest", "Northeast", "VDD_VCORE"]
+//!
+//! [[pane]]
+//! kind = "events"
+//!
+//! [[pane]]
+//! kind = "ringbuf"
+//! title = "network"
+//! ringbufs = ["net"]
+//! ```
+//!
+//! The target is briefly halted at each interval to read the kernel's task
+//! table and the ring buffers; sensors are read via the `sensor` task (see
+//! `humility sensors`).
+//!
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use crossterm::{
+    event::{self, Event, KeyCode},
+    execute,
+    terminal::{
+        disable_raw_mode, enable_raw_mode, EnterAlternateScreen,
+        LeaveAlternateScreen,
+    },
+};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::doppel::{SchedState, TaskState};
+use humility_cmd::hiffy::*;
+use humility_cmd::idol;
+use humility_cmd::kernel::{describe_fault, Heartbeat, KernelState};
+use humility_cmd::reflect::Format;
+use humility_cmd::ringbuf::{self, RingbufVariable};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+use tui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Style},
+    widgets::{Block, Borders, List, ListItem},
+    Frame, Terminal,
+};
+
+#[derive(Parser, Debug)]
+#[clap(name = "monitor", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct MonitorArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// interval between polls of the target
+    #[clap(
+        long, short, value_name = "ms", default_value = "1000",
+        parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
+    /// layout file
+    #[clap(long, short, value_name = "file")]
+    layout: Option<String>,
+}
+
+//
+// The maximum number of events and ring buffer entries that we retain.
+//
+const MAX_LINES: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PaneKind {
+    Tasks,
+    Sensors,
+    Events,
+    Ringbuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Pane {
+    kind: PaneKind,
+    title: Option<String>,
+    #[serde(default = "Pane::height")]
+    height: u32,
+    #[serde(default)]
+    sensors: Vec<String>,
+    #[serde(default)]
+    ringbufs: Vec<String>,
+}
+
+impl Pane {
+    fn height() -> u32 {
+        1
+    }
+
+    fn new(kind: PaneKind, height: u32) -> Self {
+        Self { kind, title: None, height, sensors: vec![], ringbufs: vec![] }
+    }
+
+    fn title(&self) -> &str {
+        match &self.title {
+            Some(title) => title,
+            None => match self.kind {
+                PaneKind::Tasks => "tasks",
+                PaneKind::Sensors => "sensors",
+                PaneKind::Events => "events",
+                PaneKind::Ringbuf => "ring buffers",
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayoutFile {
+    #[serde(default)]
+    pane: Vec<Pane>,
+}
+
+fn layout(filename: &Option<String>) -> Result<Vec<Pane>> {
+    let filename = match filename {
+        Some(filename) => filename,
+        None => {
+            return Ok(vec![
+                Pane::new(PaneKind::Tasks, 3),
+                Pane::new(PaneKind::Sensors, 2),
+                Pane::new(PaneKind::Events, 1),
+                Pane::new(PaneKind::Ringbuf, 2),
+            ])
+        }
+    };
+
+    let contents = fs::read_to_string(filename)
+        .with_context(|| format!("failed to read {}", filename))?;
+
+    let file: LayoutFile = toml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", filename))?;
+
+    if file.pane.is_empty() {
+        bail!("{}: no panes specified", filename);
+    }
+
+    if file.pane.iter().any(|p| p.height == 0) {
+        bail!("{}: pane height must be non-zero", filename);
+    }
+
+    Ok(file.pane)
+}
+
+//
+// A line of output, highlighted if it denotes a problem.
+//
+type Line = (String, bool);
+
+struct Monitor<'a> {
+    hubris: &'a HubrisArchive,
+    panes: Vec<Pane>,
+    context: Option<HiffyContext<'a>>,
+    ops: Vec<Op>,
+    sensors: Vec<usize>,
+    values: Vec<Option<f32>>,
+    ringbufs: Vec<RingbufVariable<'a>>,
+    seen: HashMap<&'a str, (u16, usize)>,
+    tasks: Vec<Line>,
+    events: VecDeque<Line>,
+    entries: VecDeque<(&'a str, String)>,
+    observed: HashMap<u32, (u32, bool)>,
+    heartbeat: Heartbeat,
+    started: Instant,
+}
+
+impl<'a> Monitor<'a> {
+    fn new(
+        hubris: &'a HubrisArchive,
+        core: &mut dyn Core,
+        subargs: &MonitorArgs,
+    ) -> Result<Self> {
+        let panes = layout(&subargs.layout)?;
+        let all = &hubris.manifest.sensors;
+        let mut sensors = vec![];
+
+        for pane in panes.iter().filter(|p| p.kind == PaneKind::Sensors) {
+            if pane.sensors.is_empty() {
+                sensors.extend(
+                    (0..all.len()).filter(|&i| {
+                        all[i].kind == HubrisSensorKind::Temperature
+                    }),
+                );
+                continue;
+            }
+
+            for name in &pane.sensors {
+                let found = (0..all.len()).filter(|&i| &all[i].name == name);
+                let before = sensors.len();
+                sensors.extend(found);
+
+                if sensors.len() == before {
+                    bail!("unrecognized sensor name {}", name);
+                }
+            }
+        }
+
+        sensors.sort_unstable();
+        sensors.dedup();
+
+        let mut context = None;
+        let mut ops = vec![];
+
+        if !sensors.is_empty() {
+            let mut c = HiffyContext::new(hubris, core, subargs.timeout)?;
+            let funcs = c.functions()?;
+            let op = idol::IdolOperation::new(hubris, "Sensor", "get", None)
+                .context("is the 'sensor' task present?")?;
+            let mut payload =
+                op.template(&[("id", idol::IdolArgument::Scalar(0))])?;
+
+            for i in &sensors {
+                payload.set("id", *i as u64)?;
+                c.idol_call_ops(&funcs, &op, payload.as_slice(), &mut ops)?;
+            }
+
+            ops.push(Op::Done);
+            context = Some(c);
+        }
+
+        let mut ringbufs = vec![];
+
+        for pane in panes.iter().filter(|p| p.kind == PaneKind::Ringbuf) {
+            for v in ringbuf::ringbufs(hubris)? {
+                if pane.ringbufs.is_empty()
+                    || pane.ringbufs.iter().any(|r| v.name.contains(r.as_str()))
+                {
+                    if !ringbufs.iter().any(|r: &RingbufVariable| {
+                        r.variable.addr == v.variable.addr
+                    }) {
+                        ringbufs.push(v);
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            hubris,
+            panes,
+            context,
+            ops,
+            values: vec![None; sensors.len()],
+            sensors,
+            ringbufs,
+            seen: HashMap::new(),
+            tasks: vec![],
+            events: VecDeque::new(),
+            entries: VecDeque::new(),
+            observed: HashMap::new(),
+            heartbeat: Heartbeat::default(),
+            started: Instant::now(),
+        })
+    }
+
+    fn event(&mut self, event: String, bad: bool) {
+        let time = self.started.elapsed().as_secs_f64();
+
+        if self.events.len() == MAX_LINES {
+            self.events.pop_front();
+        }
+
+        self.events.push_back((format!("{:9.1}s {}", time, event), bad));
+    }
+
+    fn poll(&mut self, core: &mut dyn Core) -> Result<()> {
+        let hubris = self.hubris;
+
+        if let Some(reset) = self.heartbeat.read(hubris, core)? {
+            self.event(reset.to_string(), true);
+            self.observed.clear();
+            self.seen.clear();
+        }
+
+        core.halt()?;
+        let rval = self.poll_halted(core);
+        core.run()?;
+        rval?;
+
+        if let Some(context) = &mut self.context {
+            let results = context.run(core, &self.ops, None)?;
+
+            self.values = results
+                .iter()
+                .map(|r| match r {
+                    Ok(val) if val.len() >= 4 => {
+                        Some(f32::from_le_bytes(val[0..4].try_into().unwrap()))
+                    }
+                    _ => None,
+                })
+                .collect();
+        }
+
+        Ok(())
+    }
+
+    fn poll_halted(&mut self, core: &mut dyn Core) -> Result<()> {
+        let hubris = self.hubris;
+        let kernel = KernelState::read(hubris, core)?;
+        let mut events = vec![];
+
+        self.tasks = kernel
+            .tasks
+            .iter()
+            .map(|t| {
+                let generation = u32::from(t.task.generation);
+
+                let (state, faulted) = match t.task.state {
+                    TaskState::Faulted { fault, .. } => (
+                        format!(
+                            "FAULT: {}",
+                            describe_fault(hubris, core, t, fault)
+                        ),
+                        true,
+                    ),
+                    TaskState::Healthy(state) => (
+                        match state {
+                            SchedState::Stopped => "not started",
+                            SchedState::Runnable => "ready",
+                            SchedState::InSend(_) => "wait: send",
+                            SchedState::InReply(_) => "wait: reply",
+                            SchedState::InRecv(_) => "wait: recv",
+                        }
+                        .to_string(),
+                        false,
+                    ),
+                };
+
+                match self.observed.insert(t.index, (generation, faulted)) {
+                    Some((g, _)) if g != generation => events.push((
+                        format!(
+                            "{} restarted (generation {})",
+                            t.name, generation
+                        ),
+                        true,
+                    )),
+                    Some((_, false)) if faulted => {
+                        events.push((format!("{} {}", t.name, state), true))
+                    }
+                    _ => {}
+                }
+
+                (
+                    format!(
+                        "{:2} {:16} {:>5} {}",
+                        t.index, t.name, generation, state
+                    ),
+                    faulted,
+                )
+            })
+            .collect();
+
+        for (event, bad) in events {
+            self.event(event, bad);
+        }
+
+        let fmt =
+            HubrisPrintFormat { hex: true, ..HubrisPrintFormat::default() };
+
+        for v in &self.ringbufs {
+            let ringbuf = match v.read(hubris, core) {
+                Ok(ringbuf) => ringbuf,
+                Err(_) => continue,
+            };
+
+            //
+            // We only display entries that are new since we last looked:
+            // those with a later generation, or in a later slot within the
+            // same generation.
+            //
+            let last = self.seen.get(v.name).copied();
+
+            for (slot, entry) in ringbuf::entries(&ringbuf) {
+                let key = (entry.generation, slot);
+
+                if let Some(last) = last {
+                    if key <= last {
+                        continue;
+                    }
+                }
+
+                let mut payload = vec![];
+                entry.payload.format(hubris, fmt, &mut payload)?;
+
+                if self.entries.len() == MAX_LINES {
+                    self.entries.pop_front();
+                }
+
+                self.entries.push_back((
+                    v.task,
+                    format!(
+                        "{:4} {}",
+                        entry.line,
+                        String::from_utf8_lossy(&payload)
+                    ),
+                ));
+
+                self.seen.insert(v.name, key);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn lines(&self, pane: &Pane) -> Vec<Line> {
+        let all = &self.hubris.manifest.sensors;
+
+        match pane.kind {
+            PaneKind::Tasks => self.tasks.clone(),
+            PaneKind::Events => self.events.iter().cloned().collect(),
+            PaneKind::Sensors => self
+                .sensors
+                .iter()
+                .zip(self.values.iter())
+                .filter(|(i, _)| {
+                    pane.sensors.is_empty()
+                        || pane.sensors.contains(&all[**i].name)
+                })
+                .filter(|(i, _)| {
+                    !pane.sensors.is_empty()
+                        || all[**i].kind == HubrisSensorKind::Temperature
+                })
+                .map(|(i, val)| {
+                    let s = &all[*i];
+
+                    match val {
+                        Some(val) => (
+                            format!(
+                                "{:24} {:12} {:10.2}",
+                                s.name,
+                                s.kind.to_string(),
+                                val
+                            ),
+                            false,
+                        ),
+                        None => (
+                            format!(
+                                "{:24} {:12} {:>10}",
+                                s.name,
+                                s.kind.to_string(),
+                                "-"
+                            ),
+                            true,
+                        ),
+                    }
+                })
+                .collect(),
+            PaneKind::Ringbuf => self
+                .entries
+                .iter()
+                .filter(|(task, _)| {
+                    self.ringbufs.iter().any(|v| {
+                        v.task == *task
+                            && (pane.ringbufs.is_empty()
+                                || pane
+                                    .ringbufs
+                                    .iter()
+                                    .any(|r| v.name.contains(r.as_str())))
+                    })
+                })
+                .map(|(task, entry)| (format!("{:16} {}", task, entry), false))
+                .collect(),
+        }
+    }
+}
+
+fn draw<B: Backend>(f: &mut Frame<B>, monitor: &Monitor) {
+    let total: u32 = monitor.panes.iter().map(|p| p.height).sum();
+
+    let constraints = monitor
+        .panes
+        .iter()
+        .map(|p| Constraint::Ratio(p.height, total))
+        .collect::<Vec<_>>();
+
+    let areas = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(constraints)
+        .split(f.size());
+
+    for (pane, area) in monitor.panes.iter().zip(areas.into_iter()) {
+        let lines = monitor.lines(pane);
+
+        //
+        // Events and ring buffer entries accumulate; we want to display the
+        // most recent of them.
+        //
+        let visible = area.height.saturating_sub(2) as usize;
+        let skip = match pane.kind {
+            PaneKind::Events | PaneKind::Ringbuf => {
+                lines.len().saturating_sub(visible)
+            }
+            _ => 0,
+        };
+
+        let items = lines
+            .into_iter()
+            .skip(skip)
+            .map(|(line, bad)| {
+                let style = if bad {
+                    Style::default().fg(Color::Red)
+                } else {
+                    Style::default()
+                };
+
+                ListItem::new(line).style(style)
+            })
+            .collect::<Vec<_>>();
+
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(pane.title()));
+
+        f.render_widget(list, area);
+    }
+}
+
+fn run_monitor<B: Backend>(
+    terminal: &mut Terminal<B>,
+    monitor: &mut Monitor,
+    core: &mut dyn Core,
+    interval: Duration,
+) -> Result<()> {
+    let tick_rate = Duration::from_millis(100);
+    let mut last_poll: Option<Instant> = None;
+
+    loop {
+        if last_poll.map_or(true, |last| last.elapsed() >= interval) {
+            monitor.poll(core)?;
+            last_poll = Some(Instant::now());
+            terminal.draw(|f| draw(f, monitor))?;
+        }
+
+        if event::poll(tick_rate)? {
+            match event::read()? {
+                Event::Key(key) => match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    _ => {}
+                },
+                Event::Resize(..) => {
+                    terminal.draw(|f| draw(f, monitor))?;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn monitor(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = MonitorArgs::try_parse_from(subargs)?;
+    let mut monitor = Monitor::new(hubris, core, &subargs)?;
+    let interval = Duration::from_millis(subargs.interval);
+
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    let res = run_monitor(&mut terminal, &mut monitor, core, interval);
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+
+    res
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "monitor",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: monitor,
+        },
+        MonitorArgs::command(),
+    )
+}
@@ -0,0 +1,563 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility net`
+//!
+//! `humility net` queries the network task via its `Net` Idol interface.
+//! By default, it displays the MAC address of the interface (and the IPv6
+//! link-local address derived from it), the state of the management link,
+//! and the sockets configured in the archive along with their queue
+//! depths:
+//!
+//! ```console
+//! % humility net
+//! humility: attached via ST-Link V3
+//!          mac => a8:40:25:04:02:81
+//!         ipv6 => fe80::aa40:25ff:fe04:281
+//!         link => ManagementLinkStatus {
+//!                     ksz8463_100base_fx_link_up: [ true, true ],
+//!                     vsc85x2_100base_fx_link_up: [ true, true ],
+//!                     vsc85x2_sgmii_link_up: [ true, true ],
+//!                 }
+//! SOCKET       KIND  PORT OWNER            TX PKTS  TX BYTES  RX PKTS  RX BYTES
+//! echo         udp      7 udpecho                3      1024        3      1024
+//! broadcast    udp    997 udpbroadcast           3      1024        0         0
+//! ```
+//!
+//! To display the management network counters, use `-c` (`--counters`);
+//! to poll them, displaying each counter that changes (and by how much),
+//! use `-f` (`--follow`):
+//!
+//! ```console
+//! % humility net --follow
+//! humility: attached via ST-Link V3
+//!      0.000 ksz8463_tx[1].unicast +12
+//!      0.000 ksz8463_rx[1].unicast +12
+//!      1.004 vsc85x2_mac_tx_good[0] +2
+//! ```
+//!
+//! To display the switch's neighbor (MAC address) table, use `-n`
+//! (`--neighbors`).  Any operation that the network task doesn't implement
+//! is skipped.
+//!
+//! If the network task is built with packet capture support, frames that it
+//! sends and receives can be captured to a pcap file with `-C`
+//! (`--capture`).  Capture runs until Control-C is pressed or until the
+//! number of frames specified with `--frames` has been captured:
+//!
+//! ```console
+//! % humility net --capture mgmt.pcap
+//! humility: attached via ST-Link V3
+//! humility: capturing to mgmt.pcap; ^C to stop
+//! ^Chumility: captured 42 frames (5316 bytes)
+//! ```
+//!
+//! When capturing, the network task copies each frame into a capture
+//! buffer that is drained by `capture_read`.  Each frame in the buffer is a
+//! little-endian `u32` timestamp (in kernel ticks), a little-endian `u16`
+//! length, a direction byte (0 for received, 1 for transmitted) and a pad
+//! byte, followed by the frame itself and padding to a 4-byte boundary.
+//! Frames that don't fit in the buffer are dropped by the network task.
+//!
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::idol::{IdolArgument, IdolOperation};
+use humility_cmd::reflect::{self, Base, Value};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[clap(name = "net", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct NetArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// display management network counters
+    #[clap(long, short)]
+    counters: bool,
+
+    /// poll counters, printing those that change
+    #[clap(long, short, conflicts_with_all = &["counters", "neighbors"])]
+    follow: bool,
+
+    /// interval between polls, in milliseconds
+    #[clap(
+        long, short, default_value = "1000", value_name = "ms",
+        requires = "follow", parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
+    /// display the neighbor (MAC address) table
+    #[clap(long, short)]
+    neighbors: bool,
+
+    /// capture frames to the specified pcap file
+    #[clap(
+        long, short = 'C', value_name = "filename",
+        conflicts_with_all = &["counters", "neighbors", "follow"]
+    )]
+    capture: Option<String>,
+
+    /// stop capturing after the specified number of frames
+    #[clap(long, value_name = "count", requires = "capture")]
+    frames: Option<usize>,
+}
+
+const INTERFACE: &str = "Net";
+
+fn call(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    op: &IdolOperation,
+    args: &[(&str, IdolArgument)],
+) -> Result<Vec<u8>> {
+    call_read(core, context, op, args, None)
+}
+
+///
+/// Calls an operation, optionally with a write lease of the specified
+/// length (the contents of which can be read from the HIF data once the
+/// call completes).
+///
+fn call_read(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    op: &IdolOperation,
+    args: &[(&str, IdolArgument)],
+    lease: Option<u32>,
+) -> Result<Vec<u8>> {
+    let funcs = context.functions()?;
+
+    match context.idol_call(core, &funcs, op, args, (None, lease), None)? {
+        Ok(val) => Ok(val),
+        Err(e) => bail!("{} failed: {}", op.name.1, e),
+    }
+}
+
+fn print(
+    hubris: &HubrisArchive,
+    op: &IdolOperation,
+    label: &str,
+    val: &[u8],
+) -> Result<()> {
+    let fmt = HubrisPrintFormat {
+        newline: true,
+        indent: 16,
+        ..HubrisPrintFormat::default()
+    };
+
+    println!("{:>12} => {}", label, hubris.printfmt(val, op.ok, &fmt)?);
+
+    Ok(())
+}
+
+///
+/// The IPv6 link-local address that the network task derives from its MAC
+/// address (by way of EUI-64).
+///
+fn link_local(mac: &[u8]) -> String {
+    let words = [
+        u16::from_be_bytes([mac[0] ^ 0x02, mac[1]]),
+        u16::from_be_bytes([mac[2], 0xff]),
+        u16::from_be_bytes([0xfe, mac[3]]),
+        u16::from_be_bytes([mac[4], mac[5]]),
+    ];
+
+    format!("fe80::{:x}:{:x}:{:x}:{:x}", words[0], words[1], words[2], words[3])
+}
+
+fn status(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+) -> Result<()> {
+    if let Ok(op) =
+        IdolOperation::new(hubris, INTERFACE, "get_mac_address", None)
+    {
+        let mac = call(core, context, &op, &[])?;
+
+        if mac.len() == 6 {
+            let s: Vec<String> =
+                mac.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("{:>12} => {}", "mac", s.join(":"));
+            println!("{:>12} => {}", "ipv6", link_local(&mac));
+        } else {
+            print(hubris, &op, "mac", &mac)?;
+        }
+    }
+
+    if let Ok(op) =
+        IdolOperation::new(hubris, INTERFACE, "management_link_status", None)
+    {
+        let link = call(core, context, &op, &[])?;
+        print(hubris, &op, "link", &link)?;
+    }
+
+    let sockets = &hubris.manifest.net_sockets;
+
+    if sockets.is_empty() {
+        return Ok(());
+    }
+
+    println!(
+        "{:12} {:4} {:>5} {:16} {:>8} {:>9} {:>8} {:>9}",
+        "SOCKET",
+        "KIND",
+        "PORT",
+        "OWNER",
+        "TX PKTS",
+        "TX BYTES",
+        "RX PKTS",
+        "RX BYTES"
+    );
+
+    for s in sockets {
+        println!(
+            "{:12} {:4} {:>5} {:16} {:>8} {:>9} {:>8} {:>9}",
+            s.name, s.kind, s.port, s.owner, s.tx.0, s.tx.1, s.rx.0, s.rx.1
+        );
+    }
+
+    Ok(())
+}
+
+///
+/// Flattens a value into its numeric leaves, named by their path.
+///
+fn leaves(path: String, val: &Value, rval: &mut BTreeMap<String, u64>) {
+    match val {
+        Value::Base(base) => {
+            let n = match *base {
+                Base::U8(n) => n as u64,
+                Base::U16(n) => n as u64,
+                Base::U32(n) => n as u64,
+                Base::U64(n) => n,
+                Base::Bool(b) => b as u64,
+                _ => return,
+            };
+
+            rval.insert(path, n);
+        }
+        Value::Struct(s) => {
+            for (name, member) in s.iter() {
+                let path = if path.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{}.{}", path, name)
+                };
+
+                leaves(path, member, rval);
+            }
+        }
+        Value::Tuple(t) => {
+            for (ndx, member) in t.iter().enumerate() {
+                leaves(format!("{}.{}", path, ndx), member, rval);
+            }
+        }
+        Value::Array(a) => {
+            for (ndx, member) in a.iter().enumerate() {
+                leaves(format!("{}[{}]", path, ndx), member, rval);
+            }
+        }
+        Value::Enum(_) | Value::Ptr(_) => {}
+    }
+}
+
+fn counters(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    subargs: &NetArgs,
+) -> Result<()> {
+    let op =
+        IdolOperation::new(hubris, INTERFACE, "management_counters", None)?;
+
+    if !subargs.follow {
+        let val = call(core, context, &op, &[])?;
+        return print(hubris, &op, "counters", &val);
+    }
+
+    let ty = hubris.lookup_type(op.ok)?;
+    let started = Instant::now();
+    let mut last: Option<BTreeMap<String, u64>> = None;
+
+    loop {
+        let val = call(core, context, &op, &[])?;
+        let time = started.elapsed().as_secs_f64();
+        let mut now = BTreeMap::new();
+
+        leaves(
+            String::new(),
+            &reflect::load_value(hubris, &val, ty, 0)?,
+            &mut now,
+        );
+
+        if let Some(last) = &last {
+            for (name, val) in &now {
+                let prev = last.get(name).copied().unwrap_or(0);
+
+                if *val != prev {
+                    println!(
+                        "{:10.3} {} {:+}",
+                        time,
+                        name,
+                        *val as i128 - prev as i128
+                    );
+                }
+            }
+        }
+
+        last = Some(now);
+        thread::sleep(Duration::from_millis(subargs.interval));
+    }
+}
+
+fn neighbors(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+) -> Result<()> {
+    let count =
+        IdolOperation::new(hubris, INTERFACE, "read_ksz8463_mac_count", None)?;
+    let entry =
+        IdolOperation::new(hubris, INTERFACE, "read_ksz8463_mac", None)?;
+
+    let val = call(core, context, &count, &[])?;
+
+    let n = match val.len() {
+        4 => u32::from_le_bytes(val[..].try_into()?) as usize,
+        len => bail!("unexpected MAC table count length {}", len),
+    };
+
+    let fmt = HubrisPrintFormat {
+        newline: false,
+        hex: true,
+        ..HubrisPrintFormat::default()
+    };
+
+    println!("neighbor table ({} entries):", n);
+
+    for i in 0..n {
+        let args = [("i", IdolArgument::Scalar(i as u64))];
+        let val = call(core, context, &entry, &args)?;
+        println!("{:>5} {}", i, hubris.printfmt(&val, entry.ok, &fmt)?);
+    }
+
+    Ok(())
+}
+
+struct Pcap {
+    file: File,
+    started: SystemTime,
+    first: Option<u32>,
+}
+
+impl Pcap {
+    fn create(filename: &str) -> Result<Self> {
+        let mut file = File::create(filename)
+            .with_context(|| format!("failed to create {}", filename))?;
+
+        //
+        // The pcap global header:  magic, version 2.4, a zero timezone
+        // offset and accuracy, our snap length and a link type of Ethernet.
+        //
+        let mut hdr = vec![];
+        hdr.extend(0xa1b2c3d4u32.to_le_bytes());
+        hdr.extend(2u16.to_le_bytes());
+        hdr.extend(4u16.to_le_bytes());
+        hdr.extend(0i32.to_le_bytes());
+        hdr.extend(0u32.to_le_bytes());
+        hdr.extend(65535u32.to_le_bytes());
+        hdr.extend(1u32.to_le_bytes());
+        file.write_all(&hdr)?;
+
+        Ok(Self { file, started: SystemTime::now(), first: None })
+    }
+
+    ///
+    /// Writes a frame, timestamped relative to the start of capture (as
+    /// target ticks are milliseconds since boot).
+    ///
+    fn write(&mut self, ticks: u32, frame: &[u8]) -> Result<()> {
+        let first = *self.first.get_or_insert(ticks);
+        let offset = Duration::from_millis(ticks.wrapping_sub(first) as u64);
+        let time =
+            (self.started + offset).duration_since(SystemTime::UNIX_EPOCH)?;
+
+        let mut rec = vec![];
+        rec.extend((time.as_secs() as u32).to_le_bytes());
+        rec.extend(time.subsec_micros().to_le_bytes());
+        rec.extend((frame.len() as u32).to_le_bytes());
+        rec.extend((frame.len() as u32).to_le_bytes());
+        rec.extend(frame);
+        self.file.write_all(&rec)?;
+
+        Ok(())
+    }
+}
+
+///
+/// Decodes the frames in a drained capture buffer, returning each frame's
+/// timestamp and contents.
+///
+fn frames(buf: &[u8]) -> Result<Vec<(u32, &[u8])>> {
+    let mut rval = vec![];
+    let mut offs = 0;
+
+    while offs + 8 <= buf.len() {
+        let ticks = u32::from_le_bytes(buf[offs..offs + 4].try_into()?);
+        let len = u16::from_le_bytes(buf[offs + 4..offs + 6].try_into()?);
+        let start = offs + 8;
+        let end = start + len as usize;
+
+        if end > buf.len() {
+            bail!("capture frame at offset {} is truncated", offs);
+        }
+
+        rval.push((ticks, &buf[start..end]));
+        offs = (end + 3) & !3;
+    }
+
+    Ok(rval)
+}
+
+fn capture(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    subargs: &NetArgs,
+    filename: &str,
+) -> Result<()> {
+    let start = IdolOperation::new(hubris, INTERFACE, "capture_start", None)
+        .context("network task does not support capture")?;
+    let stop = IdolOperation::new(hubris, INTERFACE, "capture_stop", None)?;
+    let read = IdolOperation::new(hubris, INTERFACE, "capture_read", None)?;
+
+    let mut pcap = Pcap::create(filename)?;
+    let len = context.data_size() as u32;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let d = done.clone();
+
+    ctrlc::set_handler(move || {
+        d.store(true, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    call(core, context, &start, &[])?;
+    humility::msg!("capturing to {}; ^C to stop", filename);
+
+    let mut nframes = 0;
+    let mut nbytes = 0;
+
+    //
+    // Drains the capture buffer, returning the number of frames and bytes
+    // captured.
+    //
+    let mut drain = |core: &mut dyn Core| -> Result<(usize, usize)> {
+        let val = call_read(core, context, &read, &[], Some(len))?;
+
+        if val.len() != 4 {
+            bail!("bad capture_read reply {:?}", val);
+        }
+
+        let drained = u32::from_le_bytes(val[..].try_into()?) as usize;
+
+        let buf = context.read_data(core, 0, drained)?;
+        let mut rval = (0, 0);
+
+        for (ticks, frame) in frames(&buf)? {
+            pcap.write(ticks, frame)?;
+            rval.0 += 1;
+            rval.1 += frame.len();
+        }
+
+        Ok(rval)
+    };
+
+    let rval = loop {
+        if done.load(Ordering::SeqCst)
+            || subargs.frames.map_or(false, |max| nframes >= max)
+        {
+            break Ok(());
+        }
+
+        match drain(core) {
+            Ok((0, _)) => thread::sleep(Duration::from_millis(100)),
+            Ok((f, b)) => {
+                nframes += f;
+                nbytes += b;
+            }
+            Err(err) => break Err(err),
+        }
+    };
+
+    call(core, context, &stop, &[])?;
+    rval?;
+
+    humility::msg!("captured {} frames ({} bytes)", nframes, nbytes);
+
+    Ok(())
+}
+
+fn net(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = NetArgs::try_parse_from(subargs)?;
+    let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+
+    if let Some(ref filename) = subargs.capture {
+        return capture(hubris, core, &mut context, &subargs, filename);
+    }
+
+    if subargs.follow {
+        return counters(hubris, core, &mut context, &subargs);
+    }
+
+    if !subargs.counters && !subargs.neighbors {
+        return status(hubris, core, &mut context);
+    }
+
+    if subargs.counters {
+        counters(hubris, core, &mut context, &subargs)?;
+    }
+
+    if subargs.neighbors {
+        neighbors(hubris, core, &mut context)?;
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "net",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: net,
+        },
+        NetArgs::command(),
+    )
+}
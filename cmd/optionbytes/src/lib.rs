@@ -0,0 +1,600 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility optionbytes`
+//!
+//! `humility optionbytes` displays, backs up and programs the option bytes
+//! of the attached MCU -- including its readout protection (RDP) level,
+//! security state and boot addresses.  Option bytes are currently only
+//! supported on the STM32H743/STM32H753.  To display the option bytes:
+//!
+//! ```console
+//! % humility optionbytes status
+//! humility: attached via ST-Link V3
+//! OPTION       VALUE
+//! rdp          level 0 (0xaa)
+//! security     disabled
+//! swap_bank    disabled
+//! bor_lev      0
+//! iwdg1_sw     software
+//! boot0        0x08000000
+//! boot1        0x1ff00000
+//! optsr        0x1c6aaf0
+//! boot         0x1ff00800
+//! ...
+//! ```
+//!
+//! To back up the option bytes to a file, use `backup`; the backup can later
+//! be restored with `restore`:
+//!
+//! ```console
+//! % humility optionbytes backup ob.json
+//! humility: attached via ST-Link V3
+//! humility: backed up STM32H7 option bytes to ob.json
+//! % humility optionbytes restore ob.json
+//! humility: attached via ST-Link V3
+//! humility: boot: 0x1ff00800 -> 0x08000800
+//! program 1 option byte register? [y/N] y
+//! humility: backed up STM32H7 option bytes to optionbytes-0034001e3438510a33373330-1665446400.json
+//! humility: programmed and verified 1 option byte register
+//! ```
+//!
+//! To change the boot addresses, the brown-out reset level or the RDP level,
+//! use `set`:
+//!
+//! ```console
+//! % humility optionbytes set --boot0 0x08000000
+//! ```
+//!
+//! Mis-set option bytes can render a board unusable, so every change is
+//! displayed and must be confirmed interactively (unless `-y` (`--yes`) is
+//! specified), and the option bytes are always backed up to a file in the
+//! current directory before they are programmed.  Because lowering the RDP
+//! level erases flash, changing the RDP level when restoring requires
+//! `--allow-rdp`; RDP level 2 is permanent, and will never be programmed.
+//! The secure area registers (`scar1` and `scar2`) are backed up but not
+//! restored; use `humility stmsecure` to change the secure area.
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::output::{Column, Table};
+use humility_cmd::{confirm, Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::*;
+use humility_cortex::scs::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Parser, Debug)]
+#[clap(name = "optionbytes", about = env!("CARGO_PKG_DESCRIPTION"))]
+enum OptionBytesArgs {
+    /// display the option bytes
+    Status,
+
+    /// back up the option bytes to a file
+    Backup { file: String },
+
+    /// restore the option bytes from a backup
+    Restore {
+        file: String,
+
+        /// allow the RDP level to be changed (which may erase flash)
+        #[clap(long)]
+        allow_rdp: bool,
+
+        /// do not prompt for confirmation
+        #[clap(long, short)]
+        yes: bool,
+    },
+
+    /// set the RDP level, brown-out reset level or boot addresses
+    Set {
+        /// readout protection level (0 or 1)
+        #[clap(long, value_name = "level",
+            parse(try_from_str = parse_int::parse),
+        )]
+        rdp: Option<u8>,
+
+        /// brown-out reset level (0 to 3)
+        #[clap(long, value_name = "level",
+            parse(try_from_str = parse_int::parse),
+        )]
+        bor: Option<u8>,
+
+        /// boot address when BOOT0 is low
+        #[clap(long, value_name = "address",
+            parse(try_from_str = parse_int::parse),
+        )]
+        boot0: Option<u32>,
+
+        /// boot address when BOOT0 is high
+        #[clap(long, value_name = "address",
+            parse(try_from_str = parse_int::parse),
+        )]
+        boot1: Option<u32>,
+
+        /// do not prompt for confirmation
+        #[clap(long, short)]
+        yes: bool,
+    },
+}
+
+const FLASH_BASE: u32 = 0x5200_2000;
+const FLASH_OPT_KEYR: u32 = FLASH_BASE + 0x08;
+const FLASH_OPT_CR: u32 = FLASH_BASE + 0x18;
+const FLASH_OPT_CCR: u32 = FLASH_BASE + 0x24;
+
+const FLASH_OPT_KEY1: u32 = 0x0819_2A3B;
+const FLASH_OPT_KEY2: u32 = 0x4C5D_6E7F;
+
+const OPT_CR_OPTLOCK: u32 = 1 << 0;
+const OPT_CR_OPTSTART: u32 = 1 << 1;
+const OPTSR_OPT_BUSY: u32 = 1 << 0;
+const OPTSR_OPTCHANGEERR: u32 = 1 << 30;
+const OPT_CCR_CLR_OPTCHANGEERR: u32 = 1 << 30;
+
+const OPTSR_RDP_MASK: u32 = 0x0000_ff00;
+const OPTSR_RDP_SHIFT: u32 = 8;
+const OPTSR_BOR_MASK: u32 = 0x0000_000c;
+const OPTSR_BOR_SHIFT: u32 = 2;
+
+const RDP_LEVEL0: u32 = 0xaa;
+const RDP_LEVEL1: u32 = 0xbb;
+const RDP_LEVEL2: u32 = 0xcc;
+
+const UID: u32 = 0x1ff1_e800;
+
+///
+/// An option byte register:  its current value is read from `cur`, and new
+/// values are programmed via `prg` (if the register can be restored).  Bits
+/// outside of `mask` are status bits rather than option bits.
+///
+struct Register {
+    name: &'static str,
+    cur: u32,
+    prg: Option<u32>,
+    mask: u32,
+}
+
+//
+// The option byte registers on the STM32H743/STM32H753.  The secure area
+// registers can only be changed via the RSS (see `humility stmsecure`).
+//
+const REGISTERS: &[Register] = &[
+    Register {
+        name: "optsr",
+        cur: FLASH_BASE + 0x1c,
+        prg: Some(FLASH_BASE + 0x20),
+        mask: !(OPTSR_OPT_BUSY | OPTSR_OPTCHANGEERR),
+    },
+    Register {
+        name: "boot",
+        cur: FLASH_BASE + 0x40,
+        prg: Some(FLASH_BASE + 0x44),
+        mask: 0xffff_ffff,
+    },
+    Register {
+        name: "prar1",
+        cur: FLASH_BASE + 0x28,
+        prg: Some(FLASH_BASE + 0x2c),
+        mask: 0xffff_ffff,
+    },
+    Register {
+        name: "scar1",
+        cur: FLASH_BASE + 0x30,
+        prg: None,
+        mask: 0xffff_ffff,
+    },
+    Register {
+        name: "wpsn1",
+        cur: FLASH_BASE + 0x38,
+        prg: Some(FLASH_BASE + 0x3c),
+        mask: 0xffff_ffff,
+    },
+    Register {
+        name: "prar2",
+        cur: FLASH_BASE + 0x128,
+        prg: Some(FLASH_BASE + 0x12c),
+        mask: 0xffff_ffff,
+    },
+    Register {
+        name: "scar2",
+        cur: FLASH_BASE + 0x130,
+        prg: None,
+        mask: 0xffff_ffff,
+    },
+    Register {
+        name: "wpsn2",
+        cur: FLASH_BASE + 0x138,
+        prg: Some(FLASH_BASE + 0x13c),
+        mask: 0xffff_ffff,
+    },
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Backup {
+    chip: String,
+    uid: String,
+    registers: BTreeMap<String, u32>,
+}
+
+fn register(name: &str) -> &'static Register {
+    REGISTERS.iter().find(|r| r.name == name).unwrap()
+}
+
+fn chip(core: &mut dyn Core) -> Result<(String, String)> {
+    let coreinfo = CoreInfo::read(core)?;
+
+    if coreinfo.vendor != Vendor::ST || coreinfo.part != ARMCore::CortexM7 {
+        bail!("option bytes are only supported on the STM32H7");
+    }
+
+    let idc = STM32H7_DBGMCU_IDC::read(core)?;
+
+    if idc.dev_id() != 0x450 {
+        bail!(
+            "option bytes are not supported on {} (0x{:x})",
+            stm32_chipname(idc.dev_id()),
+            idc.dev_id()
+        );
+    }
+
+    let mut uid = [0u8; 12];
+    core.read_8(UID, &mut uid)?;
+
+    Ok((
+        stm32_chipname(idc.dev_id()),
+        uid.iter().map(|b| format!("{:02x}", b)).collect(),
+    ))
+}
+
+fn read(core: &mut dyn Core) -> Result<Backup> {
+    let (chip, uid) = chip(core)?;
+    let mut registers = BTreeMap::new();
+
+    for r in REGISTERS {
+        registers
+            .insert(r.name.to_string(), core.read_word_32(r.cur)? & r.mask);
+    }
+
+    Ok(Backup { chip, uid, registers })
+}
+
+fn rdp(optsr: u32) -> u32 {
+    (optsr & OPTSR_RDP_MASK) >> OPTSR_RDP_SHIFT
+}
+
+fn rdp_level(rdp: u32) -> String {
+    match rdp {
+        RDP_LEVEL0 => format!("level 0 (0x{:02x})", rdp),
+        RDP_LEVEL2 => format!("level 2 (0x{:02x})", rdp),
+        _ => format!("level 1 (0x{:02x})", rdp),
+    }
+}
+
+fn save(backup: &Backup, file: &str) -> Result<()> {
+    std::fs::write(file, serde_json::to_string_pretty(backup)?)?;
+
+    humility::msg!("backed up {} option bytes to {}", backup.chip, file);
+
+    Ok(())
+}
+
+fn status(core: &mut dyn Core, args: &Args) -> Result<()> {
+    let backup = read(core)?;
+    let optsr = backup.registers["optsr"];
+    let boot = backup.registers["boot"];
+
+    let enabled =
+        |bit: u32| if optsr & (1 << bit) != 0 { "enabled" } else { "disabled" };
+
+    let mut table = Table::new(
+        args.format,
+        vec![Column::new("option", 12), Column::new("value", 0)],
+    );
+
+    let fields = vec![
+        ("rdp", rdp_level(rdp(optsr))),
+        ("security", enabled(21).to_string()),
+        ("swap_bank", enabled(31).to_string()),
+        ("bor_lev", ((optsr & OPTSR_BOR_MASK) >> OPTSR_BOR_SHIFT).to_string()),
+        (
+            "iwdg1_sw",
+            if optsr & (1 << 4) != 0 { "software" } else { "hardware" }
+                .to_string(),
+        ),
+        ("boot0", format!("0x{:08x}", (boot & 0xffff) << 16)),
+        ("boot1", format!("0x{:08x}", boot & 0xffff_0000)),
+    ];
+
+    for (name, value) in fields {
+        table.row(vec![name.into(), value.into()])?;
+    }
+
+    for r in REGISTERS {
+        let value = format!("0x{:x}", backup.registers[r.name]);
+        table.row(vec![r.name.into(), value.into()])?;
+    }
+
+    Ok(())
+}
+
+///
+/// Programs the specified option byte registers, after validating the
+/// changes, asking for confirmation, and backing up the current values.
+///
+fn program(
+    core: &mut dyn Core,
+    current: &Backup,
+    changes: &[(&Register, u32)],
+    yes: bool,
+) -> Result<()> {
+    if changes.is_empty() {
+        humility::msg!("option bytes are unchanged");
+        return Ok(());
+    }
+
+    for (r, value) in changes {
+        let was = current.registers[r.name];
+
+        if r.prg.is_none() {
+            bail!("{} cannot be changed via option bytes", r.name);
+        }
+
+        if r.name == "optsr" && rdp(*value) != rdp(was) {
+            if rdp(*value) == RDP_LEVEL2 {
+                bail!("refusing to set RDP level 2, which is permanent");
+            }
+
+            if rdp(*value) == RDP_LEVEL0 {
+                humility::msg!(
+                    "lowering RDP from {} will ERASE FLASH",
+                    rdp_level(rdp(was))
+                );
+            }
+        }
+
+        humility::msg!("{}: 0x{:08x} -> 0x{:08x}", r.name, was, value);
+    }
+
+    let prompt = format!(
+        "program {} option byte register{}?",
+        changes.len(),
+        if changes.len() == 1 { "" } else { "s" }
+    );
+
+    if !yes && !confirm(&prompt)? {
+        bail!("option bytes not programmed");
+    }
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let file = format!("optionbytes-{}-{}.json", current.uid, now.as_secs());
+    save(current, &file)?;
+
+    //
+    // Unlock the option bytes, and clear any prior error.
+    //
+    if core.read_word_32(FLASH_OPT_CR)? & OPT_CR_OPTLOCK != 0 {
+        core.write_word_32(FLASH_OPT_KEYR, FLASH_OPT_KEY1)?;
+        core.write_word_32(FLASH_OPT_KEYR, FLASH_OPT_KEY2)?;
+
+        if core.read_word_32(FLASH_OPT_CR)? & OPT_CR_OPTLOCK != 0 {
+            bail!("failed to unlock option bytes");
+        }
+    }
+
+    core.write_word_32(FLASH_OPT_CCR, OPT_CCR_CLR_OPTCHANGEERR)?;
+
+    for (r, value) in changes {
+        let prg = r.prg.unwrap();
+        let old = core.read_word_32(prg)?;
+        core.write_word_32(prg, (old & !r.mask) | (value & r.mask))?;
+    }
+
+    let cr = core.read_word_32(FLASH_OPT_CR)?;
+    core.write_word_32(FLASH_OPT_CR, cr | OPT_CR_OPTSTART)?;
+
+    //
+    // Programming can take some time -- especially if RDP has been lowered,
+    // which erases flash.
+    //
+    let optsr = register("optsr").cur;
+    let started = Instant::now();
+
+    let status = loop {
+        let status = core.read_word_32(optsr)?;
+
+        if status & OPTSR_OPT_BUSY == 0 {
+            break status;
+        }
+
+        if started.elapsed() > Duration::from_secs(60) {
+            bail!("timed out waiting for option bytes; backup is in {}", file);
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    let cr = core.read_word_32(FLASH_OPT_CR)?;
+    core.write_word_32(FLASH_OPT_CR, cr | OPT_CR_OPTLOCK)?;
+
+    if status & OPTSR_OPTCHANGEERR != 0 {
+        bail!("option byte change failed; backup is in {}", file);
+    }
+
+    for (r, value) in changes {
+        let actual = core.read_word_32(r.cur)? & r.mask;
+
+        if actual != *value {
+            bail!(
+                "{} is 0x{:08x} after programming, expected 0x{:08x}; \
+                backup is in {}",
+                r.name,
+                actual,
+                value,
+                file
+            );
+        }
+    }
+
+    humility::msg!(
+        "programmed and verified {} option byte register{}",
+        changes.len(),
+        if changes.len() == 1 { "" } else { "s" }
+    );
+
+    Ok(())
+}
+
+fn restore(
+    core: &mut dyn Core,
+    file: &str,
+    allow_rdp: bool,
+    yes: bool,
+) -> Result<()> {
+    let backup: Backup = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+    let current = read(core)?;
+
+    if backup.uid != current.uid {
+        humility::msg!(
+            "backup is from {} (UID {}), not this {} (UID {})",
+            backup.chip,
+            backup.uid,
+            current.chip,
+            current.uid
+        );
+
+        if !yes && !confirm("restore option bytes from another part?")? {
+            bail!("option bytes not restored");
+        }
+    }
+
+    let mut changes = vec![];
+
+    for r in REGISTERS {
+        let value = match backup.registers.get(r.name) {
+            Some(value) => value & r.mask,
+            None => bail!("backup is missing {}", r.name),
+        };
+
+        if value == current.registers[r.name] {
+            continue;
+        }
+
+        if r.prg.is_none() {
+            humility::msg!(
+                "{} differs from backup; use humility stmsecure to change it",
+                r.name
+            );
+            continue;
+        }
+
+        if r.name == "optsr"
+            && rdp(value) != rdp(current.registers[r.name])
+            && !allow_rdp
+        {
+            bail!(
+                "backup changes RDP from {} to {}; use --allow-rdp to restore",
+                rdp_level(rdp(current.registers[r.name])),
+                rdp_level(rdp(value)),
+            );
+        }
+
+        changes.push((r, value));
+    }
+
+    program(core, &current, &changes, yes)
+}
+
+fn set(
+    core: &mut dyn Core,
+    level: Option<u8>,
+    bor: Option<u8>,
+    boot: (Option<u32>, Option<u32>),
+    yes: bool,
+) -> Result<()> {
+    let current = read(core)?;
+    let mut optsr = current.registers["optsr"];
+    let mut bootval = current.registers["boot"];
+
+    match level {
+        Some(0) => {
+            optsr = (optsr & !OPTSR_RDP_MASK) | (RDP_LEVEL0 << OPTSR_RDP_SHIFT)
+        }
+        Some(1) => {
+            optsr = (optsr & !OPTSR_RDP_MASK) | (RDP_LEVEL1 << OPTSR_RDP_SHIFT)
+        }
+        Some(2) => bail!("refusing to set RDP level 2, which is permanent"),
+        Some(level) => bail!("invalid RDP level {}", level),
+        None => {}
+    }
+
+    if let Some(bor) = bor {
+        if bor > 3 {
+            bail!("invalid brown-out reset level {}", bor);
+        }
+
+        optsr = (optsr & !OPTSR_BOR_MASK) | ((bor as u32) << OPTSR_BOR_SHIFT);
+    }
+
+    for (ndx, addr) in [boot.0, boot.1].iter().enumerate() {
+        if let Some(addr) = addr {
+            if addr & 0xffff != 0 {
+                bail!("boot address 0x{:x} is not 64K-aligned", addr);
+            }
+
+            let shift = ndx * 16;
+            bootval = (bootval & !(0xffff << shift)) | ((addr >> 16) << shift);
+        }
+    }
+
+    let mut changes = vec![];
+
+    if optsr != current.registers["optsr"] {
+        changes.push((register("optsr"), optsr));
+    }
+
+    if bootval != current.registers["boot"] {
+        changes.push((register("boot"), bootval));
+    }
+
+    program(core, &current, &changes, yes)
+}
+
+fn optionbytes(
+    _hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = OptionBytesArgs::try_parse_from(subargs)?;
+
+    match subargs {
+        OptionBytesArgs::Status => status(core, args),
+        OptionBytesArgs::Backup { file } => save(&read(core)?, &file),
+        OptionBytesArgs::Restore { file, allow_rdp, yes } => {
+            restore(core, &file, allow_rdp, yes)
+        }
+        OptionBytesArgs::Set { rdp, bor, boot0, boot1, yes } => {
+            set(core, rdp, bor, (boot0, boot1), yes)
+        }
+    }
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "optionbytes",
+            archive: Archive::Optional,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: optionbytes,
+        },
+        OptionBytesArgs::command(),
+    )
+}
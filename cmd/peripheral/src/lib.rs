@@ -0,0 +1,371 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility peripheral`
+//!
+//! `humility peripheral` reads and writes peripheral registers by name,
+//! decoding their fields according to the CMSIS-SVD description of the
+//! target MCU.  The SVD file is taken from the archive (if it contains a
+//! file ending in `.svd`), or can be specified with `-s` (`--svd`) or the
+//! `HUMILITY_SVD` environment variable.
+//!
+//! To display a register and decode its fields, specify it as the
+//! peripheral name and register name, separated by a period:
+//!
+//! ```console
+//! % humility peripheral RCC.CFGR
+//! humility: attached via ST-Link V3
+//! RCC.CFGR (0x58024410) = 0x00000018
+//!         [2:0] SW                   0x0 HSI
+//!         [5:3] SWS                  0x3 PLL1
+//!           [6] STOPWUCK             0x0 HSI
+//!           [7] STOPKERWUCK          0x0 HSI
+//!        [13:8] RTCPRE               0x0
+//!          [14] HRTIMSEL             0x0
+//!          [15] TIMPRE               0x0
+//!       [21:18] MCO1PRE              0x0
+//!       [24:22] MCO1SEL              0x0 HSI
+//!       [28:25] MCO2PRE              0x0
+//!       [31:29] MCO2SEL              0x0 SYSCLK
+//! ```
+//!
+//! Specifying only a peripheral displays all of its registers, and
+//! specifying nothing at all lists the peripherals in the SVD file.  Names
+//! are not case-sensitive.
+//!
+//! To write a register, follow its name with `=` and a value; to write a
+//! single field (leaving the register's other fields untouched), name the
+//! field as well.  A field value can be given as a number or as the name of
+//! one of the field's enumerated values:
+//!
+//! ```console
+//! % humility peripheral GPIOI.ODR=0x8000
+//! humility: attached via ST-Link V3
+//! humility: GPIOI.ODR (0x58022014): 0x00000000 -> 0x00008000
+//! % humility peripheral RCC.CFGR.MCO2SEL=PLL1_P
+//! humility: attached via ST-Link V3
+//! humility: RCC.CFGR (0x58024410): 0x00000018 -> 0x40000018
+//! ```
+//!
+//! Note that reading some registers can have side-effects (e.g., clearing
+//! status bits or popping a FIFO); care should be taken when displaying all
+//! registers of a peripheral.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::io::{Cursor, Read};
+use svd_parser::svd::{Device, Field, PeripheralInfo, RegisterInfo};
+
+#[derive(Parser, Debug)]
+#[clap(name = "peripheral", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct PeripheralArgs {
+    /// CMSIS-SVD file describing the target
+    #[clap(long, short, value_name = "file", env = "HUMILITY_SVD")]
+    svd: Option<String>,
+
+    /// peripheral, register or field to read or write, e.g. RCC.CFGR
+    #[clap(value_name = "peripheral[.register[.field]][=value]")]
+    register: Option<String>,
+}
+
+fn load_svd(
+    hubris: &HubrisArchive,
+    subargs: &PeripheralArgs,
+) -> Result<Device> {
+    let xml = match &subargs.svd {
+        Some(svd) => std::fs::read_to_string(svd)
+            .with_context(|| format!("failed to read SVD file \"{}\"", svd))?,
+        None => {
+            if hubris.archive().is_empty() {
+                bail!("no archive loaded; must specify an SVD file");
+            }
+
+            let cursor = Cursor::new(hubris.archive());
+            let mut archive = zip::ZipArchive::new(cursor)?;
+
+            let name = archive
+                .file_names()
+                .find(|name| name.to_lowercase().ends_with(".svd"))
+                .map(|name| name.to_string())
+                .ok_or_else(|| {
+                    anyhow!("archive does not contain an SVD file; use --svd")
+                })?;
+
+            let mut xml = String::new();
+            archive.by_name(&name)?.read_to_string(&mut xml)?;
+            xml
+        }
+    };
+
+    svd_parser::parse(&xml).context("failed to parse SVD")
+}
+
+fn lookup_peripheral<'a>(
+    device: &'a Device,
+    name: &str,
+) -> Result<&'a PeripheralInfo> {
+    device
+        .peripherals
+        .iter()
+        .find(|p| p.name.eq_ignore_ascii_case(name))
+        .map(|p| &**p)
+        .ok_or_else(|| anyhow!("no such peripheral \"{}\"", name))
+}
+
+///
+/// Returns the registers of a peripheral, following `derivedFrom` if the
+/// peripheral has no registers of its own.
+///
+fn registers<'a>(
+    device: &'a Device,
+    peripheral: &'a PeripheralInfo,
+) -> Result<Vec<&'a RegisterInfo>> {
+    match (&peripheral.registers, &peripheral.derived_from) {
+        (None, Some(base)) => {
+            let base = lookup_peripheral(device, base)?;
+            Ok(base.registers().map(|r| &**r).collect())
+        }
+        _ => Ok(peripheral.registers().map(|r| &**r).collect()),
+    }
+}
+
+fn register_size(register: &RegisterInfo) -> Result<u32> {
+    match register.properties.size.unwrap_or(32) {
+        size @ (8 | 16 | 32) => Ok(size),
+        size => {
+            bail!("register {} has unsupported size {}", register.name, size)
+        }
+    }
+}
+
+fn read_register(
+    core: &mut dyn Core,
+    addr: u32,
+    register: &RegisterInfo,
+) -> Result<u32> {
+    match register_size(register)? {
+        32 => core.read_word_32(addr),
+        size => {
+            let mut buf = [0u8; 4];
+            core.read_8(addr, &mut buf[..(size / 8) as usize])?;
+            Ok(u32::from_le_bytes(buf))
+        }
+    }
+}
+
+fn write_register(
+    core: &mut dyn Core,
+    addr: u32,
+    register: &RegisterInfo,
+    val: u32,
+) -> Result<()> {
+    match register_size(register)? {
+        32 => core.write_word_32(addr, val),
+        size => core.write_8(addr, &val.to_le_bytes()[..(size / 8) as usize]),
+    }
+}
+
+fn field_mask(field: &Field) -> u32 {
+    let width = field.bit_range.width;
+
+    if width >= 32 {
+        u32::MAX
+    } else {
+        ((1 << width) - 1) << field.bit_range.offset
+    }
+}
+
+fn field_value_name(field: &Field, val: u32) -> Option<&str> {
+    field
+        .enumerated_values
+        .iter()
+        .flat_map(|e| e.values.iter())
+        .find(|v| v.value == Some(val as u64))
+        .map(|v| v.name.as_str())
+}
+
+fn parse_field_value(field: &Field, val: &str) -> Result<u32> {
+    if let Ok(val) = parse_int::parse::<u32>(val) {
+        return Ok(val);
+    }
+
+    field
+        .enumerated_values
+        .iter()
+        .flat_map(|e| e.values.iter())
+        .find(|v| v.name.eq_ignore_ascii_case(val))
+        .and_then(|v| v.value)
+        .map(|v| v as u32)
+        .ok_or_else(|| anyhow!("invalid value \"{}\" for {}", val, field.name))
+}
+
+fn print_register(name: &str, addr: u32, register: &RegisterInfo, val: u32) {
+    println!("{} (0x{:08x}) = 0x{:08x}", name, addr, val);
+
+    let mut fields: Vec<_> = register.fields().collect();
+    fields.sort_by_key(|f| f.bit_range.offset);
+
+    for field in fields {
+        let lsb = field.bit_range.offset;
+        let msb = lsb + field.bit_range.width - 1;
+
+        let bits = if msb == lsb {
+            format!("[{}]", lsb)
+        } else {
+            format!("[{}:{}]", msb, lsb)
+        };
+
+        let fval = (val & field_mask(field)) >> lsb;
+
+        println!(
+            "{:>14} {:20} {:>5} {}",
+            bits,
+            field.name,
+            format!("0x{:x}", fval),
+            field_value_name(field, fval).unwrap_or("")
+        );
+    }
+}
+
+fn peripheral(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = PeripheralArgs::try_parse_from(subargs)?;
+    let device = load_svd(hubris, &subargs)?;
+
+    let spec = match &subargs.register {
+        Some(spec) => spec,
+        None => {
+            println!("{:20} {:>10}", "PERIPHERAL", "BASE");
+
+            for p in &device.peripherals {
+                println!("{:20} 0x{:08x}", p.name, p.base_address);
+            }
+
+            return Ok(());
+        }
+    };
+
+    let (path, value) = match spec.split_once('=') {
+        Some((path, value)) => (path, Some(value)),
+        None => (spec.as_str(), None),
+    };
+
+    let names: Vec<&str> = path.split('.').collect();
+    let p = lookup_peripheral(&device, names[0])?;
+    let regs = registers(&device, p)?;
+
+    if names.len() == 1 {
+        if value.is_some() {
+            bail!("must specify a register to write");
+        }
+
+        for register in regs {
+            let addr = p.base_address as u32 + register.address_offset;
+            let val = read_register(core, addr, register)?;
+            println!(
+                "{:30} (0x{:08x}) = 0x{:08x}",
+                format!("{}.{}", p.name, register.name),
+                addr,
+                val
+            );
+        }
+
+        return Ok(());
+    }
+
+    if names.len() > 3 {
+        bail!("expected peripheral[.register[.field]], found \"{}\"", path);
+    }
+
+    let register = regs
+        .iter()
+        .find(|r| r.name.eq_ignore_ascii_case(names[1]))
+        .ok_or_else(|| anyhow!("no register \"{}\" in {}", names[1], p.name))?;
+
+    let name = format!("{}.{}", p.name, register.name);
+    let addr = p.base_address as u32 + register.address_offset;
+
+    let field = match names.get(2) {
+        Some(fname) => Some(
+            register
+                .fields()
+                .find(|f| f.name.eq_ignore_ascii_case(fname))
+                .ok_or_else(|| anyhow!("no field \"{}\" in {}", fname, name))?,
+        ),
+        None => None,
+    };
+
+    let val = read_register(core, addr, register)?;
+
+    match (value, field) {
+        (None, None) => print_register(&name, addr, register, val),
+        (None, Some(field)) => {
+            let fval = (val & field_mask(field)) >> field.bit_range.offset;
+
+            println!(
+                "{}.{} = 0x{:x} {}",
+                name,
+                field.name,
+                fval,
+                field_value_name(field, fval).unwrap_or("")
+            );
+        }
+        (Some(value), None) => {
+            let nval = parse_int::parse::<u32>(value)
+                .with_context(|| format!("invalid value \"{}\"", value))?;
+            write_register(core, addr, register, nval)?;
+            humility::msg!(
+                "{} (0x{:08x}): 0x{:08x} -> 0x{:08x}",
+                name,
+                addr,
+                val,
+                nval
+            );
+        }
+        (Some(value), Some(field)) => {
+            let fval = parse_field_value(field, value)?;
+            let mask = field_mask(field);
+            let shifted = fval.checked_shl(field.bit_range.offset).unwrap_or(0);
+
+            if shifted & !mask != 0 || shifted >> field.bit_range.offset != fval
+            {
+                bail!("value 0x{:x} does not fit in {}", fval, field.name);
+            }
+
+            let nval = (val & !mask) | shifted;
+            write_register(core, addr, register, nval)?;
+            humility::msg!(
+                "{} (0x{:08x}): 0x{:08x} -> 0x{:08x}",
+                name,
+                addr,
+                val,
+                nval
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "peripheral",
+            archive: Archive::Optional,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: peripheral,
+        },
+        PeripheralArgs::command(),
+    )
+}
@@ -2,6 +2,54 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! ## `humility pmbus`
+//!
+//! `humility pmbus` operates on PMBus devices in the system.  To list all
+//! PMBus devices, use `-l` (`--list`); to summarize their rails, use `-s`
+//! (`--summarize`).  To read all commands from a device, specify the
+//! device (e.g. with `-d` or by rail with `-r`); to read specific commands,
+//! use `-C`.
+//!
+//! To write to a device, use `-w` (or its alias `--set`), specifying the
+//! command and its value.  Values for commands that are encoded (e.g., as
+//! LINEAR11 or VID) may be specified in engineering units, and will be
+//! encoded according to the format of the command (and, for output
+//! voltages, the device's `VOUT_MODE`).  A unit suffix is permitted, and a
+//! milli- prefix will be scaled accordingly:
+//!
+//! ```console
+//! % humility pmbus -r V0P96_NIC_VDD_A0HP -w VOUT_COMMAND=0.95V
+//! humility: attached via ST-Link V3
+//! humility: I2C3, port H, dev 0x5a, rail 0: successfully wrote VOUT_COMMAND = 0.950V
+//! ```
+//!
+//! After each such write, the command is read back and compared to what was
+//! written; a mismatch results in an error.  To set a field of a command
+//! that consists of bitfields, name the field and the value to set it to
+//! (e.g., `-w COMMAND.FIELD=VALUE`); use `-H` with
+//! the command name for the fields and values it supports.
+//!
+//! To check the health of every PMBus rail in the system at once, use
+//! `--health`:  this reads `STATUS_WORD` and the other status registers of
+//! each rail, and displays a matrix of their values, with non-zero values
+//! (i.e., those indicating a fault or warning) in red; a `-` denotes a
+//! register not supported by the device and an `X` a register that could
+//! not be read.  With `-v`, the bits set in each register are listed by
+//! name.  To clear faults after they have been read, add `--clear-faults`:
+//!
+//! ```console
+//! % humility pmbus --health --clear-faults
+//! humility: attached via ST-Link V3
+//! DEVICE        RAIL               WORD   VOUT   IOUT  INPUT   TEMP    CML    MFR CLEAR
+//! raa229618     VDD_VCORE        0x0000   0x00   0x00   0x00   0x00   0x00   0x00 cleared
+//! raa229618     VDD_MEM_ABCD     0x0000   0x00   0x00   0x00   0x00   0x00   0x00 cleared
+//! isl68224      VDD_MEM_EFGH     0x0840   0x00   0x00   0x08   0x00   0x00   0x00 cleared
+//! ...
+//!
+//! 1 of 12 rails with non-zero status (faults cleared)
+//! ```
+//!
+
 use colored::Colorize;
 use humility::core::Core;
 use humility::hubris::*;
@@ -43,6 +91,19 @@ struct PmbusArgs {
     )]
     summarize: bool,
 
+    /// check the status registers of all PMBus components
+    #[clap(
+        long, conflicts_with_all = &[
+            "driver", "controller", "port", "bus", "summarize", "list",
+            "commands", "writes",
+        ]
+    )]
+    health: bool,
+
+    /// clear faults after checking health
+    #[clap(long, requires = "health")]
+    clear_faults: bool,
+
     /// command-specific help
     #[clap(long, short = 'H', value_name = "command")]
     commandhelp: Option<Vec<String>>,
@@ -77,7 +138,7 @@ struct PmbusArgs {
     commands: Option<Vec<String>>,
 
     /// specifies writes to perform
-    #[clap(long, short = 'w', use_value_delimiter = false)]
+    #[clap(long, short = 'w', alias = "set", use_value_delimiter = false)]
     writes: Option<Vec<String>>,
 
     /// specifies an I2C controller
@@ -463,13 +524,7 @@ fn validate_write(
             .unwrap();
 
         if let Some(bits) = bits {
-            if let Ok(val) = parse_int::parse::<u32>(value) {
-                Ok((bits.0, Replacement::Integer(val)))
-            } else if let Ok(val) = value.parse::<f32>() {
-                Ok((bits.0, Replacement::Float(val)))
-            } else {
-                bail!("illegal value: {}", value);
-            }
+            Ok((bits.0, parse_value(value)?))
         } else if bitfields {
             bail!("{} has bitfields which must be set explicitly", cmd);
         } else {
@@ -478,6 +533,51 @@ fn validate_write(
     }
 }
 
+///
+/// Units that may be used to suffix a value to be written, along with the
+/// factor needed to convert a value in that unit to the unit used by PMBus.
+/// Note that the longest suffixes must come first.
+///
+const UNITS: &[(&str, f32)] = &[
+    ("kHz", 1.0),
+    ("°C", 1.0),
+    ("mV", 0.001),
+    ("mA", 0.001),
+    ("mW", 0.001),
+    ("ms", 1.0),
+    ("Hz", 0.001),
+    ("V", 1.0),
+    ("A", 1.0),
+    ("W", 1.0),
+    ("C", 1.0),
+    ("%", 1.0),
+];
+
+///
+/// Parses a value to be written to a non-bitfield command.  Integers are
+/// taken to be raw values; anything else is taken to be in engineering units
+/// (optionally with a unit suffix, e.g. "0.95V" or "950mV"), and is encoded
+/// by the pmbus crate according to the format of the command (e.g., LINEAR11
+/// or VID).
+///
+fn parse_value(value: &str) -> Result<Replacement> {
+    if let Ok(val) = parse_int::parse::<u32>(value) {
+        return Ok(Replacement::Integer(val));
+    }
+
+    let (num, factor) = UNITS
+        .iter()
+        .find_map(|(unit, factor)| {
+            value.strip_suffix(unit).map(|num| (num, *factor))
+        })
+        .unwrap_or((value, 1.0));
+
+    match num.trim().parse::<f32>() {
+        Ok(val) => Ok(Replacement::Float(val * factor)),
+        Err(_) => bail!("illegal value: {}", value),
+    }
+}
+
 fn split_write(write: &str) -> Result<(&str, Option<&str>, Option<&str>)> {
     let expr: Vec<&str> = write.split('=').collect();
 
@@ -522,7 +622,7 @@ fn summarize_rail(
         // This is a selected rail -- we just want to be sure that it worked
         //
         if let Err(code) = results[base] {
-            bail!("rail selection failed: {}", func.strerror(code));
+            return Err(func.error(code).context("rail selection failed"));
         }
 
         base += 1;
@@ -531,7 +631,7 @@ fn summarize_rail(
     let mode = if calls[base] == CommandCode::VOUT_MODE as u8 {
         match results[base] {
             Err(code) => {
-                bail!("can't read VOUT_MODE: {}", func.strerror(code));
+                return Err(func.error(code).context("can't read VOUT_MODE"));
             }
             Ok(ref val) => {
                 base += 1;
@@ -786,6 +886,291 @@ fn summarize(
     Ok(())
 }
 
+///
+/// The status registers read when checking health, along with their column
+/// headers.
+///
+const HEALTH_STATUS: &[(u8, &str)] = &[
+    (CommandCode::STATUS_WORD as u8, "WORD"),
+    (CommandCode::STATUS_VOUT as u8, "VOUT"),
+    (CommandCode::STATUS_IOUT as u8, "IOUT"),
+    (CommandCode::STATUS_INPUT as u8, "INPUT"),
+    (CommandCode::STATUS_TEMPERATURE as u8, "TEMP"),
+    (CommandCode::STATUS_CML as u8, "CML"),
+    (CommandCode::STATUS_MFR_SPECIFIC as u8, "MFR"),
+];
+
+#[allow(clippy::too_many_arguments)]
+#[rustfmt::skip::macros(println)]
+fn health_rail(
+    subargs: &PmbusArgs,
+    device: &HubrisI2cDevice,
+    driver: &pmbus::Device,
+    rail: &str,
+    calls: &[u8],
+    results: &[Result<Vec<u8>, u32>],
+    func: &HiffyFunction,
+    write_func: &HiffyFunction,
+) -> Result<bool> {
+    let mut base = 0;
+
+    if calls[base] == CommandCode::PAGE as u8 {
+        if let Err(code) = results[base] {
+            return Err(write_func
+                .error(code)
+                .context(format!("{}: rail selection failed", rail)));
+        }
+
+        base += 1;
+    }
+
+    let mode = match (calls.get(base), results.get(base)) {
+        (Some(&code), Some(Ok(val)))
+            if code == CommandCode::VOUT_MODE as u8 =>
+        {
+            base += 1;
+            VOUT_MODE::CommandData::from_slice(val)
+        }
+        (Some(&code), _) if code == CommandCode::VOUT_MODE as u8 => {
+            base += 1;
+            None
+        }
+        _ => None,
+    };
+
+    let getmode = || match mode {
+        Some(mode) => mode,
+        None => {
+            panic!("unexpected call to VOutMode");
+        }
+    };
+
+    print!("{:13} {:16}", device.device, rail);
+
+    let mut faulted = false;
+    let mut set = vec![];
+
+    for (code, header) in HEALTH_STATUS {
+        let ndx = match calls[base..].iter().position(|c| c == code) {
+            Some(ndx) => base + ndx,
+            None => {
+                print!(" {:>6}", "-");
+                continue;
+            }
+        };
+
+        match &results[ndx] {
+            Err(err) => {
+                let str = "X".red();
+                print!(" {:>6}", str);
+
+                if subargs.errors {
+                    set.push(format!("{}: {}", header, func.errmap[err]));
+                }
+            }
+            Ok(val) => {
+                let raw = val
+                    .iter()
+                    .rev()
+                    .fold(0u32, |raw, &byte| (raw << 8) | byte as u32);
+
+                let str = format!("0x{:0w$x}", raw, w = val.len() * 2);
+
+                if raw == 0 {
+                    print!(" {:>6}", str.green());
+                    continue;
+                }
+
+                faulted = true;
+                print!(" {:>6}", str.red());
+
+                let _ =
+                    driver.interpret(*code, val, getmode, |field, value| {
+                        if field.bitfield() && value.raw() != 0 {
+                            set.push(format!(
+                                "{}: {} = {}",
+                                header,
+                                field.name(),
+                                value
+                            ));
+                        }
+                    });
+            }
+        }
+    }
+
+    if let Some(ndx) =
+        calls[base..].iter().position(|&c| c == CommandCode::CLEAR_FAULTS as u8)
+    {
+        match results[base + ndx] {
+            Ok(_) => print!(" {}", "cleared".green()),
+            Err(code) => {
+                print!(" {}", "failed".red());
+                set.push(format!("CLEAR_FAULTS: {}", write_func.errmap[&code]));
+            }
+        }
+    }
+
+    println!();
+
+    if subargs.verbose && !set.is_empty() {
+        println!("{:30}|", "");
+        println!("{:30}+--- {}", "", set[0]);
+
+        for item in set.iter().skip(1) {
+            println!("{:30}     {}", "", item);
+        }
+
+        println!();
+    }
+
+    Ok(faulted)
+}
+
+///
+/// Checks the health of every PMBus rail in the system by reading its status
+/// registers, optionally clearing faults once they have been read.
+///
+fn health(
+    subargs: &PmbusArgs,
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    write_func: &HiffyFunction,
+) -> Result<()> {
+    let page = CommandCode::PAGE as u8;
+    let vout = CommandCode::VOUT_MODE as u8;
+    let clear = CommandCode::CLEAR_FAULTS as u8;
+
+    let mut ops = vec![];
+    let mut work = vec![];
+
+    for device in &hubris.manifest.i2c_devices {
+        if let HubrisI2cDeviceClass::Pmbus { rails } = &device.class {
+            let driver = match pmbus::Device::from_str(&device.device) {
+                Some(device) => device,
+                None => pmbus::Device::Common,
+            };
+
+            let harg = I2cArgs::from_device(device);
+
+            ops.push(Op::Push(harg.controller));
+            ops.push(Op::Push(harg.port.index));
+
+            if let Some(mux) = harg.mux {
+                ops.push(Op::Push(mux.0));
+                ops.push(Op::Push(mux.1));
+            } else {
+                ops.push(Op::PushNone);
+                ops.push(Op::PushNone);
+            }
+
+            ops.push(Op::Push(harg.address.unwrap()));
+
+            for (rnum, rail) in rails.iter().enumerate() {
+                let mut calls = vec![];
+
+                if rails.len() > 1 {
+                    ops.push(Op::Push(page));
+                    ops.push(Op::Push(rnum as u8));
+                    ops.push(Op::Push(1));
+                    ops.push(Op::Call(write_func.id));
+                    ops.push(Op::DropN(3));
+                    calls.push(page);
+                }
+
+                let codes = std::iter::once(vout)
+                    .chain(HEALTH_STATUS.iter().map(|(code, _)| *code));
+
+                for code in codes {
+                    driver.command(code, |cmd| {
+                        let op = match cmd.read_op() {
+                            pmbus::Operation::ReadByte => Op::Push(1),
+                            pmbus::Operation::ReadWord => Op::Push(2),
+                            _ => {
+                                return;
+                            }
+                        };
+
+                        ops.push(Op::Push(code));
+                        ops.push(op);
+                        ops.push(Op::Call(func.id));
+                        ops.push(Op::DropN(2));
+                        calls.push(code);
+                    });
+                }
+
+                //
+                // To clear faults, we issue CLEAR_FAULTS as a send byte
+                // (that is, a 1-byte raw write) after the status registers
+                // have been read.
+                //
+                if subargs.clear_faults {
+                    ops.push(Op::PushNone);
+                    ops.push(Op::Push(clear));
+                    ops.push(Op::Push(1));
+                    ops.push(Op::Call(write_func.id));
+                    ops.push(Op::DropN(3));
+                    calls.push(clear);
+                }
+
+                work.push((device, driver, rail, calls));
+            }
+
+            ops.push(Op::DropN(5));
+        }
+    }
+
+    if work.is_empty() {
+        bail!("no PMBus rails found in manifest");
+    }
+
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+    let mut base = 0;
+    let mut faulted = 0;
+
+    print!("{:13} {:16}", "DEVICE", "RAIL");
+
+    for (_, header) in HEALTH_STATUS {
+        print!(" {:>6}", header);
+    }
+
+    if subargs.clear_faults {
+        print!(" CLEAR");
+    }
+
+    println!();
+
+    for (device, driver, rail, calls) in &work {
+        if health_rail(
+            subargs,
+            device,
+            driver,
+            rail,
+            calls,
+            &results[base..base + calls.len()],
+            func,
+            write_func,
+        )? {
+            faulted += 1;
+        }
+
+        base += calls.len();
+    }
+
+    println!(
+        "\n{} of {} rails with non-zero status{}",
+        faulted,
+        work.len(),
+        if subargs.clear_faults { " (faults cleared)" } else { "" }
+    );
+
+    Ok(())
+}
+
 fn find_rail<'a>(
     hubris: &'a HubrisArchive,
     rail: &str,
@@ -1138,10 +1523,10 @@ fn writes(
                 }
                 WriteOp::Set | WriteOp::SetBlock(_) => match results[ndx] {
                     Err(code) => {
-                        bail!(
-                                "{}: failed to set {}: {}",
-                                harg, cmd, write_func.strerror(code)
-                            )
+                        return Err(write_func.error(code).context(format!(
+                            "{}: failed to set {}",
+                            harg, cmd
+                        )))
                     }
                     Ok(_) => {
                         success(harg, rail, cmd);
@@ -1161,6 +1546,8 @@ fn writes(
     //
     let mut ops = vec![];
     let mut ndx = 0;
+    let mut modes = vec![];
+    let mut written = vec![];
 
     for (harg, rail) in &hargs {
         ops.push(Op::Push(harg.controller));
@@ -1190,12 +1577,15 @@ fn writes(
 
         let mode = match results[ndx] {
             Err(code) => {
-                bail!("bad VOUT_MODE on {}: {}", harg, func.strerror(code));
+                return Err(func
+                    .error(code)
+                    .context(format!("bad VOUT_MODE on {}", harg)));
             }
             Ok(ref val) => VOUT_MODE::CommandData::from_slice(val).unwrap(),
         };
 
         ndx += 1;
+        modes.push(mode);
 
         let getmode = || mode;
 
@@ -1203,10 +1593,9 @@ fn writes(
             if let WriteOp::Modify(size, set) = op {
                 let payload = match results[ndx] {
                     Err(code) => {
-                        bail!(
-                            "failed to read {}: {}",
-                            cmd, func.strerror(code)
-                        );
+                        return Err(func
+                            .error(code)
+                            .context(format!("failed to read {}", cmd)));
                     }
                     Ok(ref val) => val,
                 };
@@ -1230,13 +1619,23 @@ fn writes(
 
                 ops.push(Op::Push(code));
 
-                for byte in v {
+                for &byte in &v {
                     ops.push(Op::Push(byte));
                 }
 
                 ops.push(Op::Push(*size as u8));
                 ops.push(Op::Call(write_func.id));
                 ops.push(Op::DropN(*size as u8 + 2));
+
+                //
+                // And read it back, that we may verify that the write took.
+                //
+                ops.push(Op::Push(code));
+                ops.push(Op::Push(*size as u8));
+                ops.push(Op::Call(func.id));
+                ops.push(Op::DropN(2));
+
+                written.push(v);
             }
 
             ndx += 1;
@@ -1249,12 +1648,13 @@ fn writes(
 
     let results = context.run(core, ops.as_slice(), None)?;
     let mut ndx = 0;
+    let mut written = written.iter();
 
     //
     // Now take one final lap through our results, reporting any errors
-    // that we find.
+    // that we find -- and verifying that what we read back is what we wrote.
     //
-    for (harg, rail) in &hargs {
+    for ((harg, rail), mode) in hargs.iter().zip(modes) {
         if let Some(rnum) = rail {
             if let Err(code) = results[ndx] {
                 bail!("failed to set rail {} on {}: Err({})", rnum, harg, code);
@@ -1263,18 +1663,55 @@ fn writes(
             ndx += 1;
         }
 
-        for (&_code, (cmd, op)) in &writes {
+        for (&code, (cmd, op)) in &writes {
             if let WriteOp::Modify(_, _) = op {
+                let expected = written.next().unwrap();
+
                 if let Err(code) = results[ndx] {
+                    return Err(write_func.error(code).context(format!(
+                        "{}: failed to write {}",
+                        harg, cmd
+                    )));
+                }
+
+                let readback = match results[ndx + 1] {
+                    Err(code) => {
+                        return Err(func.error(code).context(format!(
+                            "{}: failed to read back {}",
+                            harg, cmd
+                        )));
+                    }
+                    Ok(ref val) => val,
+                };
+
+                let mut value = None;
+
+                let _ = device.interpret(
+                    code,
+                    readback,
+                    || mode,
+                    |f, v| {
+                        if !f.bitfield() {
+                            value = Some(format!("{}", v));
+                        }
+                    },
+                );
+
+                let value = match value {
+                    Some(value) => value,
+                    None => format!("{:x?}", readback),
+                };
+
+                if readback != expected {
                     bail!(
-                        "{}: failed to write {}: {}",
-                        harg, cmd, write_func.strerror(code)
+                        "{}: wrote {:x?} to {}, but read back {:x?} ({})",
+                        harg, expected, cmd, readback, value
                     );
-                } else {
-                    success(harg, rail, cmd);
                 }
 
-                ndx += 1;
+                success(harg, rail, &format!("{} = {}", cmd, value));
+
+                ndx += 2;
             }
         }
     }
@@ -1334,6 +1771,11 @@ fn pmbus(
         return Ok(());
     }
 
+    if subargs.health {
+        health(&subargs, hubris, core, &mut context, func, write_func)?;
+        return Ok(());
+    }
+
     if subargs.writes.is_some() {
         writes(&subargs, hubris, core, &mut context, func, write_func)?;
         return Ok(());
@@ -1568,7 +2010,9 @@ fn pmbus(
     let base = if setrail {
         match results[0] {
             Err(code) => {
-                bail!("couldn't set rail: {}", write_func.strerror(code));
+                return Err(write_func
+                    .error(code)
+                    .context("couldn't set rail"));
             }
             Ok(_) => 1,
         }
@@ -1579,7 +2023,7 @@ fn pmbus(
     let (mode, ndx) = if cmds[base] == vout {
         let mode = match results[base] {
             Err(code) => {
-                bail!("can't read VOUT_MODE: {}", func.strerror(code));
+                return Err(func.error(code).context("can't read VOUT_MODE"));
             }
             Ok(ref val) => VOUT_MODE::CommandData::from_slice(val).unwrap(),
         };
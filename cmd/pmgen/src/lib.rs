@@ -0,0 +1,163 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility pmgen`
+//!
+//! `humility pmgen` ingests a configuration exported by a PMBus device
+//! vendor's configuration software, and generates a Rust function that
+//! iterates over the writes that apply the configuration -- suitable for
+//! inclusion in a Hubris driver.  The device is specified by its PMBus
+//! driver with `-D` (`--driver`):
+//!
+//! ```console
+//! % humility pmgen -D tps546b24a ./tps546b24a-export.txt > payload.rs
+//! ```
+//!
+//! For Renesas devices (that is, devices with DMA commands), the export is
+//! expected to be a Renesas Power Navigator text file, just as with `humility
+//! rendmp --ingest`.  For other devices (e.g., TI devices as exported by
+//! Fusion Digital Power Designer, or MPS devices as exported by Virtual
+//! Bench Pro), each line of the export names a command (by name or code)
+//! followed by the value to write, separated by whitespace, commas or `=`:
+//!
+//! ```text
+//! # Exported configuration
+//! VOUT_COMMAND = 0x0266
+//! 0x21, 0x0266
+//! ON_OFF_CONFIG 0x17
+//! STORE_DEFAULT_ALL
+//! ```
+//!
+//! The width of each value is determined by the command's definition in the
+//! driver; commands that are read-only are rejected.  Word values are
+//! written little-endian, as PMBus requires, and block values are written
+//! in the order given, preceded by their byte count.  The generated code is
+//! written to standard output unless an output file is specified with `-o`
+//! (`--output`).
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::hubris::*;
+use humility_cmd::pmgen::{self, Width};
+use humility_cmd::{Archive, Args, Command};
+use std::collections::HashMap;
+use std::fs;
+use std::io::BufReader;
+
+#[derive(Parser, Debug)]
+#[clap(name = "pmgen", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct PmgenArgs {
+    /// specifies a PMBus driver
+    #[clap(long, short = 'D', value_name = "driver")]
+    driver: String,
+
+    /// write generated code to the specified file
+    #[clap(long, short, value_name = "filename")]
+    output: Option<String>,
+
+    /// configuration export to ingest
+    filename: String,
+}
+
+fn pmgen(
+    _hubris: &mut HubrisArchive,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = PmgenArgs::try_parse_from(subargs)?;
+
+    let device = match pmbus::Device::from_str(&subargs.driver) {
+        Some(device) => device,
+        None => bail!("unknown device \"{}\"", subargs.driver),
+    };
+
+    let mut commands = HashMap::new();
+
+    for code in 0..=255u8 {
+        device.command(code, |cmd| {
+            commands.insert(cmd.name().to_string(), (code, cmd.write_op()));
+        });
+    }
+
+    let width = |op: &pmbus::Operation| match op {
+        pmbus::Operation::SendByte => Some(Width::None),
+        pmbus::Operation::WriteByte => Some(Width::Fixed(1)),
+        pmbus::Operation::WriteWord => Some(Width::Fixed(2)),
+        pmbus::Operation::WriteWord32 => Some(Width::Fixed(4)),
+        pmbus::Operation::WriteBlock => Some(Width::Block),
+        _ => None,
+    };
+
+    let file = BufReader::new(fs::File::open(&subargs.filename)?);
+
+    let (packets, dma) = match (commands.get("DMAADDR"), commands.get("DMAFIX"))
+    {
+        (Some((addr, _)), Some((fix, _))) => {
+            let name = |code| {
+                commands
+                    .iter()
+                    .find(|(_, (c, _))| *c == code)
+                    .map(|(name, _)| name.clone())
+            };
+
+            let dma = pmgen::Dma { addr: *addr, fix: *fix };
+            (pmgen::ingest_renesas(file, name)?, Some(dma))
+        }
+        _ => {
+            let lookup = |command: &str| {
+                let (name, (code, op)) = match parse_int::parse::<u8>(command) {
+                    Ok(code) => {
+                        commands.iter().find(|(_, (c, _))| *c == code)?
+                    }
+                    Err(_) => commands.get_key_value(command)?,
+                };
+
+                Some((*code, name.clone(), width(op)?))
+            };
+
+            (pmgen::ingest(file, lookup)?, None)
+        }
+    };
+
+    if packets.is_empty() {
+        bail!("no configuration found in {}", subargs.filename);
+    }
+
+    let doc = vec![
+        format!(
+            "Iterate over a configuration payload for a {} PMBus device.",
+            device.name()
+        ),
+        "This code was generated by \"humility pmgen\" given a configuration"
+            .to_string(),
+        "exported from the vendor's configuration software.".to_string(),
+    ];
+
+    match &subargs.output {
+        Some(output) => {
+            let mut out = fs::File::create(output)?;
+            pmgen::generate(&mut out, device.name(), &doc, &packets, dma)?;
+            humility::msg!("wrote {} writes to {}", packets.len(), output);
+        }
+        None => {
+            let mut out = std::io::stdout();
+            pmgen::generate(&mut out, device.name(), &doc, &packets, dma)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Unattached {
+            name: "pmgen",
+            archive: Archive::Ignored,
+            run: pmgen,
+        },
+        PmgenArgs::command(),
+    )
+}
@@ -0,0 +1,460 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility poke`
+//!
+//! `humility poke` writes a value to target memory.  The target is an
+//! expression of the form understood by `humility eval`: a variable,
+//! possibly with its members accessed and its elements indexed (e.g.,
+//! `VAR.member[3]`), or an integer address.  When writing to a variable,
+//! the value is encoded by the type being written:  integers are checked to
+//! fit, floating point values and booleans are encoded as such, enums are
+//! set by the name of their variant (provided that the variant carries no
+//! data), and arrays are specified as a comma-separated list of elements
+//! (e.g., `[1, 2, 3]`).  The old and new values are displayed:
+//!
+//! ```console
+//! % humility poke 'CONFIG.retries' 5
+//! humility: attached via ST-Link V3
+//! CONFIG.retries (0x20001c40) = 0x3 -> 0x5
+//! % humility poke 'CONFIG.mode' Standby
+//! humility: attached via ST-Link V3
+//! CONFIG.mode (0x20001c44) = Active -> Standby
+//! ```
+//!
+//! When writing to an address, the value is written as an integer of the
+//! size specified with `-s` (`--size`; 4 bytes by default).
+//!
+//! Before writing, the destination is checked against the memory regions
+//! of the system:  writes to memory that is not writable (e.g., flash),
+//! that is not in any task's region, or that belongs to a task other than
+//! the one that owns the variable being written are refused unless `-F`
+//! (`--force`) is specified.  To see what would be written without writing
+//! it, use `-n` (`--dry-run`).  After writing, the value is read back to
+//! verify it.
+//!
+//! To keep a record of writes, specify a file with `-a` (`--audit`) or the
+//! `HUMILITY_AUDIT` environment variable; a JSON record of each write
+//! (including the old and new contents of memory) is appended to it.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::eval::{Eval, Evaluator};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Parser, Debug)]
+#[clap(name = "poke", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct PokeArgs {
+    /// size in bytes of a value written to an address
+    #[clap(
+        long, short, default_value = "4", value_name = "bytes",
+        parse(try_from_str = parse_int::parse)
+    )]
+    size: usize,
+
+    /// write even to flash or to memory of another task
+    #[clap(long, short = 'F')]
+    force: bool,
+
+    /// display what would be written without writing it
+    #[clap(long, short = 'n')]
+    dry_run: bool,
+
+    /// append a record of the write to the specified file
+    #[clap(long, short, value_name = "file", env = "HUMILITY_AUDIT")]
+    audit: Option<String>,
+
+    /// variable (e.g., VAR.member[3]) or address to write
+    target: String,
+
+    /// value to write
+    value: String,
+}
+
+///
+/// Splits an array value into its elements, which may themselves be arrays.
+///
+fn elements(value: &str) -> Vec<&str> {
+    let value = value.trim();
+
+    let value = match value.strip_prefix('[') {
+        Some(inner) => inner.strip_suffix(']').unwrap_or(inner),
+        None => value,
+    };
+
+    let mut rval = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in value.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                rval.push(value[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    if !value[start..].trim().is_empty() {
+        rval.push(value[start..].trim());
+    }
+
+    rval
+}
+
+///
+/// Encodes an integer of the specified size, checking that it fits.
+///
+fn integer(value: &str, size: usize, signed: bool) -> Result<Vec<u8>> {
+    if size == 0 || size > 8 {
+        bail!("{}-byte integers are not supported", size);
+    }
+
+    let (negative, digits) = match value.trim().strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value.trim()),
+    };
+
+    let magnitude = parse_int::parse::<u64>(digits)
+        .map_err(|_| anyhow!("bad integer \"{}\"", value))?
+        as u128;
+
+    let bits = size * 8;
+
+    let val = if negative {
+        if !signed {
+            bail!("{} is negative, but the type is unsigned", value);
+        }
+
+        if magnitude > 1u128 << (bits - 1) {
+            bail!("{} does not fit in {} bytes", value, size);
+        }
+
+        (magnitude as u64).wrapping_neg()
+    } else {
+        let max = if signed { 1u128 << (bits - 1) } else { 1u128 << bits };
+
+        if magnitude >= max {
+            bail!("{} does not fit in {} bytes", value, size);
+        }
+
+        magnitude as u64
+    };
+
+    Ok(val.to_le_bytes()[..size].to_vec())
+}
+
+///
+/// Encodes a value of the specified type into `buf`, which contains the
+/// current contents of memory (and is therefore left as is for any part of
+/// the type that the value doesn't determine, like an enum's payload).
+///
+fn encode(
+    hubris: &HubrisArchive,
+    goff: HubrisGoff,
+    value: &str,
+    buf: &mut [u8],
+) -> Result<()> {
+    let value = value.trim();
+
+    match hubris.lookup_type(goff)? {
+        HubrisType::Base(b) => {
+            let bytes = match (b.encoding, b.size) {
+                (HubrisEncoding::Bool, _) => match value {
+                    "true" => integer("1", b.size, false)?,
+                    "false" => integer("0", b.size, false)?,
+                    _ => bail!("expected true or false, found \"{}\"", value),
+                },
+                (HubrisEncoding::Float, 4) => value
+                    .parse::<f32>()
+                    .map_err(|_| anyhow!("bad float \"{}\"", value))?
+                    .to_le_bytes()
+                    .to_vec(),
+                (HubrisEncoding::Float, 8) => value
+                    .parse::<f64>()
+                    .map_err(|_| anyhow!("bad float \"{}\"", value))?
+                    .to_le_bytes()
+                    .to_vec(),
+                (HubrisEncoding::Signed, size) => integer(value, size, true)?,
+                (HubrisEncoding::Unsigned, size) => {
+                    integer(value, size, false)?
+                }
+                _ => bail!("cannot write {}", goff),
+            };
+
+            buf.copy_from_slice(&bytes);
+        }
+        HubrisType::Ptr(_) => {
+            buf.copy_from_slice(&integer(value, 4, false)?);
+        }
+        HubrisType::Array(a) => {
+            let elements = elements(value);
+
+            if elements.len() != a.count {
+                bail!(
+                    "expected {} elements, found {}",
+                    a.count,
+                    elements.len()
+                );
+            }
+
+            let size = hubris.typesize(a.goff)?;
+
+            for (i, element) in elements.iter().enumerate() {
+                let slice = &mut buf[i * size..(i + 1) * size];
+                encode(hubris, a.goff, element, slice)?;
+            }
+        }
+        HubrisType::Enum(e) => {
+            let variant = e.lookup_variant_byname(value)?;
+
+            if let Some(goff) = variant.goff {
+                if hubris.typesize(goff)? != 0 {
+                    bail!("{}::{} carries data", e.name, variant.name);
+                }
+            }
+
+            match (e.discriminant, variant.tag) {
+                (Some(HubrisDiscriminant::Value(goff, offs)), Some(tag)) => {
+                    let size = hubris.typesize(goff)?;
+                    let tag = &tag.to_le_bytes()[..size];
+                    buf[offs..offs + size].copy_from_slice(tag);
+                }
+                (None, _) if e.variants.len() == 1 => {}
+                _ => bail!("{}::{} has no tag", e.name, variant.name),
+            }
+        }
+        HubrisType::Struct(s) if s.members.len() == 1 => {
+            let m = &s.members[0];
+            let size = hubris.typesize(m.goff)?;
+            encode(hubris, m.goff, value, &mut buf[m.offset..m.offset + size])?;
+        }
+        ty => {
+            bail!(
+                "cannot write {}; specify a member or element",
+                ty.name(hubris)?
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn task_name(hubris: &HubrisArchive, task: HubrisTask) -> String {
+    match task {
+        HubrisTask::Kernel => "the kernel".to_string(),
+        HubrisTask::Task(ndx) => match hubris.task_name(ndx as usize) {
+            Some(name) => format!("task {}", name),
+            None => task.to_string(),
+        },
+    }
+}
+
+///
+/// Checks a write against the memory regions of the system, returning the
+/// reason that it should be refused (if any).
+///
+fn check(
+    hubris: &HubrisArchive,
+    regions: &BTreeMap<u32, HubrisRegion>,
+    addr: u32,
+    size: usize,
+    task: Option<HubrisTask>,
+) -> Option<String> {
+    let end = addr as u64 + size as u64;
+
+    let region = regions
+        .range(..=addr)
+        .rev()
+        .map(|(_, region)| region)
+        .find(|r| end <= r.base as u64 + r.size as u64);
+
+    let region = match region {
+        Some(region) => region,
+        None => {
+            return Some(format!("0x{:08x} is not in any task's memory", addr))
+        }
+    };
+
+    if !region.attr.write {
+        return Some(format!("0x{:08x} is not writable", addr));
+    }
+
+    match task {
+        Some(task) if !region.tasks.contains(&task) => Some(format!(
+            "0x{:08x} is not in the memory of {}",
+            addr,
+            task_name(hubris, task)
+        )),
+        _ => None,
+    }
+}
+
+fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn audit(
+    hubris: &HubrisArchive,
+    filename: &str,
+    subargs: &PokeArgs,
+    addr: u32,
+    old: &[u8],
+    new: &[u8],
+) -> Result<()> {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let record = serde_json::json!({
+        "time": time,
+        "archive": hubris.manifest.name,
+        "target": subargs.target,
+        "value": subargs.value,
+        "addr": addr,
+        "old": hex(old),
+        "new": hex(new),
+        "forced": subargs.force,
+    });
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(filename)
+        .with_context(|| format!("failed to open {}", filename))?;
+
+    writeln!(file, "{}", record)?;
+
+    Ok(())
+}
+
+fn write(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &PokeArgs,
+) -> Result<()> {
+    let (val, task) = {
+        let mut evaluator = Evaluator::new(hubris, core, &subargs.target)?;
+        (evaluator.evaluate()?, evaluator.task())
+    };
+
+    let (addr, goff, size) = match val {
+        Eval::Place { addr, goff } => {
+            (addr, Some(goff), hubris.typesize(goff)?)
+        }
+        Eval::Int(addr) => {
+            if addr > u32::MAX as u64 {
+                bail!("address 0x{:x} is out of range", addr);
+            }
+
+            (addr as u32, None, subargs.size)
+        }
+    };
+
+    let mut old = vec![0u8; size];
+    core.read_8(addr, &mut old)?;
+
+    let mut new = old.clone();
+
+    match goff {
+        Some(goff) => encode(hubris, goff, &subargs.value, &mut new)?,
+        None => {
+            let signed = subargs.value.trim().starts_with('-');
+            new = integer(&subargs.value, size, signed)?;
+        }
+    }
+
+    let regions = hubris.regions(core)?;
+
+    if let Some(reason) = check(hubris, &regions, addr, size, task) {
+        if subargs.force {
+            humility::msg!("{}; writing anyway", reason);
+        } else {
+            bail!("{} (use -F to write anyway)", reason);
+        }
+    }
+
+    let display = |buf: &[u8]| -> Result<String> {
+        match goff {
+            Some(goff) => {
+                let fmt = HubrisPrintFormat {
+                    hex: true,
+                    ..HubrisPrintFormat::default()
+                };
+
+                hubris.printfmt(buf, goff, &fmt)
+            }
+            None => {
+                let mut bytes = [0u8; 8];
+                bytes[..buf.len()].copy_from_slice(buf);
+                Ok(format!("0x{:x}", u64::from_le_bytes(bytes)))
+            }
+        }
+    };
+
+    println!(
+        "{} (0x{:08x}) = {} -> {}",
+        subargs.target,
+        addr,
+        display(&old)?,
+        display(&new)?
+    );
+
+    if subargs.dry_run {
+        humility::msg!("dry run; not writing");
+        return Ok(());
+    }
+
+    core.write_8(addr, &new)?;
+
+    if let Some(ref filename) = subargs.audit {
+        audit(hubris, filename, subargs, addr, &old, &new)?;
+    }
+
+    let mut readback = vec![0u8; size];
+    core.read_8(addr, &mut readback)?;
+
+    if readback != new {
+        bail!("wrote 0x{:08x}, but read back {}", addr, display(&readback)?);
+    }
+
+    Ok(())
+}
+
+fn poke(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = PokeArgs::try_parse_from(subargs)?;
+
+    core.halt()?;
+    let rval = write(hubris, core, &subargs);
+    core.run()?;
+
+    rval
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "poke",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Match,
+            run: poke,
+        },
+        PokeArgs::command(),
+    )
+}
@@ -0,0 +1,575 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility profile`
+//!
+//! `humility profile` profiles scheduling on a running system by sampling
+//! the kernel's current task and task table as quickly as it can for a
+//! specified duration (5 seconds by default; use `-d` to specify a
+//! different duration in seconds).  For each task, the percentage of
+//! samples in which it was running, ready (runnable but not running) and
+//! blocked (in send, reply or receive) is displayed, along with the longest
+//! time that it was observed to be ready before it ran:
+//!
+//! ```console
+//! % humility profile -d 10
+//! humility: attached via ST-Link V3
+//! humility: profiling for 10 seconds
+//! humility: took 13529 samples (1352.9 samples/sec)
+//! TASK                 RUN%  READY%  BLOCKED%  MAXLAT
+//! jefe                 0.00    0.00    100.00       -
+//! net                  1.42    0.07     98.51   1.4ms
+//! sys                  0.03    0.00     99.97       -
+//! spi_driver           0.41    0.01     99.58   0.8ms
+//! i2c_driver           3.37    0.22     96.41   2.1ms
+//! ...
+//! idle                88.10   11.90      0.00  12.7ms
+//! ```
+//!
+//! To display a histogram of observed ready-to-run latencies for each task,
+//! use `-H` (`--histogram`).
+//!
+//! Sampling does not halt the target if the probe supports reading memory
+//! while the core is running; otherwise, the target is briefly halted for
+//! each sample.  Either way, because samples are taken far less frequently
+//! than the kernel can switch tasks, short-lived states may not be observed:
+//! the results are statistical rather than exact, and latencies are
+//! measured with the resolution of the sampling interval.
+//!
+//! To instead profile where the target is spending its time, use `-p`
+//! (`--pc`) to sample the program counter.  By default, the target is
+//! halted for each sample (as quickly as possible, or every `-i` milliseconds),
+//! and the stack of the current task is unwound; samples are emitted as
+//! folded stacks (one stack per line, with frames separated by semicolons
+//! and followed by a sample count), suitable for processing by flamegraph
+//! tooling:
+//!
+//! ```console
+//! % humility profile --pc -d 10 -o profile.folded
+//! humility: attached via ST-Link V3
+//! humility: sampling PC by halting for 10 seconds
+//! humility: took 4211 samples (421.1 samples/sec)
+//! humility: wrote 87 stacks to profile.folded
+//! % inferno-flamegraph profile.folded > profile.svg
+//! ```
+//!
+//! Samples taken while the kernel is running are attributed to the kernel
+//! function, without unwinding.  If the target has SWO, `--swo` can be used
+//! to have the DWT sample the PC every 16K cycles without halting; these
+//! samples are not unwound, and are attributed to the task (or kernel) and
+//! the function containing the PC, with samples taken while the core was
+//! asleep attributed to `sleep`.
+//!
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::doppel::{SchedState, Task, TaskState};
+use humility_cmd::kernel::KernelState;
+use humility_cmd::reflect::{self, Load};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::*;
+use humility_cortex::dwt::*;
+use humility_cortex::itm::*;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[clap(name = "profile", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct ProfileArgs {
+    /// duration to profile, in seconds
+    #[clap(
+        long, short, default_value = "5", value_name = "seconds",
+        parse(try_from_str = parse_int::parse)
+    )]
+    duration: u64,
+
+    /// display a histogram of ready-to-run latencies for each task
+    #[clap(long, short = 'H', conflicts_with = "pc")]
+    histogram: bool,
+
+    /// sample the program counter, emitting folded stacks
+    #[clap(long, short)]
+    pc: bool,
+
+    /// sample the program counter via DWT PC sampling over SWO
+    #[clap(long, requires = "pc")]
+    swo: bool,
+
+    /// interval between halting samples, in milliseconds
+    #[clap(
+        long, short, default_value = "0", value_name = "ms",
+        requires = "pc", conflicts_with = "swo",
+        parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
+    /// write folded stacks to the specified file rather than stdout
+    #[clap(long, short, value_name = "file", requires = "pc")]
+    output: Option<String>,
+}
+
+/// Latency histogram buckets, in powers of two microseconds
+const BUCKETS: usize = 24;
+
+#[derive(Default)]
+struct TaskProfile {
+    running: u64,
+    ready: u64,
+    blocked: u64,
+    ready_since: Option<Instant>,
+    max_latency: Option<Duration>,
+    histogram: [u64; BUCKETS],
+}
+
+impl TaskProfile {
+    fn latency(&mut self, latency: Duration) {
+        let us = latency.as_micros().max(1) as u64;
+        let bucket = (63 - us.leading_zeros()) as usize;
+
+        self.histogram[bucket.min(BUCKETS - 1)] += 1;
+
+        if self.max_latency.map_or(true, |max| latency > max) {
+            self.max_latency = Some(latency);
+        }
+    }
+
+    fn sample(&mut self, task: &Task, current: bool, now: Instant) {
+        match task.state {
+            TaskState::Healthy(SchedState::Runnable) if current => {
+                self.running += 1;
+
+                if let Some(since) = self.ready_since.take() {
+                    self.latency(now - since);
+                }
+            }
+            TaskState::Healthy(SchedState::Runnable) => {
+                self.ready += 1;
+
+                if self.ready_since.is_none() {
+                    self.ready_since = Some(now);
+                }
+            }
+            TaskState::Healthy(
+                SchedState::InSend(_)
+                | SchedState::InReply(_)
+                | SchedState::InRecv(_),
+            ) => {
+                self.blocked += 1;
+                self.ready_since = None;
+            }
+            _ => {
+                self.ready_since = None;
+            }
+        }
+    }
+}
+
+fn print_histogram(name: &str, histogram: &[u64; BUCKETS]) {
+    let total: u64 = histogram.iter().sum();
+
+    if total == 0 {
+        return;
+    }
+
+    let max = *histogram.iter().max().unwrap();
+
+    println!("\n{}:", name);
+    println!("{:>12} {:>8}", "LATENCY", "COUNT");
+
+    let first = histogram.iter().position(|&c| c != 0).unwrap();
+    let last = histogram.iter().rposition(|&c| c != 0).unwrap();
+
+    for (ndx, count) in histogram.iter().enumerate().take(last + 1).skip(first)
+    {
+        let us = 1u64 << ndx;
+
+        let label = if us >= 1000 {
+            format!("{}ms", us / 1000)
+        } else {
+            format!("{}us", us)
+        };
+
+        println!(
+            "{:>12} {:>8} {}",
+            format!(">= {}", label),
+            count,
+            "*".repeat(((count * 40 + max - 1) / max) as usize)
+        );
+    }
+}
+
+/// Folded stacks, keyed by their semicolon-delimited frames
+type Folded = BTreeMap<String, u64>;
+
+fn symbol(hubris: &HubrisArchive, pc: u32) -> String {
+    match hubris.instr_sym(pc) {
+        Some((name, _)) => name.to_string(),
+        None => format!("0x{:08x}", pc),
+    }
+}
+
+///
+/// Takes a single sample with the core halted, unwinding the stack of the
+/// current task if the PC is in a task.  If the PC is in the kernel, we
+/// attribute the sample to the kernel function without unwinding further.
+///
+fn halt_sample(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    kernel: &KernelState,
+    cur: u32,
+) -> Result<String> {
+    let pc = core.read_reg(ARMRegister::PC)?;
+
+    let task = match hubris.instr_mod(pc) {
+        Some("kernel") | None => {
+            return Ok(format!("kernel;{}", symbol(hubris, pc)));
+        }
+        Some(_) => {
+            let current = core.read_word_32(cur)?;
+
+            match kernel.tasks.iter().find(|t| t.addr == current) {
+                Some(task) => task,
+                None => bail!("current task 0x{:08x} not found", current),
+            }
+        }
+    };
+
+    let t = HubrisTask::Task(task.index);
+    let regs = hubris.registers(core, t)?;
+
+    let stack = match hubris.stack(core, t, task.desc.initial_stack, &regs) {
+        Ok(stack) => stack,
+        Err(_) => return Ok(format!("{};{}", task.name, symbol(hubris, pc))),
+    };
+
+    //
+    // Frames are ordered from innermost to outermost (with any inlined
+    // frames preceding the function they are inlined into); folded stacks
+    // are ordered from outermost to innermost.
+    //
+    let mut frames = vec![];
+
+    for frame in &stack {
+        if let Some(ref inlined) = frame.inlined {
+            frames.extend(inlined.iter().map(|i| i.name.to_string()));
+        }
+
+        match frame.sym {
+            Some(sym) => frames.push(sym.demangled_name.clone()),
+            None => {
+                let pc = frame.registers.get(&ARMRegister::PC).unwrap();
+                frames.push(format!("0x{:08x}", pc));
+            }
+        }
+    }
+
+    frames.push(task.name.clone());
+    frames.reverse();
+
+    Ok(frames.join(";"))
+}
+
+fn pc_halt(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &ProfileArgs,
+    folded: &mut Folded,
+) -> Result<u64> {
+    core.halt()?;
+    let kernel = KernelState::read(hubris, core);
+    core.run()?;
+    let kernel = kernel?;
+
+    let cur = hubris.lookup_symword("CURRENT_TASK_PTR")?;
+    let interval = Duration::from_millis(subargs.interval);
+    let duration = Duration::from_secs(subargs.duration);
+    let started = Instant::now();
+    let mut nsamples = 0;
+
+    while started.elapsed() < duration {
+        core.halt()?;
+        let sample = halt_sample(hubris, core, &kernel, cur);
+        core.run()?;
+
+        *folded.entry(sample?).or_insert(0) += 1;
+        nsamples += 1;
+
+        if subargs.interval != 0 {
+            thread::sleep(interval);
+        }
+    }
+
+    Ok(nsamples)
+}
+
+///
+/// The value of POSTPRESET (with POSTCNT tapping CYCCNT[10]) that results
+/// in a PC sample every 16K cycles; sampling more frequently than this
+/// risks overflowing SWO.
+///
+const SWO_POSTPRESET: u32 = 15;
+
+///
+/// Hardware source packet ID of a periodic PC sample
+///
+const ITM_PC_SAMPLE: u32 = 2;
+
+fn pc_sampling(core: &mut dyn Core, enabled: bool) -> Result<()> {
+    core.halt()?;
+
+    let mut tcr = ITM_TCR::read(core)?;
+    tcr.set_dwt_enable(enabled);
+    tcr.write(core)?;
+
+    let mut dwt = DWT_CTRL::read(core)?;
+    dwt.set_postcnt_tap(true);
+    dwt.set_postcnt_reset(SWO_POSTPRESET);
+    dwt.set_pc_sampling_enabled(enabled);
+    dwt.write(core)?;
+
+    core.run()?;
+    Ok(())
+}
+
+fn pc_swo(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &ProfileArgs,
+    folded: &mut Folded,
+) -> Result<u64> {
+    let traceid = itm_enable_ingest(core, hubris, 0)?;
+
+    pc_sampling(core, true)?;
+
+    let mut bytes: Vec<u8> = vec![];
+    let mut ndx = 0;
+    let mut nsamples = 0;
+    let started = Instant::now();
+    let duration = Duration::from_secs(subargs.duration);
+
+    let rval = itm_ingest(
+        traceid,
+        || {
+            while ndx == bytes.len() {
+                if started.elapsed() >= duration {
+                    return Ok(None);
+                }
+
+                bytes = core.read_swv()?;
+                ndx = 0;
+
+                if bytes.is_empty() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+
+            ndx += 1;
+            Ok(Some((bytes[ndx - 1], started.elapsed().as_secs_f64())))
+        },
+        |packet| {
+            if let ITMPayload::Hardware { source, payload, len } =
+                &packet.payload
+            {
+                if *source != ITM_PC_SAMPLE {
+                    return Ok(());
+                }
+
+                //
+                // A one-byte PC sample indicates that the core was asleep.
+                //
+                let sample = if *len == 4 {
+                    let pc = u32::from_le_bytes(*payload);
+                    let module = hubris.instr_mod(pc).unwrap_or("unknown");
+                    format!("{};{}", module, symbol(hubris, pc))
+                } else {
+                    "sleep".to_string()
+                };
+
+                *folded.entry(sample).or_insert(0) += 1;
+                nsamples += 1;
+            }
+
+            Ok(())
+        },
+    );
+
+    pc_sampling(core, false)?;
+    rval?;
+
+    Ok(nsamples)
+}
+
+fn pc_profile(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &ProfileArgs,
+) -> Result<()> {
+    let mut folded = Folded::new();
+
+    humility::msg!(
+        "sampling PC {} for {} seconds",
+        if subargs.swo { "via SWO" } else { "by halting" },
+        subargs.duration
+    );
+
+    let started = Instant::now();
+
+    let nsamples = if subargs.swo {
+        pc_swo(hubris, core, subargs, &mut folded)?
+    } else {
+        pc_halt(hubris, core, subargs, &mut folded)?
+    };
+
+    humility::msg!(
+        "took {} samples ({:.1} samples/sec)",
+        nsamples,
+        nsamples as f64 / started.elapsed().as_secs_f64()
+    );
+
+    if nsamples == 0 {
+        bail!("no samples taken");
+    }
+
+    let mut out: Box<dyn Write> = match &subargs.output {
+        Some(filename) => Box::new(
+            File::create(filename)
+                .with_context(|| format!("failed to create {}", filename))?,
+        ),
+        None => Box::new(std::io::stdout()),
+    };
+
+    for (stack, count) in &folded {
+        writeln!(out, "{} {}", stack, count)?;
+    }
+
+    if let Some(filename) = &subargs.output {
+        humility::msg!("wrote {} stacks to {}", folded.len(), filename);
+    }
+
+    Ok(())
+}
+
+fn profile(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = ProfileArgs::try_parse_from(subargs)?;
+
+    if subargs.duration == 0 {
+        bail!("duration must be non-zero");
+    }
+
+    if subargs.pc {
+        return pc_profile(hubris, core, &subargs);
+    }
+
+    //
+    // We read the full kernel state once (halted) to get task names; each
+    // sample thereafter reads only the current task pointer and the task
+    // table.
+    //
+    core.halt()?;
+    let kernel = KernelState::read(hubris, core);
+    core.run()?;
+    let kernel = kernel?;
+
+    let (base, count) = hubris.task_table(core)?;
+    let task_t = hubris.lookup_struct_byname("Task")?;
+    let cur = hubris.lookup_symword("CURRENT_TASK_PTR")?;
+
+    let mut profiles: Vec<TaskProfile> =
+        (0..count).map(|_| TaskProfile::default()).collect();
+    let mut taskblock = vec![0; task_t.size * count as usize];
+    let mut nsamples = 0u64;
+
+    humility::msg!("profiling for {} seconds", subargs.duration);
+
+    let started = Instant::now();
+    let duration = Duration::from_secs(subargs.duration);
+
+    while started.elapsed() < duration {
+        let current = core.read_word_32(cur)?;
+        core.read_8(base, &mut taskblock)
+            .context("failed to read task table")?;
+        let now = Instant::now();
+
+        for (i, profile) in profiles.iter_mut().enumerate() {
+            let addr = base + (i * task_t.size) as u32;
+            let value: reflect::Value =
+                reflect::load(hubris, &taskblock, task_t, i * task_t.size)?;
+            let task = Task::from_value(&value)
+                .with_context(|| format!("failed to decode task {}", i))?;
+
+            profile.sample(&task, addr == current, now);
+        }
+
+        nsamples += 1;
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+
+    humility::msg!(
+        "took {} samples ({:.1} samples/sec)",
+        nsamples,
+        nsamples as f64 / elapsed
+    );
+
+    if nsamples == 0 {
+        bail!("no samples taken");
+    }
+
+    let pct = |n: u64| n as f64 * 100.0 / nsamples as f64;
+
+    println!(
+        "{:18} {:>6} {:>7} {:>9} {:>7}",
+        "TASK", "RUN%", "READY%", "BLOCKED%", "MAXLAT"
+    );
+
+    for (task, profile) in kernel.tasks.iter().zip(profiles.iter()) {
+        let maxlat = match profile.max_latency {
+            Some(lat) => format!("{:.1}ms", lat.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+
+        println!(
+            "{:18} {:>6.2} {:>7.2} {:>9.2} {:>7}",
+            task.name,
+            pct(profile.running),
+            pct(profile.ready),
+            pct(profile.blocked),
+            maxlat
+        );
+    }
+
+    if subargs.histogram {
+        for (task, profile) in kernel.tasks.iter().zip(profiles.iter()) {
+            print_histogram(&task.name, &profile.histogram);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "profile",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: profile,
+        },
+        ProfileArgs::command(),
+    )
+}
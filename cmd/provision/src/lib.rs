@@ -0,0 +1,401 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility provision`
+//!
+//! `humility provision` writes vital product data (serial numbers, part
+//! numbers and revisions) into a board's VPD EEPROM, taking the values to
+//! write from a TOML file.  The file may contain a `[fru]` table of IPMI FRU
+//! fields (named as they are displayed by `humility eeprom -D`), a
+//! `[barcode]` table describing an Oxide barcode string, or both.  The
+//! EEPROM may be specified in the file as `device` (or on the command line,
+//! as it would be to `humility eeprom`):
+//!
+//! ```toml
+//! device = "at24csw080"
+//!
+//! [fru]
+//! "board.part" = "913-0000019"
+//! "board.serial" = "BRM42220016"
+//!
+//! [barcode]
+//! part = "913-0000019"
+//! revision = 6
+//! serial = "BRM42220016"
+//! offset = 0x100
+//! ```
+//!
+//! The contents of the EEPROM are read and each value is checked before
+//! anything is written:  a field that is already populated with a different
+//! value will not be overwritten unless `-F` (`--force`) is specified.  The
+//! fields to be provisioned are displayed, the EEPROM is written, and its
+//! contents are read back and verified:
+//!
+//! ```console
+//! % humility provision gimlet-vpd.toml
+//! humility: attached via ST-Link V3
+//! FIELD                OLD                  NEW
+//! board.part           -                    913-0000019
+//! board.serial         -                    BRM42220016
+//! barcode              -                    0XV2:913-0000019:006:BRM42220016
+//! humility: provisioned 3 fields (266 bytes at offset 0x18); verified
+//! ```
+//!
+//! To see what would be written without writing it, use `-n` (`--dry-run`).
+//! To keep a record of provisioning, use `-a` (`--audit`) to specify a
+//! file to which a JSON record of each provisioning operation (including
+//! the values written, the device and the archive) is appended.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::eeprom::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::i2c::I2cArgs;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::fs;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Parser, Debug)]
+#[clap(name = "provision", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct ProvisionArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// specifies an I2C bus by name
+    #[clap(long, short, value_name = "bus",
+        conflicts_with_all = &["port", "controller"]
+    )]
+    bus: Option<String>,
+
+    /// specifies an I2C controller
+    #[clap(long, short, value_name = "controller",
+        parse(try_from_str = parse_int::parse),
+    )]
+    controller: Option<u8>,
+
+    /// specifies an I2C controller port
+    #[clap(long, short, value_name = "port")]
+    port: Option<String>,
+
+    /// specifies I2C multiplexer and segment
+    #[clap(long, short, value_name = "mux:segment")]
+    mux: Option<String>,
+
+    /// specifies an I2C device address or name
+    #[clap(long, short, value_name = "device")]
+    device: Option<String>,
+
+    /// overwrite fields that are already populated
+    #[clap(long, short = 'F')]
+    force: bool,
+
+    /// show what would be written rather than writing it
+    #[clap(long = "dry-run", short = 'n')]
+    dryrun: bool,
+
+    /// append a record of provisioning to the specified file
+    #[clap(long, short, value_name = "filename")]
+    audit: Option<String>,
+
+    /// TOML file containing values to provision
+    file: String,
+}
+
+struct Field {
+    name: String,
+    old: Option<String>,
+    new: String,
+}
+
+fn string(value: &toml::Value, what: &str) -> Result<String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        _ => bail!("expected {} to be a string", what),
+    }
+}
+
+///
+/// Applies the `[fru]` table to the image, returning the fields that were
+/// provisioned and the extent of the encoded FRU.
+///
+fn provision_fru(
+    fru: &toml::value::Table,
+    image: &mut [u8],
+    force: bool,
+) -> Result<(Vec<Field>, usize)> {
+    let mut parsed = Fru::parse(image)
+        .context("VPD must contain a valid IPMI FRU to provision FRU fields")?;
+    let mut fields = vec![];
+
+    for (name, value) in fru {
+        let new = string(value, name)?;
+        let old = parsed.get(name)?;
+
+        let old = if old.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&old).to_string())
+        };
+
+        if let Some(ref old) = old {
+            if *old != new && !force {
+                bail!(
+                    "{} is already populated as \"{}\"; \
+                    use -F to overwrite it",
+                    name,
+                    old
+                );
+            }
+        }
+
+        parsed.set(name, &new)?;
+        fields.push(Field { name: name.clone(), old, new });
+    }
+
+    let encoded = parsed.encode()?;
+
+    image[..encoded.len()].copy_from_slice(&encoded);
+
+    Ok((fields, encoded.len()))
+}
+
+///
+/// Applies the `[barcode]` table to the image, returning the field that was
+/// provisioned and its offset.
+///
+fn provision_barcode(
+    barcode: &toml::value::Table,
+    image: &mut [u8],
+    force: bool,
+) -> Result<(Field, usize)> {
+    let get = |name: &str| {
+        barcode.get(name).ok_or_else(|| anyhow!("barcode is missing {}", name))
+    };
+
+    let part = string(get("part")?, "part")?;
+    let serial = string(get("serial")?, "serial")?;
+
+    let revision = match get("revision")? {
+        toml::Value::Integer(rev) => format!("{:03}", rev),
+        value => string(value, "revision")?,
+    };
+
+    let offset = match barcode.get("offset") {
+        Some(toml::Value::Integer(offset)) => usize::try_from(*offset)?,
+        Some(_) => bail!("expected barcode offset to be an integer"),
+        None => 0,
+    };
+
+    let new = format!("0XV2:{}:{}:{}", part, revision, serial);
+    let end = offset + new.len() + 1;
+
+    if end > image.len() {
+        bail!("barcode at offset 0x{:x} exceeds EEPROM size", offset);
+    }
+
+    let old = barcodes(image)
+        .into_iter()
+        .find(|(o, _)| *o == offset)
+        .map(|(_, barcode)| barcode);
+
+    if !force {
+        match old {
+            Some(ref old) if *old != new => {
+                bail!(
+                    "barcode is already populated as \"{}\"; \
+                    use -F to overwrite it",
+                    old
+                );
+            }
+            None if image[offset..end].iter().any(|&b| b != 0xff) => {
+                bail!(
+                    "EEPROM is not blank at offset 0x{:x}; \
+                    use -F to overwrite it",
+                    offset
+                );
+            }
+            _ => {}
+        }
+    }
+
+    image[offset..offset + new.len()].copy_from_slice(new.as_bytes());
+    image[offset + new.len()] = 0;
+
+    Ok((Field { name: "barcode".to_string(), old, new }, offset))
+}
+
+fn audit(
+    hubris: &HubrisArchive,
+    filename: &str,
+    hargs: &I2cArgs,
+    fields: &[Field],
+) -> Result<()> {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let values: serde_json::Map<String, serde_json::Value> = fields
+        .iter()
+        .map(|f| (f.name.clone(), serde_json::Value::from(f.new.clone())))
+        .collect();
+
+    let record = serde_json::json!({
+        "time": time,
+        "device": hargs.to_string(),
+        "archive": hubris.manifest.name,
+        "fields": values,
+    });
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(filename)
+        .with_context(|| format!("failed to open {}", filename))?;
+
+    writeln!(file, "{}", record)?;
+
+    Ok(())
+}
+
+fn provision(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = ProvisionArgs::try_parse_from(subargs)?;
+
+    let contents = fs::read_to_string(&subargs.file)
+        .with_context(|| format!("failed to read {}", subargs.file))?;
+    let values: toml::value::Table = toml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", subargs.file))?;
+
+    let device = match (&subargs.device, values.get("device")) {
+        (Some(device), _) => Some(device.clone()),
+        (None, Some(device)) => Some(string(device, "device")?),
+        (None, None) => None,
+    };
+
+    let hargs = I2cArgs::parse(
+        hubris,
+        &subargs.bus,
+        subargs.controller,
+        &subargs.port,
+        &subargs.mux,
+        &device,
+    )?;
+
+    let table = |name: &str| match values.get(name) {
+        Some(toml::Value::Table(table)) => Ok(Some(table)),
+        Some(_) => bail!("expected {} to be a table", name),
+        None => Ok(None),
+    };
+
+    let (fru, barcode) = (table("fru")?, table("barcode")?);
+
+    if fru.is_none() && barcode.is_none() {
+        bail!("{} contains neither [fru] nor [barcode]", subargs.file);
+    }
+
+    let geometry = geometry(&hargs, None, None)?;
+
+    let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+    let funcs = context.functions()?;
+    let mut eeprom = Eeprom::new(context, &funcs, &hargs, geometry)?;
+
+    let contents = eeprom.read(core)?;
+    let mut image = contents.clone();
+    let mut fields = vec![];
+    let mut extents = vec![];
+
+    if let Some(fru) = fru {
+        let (f, len) = provision_fru(fru, &mut image, subargs.force)?;
+        fields.extend(f);
+        extents.push((0, len));
+    }
+
+    if let Some(barcode) = barcode {
+        let (f, offset) =
+            provision_barcode(barcode, &mut image, subargs.force)?;
+        let end = offset + f.new.len() + 1;
+
+        if extents.iter().any(|&(s, e)| offset < e && end > s) {
+            bail!("barcode at offset 0x{:x} overlaps the FRU", offset);
+        }
+
+        fields.push(f);
+        extents.push((offset, end));
+    }
+
+    println!("{:20} {:20} NEW", "FIELD", "OLD");
+
+    for f in &fields {
+        println!(
+            "{:20} {:20} {}",
+            f.name,
+            f.old.as_deref().unwrap_or("-"),
+            f.new
+        );
+    }
+
+    //
+    // We write only the span of the EEPROM that has actually changed.
+    //
+    let first = (0..image.len()).find(|&i| image[i] != contents[i]);
+    let last = (0..image.len()).rev().find(|&i| image[i] != contents[i]);
+
+    let (first, last) = match (first, last) {
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            humility::msg!("VPD is already provisioned; not writing");
+            return Ok(());
+        }
+    };
+
+    if subargs.dryrun {
+        humility::msg!(
+            "would write {} bytes at offset 0x{:x}",
+            last - first + 1,
+            first
+        );
+        return Ok(());
+    }
+
+    eeprom.write_verified(core, first, &image[first..=last])?;
+
+    humility::msg!(
+        "provisioned {} field{} ({} bytes at offset 0x{:x}); verified",
+        fields.len(),
+        if fields.len() != 1 { "s" } else { "" },
+        last - first + 1,
+        first
+    );
+
+    if let Some(ref filename) = subargs.audit {
+        audit(hubris, filename, &hargs, &fields)?;
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "provision",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: provision,
+        },
+        ProvisionArgs::command(),
+    )
+}
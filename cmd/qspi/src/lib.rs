@@ -29,14 +29,17 @@
 //! [Ok([20, ba, 19, 10, 44, 0, 9a, ec, b, 0, 19, f9, ff, 39, 0, be, 69, 97, f4, a2])]
 //! ```
 //!
-//! To write an image from a file, use the `--writefile` (`-W`) option:
+//! To write an image from a file, use the `--writefile` (`-W`) option.  The
+//! sectors to be written are erased first, and the written image is then
+//! verified by comparing the hash of each sector on the target with that of
+//! the file:
 //!
 //! ```console
-//! % humility -W ./milan-spew-115k2-2dpc-0.4.1-dataeye.bin
+//! % humility qspi -W ./milan-spew-115k2-2dpc-0.4.1-dataeye.bin
 //! humility: attached via ST-Link V3
 //! humility: erasing 16777216 bytes...
 //! humility: ... done
-//! humility: flashed 16.00MB in 5 minutes
+//! humility: flashed and verified 16.00MB in 5 minutes
 //! ```
 //!
 //! If writing similar images, it is much faster to write only those blocks
@@ -48,7 +51,19 @@
 //! humility: attached via ST-Link V3
 //! humility: erasing 65536 bytes...
 //! humility: ... done
-//! humility: hashed 16.00MB, wrote 64.00KB in 16 seconds
+//! humility: hashed 16.00MB, wrote and verified 64.00KB in 16 seconds
+//! ```
+//!
+//! To dump the flash (or a region of it, as specified with `--address` and
+//! `--nbytes`) to a file, use `--readfile` (`-R`).  To compare the contents
+//! of the flash with an image without writing anything, use `--compare`
+//! (`-C`); the sectors that differ are displayed:
+//!
+//! ```console
+//! % humility qspi -C ./milan-spew-115k2-2dpc-0.4.1.bin
+//! humility: attached via ST-Link V3
+//! 0x00120000: differs
+//! humility: 1 sector differs from ./milan-spew-115k2-2dpc-0.4.1.bin
 //! ```
 //!
 //! To read, write or hash a particular region, use the `--read` (`-r`),
@@ -81,6 +96,7 @@
 use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
+use humility_cmd::progress::Progress;
 use humility_cmd::{Archive, Args, Attach, Command, Dumper, Validate};
 use sha2::{Digest, Sha256};
 use std::fmt;
@@ -90,13 +106,12 @@ use std::io::{BufWriter, Read, Write};
 use std::mem;
 use std::time::Instant;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{ArgGroup, CommandFactory, Parser};
 use hif::*;
 
 use indicatif::{HumanBytes, HumanDuration};
-use indicatif::{ProgressBar, ProgressStyle};
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -179,6 +194,10 @@ struct QspiArgs {
     /// file to differentially write
     #[clap(long, short = 'D', value_name = "filename", group = "command")]
     diffwrite: Option<String>,
+
+    /// file to compare against flash contents by hash
+    #[clap(long, short = 'C', value_name = "filename", group = "command")]
+    compare: Option<String>,
 }
 
 struct QspiDevice {
@@ -259,7 +278,7 @@ fn deltas(
         file.read_exact(&mut buf[..len as usize])?;
 
         let mut hasher = Sha256::new();
-        hasher.update(&buf);
+        hasher.update(&buf[..len as usize]);
         let sum = hasher.finalize();
 
         if !sum.iter().eq(result.iter()) {
@@ -305,7 +324,9 @@ fn erase(
 
     for (i, block_result) in results.iter().enumerate() {
         if let Err(err) = *block_result {
-            bail!("failed to erase 0x{:x}: {}", sectors[i], f.strerror(err));
+            return Err(f
+                .error(err)
+                .context(format!("failed to erase 0x{:x}", sectors[i])));
         }
     }
 
@@ -314,6 +335,126 @@ fn erase(
     Ok(())
 }
 
+///
+/// Hash the flash contents by sector, up to the specified length.
+///
+fn sector_hashes(
+    args: &Args,
+    device: &QspiDevice,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    funcs: &HiffyFunctions,
+    filelen: u32,
+) -> Result<Vec<(u32, Vec<u8>)>> {
+    let qspi_hash = funcs.get("QspiHash", 2)?;
+    let sector_size = device.sector_size;
+    let mut address = 0u32;
+    let mut sums = vec![];
+
+    let mut progress = Progress::bytes(args, "hashing", filelen as u64);
+
+    while address < filelen {
+        let mut ops = vec![];
+        let max = 8;
+        let mut laps = 0;
+        let base = address;
+
+        progress.set_position(address.into());
+
+        loop {
+            let len = if address + sector_size > filelen {
+                filelen - address
+            } else {
+                sector_size
+            };
+
+            ops.push(Op::Push32(address));
+            ops.push(Op::Push32(len));
+            ops.push(Op::Call(qspi_hash.id));
+
+            laps += 1;
+            address += len;
+
+            if address >= filelen || laps >= max {
+                break;
+            }
+        }
+
+        ops.push(Op::Done);
+        let results = context.run(core, ops.as_slice(), None)?;
+
+        for (sector, result) in results.iter().enumerate() {
+            match result {
+                Err(err) => {
+                    return Err(qspi_hash.error(*err).context(format!(
+                        "failed on address 0x{:x}",
+                        base + sector as u32 * sector_size
+                    )));
+                }
+                Ok(hash) => {
+                    sums.push((
+                        base + sector as u32 * sector_size,
+                        hash.clone(),
+                    ));
+                }
+            }
+        }
+    }
+
+    progress.finish();
+
+    Ok(sums)
+}
+
+///
+/// Compare the flash contents against the specified file, returning the
+/// addresses of the sectors that differ.
+///
+fn compare(
+    args: &Args,
+    device: &QspiDevice,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    funcs: &HiffyFunctions,
+    filename: &str,
+) -> Result<Vec<u32>> {
+    let filelen = fs::metadata(filename)?.len() as u32;
+    let sums = sector_hashes(args, device, core, context, funcs, filelen)?;
+    let mut differ = vec![];
+
+    deltas(device, filename, &sums, |offset, _| {
+        differ.push(offset);
+        Ok(())
+    })?;
+
+    Ok(differ)
+}
+
+///
+/// Verify that the flash contents match the specified file after a write.
+///
+fn verify(
+    args: &Args,
+    device: &QspiDevice,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    funcs: &HiffyFunctions,
+    filename: &str,
+) -> Result<()> {
+    let differ = compare(args, device, core, context, funcs, filename)?;
+
+    if let Some(first) = differ.first() {
+        bail!(
+            "verification failed: {} sector{} (first at 0x{:x})",
+            differ.len(),
+            if differ.len() != 1 { "s differ" } else { " differs" },
+            first
+        );
+    }
+
+    Ok(())
+}
+
 ///
 /// Write in units of blocksize.
 ///
@@ -377,12 +518,10 @@ fn write(
 
         for (i, block_result) in results.iter().enumerate() {
             if let Err(err) = block_result {
-                bail!(
-                    "failed on block {} at offset {}: {}",
-                    i,
-                    offset,
-                    qspi_page_program.strerror(*err),
-                );
+                return Err(qspi_page_program.error(*err).context(format!(
+                    "failed on block {} at offset {}",
+                    i, offset
+                )));
             }
         }
 
@@ -397,7 +536,7 @@ fn write(
 fn qspi(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = QspiArgs::try_parse_from(subargs)?;
@@ -466,7 +605,7 @@ fn qspi(
         ops.push(Op::Push32(arr.len() as u32));
         ops.push(Op::Call(qspi_page_program.id));
         (Some(arr), qspi_page_program)
-    } else if let Some(filename) = subargs.writefile {
+    } else if let Some(ref filename) = subargs.writefile {
         let qspi_sector_erase = funcs.get("QspiSectorErase", 1)?;
         let qspi_page_program = if subargs.verify {
             funcs.get("QspiVerify", 3)
@@ -474,7 +613,7 @@ fn qspi(
             funcs.get("QspiPageProgram", 3)
         }?;
 
-        let filelen = fs::metadata(filename.clone())?.len() as u32;
+        let filelen = fs::metadata(filename)?.len() as u32;
 
         if !subargs.verify {
             //
@@ -496,7 +635,9 @@ fn qspi(
 
             for (i, block_result) in results.iter().enumerate() {
                 if let Err(err) = *block_result {
-                    bail!("failed to erase sector {}: {}", i, f.strerror(err));
+                    return Err(f
+                        .error(err)
+                        .context(format!("failed to erase sector {}", i)));
                 }
             }
 
@@ -516,17 +657,8 @@ fn qspi(
         let mut file = File::open(filename)?;
 
         let started = Instant::now();
-        let bar = ProgressBar::new(filelen as u64);
-
-        if !subargs.verify {
-            bar.set_style(ProgressStyle::default_bar().template(
-                "humility: flashing [{bar:30}] {bytes}/{total_bytes}",
-            ));
-        } else {
-            bar.set_style(ProgressStyle::default_bar().template(
-                "humility: verifying [{bar:30}] {bytes}/{total_bytes}",
-            ));
-        }
+        let name = if subargs.verify { "verifying" } else { "flashing" };
+        let mut progress = Progress::bytes(args, name, filelen as u64);
 
         loop {
             let len = if offset + chunk > filelen {
@@ -569,17 +701,17 @@ fn qspi(
 
             let results = context.run(core, ops.as_slice(), Some(&buf))?;
 
-            bar.set_position((offset + len).into());
+            progress.set_position((offset + len).into());
 
             for (i, block_result) in results.iter().enumerate() {
                 match block_result {
                     Err(err) => {
-                        bail!(
-                            "failed on block {} at offset {}: {}",
-                            i,
-                            offset,
-                            qspi_page_program.strerror(*err),
-                        );
+                        return Err(qspi_page_program.error(*err).context(
+                            format!(
+                                "failed on block {} at offset {}",
+                                i, offset
+                            ),
+                        ));
                     }
                     Ok(r) if subargs.verify => {
                         if r.len() != 1 {
@@ -605,7 +737,7 @@ fn qspi(
             }
         }
 
-        bar.finish_and_clear();
+        progress.finish();
 
         if subargs.verify {
             humility::msg!(
@@ -614,8 +746,10 @@ fn qspi(
                 HumanDuration(started.elapsed())
             );
         } else {
+            verify(args, &device, core, &mut context, &funcs, &filename)?;
+
             humility::msg!(
-                "flashed {} in {}",
+                "flashed and verified {} in {}",
                 HumanBytes(filelen as u64),
                 HumanDuration(started.elapsed())
             );
@@ -632,11 +766,9 @@ fn qspi(
         // The default can/should be done in `#[clap(...` for "address"
         // if that works for the other users of the -a flag.
         let mut address = subargs.addr.or(Some(0)).unwrap() as u32;
-        println!("addr={:?}", address);
 
         let nbytes =
             optional_nbytes(core, &mut context, qspi_read_id, subargs.nbytes)?;
-        println!("nbytes={:?}", nbytes);
 
         //
         // Low-level reads are in units less than or equal to
@@ -657,27 +789,21 @@ fn qspi(
         let max_chunks = rstack_size / (chunk + overhead);
 
         let buf = vec![0u8; rstack_size as usize];
-        let output_file =
-            File::create(filename).expect("Cannot create output file");
+        let output_file = File::create(&filename)
+            .with_context(|| format!("failed to create {}", filename))?;
         let mut writer = BufWriter::with_capacity(nbytes as usize, output_file);
 
         let started = Instant::now();
-        let bar = ProgressBar::new(nbytes as u64);
-        bar.set_style(
-            ProgressStyle::default_bar()
-                .template("humility: reading [{bar:30}] {bytes}/{total_bytes}"),
-        );
+        let mut progress = Progress::bytes(args, "reading", nbytes as u64);
         let update_cycle = 64;
         let mut updates = 0;
 
         let end_address = address + nbytes;
-        // let mut out_address = address;
-        println!(
-            "address={}, chunk={}, end_address={}",
-            address, chunk, end_address
-        );
-        println!("max_chunks={}", max_chunks);
-        assert!(max_chunks > 0);
+
+        if max_chunks == 0 {
+            bail!("return stack too small for scratch size of {}", chunk);
+        }
+
         loop {
             let mut ops = vec![];
             for _ in 0..max_chunks {
@@ -701,21 +827,20 @@ fn qspi(
             let results = context.run(core, ops.as_slice(), Some(&buf))?;
 
             if updates % update_cycle == 0 {
-                bar.set_position((address).into());
+                progress.set_position((address).into());
             }
             updates += 1;
 
             for (i, block_result) in results.iter().enumerate() {
                 match &*block_result {
-                    Err(err) => bail!(
-                        "failed to read block {} at offset {}: {}",
-                        i,
-                        address,
-                        qspi_read.strerror(*err)
-                    ),
+                    Err(err) => {
+                        return Err(qspi_read.error(*err).context(format!(
+                            "failed to read block {} at offset {}",
+                            i, address
+                        )))
+                    }
                     Ok(buf) => {
-                        writer.write_all(buf).expect("write error");
-                        // _out_address += buf.len() as u32;
+                        writer.write_all(buf)?;
                     }
                 }
             }
@@ -724,8 +849,8 @@ fn qspi(
                 break;
             }
         }
-        writer.flush().unwrap();
-        bar.finish_and_clear();
+        writer.flush()?;
+        progress.finish();
         humility::msg!(
             "read {} in {}",
             HumanBytes(nbytes as u64),
@@ -735,77 +860,14 @@ fn qspi(
         return Ok(());
     } else if let Some(filename) = subargs.diffwrite {
         let filelen = fs::metadata(filename.clone())?.len() as u32;
-        let qspi_hash = funcs.get("QspiHash", 2)?;
         let started = Instant::now();
 
         //
         // We are going to hash the contents to find the differences, and
         // then erase/flash the different sectors.
         //
-        let mut address = 0u32;
-        let mut sums = vec![];
-
-        let bar = ProgressBar::new(filelen as u64);
-
-        bar.set_style(
-            ProgressStyle::default_bar()
-                .template("humility: hashing [{bar:30}] {bytes}/{total_bytes}"),
-        );
-
-        loop {
-            let mut ops = vec![];
-            let max = 8;
-            let mut laps = 0;
-            let base = address;
-
-            bar.set_position(address.into());
-
-            loop {
-                let len = if address + sector_size > filelen {
-                    filelen - address
-                } else {
-                    sector_size
-                };
-
-                ops.push(Op::Push32(address));
-                ops.push(Op::Push32(len));
-                ops.push(Op::Call(qspi_hash.id));
-
-                laps += 1;
-                address += len;
-
-                if address >= filelen || laps >= max {
-                    break;
-                }
-            }
-
-            ops.push(Op::Done);
-            let results = context.run(core, ops.as_slice(), None)?;
-
-            for (sector, result) in results.iter().enumerate() {
-                match result {
-                    Err(err) => {
-                        bail!(
-                            "failed on address 0x{:x}: {}",
-                            base + sector as u32 * sector_size,
-                            qspi_hash.strerror(*err),
-                        );
-                    }
-                    Ok(hash) => {
-                        sums.push((
-                            base + sector as u32 * sector_size,
-                            hash.clone(),
-                        ));
-                    }
-                }
-            }
-
-            if address + sector_size >= filelen {
-                break;
-            }
-        }
-
-        bar.finish_and_clear();
+        let sums =
+            sector_hashes(args, &device, core, &mut context, &funcs, filelen)?;
 
         let mut sectors = vec![];
         let mut bufs: Vec<Vec<u8>> = vec![];
@@ -830,12 +892,7 @@ fn qspi(
 
         erase(&device, core, &mut context, &funcs, &sectors)?;
 
-        let bar = ProgressBar::new(nbytes as u64);
-
-        bar.set_style(
-            ProgressStyle::default_bar()
-                .template("humility: writing [{bar:30}] {bytes}/{total_bytes}"),
-        );
+        let mut progress = Progress::bytes(args, "writing", nbytes as u64);
 
         let mut total = 0;
 
@@ -847,7 +904,7 @@ fn qspi(
             let writelen = buf.len() as u32;
 
             let w = |dest: &mut [u8]| {
-                bar.set_position(total);
+                progress.set_position(total);
                 dest.clone_from_slice(&buf[offs..offs + dest.len()]);
                 offs += dest.len();
                 total += dest.len() as u64;
@@ -857,15 +914,37 @@ fn qspi(
             write(&device, core, &mut context, &funcs, *addr, writelen, w)?;
         }
 
-        bar.finish_and_clear();
+        progress.finish();
+
+        verify(args, &device, core, &mut context, &funcs, &filename)?;
 
         humility::msg!(
-            "hashed {}, wrote {} in {}",
+            "hashed {}, wrote and verified {} in {}",
             HumanBytes(filelen as u64),
             HumanBytes(total),
             HumanDuration(started.elapsed())
         );
 
+        return Ok(());
+    } else if let Some(filename) = subargs.compare {
+        let differ =
+            compare(args, &device, core, &mut context, &funcs, &filename)?;
+
+        if differ.is_empty() {
+            humility::msg!("flash contents match {}", filename);
+        } else {
+            for addr in &differ {
+                println!("0x{:08x}: differs", addr);
+            }
+
+            bail!(
+                "{} sector{} from {}",
+                differ.len(),
+                if differ.len() != 1 { "s differ" } else { " differs" },
+                filename
+            );
+        }
+
         return Ok(());
     } else {
         bail!("expected an operation");
@@ -912,7 +991,7 @@ fn qspi(
                 println!();
             }
             Err(e) => {
-                bail!("hash failed: {}", func.strerror(*e));
+                return Err(func.error(*e).context("hash failed"));
             }
         }
         return Ok(());
@@ -86,6 +86,57 @@
 //!
 //! **Note that reading some peripheral memory may have side effects!**
 //!
+//! To change the number of bytes displayed on each line, use `--width`
+//! (which must be a power of two); to omit the ASCII translation in the right
+//! margin, use `--no-ascii`:
+//!
+//! ```console
+//! $ humility readmem --width 8 --no-ascii 0x00011d00 24
+//! humility: attached via DAPLink
+//! humility: reading at 0x11d00 for 24 bytes
+//!              \/  1  2  3  4  5  6  7
+//! 0x00011d00 | 20 62 6f 75 6e 64 73 3a
+//! 0x00011d08 | 20 74 68 65 20 6c 65 6e
+//! 0x00011d10 | 20 69 73 20 20 62 75 74
+//! ```
+//!
+//! Given an archive or dump, `-A` (`--annotate`) will precede the dump with
+//! the memory regions that the range falls within (and the tasks that own
+//! them), along with the variables that the range contains:
+//!
+//! ```console
+//! $ humility readmem -A -w 0x20000000 0x40
+//! humility: attached via ST-Link V3
+//! humility: reading at 0x20000000 for 64 bytes
+//! region 0x20000000-0x20000fff (rw) owned by jefe
+//!   0x20000000   16 task_jefe::TASK_STATE
+//!   0x20000010   48 task_jefe::main::BUF
+//!                    \/        4        8        c
+//! 0x20000000 | 00000001 20000180 0000000b 00005020 | ....... .... P..
+//! ...
+//! ```
+//!
+//! To save the contents of a range of memory for later comparison, use
+//! `--save` to write the raw bytes to a file; to compare the range against a
+//! previous save, use `--diff`.  Only lines that differ are displayed, with
+//! the saved contents (`-`) followed by the current contents (`+`) and
+//! differing values highlighted:
+//!
+//! ```console
+//! $ humility readmem -w 0x20000000 0x40 --save before.bin
+//! ...
+//! $ humility readmem -w 0x20000000 0x40 --diff before.bin
+//! humility: attached via ST-Link V3
+//! humility: reading at 0x20000000 for 64 bytes
+//! 0x20000000 - 00000001 20000180 0000000b 00005020
+//!            + 00000001 20000180 0000000c 00005020
+//! 0x20000030 - 00004d28 00004d28 00004d28 00004d28
+//!            + 00004d28 00004e00 00004d28 00004d28
+//! humility: 2 of 16 words differ
+//! ```
+//!
+//! The saved file must be of the same length as the range being compared.
+//!
 //! It can also be useful to interpret memory contents symbolically; to do this,
 //! provide a dump or achive and specify the `-s` option, e.g.:
 //!
@@ -111,13 +162,15 @@
 //! ```
 //!
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::core::Core;
 use humility::hubris::*;
+use humility_cmd::style::Severity;
 use humility_cmd::{Archive, Args, Attach, Command, Dumper, Validate};
 use std::convert::TryInto;
+use std::fs;
 
 #[derive(Parser, Debug)]
 #[clap(name = "readmem", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -134,6 +187,29 @@ struct ReadmemArgs {
     #[clap(long, short)]
     symbol: bool,
 
+    /// number of bytes to display per line
+    #[clap(
+        long, value_name = "bytes", conflicts_with = "symbol",
+        parse(try_from_str = parse_int::parse)
+    )]
+    width: Option<usize>,
+
+    /// do not display the ASCII translation of memory contents
+    #[clap(long, conflicts_with = "symbol")]
+    no_ascii: bool,
+
+    /// annotate with owning regions and tasks, and contained variables
+    #[clap(long, short = 'A')]
+    annotate: bool,
+
+    /// save the memory contents to the specified file
+    #[clap(long, value_name = "file", conflicts_with = "diff")]
+    save: Option<String>,
+
+    /// compare the memory contents against a previously saved file
+    #[clap(long, value_name = "file", conflicts_with = "symbol")]
+    diff: Option<String>,
+
     /// address to read
     address: String,
 
@@ -142,6 +218,131 @@ struct ReadmemArgs {
     length: Option<usize>,
 }
 
+fn value(bytes: &[u8]) -> u32 {
+    match bytes.len() {
+        1 => bytes[0] as u32,
+        2 => u16::from_le_bytes(bytes.try_into().unwrap()) as u32,
+        4 => u32::from_le_bytes(bytes.try_into().unwrap()),
+        _ => panic!("invalid size"),
+    }
+}
+
+///
+/// Prints the regions (and their owning tasks) that the specified range
+/// falls within, along with any variables within the range.
+///
+fn annotate(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    addr: u32,
+    length: usize,
+) -> Result<()> {
+    let end = addr as u64 + length as u64;
+    let overlaps = |base: u32, size: u64| {
+        (base as u64) < end && base as u64 + size > addr as u64
+    };
+
+    match hubris.regions(core) {
+        Ok(regions) => {
+            for region in regions.values() {
+                if !overlaps(region.base, region.size as u64) {
+                    continue;
+                }
+
+                let attr = &region.attr;
+                let mut owners = vec![];
+
+                for task in &region.tasks {
+                    owners.push(hubris.lookup_module(*task)?.name.as_str());
+                }
+
+                println!(
+                    "region 0x{:08x}-0x{:08x} ({}{}{}{}) owned by {}",
+                    region.base,
+                    region.base as u64 + region.size as u64 - 1,
+                    if attr.read { "r" } else { "" },
+                    if attr.write { "w" } else { "" },
+                    if attr.execute { "x" } else { "" },
+                    if attr.device { ", device" } else { "" },
+                    owners.join(", ")
+                );
+            }
+        }
+        Err(err) => {
+            humility::msg!("could not determine regions: {}", err);
+        }
+    }
+
+    let mut variables = hubris
+        .qualified_variables()
+        .filter(|(_, v)| overlaps(v.addr, v.size as u64))
+        .collect::<Vec<_>>();
+
+    variables.sort_by_key(|(name, v)| (v.addr, *name));
+
+    for (name, v) in variables {
+        println!("  0x{:08x} {:>4} {}", v.addr, v.size, name);
+    }
+
+    Ok(())
+}
+
+///
+/// Prints the lines that differ between the saved and current contents of
+/// memory, returning the number of differing values.
+///
+fn diff(
+    saved: &[u8],
+    bytes: &[u8],
+    addr: u32,
+    size: usize,
+    width: usize,
+) -> usize {
+    let mut ndiffs = 0;
+
+    let print = |line: &[u8], other: &[u8]| {
+        let mut rval = String::new();
+
+        for (val, oval) in line.chunks(size).zip(other.chunks(size)) {
+            let text = format!("{:0width$x}", value(val), width = size * 2);
+
+            if val != oval {
+                rval.push_str(&Severity::Changed.paint(&text));
+            } else {
+                rval.push_str(&text);
+            }
+
+            rval.push(' ');
+        }
+
+        rval
+    };
+
+    for (i, (was, now)) in
+        saved.chunks(width).zip(bytes.chunks(width)).enumerate()
+    {
+        if was == now {
+            continue;
+        }
+
+        ndiffs += was
+            .chunks(size)
+            .zip(now.chunks(size))
+            .filter(|(w, n)| w != n)
+            .count();
+
+        println!(
+            "0x{:08x} - {}",
+            addr + (i * width) as u32,
+            print(was, now).trim_end()
+        );
+
+        println!("{:10} + {}", "", print(now, was).trim_end());
+    }
+
+    ndiffs
+}
+
 fn readmem(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
@@ -164,7 +365,7 @@ fn readmem(
         bail!("length must be {}-byte aligned", size);
     }
 
-    if subargs.symbol {
+    if subargs.symbol || subargs.annotate {
         hubris.validate(core, HubrisValidate::ArchiveMatch)?;
     }
 
@@ -184,10 +385,64 @@ fn readmem(
         bail!("cannot read more than {} bytes", max);
     }
 
+    let width = subargs.width.unwrap_or(16);
+
+    if !width.is_power_of_two() || width < size || width > 64 {
+        bail!("width must be a power of two between {} and 64", size);
+    }
+
+    let saved = match &subargs.diff {
+        Some(filename) => {
+            let saved = fs::read(filename)
+                .with_context(|| format!("failed to read {}", filename))?;
+
+            if saved.len() != length {
+                bail!(
+                    "{} contains {} bytes; expected {}",
+                    filename,
+                    saved.len(),
+                    length
+                );
+            }
+
+            Some(saved)
+        }
+        None => None,
+    };
+
     let mut bytes = vec![0u8; length];
 
     core.read_8(addr, &mut bytes)?;
 
+    if let Some(filename) = &subargs.save {
+        fs::write(filename, &bytes)
+            .with_context(|| format!("failed to write {}", filename))?;
+        humility::msg!("saved {} bytes to {}", length, filename);
+    }
+
+    if subargs.annotate {
+        annotate(hubris, core, addr, length)?;
+    }
+
+    if let Some(saved) = saved {
+        let ndiffs = diff(&saved, &bytes, addr, size, width);
+        let unit = match size {
+            1 => "byte",
+            2 => "halfword",
+            _ => "word",
+        };
+
+        humility::msg!(
+            "{} of {} {}s differ{}",
+            ndiffs,
+            length / size,
+            unit,
+            if ndiffs == 1 { "s" } else { "" }
+        );
+
+        return Ok(());
+    }
+
     if subargs.symbol {
         for offs in (0..length).step_by(size) {
             let slice = &bytes[offs..offs + size];
@@ -219,6 +474,8 @@ fn readmem(
 
     let mut dumper = Dumper::new();
     dumper.size = size;
+    dumper.width = width;
+    dumper.ascii = !subargs.no_ascii;
     dumper.dump(&bytes, addr);
 
     Ok(())
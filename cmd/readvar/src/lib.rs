@@ -93,7 +93,7 @@ fn readvar(
     let subargs = ReadvarArgs::try_parse_from(subargs)?;
 
     if subargs.list {
-        return hubris.list_variables();
+        return hubris.list_variables(&mut std::io::stdout());
     }
 
     let variables = match subargs.variable {
@@ -128,6 +128,50 @@
 //! To additionally display floating point registers on platforms that support
 //! floating point, use the `--floating-point` (`-f`) option.
 //!
+//! To display the processor mode following the special-purpose registers,
+//! use the `--mode` (`-m`) option:  whether the processor is in thread or
+//! handler mode (and if the latter, the active exception), whether it is
+//! privileged, and which stack pointer is in use are displayed, and if `LR`
+//! contains an exception return value, it is decoded:
+//!
+//! ```console
+//! % humility registers --mode
+//! humility: attached via ST-Link V3
+//! ...
+//!    LR = 0xfffffffd <- EXC_RETURN: thread mode, PSP, basic frame
+//! ...
+//!  MODE = handler (SysTick), privileged, MSP
+//! ```
+//!
+//! To display the state of the system control block, SysTick and the NVIC
+//! on a live system, use the `--system` (`-S`) option.  Each register is
+//! displayed along with the fields or flags that it has set, and any
+//! interrupt that is enabled, pending or active is displayed with its name:
+//!
+//! ```console
+//! % humility registers --system
+//! humility: attached via ST-Link V3
+//! ...
+//!      ICSR = 0x0041080f <- VECTACTIVE = SysTick, VECTPENDING = IRQ 0, ISRPENDING
+//!      VTOR = 0x08000000 <- kernel: __vector_table+0x0
+//!     AIRCR = 0xfa050000 <- PRIGROUP = 0
+//!       SCR = 0x00000000
+//!       CCR = 0x00070200 <- STKALIGN, DC, IC
+//!     SHCSR = 0x00070800 <- SYSTICKACT, MEMFAULTENA, BUSFAULTENA, USGFAULTENA
+//!      CFSR = 0x00000000
+//!      HFSR = 0x00000000
+//!     MMFAR = 0xe000edf8
+//!      BFAR = 0xe000edf8
+//!  SYST_CSR = 0x00000007 <- ENABLE, TICKINT, CLKSOURCE
+//!  SYST_RVR = 0x0000f9ff <- 63999
+//!  SYST_CVR = 0x000031e6 <- 12774
+//!      NVIC = IRQ 31 (i2c1.event) enabled
+//!             IRQ 37 (usart1.irq) enabled pending
+//! ```
+//!
+//! To display everything (floating point and system registers included), use
+//! `--all` (`-a`).
+//!
 
 use anyhow::{bail, Result};
 use clap::Command as ClapCommand;
@@ -137,6 +181,7 @@ use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 use humility_cortex::debug::*;
+use humility_cortex::nvic::nvic_read;
 use num_traits::FromPrimitive;
 use std::collections::BTreeMap;
 
@@ -154,6 +199,274 @@ struct RegistersArgs {
     /// show floating point registers
     #[clap(long = "floating-point", short)]
     fp: bool,
+
+    /// show system control block, SysTick and NVIC state
+    #[clap(long, short = 'S')]
+    system: bool,
+
+    /// decode the processor mode and any exception return value in LR
+    #[clap(long, short)]
+    mode: bool,
+
+    /// show all registers, including floating point and system registers,
+    /// and decode the processor mode
+    #[clap(long, short, conflicts_with_all = &["fp", "system", "mode"])]
+    all: bool,
+}
+
+//
+// System registers, along with the names of their flags.  Registers that
+// have fields rather than flags are decoded in `system_detail`.
+//
+const SYSTEM_REGISTERS: &[(&str, u32, &[(u32, &str)])] = &[
+    ("ICSR", 0xe000_ed04, &[]),
+    ("VTOR", 0xe000_ed08, &[]),
+    ("AIRCR", 0xe000_ed0c, &[]),
+    (
+        "SCR",
+        0xe000_ed10,
+        &[(1, "SLEEPONEXIT"), (2, "SLEEPDEEP"), (4, "SEVONPEND")],
+    ),
+    (
+        "CCR",
+        0xe000_ed14,
+        &[
+            (0, "NONBASETHRDENA"),
+            (1, "USERSETMPEND"),
+            (3, "UNALIGN_TRP"),
+            (4, "DIV_0_TRP"),
+            (8, "BFHFNMIGN"),
+            (9, "STKALIGN"),
+            (16, "DC"),
+            (17, "IC"),
+            (18, "BP"),
+        ],
+    ),
+    (
+        "SHCSR",
+        0xe000_ed24,
+        &[
+            (0, "MEMFAULTACT"),
+            (1, "BUSFAULTACT"),
+            (3, "USGFAULTACT"),
+            (7, "SVCALLACT"),
+            (8, "MONITORACT"),
+            (10, "PENDSVACT"),
+            (11, "SYSTICKACT"),
+            (12, "USGFAULTPENDED"),
+            (13, "MEMFAULTPENDED"),
+            (14, "BUSFAULTPENDED"),
+            (15, "SVCALLPENDED"),
+            (16, "MEMFAULTENA"),
+            (17, "BUSFAULTENA"),
+            (18, "USGFAULTENA"),
+        ],
+    ),
+    (
+        "CFSR",
+        0xe000_ed28,
+        &[
+            (0, "IACCVIOL"),
+            (1, "DACCVIOL"),
+            (3, "MUNSTKERR"),
+            (4, "MSTKERR"),
+            (5, "MLSPERR"),
+            (7, "MMARVALID"),
+            (8, "IBUSERR"),
+            (9, "PRECISERR"),
+            (10, "IMPRECISERR"),
+            (11, "UNSTKERR"),
+            (12, "STKERR"),
+            (13, "LSPERR"),
+            (15, "BFARVALID"),
+            (16, "UNDEFINSTR"),
+            (17, "INVSTATE"),
+            (18, "INVPC"),
+            (19, "NOCP"),
+            (24, "UNALIGNED"),
+            (25, "DIVBYZERO"),
+        ],
+    ),
+    ("HFSR", 0xe000_ed2c, &[(1, "VECTTBL"), (30, "FORCED"), (31, "DEBUGEVT")]),
+    ("MMFAR", 0xe000_ed34, &[]),
+    ("BFAR", 0xe000_ed38, &[]),
+    (
+        "SYST_CSR",
+        0xe000_e010,
+        &[(0, "ENABLE"), (1, "TICKINT"), (2, "CLKSOURCE"), (16, "COUNTFLAG")],
+    ),
+    ("SYST_RVR", 0xe000_e014, &[]),
+    ("SYST_CVR", 0xe000_e018, &[]),
+];
+
+fn exception_name(hubris: &HubrisArchive, exception: u32) -> String {
+    match exception {
+        1 => "Reset".to_string(),
+        2 => "NMI".to_string(),
+        3 => "HardFault".to_string(),
+        4 => "MemManage".to_string(),
+        5 => "BusFault".to_string(),
+        6 => "UsageFault".to_string(),
+        7 => "SecureFault".to_string(),
+        11 => "SVCall".to_string(),
+        12 => "DebugMonitor".to_string(),
+        14 => "PendSV".to_string(),
+        15 => "SysTick".to_string(),
+        n if n >= 16 => match hubris.manifest.irq_names.get(&(n - 16)) {
+            Some(name) => format!("IRQ {} ({})", n - 16, name),
+            None => format!("IRQ {}", n - 16),
+        },
+        n => format!("exception {}", n),
+    }
+}
+
+///
+/// Describes the processor mode, as determined by the exception number in
+/// IPSR and the CONTROL bits in SPR.
+///
+fn mode(hubris: &HubrisArchive, psr: u32, spr: u32) -> String {
+    let exception = psr & 0x1ff;
+    let npriv = spr & (1 << 24) != 0;
+    let spsel = spr & (1 << 25) != 0;
+
+    if exception != 0 {
+        format!(
+            "handler ({}), privileged, MSP",
+            exception_name(hubris, exception)
+        )
+    } else {
+        format!(
+            "thread, {}, {}",
+            if npriv { "unprivileged" } else { "privileged" },
+            if spsel { "PSP" } else { "MSP" }
+        )
+    }
+}
+
+///
+/// Decodes an EXC_RETURN value in LR, if it is one.
+///
+fn exc_return(val: u32) -> Option<String> {
+    if val & 0xffff_ff00 != 0xffff_ff00 {
+        return None;
+    }
+
+    Some(format!(
+        "EXC_RETURN: {} mode, {}, {} frame",
+        if val & (1 << 3) != 0 { "thread" } else { "handler" },
+        if val & (1 << 2) != 0 { "PSP" } else { "MSP" },
+        if val & (1 << 4) != 0 { "basic" } else { "extended" },
+    ))
+}
+
+fn flags(val: u32, names: &[(u32, &str)]) -> Vec<String> {
+    names
+        .iter()
+        .filter(|(bit, _)| val & (1 << bit) != 0)
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+fn system_detail(
+    hubris: &HubrisArchive,
+    regions: &BTreeMap<u32, HubrisRegion>,
+    name: &str,
+    val: u32,
+    names: &[(u32, &str)],
+) -> Option<String> {
+    let detail = match name {
+        "ICSR" => {
+            let mut detail = vec![
+                format!(
+                    "VECTACTIVE = {}",
+                    match val & 0x1ff {
+                        0 => "thread".to_string(),
+                        n => exception_name(hubris, n),
+                    }
+                ),
+                format!(
+                    "VECTPENDING = {}",
+                    match (val >> 12) & 0x1ff {
+                        0 => "none".to_string(),
+                        n => exception_name(hubris, n),
+                    }
+                ),
+            ];
+
+            detail.extend(flags(
+                val,
+                &[(22, "ISRPENDING"), (26, "PENDSTSET"), (28, "PENDSVSET")],
+            ));
+
+            detail.join(", ")
+        }
+        "VTOR" => hubris.explain(regions, val)?,
+        "AIRCR" => format!("PRIGROUP = {}", (val >> 8) & 0x7),
+        "SYST_RVR" | "SYST_CVR" => format!("{}", val & 0xff_ffff),
+        _ => flags(val, names).join(", "),
+    };
+
+    if detail.is_empty() {
+        None
+    } else {
+        Some(detail)
+    }
+}
+
+fn print_system(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    regions: &BTreeMap<u32, HubrisRegion>,
+) -> Result<()> {
+    for (name, addr, names) in SYSTEM_REGISTERS {
+        let val = match core.read_word_32(*addr) {
+            Ok(val) => val,
+            Err(_) => {
+                println!("{:>9} = <unreadable>", name);
+                continue;
+            }
+        };
+
+        match system_detail(hubris, regions, name, val, names) {
+            Some(detail) => {
+                println!("{:>9} = 0x{:08x} <- {}", name, val, detail)
+            }
+            None => println!("{:>9} = 0x{:08x}", name, val),
+        }
+    }
+
+    let mut nvic = "NVIC";
+
+    for irq in nvic_read(core)? {
+        if !irq.enabled && !irq.pending && !irq.active {
+            continue;
+        }
+
+        let mut state = vec![];
+
+        if irq.enabled {
+            state.push("enabled");
+        }
+
+        if irq.pending {
+            state.push("pending");
+        }
+
+        if irq.active {
+            state.push("active");
+        }
+
+        println!(
+            "{:>9} = {} {}",
+            nvic,
+            exception_name(hubris, irq.irq + 16),
+            state.join(" ")
+        );
+
+        nvic = "";
+    }
+
+    Ok(())
 }
 
 fn print_reg(reg: ARMRegister, val: u32, fields: &[ARMRegisterField]) {
@@ -241,12 +554,17 @@ fn registers(
 ) -> Result<()> {
     let subargs = RegistersArgs::try_parse_from(subargs)?;
     let mut regs = BTreeMap::new();
+    let mut fp = subargs.fp || subargs.all;
 
-    if subargs.fp && !core.is_dump() {
+    if fp && !core.is_dump() {
         let mvfr = MVFR0::read(core)?;
 
         if mvfr.simd_registers() != 1 {
-            bail!("microcontroller does not support floating point");
+            if subargs.fp {
+                bail!("microcontroller does not support floating point");
+            }
+
+            fp = false;
         }
     }
 
@@ -279,7 +597,7 @@ fn registers(
             }
         };
 
-        if reg.is_floating_point() && !subargs.fp {
+        if reg.is_floating_point() && !fp {
             continue;
         }
 
@@ -299,25 +617,38 @@ fn registers(
         ..Default::default()
     };
 
+    let decode = subargs.mode || subargs.all;
+
     for (reg, val) in regs.iter() {
         let val = *val;
 
         if let Some(fields) = reg.fields() {
             print_reg(*reg, val, &fields);
+
+            if decode && *reg == ARMRegister::SPR {
+                if let Some(psr) = regs.get(&ARMRegister::PSR) {
+                    println!("{:>5} = {}\n", "MODE", mode(hubris, *psr, val));
+                }
+            }
+
             continue;
         }
 
+        let explain = if reg.is_floating_point() {
+            None
+        } else if decode && *reg == ARMRegister::LR {
+            exc_return(val).or_else(|| hubris.explain(&regions, val))
+        } else {
+            hubris.explain(&regions, val)
+        };
+
         println!(
             "{:>5} = 0x{:08x}{}",
             reg,
             val,
-            if !reg.is_floating_point() {
-                match hubris.explain(&regions, val) {
-                    Some(explain) => format!(" <- {}", explain),
-                    None => "".to_string(),
-                }
-            } else {
-                "".to_string()
+            match explain {
+                Some(explain) => format!(" <- {}", explain),
+                None => "".to_string(),
             }
         );
 
@@ -362,6 +693,14 @@ fn registers(
         }
     }
 
+    if subargs.system || subargs.all {
+        if core.is_dump() {
+            humility::msg!("system registers are not available in a dump");
+        } else {
+            print_system(hubris, core, &regions)?;
+        }
+    }
+
     core.run()?;
 
     Ok(())
@@ -429,25 +429,21 @@ fn rencm_attached(
                     if let Some(ndx) = calls[rndx] {
                         let job = work[ndx];
 
-                        let err = match job.3 {
-                            None => ("read", read_func.strerror(*code)),
-                            Some(_) => ("write", write_func.strerror(*code)),
+                        let (op, func) = match job.3 {
+                            None => ("read", read_func),
+                            Some(_) => ("write", write_func),
                         };
 
-                        bail!(
-                            "failed to {} {} at 0x{:x}: {}: {:?}",
-                            err.0,
+                        return Err(func.error(*code).context(format!(
+                            "failed to {} {} at 0x{:x}",
+                            op,
                             jobname(&job),
                             jobaddr(&job),
-                            err.1,
-                            results
-                        );
+                        )));
                     } else {
-                        bail!(
-                            "failed to page write: {}: {:?}",
-                            write_func.strerror(*code),
-                            results,
-                        );
+                        return Err(write_func
+                            .error(*code)
+                            .context("failed to page write"));
                     }
                 }
             }
@@ -2,17 +2,130 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! ## `humility rendmp`
+//!
+//! `humility rendmp` operates on Renesas digital multiphase PWM controllers.
+//! When attached, the device is identified by its `IC_DEVICE_ID`, and a
+//! summary of its capabilities is displayed:
+//!
+//! ```console
+//! % humility rendmp -r VDD_VCORE
+//! humility: attached via ST-Link V3
+//! PROPERTY     VALUE
+//! device       raa229618
+//! device id    0x99d28200
+//! firmware     0x00000006
+//! rails        2
+//! phases       12
+//! nvm slots    27
+//! ```
+//!
+//! The identified device determines the PMBus driver used to decode the
+//! device's commands.  A driver can be explicitly specified with `-D`
+//! (`--driver`); if the device does not identify as the specified driver,
+//! a warning is displayed.  If the device cannot be identified and no
+//! driver is specified, the device in the archive is used if it is a known
+//! Renesas device; otherwise, `humility rendmp` will refuse to proceed.
+//!
+//! To decode the device's revision and NVM usage, use `--info`:  the
+//! silicon and firmware revisions (from `IC_DEVICE_REV`), the PMBus
+//! revision, the number of NVM banks that have been used, the raw bank
+//! status registers (one nibble per bank) and the programmer status:
+//!
+//! ```console
+//! % humility rendmp -r VDD_VCORE --info
+//! humility: attached via ST-Link V3
+//! PROPERTY     VALUE
+//! device       raa229618
+//! ...
+//! silicon rev  0x00
+//! firmware rev 0x000006
+//! pmbus rev    1.3 (Part I), 1.3 (Part II)
+//! nvm used     1
+//! bank status  00000001 00000000 00000000 00000000
+//! programmer   0x00000001
+//! ```
+//!
+//! For simple interactions with the device, `--get` reads a PMBus command
+//! by name and decodes it, and `--set` writes a PMBus command by name.  The
+//! command tables of the identified device are used, so each command is read
+//! or written with the correct width and its value is decoded (or encoded)
+//! according to its data format -- including the device's `VOUT_MODE` for
+//! output voltages.  If the device has multiple rails, the rail specified
+//! with `-r` is selected before reading or writing.  Multiple commands can be
+//! read at once:
+//!
+//! ```console
+//! % humility rendmp -r VDD_VCORE --get VOUT_COMMAND,READ_VOUT,STATUS_BYTE
+//! humility: attached via ST-Link V3
+//! COMMAND         FIELD                  RAW        VALUE
+//! VOUT_COMMAND    VoltageOut             0x0384     0.900V
+//! READ_VOUT       VoltageOut             0x0382     0.898V
+//! STATUS_BYTE     Busy                   0b0        NotBusy
+//! STATUS_BYTE     Off                    0b0        On
+//! ...
+//! ```
+//!
+//! A value to be set is either a raw integer or a value in engineering
+//! units (optionally with a unit suffix, e.g. `0.95V` or `950mV`); a field
+//! of a command can be set by name (e.g., `OPERATION.OnOffState=Off`), and a
+//! command that takes no data is sent by specifying its name alone.  After a
+//! command is written, it is read back to verify the write:
+//!
+//! ```console
+//! % humility rendmp -r VDD_VCORE --set VOUT_COMMAND=0.91V
+//! humility: attached via ST-Link V3
+//! humility: I2C3, port H, device 0x5c: successfully wrote VOUT_COMMAND = 0.910V
+//! ```
+//!
+//! To dump all device memory to a file, use `--dump`.  To generate a Rust
+//! configuration payload from a Power Navigator text file, use `-i`
+//! (`--ingest`), specifying the driver with `-D`.  If the file identifies
+//! the device for which it was generated, it is refused if that is not the
+//! specified driver.  If a device is specified by rail with `-r`, the device
+//! is identified, and the file is also refused if it was generated for a
+//! different device or for a different silicon revision.
+//!
+//! To emit the ingested configuration in a structured form for consumption
+//! by other tooling (rather than as Rust), use `--emit json` or `--emit
+//! ron`.  The configuration is emitted as the device and its expected
+//! `IC_DEVICE_ID` and `IC_DEVICE_REV` (if present in the file), followed by
+//! each write in order:  a PMBus write has the `kind` of `pmbus` and the
+//! `code` and `name` of the command, and a DMA write has the `kind` of `dma`
+//! and its `address`; each has its `payload` as bytes in the order written:
+//!
+//! ```console
+//! % humility rendmp -D raa229618 -i config.txt --emit json
+//! {
+//!   "device": "raa229618",
+//!   "id": 2580709888,
+//!   "rev": 6,
+//!   "writes": [
+//!     {
+//!       "kind": "dma",
+//!       "address": 58,
+//!       "payload": [
+//! ...
+//! ```
+//!
+
 use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
 use humility_cmd::i2c::I2cArgs;
+use humility_cmd::output::{Cell, Column, OutputFormat, Table};
+use humility_cmd::pmgen;
+use humility_cmd::progress::Progress;
+use humility_cmd::style::Severity;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 
 use anyhow::{bail, Result};
 use clap::Command as ClapCommand;
-use clap::{CommandFactory, Parser};
+use clap::{ArgEnum, CommandFactory, Parser};
 use hif::*;
-use indicatif::{ProgressBar, ProgressStyle};
+use pmbus::commands::*;
+use pmbus::*;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::fs::{self, OpenOptions};
 use std::io::prelude::*;
@@ -65,6 +178,37 @@ struct RendmpArgs {
     #[clap(long)]
     dump: bool,
 
+    /// when ingesting, the format to emit
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "rust",
+        value_name = "format",
+        requires = "ingest"
+    )]
+    emit: Emit,
+
+    /// read and decode revision and NVM bank usage
+    #[clap(long, conflicts_with = "ingest")]
+    info: bool,
+
+    /// read PMBus commands by name and decode their values
+    #[clap(
+        long,
+        value_name = "command",
+        use_value_delimiter = true,
+        conflicts_with_all = &["dump", "info", "ingest", "set"]
+    )]
+    get: Option<Vec<String>>,
+
+    /// write a PMBus command by name (e.g., VOUT_COMMAND=0.9V)
+    #[clap(
+        long,
+        value_name = "command=value",
+        conflicts_with_all = &["dump", "info", "ingest"]
+    )]
+    set: Option<String>,
+
     /// ingest a Power Navigator text file
     #[clap(
         long,
@@ -75,6 +219,81 @@ struct RendmpArgs {
     ingest: Option<String>,
 }
 
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum Emit {
+    Rust,
+    Json,
+    Ron,
+}
+
+///
+/// An ingested configuration, as emitted in a structured format.
+///
+#[derive(Debug, Serialize)]
+struct Ingested<'a> {
+    device: &'a str,
+    id: Option<u32>,
+    rev: Option<u32>,
+    writes: Vec<pmgen::Record>,
+}
+
+///
+/// A Renesas device that we can identify by its `IC_DEVICE_ID`, along with
+/// its PMBus driver and its number of rails and phases.
+///
+struct Model {
+    driver: &'static str,
+    id: u32,
+    rails: u8,
+    phases: u8,
+}
+
+const MODELS: &[Model] = &[
+    Model { driver: "isl68224", id: 0x49d2_8100, rails: 3, phases: 6 },
+    Model { driver: "raa229618", id: 0x99d2_8200, rails: 2, phases: 12 },
+];
+
+//
+// The PMBus commands that identify a device, and the DMA address that
+// contains the number of NVM slots that remain.
+//
+const IC_DEVICE_ID: u8 = 0xad;
+const IC_DEVICE_REV: u8 = 0xae;
+const NVM_SLOTS: u16 = 0x00c2;
+
+//
+// The PMBus revision, and the DMA addresses of the programmer status and of
+// the bank status registers, as described in the Renesas programming guide.
+// Each bank status register contains the status of eight NVM banks, one per
+// nibble; a bank with a status of zero has not been used.
+//
+const PMBUS_REVISION: u8 = 0x98;
+const PROGRAMMER_STATUS: u16 = 0x007e;
+const BANK_STATUS: [u16; 4] = [0x0007, 0x0008, 0x0009, 0x000a];
+
+//
+// We decode `IC_DEVICE_REV` as having the silicon revision in its most
+// significant byte, and the firmware revision in the remainder.  A
+// configuration is specific to the silicon revision for which it was
+// generated.
+//
+fn silicon(rev: u32) -> u8 {
+    (rev >> 24) as u8
+}
+
+fn firmware(rev: u32) -> u32 {
+    rev & 0x00ff_ffff
+}
+
+fn word(result: Option<&Result<Vec<u8>, u32>>) -> Option<u32> {
+    match result {
+        Some(Ok(val)) if val.len() == 4 => {
+            Some(u32::from_le_bytes(val[..].try_into().unwrap()))
+        }
+        _ => None,
+    }
+}
+
 fn all_commands(
     device: pmbus::Device,
 ) -> HashMap<String, (u8, pmbus::Operation, pmbus::Operation)> {
@@ -92,44 +311,13 @@ fn all_commands(
     all
 }
 
-#[derive(Copy, Clone, Debug)]
-enum Address<'a> {
-    Dma(u16),
-    Pmbus(u8, &'a str),
-}
-
-struct Packet<'a> {
-    address: Address<'a>,
-    payload: Vec<u8>,
-}
-
-fn rendmp_gen(
-    _subargs: &RendmpArgs,
-    device: &pmbus::Device,
-    packets: &[Packet],
-    commands: &HashMap<String, (u8, pmbus::Operation, pmbus::Operation)>,
-) -> Result<()> {
-    println!(
-        r##"// This Source Code Form is subject to the terms of the Mozilla Public
-// License, v. 2.0. If a copy of the MPL was not distributed with this
-// file, You can obtain one at https://mozilla.org/MPL/2.0/.
-
 ///
-/// Iterate over a configuration payload for a Renesas {} digital multiphase
-/// PWM controller.  This code was generated by "humility rendmp -g" given
-/// a .txt dump from running Renesas configuration software.
+/// Returns the codes of the DMA commands, which we use to generate DMA writes.
 ///
-#[rustfmt::skip]
-pub fn {}_payload<E>(
-    mut func: impl FnMut(&[u8]) -> Result<(), E>
-) -> Result<(), E> {{
-
-    const PAYLOAD: &[&[u8]] = &["##,
-        device.name(),
-        device.name(),
-    );
-
-    let dmaaddr = match commands.get("DMAADDR") {
+fn dma(
+    commands: &HashMap<String, (u8, pmbus::Operation, pmbus::Operation)>,
+) -> Result<pmgen::Dma> {
+    let addr = match commands.get("DMAADDR") {
         Some((code, _, write)) => {
             if *write != pmbus::Operation::WriteWord {
                 bail!("DMAADDR mismatch: found {:?}", write);
@@ -141,7 +329,7 @@ pub fn {}_payload<E>(
         }
     };
 
-    let dmafix = match commands.get("DMAFIX") {
+    let fix = match commands.get("DMAFIX") {
         Some((code, _, write)) => {
             if *write != pmbus::Operation::WriteWord32 {
                 bail!("DMADATA mismatch: found {:?}", write);
@@ -153,174 +341,716 @@ pub fn {}_payload<E>(
         }
     };
 
-    for packet in packets {
-        match packet.address {
-            Address::Dma(addr) => {
-                let p = addr.to_le_bytes();
+    Ok(pmgen::Dma { addr, fix })
+}
 
-                println!("        // DMAADDR = 0x{:04x}", addr);
-                println!(
-                    "        &[ 0x{:02x}, 0x{:02x}, 0x{:02x} ],\n",
-                    dmaaddr, p[0], p[1]
-                );
+///
+/// Ingests a Power Navigator text file for the specified driver.  If the
+/// file identifies the device for which it was generated (via its expected
+/// `IC_DEVICE_ID` and `IC_DEVICE_REV`), we refuse it if it was generated for
+/// a different device -- or, if we have an attached device (as its
+/// `IC_DEVICE_ID` and `IC_DEVICE_REV`), for a different silicon revision.
+///
+fn rendmp_ingest(
+    subargs: &RendmpArgs,
+    driver: &str,
+    attached: Option<(Option<u32>, Option<u32>)>,
+) -> Result<()> {
+    let filename = subargs.ingest.as_ref().unwrap();
+    let file = fs::File::open(filename)?;
 
-                println!("        // DMAFIX = {:x?}", packet.payload);
-                print!("        &[ 0x{:02x}, ", dmafix);
+    let mut allcmds = HashMap::new();
+
+    let device = match pmbus::Device::from_str(driver) {
+        Some(device) => device,
+        None => {
+            bail!("unknown device \"{}\"", driver);
+        }
+    };
+
+    for code in 0..0xffu8 {
+        device.command(code, |cmd| {
+            allcmds.insert(code, cmd.name().to_string());
+        });
+    }
+
+    let name = |code| allcmds.get(&code).cloned();
+    let mut packets = pmgen::ingest_renesas(BufReader::new(file), name)?;
+
+    //
+    // The identifying commands are read-only; in an export, they denote the
+    // device for which the configuration was generated rather than writes.
+    //
+    let expected = |code| {
+        packets.iter().find_map(|p| match (&p.address, p.payload.len()) {
+            (pmgen::Address::Pmbus(c, _), 4) if *c == code => {
+                Some(u32::from_le_bytes(p.payload[..].try_into().unwrap()))
             }
+            _ => None,
+        })
+    };
 
-            Address::Pmbus(code, name) => {
-                println!("        // {} = {:x?}", name, packet.payload);
+    let (id, rev) = (expected(IC_DEVICE_ID), expected(IC_DEVICE_REV));
 
-                print!("        &[ 0x{:02x}, ", code);
+    packets.retain(|p| {
+        !matches!(p.address, pmgen::Address::Pmbus(c, _)
+            if c == IC_DEVICE_ID || c == IC_DEVICE_REV)
+    });
+
+    if let Some(id) = id {
+        if let Some(model) = MODELS.iter().find(|m| m.driver == driver) {
+            if model.id != id {
+                bail!(
+                    "{} is for IC_DEVICE_ID 0x{:08x}, but {} is 0x{:08x}",
+                    filename,
+                    id,
+                    driver,
+                    model.id
+                );
             }
         }
+    }
 
-        for byte in &packet.payload {
-            print!("0x{:02x}, ", byte);
+    if let Some((device_id, device_rev)) = attached {
+        if let (Some(id), Some(device_id)) = (id, device_id) {
+            if id != device_id {
+                bail!(
+                    "{} is for IC_DEVICE_ID 0x{:08x}, but device is 0x{:08x}",
+                    filename,
+                    id,
+                    device_id
+                );
+            }
         }
 
-        println!("],\n");
+        match (rev, device_rev) {
+            (Some(rev), Some(device_rev))
+                if silicon(rev) != silicon(device_rev) =>
+            {
+                bail!(
+                    "{} is for silicon revision 0x{:02x}, but device is \
+                    revision 0x{:02x}",
+                    filename,
+                    silicon(rev),
+                    silicon(device_rev)
+                );
+            }
+            (Some(_), None) => {
+                humility::msg!(
+                    "warning: could not read device revision; \
+                    silicon revision is unverified"
+                );
+            }
+            _ => {}
+        }
     }
 
-    println!(
-        r##"    ];
+    if subargs.emit != Emit::Rust {
+        let ingested = Ingested {
+            device: device.name(),
+            id,
+            rev,
+            writes: packets.iter().map(pmgen::Record::from).collect(),
+        };
 
-    for chunk in PAYLOAD {{
-        func(chunk)?;
-    }}
+        let out = match subargs.emit {
+            Emit::Json => serde_json::to_string_pretty(&ingested)?,
+            _ => ron::ser::to_string_pretty(
+                &ingested,
+                ron::ser::PrettyConfig::default(),
+            )?,
+        };
 
-    Ok(())
-}}"##
-    );
+        println!("{}", out);
+        return Ok(());
+    }
 
-    Ok(())
+    let commands = all_commands(device);
+
+    let mut doc = vec![
+        format!(
+            "Iterate over a configuration payload for a Renesas {} digital",
+            device.name()
+        ),
+        "multiphase PWM controller.  This code was generated by \"humility"
+            .to_string(),
+        "rendmp -i\" given a .txt dump from running Renesas configuration"
+            .to_string(),
+        "software.".to_string(),
+    ];
+
+    if let (Some(id), Some(rev)) = (id, rev) {
+        doc.push(format!(
+            "Generated for IC_DEVICE_ID 0x{:08x}, IC_DEVICE_REV 0x{:08x}.",
+            id, rev
+        ));
+    }
+
+    pmgen::generate(
+        &mut std::io::stdout(),
+        device.name(),
+        &doc,
+        &packets,
+        Some(dma(&commands)?),
+    )
 }
 
-fn rendmp_ingest(subargs: &RendmpArgs) -> Result<()> {
-    let filename = subargs.ingest.as_ref().unwrap();
-    let file = fs::File::open(filename)?;
-    let lines = BufReader::new(file).lines();
+///
+/// Units that may be used to suffix a value to be set, along with the factor
+/// needed to convert a value in that unit to the unit used by PMBus.  Note
+/// that the longest suffixes must come first.
+///
+const UNITS: &[(&str, f32)] = &[
+    ("kHz", 1.0),
+    ("°C", 1.0),
+    ("mV", 0.001),
+    ("mA", 0.001),
+    ("mW", 0.001),
+    ("ms", 1.0),
+    ("Hz", 0.001),
+    ("V", 1.0),
+    ("A", 1.0),
+    ("W", 1.0),
+    ("C", 1.0),
+    ("%", 1.0),
+];
 
-    let mut allcmds = HashMap::new();
-    let mut packets = vec![];
+///
+/// Parses a value to be set.  Integers are taken to be raw values; anything
+/// else is taken to be in engineering units, and is encoded by the pmbus
+/// crate according to the format of the command.
+///
+fn parse_value(value: &str) -> Result<Replacement> {
+    if let Ok(val) = parse_int::parse::<u32>(value) {
+        return Ok(Replacement::Integer(val));
+    }
 
-    let device = if let Some(driver) = &subargs.driver {
-        match pmbus::Device::from_str(driver) {
-            Some(device) => device,
-            None => {
-                bail!("unknown device \"{}\"", driver);
-            }
-        }
-    } else {
-        bail!("must specify device driver");
-    };
+    let (num, factor) = UNITS
+        .iter()
+        .find_map(|(unit, factor)| {
+            value.strip_suffix(unit).map(|num| (num, *factor))
+        })
+        .unwrap_or((value, 1.0));
 
-    for code in 0..0xffu8 {
-        device.command(code, |cmd| {
-            allcmds.insert(code, cmd.name());
-        });
+    match num.trim().parse::<f32>() {
+        Ok(val) => Ok(Replacement::Float(val * factor)),
+        Err(_) => bail!("illegal value: {}", value),
     }
+}
 
-    for (ndx, line) in lines.enumerate() {
-        let line = line?;
-        let lineno = ndx + 1;
+fn nbytes(op: pmbus::Operation) -> Option<usize> {
+    match op {
+        pmbus::Operation::ReadByte | pmbus::Operation::WriteByte => Some(1),
+        pmbus::Operation::ReadWord | pmbus::Operation::WriteWord => Some(2),
+        pmbus::Operation::ReadWord32 | pmbus::Operation::WriteWord32 => Some(4),
+        _ => None,
+    }
+}
 
-        if line.is_empty() || line.starts_with('#') {
-            continue;
+fn readable(op: pmbus::Operation) -> bool {
+    matches!(
+        op,
+        pmbus::Operation::ReadByte
+            | pmbus::Operation::ReadWord
+            | pmbus::Operation::ReadWord32
+            | pmbus::Operation::ReadBlock
+    )
+}
+
+///
+/// Access to the device by PMBus command name, using the command tables of
+/// the identified device.
+///
+struct Access<'a> {
+    device: pmbus::Device,
+    all: &'a HashMap<String, (u8, pmbus::Operation, pmbus::Operation)>,
+    base: &'a [Op],
+    page: Option<u8>,
+    i2c_read: &'a HiffyFunction,
+    i2c_write: &'a HiffyFunction,
+}
+
+impl Access<'_> {
+    fn lookup(
+        &self,
+        name: &str,
+    ) -> Result<(u8, pmbus::Operation, pmbus::Operation)> {
+        match self.all.get(name) {
+            Some(cmd) => Ok(*cmd),
+            None => bail!("{} has no command {}", self.device.name(), name),
+        }
+    }
+
+    ///
+    /// Returns the operations to select our rail (if any) and to read
+    /// `VOUT_MODE`, which is needed to interpret many commands.
+    ///
+    fn prologue(&self) -> Vec<Op> {
+        let mut ops = self.base.to_vec();
+
+        if let Some(page) = self.page {
+            ops.push(Op::Push(CommandCode::PAGE as u8));
+            ops.push(Op::Push(page));
+            ops.push(Op::Push(1));
+            ops.push(Op::Call(self.i2c_write.id));
+            ops.push(Op::DropN(3));
         }
 
-        let contents = line.split_whitespace().collect::<Vec<_>>();
+        ops.push(Op::Push(CommandCode::VOUT_MODE as u8));
+        ops.push(Op::Push(1));
+        ops.push(Op::Call(self.i2c_read.id));
+        ops.push(Op::DropN(2));
+
+        ops
+    }
 
-        if contents.len() != 4 || contents[2] != "#" {
-            bail!("malformed line {}", lineno);
+    fn read(&self, ops: &mut Vec<Op>, code: u8, op: pmbus::Operation) {
+        ops.push(Op::Push(code));
+
+        match nbytes(op) {
+            Some(n) => ops.push(Op::Push(n as u8)),
+            None => ops.push(Op::PushNone),
         }
 
-        let payload = contents[1];
+        ops.push(Op::Call(self.i2c_read.id));
+        ops.push(Op::DropN(2));
+    }
 
-        if !payload.starts_with("0x") {
-            bail!("bad payload prefix on line {}: {}", lineno, payload);
+    ///
+    /// Checks the results of our prologue, returning our `VOUT_MODE` and the
+    /// index of the first result that follows it.
+    ///
+    fn mode(
+        &self,
+        results: &[Result<Vec<u8>, u32>],
+    ) -> Result<(VOUT_MODE::CommandData, usize)> {
+        let mut ndx = 0;
+
+        if let Some(page) = self.page {
+            if let Err(code) = results[ndx] {
+                return Err(self
+                    .i2c_write
+                    .error(code)
+                    .context(format!("failed to select rail {}", page)));
+            }
+
+            ndx += 1;
         }
 
-        let payload = match payload.len() {
-            4 => match parse_int::parse::<u8>(payload) {
-                Ok(val) => val.to_le_bytes().to_vec(),
-                Err(_) => {
-                    bail!("bad payload on line {}: {}", lineno, payload);
-                }
+        let mode = match &results[ndx] {
+            Ok(val) if val.len() == 1 => {
+                VOUT_MODE::CommandData::from_slice(val).unwrap()
+            }
+            Ok(val) => bail!("bad VOUT_MODE: {:x?}", val),
+            Err(code) => {
+                return Err(self
+                    .i2c_read
+                    .error(*code)
+                    .context("can't read VOUT_MODE"));
+            }
+        };
+
+        Ok((mode, ndx + 1))
+    }
+
+    ///
+    /// Interprets the value of a command, returning each field's name, raw
+    /// value and value.
+    ///
+    fn interpret(
+        &self,
+        code: u8,
+        val: &[u8],
+        mode: VOUT_MODE::CommandData,
+    ) -> Vec<(String, String, String)> {
+        let mut rval = vec![];
+
+        let _ = self.device.interpret(
+            code,
+            val,
+            || mode,
+            |field, value| {
+                let nbits = field.bits().1 .0 as usize;
+
+                let raw = if field.bitfield() {
+                    format!("0b{:0w$b}", value.raw(), w = nbits)
+                } else {
+                    format!("0x{:0w$x}", value.raw(), w = (nbits + 3) / 4)
+                };
+
+                rval.push((
+                    field.name().to_string(),
+                    raw,
+                    format!("{}", value),
+                ));
             },
+        );
+
+        rval
+    }
+
+    fn get(
+        &self,
+        core: &mut dyn Core,
+        context: &mut HiffyContext,
+        format: OutputFormat,
+        names: &[String],
+    ) -> Result<()> {
+        let mut cmds = vec![];
+        let mut ops = self.prologue();
+
+        for name in names {
+            let (code, read, _) = self.lookup(name)?;
+
+            if !readable(read) {
+                bail!("{} cannot be read", name);
+            }
 
-            6 => match parse_int::parse::<u16>(payload) {
-                Ok(val) => val.to_le_bytes().to_vec(),
-                Err(_) => {
-                    bail!("bad payload on line {}: {}", lineno, payload);
+            self.read(&mut ops, code, read);
+            cmds.push((name, code));
+        }
+
+        ops.push(Op::Done);
+
+        let results = context.run(core, ops.as_slice(), None)?;
+        let (mode, base) = self.mode(&results)?;
+
+        let mut table = Table::new(
+            format,
+            vec![
+                Column::new("command", 15),
+                Column::new("field", 22),
+                Column::new("raw", 10),
+                Column::new("value", 0),
+            ],
+        );
+
+        for ((name, code), result) in cmds.iter().zip(&results[base..]) {
+            let val = match result {
+                Ok(val) => val,
+                Err(err) => {
+                    table.row(vec![
+                        name.as_str().into(),
+                        Cell::None,
+                        Cell::None,
+                        Cell::from(self.i2c_read.strerror(*err))
+                            .styled(Severity::Error),
+                    ])?;
+                    continue;
                 }
-            },
+            };
 
-            10 => match parse_int::parse::<u32>(payload) {
-                Ok(val) => val.to_le_bytes().to_vec(),
-                Err(_) => {
-                    bail!("bad payload on line {}: {}", lineno, payload);
+            let fields = self.interpret(*code, val, mode);
+
+            if fields.is_empty() {
+                table.row(vec![
+                    name.as_str().into(),
+                    Cell::None,
+                    format!("{:x?}", val).into(),
+                    Cell::None,
+                ])?;
+            }
+
+            for (field, raw, value) in fields {
+                table.row(vec![
+                    name.as_str().into(),
+                    field.into(),
+                    raw.into(),
+                    value.into(),
+                ])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Determines the field and replacement for a value to be set.
+    ///
+    fn replacement(
+        &self,
+        name: &str,
+        code: u8,
+        field: Option<&str>,
+        value: &str,
+    ) -> Result<(Bitpos, Replacement)> {
+        let mut found = None;
+        let mut all = vec![];
+
+        self.device
+            .fields(code, |f| {
+                match field {
+                    Some(field) if f.name() == field => found = Some(f.bits()),
+                    None if !f.bitfield() => found = Some(f.bits()),
+                    _ => {}
                 }
-            },
 
-            _ => {
-                bail!("badly sized payload on line {}: {}", lineno, payload);
+                all.push(f.name());
+            })
+            .unwrap();
+
+        let bits = match (found, field) {
+            (Some(bits), _) => bits,
+            (None, Some(field)) => bail!(
+                "field {} not found in {}; expected one of: {}",
+                field,
+                name,
+                all.join(", ")
+            ),
+            (None, None) if !all.is_empty() => {
+                bail!("{} has bitfields which must be set explicitly", name)
+            }
+            (None, None) => {
+                bail!("can't set {}: data has unknown type", name)
             }
         };
 
-        let address = contents[3];
+        let field = match field {
+            Some(field) => field,
+            None => return Ok((bits.0, parse_value(value)?)),
+        };
+
+        let mut replacement = None;
+        let mut sentinels = vec![];
+
+        self.device
+            .sentinels(code, bits.0, |s| {
+                if s.name() == value {
+                    replacement = Some(Replacement::Integer(s.raw()));
+                }
+
+                sentinels.push(s.name());
+            })
+            .unwrap();
+
+        match replacement {
+            Some(replacement) => Ok((bits.0, replacement)),
+            None => bail!(
+                "field {} of {} cannot be set to {}; expected one of: {}",
+                field,
+                name,
+                value,
+                sentinels.join(", ")
+            ),
+        }
+    }
+
+    fn set(
+        &self,
+        core: &mut dyn Core,
+        context: &mut HiffyContext,
+        hargs: &I2cArgs,
+        set: &str,
+    ) -> Result<()> {
+        let (lhs, value) = match set.split_once('=') {
+            Some((lhs, value)) => (lhs, Some(value)),
+            None => (set, None),
+        };
+
+        let (name, field) = match lhs.split_once('.') {
+            Some((name, field)) => (name, Some(field)),
+            None => (lhs, None),
+        };
+
+        let (code, read, write) = self.lookup(name)?;
+        let mut ops = self.prologue();
 
         //
-        // This is lame, but the only way to differentiate PMBus writes
-        // (single-byte address) from DMA writes (dual-byte) is to look
-        // at length of the string:
+        // A command that takes no data is sent as a 1-byte raw write of the
+        // command (by indicating the register to be None), and a block is
+        // written as its size followed by its payload; neither can be read
+        // back.  Anything else is read, modified and written.
         //
-        if !address.starts_with("0x") {
-            bail!("bad address on line {}: {}", lineno, address);
-        }
+        match (write, value) {
+            (pmbus::Operation::SendByte, None) => {
+                ops.push(Op::PushNone);
+                ops.push(Op::Push(code));
+                ops.push(Op::Push(1));
+                ops.push(Op::Call(self.i2c_write.id));
+                ops.push(Op::DropN(3));
+            }
+            (pmbus::Operation::SendByte, Some(_)) => {
+                bail!("{} cannot take a value", name);
+            }
+            (pmbus::Operation::WriteBlock, Some(value)) => {
+                if field.is_some() {
+                    bail!("{} can only take raw bytes", name);
+                }
+
+                let mut payload = vec![];
+
+                for byte in value.split(',') {
+                    match parse_int::parse::<u8>(byte) {
+                        Ok(val) => payload.push(val),
+                        Err(_) => bail!("invalid byte {}", byte),
+                    }
+                }
 
-        let address = if address.len() > 4 {
-            match parse_int::parse::<u16>(address) {
-                Ok(dmaaddr) => Address::Dma(dmaaddr),
-                Err(_) => {
-                    bail!("bad DMA address on line {}: {}", lineno, address);
+                ops.push(Op::Push(code));
+                ops.push(Op::Push(payload.len() as u8));
+
+                for &byte in &payload {
+                    ops.push(Op::Push(byte));
                 }
+
+                ops.push(Op::Push(payload.len() as u8 + 1));
+                ops.push(Op::Call(self.i2c_write.id));
+                ops.push(Op::DropN(payload.len() as u8 + 3));
+            }
+            (_, None) => {
+                bail!("{} needs a value, e.g. {}=value", name, name);
+            }
+            (_, Some(_)) if nbytes(write).is_none() || !readable(read) => {
+                bail!("{} cannot be set", name);
+            }
+            (_, Some(_)) => {
+                self.read(&mut ops, code, read);
             }
-        } else {
-            match parse_int::parse::<u8>(address) {
-                Ok(paddr) => {
-                    Address::Pmbus(paddr, allcmds.get(&paddr).unwrap())
+        }
+
+        ops.push(Op::Done);
+
+        let results = context.run(core, ops.as_slice(), None)?;
+        let (mode, ndx) = self.mode(&results)?;
+
+        let (size, value) = match (nbytes(write), value) {
+            (Some(size), Some(value)) => (size, value),
+            _ => {
+                if let Err(err) = results[ndx] {
+                    return Err(self
+                        .i2c_write
+                        .error(err)
+                        .context(format!("failed to set {}", name)));
                 }
-                Err(_) => {
-                    bail!("bad PMBus address on line {}: {}", lineno, address);
+
+                humility::msg!("{}: successfully wrote {}", hargs, set);
+                return Ok(());
+            }
+        };
+
+        let payload = match &results[ndx] {
+            Ok(val) if val.len() == size => val,
+            Ok(val) => bail!(
+                "mismatch on {}: expected {} bytes, found {}",
+                name,
+                size,
+                val.len()
+            ),
+            Err(err) => {
+                return Err(self
+                    .i2c_read
+                    .error(*err)
+                    .context(format!("failed to read {}", name)));
+            }
+        };
+
+        let (pos, replacement) = self.replacement(name, code, field, value)?;
+        let mut written = payload.clone();
+        let mut replaced = false;
+
+        let err = self.device.mutate(
+            code,
+            &mut written,
+            || mode,
+            |f, _| {
+                if f.bits().0 == pos {
+                    replaced = true;
+                    Some(replacement)
+                } else {
+                    None
                 }
+            },
+        );
+
+        if err.is_err() || !replaced {
+            bail!("failed to set {} to {}: {:?}", name, value, err);
+        }
+
+        let mut ops = self.prologue();
+
+        ops.push(Op::Push(code));
+
+        for &byte in &written {
+            ops.push(Op::Push(byte));
+        }
+
+        ops.push(Op::Push(size as u8));
+        ops.push(Op::Call(self.i2c_write.id));
+        ops.push(Op::DropN(size as u8 + 2));
+
+        //
+        // And read it back, that we may verify that the write took.
+        //
+        self.read(&mut ops, code, read);
+        ops.push(Op::Done);
+
+        let results = context.run(core, ops.as_slice(), None)?;
+        let (mode, ndx) = self.mode(&results)?;
+
+        if let Err(err) = results[ndx] {
+            return Err(self
+                .i2c_write
+                .error(err)
+                .context(format!("failed to write {}", name)));
+        }
+
+        let readback = match &results[ndx + 1] {
+            Ok(val) => val,
+            Err(err) => {
+                return Err(self
+                    .i2c_read
+                    .error(*err)
+                    .context(format!("failed to read back {}", name)));
             }
         };
 
-        packets.push(Packet { address, payload });
-    }
+        if *readback != written {
+            bail!(
+                "wrote {:x?} to {}, but read back {:x?}",
+                written,
+                name,
+                readback
+            );
+        }
 
-    packets.push(Packet {
-        address: Address::Pmbus(0xe7, allcmds.get(&0xe7).unwrap()),
-        payload: vec![1, 0],
-    });
+        let fields = self.interpret(code, readback, mode);
 
-    let commands = all_commands(device);
-    rendmp_gen(subargs, &device, &packets, &commands)?;
+        let value = match field {
+            Some(field) => fields.iter().find(|f| f.0 == field),
+            None => fields.first(),
+        };
 
-    Ok(())
+        match value {
+            Some((_, _, value)) => humility::msg!(
+                "{}: successfully wrote {} = {}",
+                hargs,
+                lhs,
+                value
+            ),
+            None => humility::msg!("{}: successfully wrote {}", hargs, set),
+        }
+
+        Ok(())
+    }
 }
 
 fn rendmp(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = RendmpArgs::try_parse_from(subargs)?;
 
-    if subargs.ingest.is_some() {
-        return rendmp_ingest(&subargs);
+    //
+    // Without a device, we can ingest without attaching -- but we can't
+    // check the configuration against the device.
+    //
+    if subargs.ingest.is_some() && subargs.rail.is_none() {
+        return match &subargs.driver {
+            Some(driver) => rendmp_ingest(&subargs, driver, None),
+            None => bail!("must specify device driver"),
+        };
     }
 
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
@@ -328,19 +1058,29 @@ fn rendmp(
     let i2c_read = funcs.get("I2cRead", 7)?;
     let i2c_write = funcs.get("I2cWrite", 8)?;
 
-    let hargs = match (&subargs.rail, &subargs.device) {
+    //
+    // If a device is specified by rail, we also note the rail's page (if
+    // the device has more than one rail) for any access by command.
+    //
+    let (hargs, page) = match (&subargs.rail, &subargs.device) {
         (Some(rail), None) => {
             let mut found = None;
 
             for device in &hubris.manifest.i2c_devices {
                 if let HubrisI2cDeviceClass::Pmbus { rails } = &device.class {
-                    for r in rails {
+                    for (rnum, r) in rails.iter().enumerate() {
                         if rail == r {
+                            let page = if rails.len() > 1 {
+                                Some(rnum as u8)
+                            } else {
+                                None
+                            };
+
                             found = match found {
                                 Some(_) => {
                                     bail!("multiple devices match {}", rail);
                                 }
-                                None => Some(device),
+                                None => Some((device, page)),
                             }
                         }
                     }
@@ -351,38 +1091,127 @@ fn rendmp(
                 None => {
                     bail!("rail {} not found", rail);
                 }
-                Some(device) => I2cArgs::from_device(device),
+                Some((device, page)) => (I2cArgs::from_device(device), page),
             }
         }
 
-        (_, _) => I2cArgs::parse(
-            hubris,
-            &subargs.bus,
-            subargs.controller,
-            &subargs.port,
-            &subargs.mux,
-            &subargs.device,
-        )?,
+        (_, _) => (
+            I2cArgs::parse(
+                hubris,
+                &subargs.bus,
+                subargs.controller,
+                &subargs.port,
+                &subargs.mux,
+                &subargs.device,
+            )?,
+            None,
+        ),
+    };
+
+    let mut base = vec![];
+    hargs.push_bus(&mut base);
+
+    if let Some(address) = hargs.address {
+        base.push(Op::Push(address));
+    } else {
+        bail!("expected device");
+    }
+
+    //
+    // Identify the device by reading IC_DEVICE_ID and IC_DEVICE_REV (both
+    // of which are block reads).
+    //
+    let mut ops = base.clone();
+
+    for code in [IC_DEVICE_ID, IC_DEVICE_REV] {
+        ops.push(Op::Push(code));
+        ops.push(Op::PushNone);
+        ops.push(Op::Call(i2c_read.id));
+        ops.push(Op::DropN(2));
+    }
+
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+    let id = word(results.get(0));
+    let rev = word(results.get(1));
+    let model = id.and_then(|id| MODELS.iter().find(|m| m.id == id));
+
+    let described = match id {
+        Some(id) => format!("IC_DEVICE_ID 0x{:08x}", id),
+        None => match results.get(0) {
+            Some(Err(err)) => i2c_read.strerror(*err),
+            _ => "no IC_DEVICE_ID".to_string(),
+        },
     };
 
-    let device = if let Some(driver) = &subargs.driver {
-        match pmbus::Device::from_str(driver) {
-            Some(device) => device,
-            None => {
-                bail!("unknown device \"{}\"", driver);
+    let (driver, model) = match (&subargs.driver, model) {
+        (Some(driver), model) => {
+            if let Some(model) = model {
+                if model.driver != driver.as_str() {
+                    humility::msg!(
+                        "warning: device identifies as {}, but using {}",
+                        model.driver,
+                        driver
+                    );
+                }
+            }
+
+            (
+                driver.clone(),
+                MODELS.iter().find(|m| m.driver == driver.as_str()),
+            )
+        }
+        (None, Some(model)) => (model.driver.to_string(), Some(model)),
+        (None, None) => {
+            let known = hargs.device.as_ref().and_then(|device| {
+                MODELS.iter().find(|m| m.driver == device.as_str())
+            });
+
+            match known {
+                Some(model) => {
+                    humility::msg!(
+                        "could not identify device ({}); using {} from archive",
+                        described,
+                        model.driver
+                    );
+                    (model.driver.to_string(), Some(model))
+                }
+                None => {
+                    bail!(
+                        "could not identify device ({}); \
+                        specify a driver with --driver",
+                        described
+                    );
+                }
             }
         }
-    } else if let Some(driver) = hargs.device {
-        match pmbus::Device::from_str(&driver) {
-            Some(device) => device,
-            None => pmbus::Device::Common,
+    };
+
+    let device = match pmbus::Device::from_str(&driver) {
+        Some(device) => device,
+        None => {
+            bail!("unknown device \"{}\"", driver);
         }
-    } else {
-        pmbus::Device::Common
     };
 
+    if subargs.ingest.is_some() {
+        return rendmp_ingest(&subargs, device.name(), Some((id, rev)));
+    }
+
     let all = all_commands(device);
 
+    let access =
+        Access { device, all: &all, base: &base, page, i2c_read, i2c_write };
+
+    if let Some(get) = &subargs.get {
+        return access.get(core, &mut context, args.format, get);
+    }
+
+    if let Some(set) = &subargs.set {
+        return access.set(core, &mut context, &hargs, set);
+    }
+
     let dmaaddr = match all.get("DMAADDR") {
         Some((code, _, write)) => {
             if *write != pmbus::Operation::WriteWord {
@@ -407,20 +1236,134 @@ fn rendmp(
         }
     };
 
-    let mut base = vec![Op::Push(hargs.controller), Op::Push(hargs.port.index)];
+    let dmafix = match all.get("DMAFIX") {
+        Some((code, read, _)) => {
+            if *read != pmbus::Operation::ReadWord32 {
+                bail!("DMAFIX mismatch: found {:?}", read);
+            }
+            *code
+        }
+        _ => {
+            bail!("no DMAFIX command found; is this a Renesas device?");
+        }
+    };
 
-    if let Some(mux) = hargs.mux {
-        base.push(Op::Push(mux.0));
-        base.push(Op::Push(mux.1));
-    } else {
-        base.push(Op::PushNone);
-        base.push(Op::PushNone);
+    //
+    // Now read the number of NVM slots remaining -- and, if we have been
+    // asked for more information, the programmer and bank status.  Each DMA
+    // read is a write of the DMA address followed by a read of the data,
+    // yielding two results.
+    //
+    let mut ops = base.clone();
+
+    let dma_read = |ops: &mut Vec<Op>, addr: u16| {
+        let addr = addr.to_le_bytes();
+
+        ops.push(Op::Push(dmaaddr));
+        ops.push(Op::Push(addr[0]));
+        ops.push(Op::Push(addr[1]));
+        ops.push(Op::Push(2));
+        ops.push(Op::Call(i2c_write.id));
+        ops.push(Op::DropN(4));
+        ops.push(Op::Push(dmafix));
+        ops.push(Op::Push(4));
+        ops.push(Op::Call(i2c_read.id));
+        ops.push(Op::DropN(2));
+    };
+
+    dma_read(&mut ops, NVM_SLOTS);
+
+    if subargs.info {
+        dma_read(&mut ops, PROGRAMMER_STATUS);
+
+        for addr in BANK_STATUS {
+            dma_read(&mut ops, addr);
+        }
+
+        ops.push(Op::Push(PMBUS_REVISION));
+        ops.push(Op::Push(1));
+        ops.push(Op::Call(i2c_read.id));
     }
 
-    if let Some(address) = hargs.address {
-        base.push(Op::Push(address));
-    } else {
-        bail!("expected device");
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+
+    let dma_result = |ndx: usize| match results.get(ndx * 2) {
+        Some(Ok(_)) => word(results.get(ndx * 2 + 1)),
+        _ => None,
+    };
+
+    let slots = dma_result(0);
+
+    let mut table = Table::new(
+        args.format,
+        vec![Column::new("property", 12), Column::new("value", 0)],
+    );
+
+    let hex = |val: Option<u32>| val.map(|v| format!("0x{:08x}", v));
+
+    let mut properties = vec![
+        ("device", Some(driver.clone())),
+        ("device id", hex(id)),
+        ("firmware", hex(rev)),
+        ("rails", model.map(|m| m.rails.to_string())),
+        ("phases", model.map(|m| m.phases.to_string())),
+        ("nvm slots", slots.map(|s| s.to_string())),
+    ];
+
+    if subargs.info {
+        let status = dma_result(1);
+
+        let banks = (0..BANK_STATUS.len())
+            .map(|i| dma_result(i + 2))
+            .collect::<Option<Vec<_>>>();
+
+        let used = banks.as_ref().map(|banks| {
+            banks
+                .iter()
+                .map(|b| (0..8).filter(|n| (b >> (n * 4)) & 0xf != 0).count())
+                .sum::<usize>()
+        });
+
+        //
+        // The PMBus revision has the revision of Part I in its high nibble,
+        // and that of Part II in its low nibble; each is encoded as the
+        // minor revision of 1.x.
+        //
+        let pmbus = match results.get(BANK_STATUS.len() * 2 + 4) {
+            Some(Ok(val)) if val.len() == 1 => Some(format!(
+                "1.{} (Part I), 1.{} (Part II)",
+                val[0] >> 4,
+                val[0] & 0xf
+            )),
+            _ => None,
+        };
+
+        properties.extend(vec![
+            ("silicon rev", rev.map(|r| format!("0x{:02x}", silicon(r)))),
+            ("firmware rev", rev.map(|r| format!("0x{:06x}", firmware(r)))),
+            ("pmbus rev", pmbus),
+            ("nvm used", used.map(|u| u.to_string())),
+            (
+                "bank status",
+                banks.map(|banks| {
+                    banks
+                        .iter()
+                        .map(|b| format!("{:08x}", b))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }),
+            ),
+            ("programmer", hex(status)),
+        ]);
+    }
+
+    for (name, value) in properties {
+        table.row(vec![
+            name.into(),
+            value.unwrap_or_else(|| "-".into()).into(),
+        ])?;
     }
 
     if subargs.dump {
@@ -430,8 +1373,6 @@ fn rendmp(
         let laps = memsize / (blocksize as usize * nblocks);
         let mut addr = 0;
 
-        let bar = ProgressBar::new(memsize as u64);
-
         let mut filename;
         let mut i = 0;
 
@@ -451,10 +1392,8 @@ fn rendmp(
 
         humility::msg!("dumping device memory to {}", filename);
 
-        bar.set_style(ProgressStyle::default_bar().template(
-            "humility: dumping device memory \
-                          [{bar:30}] {bytes}/{total_bytes}",
-        ));
+        let mut progress =
+            Progress::bytes(args, "dumping device memory", memsize as u64);
 
         for lap in 0..laps {
             let mut ops = base.clone();
@@ -492,10 +1431,9 @@ fn rendmp(
             let start = if lap == 0 {
                 match results[0] {
                     Err(err) => {
-                        bail!(
-                            "failed to set address: {}",
-                            i2c_write.strerror(err)
-                        )
+                        return Err(i2c_write
+                            .error(err)
+                            .context("failed to set address"))
                     }
                     Ok(_) => 1,
                 }
@@ -508,14 +1446,19 @@ fn rendmp(
                     Ok(val) => {
                         file.write_all(val)?;
                         addr += val.len();
-                        bar.set_position(addr as u64);
+                        progress.set_position(addr as u64);
                     }
                     Err(err) => {
-                        bail!("{:?}", err);
+                        return Err(i2c_read.error(*err).context(format!(
+                            "failed to read at 0x{:x}",
+                            addr
+                        )));
                     }
                 }
             }
         }
+
+        progress.finish();
     }
 
     Ok(())
@@ -50,6 +50,26 @@
 //! ...
 //! ```
 //!
+//! Ring buffers are found by their type, so any buffer declared with
+//! `ringbuf!` will be found, regardless of its name.
+//!
+//! To see the entries of all ring buffers interleaved on a single timeline,
+//! use `-m` (`--merge`).  Because ring buffer entries do not carry a
+//! timestamp, entries are ordered by generation and then by position in
+//! their buffer; the interleaving between buffers is approximate.  To
+//! continue to display new entries as they are added to a live system, use
+//! `-f` (`--follow`), optionally specifying the polling interval in
+//! milliseconds with `-i` (`--interval`):
+//!
+//! ```console
+//! % humility ringbuf -mf
+//! humility: attached via ST-Link
+//! TASK             NDX LINE      GEN    COUNT PAYLOAD
+//! i2c_driver        14  211        3        1 Reset(I2C2)
+//! net               54  134       89        1 Read(IADR5, 0x4000)
+//! ...
+//! ```
+//!
 //! See the [`ringbuf`
 //! documentation](https://github.com/oxidecomputer/hubris/blob/master/lib/ringbuf/src/lib.rs) for more details.
 
@@ -58,9 +78,13 @@ use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::core::Core;
 use humility::hubris::*;
-use humility_cmd::doppel::{Ringbuf, StaticCell};
-use humility_cmd::reflect::{self, Format, Load, Value};
+use humility_cmd::doppel::{Ringbuf, RingbufEntry};
+use humility_cmd::reflect::Format;
+use humility_cmd::ringbuf::{self, RingbufVariable};
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[clap(name = "ringbuf", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -68,74 +92,152 @@ struct RingbufArgs {
     /// list variables
     #[clap(long, short)]
     list: bool,
+
+    /// merge all ring buffers onto a single timeline
+    #[clap(long, short, conflicts_with = "list")]
+    merge: bool,
+
+    /// continue to display new entries as they are added
+    #[clap(long, short, conflicts_with = "list")]
+    follow: bool,
+
+    /// interval between reads when following, in milliseconds
+    #[clap(
+        long, short, default_value = "1000", value_name = "ms",
+        requires = "follow", parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
     /// print only a single ringbuffer by substring of name
     #[clap(conflicts_with = "list")]
     name: Option<String>,
 }
 
+///
+/// When following, we track the generation and count of every slot in every
+/// ring buffer, displaying only those entries that have changed.
+///
+#[derive(Default)]
+struct Seen(HashMap<(usize, usize), (u16, u32)>);
+
+impl Seen {
+    fn update(
+        &mut self,
+        ndx: usize,
+        slot: usize,
+        entry: &RingbufEntry,
+    ) -> bool {
+        let val = (entry.generation, entry.count);
+        self.0.insert((ndx, slot), val) != Some(val)
+    }
+
+    fn changed(&self, ndx: usize, ringbuf: &Ringbuf) -> bool {
+        ringbuf::entries(ringbuf).iter().any(|(slot, entry)| {
+            self.0.get(&(ndx, *slot)) != Some(&(entry.generation, entry.count))
+        })
+    }
+}
+
+fn format_payload(
+    hubris: &HubrisArchive,
+    entry: &RingbufEntry,
+) -> Result<String> {
+    let fmt = HubrisPrintFormat { hex: true, ..HubrisPrintFormat::default() };
+    let mut dumped = vec![];
+    entry.payload.format(hubris, fmt, &mut dumped)?;
+    Ok(String::from_utf8(dumped)?)
+}
+
 fn ringbuf_dump(
     hubris: &HubrisArchive,
-    core: &mut dyn Core,
-    definition: &HubrisStruct,
-    ringbuf_var: &HubrisVariable,
+    ndx: usize,
+    ringbuf: &Ringbuf,
+    seen: &mut Seen,
+    header: bool,
 ) -> Result<()> {
-    let mut buf: Vec<u8> = vec![];
-    buf.resize_with(ringbuf_var.size, Default::default);
-
-    let _info = core.halt()?;
-    core.read_8(ringbuf_var.addr, buf.as_mut_slice())?;
-    core.run()?;
-
-    // There are two possible shapes of ringbufs, depending on the age of the
-    // firmware.
-    // - Raw Ringbuf that is not wrapped by anything.
-    // - Safe Ringbuf that is inside a StaticCell.
-    //
-    // Here we will attempt to handle them both -- first raw, then fallback.
-    let ringbuf_val: Value =
-        Value::Struct(reflect::load_struct(hubris, &buf, definition, 0)?);
-
-    let ringbuf: Ringbuf = Ringbuf::from_value(&ringbuf_val).or_else(|_e| {
-        let cell: StaticCell = StaticCell::from_value(&ringbuf_val)?;
-        Ringbuf::from_value(&cell.cell.value)
-    })?;
-
-    let ndx = if let Some(x) = ringbuf.last {
-        x as usize
-    } else {
+    if ringbuf.last.is_none() {
         return Ok(());
-    };
-
-    let fmt = HubrisPrintFormat { hex: true, ..HubrisPrintFormat::default() };
-
-    println!("{:>4} {:>4} {:>8} {:>8} PAYLOAD", "NDX", "LINE", "GEN", "COUNT",);
+    }
 
-    for i in 0..ringbuf.buffer.len() {
-        let slot = (ndx + i + 1) % ringbuf.buffer.len();
-        let entry = &ringbuf.buffer[slot];
+    if header {
+        println!(
+            "{:>4} {:>4} {:>8} {:>8} PAYLOAD",
+            "NDX", "LINE", "GEN", "COUNT",
+        );
+    }
 
-        if entry.generation == 0 {
+    for (slot, entry) in ringbuf::entries(ringbuf) {
+        if !seen.update(ndx, slot, entry) {
             continue;
         }
 
-        let mut dumped = vec![];
-        entry.payload.format(hubris, fmt, &mut dumped)?;
-        let dumped = String::from_utf8(dumped)?;
-
         println!(
             "{:4} {:4} {:8} {:8} {}",
-            slot, entry.line, entry.generation, entry.count, dumped
+            slot,
+            entry.line,
+            entry.generation,
+            entry.count,
+            format_payload(hubris, entry)?
         );
     }
 
     Ok(())
 }
 
-fn taskname<'a>(
-    hubris: &'a HubrisArchive,
-    variable: &'a HubrisVariable,
-) -> Result<&'a str> {
-    Ok(&hubris.lookup_module(HubrisTask::from(variable.goff))?.name)
+///
+/// Displays the entries of all ring buffers interleaved on one timeline.
+/// Ring buffer entries carry no timestamp, so entries are ordered by their
+/// generation and then by their position in their buffer; the ordering
+/// between entries in different buffers is therefore approximate.  Each
+/// ring buffer is accompanied by its index in the list of all ring buffers
+/// (and not merely those that could be read), which keys what we have seen.
+///
+fn ringbuf_merge(
+    hubris: &HubrisArchive,
+    ringbufs: &[(usize, RingbufVariable, Ringbuf)],
+    seen: &mut Seen,
+    header: bool,
+) -> Result<()> {
+    let mut rows = vec![];
+
+    for (ndx, v, ringbuf) in ringbufs {
+        for (seq, (slot, entry)) in
+            ringbuf::entries(ringbuf).into_iter().enumerate()
+        {
+            if seen.update(*ndx, slot, entry) {
+                rows.push((
+                    (entry.generation, seq),
+                    *ndx,
+                    &v.task,
+                    slot,
+                    entry,
+                ));
+            }
+        }
+    }
+
+    rows.sort_by_key(|row| (row.0, row.1));
+
+    if header {
+        println!(
+            "{:15} {:>4} {:>4} {:>8} {:>8} PAYLOAD",
+            "TASK", "NDX", "LINE", "GEN", "COUNT",
+        );
+    }
+
+    for (_, _, task, slot, entry) in rows {
+        println!(
+            "{:15} {:4} {:4} {:8} {:8} {}",
+            task,
+            slot,
+            entry.line,
+            entry.generation,
+            entry.count,
+            format_payload(hubris, entry)?
+        );
+    }
+
+    Ok(())
 }
 
 // this allow is meant for the header println! in the body but you cannot apply
@@ -149,18 +251,18 @@ fn ringbuf(
 ) -> Result<()> {
     let subargs = RingbufArgs::try_parse_from(subargs)?;
 
+    if subargs.follow && core.is_dump() {
+        bail!("cannot follow ring buffers in a dump");
+    }
+
     let mut ringbufs = vec![];
 
-    for v in hubris.qualified_variables() {
+    for v in ringbuf::ringbufs(hubris)? {
         if let Some(ref name) = subargs.name {
-            if v.0.eq(name)
-                || (v.0.ends_with("RINGBUF")
-                    && (v.0.contains(name)
-                        || taskname(hubris, v.1)?.contains(name)))
-            {
+            if v.name.contains(name) || v.task.contains(name) {
                 ringbufs.push(v);
             }
-        } else if v.0.ends_with("RINGBUF") {
+        } else {
             ringbufs.push(v);
         }
     }
@@ -173,35 +275,87 @@ fn ringbuf(
         }
     }
 
-    ringbufs.sort();
-
     if subargs.list {
         println!("{:18} {:<30} {:<10} {}", "MODULE", "BUFFER", "ADDR", "SIZE");
 
         for v in ringbufs {
-            let t = taskname(hubris, v.1)?;
-
-            println!("{:18} {:<30} 0x{:08x} {:<}", t, v.0, v.1.addr, v.1.size);
+            println!(
+                "{:18} {:<30} 0x{:08x} {:<}",
+                v.task, v.name, v.variable.addr, v.variable.size
+            );
         }
 
         return Ok(());
     }
 
-    for v in ringbufs {
-        // Try not to use `?` here, because it causes one bad ringbuf to make
-        // them all unavailable.
-        println!(
-            "humility: ring buffer {} in {}:",
-            v.0,
-            taskname(hubris, v.1).unwrap_or("???")
-        );
-        if let Ok(def) = hubris.lookup_struct(v.1.goff) {
-            if let Err(e) = ringbuf_dump(hubris, core, def, v.1) {
-                humility::msg!("ringbuf dump failed: {}", e);
+    let mut seen = Seen::default();
+    let mut first = true;
+
+    loop {
+        //
+        // We halt once to read all ring buffers, giving as consistent a
+        // view across them as we can.
+        //
+        core.halt()?;
+
+        let results = ringbufs
+            .iter()
+            .map(|v| (v, v.read(hubris, core)))
+            .collect::<Vec<_>>();
+
+        core.run()?;
+
+        if subargs.merge {
+            let mut merged = vec![];
+
+            for (ndx, (v, result)) in results.into_iter().enumerate() {
+                match result {
+                    Ok(ringbuf) => merged.push((ndx, *v, ringbuf)),
+                    Err(e) => {
+                        humility::msg!("ringbuf {} dump failed: {}", v.name, e);
+                    }
+                }
             }
+
+            ringbuf_merge(hubris, &merged, &mut seen, first)?;
         } else {
-            humility::msg!("could not look up type: {:?}", v.1.goff);
+            for (ndx, (v, result)) in results.iter().enumerate() {
+                //
+                // When following, we only indicate a ring buffer if it has
+                // new entries.
+                //
+                if !first {
+                    match result {
+                        Ok(ringbuf) if seen.changed(ndx, ringbuf) => {}
+                        _ => continue,
+                    }
+                }
+
+                // Try not to use `?` here, because it causes one bad ringbuf
+                // to make them all unavailable.
+                println!("humility: ring buffer {} in {}:", v.name, v.task);
+
+                match result {
+                    Ok(ringbuf) => {
+                        if let Err(e) =
+                            ringbuf_dump(hubris, ndx, ringbuf, &mut seen, first)
+                        {
+                            humility::msg!("ringbuf dump failed: {}", e);
+                        }
+                    }
+                    Err(e) => {
+                        humility::msg!("ringbuf dump failed: {}", e);
+                    }
+                }
+            }
+        }
+
+        if !subargs.follow {
+            break;
         }
+
+        first = false;
+        thread::sleep(Duration::from_millis(subargs.interval));
     }
 
     Ok(())
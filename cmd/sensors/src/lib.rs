@@ -17,19 +17,206 @@
 //! either device), but if multiple kinds of specifications are present, they
 //! serve as a logical AND (e.g., `-t thermal -d raa229618,tmp117` would yield
 //! all thermal sensors from either device).
+//!
+//! To emit sensor values (or, with `-l`, the list of sensors) as JSON or
+//! CSV, use the global `--format` option; as JSON, each sample is emitted
+//! as an object keyed by sensor name.
+//!
+//! To correct sensor values against reference instrumentation, a
+//! calibration file can be specified with the global `--calibration`
+//! option (or `HUMILITY_CALIBRATION`, or as the `calibration` of a target
+//! in `humility.toml`).  The file specifies a linear correction (a `gain`,
+//! which defaults to 1, and an `offset`, which defaults to 0) for each
+//! sensor to be corrected, by name -- and by kind, if the name alone is
+//! ambiguous:
+//!
+//! ```toml
+//! [[sensor]]
+//! name = "Southwest"
+//! offset = -0.75
+//!
+//! [[sensor]]
+//! name = "V12_SYS_A2"
+//! kind = "current"
+//! gain = 1.021
+//! ```
+//!
+//! Calibrated values are marked with `*` in tables and summaries (with a
+//! message on stderr indicating the calibration file); as JSON or CSV,
+//! values are emitted as calibrated.
+//!
+//! For thermal and power sign-off, `-R` (`--report`) samples the thermal
+//! and power sensors (subject to any constraints) for the specified number
+//! of seconds -- e.g., over a load test -- at the interval specified by `-i`
+//! (`--interval`, in milliseconds; defaults to 1000).  Voltage, current and
+//! power sensors that share a name on the same device constitute a rail, the
+//! power of which is computed as the product of its voltage and current (or,
+//! lacking either, taken from its power sensor).  Each sample is emitted as
+//! a row (subject to `--format`) of each temperature and the power of each
+//! rail, followed by a summary of each temperature (including its rise over
+//! the interval and its correlation with power) and each rail.  Rails that
+//! supply input power can be named with `--input`, in which case temperature
+//! is correlated with input power and efficiency is estimated as the ratio
+//! of the power of the other rails to input power.  When samples are
+//! emitted as JSON or CSV, the summary is emitted on stderr:
+//!
+//! ```console
+//! % humility sensors -R 60 -d tps546b24a,raa229618,tmp117 --input V12_SYS_A2
+//! humility: attached via ST-Link V3
+//! humility: sampling 3 thermal sensors and 4 rails for 60s
+//!     TIME   TEMP.Southwest ... POWER.V12_SYS_A2
+//!     0.00            31.25 ...            48.23
+//! ...
+//! Report over 60.1s (60 samples); correlation is with input power:
+//!
+//! SENSOR                        MIN     MEAN      MAX     RISE   CORR
+//! Southwest                   31.25    33.61    35.12     3.87   0.94
+//! ...
+//!
+//! RAIL                        VOLTS     AMPS    WATTS      MAX MEASURED
+//! V12_SYS_A2 (input)          12.03     4.52    54.38    61.20    54.10
+//! VDD_VCORE                    1.10    41.30    45.43    52.17        -
+//! ...
+//!
+//! total rail power: 49.92 W mean, 56.31 W max
+//! input power: 54.38 W mean, 61.20 W max
+//! estimated efficiency: 91.8%
+//! ```
+//!
+//! For collection by automation (e.g., continuous integration),
+//! `--snapshot-metrics` takes exactly one sample of the selected sensors and
+//! writes it to the specified file as OpenMetrics text, with a gauge family
+//! for each kind of sensor and each sensor labeled by its name and device.
+//! If any selected sensor fails to read, the command fails (and the file is
+//! not written):
+//!
+//! ```console
+//! % humility sensors -t temp --snapshot-metrics sensors.om
+//! humility: attached via ST-Link V3
+//! humility: wrote 9 sensors to sensors.om
+//! % head -4 sensors.om
+//! # TYPE hubris_sensor_temperature_celsius gauge
+//! # UNIT hubris_sensor_temperature_celsius celsius
+//! # HELP hubris_sensor_temperature_celsius Hubris temperature sensor
+//! hubris_sensor_temperature_celsius{sensor="Southwest",device="tmp117",controller="2",address="0x48",archive="gimlet"} 31.25
+//! ```
+//!
+//! When sampling repeatedly (with `-s` or `-R`), resets of the target can be
+//! detected with `--heartbeat`:  the kernel's system time is read before
+//! each sample, and if it has gone backwards, the reset is noted among the
+//! samples (as a line of its own in a table, as a `#` comment in CSV, or as
+//! an object with a `note` field in JSON).
+//!
+//! When sampling repeatedly, a sample that fails (e.g., because of a
+//! transient error talking to the target) is retried once by default.  This
+//! can be changed with `--on-error`, which takes `fail` (fail on the first
+//! error), `retry:N` (retry immediately up to `N` times), `backoff:N` (retry
+//! up to `N` times, waiting increasingly long between attempts) or
+//! `continue` (note the missed sample among the samples and carry on) --
+//! the last of which is appropriate for long monitoring sessions.
+//!
+//! To catch transient events, alert rules can be specified with `--alert`
+//! (which may be repeated).  A rule names a sensor -- optionally qualified
+//! by its kind, e.g. `current.V12_SYS_A2`, or `*` for all selected sensors
+//! -- and a threshold for its value, for its rate of change in units per
+//! second (`:rate`), or for the magnitude of its change between samples
+//! (`:delta`).  When a rule's condition comes to hold, an alert is noted
+//! among the samples (in the same manner as a reset); a report (`-R`)
+//! additionally summarizes the number of alerts raised:
+//!
+//! ```console
+//! % humility sensors -s -t temp,current --alert "Southwest:rate>2" \
+//!     --alert "current.V12_SYS_A2:delta>5" --alert "*>95"
+//! humility: attached via ST-Link V3
+//! ...
+//! *** alert Southwest:rate>2/s: temp.Southwest is 41.250, changing at 2.875/s ***
+//! ...
+//! ```
+//!
+//! Sensors are displayed in the order in which they are defined; to order
+//! them by name, kind or device, use `--sort`.  A selection of sensors (by
+//! `-t`, `-d` and `-n`), along with their order, the output format and any
+//! alert rules, can be saved as a named view with `--save-view`, which
+//! appends it to `humility.toml` (creating
+//! `~/.config/humility/humility.toml` if there is no configuration file).
+//! A view is recalled with `--view`, with options given on the command line
+//! taking precedence over those of the view:
+//!
+//! ```console
+//! % humility sensors -t temp -d tmp117,tmp451 --sort name --save-view thermal-debug -s
+//! humility: attached via ST-Link V3
+//! humility: saved view "thermal-debug" to /home/user/.config/humility/humility.toml
+//! ...
+//! % humility sensors --view thermal-debug -s
+//! ```
+//!
+//! Views are saved as tables of the form `[views.sensors.NAME]`, and so can
+//! be shared by way of a `humility.toml` checked in alongside a project:
+//!
+//! ```toml
+//! [views.sensors.thermal-debug]
+//! types = ["temp"]
+//! devices = ["tmp117", "tmp451"]
+//! sort = "name"
+//! ```
+//!
+//! To check the sensors defined in the archive's application TOML, use
+//! `--lint`.  This flags sensors whose names are duplicated (often the
+//! result of devices lacking names, as their sensors are named after the
+//! part), sensors on devices that don't exist, and sensors of a kind that
+//! their device can't provide; devices that provide sensors but have none
+//! defined are also noted.  If any problems are found, the command fails:
+//!
+//! ```console
+//! % humility sensors --lint
+//! humility: attached via ST-Link V3
+//! PROBLEM        DETAIL
+//! duplicate name temp sensor tmp117 is defined 2 times (on tmp117 at I2C2 0x48, tmp117 at I2C2 0x49); are devices missing names?
+//! kind mismatch  voltage sensor T6 is on tmp451 at I2C2 0x4c, which has no voltage sensors
+//! unreferenced   max31790 at I2C4 0x20 (Fan controller) provides sensors, but none are defined
+//! humility sensors failed: 2 problems found
+//! ```
+//!
+//! Before sampling repeatedly, each selected sensor is read once.  If any
+//! cannot be read, their devices are validated (if there is a `validate`
+//! task) and the reason that their values will be missing is displayed --
+//! e.g., that the device is missing from the bus, that it failed
+//! validation, or that it is present but lacks a reading (suggesting that
+//! there is no driver for it):
+//!
+//! ```console
+//! % humility sensors -s -t temp
+//! humility: attached via ST-Link V3
+//! humility: 2 of 9 sensors could not be read:
+//! humility:   tmp117 at I2C2 port F 0x48: Southwest temperature (NoReading)
+//! humility:     device is absent (it is removable; is it installed?)
+//! humility:   tmp451 at I2C2 port B 0x4c: T6 temperature (NoReading)
+//! humility:     device is validated but has no reading; is a driver for tmp451 present?
+//! ...
+//! ```
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ArgEnum;
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use hif::*;
 use humility::core::Core;
 use humility::hubris::*;
+use humility_cmd::alert::Alerts;
+use humility_cmd::config;
 use humility_cmd::hiffy::*;
 use humility_cmd::idol;
+use humility_cmd::kernel::Heartbeat;
+use humility_cmd::output::{Cell, Column, OutputFormat, Table};
+use humility_cmd::retry::RetryPolicy;
+use humility_cmd::style::Severity;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
-use std::collections::HashSet;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Parser, Debug)]
 #[clap(name = "sensors", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -45,6 +232,10 @@ struct SensorsArgs {
     #[clap(long, short)]
     list: bool,
 
+    /// check the sensors in the manifest for problems
+    #[clap(long, conflicts_with_all = &["list", "sleep", "report"])]
+    lint: bool,
+
     /// print sensors every second
     #[clap(long, short, conflicts_with = "list")]
     sleep: bool,
@@ -70,30 +261,410 @@ struct SensorsArgs {
         use_value_delimiter = true
     )]
     named: Option<Vec<String>>,
+
+    /// sample thermal and power sensors for the specified number of seconds
+    /// and report on how they correlate
+    #[clap(
+        long, short = 'R', value_name = "seconds",
+        conflicts_with_all = &["list", "sleep"],
+        parse(try_from_str = parse_int::parse)
+    )]
+    report: Option<u64>,
+
+    /// interval between samples when reporting
+    #[clap(
+        long, short, value_name = "ms", default_value = "1000",
+        parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
+    /// when reporting, rails that supply input power (for efficiency)
+    #[clap(
+        long,
+        value_name = "rail",
+        requires = "report",
+        use_value_delimiter = true
+    )]
+    input: Option<Vec<String>>,
+
+    /// take one sample of the selected sensors and write it to the specified
+    /// file as OpenMetrics text
+    #[clap(
+        long,
+        value_name = "file",
+        conflicts_with_all = &["list", "sleep", "report"]
+    )]
+    snapshot_metrics: Option<String>,
+
+    /// when sampling repeatedly, check for and annotate resets of the target
+    #[clap(long)]
+    heartbeat: bool,
+
+    /// when sampling repeatedly, what to do when a sample fails:  fail,
+    /// retry[:N], backoff[:N] or continue
+    #[clap(long, value_name = "policy", default_value = "retry:1")]
+    on_error: String,
+
+    /// raise an alert when a rule (e.g., "Southwest:rate>2") is violated
+    #[clap(
+        long,
+        value_name = "rule",
+        multiple_occurrences = true,
+        conflicts_with_all = &["list", "snapshot-metrics"]
+    )]
+    alert: Vec<String>,
+
+    /// order sensors by name, kind or device
+    #[clap(long, value_name = "name|kind|device")]
+    sort: Option<String>,
+
+    /// use the sensors, order and format of the named view
+    #[clap(long, value_name = "view")]
+    view: Option<String>,
+
+    /// save the sensors, order and format as a named view
+    #[clap(long, value_name = "view")]
+    save_view: Option<String>,
+}
+
+///
+/// A saved selection of sensors, their order, and the format in which they
+/// are displayed.
+///
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SensorsView {
+    types: Option<Vec<String>>,
+    devices: Option<Vec<String>>,
+    named: Option<Vec<String>>,
+    sort: Option<String>,
+    format: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    alert: Vec<String>,
+}
+
+///
+/// Applies the named view to our arguments (and format); the view's settings
+/// apply only to those that have not been specified.
+///
+fn apply_view(
+    name: &str,
+    subargs: &mut SensorsArgs,
+    format: &mut OutputFormat,
+) -> Result<()> {
+    let view: SensorsView = config::view("sensors", name)?;
+
+    if subargs.types.is_none() {
+        subargs.types = view.types;
+    }
+
+    if subargs.devices.is_none() {
+        subargs.devices = view.devices;
+    }
+
+    if subargs.named.is_none() {
+        subargs.named = view.named;
+    }
+
+    if subargs.sort.is_none() {
+        subargs.sort = view.sort;
+    }
+
+    if subargs.alert.is_empty() {
+        subargs.alert = view.alert;
+    }
+
+    if let Some(f) = view.format {
+        if *format == OutputFormat::Table {
+            *format = OutputFormat::from_str(&f, false).map_err(|_| {
+                anyhow!("view \"{}\" has invalid format \"{}\"", name, f)
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+fn save_view(
+    name: &str,
+    subargs: &SensorsArgs,
+    format: OutputFormat,
+) -> Result<()> {
+    let view = SensorsView {
+        types: subargs.types.clone(),
+        devices: subargs.devices.clone(),
+        named: subargs.named.clone(),
+        sort: subargs.sort.clone(),
+        format: match format {
+            OutputFormat::Table => None,
+            OutputFormat::Json => Some("json".to_string()),
+            OutputFormat::Csv => Some("csv".to_string()),
+        },
+        alert: subargs.alert.clone(),
+    };
+
+    let path = config::save_view("sensors", name, &view)?;
+    humility::msg!("saved view \"{}\" to {}", name, path.display());
+
+    Ok(())
+}
+
+//
+// Orders the specified sensors by name, kind or device; sensors that are
+// otherwise equal remain in the order in which they are defined.
+//
+fn sort(hubris: &HubrisArchive, sensors: &mut [usize], by: &str) -> Result<()> {
+    let all = &hubris.manifest.sensors;
+    let devices = &hubris.manifest.i2c_devices;
+
+    match by {
+        "name" => sensors.sort_by(|a, b| all[*a].name.cmp(&all[*b].name)),
+        "kind" => sensors.sort_by(|a, b| {
+            all[*a].kind.to_string().cmp(all[*b].kind.to_string())
+        }),
+        "device" => sensors.sort_by(|a, b| {
+            devices[all[*a].device].device.cmp(&devices[all[*b].device].device)
+        }),
+        _ => bail!("cannot sort by \"{}\"; expected name, kind or device", by),
+    }
+
+    Ok(())
 }
 
 fn list(
+    hubris: &HubrisArchive,
+    format: OutputFormat,
+    sensors: &[usize],
+) -> Result<()> {
+    let mut table = Table::new(
+        format,
+        vec![
+            Column::new("id", 2).right(),
+            Column::new("kind", 7),
+            Column::new("controller", 2).heading("C").right(),
+            Column::new("port", 2).heading("P"),
+            Column::new("mux", 3),
+            Column::new("addr", 4),
+            Column::new("device", 13),
+            Column::new("name", 0),
+        ],
+    );
+
+    for &ndx in sensors {
+        let s = &hubris.manifest.sensors[ndx];
+        let device = &hubris.manifest.i2c_devices[s.device];
+
+        let mux = match (device.mux, device.segment) {
+            (Some(m), Some(s)) => format!("{}:{}", m, s),
+            (None, None) => "-".to_string(),
+            (_, _) => "?:?".to_string(),
+        };
+
+        table.row(vec![
+            ndx.into(),
+            s.kind.to_string().into(),
+            device.controller.into(),
+            device.port.name.as_str().into(),
+            mux.into(),
+            Cell::Hex(device.address.into(), 2),
+            device.device.as_str().into(),
+            s.name.as_str().into(),
+        ])?;
+    }
+
+    Ok(())
+}
+
+///
+/// Parts and the kinds of sensors that they provide.  This isn't exhaustive:
+/// it's used only to flag sensors of a kind that a part can't provide, and
+/// parts that provide sensors but have none defined.
+///
+const PART_KINDS: &[(&str, &[HubrisSensorKind])] = {
+    use HubrisSensorKind::*;
+
+    &[
+        ("adm1272", &[Temperature, Voltage, Current, Power]),
+        ("bmr491", &[Temperature, Voltage, Current, Power]),
+        ("ina219", &[Voltage, Current, Power]),
+        ("ina226", &[Voltage, Current, Power]),
+        ("isl68224", &[Temperature, Voltage, Current, Power]),
+        ("lm5066", &[Temperature, Voltage, Current, Power]),
+        ("max31790", &[Speed]),
+        ("max5970", &[Voltage, Current]),
+        ("mcp9808", &[Temperature]),
+        ("mwocp68", &[Temperature, Voltage, Current, Power, Speed]),
+        ("nvme_bmc", &[Temperature]),
+        ("pct2075", &[Temperature]),
+        ("raa229618", &[Temperature, Voltage, Current, Power]),
+        ("sbtsi", &[Temperature]),
+        ("tmp117", &[Temperature]),
+        ("tmp421", &[Temperature]),
+        ("tmp451", &[Temperature]),
+        ("tps546b24a", &[Temperature, Voltage, Current, Power]),
+        ("tse2004av", &[Temperature]),
+    ]
+};
+
+struct Lint {
+    severity: Severity,
+    problem: &'static str,
+    detail: String,
+}
+
+///
+/// Checks the sensors in the manifest for duplicated names, sensors on
+/// absent devices, sensors of a kind that their device can't provide, and
+/// devices that provide sensors but have none defined.
+///
+fn lint(hubris: &HubrisArchive, format: OutputFormat) -> Result<()> {
+    let devices = &hubris.manifest.i2c_devices;
+    let sensors = &hubris.manifest.sensors;
+    let mut lints = vec![];
+
+    let describe = |ndx: usize| {
+        let d = &devices[ndx];
+        format!("{} at I2C{} 0x{:02x}", d.device, d.controller, d.address)
+    };
+
+    let mut defined: IndexMap<(&str, HubrisSensorKind), Vec<usize>> =
+        IndexMap::new();
+
+    for s in sensors.iter() {
+        if s.device >= devices.len() {
+            lints.push(Lint {
+                severity: Severity::Error,
+                problem: "absent device",
+                detail: format!(
+                    "{} sensor {} is on device {}, but there are only {} \
+                    devices",
+                    s.kind.to_string(),
+                    s.name,
+                    s.device,
+                    devices.len()
+                ),
+            });
+            continue;
+        }
+
+        defined.entry((s.name.as_str(), s.kind)).or_default().push(s.device);
+
+        let part = &devices[s.device].device;
+
+        if let Some((_, kinds)) = PART_KINDS.iter().find(|(p, _)| *p == *part) {
+            if !kinds.contains(&s.kind) {
+                lints.push(Lint {
+                    severity: Severity::Warning,
+                    problem: "kind mismatch",
+                    detail: format!(
+                        "{} sensor {} is on {}, which has no {} sensors",
+                        s.kind.to_string(),
+                        s.name,
+                        describe(s.device),
+                        s.kind.to_string()
+                    ),
+                });
+            }
+        }
+    }
+
+    for ((name, kind), on) in &defined {
+        if on.len() < 2 {
+            continue;
+        }
+
+        //
+        // Sensors on devices without names are named after the part, so
+        // duplicates are often the result of a missing name.
+        //
+        let unnamed = on.iter().any(|&d| devices[d].name.is_none());
+
+        lints.push(Lint {
+            severity: Severity::Error,
+            problem: "duplicate name",
+            detail: format!(
+                "{} sensor {} is defined {} times (on {}){}",
+                kind.to_string(),
+                name,
+                on.len(),
+                on.iter().map(|&d| describe(d)).collect::<Vec<_>>().join(", "),
+                if unnamed { "; are devices missing names?" } else { "" }
+            ),
+        });
+    }
+
+    for (ndx, d) in devices.iter().enumerate() {
+        let provides = PART_KINDS.iter().any(|(p, _)| *p == d.device)
+            || matches!(d.class, HubrisI2cDeviceClass::Pmbus { .. });
+
+        if provides && !sensors.iter().any(|s| s.device == ndx) {
+            lints.push(Lint {
+                severity: Severity::Info,
+                problem: "unreferenced",
+                detail: format!(
+                    "{} ({}) provides sensors, but none are defined",
+                    describe(ndx),
+                    d.description
+                ),
+            });
+        }
+    }
+
+    if !lints.is_empty() {
+        let mut table = Table::new(
+            format,
+            vec![Column::new("problem", 14), Column::new("detail", 0)],
+        );
+
+        for l in &lints {
+            table.row(vec![
+                Cell::from(l.problem).styled(l.severity),
+                l.detail.as_str().into(),
+            ])?;
+        }
+    }
+
+    let problems = lints
+        .iter()
+        .filter(|l| matches!(l.severity, Severity::Error | Severity::Warning))
+        .count();
+
+    match problems {
+        0 => {
+            humility::msg!(
+                "{} sensors on {} devices checked",
+                sensors.len(),
+                devices.len()
+            );
+            Ok(())
+        }
+        1 => bail!("1 problem found"),
+        n => bail!("{} problems found", n),
+    }
+}
+
+//
+// Returns the indices of the sensors that match the specified constraints.
+//
+fn selected(
     hubris: &HubrisArchive,
     types: &Option<HashSet<HubrisSensorKind>>,
     devices: &Option<HashSet<&String>>,
     named: &Option<HashSet<&String>>,
-) -> Result<()> {
-    println!(
-        "{:2} {:<7} {:2} {:2} {:3} {:4} {:13} {:4}",
-        "ID", "KIND", "C", "P", "MUX", "ADDR", "DEVICE", "NAME"
-    );
+) -> Vec<usize> {
+    let mut rval = vec![];
 
-    for (ndx, s) in hubris.manifest.sensors.iter().enumerate() {
+    for (i, s) in hubris.manifest.sensors.iter().enumerate() {
         if let Some(types) = types {
             if types.get(&s.kind).is_none() {
                 continue;
             }
         }
 
-        let device = &hubris.manifest.i2c_devices[s.device];
-
         if let Some(devices) = devices {
-            if devices.get(&device.device).is_none() {
+            let d = &hubris.manifest.i2c_devices[s.device];
+
+            if devices.get(&d.device).is_none() {
                 continue;
             }
         }
@@ -104,37 +675,20 @@ fn list(
             }
         }
 
-        let mux = match (device.mux, device.segment) {
-            (Some(m), Some(s)) => format!("{}:{}", m, s),
-            (None, None) => "-".to_string(),
-            (_, _) => "?:?".to_string(),
-        };
-
-        println!(
-            "{:2} {:7} {:2} {:2} {:3} 0x{:02x} {:13} {:<1}",
-            ndx,
-            s.kind.to_string(),
-            device.controller,
-            device.port.name,
-            mux,
-            device.address,
-            device.device,
-            s.name,
-        );
+        rval.push(i);
     }
 
-    Ok(())
+    rval
 }
 
-fn print(
+//
+// Returns the HIF program to read the specified sensors.
+//
+fn sensor_ops(
     hubris: &HubrisArchive,
-    core: &mut dyn Core,
-    subargs: &SensorsArgs,
     context: &mut HiffyContext,
-    types: &Option<HashSet<HubrisSensorKind>>,
-    devices: &Option<HashSet<&String>>,
-    named: &Option<HashSet<&String>>,
-) -> Result<()> {
+    sensors: &[usize],
+) -> Result<Vec<Op>> {
     let mut ops = vec![];
     let funcs = context.functions()?;
     let op = idol::IdolOperation::new(hubris, "Sensor", "get", None)
@@ -154,78 +708,1030 @@ fn print(
         bail!("no sensors found");
     }
 
-    let mut rvals = vec![];
+    let mut payload = op.template(&[("id", idol::IdolArgument::Scalar(0))])?;
 
-    for (i, s) in hubris.manifest.sensors.iter().enumerate() {
-        if let Some(types) = types {
-            if types.get(&s.kind).is_none() {
-                continue;
+    for i in sensors {
+        payload.set("id", *i as u64)?;
+        context.idol_call_ops(&funcs, &op, payload.as_slice(), &mut ops)?;
+    }
+
+    ops.push(Op::Done);
+
+    Ok(ops)
+}
+
+//
+// A linear correction for a sensor:  a calibrated value is the raw value
+// multiplied by the gain, plus the offset.
+//
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Correction {
+    #[serde(default = "Correction::unity")]
+    gain: f64,
+    #[serde(default)]
+    offset: f64,
+}
+
+impl Correction {
+    fn unity() -> f64 {
+        1.0
+    }
+
+    fn apply(&self, val: f32) -> f32 {
+        (self.gain * val as f64 + self.offset) as f32
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SensorCalibration {
+    name: String,
+    kind: Option<String>,
+    #[serde(flatten)]
+    correction: Correction,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CalibrationFile {
+    #[serde(default)]
+    sensor: Vec<SensorCalibration>,
+}
+
+//
+// Corrections to apply, by the index of the sensor in the manifest.
+//
+type Calibration = HashMap<usize, Correction>;
+
+fn calibration(hubris: &HubrisArchive, filename: &str) -> Result<Calibration> {
+    let contents = fs::read_to_string(filename)
+        .with_context(|| format!("failed to read {}", filename))?;
+
+    let file: CalibrationFile = toml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", filename))?;
+
+    let mut rval = HashMap::new();
+
+    for cal in &file.sensor {
+        let kind = match &cal.kind {
+            Some(kind) => match HubrisSensorKind::from_string(kind) {
+                Some(kind) => Some(kind),
+                None => {
+                    bail!("{}: unrecognized sensor kind \"{}\"", filename, kind)
+                }
+            },
+            None => None,
+        };
+
+        let found = hubris
+            .manifest
+            .sensors
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.name == cal.name)
+            .filter(|(_, s)| kind.map_or(true, |k| s.kind == k))
+            .map(|(ndx, _)| ndx)
+            .collect::<Vec<_>>();
+
+        match found.len() {
+            0 => bail!("{}: unrecognized sensor {}", filename, cal.name),
+            1 => {
+                if rval.insert(found[0], cal.correction).is_some() {
+                    bail!("{}: sensor {} calibrated twice", filename, cal.name);
+                }
             }
+            _ => bail!(
+                "{}: sensor {} is ambiguous; specify its kind",
+                filename,
+                cal.name
+            ),
         }
+    }
 
-        if let Some(devices) = devices {
-            let d = &hubris.manifest.i2c_devices[s.device];
+    Ok(rval)
+}
 
-            if devices.get(&d.device).is_none() {
-                continue;
+//
+// Reads the sensors with the specified program, returning `None` for any
+// sensor that could not be read and applying any calibration.
+//
+fn read(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    ops: &[Op],
+    sensors: &[usize],
+    calibration: &Calibration,
+) -> Result<Vec<Option<f32>>> {
+    let results = context.run(core, ops, None)?;
+    let mut rval = vec![];
+
+    for (r, ndx) in results.iter().zip(sensors.iter()) {
+        if let Ok(val) = r {
+            let val = f32::from_le_bytes(val[0..4].try_into()?);
+
+            rval.push(Some(match calibration.get(ndx) {
+                Some(correction) => correction.apply(val),
+                None => val,
+            }));
+        } else {
+            rval.push(None);
+        }
+    }
+
+    Ok(rval)
+}
+
+//
+// Explains the result of validating a device whose sensors could not be
+// read.
+//
+fn diagnosis(
+    op: &idol::IdolOperation,
+    device: &HubrisI2cDevice,
+    ok: &HubrisEnum,
+    result: &Result<Vec<u8>, u32>,
+) -> String {
+    match result {
+        Ok(val) => match ok.lookup_variant(val[0].into()) {
+            Some(variant) => format!(
+                "device is {} but has no reading; is a driver for {} present?",
+                variant.name.to_lowercase(),
+                device.device
+            ),
+            None => format!("unexpected validation result {:x?}", val),
+        },
+        Err(e) => {
+            match op.error.and_then(|err| err.lookup_variant(*e as u64)) {
+                Some(variant) => match variant.name.as_str() {
+                    "NotPresent" if device.removable => {
+                        "device is absent (it is removable; is it installed?)"
+                            .to_string()
+                    }
+                    "NotPresent" => {
+                        "device is missing from the bus".to_string()
+                    }
+                    "BadValidation" => {
+                        "device failed validation; is the wrong device at this \
+                    address?"
+                            .to_string()
+                    }
+                    "DeviceTimeout" => "device timed out".to_string(),
+                    "DeviceError" => "device returned an error".to_string(),
+                    name => format!("validation failed: {}", name),
+                },
+                None => format!("validation failed: Err(0x{:x})", e),
             }
         }
+    }
+}
 
-        if let Some(named) = named {
-            if named.get(&s.name).is_none() {
-                continue;
+//
+// Before sampling repeatedly, reads each selected sensor once.  For any
+// sensors that can't be read, the devices are validated (if a validate task
+// is present) to explain why their values will be missing.
+//
+fn precheck(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    sensors: &[usize],
+) -> Result<()> {
+    let get = idol::IdolOperation::new(hubris, "Sensor", "get", None)?;
+    let ops = sensor_ops(hubris, context, sensors)?;
+    let results = context.run(core, &ops, None)?;
+
+    let failed = results
+        .iter()
+        .zip(sensors.iter())
+        .filter_map(|(r, ndx)| match r {
+            Ok(_) => None,
+            Err(e) => Some((
+                *ndx,
+                match get.error.and_then(|err| err.lookup_variant(*e as u64)) {
+                    Some(variant) => variant.name.to_string(),
+                    None => format!("Err(0x{:x})", e),
+                },
+            )),
+        })
+        .collect::<Vec<_>>();
+
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    let all = &hubris.manifest.sensors;
+    let mut devices: Vec<usize> =
+        failed.iter().map(|(i, _)| all[*i].device).collect();
+    devices.sort_unstable();
+    devices.dedup();
+
+    let mut diagnoses = HashMap::new();
+
+    if let Ok(op) =
+        idol::IdolOperation::new(hubris, "Validate", "validate_i2c", None)
+    {
+        let funcs = context.functions()?;
+        let ok = hubris.lookup_enum(op.ok)?;
+        let mut payload =
+            op.template(&[("index", idol::IdolArgument::Scalar(0))])?;
+        let mut ops = vec![];
+
+        for d in &devices {
+            payload.set("index", *d as u64)?;
+            context.idol_call_ops(&funcs, &op, payload.as_slice(), &mut ops)?;
+        }
+
+        ops.push(Op::Done);
+
+        let results = context.run(core, &ops, None)?;
+
+        for (d, r) in devices.iter().zip(results.iter()) {
+            let device = &hubris.manifest.i2c_devices[*d];
+            diagnoses.insert(*d, diagnosis(&op, device, ok, r));
+        }
+    }
+
+    humility::msg!(
+        "{} of {} sensors could not be read:",
+        failed.len(),
+        sensors.len()
+    );
+
+    for d in &devices {
+        let device = &hubris.manifest.i2c_devices[*d];
+
+        let names = failed
+            .iter()
+            .filter(|(i, _)| all[*i].device == *d)
+            .map(|(i, err)| {
+                format!("{} {} ({})", all[*i].name, all[*i].kind, err)
+            })
+            .collect::<Vec<_>>();
+
+        humility::msg!(
+            "  {} at I2C{} port {} 0x{:02x}: {}",
+            device.device,
+            device.controller,
+            device.port.name,
+            device.address,
+            names.join(", ")
+        );
+
+        humility::msg!(
+            "    {}",
+            match diagnoses.get(d) {
+                Some(diagnosis) => diagnosis.as_str(),
+                None => "no validate task; cannot check device",
             }
+        );
+    }
+
+    Ok(())
+}
+
+//
+// If we have been asked to check for resets and the target has reset, notes
+// it among the samples (and discards the samples on which any alerts are
+// based).
+//
+fn heartbeat(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &SensorsArgs,
+    beat: &mut Heartbeat,
+    alerts: &mut Alerts,
+    table: &mut Table,
+) -> Result<()> {
+    if subargs.heartbeat {
+        if let Some(reset) = beat.read(hubris, core)? {
+            table.note(&reset.to_string())?;
+            alerts.reset();
         }
+    }
 
-        rvals.push(s);
+    Ok(())
+}
 
-        let payload =
-            op.payload(&[("id", idol::IdolArgument::Scalar(i as u64))])?;
-        context.idol_call_ops(&funcs, &op, &payload, &mut ops)?;
+//
+// Returns the name of the series for a sensor, as named by alert rules:
+// the sensor name qualified by its kind (e.g., "current.V12_SYS_A2").
+//
+fn series(sensor: &HubrisSensor) -> String {
+    format!("{}.{}", sensor.kind.to_string(), sensor.name)
+}
+
+//
+// Returns the alerts for the specified sensors, checking that each rule
+// applies to at least one of them.
+//
+fn alerts(
+    hubris: &HubrisArchive,
+    subargs: &SensorsArgs,
+    sensors: &[usize],
+) -> Result<Alerts> {
+    let alerts = Alerts::new(&subargs.alert)?;
+    let names = sensors
+        .iter()
+        .map(|i| series(&hubris.manifest.sensors[*i]))
+        .collect::<Vec<_>>();
+
+    alerts.check_series(names.iter().map(String::as_str))?;
+
+    Ok(alerts)
+}
+
+//
+// Records a sample with our alerts, noting any that are raised among the
+// samples and returning the number raised.
+//
+fn alert(
+    hubris: &HubrisArchive,
+    alerts: &mut Alerts,
+    sensors: &[usize],
+    time: f64,
+    sample: &[Option<f32>],
+    table: &mut Table,
+) -> Result<usize> {
+    let mut raised = 0;
+
+    if alerts.is_empty() {
+        return Ok(raised);
     }
 
-    ops.push(Op::Done);
+    for (ndx, val) in sensors.iter().zip(sample.iter()) {
+        let name = series(&hubris.manifest.sensors[*ndx]);
+
+        match val {
+            Some(val) => {
+                for a in alerts.sample(&name, time, *val as f64) {
+                    table.note(&a.to_string())?;
+                    raised += 1;
+                }
+            }
+            None => alerts.missed(&name),
+        }
+    }
 
-    for r in &rvals {
-        print!(" {:>12}", r.name.to_uppercase());
+    Ok(raised)
+}
+
+//
+// Returns the cell for a sensor value, highlighting a sensor that could not
+// be read.
+//
+fn value(val: Option<f32>) -> Cell {
+    match val {
+        Some(val) => val.into(),
+        None => Cell::None.styled(Severity::Warning),
     }
+}
 
-    println!();
+//
+// Takes a sample of the sensors, retrying per our policy; a sample that is
+// missed is noted among the samples, and has no value for any sensor.
+//
+fn take_sample(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    ops: &[Op],
+    sensors: &[usize],
+    calibration: &Calibration,
+    policy: &RetryPolicy,
+    table: &mut Table,
+) -> Result<Vec<Option<f32>>> {
+    let sample = policy
+        .sample("sample", || read(core, context, ops, sensors, calibration))?;
 
-    for r in &rvals {
-        print!(" {:>12}", r.kind.to_string().to_uppercase());
+    match sample {
+        Ok(sample) => Ok(sample),
+        Err(err) => {
+            table.note(&format!("sample missed: {}", err))?;
+            Ok(vec![None; sensors.len()])
+        }
     }
+}
+
+fn print(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    format: OutputFormat,
+    subargs: &SensorsArgs,
+    context: &mut HiffyContext,
+    sensors: &[usize],
+    calibration: &Calibration,
+) -> Result<()> {
+    let ops = sensor_ops(hubris, context, sensors)?;
+    let rvals = sensors
+        .iter()
+        .map(|i| &hubris.manifest.sensors[*i])
+        .collect::<Vec<_>>();
+
+    let mut table = Table::new(
+        format,
+        rvals.iter().map(|r| Column::new(&r.name, 12).right()).collect(),
+    );
 
-    println!();
+    //
+    // In a table, we follow the sensor names with their kinds -- marking
+    // any sensor that has been calibrated.
+    //
+    table.header()?;
+
+    if format == OutputFormat::Table {
+        let kinds = rvals
+            .iter()
+            .zip(sensors.iter())
+            .map(|(r, ndx)| {
+                let kind = r.kind.to_string().to_uppercase();
+
+                if calibration.contains_key(ndx) {
+                    format!("{:>12}", format!("{}*", kind))
+                } else {
+                    format!("{:>12}", kind)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        println!("{}", kinds.join(" "));
+    }
+
+    if subargs.sleep {
+        precheck(hubris, core, context, sensors)?;
+    }
+
+    let policy: RetryPolicy = subargs.on_error.parse()?;
+    let mut alerts = alerts(hubris, subargs, sensors)?;
+    let mut beat = Heartbeat::default();
+    let started = Instant::now();
 
     loop {
-        let results = context.run(core, ops.as_slice(), None)?;
+        heartbeat(hubris, core, subargs, &mut beat, &mut alerts, &mut table)?;
+
+        let time = started.elapsed().as_secs_f64();
+        let rval = take_sample(
+            core,
+            context,
+            &ops,
+            sensors,
+            calibration,
+            &policy,
+            &mut table,
+        )?;
+        alert(hubris, &mut alerts, sensors, time, &rval, &mut table)?;
+        table.row(rval.into_iter().map(value).collect())?;
+
+        if !subargs.sleep {
+            break;
+        }
+
+        thread::sleep(Duration::from_millis(1000));
+    }
+
+    Ok(())
+}
+
+//
+// Returns the OpenMetrics family, unit and help text for a kind of sensor.
+//
+fn metric(
+    kind: HubrisSensorKind,
+) -> (&'static str, &'static str, &'static str) {
+    match kind {
+        HubrisSensorKind::Temperature => {
+            ("hubris_sensor_temperature_celsius", "celsius", "temperature")
+        }
+        HubrisSensorKind::Power => {
+            ("hubris_sensor_power_watts", "watts", "power")
+        }
+        HubrisSensorKind::Current => {
+            ("hubris_sensor_current_amperes", "amperes", "current")
+        }
+        HubrisSensorKind::Voltage => {
+            ("hubris_sensor_voltage_volts", "volts", "voltage")
+        }
+        HubrisSensorKind::Speed => {
+            ("hubris_sensor_speed_rpm", "rpm", "fan speed")
+        }
+    }
+}
+
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn number(val: f32) -> String {
+    if val.is_nan() {
+        "NaN".to_string()
+    } else if val.is_infinite() {
+        if val > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        val.to_string()
+    }
+}
+
+//
+// Takes exactly one sample of the selected sensors, writing it as OpenMetrics
+// text.  If any sensor fails to read, we fail without writing anything,
+// lest a partial snapshot be mistaken for a complete one.
+//
+fn snapshot(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    filename: &str,
+    context: &mut HiffyContext,
+    sensors: &[usize],
+    calibration: &Calibration,
+) -> Result<()> {
+    if sensors.is_empty() {
+        bail!("no sensors selected");
+    }
+
+    let ops = sensor_ops(hubris, context, sensors)?;
+    let sample = read(core, context, &ops, sensors, calibration)?;
+
+    let failed = sensors
+        .iter()
+        .zip(sample.iter())
+        .filter(|(_, val)| val.is_none())
+        .map(|(ndx, _)| hubris.manifest.sensors[*ndx].name.as_str())
+        .collect::<Vec<_>>();
+
+    if !failed.is_empty() {
+        bail!(
+            "failed to read {} of {} sensors: {}",
+            failed.len(),
+            sensors.len(),
+            failed.join(", ")
+        );
+    }
+
+    let mut out = String::new();
+
+    for kind in [
+        HubrisSensorKind::Temperature,
+        HubrisSensorKind::Power,
+        HubrisSensorKind::Current,
+        HubrisSensorKind::Voltage,
+        HubrisSensorKind::Speed,
+    ] {
+        let (family, unit, help) = metric(kind);
+
+        let values = sensors
+            .iter()
+            .zip(sample.iter())
+            .filter(|(ndx, _)| hubris.manifest.sensors[**ndx].kind == kind)
+            .collect::<Vec<_>>();
+
+        if values.is_empty() {
+            continue;
+        }
+
+        out += &format!("# TYPE {} gauge\n", family);
+        out += &format!("# UNIT {} {}\n", family, unit);
+        out += &format!("# HELP {} Hubris {} sensor\n", family, help);
+
+        for (ndx, val) in values {
+            let s = &hubris.manifest.sensors[*ndx];
+            let d = &hubris.manifest.i2c_devices[s.device];
+
+            out += &format!(
+                "{}{{sensor=\"{}\",device=\"{}\",controller=\"{}\",\
+                address=\"0x{:02x}\",archive=\"{}\"}} {}\n",
+                family,
+                label(&s.name),
+                label(&d.device),
+                d.controller,
+                d.address,
+                label(&hubris.manifest.name),
+                number(val.unwrap()),
+            );
+        }
+    }
+
+    out += "# EOF\n";
+
+    fs::write(filename, out)
+        .with_context(|| format!("failed to write {}", filename))?;
+
+    humility::msg!("wrote {} sensors to {}", sensors.len(), filename);
+
+    Ok(())
+}
+
+//
+// A rail, as identified by the voltage, current and power sensors that share
+// a name and a device.  Each sensor is identified by its index in the
+// sampled sensors.
+//
+#[derive(Debug, Default)]
+struct Rail<'a> {
+    name: &'a str,
+    device: usize,
+    voltage: Option<usize>,
+    current: Option<usize>,
+    power: Option<usize>,
+    input: bool,
+}
+
+impl Rail<'_> {
+    //
+    // Returns the power of the rail in a sample:  the product of its voltage
+    // and current if we have both, or its measured power if not.
+    //
+    fn power(&self, sample: &[Option<f32>]) -> Option<f64> {
+        let get = |ndx: Option<usize>| ndx.and_then(|n| sample[n]);
+
+        match (get(self.voltage), get(self.current)) {
+            (Some(v), Some(i)) => Some(v as f64 * i as f64),
+            _ => get(self.power).map(f64::from),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    n: usize,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    first: Option<f64>,
+    last: Option<f64>,
+}
+
+impl Summary {
+    fn new(vals: impl Iterator<Item = Option<f64>>) -> Self {
+        let mut s = Self::default();
+
+        for val in vals.flatten() {
+            s.n += 1;
+            s.sum += val;
+            s.min = Some(s.min.map_or(val, |m| m.min(val)));
+            s.max = Some(s.max.map_or(val, |m| m.max(val)));
+            s.first = s.first.or(Some(val));
+            s.last = Some(val);
+        }
+
+        s
+    }
+
+    fn mean(&self) -> Option<f64> {
+        if self.n == 0 {
+            None
+        } else {
+            Some(self.sum / self.n as f64)
+        }
+    }
+}
+
+//
+// Returns the Pearson correlation coefficient of two series, considering
+// only those samples for which both have a value.
+//
+fn correlation(a: &[Option<f64>], b: &[Option<f64>]) -> Option<f64> {
+    let pairs = a
+        .iter()
+        .zip(b.iter())
+        .filter_map(|(a, b)| Some(((*a)?, (*b)?)))
+        .collect::<Vec<_>>();
+
+    if pairs.len() < 3 {
+        return None;
+    }
+
+    let n = pairs.len() as f64;
+    let ma = pairs.iter().map(|(a, _)| a).sum::<f64>() / n;
+    let mb = pairs.iter().map(|(_, b)| b).sum::<f64>() / n;
+
+    let (mut cov, mut va, mut vb) = (0.0, 0.0, 0.0);
 
-        let mut rval = vec![];
+    for (a, b) in &pairs {
+        cov += (a - ma) * (b - mb);
+        va += (a - ma) * (a - ma);
+        vb += (b - mb) * (b - mb);
+    }
+
+    if va == 0.0 || vb == 0.0 {
+        None
+    } else {
+        Some(cov / (va.sqrt() * vb.sqrt()))
+    }
+}
 
-        for r in results {
-            if let Ok(val) = r {
-                rval.push(Some(f32::from_le_bytes(val[0..4].try_into()?)));
-            } else {
-                rval.push(None);
+fn report(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    format: OutputFormat,
+    subargs: &SensorsArgs,
+    context: &mut HiffyContext,
+    sensors: &[usize],
+    calibration: &Calibration,
+) -> Result<()> {
+    use HubrisSensorKind::*;
+
+    let duration = Duration::from_secs(subargs.report.unwrap());
+    let interval = Duration::from_millis(subargs.interval);
+
+    let sensors = sensors
+        .iter()
+        .filter(|&&i| hubris.manifest.sensors[i].kind != Speed)
+        .copied()
+        .collect::<Vec<_>>();
+
+    precheck(hubris, core, context, &sensors)?;
+
+    let all = &hubris.manifest.sensors;
+    let mut temps = vec![];
+    let mut rails: Vec<Rail> = vec![];
+
+    for (ndx, s) in sensors.iter().map(|i| &all[*i]).enumerate() {
+        if s.kind == Temperature {
+            temps.push((ndx, s.name.as_str()));
+            continue;
+        }
+
+        let rail = match rails
+            .iter_mut()
+            .find(|r| r.name == s.name && r.device == s.device)
+        {
+            Some(rail) => rail,
+            None => {
+                rails.push(Rail {
+                    name: &s.name,
+                    device: s.device,
+                    ..Default::default()
+                });
+                rails.last_mut().unwrap()
             }
+        };
+
+        match s.kind {
+            Voltage => rail.voltage = Some(ndx),
+            Current => rail.current = Some(ndx),
+            Power => rail.power = Some(ndx),
+            _ => {}
         }
+    }
+
+    //
+    // A rail that has only a voltage (or only a current) has no power of
+    // which to speak; we drop it.
+    //
+    rails.retain(|r| {
+        r.power.is_some() || (r.voltage.is_some() && r.current.is_some())
+    });
+
+    if let Some(input) = &subargs.input {
+        for name in input {
+            let mut found = false;
 
-        for val in rval {
-            if let Some(val) = val {
-                print!(" {:>12.2}", val);
-            } else {
-                print!(" {:>12}", "-");
+            for rail in rails.iter_mut().filter(|r| r.name == name.as_str()) {
+                rail.input = true;
+                found = true;
+            }
+
+            if !found {
+                bail!("no rail named \"{}\" has power", name);
             }
         }
+    }
+
+    if temps.is_empty() || rails.is_empty() {
+        bail!("a report requires both thermal and power sensors");
+    }
 
-        println!();
+    let ops = sensor_ops(hubris, context, &sensors)?;
 
-        if !subargs.sleep {
+    let mut columns = vec![Column::new("time", 8).right()];
+
+    for (_, name) in &temps {
+        columns.push(Column::new(&format!("temp.{}", name), 12).right());
+    }
+
+    for rail in &rails {
+        columns.push(Column::new(&format!("power.{}", rail.name), 12).right());
+    }
+
+    let mut table = Table::new(format, columns);
+
+    humility::msg!(
+        "sampling {} thermal sensors and {} rails for {}s",
+        temps.len(),
+        rails.len(),
+        duration.as_secs()
+    );
+
+    let policy: RetryPolicy = subargs.on_error.parse()?;
+    let mut alerts = alerts(hubris, subargs, &sensors)?;
+    let mut raised = 0;
+
+    let started = Instant::now();
+    let mut samples = vec![];
+    let mut beat = Heartbeat::default();
+
+    loop {
+        heartbeat(hubris, core, subargs, &mut beat, &mut alerts, &mut table)?;
+
+        let now = started.elapsed();
+        let sample = take_sample(
+            core,
+            context,
+            &ops,
+            &sensors,
+            calibration,
+            &policy,
+            &mut table,
+        )?;
+        let time = now.as_secs_f64();
+        raised +=
+            alert(hubris, &mut alerts, &sensors, time, &sample, &mut table)?;
+
+        let mut row = vec![Cell::from(now.as_secs_f64())];
+
+        for (ndx, _) in &temps {
+            row.push(value(sample[*ndx]));
+        }
+
+        for rail in &rails {
+            row.push(Cell::from(rail.power(&sample)));
+        }
+
+        table.row(row)?;
+        samples.push(sample);
+
+        if now + interval > duration {
             break;
         }
 
-        thread::sleep(Duration::from_millis(1000));
+        let next = interval * samples.len() as u32;
+        thread::sleep(next.saturating_sub(started.elapsed()));
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+
+    //
+    // Our total power is our input power if we have it, or the sum of the
+    // power of our rails if we don't.
+    //
+    let input = rails.iter().any(|r| r.input);
+
+    let total = |sample: &Vec<Option<f32>>, input: bool| {
+        rails
+            .iter()
+            .filter(|r| r.input == input)
+            .map(|r| r.power(sample))
+            .sum::<Option<f64>>()
+    };
+
+    let output = samples.iter().map(|s| total(s, false)).collect::<Vec<_>>();
+    let input = if input {
+        Some(samples.iter().map(|s| total(s, true)).collect::<Vec<_>>())
+    } else {
+        None
+    };
+
+    let reference = input.as_ref().unwrap_or(&output);
+
+    let mut summary = vec![];
+
+    summary.push(format!(
+        "Report over {:.1}s ({} samples); correlation is with {} power:",
+        elapsed,
+        samples.len(),
+        if input.is_some() { "input" } else { "total rail" }
+    ));
+
+    summary.push(String::new());
+
+    summary.push(format!(
+        "{:24} {:>8} {:>8} {:>8} {:>8} {:>6}",
+        "SENSOR", "MIN", "MEAN", "MAX", "RISE", "CORR"
+    ));
+
+    let f = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{:.2}", v));
+
+    //
+    // Calibrated sensors (and rails with any calibrated sensor) are marked
+    // in the summary.
+    //
+    let calibrated = |ndx: Option<usize>| {
+        ndx.map_or(false, |n| calibration.contains_key(&sensors[n]))
+    };
+
+    let mark = |name: &str, cal: bool| {
+        if cal {
+            format!("{}*", name)
+        } else {
+            name.to_string()
+        }
+    };
+
+    for (ndx, name) in &temps {
+        let vals =
+            samples.iter().map(|s| s[*ndx].map(f64::from)).collect::<Vec<_>>();
+
+        let s = Summary::new(vals.iter().copied());
+
+        let rise = match (s.first, s.last) {
+            (Some(first), Some(last)) => Some(last - first),
+            _ => None,
+        };
+
+        summary.push(format!(
+            "{:24} {:>8} {:>8} {:>8} {:>8} {:>6}",
+            mark(name, calibrated(Some(*ndx))),
+            f(s.min),
+            f(s.mean()),
+            f(s.max),
+            f(rise),
+            f(correlation(&vals, reference)),
+        ));
+    }
+
+    summary.push(String::new());
+
+    summary.push(format!(
+        "{:24} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        "RAIL", "VOLTS", "AMPS", "WATTS", "MAX", "MEASURED"
+    ));
+
+    for rail in &rails {
+        let mean = |ndx: Option<usize>| {
+            ndx.and_then(|n| {
+                Summary::new(samples.iter().map(|s| s[n].map(f64::from))).mean()
+            })
+        };
+
+        let power = Summary::new(samples.iter().map(|s| rail.power(s)));
+
+        let cal = calibrated(rail.voltage)
+            || calibrated(rail.current)
+            || calibrated(rail.power);
+
+        let name = if rail.input {
+            format!("{} (input)", mark(rail.name, cal))
+        } else {
+            mark(rail.name, cal)
+        };
+
+        summary.push(format!(
+            "{:24} {:>8} {:>8} {:>8} {:>8} {:>8}",
+            name,
+            f(mean(rail.voltage)),
+            f(mean(rail.current)),
+            f(power.mean()),
+            f(power.max),
+            f(mean(rail.power)),
+        ));
+    }
+
+    summary.push(String::new());
+
+    let output = Summary::new(output.iter().copied());
+
+    summary.push(format!(
+        "total rail power: {} W mean, {} W max",
+        f(output.mean()),
+        f(output.max)
+    ));
+
+    if let Some(input) = &input {
+        let input = Summary::new(input.iter().copied());
+
+        summary.push(format!(
+            "input power: {} W mean, {} W max",
+            f(input.mean()),
+            f(input.max)
+        ));
+
+        if let (Some(o), Some(i)) = (output.mean(), input.mean()) {
+            if i > 0.0 {
+                summary.push(format!(
+                    "estimated efficiency: {:.1}%",
+                    (o / i) * 100.0
+                ));
+            }
+        }
+    }
+
+    if !alerts.is_empty() {
+        summary.push(format!(
+            "alerts raised: {} (of {} rules)",
+            raised,
+            alerts.rules().len()
+        ));
+    }
+
+    //
+    // If we are emitting our samples as JSON or CSV, we emit our summary on
+    // stderr so as to not corrupt them.
+    //
+    for line in &summary {
+        if format == OutputFormat::Table {
+            println!("{}", line);
+        } else {
+            eprintln!("{}", line);
+        }
     }
 
     Ok(())
@@ -234,10 +1740,19 @@ fn print(
 fn sensors(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
-    let subargs = SensorsArgs::try_parse_from(subargs)?;
+    let mut subargs = SensorsArgs::try_parse_from(subargs)?;
+    let mut format = args.format;
+
+    if let Some(name) = subargs.view.clone() {
+        apply_view(&name, &mut subargs, &mut format)?;
+    }
+
+    if subargs.lint {
+        return lint(hubris, format);
+    }
 
     let types = if let Some(ref types) = subargs.types {
         let mut rval = HashSet::new();
@@ -306,14 +1821,62 @@ fn sensors(
         None
     };
 
+    let mut sensors = selected(hubris, &types, &devices, &named);
+
+    if let Some(ref by) = subargs.sort {
+        sort(hubris, &mut sensors, by)?;
+    }
+
+    if let Some(ref name) = subargs.save_view {
+        save_view(name, &subargs, format)?;
+    }
+
     if subargs.list {
-        list(hubris, &types, &devices, &named)?;
+        list(hubris, format, &sensors)?;
         return Ok(());
     }
 
+    let calibration = match &args.calibration {
+        Some(filename) => {
+            let calibration = calibration(hubris, filename)?;
+
+            if sensors.iter().any(|s| calibration.contains_key(s)) {
+                humility::msg!(
+                    "values marked with * are calibrated per {}",
+                    filename
+                );
+            }
+
+            calibration
+        }
+        None => HashMap::new(),
+    };
+
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
 
-    print(hubris, core, &subargs, &mut context, &types, &devices, &named)?;
+    if let Some(ref filename) = subargs.snapshot_metrics {
+        snapshot(hubris, core, filename, &mut context, &sensors, &calibration)?;
+    } else if subargs.report.is_some() {
+        report(
+            hubris,
+            core,
+            format,
+            &subargs,
+            &mut context,
+            &sensors,
+            &calibration,
+        )?;
+    } else {
+        print(
+            hubris,
+            core,
+            format,
+            &subargs,
+            &mut context,
+            &sensors,
+            &calibration,
+        )?;
+    }
 
     Ok(())
 }
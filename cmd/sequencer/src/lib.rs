@@ -0,0 +1,320 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility sequencer`
+//!
+//! `humility sequencer` communicates with the power sequencer task via its
+//! `Sequencer` Idol interface.  With no arguments, it displays the status of
+//! the sequencer:  every operation in the interface that takes no arguments
+//! and is named `get_*` or `is_*` is called, and its result is displayed.
+//! This includes the state of the power state machine -- and, depending on
+//! the board, rail enables, power-good and fault latches:
+//!
+//! ```console
+//! % humility sequencer
+//! humility: attached via ST-Link V3
+//!                get_state => A2
+//!   is_clock_config_loaded => true
+//! ```
+//!
+//! To poll the status, displaying it whenever it changes, use `-w`
+//! (`--watch`):
+//!
+//! ```console
+//! % humility sequencer --watch
+//! humility: attached via ST-Link V3
+//!      0.000 get_state => A2
+//!      0.000 is_clock_config_loaded => true
+//!     12.514 get_state => A0
+//! ```
+//!
+//! To request a transition to a different power state, use `-s`
+//! (`--state`).  The transition must be confirmed interactively unless
+//! `-y` (`--yes`) is specified; the resulting state is displayed once the
+//! request completes:
+//!
+//! ```console
+//! % humility sequencer --state A0
+//! humility: attached via ST-Link V3
+//! transition from A2 to A0? [y/N] y
+//! humility: requested A0; state is now A0
+//! ```
+//!
+//! To list the valid states, use `-l` (`--list`).
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::idol::{IdolArgument, IdolOperation};
+use humility_cmd::{confirm, Archive, Args, Attach, Command, Validate};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[clap(name = "sequencer", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct SequencerArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// list valid power states
+    #[clap(long, short, conflicts_with_all = &["state", "watch"])]
+    list: bool,
+
+    /// poll the sequencer status, printing it whenever it changes
+    #[clap(long, short, conflicts_with = "state")]
+    watch: bool,
+
+    /// interval between polls, in milliseconds
+    #[clap(
+        long, short, default_value = "1000", value_name = "ms",
+        requires = "watch", parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
+    /// request a transition to the specified power state
+    #[clap(long, short, value_name = "state")]
+    state: Option<String>,
+
+    /// do not prompt for confirmation of a state transition
+    #[clap(long, short, requires = "state")]
+    yes: bool,
+}
+
+const INTERFACE: &str = "Sequencer";
+
+fn sequencer_module(hubris: &HubrisArchive) -> Result<&HubrisModule> {
+    for i in 0..hubris.ntasks() {
+        let module = hubris.lookup_module(HubrisTask::Task(i as u32))?;
+
+        if let Some(iface) = &module.iface {
+            if iface.name == INTERFACE {
+                return Ok(module);
+            }
+        }
+    }
+
+    bail!("no task implements the {} interface", INTERFACE);
+}
+
+///
+/// Returns the operations that constitute the status of the sequencer:
+/// those that take no arguments and are named like accessors.  (Other
+/// operations without arguments -- like `fans_on` -- have side effects, and
+/// must not be called.)
+///
+fn status_ops<'a>(
+    hubris: &'a HubrisArchive,
+    module: &HubrisModule,
+) -> Vec<IdolOperation<'a>> {
+    let iface = module.iface.as_ref().unwrap();
+    let mut rval = vec![];
+
+    for (name, op) in &iface.ops {
+        if !op.args.is_empty() {
+            continue;
+        }
+
+        if !name.starts_with("get_") && !name.starts_with("is_") {
+            continue;
+        }
+
+        match IdolOperation::new(hubris, INTERFACE, name, Some(&module.task)) {
+            Ok(op) => rval.push(op),
+            Err(err) => {
+                humility::msg!("can't call {}.{}: {}", INTERFACE, name, err);
+            }
+        }
+    }
+
+    rval
+}
+
+fn status(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    ops: &[IdolOperation],
+) -> Result<Vec<String>> {
+    let funcs = context.functions()?;
+    let mut program = vec![];
+
+    for op in ops {
+        context.idol_call_ops(&funcs, op, &[], &mut program)?;
+    }
+
+    program.push(Op::Done);
+
+    let results = context.run(core, program.as_slice(), None)?;
+
+    let fmt = HubrisPrintFormat {
+        newline: false,
+        hex: true,
+        ..HubrisPrintFormat::default()
+    };
+
+    let mut rval = vec![];
+
+    for (op, result) in ops.iter().zip(results.iter()) {
+        rval.push(match result {
+            Ok(val) => hubris.printfmt(val, op.ok, &fmt)?,
+            Err(e) => {
+                match op.error.and_then(|err| err.lookup_variant(*e as u64)) {
+                    Some(variant) => format!("Err({})", variant.name),
+                    None => format!("Err(0x{:x})", e),
+                }
+            }
+        });
+    }
+
+    Ok(rval)
+}
+
+fn states<'a>(
+    hubris: &'a HubrisArchive,
+    module: &HubrisModule,
+) -> Result<&'a HubrisEnum> {
+    let op =
+        module.iface.as_ref().unwrap().ops.get("set_state").ok_or_else(
+            || anyhow!("{} has no set_state operation", INTERFACE),
+        )?;
+
+    let arg = op
+        .args
+        .get("state")
+        .ok_or_else(|| anyhow!("set_state has no state argument"))?;
+
+    module
+        .lookup_enum_byname(hubris, &arg.ty.0)
+        .context(format!("failed to find power state type {}", arg.ty.0))
+}
+
+fn transition(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    subargs: &SequencerArgs,
+    state: &str,
+) -> Result<()> {
+    let get = IdolOperation::new(hubris, INTERFACE, "get_state", None)?;
+    let set = IdolOperation::new(hubris, INTERFACE, "set_state", None)?;
+
+    //
+    // Validate the state before we ask for confirmation.
+    //
+    let payload = set.payload(&[("state", IdolArgument::String(state))])?;
+    let current = status(hubris, core, context, std::slice::from_ref(&get))?;
+
+    if current[0] == state {
+        humility::msg!("already in {}", state);
+        return Ok(());
+    }
+
+    let prompt = format!("transition from {} to {}?", current[0], state);
+
+    if !subargs.yes && !confirm(&prompt)? {
+        bail!("transition not confirmed");
+    }
+
+    let funcs = context.functions()?;
+    let mut ops = vec![];
+    context.idol_call_ops(&funcs, &set, &payload, &mut ops)?;
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+
+    if let Some(Err(e)) = results.get(0) {
+        match set.error.and_then(|err| err.lookup_variant(*e as u64)) {
+            Some(variant) => bail!("set_state failed: {}", variant.name),
+            None => bail!("set_state failed: Err(0x{:x})", e),
+        }
+    }
+
+    let now = status(hubris, core, context, std::slice::from_ref(&get))?;
+    humility::msg!("requested {}; state is now {}", state, now[0]);
+
+    Ok(())
+}
+
+fn sequencer(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = SequencerArgs::try_parse_from(subargs)?;
+    let module = sequencer_module(hubris)?;
+
+    if subargs.list {
+        let states = states(hubris, module)?;
+
+        for variant in &states.variants {
+            println!("{}", variant.name);
+        }
+
+        return Ok(());
+    }
+
+    let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+
+    if let Some(ref state) = subargs.state {
+        return transition(hubris, core, &mut context, &subargs, state);
+    }
+
+    let ops = status_ops(hubris, module);
+
+    if ops.is_empty() {
+        bail!("{} interface has no status operations", INTERFACE);
+    }
+
+    let width = ops.iter().map(|op| op.name.1.len()).max().unwrap_or(0);
+
+    if !subargs.watch {
+        for (op, val) in
+            ops.iter().zip(status(hubris, core, &mut context, &ops)?)
+        {
+            println!("{:>w$} => {}", op.name.1, val, w = width + 2);
+        }
+
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    let mut last: Vec<Option<String>> = vec![None; ops.len()];
+
+    loop {
+        let vals = status(hubris, core, &mut context, &ops)?;
+        let time = started.elapsed().as_secs_f64();
+
+        for (ndx, val) in vals.into_iter().enumerate() {
+            if last[ndx].as_ref() != Some(&val) {
+                println!("{:10.3} {} => {}", time, ops[ndx].name.1, val);
+                last[ndx] = Some(val);
+            }
+        }
+
+        thread::sleep(Duration::from_millis(subargs.interval));
+    }
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "sequencer",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: sequencer,
+        },
+        SequencerArgs::command(),
+    )
+}
@@ -0,0 +1,101 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility snapshot`
+//!
+//! `humility snapshot` captures the memory and registers of a running
+//! system such that they can later be restored to the same board, allowing
+//! an experiment to be repeated from an identical state.  A snapshot is
+//! taken by halting the core and writing a dump (in the same format as
+//! `humility dump`, and therefore usable with `-d`):
+//!
+//! ```console
+//! % humility snapshot before-reset.core
+//! humility: attached via ST-Link V3
+//! humility: core halted
+//! humility: dumping to before-reset.core
+//! humility: dumped 1.12MB in 24 seconds
+//! humility: core resumed
+//! ```
+//!
+//! To restore a snapshot, use `-r` (`--restore`).  The core is halted, all
+//! writable memory and the core's registers are restored from the
+//! snapshot, and the core is resumed (use `--halt` to leave it halted):
+//!
+//! ```console
+//! % humility snapshot --restore before-reset.core
+//! humility: attached via ST-Link V3
+//! humility: core halted
+//! humility: restored 1.06MB in 19 seconds
+//! humility: core resumed
+//! ```
+//!
+//! A snapshot can only be restored to a target running the archive from
+//! which it was taken.  Note that only memory and core registers are
+//! restored: the state of peripherals (and of anything external to the
+//! microcontroller) is not, and experiments that depend on such state may
+//! not behave identically when restored.
+//!
+
+use anyhow::Result;
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+
+#[derive(Parser, Debug)]
+#[clap(name = "snapshot", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct SnapshotArgs {
+    /// restore the specified snapshot
+    #[clap(long, short, value_name = "snapshot", conflicts_with = "file")]
+    restore: Option<String>,
+
+    /// leave the core halted after taking or restoring a snapshot
+    #[clap(long)]
+    halt: bool,
+
+    /// file to which to write the snapshot
+    file: Option<String>,
+}
+
+fn snapshot(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = SnapshotArgs::try_parse_from(subargs)?;
+
+    core.halt()?;
+    humility::msg!("core halted");
+
+    let rval = match &subargs.restore {
+        Some(snapshot) => hubris.restore(core, snapshot),
+        None => {
+            let options = HubrisDumpOptions { device: vec![], compress: false };
+            hubris.dump(core, subargs.file.as_deref(), &options)
+        }
+    };
+
+    if !subargs.halt {
+        core.run()?;
+        humility::msg!("core resumed");
+    }
+
+    rval
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "snapshot",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Match,
+            run: snapshot,
+        },
+        SnapshotArgs::command(),
+    )
+}
@@ -2,12 +2,47 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! ## `humility spd`
+//!
+//! `humility spd` reads and decodes the serial presence detect (SPD) data
+//! of DIMMs.  If no bus is specified, the SPD data that the firmware has
+//! cached in its `SPD_DATA` variable is used (allowing this to be run on a
+//! dump); otherwise, the specified bus is scanned for SPD EEPROMs (DDR4) or
+//! SPD hubs (DDR5), and their contents read:
+//!
+//! ```console
+//! % humility spd
+//! humility: attached via ST-Link V3
+//! ADDR MANUFACTURER              PART                 WEEK YEAR
+//!    0 Samsung                   M393A8G40AB2-CWE        1 2021
+//!    1 Micron Technology         36ASF8G72PZ-3G2E1       1 2021
+//! ```
+//!
+//! To see the decoded SPD fields (including capacity, organization and
+//! timings) along with the raw contents, use `-v` (`--verbose`):
+//!
+//! ```console
+//! % humility spd -v
+//! humility: attached via ST-Link V3
+//! ADDR MANUFACTURER              PART                 WEEK YEAR
+//!    0 Samsung                   M393A8G40AB2-CWE        1 2021
+//!    |
+//!    +---->               type: DDR4 RDIMM
+//!                     capacity: 64 GB
+//!                        ranks: 2
+//!                 organization: 1 x4 die of 16 Gbit
+//! ...
+//! ```
+//!
+//! To emit the decoded fields as JSON (one object per DIMM), use `-j`
+//! (`--json`).
+//!
+
 use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
 use humility_cmd::i2c::I2cArgs;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
-use std::str;
 
 use anyhow::{bail, Result};
 use clap::Command as ClapCommand;
@@ -28,6 +63,10 @@ struct SpdArgs {
     #[clap(long, short)]
     verbose: bool,
 
+    /// emit decoded SPD data as JSON
+    #[clap(long, short, conflicts_with = "verbose")]
+    json: bool,
+
     /// specifies an I2C controller
     #[clap(long, short, value_name = "controller",
         parse(try_from_str = parse_int::parse),
@@ -50,33 +89,289 @@ struct SpdArgs {
 }
 
 const SPD_SIZE: usize = 512;
+const DDR5_SPD_SIZE: usize = 1024;
+
+//
+// The memory type, as found in byte 2 of the SPD
+//
+const SPD_TYPE_DDR4: u8 = 0x0c;
+const SPD_TYPE_DDR5: u8 = 0x12;
+
+//
+// An SPD5118 hub (as found on DDR5 DIMMs) identifies itself in its first
+// two registers; its memory is accessed in 128-byte pages selected via
+// MR11, and read at offset 0x80.
+//
+const SPD5118_ID: [u8; 2] = [0x51, 0x18];
+const SPD5118_MR11: u8 = 0x0b;
+const SPD5118_PAGE_SIZE: usize = 128;
 
 fn from_bcd(val: u8) -> u8 {
     (val >> 4) * 10 + (val & 0xf)
 }
 
+/// Decoded SPD contents, common to DDR4 and DDR5
+#[derive(Debug)]
+struct SpdInfo {
+    kind: &'static str,
+    module: &'static str,
+    manufacturer: String,
+    dram_manufacturer: String,
+    part: String,
+    serial: u32,
+    revision: u8,
+    week: u8,
+    year: u16,
+    /// Total capacity, in MiB
+    capacity: u64,
+    /// Package ranks
+    ranks: u32,
+    /// Dies per package
+    dies: u32,
+    /// SDRAM I/O width, in bits
+    width: u32,
+    /// Capacity of each die, in Mbit
+    density: u32,
+    /// Timing parameters, in picoseconds
+    timings: Vec<(&'static str, u32)>,
+}
+
+fn jedec_manufacturer(lsb: u8, msb: u8) -> String {
+    jep106::JEP106Code::new(lsb & 0x7f, msb & 0x7f)
+        .get()
+        .unwrap_or("<unknown>")
+        .to_string()
+}
+
+fn module_type(val: u8) -> &'static str {
+    match val & 0xf {
+        0x1 => "RDIMM",
+        0x2 => "UDIMM",
+        0x3 => "SO-DIMM",
+        0x4 => "LRDIMM",
+        0x5 => "Mini-RDIMM",
+        0x6 => "Mini-UDIMM",
+        0xa => "DDIMM",
+        0xb => "Solder-down",
+        _ => "<unknown>",
+    }
+}
+
+///
+/// Decodes the manufacturing information, which has the same layout (but
+/// at a different base and with a different part number length) on DDR4
+/// and DDR5.
+///
+fn decode_mfg(buf: &[u8], base: usize, partlen: usize) -> SpdInfo {
+    let part = &buf[base + 9..base + 9 + partlen];
+    let dram = base + 9 + partlen + 1;
+
+    SpdInfo {
+        kind: "",
+        module: module_type(buf[3]),
+        manufacturer: jedec_manufacturer(buf[base], buf[base + 1]),
+        dram_manufacturer: jedec_manufacturer(buf[dram], buf[dram + 1]),
+        part: String::from_utf8_lossy(part).trim_end().to_string(),
+        serial: u32::from_be_bytes(buf[base + 5..base + 9].try_into().unwrap()),
+        revision: buf[base + 9 + partlen],
+        year: 2000 + from_bcd(buf[base + 3]) as u16,
+        week: from_bcd(buf[base + 4]),
+        capacity: 0,
+        ranks: 0,
+        dies: 0,
+        width: 0,
+        density: 0,
+        timings: vec![],
+    }
+}
+
+fn decode_ddr4(buf: &[u8]) -> SpdInfo {
+    //
+    // Timings are in units of the medium timebase (125 ps) with a signed
+    // adjustment in units of the fine timebase (1 ps).
+    //
+    let t = |mtb: u32, fine: Option<usize>| {
+        let fine = fine.map_or(0, |f| buf[f] as i8 as i32);
+        (mtb as i32 * 125 + fine) as u32
+    };
+
+    let density = match buf[4] & 0xf {
+        n @ 0..=7 => 256 << n,
+        8 => 12 * 1024,
+        9 => 24 * 1024,
+        _ => 0,
+    };
+
+    let width = 4 << (buf[12] & 0x7);
+    let bus = 8 << (buf[13] & 0x7);
+    let ranks = ((buf[12] >> 3) & 0x7) as u32 + 1;
+    let dies = ((buf[6] >> 4) & 0x7) as u32 + 1;
+
+    //
+    // For 3DS packages, each die is a logical rank.
+    //
+    let logical = if buf[6] & 0x3 == 0x2 { ranks * dies } else { ranks };
+
+    let tras = ((buf[27] as u32 & 0xf) << 8) | buf[28] as u32;
+    let trc = ((buf[27] as u32 >> 4) << 8) | buf[29] as u32;
+
+    SpdInfo {
+        kind: "DDR4",
+        capacity: (density as u64 / 8) * (bus / width) as u64 * logical as u64,
+        ranks,
+        dies,
+        width,
+        density,
+        timings: vec![
+            ("tCKAVGmin", t(buf[18] as u32, Some(125))),
+            ("tAAmin", t(buf[24] as u32, Some(123))),
+            ("tRCDmin", t(buf[25] as u32, Some(122))),
+            ("tRPmin", t(buf[26] as u32, Some(121))),
+            ("tRASmin", t(tras, None)),
+            ("tRCmin", t(trc, Some(120))),
+        ],
+        ..decode_mfg(buf, 320, 20)
+    }
+}
+
+fn decode_ddr5(buf: &[u8]) -> SpdInfo {
+    let ps =
+        |offs: usize| u16::from_le_bytes([buf[offs], buf[offs + 1]]) as u32;
+
+    let density = match buf[4] & 0x1f {
+        1 => 4,
+        2 => 8,
+        3 => 12,
+        4 => 16,
+        5 => 24,
+        6 => 32,
+        7 => 48,
+        8 => 64,
+        _ => 0,
+    } * 1024;
+
+    let dies = match buf[4] >> 5 {
+        0 => 1,
+        n @ 2..=5 => 1 << (n - 1),
+        _ => 0,
+    };
+
+    let width = 4 << (buf[6] >> 5);
+    let ranks = ((buf[234] >> 3) & 0x7) as u32 + 1;
+    let bus = 8 << (buf[235] & 0x7);
+    let channels = ((buf[235] >> 5) & 0x3) as u64 + 1;
+
+    SpdInfo {
+        kind: "DDR5",
+        capacity: channels
+            * (bus / width) as u64
+            * dies as u64
+            * (density as u64 / 8)
+            * ranks as u64,
+        ranks,
+        dies,
+        width,
+        density,
+        timings: vec![
+            ("tCKAVGmin", ps(20)),
+            ("tAAmin", ps(30)),
+            ("tRCDmin", ps(32)),
+            ("tRPmin", ps(34)),
+            ("tRASmin", ps(36)),
+            ("tRCmin", ps(38)),
+        ],
+        ..decode_mfg(buf, 512, 30)
+    }
+}
+
+fn decode_spd(buf: &[u8]) -> Result<SpdInfo> {
+    match buf.get(2) {
+        Some(&SPD_TYPE_DDR4) if buf.len() >= SPD_SIZE => Ok(decode_ddr4(buf)),
+        Some(&SPD_TYPE_DDR5) if buf.len() >= DDR5_SPD_SIZE => {
+            Ok(decode_ddr5(buf))
+        }
+        Some(&SPD_TYPE_DDR5) => bail!("DDR5 SPD is truncated"),
+        Some(kind) => bail!("unsupported SPD memory type 0x{:x}", kind),
+        None => bail!("SPD is empty"),
+    }
+}
+
+fn spd_json(addr: u8, info: &SpdInfo) -> serde_json::Value {
+    let timings = info
+        .timings
+        .iter()
+        .map(|(name, ps)| (name.to_string(), serde_json::json!(ps)))
+        .collect::<serde_json::Map<_, _>>();
+
+    serde_json::json!({
+        "addr": addr,
+        "type": info.kind,
+        "module": info.module,
+        "manufacturer": info.manufacturer,
+        "dram_manufacturer": info.dram_manufacturer,
+        "part": info.part,
+        "serial": format!("{:08x}", info.serial),
+        "revision": info.revision,
+        "week": info.week,
+        "year": info.year,
+        "capacity_mib": info.capacity,
+        "ranks": info.ranks,
+        "dies": info.dies,
+        "width": info.width,
+        "density_mbit": info.density,
+        "timings_ps": timings,
+    })
+}
+
+#[rustfmt::skip::macros(println)]
+fn print_spd(info: &SpdInfo) {
+    let size = |mbit: u64| {
+        if mbit >= 1024 && mbit % 1024 == 0 {
+            format!("{} G", mbit / 1024)
+        } else {
+            format!("{} M", mbit)
+        }
+    };
+
+    println!("   |");
+    println!("   +----> {:>18}: {} {}", "type", info.kind, info.module);
+    println!("          {:>18}: {}B", "capacity", size(info.capacity as u64));
+    println!("          {:>18}: {}", "ranks", info.ranks);
+    println!("          {:>18}: {} x{} die{} of {}bit", "organization",
+        info.dies, info.width, if info.dies > 1 { "s" } else { "" },
+        size(info.density as u64));
+    println!("          {:>18}: {:08x}", "serial", info.serial);
+    println!("          {:>18}: {}", "revision", info.revision);
+    println!("          {:>18}: {}", "DRAM manufacturer",
+        info.dram_manufacturer);
+
+    for (name, ps) in &info.timings {
+        println!("          {:>18}: {}.{:03} ns", name, ps / 1000, ps % 1000);
+    }
+
+    if let Some((_, tck)) = info.timings.first() {
+        if *tck != 0 {
+            println!("          {:>18}: {} MT/s", "speed",
+                2_000_000 / tck);
+        }
+    }
+}
+
 fn dump_spd(
     subargs: &SpdArgs,
     addr: u8,
     buf: &[u8],
     header: bool,
 ) -> Result<()> {
-    use spd::Offset;
-
-    let jep_cc = Offset::ModuleManufacturerIDCodeLSB.within(buf) & 0x7f;
-    let jep_id = Offset::ModuleManufacturerIDCodeMSB.within(buf) & 0x7f;
-
-    let year = from_bcd(Offset::ModuleManufacturingDateYear.within(buf));
-    let week = from_bcd(Offset::ModuleManufacturingDateWeek.within(buf));
-
     let width: usize = 16;
 
-    let manufacturer = jep106::JEP106Code::new(jep_cc, jep_id);
+    let info = decode_spd(buf);
 
-    let part = str::from_utf8(
-        &buf[Offset::PartNumberBase.to_usize()
-            ..=Offset::PartNumberLimit.to_usize()],
-    );
+    if subargs.json {
+        let info = info?;
+        println!("{}", spd_json(addr, &info));
+        return Ok(());
+    }
 
     if header || subargs.verbose {
         println!(
@@ -85,19 +380,22 @@ fn dump_spd(
         )
     }
 
-    println!(
-        "{:4} {:25} {:20} {:4} {:4}",
-        addr,
-        manufacturer.get().unwrap_or("<unknown>"),
-        part.unwrap_or("<unknown>"),
-        week,
-        2000 + (year as u16),
-    );
+    match &info {
+        Ok(info) => println!(
+            "{:4} {:25} {:20} {:4} {:4}",
+            addr, info.manufacturer, info.part, info.week, info.year,
+        ),
+        Err(err) => println!("{:4} {}", addr, err),
+    }
 
     if !subargs.verbose {
         return Ok(());
     }
 
+    if let Ok(info) = &info {
+        print_spd(info);
+    }
+
     println!("   |");
     print!("   +---->   ");
 
@@ -107,7 +405,7 @@ fn dump_spd(
 
     println!();
 
-    for offs in (0..SPD_SIZE).step_by(width) {
+    for offs in (0..buf.len()).step_by(width) {
         print!("    0x{:03x} | ", offs);
 
         for i in 0..width {
@@ -144,6 +442,63 @@ fn set_page(ops: &mut Vec<Op>, i2c_write: &HiffyFunction, page: u8) {
     ops.push(Op::DropN(4));
 }
 
+///
+/// Reads the SPD from the DDR5 SPD hub at the specified address, one page at
+/// a time.
+///
+fn read_ddr5(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    base: &[Op],
+    addr: u8,
+    funcs: &HiffyFunctions,
+) -> Result<Vec<u8>> {
+    let i2c_read = funcs.get("I2cRead", 7)?;
+    let i2c_write = funcs.get("I2cWrite", 8)?;
+    let dev = spd::Function::Memory(addr).to_device_code().unwrap();
+    let npages = DDR5_SPD_SIZE / SPD5118_PAGE_SIZE;
+
+    let mut ops = base.to_vec();
+
+    let set_page = |ops: &mut Vec<Op>, page: u8| {
+        ops.push(Op::Push(dev));
+        ops.push(Op::Push(SPD5118_MR11));
+        ops.push(Op::Push(page));
+        ops.push(Op::Push(1));
+        ops.push(Op::Call(i2c_write.id));
+        ops.push(Op::DropN(4));
+    };
+
+    for page in 0..npages {
+        set_page(&mut ops, page as u8);
+
+        ops.push(Op::Push(dev));
+        ops.push(Op::Push(0x80));
+        ops.push(Op::Push(SPD5118_PAGE_SIZE as u8));
+        ops.push(Op::Call(i2c_read.id));
+        ops.push(Op::DropN(3));
+    }
+
+    set_page(&mut ops, 0);
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+    let mut buf = vec![];
+
+    for result in &results {
+        match result {
+            Ok(val) => buf.extend_from_slice(val),
+            Err(_) => bail!("failed to read DDR5 SPD: {:?}", results),
+        }
+    }
+
+    if buf.len() != DDR5_SPD_SIZE {
+        bail!("bad DDR5 SPD length ({} bytes)", buf.len());
+    }
+
+    Ok(buf)
+}
+
 fn spd(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
@@ -170,15 +525,28 @@ fn spd(
             }
         };
 
-        if spd_data.size % SPD_SIZE != 0 {
+        //
+        // SPD_DATA is an array of SPDs; we use the size of its elements to
+        // determine whether it contains DDR4 or DDR5 SPDs, falling back to
+        // DDR4 if we can't determine it.
+        //
+        let spd_size = match hubris
+            .lookup_array(spd_data.goff)
+            .and_then(|array| hubris.typesize(array.goff))
+        {
+            Ok(DDR5_SPD_SIZE) => DDR5_SPD_SIZE,
+            _ => SPD_SIZE,
+        };
+
+        if spd_data.size % spd_size != 0 {
             bail!(
                 "SPD_DATA is {} bytes; expected even multiple of {}",
                 spd_data.size,
-                SPD_SIZE
+                spd_size
             );
         }
 
-        let nspd = spd_data.size / SPD_SIZE;
+        let nspd = spd_data.size / spd_size;
         let mut bytes = vec![0u8; spd_data.size];
 
         let _info = core.halt()?;
@@ -190,8 +558,8 @@ fn spd(
         let mut header = true;
 
         for addr in 0..nspd {
-            let offs = addr * SPD_SIZE;
-            let data = &bytes[offs..offs + SPD_SIZE];
+            let offs = addr * spd_size;
+            let data = &bytes[offs..offs + spd_size];
 
             if !data.iter().any(|&datum| datum != 0) {
                 continue;
@@ -248,14 +616,15 @@ fn spd(
     set_page(&mut ops, i2c_write, 0);
 
     //
-    // Now issue single byte register reads to determine where our devices are.
+    // Now issue two byte register reads to determine where our devices are
+    // (and if they are DDR5 SPD hubs).
     //
     for addr in 0..spd::MAX_DEVICES {
         ops.push(Op::Push(
             spd::Function::Memory(addr).to_device_code().unwrap(),
         ));
         ops.push(Op::Push(0));
-        ops.push(Op::Push(1));
+        ops.push(Op::Push(2));
         ops.push(Op::Call(i2c_read.id));
         ops.push(Op::DropN(3));
     }
@@ -265,87 +634,102 @@ fn spd(
     let results = context.run(core, ops.as_slice(), None)?;
     let mut header = true;
 
-    if let Err(err) = results[0] {
-        bail!("failed to set page to 0: {}", i2c_write.strerror(err));
-    }
+    //
+    // Only DDR4 SPDs respond to the page address, so a failure to set it
+    // is only an error if we subsequently find one.
+    //
+    let paged = results[0].clone().map_err(|err| i2c_write.strerror(err));
 
     for addr in 0..spd::MAX_DEVICES {
-        if results[addr as usize + 1].is_ok() {
-            let mut ops = base.clone();
-
-            //
-            // Issue the read for the bottom 128 bytes from the 0 page
-            //
-            let dev = spd::Function::Memory(addr).to_device_code().unwrap();
-            ops.push(Op::Push(dev));
-            ops.push(Op::Push(0));
-            ops.push(Op::Push(128));
-            ops.push(Op::Call(i2c_read.id));
-
-            //
-            // And now read the top 128 bytes from the 0 page...
-            //
-            ops.push(Op::DropN(2));
-            ops.push(Op::Push(128));
-            ops.push(Op::Push(128));
-            ops.push(Op::Call(i2c_read.id));
-
-            //
-            // Switch to the 1 page
-            //
-            ops.push(Op::DropN(3));
-            set_page(&mut ops, i2c_write, 1);
-
-            //
-            // Issue an identical read for the bottom 128 bytes...
-            //
-            ops.push(Op::Push(dev));
-            ops.push(Op::Push(0));
-            ops.push(Op::Push(128));
-            ops.push(Op::Call(i2c_read.id));
-
-            //
-            // ...and the top 128 bytes
-            //
-            ops.push(Op::DropN(2));
-            ops.push(Op::Push(128));
-            ops.push(Op::Push(128));
-            ops.push(Op::Call(i2c_read.id));
-            ops.push(Op::DropN(3));
-
-            //
-            // Finally, set ourselves back to the 0 page
-            //
-            set_page(&mut ops, i2c_write, 0);
-
-            ops.push(Op::Done);
-
-            let results = context.run(core, ops.as_slice(), None)?;
-
-            //
-            // If that succeeded, we'll have four buffers that should add up
-            // to 512 bytes.
-            //
-            let mut buf = vec![];
-
-            for result in &results {
-                match result {
-                    Ok(val) => {
-                        buf.extend_from_slice(val);
-                    }
-                    Err(_) => {
-                        bail!("failed to read SPD: {:?}", results);
-                    }
-                }
+        match &results[addr as usize + 1] {
+            Ok(val) if val[..] == SPD5118_ID => {
+                let buf = read_ddr5(core, &mut context, &base, addr, &funcs)?;
+                dump_spd(&subargs, addr, &buf, header)?;
+                header = false;
+                continue;
             }
+            Ok(_) => {}
+            Err(_) => continue,
+        }
+
+        if let Err(err) = &paged {
+            bail!("failed to set page to 0: {}", err);
+        }
+
+        let mut ops = base.clone();
+
+        //
+        // Issue the read for the bottom 128 bytes from the 0 page
+        //
+        let dev = spd::Function::Memory(addr).to_device_code().unwrap();
+        ops.push(Op::Push(dev));
+        ops.push(Op::Push(0));
+        ops.push(Op::Push(128));
+        ops.push(Op::Call(i2c_read.id));
+
+        //
+        // And now read the top 128 bytes from the 0 page...
+        //
+        ops.push(Op::DropN(2));
+        ops.push(Op::Push(128));
+        ops.push(Op::Push(128));
+        ops.push(Op::Call(i2c_read.id));
+
+        //
+        // Switch to the 1 page
+        //
+        ops.push(Op::DropN(3));
+        set_page(&mut ops, i2c_write, 1);
+
+        //
+        // Issue an identical read for the bottom 128 bytes...
+        //
+        ops.push(Op::Push(dev));
+        ops.push(Op::Push(0));
+        ops.push(Op::Push(128));
+        ops.push(Op::Call(i2c_read.id));
+
+        //
+        // ...and the top 128 bytes
+        //
+        ops.push(Op::DropN(2));
+        ops.push(Op::Push(128));
+        ops.push(Op::Push(128));
+        ops.push(Op::Call(i2c_read.id));
+        ops.push(Op::DropN(3));
+
+        //
+        // Finally, set ourselves back to the 0 page
+        //
+        set_page(&mut ops, i2c_write, 0);
+
+        ops.push(Op::Done);
+
+        let results = context.run(core, ops.as_slice(), None)?;
 
-            if buf.len() != SPD_SIZE {
-                bail!("bad SPD length ({} bytes): {:?}", buf.len(), results);
+        //
+        // If that succeeded, we'll have four buffers that should add up
+        // to 512 bytes.
+        //
+        let mut buf = vec![];
+
+        for result in &results {
+            match result {
+                Ok(val) => {
+                    buf.extend_from_slice(val);
+                }
+                Err(_) => {
+                    bail!("failed to read SPD: {:?}", results);
+                }
             }
+        }
 
-            dump_spd(&subargs, addr, &buf, header)?;
-            header = false;
+        if buf.len() != SPD_SIZE {
+            bail!("bad SPD length ({} bytes): {:?}", buf.len(), results);
         }
+
+        dump_spd(&subargs, addr, &buf, header)?;
+        header = false;
     }
 
     Ok(())
@@ -363,3 +747,112 @@ pub fn init() -> (Command, ClapCommand<'static>) {
         SpdArgs::command(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(size: usize, fields: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut buf = vec![0u8; size];
+
+        for (offs, val) in fields {
+            buf[*offs..*offs + val.len()].copy_from_slice(val);
+        }
+
+        buf
+    }
+
+    fn timing(info: &SpdInfo, name: &str) -> u32 {
+        info.timings.iter().find(|(n, _)| *n == name).unwrap().1
+    }
+
+    #[test]
+    fn test_ddr4() {
+        //
+        // A Micron MTA18ASF2G72PZ-2G3B1: a 16 GiB DDR4-2400 RDIMM built
+        // from single-rank x4 8 Gbit parts, with an ECC bus.
+        //
+        let part = format!("{:<20}", "18ASF2G72PZ-2G3B1");
+        let buf = image(
+            SPD_SIZE,
+            &[
+                (2, &[SPD_TYPE_DDR4, 0x01, 0x85, 0x00, 0x00]),
+                (12, &[0x00, 0x0b]),
+                (18, &[0x07]),
+                (24, &[0x6b, 0x6b, 0x6b, 0x11, 0x00, 0x6e]),
+                (120, &[0x00, 0xd5, 0xd5, 0xd5, 0x00, 0xd6]),
+                (320, &[0x80, 0x2c, 0x0f, 0x17, 0x23]),
+                (325, &[0x1a, 0x2b, 0x3c, 0x4d]),
+                (329, part.as_bytes()),
+                (349, &[0x31, 0x80, 0x2c]),
+            ],
+        );
+
+        let info = decode_spd(&buf).unwrap();
+
+        assert_eq!(info.kind, "DDR4");
+        assert_eq!(info.module, "RDIMM");
+        assert_eq!(info.manufacturer, "Micron Technology");
+        assert_eq!(info.dram_manufacturer, "Micron Technology");
+        assert_eq!(info.part, "18ASF2G72PZ-2G3B1");
+        assert_eq!(info.serial, 0x1a2b3c4d);
+        assert_eq!(info.revision, 0x31);
+        assert_eq!((info.year, info.week), (2017, 23));
+        assert_eq!(info.capacity, 16 * 1024);
+        assert_eq!((info.ranks, info.dies), (1, 1));
+        assert_eq!((info.width, info.density), (4, 8 * 1024));
+        assert_eq!(timing(&info, "tCKAVGmin"), 833);
+        assert_eq!(timing(&info, "tAAmin"), 13332);
+        assert_eq!(timing(&info, "tRASmin"), 32000);
+        assert_eq!(timing(&info, "tRCmin"), 45750);
+    }
+
+    #[test]
+    fn test_ddr5() {
+        //
+        // A Micron MTC20F2085S1RC48BA1: a 32 GiB DDR5-4800 RDIMM built from
+        // dual-rank x8 16 Gbit parts, with two 32-bit subchannels.
+        //
+        let part = format!("{:<30}", "MTC20F2085S1RC48BA1");
+        let buf = image(
+            DDR5_SPD_SIZE,
+            &[
+                (2, &[SPD_TYPE_DDR5, 0x01, 0x04, 0x00, 0x20]),
+                (20, &[0xa0, 0x01]),
+                (30, &[0x80, 0x3e, 0x80, 0x3e, 0x80, 0x3e]),
+                (36, &[0x00, 0x7d, 0x80, 0xbb]),
+                (234, &[0x08, 0x2a]),
+                (512, &[0x80, 0x2c, 0x0f, 0x22, 0x41]),
+                (517, &[0xde, 0xad, 0xbe, 0xef]),
+                (521, part.as_bytes()),
+                (551, &[0x00, 0x80, 0xce]),
+            ],
+        );
+
+        let info = decode_spd(&buf).unwrap();
+
+        assert_eq!(info.kind, "DDR5");
+        assert_eq!(info.module, "RDIMM");
+        assert_eq!(info.manufacturer, "Micron Technology");
+        assert_eq!(info.dram_manufacturer, "Samsung");
+        assert_eq!(info.part, "MTC20F2085S1RC48BA1");
+        assert_eq!(info.serial, 0xdeadbeef);
+        assert_eq!((info.year, info.week), (2022, 41));
+        assert_eq!(info.capacity, 32 * 1024);
+        assert_eq!((info.ranks, info.dies), (2, 1));
+        assert_eq!((info.width, info.density), (8, 16 * 1024));
+        assert_eq!(timing(&info, "tCKAVGmin"), 416);
+        assert_eq!(timing(&info, "tAAmin"), 16000);
+        assert_eq!(timing(&info, "tRASmin"), 32000);
+        assert_eq!(timing(&info, "tRCmin"), 48000);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        assert!(decode_spd(&[]).is_err());
+        assert!(decode_spd(&image(SPD_SIZE, &[(2, &[0x0b])])).is_err());
+
+        let truncated = image(SPD_SIZE, &[(2, &[SPD_TYPE_DDR5])]);
+        assert!(decode_spd(&truncated).is_err());
+    }
+}
@@ -31,9 +31,38 @@
 //! 0x00000010 | 00 00 00 00 ff ff ff 06 12 00 00 00 06          | .............
 //! ```
 //!
+//! The device can be specified with `--device` (`-D`), either by its index
+//! or by its name in the application TOML; when a device is specified by
+//! name, the peripheral is determined by the device (and need not be
+//! specified).  To write bytes and report the bytes read back during the
+//! write (that is, to perform an exchange), use `--exchange` (`-x`):
+//!
+//! ```console
+//! % humility spi -D spi_flash --exchange --write 0x9f,0,0,0 --discard 1
+//! humility: attached to 0483:374e:003C00174741500520383733 via ST-Link V3
+//! humility: SPI master is spi2_driver; spi_flash is device 0
+//!              \/  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f
+//! 0x00000000 | 20 ba 19                                        | ...
+//! ```
+//!
+//! Data read is displayed as bytes by default; to display it as 16- or
+//! 32-bit little-endian words, specify the word size in bytes with
+//! `--word-size` (`--word` is equivalent to `--word-size 4`).  To emit
+//! results as JSON or CSV, use the global `--format` option; each operation
+//! is emitted as a single row with the task, device, bytes written, status,
+//! and bytes read (less any discarded):
+//!
+//! ```console
+//! % humility --format json spi -D spi_flash -x -w 0x9f,0,0,0 -d 1
+//! humility: attached to 0483:374e:003C00174741500520383733 via ST-Link V3
+//! humility: SPI master is spi2_driver; spi_flash is device 0
+//! {"task":"spi2_driver","device":"spi_flash","write":[159,0,0,0],"status":"Ok","read":[32,186,25]}
+//! ```
+//!
 use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
+use humility_cmd::output::{Cell, Column, OutputFormat, Table};
 use humility_cmd::{Archive, Args, Attach, Command, Dumper, Validate};
 
 use std::convert::TryInto;
@@ -72,10 +101,21 @@ struct SpiArgs {
     )]
     nbytes: Option<usize>,
 
+    /// write bytes, reporting the bytes read back during the write
+    #[clap(
+        long, short = 'x', requires = "write",
+        conflicts_with_all = &["read", "nbytes"]
+    )]
+    exchange: bool,
+
     /// print out data read as words rather than bytes
-    #[clap(long, short = 'W', requires = "read")]
+    #[clap(long, short = 'W', conflicts_with = "word-size")]
     word: bool,
 
+    /// size of words (1, 2 or 4 bytes) in which to print out data read
+    #[clap(long, value_name = "bytes", parse(try_from_str = parse_int::parse))]
+    word_size: Option<usize>,
+
     /// interpret the specified number of trailing bytes on a write as a
     /// bigendian address
     #[clap(
@@ -93,10 +133,10 @@ struct SpiArgs {
     littleendian_address: Option<usize>,
 
     /// number of bytes to discard when printing read result
-    #[clap(long, short, value_name = "nbytes", requires = "read")]
+    #[clap(long, short, value_name = "nbytes")]
     discard: Option<usize>,
 
-    /// device open which to operate
+    /// device (by name or index) on which to operate
     #[clap(long, short = 'D', value_name = "device")]
     device: Option<String>,
 }
@@ -159,20 +199,93 @@ pub fn spi_task(
     Ok(task)
 }
 
+///
+/// Resolves a SPI device -- specified either by its name in the application
+/// TOML or by its index (defaulting to device 0) -- to the task that
+/// controls it and the index of the device.
+///
+pub fn spi_device(
+    hubris: &HubrisArchive,
+    peripheral: Option<u8>,
+    device: Option<&str>,
+) -> Result<(HubrisTask, u8)> {
+    let device = match device {
+        Some(device) => device,
+        None => return Ok((spi_task(hubris, peripheral)?, 0)),
+    };
+
+    if let Some(d) =
+        hubris.manifest.spi_devices.iter().find(|d| d.name == device)
+    {
+        if let Some(peripheral) = peripheral {
+            if peripheral != d.controller {
+                bail!(
+                    "device {} is on SPI{}, not SPI{}",
+                    device,
+                    d.controller,
+                    peripheral
+                );
+            }
+        }
+
+        return Ok((spi_task(hubris, Some(d.controller))?, d.index));
+    }
+
+    match parse_int::parse::<u8>(device) {
+        Ok(index) => Ok((spi_task(hubris, peripheral)?, index)),
+        Err(_) => {
+            let all = &hubris.manifest.spi_devices;
+
+            if all.is_empty() {
+                bail!("illegal device {}", device);
+            }
+
+            bail!(
+                "unknown device {}; valid devices: {}",
+                device,
+                all.iter()
+                    .map(|d| d.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+}
+
 fn spi(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = SpiArgs::try_parse_from(subargs)?;
+
+    let size = match (subargs.word, subargs.word_size) {
+        (true, _) => 4,
+        (false, Some(size @ (1 | 2 | 4))) => size,
+        (false, Some(size)) => bail!("invalid word size {}", size),
+        (false, None) => 1,
+    };
+
+    let read = subargs.read || subargs.exchange;
+
+    if (subargs.word || subargs.word_size.is_some()) && !read {
+        bail!("word size can only be specified with a read or exchange");
+    }
+
+    if subargs.discard.is_some() && !read {
+        bail!("bytes can only be discarded on a read or exchange");
+    }
+
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
     let funcs = context.functions()?;
 
     let spi_read = funcs.get("SpiRead", 4)?;
     let spi_write = funcs.get("SpiWrite", 3)?;
 
-    let task = spi_task(hubris, subargs.peripheral)?;
+    let (task, device) =
+        spi_device(hubris, subargs.peripheral, subargs.device.as_deref())?;
+
     let mut ops = vec![];
 
     if let HubrisTask::Task(task) = task {
@@ -181,19 +294,28 @@ fn spi(
         bail!("SPI task cannot be the kernel");
     }
 
-    if let Some(device) = subargs.device {
-        if let Ok(device) = parse_int::parse::<u8>(&device) {
-            ops.push(Op::Push(device));
-        } else {
-            bail!("illegal device {}", device);
-        }
-    } else {
-        // Device 0
-        ops.push(Op::Push(0));
+    ops.push(Op::Push(device));
+
+    let master = &hubris.lookup_module(task)?.name;
+
+    //
+    // If the device was specified by name, we refer to it by name.
+    //
+    let name = subargs
+        .device
+        .as_deref()
+        .filter(|d| parse_int::parse::<u8>(d).is_err());
+
+    match name {
+        Some(name) => humility::msg!(
+            "SPI master is {}; {} is device {}",
+            master,
+            name,
+            device
+        ),
+        None => humility::msg!("SPI master is {}", master),
     }
 
-    humility::msg!("SPI master is {}", hubris.lookup_module(task)?.name);
-
     let mut addr = 0;
 
     let data = if let Some(ref write) = subargs.write {
@@ -257,8 +379,17 @@ fn spi(
         None
     };
 
+    //
+    // An exchange is a read of as many bytes as we write:  because SPI is
+    // full duplex, we report the bytes that were read back as we wrote.
+    //
+    let nbytes = match (&data, subargs.exchange) {
+        (Some(data), true) => Some(data.len()),
+        _ => subargs.nbytes,
+    };
+
     let discard = if let Some(discard) = subargs.discard {
-        if discard > subargs.nbytes.unwrap() {
+        if discard > nbytes.unwrap() {
             bail!("cannot discard more than specified number of bytes");
         }
         discard
@@ -266,8 +397,8 @@ fn spi(
         0
     };
 
-    if subargs.read {
-        ops.push(Op::Push32(subargs.nbytes.unwrap() as u32));
+    if read {
+        ops.push(Op::Push32(nbytes.unwrap() as u32));
         ops.push(Op::Call(spi_read.id));
     } else {
         ops.push(Op::Call(spi_write.id));
@@ -284,15 +415,63 @@ fn spi(
         },
     )?;
 
-    if subargs.read {
+    if args.format != OutputFormat::Table {
+        let func = if read { spi_read } else { spi_write };
+
+        let mut table = Table::new(
+            args.format,
+            vec![
+                Column::new("task", 15),
+                Column::new("device", 6),
+                Column::new("write", 0),
+                Column::new("status", 12),
+                Column::new("read", 0),
+            ],
+        );
+
+        let (status, value) = match results.get(0) {
+            None => (Cell::from("TimedOut"), Cell::None),
+            Some(Ok(val)) if read => (
+                Cell::from("Ok"),
+                Cell::Bytes(val.get(discard..).unwrap_or(&[]).to_vec()),
+            ),
+            Some(Ok(_)) => (Cell::from("Ok"), Cell::None),
+            Some(Err(err)) => (Cell::from(func.strerror(*err)), Cell::None),
+        };
+
+        table.row(vec![
+            master.as_str().into(),
+            match name {
+                Some(name) => name.into(),
+                None => Cell::Unsigned(device.into()),
+            },
+            data.map(Cell::Bytes).into(),
+            status,
+            value,
+        ])?;
+
+        return Ok(());
+    }
+
+    if read {
         if let Ok(results) = &results[0] {
             if results.len() < discard {
                 bail!("short read: {:x?}", results);
             }
 
+            let results = &results[discard..];
+
+            if results.len() % size != 0 {
+                bail!(
+                    "{} bytes read is not a multiple of the word size ({})",
+                    results.len(),
+                    size
+                );
+            }
+
             let mut dumper = Dumper::new();
-            dumper.size = if subargs.word { 4 } else { 1 };
-            dumper.dump(&results[discard..], addr);
+            dumper.size = size;
+            dumper.dump(results, addr);
 
             return Ok(());
         }
@@ -22,12 +22,47 @@
 //!  6 idle               0x20002800        256        104        152
 //! ```
 //!
+//! To additionally show the depth of each task's stack as of its last
+//! context switch (that is, from its saved stack pointer), use `-c`
+//! (`--current`):
+//!
+//! ```console
+//! % humility -d ./hubris.core.10 stackmargin --current
+//! humility: attached to dump
+//! ID TASK                STACKBASE  STACKSIZE   MAXDEPTH     MARGIN    CURRENT
+//!  0 jefe               0x20001000       1024        768        256        192
+//!  1 rcc_driver         0x20001400       1024        176        848        112
+//!  2 usart_driver       0x20001800       1024        216        808        144
+//!  3 user_leds          0x20001c00       1024        208        816        136
+//!  4 ping               0x20002000        512        224        288        160
+//!  5 pong               0x20002400       1024        208        816        136
+//!  6 idle               0x20002800        256        104        152         72
+//! ```
+//!
+//! To flag tasks that are running low on stack, specify a margin with `-m`
+//! (`--margin`), either in bytes or as a percentage of the stack size.  Any
+//! task whose margin is at or below the specified margin is marked with `!`,
+//! and the command fails (exiting with a non-zero status) -- making it
+//! suitable for use in CI:
+//!
+//! ```console
+//! % humility -d ./hubris.core.10 stackmargin --margin 30%
+//! humility: attached to dump
+//! ID TASK                STACKBASE  STACKSIZE   MAXDEPTH     MARGIN
+//!  0 jefe               0x20001000       1024        768        256 !
+//! ...
+//! humility stackmargin failed: 1 of 7 tasks within margin
+//! ```
+//!
+//! To emit the results as JSON (e.g., for tracking stack usage over time),
+//! use `-j` (`--json`).
+//!
 //! Note that the margin is only valid for the task's lifetime -- and in
 //! particular, will not be correct if the task has restarted due to a
 //! stack overflow!
 //!
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::core::Core;
@@ -37,15 +72,95 @@ use std::convert::TryInto;
 
 #[derive(Parser, Debug)]
 #[clap(name = "stackmargin", about = env!("CARGO_PKG_DESCRIPTION"))]
-struct StackmarginArgs {}
+struct StackmarginArgs {
+    /// flag tasks with no more than the specified margin, in bytes or as a
+    /// percentage of stack size (e.g., "20%")
+    #[clap(
+        long, short, value_name = "margin",
+        parse(try_from_str = parse_margin)
+    )]
+    margin: Option<Margin>,
+
+    /// show the current depth of each stack
+    #[clap(long, short)]
+    current: bool,
 
-#[rustfmt::skip::macros(println, bail)]
+    /// emit results as JSON
+    #[clap(long, short)]
+    json: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Margin {
+    Bytes(usize),
+    Percent(f64),
+}
+
+impl Margin {
+    fn exceeded(&self, size: usize, margin: usize) -> bool {
+        match self {
+            Margin::Bytes(bytes) => margin <= *bytes,
+            Margin::Percent(pct) => {
+                (margin as f64) <= (size as f64) * pct / 100.0
+            }
+        }
+    }
+}
+
+fn parse_margin(s: &str) -> Result<Margin> {
+    if let Some(pct) = s.strip_suffix('%') {
+        let pct: f64 = pct
+            .parse()
+            .with_context(|| format!("invalid percentage \"{}\"", s))?;
+
+        if !(0.0..=100.0).contains(&pct) {
+            bail!("percentage must be between 0 and 100");
+        }
+
+        Ok(Margin::Percent(pct))
+    } else {
+        Ok(Margin::Bytes(parse_int::parse::<usize>(s)?))
+    }
+}
+
+struct StackUsage {
+    id: u32,
+    name: String,
+    base: u32,
+    size: usize,
+    depth: usize,
+    current: Option<usize>,
+    flagged: bool,
+}
+
+impl StackUsage {
+    fn used(&self) -> f64 {
+        self.depth as f64 * 100.0 / self.size as f64
+    }
+
+    fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "task": self.name,
+            "stackbase": self.base,
+            "stacksize": self.size,
+            "maxdepth": self.depth,
+            "margin": self.size - self.depth,
+            "current": self.current,
+            "used": self.used(),
+            "flagged": self.flagged,
+        })
+    }
+}
+
+#[rustfmt::skip::macros(print, println, bail)]
 fn stackmargin(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
     _args: &Args,
-    _subargs: &[String],
+    subargs: &[String],
 ) -> Result<()> {
+    let subargs = StackmarginArgs::try_parse_from(subargs)?;
     let regions = hubris.regions(core)?;
 
     let (base, size) = hubris.task_table(core)?;
@@ -59,8 +174,16 @@ fn stackmargin(
     let descriptor = task.lookup_member("descriptor")?.offset as u32;
     let initial_stack = taskdesc.lookup_member("initial_stack")?.offset as u32;
 
-    println!("{:2} {:18} {:>10} {:>10} {:>10} {:>10}",
-        "ID", "TASK", "STACKBASE", "STACKSIZE", "MAXDEPTH", "MARGIN");
+    //
+    // The saved PSP lets us determine the current depth of each stack; if
+    // we can't find it, we just won't report current depth.
+    //
+    let psp = (|| -> Result<usize> {
+        let save = task.lookup_member("save")?.offset;
+        let state = hubris.lookup_struct_byname("SavedState")?;
+        Ok(save + state.lookup_member("psp")?.offset)
+    })()
+    .ok();
 
     let taskblock32 =
         |o| u32::from_le_bytes(taskblock[o..o + 4].try_into().unwrap());
@@ -75,6 +198,8 @@ fn stackmargin(
         bail!(format!("could not find region for address {:x}", addr));
     };
 
+    let mut usage = vec![];
+
     for i in 0..size {
         let offs = i as usize * task.size;
         let daddr = taskblock32(offs + descriptor as usize);
@@ -107,9 +232,66 @@ fn stackmargin(
             o += 4;
         };
 
-        println!("{:2} {:18} 0x{:<8x} {:10} {:10} {:10}",
-            i, module.name, region.base,
-            size, depth, size - depth);
+        //
+        // A saved PSP outside of the stack (e.g., for a task that has
+        // never run) tells us nothing, so we don't report it.
+        //
+        let current = psp.and_then(|psp| {
+            let sp = taskblock32(offs + psp);
+
+            if sp >= region.base && sp <= initial {
+                Some((initial - sp) as usize)
+            } else {
+                None
+            }
+        });
+
+        let flagged =
+            subargs.margin.map_or(false, |m| m.exceeded(size, size - depth));
+
+        usage.push(StackUsage {
+            id: i,
+            name: module.name.clone(),
+            base: region.base,
+            size,
+            depth,
+            current,
+            flagged,
+        });
+    }
+
+    if subargs.json {
+        let json: Vec<_> = usage.iter().map(|u| u.json()).collect();
+        println!("{}", serde_json::Value::from(json));
+    } else {
+        print!("{:2} {:18} {:>10} {:>10} {:>10} {:>10}",
+            "ID", "TASK", "STACKBASE", "STACKSIZE", "MAXDEPTH", "MARGIN");
+
+        if subargs.current {
+            print!(" {:>10}", "CURRENT");
+        }
+
+        println!();
+
+        for u in &usage {
+            print!("{:2} {:18} 0x{:<8x} {:10} {:10} {:10}",
+                u.id, u.name, u.base, u.size, u.depth, u.size - u.depth);
+
+            if subargs.current {
+                match u.current {
+                    Some(current) => print!(" {:10}", current),
+                    None => print!(" {:>10}", "-"),
+                }
+            }
+
+            println!("{}", if u.flagged { " !" } else { "" });
+        }
+    }
+
+    let flagged = usage.iter().filter(|u| u.flagged).count();
+
+    if flagged != 0 {
+        bail!("{} of {} tasks within margin", flagged, usage.len());
     }
 
     Ok(())
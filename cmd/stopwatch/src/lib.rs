@@ -0,0 +1,299 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility stopwatch`
+//!
+//! `humility stopwatch` measures the execution time of a function without
+//! instrumenting the firmware.  A hardware breakpoint is set on the entry
+//! to the function; when it is hit, the DWT cycle counter is read and a
+//! second breakpoint is set on the function's return address.  When that
+//! breakpoint is hit (in the same frame), the cycle counter is read again.
+//! Because the cycle counter does not count while the core is halted, the
+//! difference is the number of cycles spent in the function.  This is
+//! repeated for the specified number of invocations (10 by default; set
+//! with `-n`), after which the minimum, mean and maximum are displayed,
+//! both in cycles and -- using the clock frequency from the archive -- in
+//! microseconds:
+//!
+//! ```console
+//! % humility stopwatch -n 100 task_thermal::ThermalControl::run_control
+//! humility: attached via ST-Link V3
+//! humility: timing task_thermal::ThermalControl::run_control (0x8052d5c)
+//! humility: 100 invocations measured at 400000 kHz
+//! STAT           CYCLES       USECS
+//! min             38042       95.11
+//! mean            40110      100.28
+//! max             61734      154.34
+//! ```
+//!
+//! The function may be specified by its full name or by its last path
+//! components (e.g., `run_control`); if the name matches more than one
+//! function (including the same function in more than one task), the
+//! command fails and lists the candidates, any of which can be specified by
+//! address.  Because the measurement is of elapsed cycles, it includes any
+//! time spent in interrupts -- or in other tasks -- while the function is
+//! running.  The target is halted at each breakpoint, which perturbs the
+//! system; this should not be used on a system that is sensitive to being
+//! halted.  If the clock frequency cannot be determined from the archive,
+//! it can be specified in kHz with `--clock`.
+//!
+//! This requires a core with a DWT cycle counter (that is, not ARMv6-M).
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::output::{Cell, Column, Table};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::*;
+use humility_cortex::dwt::*;
+use humility_cortex::fpb::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[clap(name = "stopwatch", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct StopwatchArgs {
+    /// number of invocations to measure
+    #[clap(
+        long = "count", short = 'n', default_value = "10",
+        value_name = "count", parse(try_from_str = parse_int::parse)
+    )]
+    count: u32,
+
+    /// time to wait for each invocation, in milliseconds
+    #[clap(
+        long, short = 'T', default_value = "10000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u64,
+
+    /// clock frequency, in kHz (if not determined from the archive)
+    #[clap(long, value_name = "khz", parse(try_from_str = parse_int::parse))]
+    clock: Option<u32>,
+
+    /// function (name or address) to time
+    function: String,
+}
+
+//
+// Our breakpoint comparators: one on the function entry, and one on the
+// return address.
+//
+const ENTRY: u32 = 0;
+const RETURN: u32 = 1;
+
+fn function(hubris: &HubrisArchive, name: &str) -> Result<(String, u32)> {
+    if let Ok(addr) = parse_int::parse::<u32>(name) {
+        return match hubris.instr_sym(addr) {
+            Some((sym, base)) if base == addr => Ok((sym.to_string(), addr)),
+            _ => bail!("0x{:x} is not the address of a function", addr),
+        };
+    }
+
+    let found = hubris.lookup_functions(name);
+
+    match found.len() {
+        0 => bail!("no function matches \"{}\"", name),
+        1 => Ok((found[0].0.to_string(), found[0].1)),
+        _ => {
+            for (sym, addr, _) in &found {
+                humility::msg!(
+                    "0x{:08x} {} ({})",
+                    addr,
+                    sym,
+                    hubris.instr_mod(*addr).unwrap_or("?")
+                );
+            }
+
+            bail!("\"{}\" matches multiple functions; specify by address", name)
+        }
+    }
+}
+
+//
+// Waits for the core to halt (at a breakpoint), returning its PC and SP.
+//
+fn wait(core: &mut dyn Core, timeout: Duration) -> Result<(u32, u32)> {
+    let started = Instant::now();
+
+    while !DHCSR::read(core)?.halted() {
+        if started.elapsed() > timeout {
+            bail!("timed out waiting for function to be called");
+        }
+
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    Ok((core.read_reg(ARMRegister::PC)?, core.read_reg(ARMRegister::SP)?))
+}
+
+fn measure(
+    core: &mut dyn Core,
+    entry: u32,
+    count: u32,
+    timeout: Duration,
+) -> Result<Vec<u32>> {
+    let mut cycles = vec![];
+
+    fpb_set(core, ENTRY, entry)?;
+    core.run()?;
+
+    while cycles.len() < count as usize {
+        let (pc, sp) = wait(core, timeout)?;
+
+        if pc != entry {
+            bail!("core halted at unexpected address 0x{:x}", pc);
+        }
+
+        let start = DWT_CYCCNT::read(core)?.count();
+        let ret = core.read_reg(ARMRegister::LR)? & !1;
+
+        //
+        // While we wait for the return, we disable the entry breakpoint so
+        // we don't stop on recursive calls.
+        //
+        fpb_clear(core, ENTRY)?;
+        fpb_set(core, RETURN, ret)?;
+
+        loop {
+            core.run()?;
+            let (pc, now) = wait(core, timeout)?;
+
+            //
+            // The return address may be hit by a frame other than ours (for
+            // example, if the caller is itself recursive); on return, the
+            // stack pointer will be what it was on entry.
+            //
+            if pc == ret && now >= sp {
+                break;
+            }
+        }
+
+        let end = DWT_CYCCNT::read(core)?.count();
+        cycles.push(end.wrapping_sub(start));
+
+        fpb_clear(core, RETURN)?;
+        fpb_set(core, ENTRY, entry)?;
+        core.run()?;
+    }
+
+    Ok(cycles)
+}
+
+fn stopwatch(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = StopwatchArgs::try_parse_from(subargs)?;
+
+    if subargs.count == 0 {
+        bail!("must measure at least one invocation");
+    }
+
+    let (name, entry) = function(hubris, &subargs.function)?;
+
+    let khz = match subargs.clock {
+        Some(khz) => Some(khz),
+        None => hubris.clock(core)?,
+    };
+
+    core.halt()?;
+
+    if DWT_CTRL::read(core)?.no_cycle_counter() {
+        core.run()?;
+        bail!("core does not have a cycle counter");
+    }
+
+    //
+    // Save the state that we're going to change so we can restore it.
+    //
+    let demcr = DEMCR::read(core)?;
+    let dwt = DWT_CTRL::read(core)?;
+    let fpctrl = FP_CTRL::read(core)?;
+    let saved = (fpb_read(core, ENTRY)?, fpb_read(core, RETURN)?);
+
+    let mut enabled = demcr;
+    enabled.set_trcena(true);
+    enabled.write(core)?;
+
+    let mut counting = DWT_CTRL::read(core)?;
+    counting.set_cyccnt_enabled(true);
+    counting.write(core)?;
+
+    fpb_enable(core)?;
+
+    humility::msg!("timing {} (0x{:x})", name, entry);
+
+    let timeout = Duration::from_millis(subargs.timeout);
+    let rval = measure(core, entry, subargs.count, timeout);
+
+    //
+    // Regardless of how we fared, restore the FPB and DWT and let the core
+    // run.
+    //
+    core.halt()?;
+    fpb_write(core, ENTRY, saved.0)?;
+    fpb_write(core, RETURN, saved.1)?;
+
+    let mut ctrl = fpctrl;
+    ctrl.set_key(true);
+    ctrl.write(core)?;
+    dwt.write(core)?;
+    demcr.write(core)?;
+    core.run()?;
+
+    let cycles = rval?;
+
+    let n = cycles.len() as u64;
+    let min = *cycles.iter().min().unwrap() as u64;
+    let max = *cycles.iter().max().unwrap() as u64;
+    let mean = cycles.iter().map(|&c| c as u64).sum::<u64>() / n;
+
+    match khz {
+        Some(khz) => {
+            humility::msg!("{} invocations measured at {} kHz", n, khz)
+        }
+        None => humility::msg!(
+            "{} invocations measured; clock unknown (use --clock)",
+            n
+        ),
+    }
+
+    let mut table = Table::new(
+        args.format,
+        vec![
+            Column::new("stat", 6),
+            Column::new("cycles", 12).right(),
+            Column::new("usecs", 11).right(),
+        ],
+    );
+
+    for (stat, val) in [("min", min), ("mean", mean), ("max", max)] {
+        let usecs =
+            khz.map(|khz| Cell::Float(val as f64 * 1000.0 / khz as f64));
+
+        table.row(vec![stat.into(), val.into(), usecs.into()])?;
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "stopwatch",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: stopwatch,
+        },
+        StopwatchArgs::command(),
+    )
+}
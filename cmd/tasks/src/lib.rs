@@ -110,18 +110,88 @@
 //!
 //! These options can naturally be combined, e.g. `humility tasks -slvr`.
 //!
+//! To see which task owns each IRQ (and whether that task is currently
+//! waiting on the notification to which the IRQ is routed), use the `-i`
+//! flag:
+//!
+//! ```console
+//! % humility tasks -i
+//! humility: attached via ST-Link
+//!  IRQ ID TASK               GEN NOTIFICATION WAITING
+//!   39  3 usart_driver         0 0x00000001   true
+//! ```
+//!
+//! To watch tasks as they change, use `-f` (`--follow`), optionally
+//! specifying the polling interval in milliseconds with `--interval`.  The
+//! state of each task is displayed when following begins; thereafter, an
+//! event is displayed whenever a task restarts (that is, its generation
+//! changes), faults, or otherwise changes state, along with the time since
+//! following began and the system time at which the change was observed:
+//!
+//! ```console
+//! % humility tasks -f
+//! humility: attached via ST-Link
+//!       TIME      TICKS ID TASK                 GEN EVENT   STATE
+//!      0.000    1764993  0 jefe                   0 start   recv, notif: bit0 bit1(T+7)
+//! ...
+//!      7.315    1772308  7 pong                   0 fault   FAULT: killed by jefe/gen0 (was: recv, notif: bit0)
+//!      8.321    1773314  7 pong                   1 restart recv, notif: bit0
+//! ```
+//!
+//! Changes that occur between polls are not seen; a task that restarts more
+//! than once between polls is reported as a single restart.  To run a
+//! command when an event occurs, specify the kind of event with
+//! `--notify-on` (one of `restart`, `fault` or `state`; it may be repeated)
+//! and the command with `--notify-command`.  The command is run via `sh -c`
+//! with the `HUMILITY_EVENT`, `HUMILITY_TASK`, `HUMILITY_TASK_ID`,
+//! `HUMILITY_GENERATION`, `HUMILITY_STATE` and `HUMILITY_TICKS` environment
+//! variables describing the event, and following resumes once it completes:
+//!
+//! ```console
+//! % humility tasks -f --notify-on restart --notify-command \
+//!     'logger "$HUMILITY_TASK restarted (gen $HUMILITY_GENERATION)"'
+//! ```
+//!
+//! Tasks (and IRQ ownership) can be emitted as JSON or CSV with the global
+//! `--format` option; stack backtraces, registers and verbose output are
+//! only available in the default table format.
+//!
 
 use anyhow::{bail, Result};
 use clap::Command as ClapCommand;
-use clap::{CommandFactory, Parser};
+use clap::{ArgEnum, CommandFactory, Parser};
 use humility::arch::ARMRegister;
 use humility::core::Core;
 use humility::hubris::*;
-use humility_cmd::doppel::{self, Task, TaskDesc, TaskId, TaskState};
-use humility_cmd::reflect::{self, Format, Load};
+use humility_cmd::doppel::{self, TaskId, TaskState};
+use humility_cmd::kernel::{KernelState, KernelTask};
+use humility_cmd::output::{Cell, Column, OutputFormat, Table};
+use humility_cmd::reflect::Format;
+use humility_cmd::style::Severity;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 use num_traits::FromPrimitive;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum Event {
+    Restart,
+    Fault,
+    State,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Restart => "restart",
+            Event::Fault => "fault",
+            Event::State => "state",
+        }
+    }
+}
 
 #[derive(Parser, Debug)]
 #[clap(name = "tasks", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -146,6 +216,35 @@ struct TasksArgs {
     #[clap(long, short)]
     verbose: bool,
 
+    /// show IRQ ownership
+    #[clap(long, short, conflicts_with_all = &["registers", "stack", "task"])]
+    irqs: bool,
+
+    /// continue to display task state changes as they happen
+    #[clap(
+        long, short,
+        conflicts_with_all = &["registers", "stack", "spin", "verbose", "irqs"]
+    )]
+    follow: bool,
+
+    /// interval between reads when following, in milliseconds
+    #[clap(
+        long, default_value = "1000", value_name = "ms",
+        requires = "follow", parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
+    /// kind of event on which to run the notify command
+    #[clap(
+        long, arg_enum, value_name = "event", multiple_occurrences = true,
+        requires_all = &["follow", "notify-command"]
+    )]
+    notify_on: Vec<Event>,
+
+    /// command to run (via sh -c) when a task event occurs
+    #[clap(long, value_name = "command", requires = "notify-on")]
+    notify_command: Option<String>,
+
     /// single task to display
     task: Option<String>,
 }
@@ -176,18 +275,20 @@ fn print_regs(regs: &BTreeMap<ARMRegister, u32>, additional: bool) {
 fn tasks(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = TasksArgs::try_parse_from(subargs)?;
 
-    let (base, task_count) = hubris.task_table(core)?;
-    let ticks = core.read_word_64(hubris.lookup_variable("TICKS")?.addr)?;
+    if args.format != OutputFormat::Table
+        && (subargs.stack || subargs.registers || subargs.verbose)
+    {
+        bail!("stacks, registers and verbose output require table format");
+    }
 
-    let task_t = hubris.lookup_struct_byname("Task")?;
-    let save = task_t.lookup_member("save")?.offset;
-    let state = hubris.lookup_struct_byname("SavedState")?;
-    let r4 = save + state.lookup_member("r4")?.offset;
+    if subargs.follow {
+        return follow(hubris, core, args, &subargs);
+    }
 
     let mut found = false;
 
@@ -200,47 +301,20 @@ fn tasks(
     loop {
         core.halt()?;
 
-        let cur =
-            core.read_word_32(hubris.lookup_symword("CURRENT_TASK_PTR")?)?;
-
-        //
-        // We read the entire task table at a go to get as consistent a
-        // snapshot as possible.
-        //
-        let mut taskblock = vec![0; task_t.size * task_count as usize];
-        core.read_8(base, &mut taskblock)?;
-
-        let mut tasks = vec![];
-        let mut panicked = false;
-        let mut regs = HashMap::new();
-
-        for i in 0..task_count {
-            let addr = base + i * task_t.size as u32;
-            let offs = i as usize * task_t.size;
-
-            let task_value: reflect::Value =
-                reflect::load(hubris, &taskblock, task_t, offs)?;
-            let task: Task = Task::from_value(&task_value)?;
-
-            //
-            // Always load R4, R5 and R6, which are in the saved state in our
-            // task structure (and are needed to print state).
-            //
-            for r in 4..=6 {
-                let o = offs + r4 + (r - 4) * 4;
-                let v =
-                    u32::from_le_bytes(taskblock[o..o + 4].try_into().unwrap());
-                regs.insert((i, ARMRegister::from_usize(r).unwrap()), v);
+        let kernel = match KernelState::read(hubris, core) {
+            Ok(kernel) => kernel,
+            Err(e) => {
+                core.run()?;
+                return Err(e);
             }
+        };
 
-            tasks.push((addr, task_value, task));
-
-            if let TaskState::Faulted { fault, .. } = task.state {
-                if fault == doppel::FaultInfo::Panic {
-                    panicked = true;
-                }
-            }
-        }
+        let panicked = kernel.tasks.iter().any(|t| {
+            matches!(
+                t.task.state,
+                TaskState::Faulted { fault: doppel::FaultInfo::Panic, .. }
+            )
+        });
 
         let keep_halted = subargs.stack || subargs.registers || panicked;
 
@@ -248,66 +322,84 @@ fn tasks(
             core.run()?;
         }
 
-        println!("system time = {}", ticks);
+        if subargs.irqs {
+            let rval = print_irqs(hubris, &kernel, args.format);
 
-        println!("{:2} {:15} {:>8} {:3} {:9}",
-            "ID", "TASK", "GEN", "PRI", "STATE");
+            if keep_halted {
+                core.run()?;
+            }
 
-        let mut any_names_truncated = false;
+            return rval;
+        }
 
-        for (i, (addr, task_value, task)) in tasks.iter().enumerate() {
-            let i = i as u32;
-            let desc: TaskDesc = task.descriptor.load_from(hubris, core)?;
-            let module =
-                hubris.instr_mod(desc.entry_point).unwrap_or("<unknown>");
+        let mut table = Table::new(
+            args.format,
+            vec![
+                Column::new("id", 2).right(),
+                Column::new("task", 15),
+                Column::new("gen", 8).right(),
+                Column::new("pri", 3).right(),
+                Column::new("state", 9),
+            ],
+        );
+
+        if args.format == OutputFormat::Table {
+            println!("system time = {}", kernel.ticks);
+        }
 
-            let irqs = hubris.manifest.task_irqs.get(module);
+        let mut any_names_truncated = false;
+
+        for ktask in &kernel.tasks {
+            let i = ktask.index;
+            let task = &ktask.task;
 
             if let Some(ref task) = subargs.task {
-                if task != module {
+                if *task != ktask.name {
                     continue;
                 }
 
                 found = true;
             }
 
-            let timer = task.timer.deadline.map(|deadline| {
-                (deadline.0 as i64 - ticks as i64, task.timer.to_post.0)
-            });
+            let mut modname = ktask.name.clone();
 
-            {
-                let mut modname = module.to_string();
-                if modname.len() > 14 {
-                    modname.truncate(14);
-                    modname.push('…');
-                    any_names_truncated = true;
-                }
-                print!(
-                    "{:2} {:15} {:>8} {:3} ",
-                    i,
-                    modname,
-                    u32::from(task.generation),
-                    task.priority.0
-                );
+            if args.format == OutputFormat::Table && modname.len() > 14 {
+                modname.truncate(14);
+                modname.push('…');
+                any_names_truncated = true;
             }
-            explain_state(
+
+            let state = explain_state(
                 hubris,
                 core,
-                i,
-                &regs,
+                ktask,
                 task.state,
-                *addr == cur,
-                irqs,
-                timer,
+                kernel.current == Some(i),
             )?;
-            println!();
+
+            let state = match task.state {
+                TaskState::Faulted { .. } => {
+                    Cell::from(state).styled(Severity::Error)
+                }
+                _ => state.into(),
+            };
+
+            table.row(vec![
+                i.into(),
+                modname.into(),
+                u32::from(task.generation).into(),
+                task.priority.0.into(),
+                state,
+            ])?;
 
             if subargs.stack || subargs.registers {
                 let t = HubrisTask::Task(i);
                 let regs = hubris.registers(core, t)?;
 
                 if subargs.stack {
-                    match hubris.stack(core, t, desc.initial_stack, &regs) {
+                    let initial = ktask.desc.initial_stack;
+
+                    match hubris.stack(core, t, initial, &regs) {
                         Ok(stack) => printer.print(hubris, &stack),
                         Err(e) => {
                             println!("   stack unwind failed: {:?} ", e);
@@ -329,7 +421,7 @@ fn tasks(
                 };
 
                 print!("   |\n   +-----------> ");
-                task_value.format(hubris, fmt, &mut std::io::stdout())?;
+                ktask.value.format(hubris, fmt, &mut std::io::stdout())?;
                 println!("\n");
             }
 
@@ -359,176 +451,411 @@ fn tasks(
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
+//
+// What we remember about each task between polls when following.
+//
+struct Observed {
+    generation: u32,
+    state: TaskState,
+}
+
+fn notify(
+    command: &str,
+    event: Event,
+    ktask: &KernelTask,
+    state: &str,
+    ticks: u64,
+) {
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("HUMILITY_EVENT", event.name())
+        .env("HUMILITY_TASK", &ktask.name)
+        .env("HUMILITY_TASK_ID", ktask.index.to_string())
+        .env(
+            "HUMILITY_GENERATION",
+            u32::from(ktask.task.generation).to_string(),
+        )
+        .env("HUMILITY_STATE", state)
+        .env("HUMILITY_TICKS", ticks.to_string())
+        .status();
+
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            humility::msg!("warning: notify command failed: {}", status);
+        }
+        Err(err) => {
+            humility::msg!("warning: failed to run notify command: {}", err);
+        }
+    }
+}
+
+fn follow(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &TasksArgs,
+) -> Result<()> {
+    if core.is_dump() {
+        bail!("cannot follow tasks in a dump");
+    }
+
+    let mut table = Table::new(
+        args.format,
+        vec![
+            Column::new("time", 10),
+            Column::new("ticks", 10).right(),
+            Column::new("id", 2).right(),
+            Column::new("task", 15),
+            Column::new("gen", 8).right(),
+            Column::new("event", 7),
+            Column::new("state", 9),
+        ],
+    );
+
+    let interval = Duration::from_millis(subargs.interval);
+    let started = Instant::now();
+    let mut observed: BTreeMap<u32, Observed> = BTreeMap::new();
+
+    loop {
+        core.halt()?;
+
+        let kernel = match KernelState::read(hubris, core) {
+            Ok(kernel) => kernel,
+            Err(e) => {
+                core.run()?;
+                return Err(e);
+            }
+        };
+
+        let time = started.elapsed().as_secs_f64();
+
+        //
+        // We determine what has changed -- and explain the new states --
+        // with the target halted, but don't run any notify command until
+        // the target is running again.
+        //
+        let mut events = vec![];
+        let mut found = false;
+
+        for ktask in &kernel.tasks {
+            if let Some(ref task) = subargs.task {
+                if *task != ktask.name {
+                    continue;
+                }
+
+                found = true;
+            }
+
+            let generation = u32::from(ktask.task.generation);
+            let state = ktask.task.state;
+
+            let event = match observed.get(&ktask.index) {
+                None => None,
+                Some(prev) if prev.generation != generation => {
+                    Some(Event::Restart)
+                }
+                Some(prev) if prev.state == state => continue,
+                Some(prev) => match (prev.state, state) {
+                    (TaskState::Healthy(_), TaskState::Faulted { .. }) => {
+                        Some(Event::Fault)
+                    }
+                    _ => Some(Event::State),
+                },
+            };
+
+            observed.insert(ktask.index, Observed { generation, state });
+
+            let explained = match explain_state(
+                hubris,
+                core,
+                ktask,
+                state,
+                kernel.current == Some(ktask.index),
+            ) {
+                Ok(explained) => explained,
+                Err(e) => {
+                    core.run()?;
+                    return Err(e);
+                }
+            };
+
+            events.push((ktask, event, explained));
+        }
+
+        core.run()?;
+
+        if subargs.task.is_some() && !found {
+            bail!("\"{}\" is not a valid task", subargs.task.as_ref().unwrap());
+        }
+
+        for (ktask, event, state) in events {
+            let severity = match (event, ktask.task.state) {
+                (_, TaskState::Faulted { .. }) => Some(Severity::Error),
+                (Some(Event::Restart), _) => Some(Severity::Warning),
+                (Some(_), _) => Some(Severity::Changed),
+                (None, _) => None,
+            };
+
+            let style = |cell: Cell| match severity {
+                Some(severity) => cell.styled(severity),
+                None => cell,
+            };
+
+            table.row(vec![
+                Cell::Float(time),
+                kernel.ticks.into(),
+                ktask.index.into(),
+                ktask.name.as_str().into(),
+                u32::from(ktask.task.generation).into(),
+                style(event.map_or("start", |e| e.name()).into()),
+                style(state.as_str().into()),
+            ])?;
+
+            if let (Some(event), Some(command)) =
+                (event, &subargs.notify_command)
+            {
+                if subargs.notify_on.contains(&event) {
+                    notify(command, event, ktask, &state, kernel.ticks);
+                }
+            }
+        }
+
+        thread::sleep(interval);
+    }
+}
+
+fn print_irqs(
+    hubris: &HubrisArchive,
+    kernel: &KernelState,
+    format: OutputFormat,
+) -> Result<()> {
+    let owners = kernel.irq_owners();
+
+    let mut table = Table::new(
+        format,
+        vec![
+            Column::new("irq", 4).right(),
+            Column::new("id", 2).right(),
+            Column::new("task", 15),
+            Column::new("gen", 6).right(),
+            Column::new("notification", 12),
+            Column::new("waiting", 0),
+        ],
+    );
+
+    for (irq, (ndx, mask)) in &owners {
+        let task = &kernel.tasks[*ndx as usize];
+
+        let waiting = match task.task.state {
+            TaskState::Healthy(doppel::SchedState::InRecv(_)) => {
+                task.saved(ARMRegister::R6) & mask != 0
+            }
+            _ => false,
+        };
+
+        table.row(vec![
+            (*irq).into(),
+            (*ndx).into(),
+            task.name.as_str().into(),
+            u32::from(task.task.generation).into(),
+            Cell::Hex((*mask).into(), 8),
+            Cell::Bool(waiting),
+        ])?;
+    }
+
+    if owners.is_empty() {
+        humility::msg!(
+            "no IRQs found in application \"{}\"",
+            hubris.manifest.name.as_deref().unwrap_or("<unknown>")
+        );
+    }
+
+    Ok(())
+}
+
 fn explain_state(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    task_index: u32,
-    regs: &HashMap<(u32, ARMRegister), u32>,
+    task: &KernelTask,
     ts: TaskState,
     current: bool,
-    irqs: Option<&Vec<(u32, u32)>>,
-    timer: Option<(i64, u32)>,
-) -> Result<()> {
+) -> Result<String> {
+    let mut out = String::new();
+
     match ts {
         TaskState::Healthy(ss) => {
-            explain_sched_state(
-                hubris, task_index, regs, current, irqs, timer, ss,
-            )?;
+            explain_sched_state(&mut out, hubris, task, current, ss)?;
         }
         TaskState::Faulted { fault, original_state } => {
-            explain_fault_info(hubris, core, task_index, regs, fault)?;
-            print!(" (was: ");
+            explain_fault_info(&mut out, hubris, core, task, fault)?;
+            write!(out, " (was: ")?;
             explain_sched_state(
+                &mut out,
                 hubris,
-                task_index,
-                regs,
+                task,
                 current,
-                irqs,
-                timer,
                 original_state,
             )?;
-            print!(")");
+            write!(out, ")")?;
         }
     }
-    Ok(())
+
+    Ok(out)
 }
 
 fn explain_sched_state(
+    out: &mut String,
     hubris: &HubrisArchive,
-    task_index: u32,
-    regs: &HashMap<(u32, ARMRegister), u32>,
+    task: &KernelTask,
     current: bool,
-    irqs: Option<&Vec<(u32, u32)>>,
-    timer: Option<(i64, u32)>,
     e: doppel::SchedState,
 ) -> Result<()> {
     use doppel::SchedState;
 
     match e {
-        SchedState::Stopped => print!("not started"),
+        SchedState::Stopped => write!(out, "not started")?,
         SchedState::Runnable => {
             if current {
-                print!("RUNNING")
+                write!(out, "RUNNING")?
             } else {
-                print!("ready")
+                write!(out, "ready")?
             }
         }
         SchedState::InSend(tid) => {
             if tid == TaskId::KERNEL {
-                print!("HALT: send to kernel");
+                write!(out, "HALT: send to kernel")?;
             } else {
-                print!("wait: send to ");
-                print_task_id(hubris, tid);
+                write!(out, "wait: send to ")?;
+                explain_task_id(out, hubris, tid)?;
             }
         }
         SchedState::InReply(tid) => {
-            print!("wait: reply from ");
-            print_task_id(hubris, tid);
+            write!(out, "wait: reply from ")?;
+            explain_task_id(out, hubris, tid)?;
         }
         SchedState::InRecv(tid) => {
-            let notmask = *regs.get(&(task_index, ARMRegister::R6)).unwrap();
-            explain_recv(hubris, tid, notmask, irqs, timer);
+            let notmask = task.saved(ARMRegister::R6);
+            let timer = task.timer.map(|t| (t.delta, t.to_post));
+            explain_recv(out, hubris, tid, notmask, &task.irqs, timer)?;
         }
     }
     Ok(())
 }
 
-fn print_task_id(hubris: &HubrisArchive, task_id: TaskId) {
+fn explain_task_id(
+    out: &mut String,
+    hubris: &HubrisArchive,
+    task_id: TaskId,
+) -> Result<()> {
     if let Some(n) = hubris.task_name(task_id.index()) {
-        print!("{}/gen{}", n, task_id.generation());
+        write!(out, "{}/gen{}", n, task_id.generation())?;
     } else {
-        print!("unknown#{}/gen{}", task_id.index(), task_id.generation());
+        write!(out, "unknown#{}/gen{}", task_id.index(), task_id.generation())?;
     }
+
+    Ok(())
 }
 
 fn explain_fault_info(
+    out: &mut String,
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    task_index: u32,
-    regs: &HashMap<(u32, ARMRegister), u32>,
+    task: &KernelTask,
     fi: doppel::FaultInfo,
 ) -> Result<()> {
     use doppel::FaultInfo;
 
-    print!("FAULT: ");
+    write!(out, "FAULT: ")?;
     match fi {
-        FaultInfo::DivideByZero => print!("divide by zero"),
-        FaultInfo::IllegalText => print!("jump to non-executable mem"),
-        FaultInfo::IllegalInstruction => print!("illegal instruction"),
+        FaultInfo::DivideByZero => write!(out, "divide by zero")?,
+        FaultInfo::IllegalText => write!(out, "jump to non-executable mem")?,
+        FaultInfo::IllegalInstruction => write!(out, "illegal instruction")?,
         FaultInfo::InvalidOperation(bits) => {
-            print!("general fault, cfsr=0x{:x}", bits);
+            write!(out, "general fault, cfsr=0x{:x}", bits)?;
         }
         FaultInfo::StackOverflow { address } => {
-            print!("stack overflow; sp=0x{:x}", address);
+            write!(out, "stack overflow; sp=0x{:x}", address)?;
         }
         FaultInfo::Injected(task) => {
-            print!("killed by ");
-            print_task_id(hubris, task);
+            write!(out, "killed by ")?;
+            explain_task_id(out, hubris, task)?;
         }
         FaultInfo::MemoryAccess { address, source } => {
-            print!("mem fault (");
+            write!(out, "mem fault (")?;
             if let Some(addr) = address {
-                print!("precise: 0x{:x}", addr);
+                write!(out, "precise: 0x{:x}", addr)?;
             } else {
-                print!("imprecise");
+                write!(out, "imprecise")?;
             }
-            print!(")");
+            write!(out, ")")?;
 
-            explain_fault_source(source);
+            explain_fault_source(out, source)?;
         }
         FaultInfo::BusError { address, source } => {
-            print!("bus fault (");
+            write!(out, "bus fault (")?;
             if let Some(addr) = address {
-                print!("precise: 0x{:x}", addr);
+                write!(out, "precise: 0x{:x}", addr)?;
             } else {
-                print!("imprecise");
+                write!(out, "imprecise")?;
             }
-            print!(")");
+            write!(out, ")")?;
 
-            explain_fault_source(source);
+            explain_fault_source(out, source)?;
         }
         FaultInfo::SyscallUsage(ue) => {
-            print!("in syscall: ");
-            explain_usage_error(ue);
+            write!(out, "in syscall: ")?;
+            explain_usage_error(out, ue)?;
         }
         FaultInfo::Panic => {
-            let msg_base = *regs.get(&(task_index, ARMRegister::R4)).unwrap();
-            let msg_len = *regs.get(&(task_index, ARMRegister::R5)).unwrap();
+            let msg_base = task.saved(ARMRegister::R4);
+            let msg_len = task.saved(ARMRegister::R5);
             let msg_len = msg_len.min(255) as usize;
             let mut buf = vec![0; msg_len];
             core.read_8(msg_base, &mut buf)?;
             match std::str::from_utf8(&buf) {
-                Ok(msg) => print!("{}", msg),
-                Err(_) => print!("panic with invalid message"),
+                Ok(msg) => write!(out, "{}", msg)?,
+                Err(_) => write!(out, "panic with invalid message")?,
             }
         }
         FaultInfo::FromServer(task_id, reason) => {
-            print!("reply fault: task id {}, reason {:?}", task_id, reason);
+            write!(
+                out,
+                "reply fault: task id {}, reason {:?}",
+                task_id, reason
+            )?;
         }
     }
     Ok(())
 }
 
-fn explain_usage_error(e: doppel::UsageError) {
+fn explain_usage_error(out: &mut String, e: doppel::UsageError) -> Result<()> {
     use doppel::UsageError::*;
     match e {
-        BadSyscallNumber => print!("undefined syscall number"),
-        InvalidSlice => print!("sent malformed slice to kernel"),
-        TaskOutOfRange => print!("used bogus task index"),
-        IllegalTask => print!("illegal task operation"),
-        LeaseOutOfRange => print!("bad caller lease index"),
-        OffsetOutOfRange => print!("bad caller lease offset"),
-        NoIrq => print!("referred to undefined interrupt"),
-        BadKernelMessage => print!("sent nonsense IPC to kernel"),
+        BadSyscallNumber => write!(out, "undefined syscall number")?,
+        InvalidSlice => write!(out, "sent malformed slice to kernel")?,
+        TaskOutOfRange => write!(out, "used bogus task index")?,
+        IllegalTask => write!(out, "illegal task operation")?,
+        LeaseOutOfRange => write!(out, "bad caller lease index")?,
+        OffsetOutOfRange => write!(out, "bad caller lease offset")?,
+        NoIrq => write!(out, "referred to undefined interrupt")?,
+        BadKernelMessage => write!(out, "sent nonsense IPC to kernel")?,
     }
+
+    Ok(())
 }
 
-fn explain_fault_source(e: doppel::FaultSource) {
+fn explain_fault_source(
+    out: &mut String,
+    e: doppel::FaultSource,
+) -> Result<()> {
     match e {
-        doppel::FaultSource::User => print!(" in task code"),
-        doppel::FaultSource::Kernel => print!(" in syscall"),
+        doppel::FaultSource::User => write!(out, " in task code")?,
+        doppel::FaultSource::Kernel => write!(out, " in syscall")?,
     }
+
+    Ok(())
 }
 
 /// Heuristic recognition of receive states used by normal programs.
@@ -544,12 +871,13 @@ fn explain_fault_source(e: doppel::FaultSource) {
 ///
 /// - Make common cases unobtrusive and easy to scan.
 fn explain_recv(
+    out: &mut String,
     hubris: &HubrisArchive,
     src: Option<TaskId>,
     notmask: u32,
-    irqs: Option<&Vec<(u32, u32)>>,
+    irqs: &[(u32, u32)],
     timer: Option<(i64, u32)>,
-) {
+) -> Result<()> {
     // Come up with a description for each notification bit.
     struct NoteInfo {
         irqs: Vec<u32>,
@@ -565,14 +893,11 @@ fn explain_recv(
 
         // Collect the IRQs that correspond to this enabled notification mask
         // bit.
-        let irqnums = if let Some(irqs) = irqs {
-            irqs.iter()
-                .filter(|&&(m, _)| m == bitmask)
-                .map(|&(_, n)| n)
-                .collect::<Vec<_>>()
-        } else {
-            vec![]
-        };
+        let irqnums = irqs
+            .iter()
+            .filter(|&&(m, _)| m == bitmask)
+            .map(|&(_, n)| n)
+            .collect::<Vec<_>>();
         let timer_assoc =
             timer.and_then(
                 |(ts, mask)| if mask & bitmask != 0 { Some(ts) } else { None },
@@ -588,40 +913,42 @@ fn explain_recv(
             outer_first = true;
         }
         Some(other) => {
-            print!("recv(");
-            print_task_id(hubris, other);
-            print!(" only)");
+            write!(out, "recv(")?;
+            explain_task_id(out, hubris, other)?;
+            write!(out, " only)")?;
         }
         None => {
-            print!("recv");
+            write!(out, "recv")?;
         }
     }
 
     // Display notification bits, along with meaning where we can.
     if notmask != 0 {
-        print!("{}notif:", if outer_first { "" } else { ", " });
+        write!(out, "{}notif:", if outer_first { "" } else { ", " })?;
         for nt in note_types {
-            print!(" bit{}", nt.bit);
+            write!(out, " bit{}", nt.bit)?;
             if !nt.irqs.is_empty() || nt.timer.is_some() {
-                print!("(");
+                write!(out, "(")?;
                 let mut first = true;
                 if let Some(ts) = nt.timer {
-                    print!("T{:+}", ts);
+                    write!(out, "T{:+}", ts)?;
                     first = false;
                 }
                 for irq in &nt.irqs {
-                    print!("{}irq{}", if !first { "/" } else { "" }, irq);
+                    write!(out, "{}irq{}", if !first { "/" } else { "" }, irq)?;
                     first = false;
                 }
-                print!(")");
+                write!(out, ")")?;
             }
         }
     }
 
     // Flag things that are probably bugs
     if src == Some(TaskId::KERNEL) && notmask == 0 {
-        print!("(DEAD)");
+        write!(out, "(DEAD)")?;
     }
+
+    Ok(())
 }
 
 pub fn init() -> (Command, ClapCommand<'static>) {
@@ -106,17 +106,51 @@
 //! All received packet data will be dumped to the resulting output file,
 //! allowing these transient failures to be differentiated from deeper issues.
 //!
+//! If the archive's test runner has a `TestRunner` Idol interface,
+//! `humility test` instead drives the test runner by way of HIF, running
+//! each case in turn and reporting its result as it completes.  If a case
+//! fails, any fault that occurred while it ran -- including the message of a
+//! failed assertion -- is decoded and displayed:
+//!
+//! ```console
+//! $ humility test
+//! humility: attached via ST-Link
+//! humility: running 22 of 22 cases
+//! humility: running test_send ... ok
+//! humility: running test_recv_reply ... fail
+//!       result: Failed
+//!        fault: suite: panic: panicked at 'assertion failed: false', test/test-suite/src/main.rs:124:5
+//! ...
+//! humility: running test_timer_notify_past ... ok
+//! humility: tests completed: fail
+//! humility: failed: test_recv_reply
+//! humility test failed: 1 of 22 tests failed
+//! ```
+//!
+//! Because the result is an error if any test fails, `humility test` can be
+//! used as a step in CI.  To list the cases, use `--list`; to run only some
+//! cases, specify substrings of their names, e.g. `humility test timer`.
+//! Each case must complete within the timeout (by default, 30 seconds),
+//! which can be changed with `-T`.  When running via the test runner, test
+//! output is not dumped to a file (`-d` and `-o` apply only to ITM).
+//!
 
 use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::core::Core;
 use humility::hubris::*;
+use humility_cmd::doppel::TaskState;
+use humility_cmd::hiffy::*;
+use humility_cmd::idol::{IdolArgument, IdolOperation};
+use humility_cmd::kernel::{describe_fault, KernelState};
+use humility_cmd::style::Severity;
 use humility_cmd::test::*;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 use humility_cortex::itm::*;
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
 use std::time::Instant;
 
 #[derive(Parser, Debug)]
@@ -128,8 +162,29 @@ struct TestArgs {
     /// sets the output file
     #[clap(long, short, value_name = "filename")]
     output: Option<String>,
+
+    /// sets timeout for each test case run via the test runner
+    #[clap(
+        long, short = 'T', default_value = "30000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// list the test cases rather than running them
+    #[clap(long, short)]
+    list: bool,
+
+    /// run only the cases whose names contain any of these substrings
+    #[clap(value_name = "case")]
+    cases: Vec<String>,
 }
 
+/// Idol interface of a test runner that can be driven by way of HIF
+const INTERFACE: &str = "TestRunner";
+
+/// Maximum length of a test case name
+const NAME_MAX: u32 = 64;
+
 fn test_ingest(
     core: &mut dyn Core,
     subargs: &TestArgs,
@@ -282,6 +337,196 @@ fn test_ingest(
     }
 }
 
+struct Runner<'a> {
+    hubris: &'a HubrisArchive,
+    context: HiffyContext<'a>,
+    funcs: HiffyFunctions,
+}
+
+impl<'a> Runner<'a> {
+    fn new(
+        hubris: &'a HubrisArchive,
+        core: &mut dyn Core,
+        timeout: u32,
+    ) -> Result<Self> {
+        let mut context = HiffyContext::new(hubris, core, timeout)?;
+        let funcs = context.functions()?;
+
+        Ok(Self { hubris, context, funcs })
+    }
+
+    ///
+    /// Calls the specified operation, returning its (raw) reply or the name
+    /// of the error.  If `lease` is specified, the operation is lent that
+    /// many bytes of HIF data to write into.
+    ///
+    fn call(
+        &mut self,
+        core: &mut dyn Core,
+        name: &str,
+        args: &[(&str, IdolArgument)],
+        lease: Option<u32>,
+    ) -> Result<std::result::Result<Vec<u8>, String>> {
+        let op = IdolOperation::new(self.hubris, INTERFACE, name, None)?;
+        let leases = (None, lease);
+
+        self.context.idol_call(core, &self.funcs, &op, args, leases, None)
+    }
+
+    fn word(
+        &mut self,
+        core: &mut dyn Core,
+        name: &str,
+        args: &[(&str, IdolArgument)],
+        lease: Option<u32>,
+    ) -> Result<u32> {
+        match self.call(core, name, args, lease)? {
+            Ok(val) if val.len() == 4 => {
+                Ok(u32::from_le_bytes(val[..].try_into()?))
+            }
+            Ok(val) => bail!("unexpected {} reply length {}", name, val.len()),
+            Err(e) => bail!("{} failed: {}", name, e),
+        }
+    }
+
+    fn cases(&mut self, core: &mut dyn Core) -> Result<Vec<String>> {
+        let count = self.word(core, "get_case_count", &[], None)?;
+        let mut rval = vec![];
+
+        for case in 0..count {
+            let args = [("case", IdolArgument::Scalar(case as u64))];
+            let len =
+                self.word(core, "get_case_name", &args, Some(NAME_MAX))?;
+            let name =
+                self.context.read_data(core, 0, len.min(NAME_MAX) as usize)?;
+
+            rval.push(String::from_utf8_lossy(&name).to_string());
+        }
+
+        Ok(rval)
+    }
+}
+
+///
+/// Returns the faults in the system, keyed by task index and generation.
+///
+fn faults(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+) -> Result<BTreeMap<(u32, u32), String>> {
+    core.halt()?;
+    let kernel = KernelState::read(hubris, core);
+    core.run()?;
+
+    let mut rval = BTreeMap::new();
+
+    for task in &kernel?.tasks {
+        if let TaskState::Faulted { fault, .. } = task.task.state {
+            let generation = u32::from(task.task.generation);
+
+            rval.insert(
+                (task.index, generation),
+                format!(
+                    "{}: {}",
+                    task.name,
+                    describe_fault(hubris, core, task, fault)
+                ),
+            );
+        }
+    }
+
+    Ok(rval)
+}
+
+///
+/// Runs the test cases by way of the test runner's Idol interface, reporting
+/// each result as it completes.  Any failure results in an error.
+///
+fn test_idol(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &TestArgs,
+) -> Result<()> {
+    let mut runner = Runner::new(hubris, core, subargs.timeout)?;
+    let cases = runner.cases(core)?;
+
+    let selected = cases
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| {
+            subargs.cases.is_empty()
+                || subargs.cases.iter().any(|c| name.contains(c.as_str()))
+        })
+        .collect::<Vec<_>>();
+
+    if subargs.list {
+        for (ndx, name) in &selected {
+            println!("{:>4} {}", ndx, name);
+        }
+
+        return Ok(());
+    }
+
+    if selected.is_empty() {
+        bail!("no test cases match {}", subargs.cases.join(", "));
+    }
+
+    humility::msg!("running {} of {} cases", selected.len(), cases.len());
+
+    let mut failed = vec![];
+
+    for (ndx, name) in &selected {
+        let before = faults(hubris, core)?;
+
+        print!("humility: running {} ... ", name);
+        std::io::stdout().flush()?;
+
+        let args = [("case", IdolArgument::Scalar(*ndx as u64))];
+
+        let result = match runner.call(core, "run_case", &args, None) {
+            Ok(result) => result,
+            Err(err) => {
+                println!("{}", Severity::Error.paint("fail"));
+                return Err(err.context(format!("{} did not complete", name)));
+            }
+        };
+
+        match result {
+            Ok(_) => println!("{}", Severity::Ok.paint("ok")),
+            Err(e) => {
+                println!("{}", Severity::Error.paint("fail"));
+                println!("{:>12}: {}", "result", e);
+
+                //
+                // Any fault that occurred while running the case (and most
+                // importantly, the panic message of a failed assertion) is
+                // reported as the cause.
+                //
+                for (key, fault) in faults(hubris, core)? {
+                    if !before.contains_key(&key) {
+                        println!("{:>12}: {}", "fault", fault);
+                    }
+                }
+
+                failed.push(name.to_string());
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        humility::msg!("tests completed: {}", Severity::Ok.paint("pass"));
+        return Ok(());
+    }
+
+    humility::msg!("tests completed: {}", Severity::Error.paint("fail"));
+
+    for name in &failed {
+        humility::msg!("failed: {}", name);
+    }
+
+    bail!("{} of {} tests failed", failed.len(), selected.len());
+}
+
 fn test(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
@@ -292,6 +537,14 @@ fn test(
 
     hubris.validate(core, HubrisValidate::Booted)?;
 
+    if IdolOperation::new(hubris, INTERFACE, "run_case", None).is_ok() {
+        return test_idol(hubris, core, &subargs);
+    }
+
+    if subargs.list || !subargs.cases.is_empty() {
+        bail!("selecting test cases requires the {} interface", INTERFACE);
+    }
+
     let stim = 0x0000_ffff;
     let traceid = itm_enable_ingest(core, hubris, stim)?;
     test_ingest(core, &subargs, hubris, traceid)?;
@@ -0,0 +1,548 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility thermal`
+//!
+//! `humility thermal` interacts with the thermal loop via the `Thermal`
+//! Idol interface of the `thermal` task.  With no arguments, it displays
+//! the state that the thermal task exposes -- every `Thermal` operation
+//! that takes no arguments and whose name begins with `get_` is called
+//! (e.g., the control mode, the state of the control loop and the margin)
+//! -- followed by the temperature sensors and their values:
+//!
+//! ```console
+//! % humility thermal
+//! humility: attached via ST-Link V3
+//!             mode: Auto
+//!       auto state: Running
+//!           margin: 0
+//! SENSOR                   TEMP
+//! Southwest               36.21
+//! South                   38.94
+//! Southeast               35.02
+//! ...
+//! ```
+//!
+//! To set the margin (in degrees Celsius) by which the thermal loop is to
+//! keep components below their target temperatures, use `-m` (`--margin`).
+//!
+//! To validate the thermal loop without a special firmware build, the loop
+//! can be overridden temporarily:  `--manual` forces manual fan control at
+//! the specified duty cycle, and `-i` (`--input`) substitutes a synthetic
+//! value for a temperature sensor as an input to the loop (e.g.,
+//! `--input Southwest=85`; this may be specified more than once, and
+//! requires a thermal task that provides the `set_input_override` and
+//! `clear_input_overrides` operations).  While an override is in effect,
+//! the state of the loop and the fan speeds are displayed every second (for
+//! `-c` seconds, or until Control-C is pressed), after which the overrides
+//! are reverted:
+//!
+//! ```console
+//! % humility thermal --input Southwest=85 -c 5
+//! humility: attached via ST-Link V3
+//! humility: overriding Southwest at 85 degrees C; ^C to revert
+//!       STATE     ESE_FAN0     ESE_FAN1     ESE_FAN2     ESE_FAN3
+//!     Running      4871.23      4903.49      4829.81      4862.60
+//!     Running      6312.08      6355.12      6290.44      6301.98
+//!     Running      8107.55      8164.20      8099.02      8112.31
+//!    Critical      9511.93      9570.37      9490.77      9502.14
+//!    Critical      9530.13      9581.20      9502.53      9511.87
+//! humility: reverting input overrides
+//! ```
+//!
+//! When forcing manual control, the thermal task's watchdog (if it has
+//! one) is enabled with a timeout specified by `-w` (`--watchdog`; 10
+//! seconds by default), and the duty cycle is reasserted every second:
+//! should Humility exit without reverting the override (e.g., because the
+//! probe was detached), the thermal task returns itself to automatic
+//! control once the watchdog expires.
+//!
+//! A sample that fails while an override is in effect is handled as
+//! specified by `--on-error` (as with `humility sensors`); by default, it is
+//! retried once.
+//!
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::idol::{IdolArgument, IdolOperation};
+use humility_cmd::retry::RetryPolicy;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+#[clap(name = "thermal", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct ThermalArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// set the thermal margin, in degrees Celsius
+    #[clap(
+        long, short, value_name = "degrees",
+        conflicts_with_all = &["manual", "input"]
+    )]
+    margin: Option<f32>,
+
+    /// temporarily force manual fan control at the specified duty cycle
+    #[clap(
+        long, value_name = "percent",
+        parse(try_from_str = parse_int::parse)
+    )]
+    manual: Option<u8>,
+
+    /// temporarily override the value of a temperature sensor as an input
+    /// to the thermal loop
+    #[clap(
+        long,
+        short,
+        value_name = "sensor=degrees",
+        multiple_occurrences = true
+    )]
+    input: Vec<String>,
+
+    /// number of seconds to hold an override
+    #[clap(
+        long, short, value_name = "seconds",
+        parse(try_from_str = parse_int::parse)
+    )]
+    count: Option<u32>,
+
+    /// timeout of the thermal task's watchdog when forcing manual control,
+    /// in seconds
+    #[clap(
+        long, short, value_name = "seconds", default_value = "10",
+        requires = "manual", parse(try_from_str = parse_int::parse)
+    )]
+    watchdog: u8,
+
+    /// when holding an override, what to do when a sample fails:  fail,
+    /// retry[:N], backoff[:N] or continue
+    #[clap(long, value_name = "policy", default_value = "retry:1")]
+    on_error: String,
+}
+
+struct Thermal<'a> {
+    hubris: &'a HubrisArchive,
+    context: HiffyContext<'a>,
+}
+
+impl<'a> Thermal<'a> {
+    ///
+    /// Returns the names of the operations of the `Thermal` interface that
+    /// take no arguments and whose names begin with `get_`.
+    ///
+    fn getters(&self) -> Result<Vec<&'a str>> {
+        let hubris = self.hubris;
+
+        for t in 0..hubris.ntasks() {
+            let module = hubris.lookup_module(HubrisTask::Task(t as u32))?;
+
+            match &module.iface {
+                Some(iface) if iface.name == "Thermal" => {
+                    return Ok(iface
+                        .ops
+                        .iter()
+                        .filter(|(name, op)| {
+                            name.starts_with("get_") && op.args.is_empty()
+                        })
+                        .map(|(name, _)| name.as_str())
+                        .collect());
+                }
+                _ => {}
+            }
+        }
+
+        bail!("Thermal interface not found; is the 'thermal' task present?");
+    }
+
+    ///
+    /// Makes a single call to the `Thermal` interface, returning `None` if
+    /// the operation is not present in the archive.
+    ///
+    fn call(
+        &mut self,
+        core: &mut dyn Core,
+        operation: &str,
+        args: &[(&str, IdolArgument)],
+    ) -> Result<Option<Result<String, String>>> {
+        let hubris = self.hubris;
+
+        let op = match IdolOperation::new(hubris, "Thermal", operation, None) {
+            Ok(op) => op,
+            Err(_) => return Ok(None),
+        };
+
+        let funcs = self.context.functions()?;
+        let fmt = HubrisPrintFormat::default();
+
+        let leases = (None, None);
+        let reply =
+            self.context.idol_call(core, &funcs, &op, args, leases, None)?;
+
+        Ok(Some(match reply {
+            Ok(val) if val.is_empty() => Ok(String::new()),
+            Ok(val) => Ok(hubris.printfmt(&val, op.ok, &fmt)?),
+            Err(e) => Err(e),
+        }))
+    }
+
+    ///
+    /// Makes a call to the `Thermal` interface that must succeed.
+    ///
+    fn require(
+        &mut self,
+        core: &mut dyn Core,
+        operation: &str,
+        args: &[(&str, IdolArgument)],
+    ) -> Result<String> {
+        match self.call(core, operation, args)? {
+            Some(Ok(val)) => Ok(val),
+            Some(Err(err)) => bail!("Thermal.{} failed: {}", operation, err),
+            None => bail!(
+                "Thermal.{} not found; does the thermal task support it?",
+                operation
+            ),
+        }
+    }
+
+    ///
+    /// Reads the specified sensors via the `sensor` task.
+    ///
+    fn read(
+        &mut self,
+        core: &mut dyn Core,
+        sensors: &[usize],
+    ) -> Result<Vec<Option<f32>>> {
+        let funcs = self.context.functions()?;
+        let op = IdolOperation::new(self.hubris, "Sensor", "get", None)
+            .context("is the 'sensor' task present?")?;
+        let mut payload = op.template(&[("id", IdolArgument::Scalar(0))])?;
+        let mut ops = vec![];
+
+        for id in sensors {
+            payload.set("id", *id as u64)?;
+            self.context.idol_call_ops(
+                &funcs,
+                &op,
+                payload.as_slice(),
+                &mut ops,
+            )?;
+        }
+
+        ops.push(Op::Done);
+
+        Ok(self
+            .context
+            .run(core, ops.as_slice(), None)?
+            .iter()
+            .map(|r| match r {
+                Ok(val) if val.len() >= 4 => {
+                    Some(f32::from_le_bytes(val[0..4].try_into().unwrap()))
+                }
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+fn sensors(hubris: &HubrisArchive, kind: HubrisSensorKind) -> Vec<usize> {
+    (0..hubris.manifest.sensors.len())
+        .filter(|&i| hubris.manifest.sensors[i].kind == kind)
+        .collect()
+}
+
+fn status(thermal: &mut Thermal, core: &mut dyn Core) -> Result<()> {
+    let hubris = thermal.hubris;
+
+    for getter in thermal.getters()? {
+        let label = getter.trim_start_matches("get_").replace('_', " ");
+
+        match thermal.call(core, getter, &[])? {
+            Some(Ok(val)) => println!("{:>16}: {}", label, val),
+            Some(Err(err)) => println!("{:>16}: <{}>", label, err),
+            None => {}
+        }
+    }
+
+    let temps = sensors(hubris, HubrisSensorKind::Temperature);
+
+    println!("{:20} {:>8}", "SENSOR", "TEMP");
+
+    for (id, val) in temps.iter().zip(thermal.read(core, &temps)?) {
+        let name = &hubris.manifest.sensors[*id].name;
+
+        match val {
+            Some(val) => println!("{:20} {:>8.2}", name, val),
+            None => println!("{:20} {:>8}", name, "-"),
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Parses the specified input overrides, returning the sensor ID, the name
+/// and the value of each.
+///
+fn inputs<'a>(
+    hubris: &'a HubrisArchive,
+    subargs: &ThermalArgs,
+) -> Result<Vec<(usize, &'a str, f32)>> {
+    let mut rval = vec![];
+
+    for input in &subargs.input {
+        let (name, value) = match input.split_once('=') {
+            Some((name, value)) => (name, value),
+            None => bail!("input override must be sensor=degrees"),
+        };
+
+        let value: f32 = value
+            .parse()
+            .with_context(|| format!("bad temperature \"{}\"", value))?;
+
+        let id = hubris
+            .manifest
+            .sensors
+            .iter()
+            .position(|s| {
+                s.name == name && s.kind == HubrisSensorKind::Temperature
+            })
+            .with_context(|| format!("{} is not a temperature sensor", name))?;
+
+        rval.push((id, hubris.manifest.sensors[id].name.as_str(), value));
+    }
+
+    Ok(rval)
+}
+
+fn apply(
+    thermal: &mut Thermal,
+    core: &mut dyn Core,
+    pwm: Option<u8>,
+    fans: &[usize],
+    inputs: &[(usize, &str, f32)],
+) -> Result<()> {
+    if let Some(pwm) = pwm {
+        for index in 0..fans.len() {
+            let args = [
+                ("index", IdolArgument::Scalar(index as u64)),
+                ("pwm", IdolArgument::Scalar(pwm as u64)),
+            ];
+
+            thermal.require(core, "set_fan_pwm", &args)?;
+        }
+    }
+
+    for (id, _, value) in inputs {
+        let value = value.to_string();
+        let args = [
+            ("sensor", IdolArgument::Scalar(*id as u64)),
+            ("value", IdolArgument::String(&value)),
+        ];
+
+        thermal.require(core, "set_input_override", &args)?;
+    }
+
+    Ok(())
+}
+
+fn watch(
+    thermal: &mut Thermal,
+    core: &mut dyn Core,
+    subargs: &ThermalArgs,
+    fans: &[usize],
+    inputs: &[(usize, &str, f32)],
+    policy: &RetryPolicy,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let hubris = thermal.hubris;
+
+    print!("{:>12}", "STATE");
+
+    for id in fans {
+        print!(" {:>12}", hubris.manifest.sensors[*id].name.to_uppercase());
+    }
+
+    println!();
+
+    let mut seconds = 0;
+
+    while !stop.load(Ordering::SeqCst) {
+        //
+        // Reassert our overrides:  this services the watchdog in manual
+        // mode, and accounts for the thermal task having restarted.
+        //
+        let sample = policy.sample("sample", || {
+            apply(thermal, core, subargs.manual, fans, inputs)?;
+
+            let state = match thermal.call(core, "get_auto_state", &[])? {
+                Some(Ok(state)) => state,
+                _ => "-".to_string(),
+            };
+
+            Ok((state, thermal.read(core, fans)?))
+        })?;
+
+        let (state, rpms) = match sample {
+            Ok(sample) => sample,
+            Err(err) => {
+                humility::msg!("sample missed: {}", err);
+                ("-".to_string(), vec![None; fans.len()])
+            }
+        };
+
+        print!("{:>12}", state);
+
+        for rpm in rpms {
+            match rpm {
+                Some(rpm) => print!(" {:>12.2}", rpm),
+                None => print!(" {:>12}", "-"),
+            }
+        }
+
+        println!();
+        seconds += 1;
+
+        if let Some(count) = subargs.count {
+            if seconds >= count {
+                break;
+            }
+        }
+
+        thread::sleep(Duration::from_millis(1000));
+    }
+
+    Ok(())
+}
+
+fn override_loop(
+    thermal: &mut Thermal,
+    core: &mut dyn Core,
+    subargs: &ThermalArgs,
+) -> Result<()> {
+    let hubris = thermal.hubris;
+    let fans = sensors(hubris, HubrisSensorKind::Speed);
+    let inputs = inputs(hubris, subargs)?;
+    let policy: RetryPolicy = subargs.on_error.parse()?;
+    let mut watchdog = false;
+
+    let mode = thermal.call(core, "get_mode", &[])?;
+    let auto = matches!(mode, Some(Ok(ref mode)) if mode == "Auto");
+
+    if let Some(pwm) = subargs.manual {
+        if pwm > 100 {
+            bail!("PWM must be a percentage between 0 and 100");
+        }
+
+        let args =
+            [("timeout_s", IdolArgument::Scalar(subargs.watchdog.into()))];
+
+        match thermal.call(core, "enable_watchdog", &args)? {
+            Some(Ok(_)) => watchdog = true,
+            Some(Err(err)) => bail!("failed to enable watchdog: {}", err),
+            None => humility::msg!(
+                "thermal task has no watchdog; manual control will not be \
+                reverted if humility exits abnormally"
+            ),
+        }
+
+        let args = [("initial_pwm", IdolArgument::Scalar(pwm as u64))];
+        thermal.require(core, "set_mode_manual", &args)?;
+
+        humility::msg!("forcing manual control at {}% PWM", pwm);
+    }
+
+    if !inputs.is_empty() {
+        apply(thermal, core, None, &fans, &inputs)?;
+
+        let overrides: Vec<String> = inputs
+            .iter()
+            .map(|(_, name, value)| format!("{} at {} degrees C", name, value))
+            .collect();
+
+        humility::msg!("overriding {}", overrides.join(", "));
+    }
+
+    humility::msg!("^C to revert");
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let s = stop.clone();
+
+    ctrlc::set_handler(move || {
+        s.store(true, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    //
+    // Whatever happens while watching, we want to revert our overrides.
+    //
+    let watched = watch(thermal, core, subargs, &fans, &inputs, &policy, &stop);
+
+    if !inputs.is_empty() {
+        humility::msg!("reverting input overrides");
+        thermal.require(core, "clear_input_overrides", &[])?;
+    }
+
+    if subargs.manual.is_some() {
+        if auto {
+            humility::msg!("restoring thermal task to automatic mode");
+            thermal.require(core, "set_mode_auto", &[])?;
+        }
+
+        if watchdog {
+            thermal.require(core, "disable_watchdog", &[])?;
+        }
+    }
+
+    watched
+}
+
+fn thermalcmd(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = ThermalArgs::try_parse_from(subargs)?;
+    let context = HiffyContext::new(hubris, core, subargs.timeout)?;
+    let mut thermal = Thermal { hubris, context };
+
+    if let Some(margin) = subargs.margin {
+        let margin = margin.to_string();
+        let args = [("margin", IdolArgument::String(&margin))];
+        thermal.require(core, "set_margin", &args)?;
+        humility::msg!("set thermal margin to {} degrees C", margin);
+        return Ok(());
+    }
+
+    if subargs.manual.is_some() || !subargs.input.is_empty() {
+        return override_loop(&mut thermal, core, &subargs);
+    }
+
+    status(&mut thermal, core)
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "thermal",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: thermalcmd,
+        },
+        ThermalArgs::command(),
+    )
+}
@@ -0,0 +1,256 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility timers`
+//!
+//! `humility timers` displays the timer of each task that has one set,
+//! sorted by deadline.  For each timer, the deadline is shown both as an
+//! absolute time and relative to the system time (in ticks), along with an
+//! estimate of the time until the deadline (in milliseconds) and the
+//! notification bits that will be posted when the deadline is reached:
+//!
+//! ```console
+//! % humility timers
+//! humility: attached via ST-Link V3
+//! system time = 1764993
+//! ID TASK                   DEADLINE    DELTA      MS  PERIOD NOTIFICATION STATUS
+//!  9 hiffy                   1765000        7    7.00       - 0x00000001   -
+//! 10 hf                      1765011       18   18.00       - 0x00000001   -
+//!  0 jefe                    1765100      107  107.00       - 0x00000002   -
+//! ```
+//!
+//! A timer is flagged as `expired` if its deadline has passed but it has
+//! not yet fired, and as `pending` if it has fired but its notification
+//! has not yet been received by the task (such a task is shown even though
+//! its timer is no longer set).  Either of these denotes a task
+//! (or, in the case of an expired timer, a kernel) that is not servicing its
+//! timer; a timer that remains `pending` across several invocations is
+//! likely a bug in the task.
+//!
+//! The kernel only records a timer's deadline, not its period.  To estimate
+//! the period of each timer, use `-s` (`--samples`) to take several samples,
+//! `-i` (`--interval`) milliseconds apart; the period is the difference
+//! between successive deadlines of a timer that is re-armed.  Sampling also
+//! allows the tick rate to be measured; otherwise, a tick is assumed to be
+//! one millisecond.  When sampling, the timers of the final sample are
+//! shown.  To show every task, including those without a timer set, use
+//! `-a` (`--all`).
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::kernel::{KernelState, KernelTask};
+use humility_cmd::output::{Cell, Column, OutputFormat, Table};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[clap(name = "timers", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct TimersArgs {
+    /// show all tasks, not just those with a timer set
+    #[clap(long, short)]
+    all: bool,
+
+    /// number of samples to take to estimate timer periods
+    #[clap(
+        long, short, default_value = "1", value_name = "count",
+        parse(try_from_str = parse_int::parse)
+    )]
+    samples: u32,
+
+    /// interval between samples, in milliseconds
+    #[clap(
+        long, short, default_value = "100", value_name = "ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+}
+
+//
+// The tick rate (in ticks per second) that we assume if we can't measure it.
+//
+const DEFAULT_TICK_RATE: f64 = 1000.0;
+
+fn read(hubris: &HubrisArchive, core: &mut dyn Core) -> Result<KernelState> {
+    core.halt()?;
+    let kernel = KernelState::read(hubris, core);
+    core.run()?;
+    kernel
+}
+
+//
+// Returns the notifications pending for a task, if the kernel's task
+// structure has them.
+//
+fn notifications(task: &KernelTask) -> Option<u32> {
+    let value = task.value.as_struct().ok()?;
+
+    value
+        .iter()
+        .find(|(name, _)| *name == "notifications")
+        .and_then(|(_, v)| v.as_base().ok()?.as_u32())
+}
+
+fn timers(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = TimersArgs::try_parse_from(subargs)?;
+
+    if subargs.samples == 0 {
+        bail!("must take at least one sample");
+    }
+
+    if subargs.samples > 1 && core.is_dump() {
+        bail!("cannot take multiple samples of a dump");
+    }
+
+    let interval = Duration::from_millis(subargs.interval);
+    let started = Instant::now();
+
+    let first = read(hubris, core)?;
+    let mut kernel = first.clone();
+
+    //
+    // For each task, the last deadline that we saw and the period between
+    // the last two distinct deadlines.
+    //
+    let mut deadlines: HashMap<u32, u64> = HashMap::new();
+    let mut periods: HashMap<u32, u64> = HashMap::new();
+
+    for _ in 1..subargs.samples {
+        for task in &kernel.tasks {
+            if let Some(timer) = task.timer {
+                deadlines.insert(task.index, timer.deadline);
+            }
+        }
+
+        thread::sleep(interval);
+        kernel = read(hubris, core)?;
+
+        for task in &kernel.tasks {
+            let timer = match task.timer {
+                Some(timer) => timer,
+                None => continue,
+            };
+
+            if let Some(&last) = deadlines.get(&task.index) {
+                if timer.deadline > last {
+                    periods.insert(task.index, timer.deadline - last);
+                }
+            }
+        }
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let rate = if subargs.samples > 1 && kernel.ticks > first.ticks {
+        (kernel.ticks - first.ticks) as f64 / elapsed
+    } else {
+        DEFAULT_TICK_RATE
+    };
+
+    if args.format == OutputFormat::Table {
+        println!("system time = {}", kernel.ticks);
+    }
+
+    if subargs.samples > 1 {
+        humility::msg!("measured tick rate of {:.1} ticks/sec", rate);
+    }
+
+    let mut table = Table::new(
+        args.format,
+        vec![
+            Column::new("id", 2).right(),
+            Column::new("task", 15),
+            Column::new("deadline", 12).right(),
+            Column::new("delta", 8).right(),
+            Column::new("ms", 10).right(),
+            Column::new("period", 7).right(),
+            Column::new("notification", 12),
+            Column::new("status", 6),
+        ],
+    );
+
+    //
+    // A task is shown if its timer is set or if its timer has fired but its
+    // notification is still pending -- or if we're showing everything.
+    //
+    let mut shown = kernel
+        .tasks
+        .iter()
+        .filter_map(|task| {
+            let to_post = task.task.timer.to_post.0;
+            let pending = to_post != 0
+                && notifications(task).map_or(false, |n| n & to_post != 0);
+
+            if task.timer.is_some() || pending || subargs.all {
+                Some((task, pending))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    shown.sort_by_key(|(task, _)| {
+        (task.timer.is_none(), task.timer.map(|t| t.deadline), task.index)
+    });
+
+    for (task, pending) in shown {
+        let period = periods.get(&task.index).copied();
+        let to_post = task.task.timer.to_post.0;
+
+        let status = match task.timer {
+            Some(timer) if timer.delta < 0 => Some("expired"),
+            _ if pending => Some("pending"),
+            _ => None,
+        };
+
+        let (deadline, delta, ms) = match task.timer {
+            Some(timer) => (
+                timer.deadline.into(),
+                timer.delta.into(),
+                Cell::Float(timer.delta as f64 * 1000.0 / rate),
+            ),
+            None => (Cell::None, Cell::None, Cell::None),
+        };
+
+        table.row(vec![
+            task.index.into(),
+            task.name.as_str().into(),
+            deadline,
+            delta,
+            ms,
+            period.into(),
+            if to_post != 0 {
+                Cell::Hex(to_post.into(), 8)
+            } else {
+                Cell::None
+            },
+            status.into(),
+        ])?;
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "timers",
+            archive: Archive::Required,
+            attach: Attach::Any,
+            validate: Validate::Booted,
+            run: timers,
+        },
+        TimersArgs::command(),
+    )
+}
@@ -2,28 +2,181 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{anyhow, Result};
+//! ## `humility trace`
+//!
+//! `humility trace` traces the scheduling of Hubris tasks via ITM, on a
+//! kernel that has been built with task-switch instrumentation (which emits
+//! the index of each task switched to on stimulus port 30, and each change
+//! of a task's scheduling state on stimulus port 31).  By default, each
+//! event is displayed as it is received; to emit output suitable for
+//! `statemap`, use `-s` (`--statemap`).
+//!
+//! To reconstruct the execution timeline of each task, use `-t`
+//! (`--timeline`).  Trace is collected for a specified duration (5 seconds
+//! by default; use `-d` to specify a different duration in seconds), with
+//! exception trace enabled so that the preemption of tasks by interrupts can
+//! be seen.  The timeline is rendered with one row per task (and a row for
+//! interrupts), followed by a summary of each task's share of run time, the
+//! number of times it was switched to, its context-switch latency (the time
+//! from when it became runnable to when it was switched to), and the time
+//! that it was preempted by interrupts:
+//!
+//! ```console
+//! % humility trace --timeline -d 2
+//! humility: attached via ST-Link V3
+//! humility: tracing for 2 seconds
+//!                  |0.000s                                        2.000s|
+//! jefe             |                                                    |
+//! net              |+    ++ ++     +     +  ++           +++   +        |
+//! sys              |             +         + +       + +    +           |
+//! i2c_driver       | ++ ++ ++++++  + + +  ++  ++++++++++ ++ ++ +++ +++ +|
+//! ...
+//! idle             |###############################  ###################|
+//! (interrupts)     |+ +++++ +++ + +++ +    ++ ++++++ +++++  ++++++++++++|
+//! TASK              RUN%  SWITCHES   MEANLAT    MAXLAT  PREEMPTED
+//! jefe              0.00         0         -         -          -
+//! net               1.61       412    11.2us    87.5us     40.1us
+//! sys               0.05        34     8.9us    12.1us          -
+//! i2c_driver        2.95      1040     7.3us    31.0us    103.4us
+//! ...
+//! idle             94.12      1493         -         -      2.1ms
+//! ```
+//!
+//! In the timeline, `#` denotes that a task ran for most of an interval and
+//! `+` that it ran for some of it; `.` denotes that a task was runnable but
+//! not running for some of the interval.  To export the timeline to a file
+//! that can be loaded into Perfetto (<https://ui.perfetto.dev>), specify the
+//! file with `-p` (`--perfetto`); the file contains a track for each task
+//! (showing when it was running and when it was waiting to run) and a track
+//! for each interrupt.
+//!
+//! To correlate the trace with the phases of a test running on the host,
+//! markers injected into the ITM stream (see `humility itm`) can be decoded
+//! by specifying their stimulus port with `--markers`; with
+//! `--markers-from`, each line appended to the specified file is injected
+//! as a marker while tracing.  Markers are displayed in sequence with the
+//! events around them; in a timeline, they are shown in a row of their own
+//! (and listed with their times), and they are exported to Perfetto as
+//! instant events.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::core::Core;
 use humility::hubris::*;
+use humility_cmd::kernel::KernelState;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::dwt::*;
 use humility_cortex::itm::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryInto;
-use std::time::Instant;
-use std::time::SystemTime;
+use std::fs::File;
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Parser, Debug)]
 #[clap(name = "trace", about = env!("CARGO_PKG_DESCRIPTION"))]
 struct TraceArgs {
     /// provide statemap-ready output
-    #[clap(long, short)]
+    #[clap(long, short, conflicts_with_all = &["timeline", "perfetto"])]
     statemap: bool,
+
+    /// reconstruct and display the execution timeline of each task
+    #[clap(long, short)]
+    timeline: bool,
+
+    /// export the execution timeline to the specified file for Perfetto
+    #[clap(long, short, value_name = "file")]
+    perfetto: Option<String>,
+
+    /// duration of a timeline, in seconds
+    #[clap(
+        long, short, value_name = "seconds", default_value = "5",
+        parse(try_from_str = parse_int::parse)
+    )]
+    duration: u64,
+
+    /// decode markers on the specified stimulus port
+    #[clap(long, value_name = "port",
+        parse(try_from_str = parse_int::parse),
+    )]
+    markers: Option<u8>,
+
+    /// inject each line appended to the specified file as a marker
+    #[clap(long, value_name = "file", requires = "markers")]
+    markers_from: Option<String>,
+}
+
+///
+/// Rate of the ITM local timestamp counter
+///
+const TIMESTAMP_HZ: f64 = 16_000_000_f64;
+
+///
+/// Hardware source packet ID of an exception trace packet
+///
+const ITM_EXCEPTION_TRACE: u32 = 1;
+
+///
+/// Exception trace functions:  an exception has been entered, exited, or
+/// returned to.
+///
+const EXCEPTION_ENTERED: u8 = 1;
+const EXCEPTION_EXITED: u8 = 2;
+
+///
+/// Exception number of SVCall; syscalls are made by a task (rather than
+/// preempting it), so we don't count them as preemption.
+///
+const EXCEPTION_SVCALL: u32 = 11;
+
+///
+/// Number of columns in a rendered timeline
+///
+const TIMELINE_COLUMNS: usize = 52;
+
+///
+/// Returns the definition of the kernel's `SchedState`, as emitted on
+/// stimulus port 31.
+///
+fn schedstate(hubris: &HubrisArchive) -> Result<&HubrisEnum> {
+    let tstruct = hubris.lookup_struct_byname("Task")?;
+    let state = tstruct.lookup_member("state")?;
+    let state_enum = hubris.lookup_enum(state.goff)?;
+    let healthy = state_enum.lookup_variant_byname("Healthy")?;
+    let hh = hubris.lookup_struct(
+        healthy.goff.ok_or_else(|| anyhow!("incomplete Healthy structure"))?,
+    )?;
+
+    hubris.lookup_enum(hh.lookup_member("__0")?.goff)
 }
 
 #[rustfmt::skip::macros(println)]
 
+fn marker_file(subargs: &TraceArgs) -> Result<Option<ITMMarkerFile>> {
+    match &subargs.markers_from {
+        Some(filename) => Ok(Some(
+            ITMMarkerFile::open(filename)
+                .with_context(|| format!("failed to open {}", filename))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+///
+/// Returns the stimulus ports to enable:  those of the kernel's
+/// instrumentation, and that of markers (if any).
+///
+fn stimuli(subargs: &TraceArgs) -> Result<u32> {
+    match subargs.markers {
+        Some(port) if port < 32 => Ok(0xf000_0000 | (1 << port)),
+        Some(port) => bail!("invalid stimulus port {}", port),
+        None => Ok(0xf000_0000),
+    }
+}
+
 fn tracecmd_ingest(
     core: &mut dyn Core,
     subargs: &TraceArgs,
@@ -54,12 +207,18 @@ fn tracecmd_ingest(
         println!("\t\"entityKind\": \"Task\",");
 
         println!("\t\"states\": {{");
-        println!("\t\t\"Running\": \
-            {{ \"value\": 0, \"color\": \"#DAF7A6\" }},");
-        println!("\t\t\"Runnable\": \
-            {{ \"value\": 1, \"color\": \"#9BC362\" }},");
-        println!("\t\t\"InRecv\": \
-            {{ \"value\": 2, \"color\": \"#e0e0e0\" }},");
+        println!(
+            "\t\t\"Running\": \
+            {{ \"value\": 0, \"color\": \"#DAF7A6\" }},"
+        );
+        println!(
+            "\t\t\"Runnable\": \
+            {{ \"value\": 1, \"color\": \"#9BC362\" }},"
+        );
+        println!(
+            "\t\t\"InRecv\": \
+            {{ \"value\": 2, \"color\": \"#e0e0e0\" }},"
+        );
 
         states.insert("Runnable".to_string(), 1);
         states.insert("InRecv(None)".to_string(), 2);
@@ -73,9 +232,14 @@ fn tracecmd_ingest(
             let state = 3 + i;
 
             states.insert(s, state as i32);
-            println!("\t\t\"InReply({})\": {{ \"value\": {}, \
-                \"color\": \"{}\" }}{}", name, state, colors[i],
-                if i < tasks.len() - 1 { "," } else { "" });
+            println!(
+                "\t\t\"InReply({})\": {{ \"value\": {}, \
+                \"color\": \"{}\" }}{}",
+                name,
+                state,
+                colors[i],
+                if i < tasks.len() - 1 { "," } else { "" }
+            );
         }
 
         println!("\t}}");
@@ -83,28 +247,28 @@ fn tracecmd_ingest(
 
         for i in 0..tasks.len() {
             let name = tasks.get(&(i as u32)).unwrap();
-            println!("{{ \"entity\": \"{}\", \"description\": \"{}\" }}",
-                i, name);
+            println!(
+                "{{ \"entity\": \"{}\", \"description\": \"{}\" }}",
+                i, name
+            );
         }
     }
 
-    let tstruct = hubris.lookup_struct_byname("Task")?;
-    let state = tstruct.lookup_member("state")?;
-    let state_enum = hubris.lookup_enum(state.goff)?;
-    let healthy = state_enum.lookup_variant_byname("Healthy")?;
-    let hh = hubris.lookup_struct(
-        healthy.goff.ok_or_else(|| anyhow!("incomplete Healthy structure"))?,
-    )?;
-
-    let schedstate = hubris.lookup_enum(hh.lookup_member("__0")?.goff)?;
+    let schedstate = schedstate(hubris)?;
     let mut spayload = Vec::with_capacity(schedstate.size);
     let mut task = 0;
     let mut newtask = None;
+    let mut markers = ITMMarkers::default();
+    let mut file = marker_file(subargs)?;
 
     itm_ingest(
         traceid,
         || {
             while ndx == bytes.len() {
+                if let (Some(file), Some(port)) = (&mut file, subargs.markers) {
+                    file.inject(core, port)?;
+                }
+
                 bytes = core.read_swv().unwrap();
                 ts = start.elapsed().as_secs_f64();
                 ndx = 0;
@@ -115,6 +279,20 @@ fn tracecmd_ingest(
         |packet| {
             match &packet.payload {
                 ITMPayload::Instrumentation { payload, port } => {
+                    if Some(*port as u8) == subargs.markers {
+                        for marker in markers.push(payload) {
+                            if !subargs.statemap {
+                                println!(
+                                    "{:.9} marker: {}",
+                                    time as f64 / TIMESTAMP_HZ,
+                                    marker
+                                );
+                            }
+                        }
+
+                        return Ok(());
+                    }
+
                     if *port == 30 {
                         newtask = Some(payload[0] as u32);
                         return Ok(());
@@ -136,24 +314,28 @@ fn tracecmd_ingest(
 
                         if !subargs.statemap {
                             println!(
-                            "{:.9} {} ({}): {}",
-                            time as f64 / 16_000_000_f64,
-                            task,
-                            tasks.get(&task).unwrap_or(&"<invalid>".to_string()),
-                            hubris.print(&spayload[..], schedstate.goff)?,
-                        );
+                                "{:.9} {} ({}): {}",
+                                time as f64 / TIMESTAMP_HZ,
+                                task,
+                                tasks
+                                    .get(&task)
+                                    .unwrap_or(&"<invalid>".to_string()),
+                                hubris.print(&spayload[..], schedstate.goff)?,
+                            );
                             return Ok(());
                         }
 
                         let state =
                             hubris.print(&spayload[..], schedstate.goff)?;
 
-                        println!("{{ \"time\": \"{}\", \"entity\": \"{}\", \
+                        println!(
+                            "{{ \"time\": \"{}\", \"entity\": \"{}\", \
                         \"state\": {} }}",
-                        ((time as f64 / 16_000_000_f64) *
-                        1_000_000_000_f64) as u64,
-                        task, states.get(&state).unwrap_or(&-1)
-                    );
+                            ((time as f64 / TIMESTAMP_HZ) * 1_000_000_000_f64)
+                                as u64,
+                            task,
+                            states.get(&state).unwrap_or(&-1)
+                        );
 
                         return Ok(());
                     }
@@ -174,19 +356,23 @@ fn tracecmd_ingest(
 
                     if let Some(task) = newtask {
                         if subargs.statemap {
-                            println!("{{ \"time\": \"{}\", \"entity\": \"{}\", \
+                            println!(
+                                "{{ \"time\": \"{}\", \"entity\": \"{}\", \
                             \"state\": 0 }}",
-                            ((time as f64 / 16_000_000_f64) *
-                            1_000_000_000_f64) as u64,
-                            task
-                        );
+                                ((time as f64 / TIMESTAMP_HZ)
+                                    * 1_000_000_000_f64)
+                                    as u64,
+                                task
+                            );
                         } else {
                             println!(
-                            "{:.9} {} ({}): Running",
-                            time as f64 / 16_000_000_f64,
-                            task,
-                            tasks.get(&task).unwrap_or(&"<invalid>".to_string())
-                        );
+                                "{:.9} {} ({}): Running",
+                                time as f64 / TIMESTAMP_HZ,
+                                task,
+                                tasks
+                                    .get(&task)
+                                    .unwrap_or(&"<invalid>".to_string())
+                            );
                         }
 
                         newtask = None;
@@ -200,6 +386,589 @@ fn tracecmd_ingest(
     )
 }
 
+#[derive(Copy, Clone, Debug)]
+enum TraceEvent {
+    /// The kernel switched to the specified task
+    Switch(u32),
+    /// The specified task changed its scheduling state, becoming runnable
+    /// (or otherwise)
+    State(u32, bool),
+    /// The specified exception was entered
+    Enter(u32),
+    /// The specified exception was exited
+    Exit(u32),
+}
+
+/// A span of time during which a task was running
+#[derive(Copy, Clone, Debug)]
+struct RunSpan {
+    task: u32,
+    start: u64,
+    end: u64,
+    /// The time at which the task became runnable, if it was observed
+    ready: Option<u64>,
+    /// The time spent in exceptions that preempted the task
+    preempted: u64,
+}
+
+/// A span of time spent in an exception
+#[derive(Copy, Clone, Debug)]
+struct ExceptionSpan {
+    exception: u32,
+    start: u64,
+    end: u64,
+}
+
+#[derive(Debug, Default)]
+struct Timeline {
+    start: u64,
+    end: u64,
+    runs: Vec<RunSpan>,
+    exceptions: Vec<ExceptionSpan>,
+    /// Markers injected by the host, with the times at which they were seen
+    markers: Vec<(u64, String)>,
+}
+
+fn exception_name(
+    exception: u32,
+    tasks: &HashMap<u32, String>,
+    owners: &BTreeMap<u32, (u32, u32)>,
+) -> String {
+    match exception {
+        2 => "NMI".to_string(),
+        3 => "HardFault".to_string(),
+        4 => "MemManage".to_string(),
+        5 => "BusFault".to_string(),
+        6 => "UsageFault".to_string(),
+        11 => "SVCall".to_string(),
+        12 => "DebugMon".to_string(),
+        14 => "PendSV".to_string(),
+        15 => "SysTick".to_string(),
+        n if n >= 16 => {
+            let irq = n - 16;
+
+            match owners.get(&irq).and_then(|(task, _)| tasks.get(task)) {
+                Some(owner) => format!("irq {} ({})", irq, owner),
+                None => format!("irq {}", irq),
+            }
+        }
+        n => format!("exception {}", n),
+    }
+}
+
+fn format_time(ticks: u64) -> String {
+    let us = ticks as f64 / TIMESTAMP_HZ * 1_000_000_f64;
+
+    if us >= 1000.0 {
+        format!("{:.1}ms", us / 1000.0)
+    } else {
+        format!("{:.1}us", us)
+    }
+}
+
+impl Timeline {
+    ///
+    /// Reconstructs a timeline from timestamped trace events.
+    ///
+    fn reconstruct(events: &[(u64, TraceEvent)]) -> Self {
+        let mut timeline = Timeline {
+            start: events.first().map_or(0, |(time, _)| *time),
+            end: events.last().map_or(0, |(time, _)| *time),
+            ..Default::default()
+        };
+
+        let mut current: Option<RunSpan> = None;
+        let mut ready: HashMap<u32, u64> = HashMap::new();
+        let mut stack: Vec<(u32, u64)> = vec![];
+
+        for &(time, event) in events {
+            match event {
+                TraceEvent::Switch(task) => {
+                    if current.map_or(false, |run| run.task == task) {
+                        continue;
+                    }
+
+                    if let Some(mut run) = current.take() {
+                        run.end = time;
+                        timeline.runs.push(run);
+                    }
+
+                    current = Some(RunSpan {
+                        task,
+                        start: time,
+                        end: time,
+                        ready: ready.remove(&task),
+                        preempted: 0,
+                    });
+                }
+
+                TraceEvent::State(task, true) => {
+                    if current.map_or(true, |run| run.task != task) {
+                        ready.entry(task).or_insert(time);
+                    }
+                }
+
+                TraceEvent::State(task, false) => {
+                    ready.remove(&task);
+                }
+
+                TraceEvent::Enter(exception) => {
+                    stack.push((exception, time));
+                }
+
+                TraceEvent::Exit(exception) => {
+                    //
+                    // If we didn't see the exception entered (e.g., because
+                    // tracing began within it), we ignore its exit.
+                    //
+                    let ndx = match stack.iter().rposition(|e| e.0 == exception)
+                    {
+                        Some(ndx) => ndx,
+                        None => continue,
+                    };
+
+                    let start = stack[ndx].1;
+                    stack.truncate(ndx);
+
+                    timeline.exceptions.push(ExceptionSpan {
+                        exception,
+                        start,
+                        end: time,
+                    });
+
+                    if stack.is_empty() && exception != EXCEPTION_SVCALL {
+                        if let Some(run) = &mut current {
+                            run.preempted += time - start;
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(mut run) = current {
+            run.end = timeline.end;
+            timeline.runs.push(run);
+        }
+
+        timeline
+    }
+
+    ///
+    /// Accumulates the time from `start` to `end` in each column of a row of
+    /// a rendered timeline.
+    ///
+    fn fill(&self, row: &mut [f64], start: u64, end: u64) {
+        let width =
+            (self.end - self.start).max(1) as f64 / TIMELINE_COLUMNS as f64;
+        let column = |t: u64| {
+            ((t.saturating_sub(self.start) as f64 / width) as usize)
+                .min(TIMELINE_COLUMNS - 1)
+        };
+
+        for (c, val) in
+            row.iter_mut().enumerate().take(column(end) + 1).skip(column(start))
+        {
+            let lo = self.start as f64 + c as f64 * width;
+            let hi = lo + width;
+            let overlap = (end as f64).min(hi) - (start as f64).max(lo);
+
+            if overlap > 0.0 {
+                *val += overlap / width;
+            }
+        }
+    }
+
+    fn render(&self, tasks: &HashMap<u32, String>) {
+        let ntasks = tasks.len() as u32;
+        let mut running = vec![vec![0.0; TIMELINE_COLUMNS]; ntasks as usize];
+        let mut waiting = vec![vec![0.0; TIMELINE_COLUMNS]; ntasks as usize];
+        let mut exceptions = vec![0.0; TIMELINE_COLUMNS];
+
+        for run in self.runs.iter().filter(|run| run.task < ntasks) {
+            self.fill(&mut running[run.task as usize], run.start, run.end);
+
+            if let Some(ready) = run.ready {
+                self.fill(&mut waiting[run.task as usize], ready, run.start);
+            }
+        }
+
+        for e in &self.exceptions {
+            if e.exception != EXCEPTION_SVCALL {
+                self.fill(&mut exceptions, e.start, e.end);
+            }
+        }
+
+        let row = |running: &[f64], waiting: Option<&Vec<f64>>| {
+            (0..TIMELINE_COLUMNS)
+                .map(|c| {
+                    if running[c] >= 0.5 {
+                        '#'
+                    } else if running[c] > 0.0 {
+                        '+'
+                    } else if waiting.map_or(false, |w| w[c] > 0.0) {
+                        '.'
+                    } else {
+                        ' '
+                    }
+                })
+                .collect::<String>()
+        };
+
+        let first = "0.000s";
+        let last =
+            format!("{:.3}s", (self.end - self.start) as f64 / TIMESTAMP_HZ);
+
+        println!(
+            "{:16} |{}{:>width$}|",
+            "",
+            first,
+            last,
+            width = TIMELINE_COLUMNS - first.len()
+        );
+
+        for i in 0..ntasks {
+            println!(
+                "{:16} |{}|",
+                tasks[&i],
+                row(&running[i as usize], Some(&waiting[i as usize]))
+            );
+        }
+
+        println!("{:16} |{}|", "(interrupts)", row(&exceptions, None));
+
+        if self.markers.is_empty() {
+            return;
+        }
+
+        let mut markers = vec![' '; TIMELINE_COLUMNS];
+        let width =
+            (self.end - self.start).max(1) as f64 / TIMELINE_COLUMNS as f64;
+
+        for (time, _) in &self.markers {
+            let c = (time.saturating_sub(self.start) as f64 / width) as usize;
+            markers[c.min(TIMELINE_COLUMNS - 1)] = '|';
+        }
+
+        println!("{:16} |{}|", "(markers)", markers.iter().collect::<String>());
+
+        for (time, marker) in &self.markers {
+            let offset = time.saturating_sub(self.start) as f64;
+            println!("{:16}  {:.6}s {}", "", offset / TIMESTAMP_HZ, marker);
+        }
+    }
+
+    fn summarize(&self, tasks: &HashMap<u32, String>) {
+        let duration = (self.end - self.start).max(1) as f64;
+
+        println!(
+            "{:16} {:>5} {:>9} {:>9} {:>9} {:>10}",
+            "TASK", "RUN%", "SWITCHES", "MEANLAT", "MAXLAT", "PREEMPTED"
+        );
+
+        for i in 0..tasks.len() as u32 {
+            let runs = self.runs.iter().filter(|run| run.task == i);
+            let mut ran = 0;
+            let mut preempted = 0;
+            let mut switches = 0;
+            let mut latencies = vec![];
+
+            for run in runs {
+                ran += run.end - run.start;
+                preempted += run.preempted;
+                switches += 1;
+
+                if let Some(ready) = run.ready {
+                    latencies.push(run.start - ready);
+                }
+            }
+
+            let time = |t: Option<u64>| match t {
+                Some(t) if t > 0 => format_time(t),
+                _ => "-".to_string(),
+            };
+
+            let mean = if latencies.is_empty() {
+                None
+            } else {
+                Some(latencies.iter().sum::<u64>() / latencies.len() as u64)
+            };
+
+            println!(
+                "{:16} {:5.2} {:9} {:>9} {:>9} {:>10}",
+                tasks[&i],
+                (ran - preempted.min(ran)) as f64 / duration * 100.0,
+                switches,
+                time(mean),
+                time(latencies.iter().max().copied()),
+                time(Some(preempted)),
+            );
+        }
+    }
+
+    ///
+    /// Writes the timeline in the Chrome JSON trace event format, as
+    /// understood by Perfetto.  Tasks are threads within a "tasks" process,
+    /// and exceptions are threads within an "exceptions" process.
+    ///
+    fn perfetto(
+        &self,
+        tasks: &HashMap<u32, String>,
+        owners: &BTreeMap<u32, (u32, u32)>,
+        out: impl Write,
+    ) -> Result<()> {
+        const TASKS: u32 = 1;
+        const EXCEPTIONS: u32 = 2;
+
+        let us =
+            |t: u64| t.saturating_sub(self.start) as f64 / TIMESTAMP_HZ * 1e6;
+        let metadata = |name: &str, pid: u32, tid: u32, value: &str| {
+            serde_json::json!({
+                "name": name, "ph": "M", "pid": pid, "tid": tid,
+                "args": { "name": value },
+            })
+        };
+
+        let mut events = vec![
+            metadata("process_name", TASKS, 0, "tasks"),
+            metadata("process_name", EXCEPTIONS, 0, "exceptions"),
+        ];
+
+        for (index, name) in tasks {
+            events.push(metadata("thread_name", TASKS, *index, name));
+        }
+
+        for run in &self.runs {
+            if let Some(ready) = run.ready {
+                events.push(serde_json::json!({
+                    "name": "ready", "ph": "X", "pid": TASKS, "tid": run.task,
+                    "ts": us(ready), "dur": us(run.start) - us(ready),
+                }));
+            }
+
+            events.push(serde_json::json!({
+                "name": "running", "ph": "X", "pid": TASKS, "tid": run.task,
+                "ts": us(run.start), "dur": us(run.end) - us(run.start),
+                "args": {
+                    "latency_us": run.ready.map(|r| us(run.start) - us(r)),
+                    "preempted_us": run.preempted as f64 / TIMESTAMP_HZ * 1e6,
+                },
+            }));
+        }
+
+        let mut named = BTreeMap::new();
+
+        for e in &self.exceptions {
+            let name = named
+                .entry(e.exception)
+                .or_insert_with(|| exception_name(e.exception, tasks, owners));
+
+            events.push(serde_json::json!({
+                "name": name, "ph": "X", "pid": EXCEPTIONS, "tid": e.exception,
+                "ts": us(e.start), "dur": us(e.end) - us(e.start),
+            }));
+        }
+
+        for (exception, name) in &named {
+            events.push(metadata("thread_name", EXCEPTIONS, *exception, name));
+        }
+
+        for (time, marker) in &self.markers {
+            events.push(serde_json::json!({
+                "name": marker, "ph": "i", "s": "g", "pid": TASKS, "tid": 0,
+                "ts": us(*time),
+            }));
+        }
+
+        serde_json::to_writer(
+            out,
+            &serde_json::json!({
+                "traceEvents": events,
+                "displayTimeUnit": "ns",
+            }),
+        )?;
+
+        Ok(())
+    }
+}
+
+fn exception_trace(core: &mut dyn Core, enabled: bool) -> Result<()> {
+    core.halt()?;
+
+    let mut tcr = ITM_TCR::read(core)?;
+    tcr.set_dwt_enable(enabled);
+    tcr.write(core)?;
+
+    let mut dwt = DWT_CTRL::read(core)?;
+    dwt.set_exception_trace_enabled(enabled);
+    dwt.write(core)?;
+
+    core.run()?;
+    Ok(())
+}
+
+fn timeline_ingest(
+    core: &mut dyn Core,
+    subargs: &TraceArgs,
+    hubris: &HubrisArchive,
+    traceid: Option<u8>,
+) -> Result<(Vec<(u64, TraceEvent)>, Vec<(u64, String)>)> {
+    let schedstate = schedstate(hubris)?;
+    let mut spayload = Vec::with_capacity(schedstate.size);
+    let mut task = 0;
+
+    let mut bytes: Vec<u8> = vec![];
+    let mut ndx = 0;
+    let mut time: u64 = 0;
+    let started = Instant::now();
+    let duration = Duration::from_secs(subargs.duration);
+
+    //
+    // Events are timestamped by the local timestamp packet that follows
+    // them, so we hold them until we see it.
+    //
+    let mut pending = vec![];
+    let mut events = vec![];
+    let mut pending_markers = vec![];
+    let mut markers = vec![];
+    let mut reassembly = ITMMarkers::default();
+    let mut file = marker_file(subargs)?;
+
+    itm_ingest(
+        traceid,
+        || {
+            while ndx == bytes.len() {
+                if started.elapsed() >= duration {
+                    return Ok(None);
+                }
+
+                if let (Some(file), Some(port)) = (&mut file, subargs.markers) {
+                    file.inject(core, port)?;
+                }
+
+                bytes = core.read_swv()?;
+                ndx = 0;
+
+                if bytes.is_empty() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+
+            ndx += 1;
+            Ok(Some((bytes[ndx - 1], started.elapsed().as_secs_f64())))
+        },
+        |packet| {
+            match &packet.payload {
+                ITMPayload::Instrumentation { payload, port }
+                    if Some(*port as u8) == subargs.markers =>
+                {
+                    pending_markers.extend(reassembly.push(payload));
+                }
+
+                ITMPayload::Instrumentation { payload, port: 30 } => {
+                    pending.push(TraceEvent::Switch(payload[0] as u32));
+                }
+
+                ITMPayload::Instrumentation { payload, port: 31 } => {
+                    if payload.len() == 1 {
+                        task = payload[0] as u32;
+                        spayload.truncate(0);
+                    } else {
+                        spayload.extend_from_slice(payload);
+                    }
+
+                    if spayload.len() >= schedstate.size {
+                        let state =
+                            hubris.print(&spayload[..], schedstate.goff)?;
+                        let runnable = state == "Runnable";
+                        pending.push(TraceEvent::State(task, runnable));
+                        spayload.truncate(0);
+                    }
+                }
+
+                ITMPayload::Hardware { source, payload, len } => {
+                    if *source != ITM_EXCEPTION_TRACE || *len < 2 {
+                        return Ok(());
+                    }
+
+                    let exception =
+                        payload[0] as u32 | ((payload[1] as u32 & 1) << 8);
+
+                    match (payload[1] >> 4) & 0b11 {
+                        EXCEPTION_ENTERED => {
+                            pending.push(TraceEvent::Enter(exception))
+                        }
+                        EXCEPTION_EXITED => {
+                            pending.push(TraceEvent::Exit(exception))
+                        }
+                        _ => {}
+                    }
+                }
+
+                ITMPayload::LocalTimestamp { timedelta, .. } => {
+                    time += *timedelta as u64;
+                    events.extend(pending.drain(..).map(|e| (time, e)));
+                    markers
+                        .extend(pending_markers.drain(..).map(|m| (time, m)));
+                }
+
+                _ => {}
+            }
+
+            Ok(())
+        },
+    )?;
+
+    Ok((events, markers))
+}
+
+fn timeline(
+    core: &mut dyn Core,
+    subargs: &TraceArgs,
+    hubris: &HubrisArchive,
+    tasks: &HashMap<u32, String>,
+) -> Result<()> {
+    core.halt()?;
+    let kernel = KernelState::read(hubris, core);
+    core.run()?;
+    let owners = kernel?.irq_owners();
+
+    let traceid = itm_enable_ingest(core, hubris, stimuli(subargs)?)?;
+
+    humility::msg!("tracing for {} seconds", subargs.duration);
+
+    exception_trace(core, true)?;
+    let events = timeline_ingest(core, subargs, hubris, traceid);
+    exception_trace(core, false)?;
+    let (events, markers) = events?;
+
+    if events.is_empty() {
+        bail!("no trace events received; is the kernel instrumented?");
+    }
+
+    let mut timeline = Timeline::reconstruct(&events);
+    timeline.markers = markers;
+
+    if subargs.timeline {
+        timeline.render(tasks);
+        timeline.summarize(tasks);
+    }
+
+    if let Some(filename) = &subargs.perfetto {
+        let file = File::create(filename)
+            .with_context(|| format!("failed to create {}", filename))?;
+        timeline.perfetto(tasks, &owners, file)?;
+
+        humility::msg!(
+            "wrote {} task spans and {} exception spans to {}",
+            timeline.runs.len(),
+            timeline.exceptions.len(),
+            filename
+        );
+    }
+
+    Ok(())
+}
+
 fn tracecmd(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
@@ -236,10 +1005,14 @@ fn tracecmd(
         tasks.insert(i, module.to_string());
     }
 
+    if subargs.timeline || subargs.perfetto.is_some() {
+        return timeline(core, subargs, hubris, &tasks);
+    }
+
     //
     // Now enable ITM and ingest.
     //
-    let traceid = itm_enable_ingest(core, hubris, 0xf000_0000)?;
+    let traceid = itm_enable_ingest(core, hubris, stimuli(subargs)?)?;
     tracecmd_ingest(core, subargs, hubris, &tasks, traceid)?;
 
     Ok(())
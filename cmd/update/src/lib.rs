@@ -0,0 +1,456 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility update`
+//!
+//! `humility update` writes a new firmware image to the target by way of
+//! the update server -- the task that implements the `Update` Idol
+//! interface -- rather than by way of the debug probe.  The image may be
+//! either a Hubris archive (in which case its `img/final.bin` is used) or a
+//! raw binary.  The image is staged by preparing the update, is written
+//! block by block via `hiffy`, and the update is then finished; should any
+//! step fail, the update is aborted:
+//!
+//! ```console
+//! % humility update build-gimlet-b.zip
+//! humility: attached via ST-Link V3
+//! humility: current version: ImageVersion { epoch: 0x0, version: 0x3 }
+//! humility: writing 471040 bytes to Alternate in 920 blocks of 512 bytes
+//! humility: updating [##############################] 460KB/460KB
+//! humility: update of Alternate complete
+//! ```
+//!
+//! The image type to update defaults to `Alternate` (the image that is not
+//! running); a different image type can be specified with `-i`
+//! (`--image-type`), and `-l` (`--list`) lists the image types that the
+//! update server accepts.  The same command updates the root of trust:
+//! attach to the RoT (with its archive) rather than to the SP.
+//!
+//! The new image does not run until the target is reset.  If the update
+//! server can reset the target, `-r` (`--reset`) will do so once the update
+//! is finished; if the image was a Hubris archive, the target is then
+//! checked to be running the new image:
+//!
+//! ```console
+//! % humility update --reset build-gimlet-b.zip
+//! ...
+//! humility: update of Alternate complete
+//! humility: resetting target
+//! humility: verified that target is running gimlet-b
+//! ```
+//!
+//! Images are transferred only via `hiffy`; transfer over the management
+//! network is not supported.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::idol::{IdolArgument, IdolOperation};
+use humility_cmd::progress::Progress;
+use humility_cmd::transfer::Transfer;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::fs;
+use std::io::{Cursor, Read};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[clap(name = "update", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct UpdateArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// list image types accepted by the update server
+    #[clap(long, short, conflicts_with_all = &["image", "reset"])]
+    list: bool,
+
+    /// image type to update
+    #[clap(long, short, value_name = "type")]
+    image_type: Option<String>,
+
+    /// reset the target once the update is complete
+    #[clap(long, short)]
+    reset: bool,
+
+    /// Hubris archive or binary image to write
+    #[clap(required_unless_present = "list")]
+    image: Option<String>,
+}
+
+const INTERFACE: &str = "Update";
+
+struct Updater<'a> {
+    hubris: &'a HubrisArchive,
+    context: HiffyContext<'a>,
+    funcs: HiffyFunctions,
+}
+
+impl<'a> Updater<'a> {
+    fn new(
+        hubris: &'a HubrisArchive,
+        core: &mut dyn Core,
+        timeout: u32,
+    ) -> Result<Self> {
+        let mut context = HiffyContext::new(hubris, core, timeout)?;
+        let funcs = context.functions()?;
+
+        Ok(Self { hubris, context, funcs })
+    }
+
+    fn op(&self, name: &str) -> Result<IdolOperation<'a>> {
+        IdolOperation::new(self.hubris, INTERFACE, name, None)
+    }
+
+    fn has_op(&self, name: &str) -> bool {
+        self.op(name).is_ok()
+    }
+
+    ///
+    /// Calls the specified operation, returning its (raw) reply.  If `lease`
+    /// is specified, that many bytes of the HIF data are lent to the
+    /// operation; if `data` is specified, it is written to the HIF data
+    /// before the call (otherwise, the HIF data is assumed to have been
+    /// staged).
+    ///
+    fn call(
+        &mut self,
+        core: &mut dyn Core,
+        op: &IdolOperation,
+        args: &[(&str, IdolArgument)],
+        lease: Option<u32>,
+        data: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        let leases = (lease, None);
+        let reply = self.context.idol_call(
+            core,
+            &self.funcs,
+            op,
+            args,
+            leases,
+            data,
+        )?;
+
+        match reply {
+            Ok(val) => Ok(val),
+            Err(e) => bail!("{} failed: {}", op.name.1, e),
+        }
+    }
+
+    fn version(&mut self, core: &mut dyn Core) -> Result<Option<String>> {
+        if !self.has_op("current_version") {
+            return Ok(None);
+        }
+
+        let op = self.op("current_version")?;
+        let val = self.call(core, &op, &[], None, None)?;
+
+        let fmt = HubrisPrintFormat {
+            newline: false,
+            hex: true,
+            ..HubrisPrintFormat::default()
+        };
+
+        Ok(Some(self.hubris.printfmt(&val, op.ok, &fmt)?))
+    }
+
+    fn block_size(&mut self, core: &mut dyn Core) -> Result<usize> {
+        let op = self.op("block_size")?;
+        let val = self.call(core, &op, &[], None, None)?;
+
+        let size = match val.len() {
+            4 => u32::from_le_bytes(val[..].try_into()?) as usize,
+            8 => u64::from_le_bytes(val[..].try_into()?) as usize,
+            len => bail!("unexpected block size reply length {}", len),
+        };
+
+        if size == 0 {
+            bail!("update server reports a block size of 0");
+        }
+
+        Ok(size)
+    }
+
+    fn write(
+        &mut self,
+        core: &mut dyn Core,
+        args: &Args,
+        image_type: &str,
+        image: &[u8],
+    ) -> Result<()> {
+        let block_size = self.block_size(core)?;
+
+        if block_size > self.context.data_size() {
+            bail!(
+                "block size ({}) exceeds HIFFY_DATA size ({})",
+                block_size,
+                self.context.data_size()
+            );
+        }
+
+        let nblocks = (image.len() + block_size - 1) / block_size;
+
+        humility::msg!(
+            "writing {} bytes to {} in {} blocks of {} bytes",
+            image.len(),
+            image_type,
+            nblocks,
+            block_size
+        );
+
+        let prep = self.op("prep_image_update")?;
+        let write = self.op("write_one_block")?;
+        let finish = self.op("finish_image_update")?;
+
+        self.call(
+            core,
+            &prep,
+            &[("image_type", IdolArgument::String(image_type))],
+            None,
+            None,
+        )?;
+
+        //
+        // The final block is padded out to the block size with the erased
+        // value of flash.
+        //
+        let mut padded = image.to_vec();
+        padded.resize(nblocks * block_size, 0xff);
+
+        let mut transfer = Transfer::new(&self.funcs);
+        let frames =
+            transfer.frames(&self.context, &padded, block_size, block_size)?;
+
+        let mut progress =
+            Progress::bytes(args, "updating", image.len() as u64);
+
+        for frame in &frames {
+            let data = transfer.stage(&mut self.context, core, frame)?;
+            let lease = Some(block_size as u32);
+
+            //
+            // Each frame holds exactly one block.
+            //
+            let ndx = frame.offset / block_size;
+
+            self.call(
+                core,
+                &write,
+                &[("block_num", IdolArgument::Scalar(ndx as u64))],
+                lease,
+                data,
+            )?;
+
+            let written = (frame.offset + frame.len).min(image.len());
+            progress.set_position(written as u64);
+        }
+
+        progress.finish();
+
+        let stats = transfer.stats();
+
+        if transfer.framed() {
+            humility::msg!(
+                "sent {} bytes in {} frames ({:.1}% of {} bytes, {} resent)",
+                stats.sent,
+                stats.frames,
+                stats.sent as f64 * 100.0 / stats.bytes.max(1) as f64,
+                stats.bytes,
+                stats.retries
+            );
+        }
+
+        self.call(core, &finish, &[], None, None)?;
+
+        Ok(())
+    }
+
+    fn abort(&mut self, core: &mut dyn Core) -> Result<()> {
+        let abort = self.op("abort_update")?;
+        self.call(core, &abort, &[], None, None)?;
+        Ok(())
+    }
+
+    ///
+    /// Asks the update server to reset the target.  The call will not
+    /// complete (the target resets out from under it), so we don't wait for
+    /// it.
+    ///
+    fn reset(&mut self, core: &mut dyn Core) -> Result<()> {
+        let reset = self.op("reset").map_err(|_| {
+            anyhow!("update server does not support reset; reset manually")
+        })?;
+
+        let mut ops = vec![];
+        self.context.idol_call_ops(&self.funcs, &reset, &[], &mut ops)?;
+        ops.push(Op::Done);
+
+        self.context.start(core, ops.as_slice(), None)
+    }
+}
+
+///
+/// Loads the image to write, returning it and -- if it's a Hubris archive --
+/// the archive.
+///
+fn load(filename: &str) -> Result<(Vec<u8>, Option<HubrisArchive>)> {
+    let contents = fs::read(filename)
+        .with_context(|| format!("failed to read {}", filename))?;
+
+    if !contents.starts_with(b"PK\x03\x04") {
+        return Ok((contents, None));
+    }
+
+    let mut archive = HubrisArchive::new()?;
+    archive
+        .load(filename, HubrisArchiveDoneness::Cook)
+        .with_context(|| format!("failed to load archive {}", filename))?;
+
+    let mut image = vec![];
+
+    {
+        let cursor = Cursor::new(archive.archive());
+        let mut zip = zip::ZipArchive::new(cursor)?;
+        let mut file = zip
+            .by_name("img/final.bin")
+            .with_context(|| format!("{} has no img/final.bin", filename))?;
+
+        file.read_to_end(&mut image)?;
+    }
+
+    Ok((image, Some(archive)))
+}
+
+fn image_types<'a>(hubris: &'a HubrisArchive) -> Result<&'a HubrisEnum> {
+    let op = IdolOperation::new(hubris, INTERFACE, "prep_image_update", None)?;
+    let module = hubris.lookup_module(op.task)?;
+
+    let arg = op
+        .operation
+        .args
+        .get("image_type")
+        .ok_or_else(|| anyhow!("prep_image_update has no image_type"))?;
+
+    module
+        .lookup_enum_byname(hubris, &arg.ty.0)
+        .context(format!("failed to find image type {}", arg.ty.0))
+}
+
+///
+/// Once the target has been reset, waits for it to come up running the
+/// image in the specified archive.
+///
+fn verify(
+    archive: &HubrisArchive,
+    core: &mut dyn Core,
+    timeout: u32,
+) -> Result<()> {
+    let started = Instant::now();
+
+    loop {
+        thread::sleep(Duration::from_millis(500));
+
+        match archive.validate(core, HubrisValidate::Booted) {
+            Ok(_) => return Ok(()),
+            Err(err) => {
+                if started.elapsed().as_millis() > timeout as u128 {
+                    return Err(err.context(
+                        "target does not appear to be running the new image",
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn update(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = UpdateArgs::try_parse_from(subargs)?;
+    let types = image_types(hubris)?;
+
+    if subargs.list {
+        for variant in &types.variants {
+            println!("{}", variant.name);
+        }
+
+        return Ok(());
+    }
+
+    let image_type = match &subargs.image_type {
+        Some(image_type) => image_type.clone(),
+        None => match types.lookup_variant_byname("Alternate") {
+            Ok(variant) => variant.name.clone(),
+            Err(_) => bail!("must specify image type (-l to list)"),
+        },
+    };
+
+    let filename = subargs.image.as_ref().unwrap();
+    let (image, archive) = load(filename)?;
+
+    if image.is_empty() {
+        bail!("{} is empty", filename);
+    }
+
+    let mut updater = Updater::new(hubris, core, subargs.timeout)?;
+
+    if let Some(version) = updater.version(core)? {
+        humility::msg!("current version: {}", version);
+    }
+
+    if let Err(err) = updater.write(core, args, &image_type, &image) {
+        humility::msg!("update failed; aborting");
+
+        if let Err(abort) = updater.abort(core) {
+            humility::msg!("failed to abort update: {}", abort);
+        }
+
+        return Err(err);
+    }
+
+    humility::msg!("update of {} complete", image_type);
+
+    if !subargs.reset {
+        humility::msg!("new image will run when the target is reset");
+        return Ok(());
+    }
+
+    humility::msg!("resetting target");
+    updater.reset(core)?;
+
+    if let Some(archive) = archive {
+        verify(&archive, core, subargs.timeout)?;
+
+        humility::msg!(
+            "verified that target is running {}",
+            archive.manifest.name.as_deref().unwrap_or("<unknown>")
+        );
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "update",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: update,
+        },
+        UpdateArgs::command(),
+    )
+}
@@ -164,6 +164,8 @@ fn validate(
     let op = idol::IdolOperation::new(hubris, "Validate", "validate_i2c", None)
         .context("is the 'validate' task present?")?;
     let mut ops = vec![];
+    let mut payload =
+        op.template(&[("index", idol::IdolArgument::Scalar(0))])?;
 
     let mut devices = vec![];
 
@@ -186,9 +188,8 @@ fn validate(
 
         devices.push((ndx, device));
 
-        let payload =
-            op.payload(&[("index", idol::IdolArgument::Scalar(ndx as u64))])?;
-        context.idol_call_ops(&funcs, &op, &payload, &mut ops)?;
+        payload.set("index", ndx as u64)?;
+        context.idol_call_ops(&funcs, &op, payload.as_slice(), &mut ops)?;
     }
 
     ops.push(Op::Done);
@@ -0,0 +1,351 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility versions`
+//!
+//! `humility versions` consolidates the versions of the firmware and
+//! components of a system into a single report, e.g. for inventory or to
+//! attach to a support ticket.  The report consists of:
+//!
+//! - The Hubris image:  its name, board, target, version and git revision
+//!   (as recorded in the archive), and its image ID (along with whether the
+//!   image ID on the target matches the archive)
+//!
+//! - The kernel and each task:  the hash of its text in the archive, and
+//!   whether the text on the target matches it (see `humility compat`)
+//!
+//! - Firmware identifiers that tasks expose via Idol:  every operation that
+//!   takes no arguments and whose name contains `version` or `ident` (e.g.,
+//!   RoT firmware versions via `SpRot`, or FPGA bitstream identifiers) is
+//!   called, and its reply is reported
+//!
+//! - PMBus devices:  the `MFR_REVISION` and `IC_DEVICE_REV` of each PMBus
+//!   device in the archive
+//!
+//! For example:
+//!
+//! ```console
+//! % humility versions
+//! humility: attached via ST-Link V3
+//! COMPONENT  NAME                     VERSION                    STATUS
+//! image      name                     gimlet-b                   -
+//! image      board                    gimlet-b                   -
+//! image      target                   thumbv7em-none-eabihf      -
+//! image      version                  1.0.2                      -
+//! image      git rev                  0b1ae1b8f4e11a9c           -
+//! image      image ID                 [5f, 2a, 11, 9b, ...]      match
+//! kernel     kernel                   0x6b1d0e8e7a3c2f55         match
+//! task       jefe                     0x0c52b5f1d9e2a4a7         match
+//! task       net                      0x3fa1c0d7b2e85190         match
+//! ...
+//! sprot      SpRot.rot_version        RotVersion { ... }         ok
+//! gimlet_seq Sequencer.fpga_ident     0x1de                      ok
+//! pmbus      isl68224 (v1p8_sp3)      MFR_REVISION=[1, 2]        ok
+//! pmbus      tps546b24a (v3p3_sp)     -                          error
+//! ...
+//! ```
+//!
+//! As with all tabular output, `--format json` emits the report as JSON
+//! (one object per row) for consumption by other tools.  When run on a dump
+//! (or if the `hiffy` task is absent), only the image and the kernel and
+//! tasks are reported.
+//!
+
+use anyhow::Result;
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::i2c::I2cArgs;
+use humility_cmd::idol::IdolOperation;
+use humility_cmd::output::{Cell, Column, Table};
+use humility_cmd::style::Severity;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+
+#[derive(Parser, Debug)]
+#[clap(name = "versions", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct VersionsArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+}
+
+///
+/// The PMBus commands that we read to determine the revision of a device
+/// (both of which are block reads).
+///
+const PMBUS_REVISIONS: &[(&str, u8)] =
+    &[("MFR_REVISION", 0x9b), ("IC_DEVICE_REV", 0xae)];
+
+fn unknown(val: &Option<String>) -> Cell {
+    match val {
+        Some(val) => val.as_str().into(),
+        None => Cell::None,
+    }
+}
+
+fn status(ok: bool, text: &str) -> Cell {
+    Cell::from(text).styled(if ok { Severity::Ok } else { Severity::Error })
+}
+
+fn image(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    table: &mut Table,
+) -> Result<()> {
+    let manifest = &hubris.manifest;
+
+    for (name, val) in [
+        ("name", &manifest.name),
+        ("board", &manifest.board),
+        ("target", &manifest.target),
+        ("version", &manifest.version),
+        ("git rev", &manifest.gitrev),
+    ] {
+        table.row(vec![
+            "image".into(),
+            name.into(),
+            unknown(val),
+            Cell::None,
+        ])?;
+    }
+
+    let id = match hubris.image_id() {
+        Some(id) => format!("{:x?}", id),
+        None => "<none>".to_string(),
+    };
+
+    let matches = hubris.validate(core, HubrisValidate::ArchiveMatch).is_ok();
+
+    table.row(vec![
+        "image".into(),
+        "image ID".into(),
+        id.into(),
+        status(matches, if matches { "match" } else { "MISMATCH" }),
+    ])?;
+
+    for check in hubris.check_text(core) {
+        let component = match check.task {
+            HubrisTask::Kernel => "kernel",
+            HubrisTask::Task(_) => "task",
+        };
+
+        let state = match &check.target {
+            Ok(_) if check.matches() => status(true, "match"),
+            Ok(_) => status(false, "MISMATCH"),
+            Err(_) => Cell::from("unavailable"),
+        };
+
+        table.row(vec![
+            component.into(),
+            check.name.as_str().into(),
+            Cell::Hex(check.archive, 16),
+            state,
+        ])?;
+    }
+
+    Ok(())
+}
+
+fn idol(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    table: &mut Table,
+) -> Result<()> {
+    let funcs = context.functions()?;
+    let fmt = HubrisPrintFormat::default();
+
+    for t in 0..hubris.ntasks() {
+        let task = HubrisTask::Task(t as u32);
+        let module = hubris.lookup_module(task)?;
+
+        let iface = match &module.iface {
+            Some(iface) => iface,
+            None => continue,
+        };
+
+        for (name, op) in &iface.ops {
+            if !op.args.is_empty()
+                || !(name.contains("version") || name.contains("ident"))
+            {
+                continue;
+            }
+
+            let idol = match IdolOperation::new(
+                hubris,
+                &iface.name,
+                name,
+                Some(&task),
+            ) {
+                Ok(idol) => idol,
+                Err(err) => {
+                    humility::msg!("{}.{}: {}", iface.name, name, err);
+                    continue;
+                }
+            };
+
+            let mut ops = vec![];
+            context.idol_call_ops(&funcs, &idol, &[], &mut ops)?;
+            ops.push(Op::Done);
+
+            let (val, ok) =
+                match context.run(core, ops.as_slice(), None)?.get(0) {
+                    Some(Ok(val)) => {
+                        (Cell::from(hubris.printfmt(val, idol.ok, &fmt)?), true)
+                    }
+                    Some(Err(e)) => {
+                        let err = idol
+                            .error
+                            .and_then(|err| err.lookup_variant(*e as u64))
+                            .map(|v| v.name.clone())
+                            .unwrap_or_else(|| format!("Err(0x{:x})", e));
+
+                        (Cell::from(err), false)
+                    }
+                    None => (Cell::None, false),
+                };
+
+            table.row(vec![
+                module.name.as_str().into(),
+                format!("{}.{}", iface.name, name).into(),
+                val,
+                status(ok, if ok { "ok" } else { "error" }),
+            ])?;
+        }
+    }
+
+    Ok(())
+}
+
+fn pmbus(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    table: &mut Table,
+) -> Result<()> {
+    let funcs = context.functions()?;
+
+    let i2c_read = match funcs.get("I2cRead", 7) {
+        Ok(func) => func,
+        Err(_) => {
+            humility::msg!("I2cRead not found; not reporting PMBus devices");
+            return Ok(());
+        }
+    };
+
+    let printable = |val: &[u8]| {
+        !val.is_empty()
+            && val.iter().all(|&c| c.is_ascii_graphic() || c == b' ')
+    };
+
+    for device in &hubris.manifest.i2c_devices {
+        let rails = match &device.class {
+            HubrisI2cDeviceClass::Pmbus { rails } => rails,
+            _ => continue,
+        };
+
+        let hargs = I2cArgs::from_device(device);
+        let mut ops = vec![];
+
+        hargs.push_bus(&mut ops);
+        ops.push(Op::Push(device.address));
+
+        for (_, code) in PMBUS_REVISIONS {
+            ops.push(Op::Push(*code));
+            ops.push(Op::PushNone);
+            ops.push(Op::Call(i2c_read.id));
+            ops.push(Op::DropN(2));
+        }
+
+        ops.push(Op::Done);
+
+        let results = context.run(core, ops.as_slice(), None)?;
+        let mut revisions = vec![];
+        let mut err = None;
+
+        for ((name, _), result) in PMBUS_REVISIONS.iter().zip(&results) {
+            match result {
+                Ok(val) if printable(val) => revisions.push(format!(
+                    "{}=\"{}\"",
+                    name,
+                    String::from_utf8_lossy(val)
+                )),
+                Ok(val) => revisions.push(format!("{}={:x?}", name, val)),
+                Err(code) => err = Some(i2c_read.strerror(*code)),
+            }
+        }
+
+        let name = match (&device.name, rails.first()) {
+            (Some(name), _) => format!("{} ({})", device.device, name),
+            (None, Some(rail)) => format!("{} ({})", device.device, rail),
+            (None, None) => device.device.clone(),
+        };
+
+        let (val, state) = match (revisions.is_empty(), err) {
+            (true, Some(err)) => (Cell::None, status(false, &err)),
+            (_, _) => (revisions.join(", ").into(), status(true, "ok")),
+        };
+
+        table.row(vec!["pmbus".into(), name.into(), val, state])?;
+    }
+
+    Ok(())
+}
+
+fn versions(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = VersionsArgs::try_parse_from(subargs)?;
+
+    let mut table = Table::new(
+        args.format,
+        vec![
+            Column::new("component", 10),
+            Column::new("name", 24),
+            Column::new("version", 26),
+            Column::new("status", 0),
+        ],
+    );
+
+    image(hubris, core, &mut table)?;
+
+    if core.is_dump() {
+        return Ok(());
+    }
+
+    let mut context = match HiffyContext::new(hubris, core, subargs.timeout) {
+        Ok(context) => context,
+        Err(err) => {
+            humility::msg!("not reporting device firmware: {}", err);
+            return Ok(());
+        }
+    };
+
+    idol(hubris, core, &mut context, &mut table)?;
+    pmbus(hubris, core, &mut context, &mut table)?;
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "versions",
+            archive: Archive::Required,
+            attach: Attach::Any,
+            validate: Validate::None,
+            run: versions,
+        },
+        VersionsArgs::command(),
+    )
+}
@@ -0,0 +1,335 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility watch`
+//!
+//! `humility watch` polls one or more static variables on a live system,
+//! printing each variable whenever its value changes.  Unlike `humility
+//! readvar`, the target is not halted to read the variables; the values are
+//! decoded by type and printed with the time (in seconds) since watching
+//! began:
+//!
+//! ```console
+//! % humility watch TICKS CURRENT_TASK_PTR
+//! humility: attached via ST-Link
+//!      0.000 TICKS = 0x6c2b0e
+//!      0.000 CURRENT_TASK_PTR = Some(NonNull<kern::task::Task> { pointer: 0x20000880 (*const kern::task::Task) })
+//!      1.003 TICKS = 0x6c2efa
+//!      1.004 CURRENT_TASK_PTR = Some(NonNull<kern::task::Task> { pointer: 0x200004b0 (*const kern::task::Task) })
+//!      2.007 TICKS = 0x6c32e6
+//! ...
+//! ```
+//!
+//! Variables can be specified by their name or by their fully qualified
+//! name (e.g., `task_jefe::external::REQUESTS`).  To specify the polling
+//! interval in milliseconds, use `-i` (`--interval`); to stop after a number
+//! of polls, use `-c` (`--count`).  To emit each change as a line of JSON
+//! (suitable for consumption by other tools), use `-j` (`--json`):
+//!
+//! ```console
+//! % humility watch -j -c 2 TICKS
+//! humility: attached via ST-Link
+//! {"addr":536870920,"name":"TICKS","time":0.0,"value":"0x6d1f7a"}
+//! {"addr":536870920,"name":"TICKS","time":1.002,"value":"0x6d2366"}
+//! ```
+//!
+//! To detect the target resetting while it is being watched, use
+//! `--heartbeat`:  the kernel's system time is read on each poll, and if it
+//! has gone backwards, the reset is noted in the output (as an object with
+//! a `reset` field when emitting JSON) and every variable is displayed
+//! anew.
+//!
+//! To be alerted to transient events, one or more rules can be specified
+//! with `--alert`.  A rule names a variable (which must be an integer,
+//! floating point or boolean variable) and a threshold for its value, for
+//! its rate of change per second (`:rate`), or for the magnitude of its
+//! change between polls (`:delta`).  Rules are evaluated on every poll (not
+//! just when a value changes), and an alert is displayed (as an object with
+//! an `alert` field when emitting JSON) when a rule's condition comes to
+//! hold:
+//!
+//! ```console
+//! % humility watch -i 100 --alert "DROPS:rate>50" --alert "DEPTH>30" DROPS DEPTH
+//! humility: attached via ST-Link
+//!      0.000 DROPS = 0x1a
+//!      0.000 DEPTH = 0x4
+//! ...
+//!      7.412 *** alert DROPS:rate>50/s: DROPS is 112.000, changing at 80.000/s ***
+//!      7.412 DROPS = 0x70
+//! ```
+//!
+//! Rules are of the same form as those given to `humility sensors --alert`.
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::alert::{Alert, Alerts};
+use humility_cmd::kernel::Heartbeat;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[clap(name = "watch", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct WatchArgs {
+    /// interval between reads, in milliseconds
+    #[clap(
+        long, short, default_value = "1000", value_name = "ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
+    /// number of times to read the variables before exiting
+    #[clap(
+        long, short, value_name = "count",
+        parse(try_from_str = parse_int::parse)
+    )]
+    count: Option<u64>,
+
+    /// values in decimal instead of hex
+    #[clap(long, short)]
+    decimal: bool,
+
+    /// emit changes as JSON, one object per line
+    #[clap(long, short)]
+    json: bool,
+
+    /// check for and annotate resets of the target
+    #[clap(long)]
+    heartbeat: bool,
+
+    /// raise an alert when a rule (e.g., "DROPS:rate>50") is violated
+    #[clap(long, value_name = "rule", multiple_occurrences = true)]
+    alert: Vec<String>,
+
+    /// variables to watch
+    #[clap(required = true)]
+    variables: Vec<String>,
+}
+
+struct Watched<'a> {
+    name: String,
+    variable: &'a HubrisVariable,
+    basetype: Option<&'a HubrisBasetype>,
+    last: Option<Vec<u8>>,
+}
+
+//
+// Returns the value of a variable of a base type as a number, for purposes
+// of alerting.
+//
+fn numeric(basetype: &HubrisBasetype, buf: &[u8]) -> Option<f64> {
+    let mut bytes = [0u8; 8];
+    let size = basetype.size;
+
+    if size == 0 || size > 8 || buf.len() < size {
+        return None;
+    }
+
+    bytes[..size].copy_from_slice(&buf[..size]);
+    let raw = u64::from_le_bytes(bytes);
+
+    match (basetype.encoding, size) {
+        (HubrisEncoding::Unsigned, _) => Some(raw as f64),
+        (HubrisEncoding::Bool, _) => Some(if raw != 0 { 1.0 } else { 0.0 }),
+        (HubrisEncoding::Signed, _) => {
+            let shift = 64 - size * 8;
+            Some(((raw << shift) as i64 >> shift) as f64)
+        }
+        (HubrisEncoding::Float, 4) => Some(f32::from_bits(raw as u32) as f64),
+        (HubrisEncoding::Float, 8) => Some(f64::from_bits(raw)),
+        _ => None,
+    }
+}
+
+fn alert(json: bool, alert: &Alert) {
+    if json {
+        let obj = serde_json::json!({
+            "time": alert.time,
+            "alert": {
+                "rule": alert.rule.to_string(),
+                "name": alert.series,
+                "value": alert.value,
+                "observed": alert.observed,
+            },
+        });
+
+        println!("{}", obj);
+    } else {
+        println!("{:10.3} *** {} ***", alert.time, alert);
+    }
+}
+
+fn lookup<'a>(
+    hubris: &'a HubrisArchive,
+    name: &str,
+) -> Result<Vec<&'a HubrisVariable>> {
+    if let Ok(variables) = hubris.lookup_variables(name) {
+        return Ok(variables.iter().collect());
+    }
+
+    let variables = hubris
+        .qualified_variables()
+        .filter(|(n, _)| *n == name)
+        .map(|(_, v)| v)
+        .collect::<Vec<_>>();
+
+    if variables.is_empty() {
+        bail!("variable {} not found (use \"readvar -l\" to list)", name);
+    }
+
+    Ok(variables)
+}
+
+fn watch(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = WatchArgs::try_parse_from(subargs)?;
+    let mut watched = vec![];
+
+    for name in &subargs.variables {
+        let variables = lookup(hubris, name)?;
+        let multiple = variables.len() > 1;
+
+        for variable in variables {
+            //
+            // If a name resolves to more than one variable, we disambiguate
+            // with the owning task.
+            //
+            let name = if multiple {
+                let task = HubrisTask::from(variable.goff);
+                format!("{}:{}", hubris.lookup_module(task)?.name, name)
+            } else {
+                name.to_string()
+            };
+
+            let basetype = hubris.lookup_basetype(variable.goff).ok();
+            watched.push(Watched { name, variable, basetype, last: None });
+        }
+    }
+
+    let mut alerts = Alerts::new(&subargs.alert)?;
+
+    for rule in alerts.rules().iter().filter(|r| r.name != "*") {
+        if let Some(w) = watched.iter().find(|w| rule.matches(&w.name)) {
+            if w.basetype.is_none() {
+                bail!("{} is not a number; cannot alert on it", w.name);
+            }
+        }
+    }
+
+    alerts.check_series(watched.iter().map(|w| w.name.as_str()))?;
+
+    let fmt = HubrisPrintFormat {
+        newline: false,
+        hex: !subargs.decimal,
+        ..HubrisPrintFormat::default()
+    };
+
+    let started = Instant::now();
+    let mut polls = 0;
+    let mut heartbeat = Heartbeat::default();
+
+    loop {
+        //
+        // If the target has reset, the values that follow are from a new
+        // boot; we say so, and display every variable anew.
+        //
+        let reset = if subargs.heartbeat {
+            heartbeat.read(hubris, core)?
+        } else {
+            None
+        };
+
+        if let Some(reset) = reset {
+            let time = started.elapsed().as_secs_f64();
+
+            if subargs.json {
+                let obj = serde_json::json!({
+                    "time": time,
+                    "reset": { "before": reset.before, "after": reset.after },
+                });
+
+                println!("{}", obj);
+            } else {
+                println!("{:10.3} *** {} ***", time, reset);
+            }
+
+            for w in watched.iter_mut() {
+                w.last = None;
+            }
+
+            alerts.reset();
+        }
+
+        for w in watched.iter_mut() {
+            let mut buf = vec![0u8; w.variable.size];
+
+            //
+            // We deliberately do not halt the target here:  the point of
+            // watching is to observe the system without perturbing it.
+            //
+            core.read_8(w.variable.addr, &mut buf)?;
+
+            let time = started.elapsed().as_secs_f64();
+
+            if let Some(val) = w.basetype.and_then(|b| numeric(b, &buf)) {
+                for a in alerts.sample(&w.name, time, val) {
+                    alert(subargs.json, &a);
+                }
+            }
+
+            if w.last.as_ref() == Some(&buf) {
+                continue;
+            }
+            let value = hubris.printfmt(&buf, w.variable.goff, &fmt)?;
+
+            if subargs.json {
+                let obj = serde_json::json!({
+                    "time": time,
+                    "name": w.name,
+                    "addr": w.variable.addr,
+                    "value": value,
+                });
+
+                println!("{}", obj);
+            } else {
+                println!("{:10.3} {} = {}", time, w.name, value);
+            }
+
+            w.last = Some(buf);
+        }
+
+        polls += 1;
+
+        if let Some(count) = subargs.count {
+            if polls >= count {
+                break;
+            }
+        }
+
+        thread::sleep(Duration::from_millis(subargs.interval));
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "watch",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Match,
+            run: watch,
+        },
+        WatchArgs::command(),
+    )
+}
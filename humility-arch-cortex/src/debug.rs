@@ -169,6 +169,26 @@ register!(HFSR, 0xe000_ed2c,
     pub vector_fault, _: 1;
 );
 
+//
+// MemManage Fault Address Register
+//
+register!(MMFAR, 0xe000_ed34,
+    #[derive(Copy, Clone)]
+    pub struct MMFAR(u32);
+    impl Debug;
+    pub address, _: 31, 0;
+);
+
+//
+// BusFault Address Register
+//
+register!(BFAR, 0xe000_ed38,
+    #[derive(Copy, Clone)]
+    pub struct BFAR(u32);
+    impl Debug;
+    pub address, _: 31, 0;
+);
+
 //
 // Debug Fault Status Register
 //
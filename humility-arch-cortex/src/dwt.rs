@@ -4,6 +4,7 @@
 
 use crate::debug::Register;
 use crate::register;
+use anyhow::{bail, Result};
 use bitfield::bitfield;
 use humility::core::Core;
 
@@ -26,15 +27,26 @@ register!(DWT_CTRL, 0xe000_1000,
     pub sleep_enabled, _: 19;
     pub exception_enabled, _: 18;
     pub cpi_enabled, _: 17;
-    pub exception_trace_enabled, _: 16;
-    pub pc_sampling_enabled, _: 12;
+    pub exception_trace_enabled, set_exception_trace_enabled: 16;
+    pub pc_sampling_enabled, set_pc_sampling_enabled: 12;
     pub _synctap, _set_synctap: 11, 10;
-    pub postcnt_tap, _: 9;
+    pub postcnt_tap, set_postcnt_tap: 9;
     pub postcnt_init, _: 8, 5;
-    pub postcnt_reset, _: 4, 1;
+    pub postcnt_reset, set_postcnt_reset: 4, 1;
     pub cyccnt_enabled, set_cyccnt_enabled: 0;
 );
 
+/*
+ * DWT Cycle Count Register
+ */
+register!(DWT_CYCCNT, 0xe000_1004,
+    #[derive(Copy, Clone)]
+    #[allow(non_camel_case_types)]
+    pub struct DWT_CYCCNT(u32);
+    impl Debug;
+    pub count, set_count: 31, 0;
+);
+
 pub enum DWTSyncTapFrequency {
     Disabled,
     CycCnt8M,   // Every 2^23rd (8M) cycles
@@ -54,3 +66,147 @@ impl DWT_CTRL {
         self._set_synctap(val);
     }
 }
+
+const DWT_COMP_BASE: u32 = 0xe000_1020;
+const DWT_COMP_STRIDE: u32 = 16;
+const DWT_MASK_OFFSET: u32 = 4;
+const DWT_FUNCTION_OFFSET: u32 = 8;
+
+/// The kind of data access that triggers a watchpoint
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DWTAccess {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// A watchpoint, as decoded from a DWT comparator
+#[derive(Copy, Clone, Debug)]
+pub struct DWTWatchpoint {
+    pub addr: u32,
+    pub size: u32,
+    pub access: DWTAccess,
+    /// The watchpoint has triggered since its comparator was last read
+    pub matched: bool,
+}
+
+fn dwt_comparator(core: &mut dyn Core, ndx: u32) -> Result<u32> {
+    let ctrl = DWT_CTRL::read(core)?;
+
+    if ndx >= ctrl.num_comparators() {
+        bail!(
+            "watchpoint {} is out of range; DWT has {} comparators",
+            ndx,
+            ctrl.num_comparators()
+        );
+    }
+
+    Ok(DWT_COMP_BASE + ndx * DWT_COMP_STRIDE)
+}
+
+//
+// ARMv8-M has a different encoding of DWT_FUNCTION than ARMv6-M/ARMv7-M
+// (and no DWT_MASK); we can differentiate them by the ID field, which is
+// only present on ARMv8-M.
+//
+fn dwt_v8(core: &mut dyn Core) -> Result<bool> {
+    let function = core.read_word_32(DWT_COMP_BASE + DWT_FUNCTION_OFFSET)?;
+    Ok(function >> 27 != 0)
+}
+
+///
+/// Sets the specified DWT comparator to halt the core on the specified
+/// access to the naturally aligned region of `size` bytes at `addr`.  On
+/// ARMv8-M, the region can be no larger than a word.
+///
+pub fn dwt_watch_set(
+    core: &mut dyn Core,
+    ndx: u32,
+    addr: u32,
+    size: u32,
+    access: DWTAccess,
+) -> Result<()> {
+    let comp = dwt_comparator(core, ndx)?;
+
+    if !size.is_power_of_two() || addr & (size - 1) != 0 {
+        bail!("watched region must be a naturally aligned power of two");
+    }
+
+    let shift = size.trailing_zeros();
+
+    let function = if dwt_v8(core)? {
+        if size > 4 {
+            bail!("watched region cannot exceed 4 bytes on ARMv8-M");
+        }
+
+        let matching = match access {
+            DWTAccess::ReadWrite => 0b0100,
+            DWTAccess::Write => 0b0101,
+            DWTAccess::Read => 0b0110,
+        };
+
+        //
+        // DATAVSIZE is the size of the region; an ACTION of 0b01 generates
+        // a debug event.
+        //
+        (shift << 10) | (0b01 << 4) | matching
+    } else {
+        core.write_word_32(comp + DWT_MASK_OFFSET, shift)?;
+
+        match access {
+            DWTAccess::Read => 0b0101,
+            DWTAccess::Write => 0b0110,
+            DWTAccess::ReadWrite => 0b0111,
+        }
+    };
+
+    core.write_word_32(comp, addr)?;
+    core.write_word_32(comp + DWT_FUNCTION_OFFSET, function)
+}
+
+/// Clears the specified DWT comparator.
+pub fn dwt_watch_clear(core: &mut dyn Core, ndx: u32) -> Result<()> {
+    let comp = dwt_comparator(core, ndx)?;
+    core.write_word_32(comp + DWT_FUNCTION_OFFSET, 0)
+}
+
+///
+/// Reads the watchpoint set in the specified DWT comparator, if any.  Note
+/// that reading a comparator clears its matched status.
+///
+pub fn dwt_watch_read(
+    core: &mut dyn Core,
+    ndx: u32,
+) -> Result<Option<DWTWatchpoint>> {
+    let comp = dwt_comparator(core, ndx)?;
+    let function = core.read_word_32(comp + DWT_FUNCTION_OFFSET)?;
+    let addr = core.read_word_32(comp)?;
+    let matched = function & (1 << 24) != 0;
+
+    let (size, access) = if dwt_v8(core)? {
+        if (function >> 4) & 0b11 != 0b01 {
+            return Ok(None);
+        }
+
+        let access = match function & 0b1111 {
+            0b0100 => DWTAccess::ReadWrite,
+            0b0101 => DWTAccess::Write,
+            0b0110 => DWTAccess::Read,
+            _ => return Ok(None),
+        };
+
+        (1 << ((function >> 10) & 0b11), access)
+    } else {
+        let access = match function & 0b1111 {
+            0b0101 => DWTAccess::Read,
+            0b0110 => DWTAccess::Write,
+            0b0111 => DWTAccess::ReadWrite,
+            _ => return Ok(None),
+        };
+
+        let mask = core.read_word_32(comp + DWT_MASK_OFFSET)? & 0b1_1111;
+        (1 << mask, access)
+    };
+
+    Ok(Some(DWTWatchpoint { addr, size, access, matched }))
+}
@@ -0,0 +1,125 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Flash Patch and Breakpoint (FPB) unit, which we use to set hardware
+//! breakpoints.  There are two revisions of the FPB with different encodings
+//! of the comparators:  revision 1 (ARMv6-M and ARMv7-M) can only break on
+//! addresses in the code region (below 0x2000_0000), while revision 2
+//! (ARMv8-M) can break on any address.
+//!
+
+use crate::debug::Register;
+use crate::register;
+use anyhow::{bail, Result};
+use bitfield::bitfield;
+use humility::core::Core;
+
+register!(FP_CTRL, 0xe000_2000,
+    #[derive(Copy, Clone)]
+    #[allow(non_camel_case_types)]
+    pub struct FP_CTRL(u32);
+    impl Debug;
+    pub rev, _: 31, 28;
+    pub num_code_hi, _: 14, 12;
+    pub num_lit, _: 11, 8;
+    pub num_code_lo, _: 7, 4;
+    pub key, set_key: 1;
+    pub enable, set_enable: 0;
+);
+
+const FP_COMP_BASE: u32 = 0xe000_2008;
+
+impl FP_CTRL {
+    /// Returns the number of instruction address comparators.
+    pub fn num_code(&self) -> u32 {
+        (self.num_code_hi() << 4) | self.num_code_lo()
+    }
+}
+
+fn comparator(core: &mut dyn Core, ndx: u32) -> Result<u32> {
+    let ctrl = FP_CTRL::read(core)?;
+
+    if ndx >= ctrl.num_code() {
+        bail!(
+            "breakpoint {} is out of range; FPB has {} comparators",
+            ndx,
+            ctrl.num_code()
+        );
+    }
+
+    Ok(FP_COMP_BASE + ndx * 4)
+}
+
+///
+/// Enables the FPB.  Breakpoints are only taken while the FPB is enabled --
+/// and only halt the core if halting debug is enabled.
+///
+pub fn fpb_enable(core: &mut dyn Core) -> Result<()> {
+    let mut ctrl = FP_CTRL::read(core)?;
+    ctrl.set_key(true);
+    ctrl.set_enable(true);
+    ctrl.write(core)
+}
+
+///
+/// Sets the specified breakpoint comparator to break at the specified
+/// (Thumb) instruction address.
+///
+pub fn fpb_set(core: &mut dyn Core, ndx: u32, addr: u32) -> Result<()> {
+    let comp = comparator(core, ndx)?;
+
+    let val = match FP_CTRL::read(core)?.rev() {
+        0 => {
+            if addr >= 0x2000_0000 {
+                bail!("cannot set breakpoint outside of code region");
+            }
+
+            let replace = if addr & 0b10 != 0 { 0b10 } else { 0b01 };
+            (replace << 30) | (addr & 0x1fff_fffc) | 1
+        }
+        1 => (addr & !1) | 1,
+        rev => bail!("unknown FPB revision {}", rev + 1),
+    };
+
+    core.write_word_32(comp, val)
+}
+
+/// Clears the specified breakpoint comparator.
+pub fn fpb_clear(core: &mut dyn Core, ndx: u32) -> Result<()> {
+    let comp = comparator(core, ndx)?;
+    core.write_word_32(comp, 0)
+}
+
+///
+/// Returns the address at which the specified breakpoint comparator is set
+/// to break, if it is enabled.
+///
+pub fn fpb_addr(core: &mut dyn Core, ndx: u32) -> Result<Option<u32>> {
+    let val = fpb_read(core, ndx)?;
+
+    if val & 1 == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(match FP_CTRL::read(core)?.rev() {
+        0 => {
+            let offset = if val >> 30 == 0b10 { 2 } else { 0 };
+            (val & 0x1fff_fffc) + offset
+        }
+        _ => val & !1,
+    }))
+}
+
+/// Reads the raw value of the specified breakpoint comparator.
+pub fn fpb_read(core: &mut dyn Core, ndx: u32) -> Result<u32> {
+    let comp = comparator(core, ndx)?;
+    core.read_word_32(comp)
+}
+
+/// Writes the raw value of the specified breakpoint comparator.
+pub fn fpb_write(core: &mut dyn Core, ndx: u32, val: u32) -> Result<()> {
+    let comp = comparator(core, ndx)?;
+    core.write_word_32(comp, val)
+}
@@ -7,11 +7,14 @@ use crate::dwt::*;
 use crate::register;
 use crate::scs::*;
 use crate::swo::*;
+use crate::tmc::*;
 use crate::tpiu::*;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use bitfield::bitfield;
 use humility::core::Core;
 use humility::hubris::HubrisArchive;
+use std::fs::File;
+use std::io::Read;
 
 //
 // ITM Trace Enable Register
@@ -114,7 +117,6 @@ pub enum ITMPayload {
         port: u32,
         payload: Vec<u8>,
     },
-    #[allow(dead_code)]
     Hardware {
         source: u32,
         payload: [u8; 4],
@@ -294,6 +296,14 @@ fn itm_payload_decode(hdr: ITMHeader, payload: &[u8]) -> ITMPayload {
             payload: payload.to_vec(),
         },
 
+        ITMHeader::Hardware { a, .. } => {
+            let mut bytes = [0u8; 4];
+            let len = payload.len().min(4);
+            bytes[..len].copy_from_slice(&payload[..len]);
+
+            ITMPayload::Hardware { source: a as u32, payload: bytes, len }
+        }
+
         ITMHeader::LocalTimestamp1 { tc } => {
             let mut delta: u32 = 0;
 
@@ -425,15 +435,11 @@ pub fn itm_ingest(
     }
 }
 
-///
-/// Enables ITM with an explict clockscaler and traceid.
-pub fn itm_enable_explicit(
-    core: &mut dyn Core,
-    coreinfo: &CoreInfo,
-    clockscaler: u16,
-    traceid: u8,
-    stimuli: u32,
-) -> Result<()> {
+//
+// Enables trace:  TRCENA in the DEMCR, along with any vendor-specific
+// enabling.
+//
+fn trace_enable(core: &mut dyn Core, coreinfo: &CoreInfo) -> Result<()> {
     //
     // First, enable TRCENA in the DEMCR.
     //
@@ -483,6 +489,20 @@ pub fn itm_enable_explicit(
         _ => {}
     }
 
+    Ok(())
+}
+
+///
+/// Enables ITM with an explict clockscaler and traceid.
+pub fn itm_enable_explicit(
+    core: &mut dyn Core,
+    coreinfo: &CoreInfo,
+    clockscaler: u16,
+    traceid: u8,
+    stimuli: u32,
+) -> Result<()> {
+    trace_enable(core, coreinfo)?;
+
     let swoscaler = clockscaler as u32;
 
     if let Some(swo) = coreinfo.address(CoreSightComponent::SWO) {
@@ -518,6 +538,29 @@ pub fn itm_enable_explicit(
         log::trace!("{:#x?}", TPIU_ACPR::read(core)?);
     }
 
+    itm_configure(core, traceid, stimuli)
+}
+
+///
+/// Enables ITM with the specified traceid, capturing into an on-chip trace
+/// buffer rather than emitting via SWO or the TPIU.  Trace is captured
+/// until the buffer is stopped with [`TraceBuffer::stop`].
+pub fn itm_enable_buffer(
+    core: &mut dyn Core,
+    coreinfo: &CoreInfo,
+    buffer: &TraceBuffer,
+    traceid: u8,
+    stimuli: u32,
+) -> Result<()> {
+    trace_enable(core, coreinfo)?;
+    buffer.start(core, coreinfo)?;
+    itm_configure(core, traceid, stimuli)
+}
+
+//
+// Configures the ITM itself, independent of where its output goes.
+//
+fn itm_configure(core: &mut dyn Core, traceid: u8, stimuli: u32) -> Result<()> {
     //
     // Unlock the ITM.
     //
@@ -603,3 +646,165 @@ pub fn itm_enable_ingest(
         Some(traceid)
     })
 }
+
+//
+// Base address of the ITM stimulus ports, each of which is a 32-bit register
+//
+const ITM_STIM_BASE: u32 = 0xe000_0000;
+
+///
+/// The stimulus port conventionally used for markers (see
+/// [`itm_marker_write`]).
+///
+pub const ITM_MARKER_PORT: u8 = 29;
+
+///
+/// Writes the specified data to an ITM stimulus port from the debugger.  The
+/// data is emitted as instrumentation packets, just as if the target had
+/// written it, in four-byte words (with the last padded with zeroes).  The
+/// port must be enabled in the ITM_TER.
+///
+pub fn itm_stimulus_write(
+    core: &mut dyn Core,
+    port: u8,
+    data: &[u8],
+) -> Result<()> {
+    if port >= 32 {
+        bail!("invalid stimulus port {}", port);
+    }
+
+    let addr = ITM_STIM_BASE + port as u32 * 4;
+
+    for chunk in data.chunks(4) {
+        let mut word = [0u8; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+
+        //
+        // A read of a stimulus port returns (in bit 0) whether its FIFO can
+        // accept a write; the FIFO drains quickly, so we don't wait long.
+        //
+        let mut ready = false;
+
+        for _ in 0..100 {
+            if core.read_word_32(addr)? & 1 != 0 {
+                ready = true;
+                break;
+            }
+        }
+
+        if !ready {
+            bail!("stimulus port {} is not ready; is ITM enabled?", port);
+        }
+
+        core.write_word_32(addr, u32::from_le_bytes(word))?;
+    }
+
+    Ok(())
+}
+
+///
+/// Writes a marker -- a string that denotes an epoch in a trace (e.g., the
+/// start of a test) -- to the specified stimulus port.  A marker is sent as
+/// its text followed by a NUL; see [`ITMMarkers`] for its reassembly.
+///
+pub fn itm_marker_write(
+    core: &mut dyn Core,
+    port: u8,
+    text: &str,
+) -> Result<()> {
+    let mut data = text.as_bytes().to_vec();
+    data.push(0);
+    itm_stimulus_write(core, port, &data)
+}
+
+///
+/// Reassembles markers from the payloads of the instrumentation packets on
+/// the marker port.
+///
+#[derive(Debug, Default)]
+pub struct ITMMarkers {
+    pending: Vec<u8>,
+}
+
+impl ITMMarkers {
+    ///
+    /// Adds a payload, returning any markers that it completes.
+    ///
+    pub fn push(&mut self, payload: &[u8]) -> Vec<String> {
+        let mut rval = vec![];
+
+        for &b in payload {
+            if b != 0 {
+                self.pending.push(b);
+            } else if !self.pending.is_empty() {
+                rval.push(String::from_utf8_lossy(&self.pending).to_string());
+                self.pending.clear();
+            }
+        }
+
+        rval
+    }
+}
+
+///
+/// A file from which markers are read for injection:  each line appended
+/// to the file (e.g., by a test harness) is a marker.
+///
+#[derive(Debug)]
+pub struct ITMMarkerFile {
+    file: File,
+    partial: Vec<u8>,
+}
+
+impl ITMMarkerFile {
+    ///
+    /// Opens the specified file; only lines appended after it is opened are
+    /// considered markers.
+    ///
+    pub fn open(filename: &str) -> Result<Self> {
+        let mut file = File::open(filename)?;
+        std::io::copy(&mut file, &mut std::io::sink())?;
+
+        Ok(Self { file, partial: vec![] })
+    }
+
+    ///
+    /// Returns the markers that have been appended since the last call.
+    ///
+    pub fn poll(&mut self) -> Result<Vec<String>> {
+        let mut buf = vec![];
+        self.file.read_to_end(&mut buf)?;
+        self.partial.extend_from_slice(&buf);
+
+        let mut rval = vec![];
+
+        while let Some(ndx) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=ndx).collect();
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+
+            if !line.is_empty() {
+                rval.push(line);
+            }
+        }
+
+        Ok(rval)
+    }
+
+    ///
+    /// Injects any markers that have been appended to the file, returning
+    /// them.
+    ///
+    pub fn inject(
+        &mut self,
+        core: &mut dyn Core,
+        port: u8,
+    ) -> Result<Vec<String>> {
+        let markers = self.poll()?;
+
+        for marker in &markers {
+            itm_marker_write(core, port, marker)?;
+        }
+
+        Ok(markers)
+    }
+}
@@ -5,9 +5,12 @@
 pub mod debug;
 pub mod dwt;
 pub mod etm;
+pub mod fpb;
 pub mod itm;
+pub mod nvic;
 pub mod scs;
 pub mod swo;
+pub mod tmc;
 pub mod tpiu;
 
 #[macro_use]
@@ -0,0 +1,73 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::debug::*;
+use crate::register;
+use anyhow::Result;
+use bitfield::bitfield;
+use humility::core::Core;
+
+//
+// Interrupt Controller Type Register
+//
+register!(ICTR, 0xe000_e004,
+    #[derive(Copy, Clone)]
+    pub struct ICTR(u32);
+    impl Debug;
+    pub intlinesnum, _: 3, 0;
+);
+
+const NVIC_ISER: u32 = 0xe000_e100;
+const NVIC_ISPR: u32 = 0xe000_e200;
+const NVIC_IABR: u32 = 0xe000_e300;
+const NVIC_IPR: u32 = 0xe000_e400;
+
+/// The NVIC state of a single external interrupt
+#[derive(Copy, Clone, Debug)]
+pub struct NvicIrq {
+    pub irq: u32,
+    pub enabled: bool,
+    pub pending: bool,
+    pub active: bool,
+    /// Raw priority; only the implemented (high-order) bits are significant
+    pub priority: u8,
+}
+
+///
+/// Reads the state of every external interrupt that the NVIC implements.
+///
+pub fn nvic_read(core: &mut dyn Core) -> Result<Vec<NvicIrq>> {
+    let nwords = (ICTR::read(core)?.intlinesnum() + 1) as usize;
+    let nirqs = nwords * 32;
+
+    let mut bits = |base: u32| -> Result<Vec<u32>> {
+        let mut buf = vec![0u8; nwords * 4];
+        core.read_8(base, &mut buf)?;
+
+        Ok(buf
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect())
+    };
+
+    let enabled = bits(NVIC_ISER)?;
+    let pending = bits(NVIC_ISPR)?;
+    let active = bits(NVIC_IABR)?;
+
+    let mut priority = vec![0u8; nirqs];
+    core.read_8(NVIC_IPR, &mut priority)?;
+
+    let set =
+        |words: &[u32], irq: usize| words[irq / 32] & (1 << (irq % 32)) != 0;
+
+    Ok((0..nirqs)
+        .map(|irq| NvicIrq {
+            irq: irq as u32,
+            enabled: set(&enabled, irq),
+            pending: set(&pending, irq),
+            active: set(&active, irq),
+            priority: priority[irq],
+        })
+        .collect())
+}
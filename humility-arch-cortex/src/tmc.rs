@@ -0,0 +1,275 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::register_offs;
+use crate::scs::*;
+use crate::swo::*;
+use anyhow::{bail, Result};
+use humility::core::Core;
+
+//
+// The registers of the on-chip trace buffers:  the Embedded Trace Buffer
+// (ETB) and the Trace Memory Controller (TMC) configured as an Embedded
+// Trace FIFO (ETF) or Embedded Trace Buffer.  The TMC is register-compatible
+// with the ETB for our purposes, with two exceptions:  it has a MODE
+// register (which must be set to circular buffer mode), and its read and
+// write pointers are byte addresses rather than word indices.
+//
+
+//
+// RAM Size Register (in 32-bit words)
+//
+register_offs!(TMC_RSZ, 0x004,
+    pub size, _: 31, 0;
+);
+
+//
+// Status Register
+//
+register_offs!(TMC_STS, 0x00c,
+    pub empty, _: 4;
+    pub ftempty, _: 3;
+    pub ready, _: 2;
+    pub triggered, _: 1;
+    pub full, _: 0;
+);
+
+//
+// RAM Read Data Register
+//
+register_offs!(TMC_RRD, 0x010,
+    pub data, _: 31, 0;
+);
+
+//
+// RAM Read Pointer Register
+//
+register_offs!(TMC_RRP, 0x014,
+    pub pointer, set_pointer: 31, 0;
+);
+
+//
+// RAM Write Pointer Register
+//
+register_offs!(TMC_RWP, 0x018,
+    pub pointer, set_pointer: 31, 0;
+);
+
+//
+// Control Register
+//
+register_offs!(TMC_CTL, 0x020,
+    pub capture_enabled, set_capture_enabled: 0;
+);
+
+//
+// Mode Register (TMC only)
+//
+register_offs!(TMC_MODE, 0x028,
+    pub mode, set_mode: 1, 0;
+);
+
+//
+// Formatter and Flush Control Register
+//
+register_offs!(TMC_FFCR, 0x304,
+    pub stop_on_flush, set_stop_on_flush: 12;
+    pub flush_manual, set_flush_manual: 6;
+    pub flush_on_flushin, set_flush_on_flushin: 4;
+    pub trigger_insertion, set_trigger_insertion: 1;
+    pub formatting, set_formatting: 0;
+);
+
+//
+// CoreSight Trace Funnel Control Register
+//
+register_offs!(CSTF_CTRL, 0x0,
+    pub min_hold_time, _: 11, 8;
+    pub enabled, set_enabled: 7, 0;
+);
+
+//
+// TMC modes
+//
+const TMC_MODE_CIRCULAR: u32 = 0;
+
+//
+// The number of times we will poll for the buffer to become ready before
+// giving up on it.
+//
+const TMC_READY_POLLS: usize = 1000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TraceBufferKind {
+    ETB,
+    TMC,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TraceBuffer {
+    pub kind: TraceBufferKind,
+    pub base: u32,
+}
+
+///
+/// The contents of a trace buffer, as drained by [`TraceBuffer::drain`].
+///
+pub struct TraceBufferContents {
+    /// Raw (TPIU-formatted) trace data, oldest first
+    pub data: Vec<u8>,
+    /// Indicates that the buffer wrapped, overwriting its oldest data
+    pub wrapped: bool,
+}
+
+impl TraceBuffer {
+    ///
+    /// Finds the on-chip trace buffer, if any, preferring a TMC to an ETB.
+    ///
+    pub fn find(coreinfo: &CoreInfo) -> Option<Self> {
+        if let Some(base) = coreinfo.address(CoreSightComponent::TMC) {
+            Some(Self { kind: TraceBufferKind::TMC, base })
+        } else {
+            coreinfo
+                .address(CoreSightComponent::ETB)
+                .map(|base| Self { kind: TraceBufferKind::ETB, base })
+        }
+    }
+
+    ///
+    /// Returns the size of the buffer, in bytes.
+    ///
+    pub fn size(&self, core: &mut dyn Core) -> Result<u32> {
+        Ok(TMC_RSZ::read(core, self.base)?.register.size() * 4)
+    }
+
+    fn wait(&self, core: &mut dyn Core) -> Result<()> {
+        for _ in 0..TMC_READY_POLLS {
+            if TMC_STS::read(core, self.base)?.register.ready() {
+                return Ok(());
+            }
+        }
+
+        bail!("timed out waiting for {:?} at {:#x}", self.kind, self.base);
+    }
+
+    ///
+    /// Enables all ports on all trace funnels, and then starts capture into
+    /// the buffer as a circular buffer, with formatting enabled so that the
+    /// contents can be decoded as TPIU frames.
+    ///
+    pub fn start(
+        &self,
+        core: &mut dyn Core,
+        coreinfo: &CoreInfo,
+    ) -> Result<()> {
+        if let Some(cstf) =
+            coreinfo.components.get_vec(&CoreSightComponent::CSTF)
+        {
+            for base in cstf {
+                log::trace!("enabling CSTF at {:x}", base);
+                SWO_LAR::unlock(core, *base)?;
+                let mut ctrl = CSTF_CTRL::read(core, *base)?;
+                ctrl.register.set_enabled(0xff);
+                ctrl.write(core)?;
+            }
+        }
+
+        SWO_LAR::unlock(core, self.base)?;
+
+        //
+        // Disable capture, and wait for any capture in progress to drain.
+        //
+        let mut ctl = TMC_CTL::read(core, self.base)?;
+        ctl.register.set_capture_enabled(false);
+        ctl.write(core)?;
+        self.wait(core)?;
+
+        if self.kind == TraceBufferKind::TMC {
+            let mut mode = TMC_MODE::read(core, self.base)?;
+            mode.register.set_mode(TMC_MODE_CIRCULAR);
+            mode.write(core)?;
+        }
+
+        let mut ffcr = TMC_FFCR::read(core, self.base)?;
+        ffcr.register.set_formatting(true);
+        ffcr.register.set_trigger_insertion(true);
+        ffcr.register.set_stop_on_flush(false);
+        ffcr.register.set_flush_manual(false);
+        ffcr.write(core)?;
+
+        //
+        // Reset the write pointer (which also clears the full flag on the
+        // ETB; the TMC clears it when capture is enabled).
+        //
+        let mut rwp = TMC_RWP::read(core, self.base)?;
+        rwp.register.set_pointer(0);
+        rwp.write(core)?;
+
+        ctl.register.set_capture_enabled(true);
+        ctl.write(core)
+    }
+
+    ///
+    /// Stops capture, flushing any trace in flight into the buffer.
+    ///
+    pub fn stop(&self, core: &mut dyn Core) -> Result<()> {
+        SWO_LAR::unlock(core, self.base)?;
+
+        let mut ctl = TMC_CTL::read(core, self.base)?;
+
+        if ctl.register.capture_enabled() {
+            let mut ffcr = TMC_FFCR::read(core, self.base)?;
+            ffcr.register.set_stop_on_flush(true);
+            ffcr.register.set_flush_manual(true);
+            ffcr.write(core)?;
+            self.wait(core)?;
+
+            ctl.register.set_capture_enabled(false);
+            ctl.write(core)?;
+        }
+
+        self.wait(core)
+    }
+
+    ///
+    /// Reads the contents of the buffer, which must be stopped.  If the
+    /// buffer has wrapped, the contents are read starting from the write
+    /// pointer (that is, from the oldest data that remains).
+    ///
+    pub fn drain(&self, core: &mut dyn Core) -> Result<TraceBufferContents> {
+        let words = TMC_RSZ::read(core, self.base)?.register.size();
+
+        if words == 0 {
+            bail!("{:?} at {:#x} has no RAM", self.kind, self.base);
+        }
+
+        let wrapped = TMC_STS::read(core, self.base)?.register.full();
+        let mut rwp = TMC_RWP::read(core, self.base)?.register.pointer();
+
+        //
+        // The TMC's pointers are byte addresses; the ETB's are word indices.
+        //
+        let scale = match self.kind {
+            TraceBufferKind::TMC => 4,
+            TraceBufferKind::ETB => 1,
+        };
+
+        rwp = (rwp / scale) % words;
+
+        let (start, count) = if wrapped { (rwp, words) } else { (0, rwp) };
+
+        let mut rrp = TMC_RRP::read(core, self.base)?;
+        rrp.register.set_pointer(start * scale);
+        rrp.write(core)?;
+
+        let mut data = Vec::with_capacity(count as usize * 4);
+
+        for _ in 0..count {
+            let word = TMC_RRD::read(core, self.base)?.register.data();
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+
+        Ok(TraceBufferContents { data, wrapped })
+    }
+}
@@ -0,0 +1,345 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Alerting on sampled values.  Commands that sample values repeatedly
+//! (e.g., `sensors` and `watch`) take alert rules as strings of the form
+//! `NAME[:KIND](>|<)THRESHOLD`, where `KIND` is one of:
+//!
+//! - `value` (the default):  the sampled value itself, e.g. `Southwest>80`
+//!
+//! - `rate`:  the rate of change of the value, in units per second, e.g.
+//!   `Southwest:rate>2` (rising faster than 2 degrees per second) or
+//!   `Southwest:rate<-2` (falling faster than 2 degrees per second); the
+//!   threshold may be suffixed with `/s` for clarity
+//!
+//! - `delta`:  the magnitude of the change in the value since the previous
+//!   sample, e.g. `V12_SYS_A2:delta>5` (current changed by more than 5A
+//!   between samples)
+//!
+//! Values are sampled as named series.  A series name may have a qualifier
+//! separated by a period (e.g., `current.V12_SYS_A2`), in which case a rule
+//! matches the series if it names either the series or the name that
+//! follows the qualifier; `*` matches every series.  An alert is raised when
+//! a rule's condition comes to hold for a series, and is not raised again
+//! until the condition has ceased to hold -- so a transient event results in
+//! a single alert, and a sustained one does not result in an alert for
+//! every sample.
+//!
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AlertKind {
+    /// The value itself
+    Value,
+    /// The rate of change of the value, in units per second
+    Rate,
+    /// The magnitude of the change in value between consecutive samples
+    Delta,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AlertOp {
+    Above,
+    Below,
+}
+
+#[derive(Clone, Debug)]
+pub struct AlertRule {
+    /// Name of the series (or `*` for all series)
+    pub name: String,
+    pub kind: AlertKind,
+    pub op: AlertOp,
+    pub threshold: f64,
+}
+
+impl FromStr for AlertRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (pos, op) = match (s.find('>'), s.find('<')) {
+            (Some(pos), None) => (pos, AlertOp::Above),
+            (None, Some(pos)) => (pos, AlertOp::Below),
+            _ => bail!(
+                "alert \"{}\" must be of the form NAME[:KIND](>|<)THRESHOLD",
+                s
+            ),
+        };
+
+        let (subject, threshold) = (s[..pos].trim(), s[pos + 1..].trim());
+
+        let (name, kind) = match subject.rsplit_once(':') {
+            Some((name, "value")) => (name, AlertKind::Value),
+            Some((name, "rate")) => (name, AlertKind::Rate),
+            Some((name, "delta")) => (name, AlertKind::Delta),
+            Some((_, kind)) => bail!(
+                "alert \"{}\" has unknown kind \"{}\" \
+                (expected value, rate or delta)",
+                s,
+                kind
+            ),
+            None => (subject, AlertKind::Value),
+        };
+
+        if name.is_empty() {
+            bail!("alert \"{}\" is missing a name", s);
+        }
+
+        let threshold = match kind {
+            AlertKind::Rate => threshold.trim_end_matches("/s"),
+            _ => threshold,
+        };
+
+        let threshold = threshold.parse::<f64>().map_err(|_| {
+            anyhow!("alert \"{}\" has invalid threshold \"{}\"", s, threshold)
+        })?;
+
+        Ok(Self { name: name.to_string(), kind, op, threshold })
+    }
+}
+
+impl fmt::Display for AlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+
+        match self.kind {
+            AlertKind::Value => {}
+            AlertKind::Rate => write!(f, ":rate")?,
+            AlertKind::Delta => write!(f, ":delta")?,
+        }
+
+        write!(
+            f,
+            "{}{}",
+            match self.op {
+                AlertOp::Above => ">",
+                AlertOp::Below => "<",
+            },
+            self.threshold
+        )?;
+
+        if self.kind == AlertKind::Rate {
+            write!(f, "/s")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AlertRule {
+    ///
+    /// Returns true if this rule applies to the specified series.
+    ///
+    pub fn matches(&self, series: &str) -> bool {
+        self.name == "*"
+            || self.name == series
+            || matches!(
+                series.split_once('.'), Some((_, name)) if name == self.name
+            )
+    }
+}
+
+/// An alert raised by a rule.
+#[derive(Clone, Debug)]
+pub struct Alert {
+    /// The rule that raised the alert
+    pub rule: AlertRule,
+    /// The series for which the alert was raised
+    pub series: String,
+    /// The time of the sample that raised the alert, in seconds
+    pub time: f64,
+    /// The sampled value
+    pub value: f64,
+    /// The quantity that violated the rule (that is, the value, its rate of
+    /// change, or the magnitude of its change)
+    pub observed: f64,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.rule.kind {
+            AlertKind::Value => write!(
+                f,
+                "alert {}: {} is {:.3}",
+                self.rule, self.series, self.value
+            ),
+            AlertKind::Rate => write!(
+                f,
+                "alert {}: {} is {:.3}, changing at {:.3}/s",
+                self.rule, self.series, self.value, self.observed
+            ),
+            AlertKind::Delta => write!(
+                f,
+                "alert {}: {} is {:.3}, changed by {:.3}",
+                self.rule, self.series, self.value, self.observed
+            ),
+        }
+    }
+}
+
+///
+/// A set of alert rules, along with the state needed to evaluate them:  the
+/// previous sample of each series, and which rules are currently raised.
+///
+#[derive(Debug, Default)]
+pub struct Alerts {
+    rules: Vec<AlertRule>,
+    last: HashMap<String, (f64, f64)>,
+    raised: HashSet<(usize, String)>,
+}
+
+impl Alerts {
+    pub fn new(rules: &[String]) -> Result<Self> {
+        Ok(Self {
+            rules: rules
+                .iter()
+                .map(|r| r.parse())
+                .collect::<Result<Vec<_>>>()?,
+            ..Default::default()
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn rules(&self) -> &[AlertRule] {
+        &self.rules
+    }
+
+    ///
+    /// Checks that every rule (other than a wildcard) matches one of the
+    /// specified series.
+    ///
+    pub fn check_series<'a, I>(&self, series: I) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a str> + Clone,
+    {
+        for rule in self.rules.iter().filter(|r| r.name != "*") {
+            if !series.clone().into_iter().any(|s| rule.matches(s)) {
+                bail!("alert {} does not match anything sampled", rule);
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Records a sample of the specified series at the specified time (in
+    /// seconds), returning any alerts that it raises.
+    ///
+    pub fn sample(
+        &mut self,
+        series: &str,
+        time: f64,
+        value: f64,
+    ) -> Vec<Alert> {
+        let last = self.last.insert(series.to_string(), (time, value));
+        let mut rval = vec![];
+
+        for (ndx, rule) in self.rules.iter().enumerate() {
+            if !rule.matches(series) {
+                continue;
+            }
+
+            let observed = match (rule.kind, last) {
+                (AlertKind::Value, _) => Some(value),
+                (AlertKind::Delta, Some((_, lval))) => {
+                    Some((value - lval).abs())
+                }
+                (AlertKind::Rate, Some((ltime, lval))) if time > ltime => {
+                    Some((value - lval) / (time - ltime))
+                }
+                _ => None,
+            };
+
+            let holds = match (observed, rule.op) {
+                (Some(o), AlertOp::Above) => o > rule.threshold,
+                (Some(o), AlertOp::Below) => o < rule.threshold,
+                (None, _) => false,
+            };
+
+            let key = (ndx, series.to_string());
+
+            if !holds {
+                self.raised.remove(&key);
+            } else if self.raised.insert(key) {
+                rval.push(Alert {
+                    rule: rule.clone(),
+                    series: series.to_string(),
+                    time,
+                    value,
+                    observed: observed.unwrap(),
+                });
+            }
+        }
+
+        rval
+    }
+
+    ///
+    /// Notes that a series could not be sampled:  its next sample will not be
+    /// compared against its last one.
+    ///
+    pub fn missed(&mut self, series: &str) {
+        self.last.remove(series);
+    }
+
+    ///
+    /// Discards all previous samples (e.g., because the target has reset);
+    /// rates and deltas will not be computed until each series is sampled
+    /// anew.
+    ///
+    pub fn reset(&mut self) {
+        self.last.clear();
+        self.raised.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alert_rules() {
+        let rule: AlertRule = "Southwest:rate>2/s".parse().unwrap();
+        assert_eq!(rule.name, "Southwest");
+        assert_eq!(rule.kind, AlertKind::Rate);
+        assert_eq!(rule.op, AlertOp::Above);
+        assert_eq!(rule.threshold, 2.0);
+        assert!(rule.matches("temp.Southwest"));
+        assert!(!rule.matches("temp.Northeast"));
+
+        let rule: AlertRule = "current.V12_SYS_A2:delta>5".parse().unwrap();
+        assert!(rule.matches("current.V12_SYS_A2"));
+        assert!(!rule.matches("voltage.V12_SYS_A2"));
+
+        assert!("Southwest".parse::<AlertRule>().is_err());
+        assert!("Southwest:slope>2".parse::<AlertRule>().is_err());
+        assert!(">2".parse::<AlertRule>().is_err());
+    }
+
+    #[test]
+    fn test_alerts() {
+        let rules = vec!["T:rate>2".to_string(), "I:delta>5".to_string()];
+        let mut alerts = Alerts::new(&rules).unwrap();
+
+        assert!(alerts.sample("temp.T", 0.0, 30.0).is_empty());
+        assert!(alerts.sample("temp.T", 1.0, 31.0).is_empty());
+        assert_eq!(alerts.sample("temp.T", 2.0, 34.0).len(), 1);
+        assert!(alerts.sample("temp.T", 3.0, 37.0).is_empty());
+        assert!(alerts.sample("temp.T", 4.0, 37.5).is_empty());
+        assert_eq!(alerts.sample("temp.T", 5.0, 40.0).len(), 1);
+
+        assert!(alerts.sample("current.I", 0.0, 10.0).is_empty());
+        assert_eq!(alerts.sample("current.I", 1.0, 3.0).len(), 1);
+
+        alerts.reset();
+        assert!(alerts.sample("current.I", 2.0, 20.0).is_empty());
+    }
+}
@@ -0,0 +1,364 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Retrieval of attestation data (certificate chain, measurement log and
+//! attestations) from a root of trust, and decoding of their formats.  The
+//! RoT implements the `Attest` interface; an SP attached to a RoT proxies
+//! the same operations via its `SpRot` interface, so we can use either.
+
+use crate::hiffy::*;
+use crate::idol::{IdolArgument, IdolOperation};
+use anyhow::{anyhow, bail, Result};
+use humility::core::Core;
+use humility::hubris::*;
+
+const INTERFACES: &[&str] = &["Attest", "SpRot"];
+
+pub const NONCE_SIZE: usize = 32;
+
+pub struct Attest<'a> {
+    hubris: &'a HubrisArchive,
+    context: HiffyContext<'a>,
+    funcs: HiffyFunctions,
+    iface: &'static str,
+}
+
+impl<'a> Attest<'a> {
+    pub fn new(
+        hubris: &'a HubrisArchive,
+        core: &mut dyn Core,
+        timeout: u32,
+    ) -> Result<Self> {
+        let iface = INTERFACES
+            .iter()
+            .find(|&&iface| {
+                IdolOperation::new(hubris, iface, "cert_chain_len", None)
+                    .is_ok()
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "no task implements attestation (via {})",
+                    INTERFACES.join(" or ")
+                )
+            })?;
+
+        let mut context = HiffyContext::new(hubris, core, timeout)?;
+        let funcs = context.functions()?;
+
+        Ok(Self { hubris, context, funcs, iface })
+    }
+
+    /// The interface via which attestation data is retrieved
+    pub fn interface(&self) -> &str {
+        self.iface
+    }
+
+    fn call(
+        &mut self,
+        core: &mut dyn Core,
+        name: &str,
+        args: &[(&str, IdolArgument)],
+        leases: (Option<&[u8]>, Option<usize>),
+    ) -> Result<Vec<u8>> {
+        let op = IdolOperation::new(self.hubris, self.iface, name, None)?;
+        let (data, write) = leases;
+        let leases = (data.map(|r| r.len() as u32), write.map(|w| w as u32));
+
+        let reply = self.context.idol_call(
+            core,
+            &self.funcs,
+            &op,
+            args,
+            leases,
+            data,
+        )?;
+
+        match reply {
+            Ok(val) => Ok(val),
+            Err(e) => bail!("{} failed: {}", name, e),
+        }
+    }
+
+    fn call_u32(
+        &mut self,
+        core: &mut dyn Core,
+        name: &str,
+        args: &[(&str, IdolArgument)],
+    ) -> Result<u32> {
+        let val = self.call(core, name, args, (None, None))?;
+
+        if val.len() != 4 {
+            bail!("{} returned {} bytes; expected 4", name, val.len());
+        }
+
+        Ok(u32::from_le_bytes(val[..].try_into()?))
+    }
+
+    ///
+    /// Reads an object of the specified length in chunks of at most the
+    /// size of the HIF data, calling `name` with each chunk's offset.
+    ///
+    fn read_chunked(
+        &mut self,
+        core: &mut dyn Core,
+        name: &str,
+        index: Option<u32>,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let chunk = self.context.data_size();
+        let mut rval = vec![];
+
+        while rval.len() < len {
+            let offset = rval.len() as u64;
+            let nbytes = std::cmp::min(chunk, len - rval.len());
+
+            let mut args = vec![("offset", IdolArgument::Scalar(offset))];
+
+            if let Some(index) = index {
+                args.push(("index", IdolArgument::Scalar(index as u64)));
+            }
+
+            self.call(core, name, &args, (None, Some(nbytes)))?;
+            rval.extend(self.context.read_data(core, 0, nbytes)?);
+        }
+
+        Ok(rval)
+    }
+
+    /// Returns the certificate chain, as DER-encoded certificates
+    pub fn cert_chain(&mut self, core: &mut dyn Core) -> Result<Vec<Vec<u8>>> {
+        let n = self.call_u32(core, "cert_chain_len", &[])?;
+        let mut rval = vec![];
+
+        for index in 0..n {
+            let arg = [("index", IdolArgument::Scalar(index as u64))];
+            let len = self.call_u32(core, "cert_len", &arg)? as usize;
+            rval.push(self.read_chunked(core, "cert", Some(index), len)?);
+        }
+
+        Ok(rval)
+    }
+
+    /// Returns the raw measurement log
+    pub fn log(&mut self, core: &mut dyn Core) -> Result<Vec<u8>> {
+        let len = self.call_u32(core, "log_len", &[])? as usize;
+        self.read_chunked(core, "log", None, len)
+    }
+
+    /// Returns the raw attestation over the measurement log and the nonce
+    pub fn attest(
+        &mut self,
+        core: &mut dyn Core,
+        nonce: &[u8],
+    ) -> Result<Vec<u8>> {
+        if nonce.len() != NONCE_SIZE {
+            bail!("nonce must be {} bytes", NONCE_SIZE);
+        }
+
+        let len = self.call_u32(core, "attest_len", &[])? as usize;
+        self.call(core, "attest", &[], (Some(nonce), Some(len)))?;
+        self.context.read_data(core, nonce.len(), len)
+    }
+}
+
+#[derive(Debug)]
+pub enum Measurement {
+    Sha3_256([u8; 32]),
+}
+
+///
+/// Decodes a measurement log, which is serialized with `hubpack`:  a `u32`
+/// count of valid measurements, followed by the measurements themselves,
+/// each of which is a tag followed by a digest.
+///
+pub fn decode_log(buf: &[u8]) -> Result<Vec<Measurement>> {
+    if buf.len() < 4 {
+        bail!("measurement log is too short ({} bytes)", buf.len());
+    }
+
+    let count = u32::from_le_bytes(buf[..4].try_into()?) as usize;
+    let mut offs = 4;
+    let mut rval = vec![];
+
+    for ndx in 0..count {
+        match buf.get(offs) {
+            Some(0) if buf.len() >= offs + 33 => {
+                rval.push(Measurement::Sha3_256(
+                    buf[offs + 1..offs + 33].try_into()?,
+                ));
+                offs += 33;
+            }
+            Some(0) => bail!("measurement {} is truncated", ndx),
+            Some(tag) => bail!("measurement {} has unknown tag {}", ndx, tag),
+            None => bail!("log has {} measurements; expected {}", ndx, count),
+        }
+    }
+
+    Ok(rval)
+}
+
+#[derive(Debug)]
+pub enum Attestation {
+    Ed25519([u8; 64]),
+}
+
+/// Decodes an attestation, which (like the log) is serialized by `hubpack`
+pub fn decode_attestation(buf: &[u8]) -> Result<Attestation> {
+    match buf.first() {
+        Some(0) if buf.len() >= 65 => {
+            Ok(Attestation::Ed25519(buf[1..65].try_into()?))
+        }
+        Some(0) => bail!("Ed25519 attestation is truncated"),
+        Some(tag) => bail!("attestation has unknown tag {}", tag),
+        None => bail!("attestation is empty"),
+    }
+}
+
+///
+/// A DER element:  its tag, and the contents and total length of the
+/// element.
+///
+fn der(buf: &[u8]) -> Result<(u8, &[u8], usize)> {
+    let short = || anyhow!("DER element is truncated");
+    let tag = *buf.first().ok_or_else(short)?;
+    let first = *buf.get(1).ok_or_else(short)?;
+
+    let (len, hdr) = if first & 0x80 == 0 {
+        (first as usize, 2)
+    } else {
+        let n = (first & 0x7f) as usize;
+
+        if n == 0 || n > 4 {
+            bail!("unsupported DER length encoding 0x{:x}", first);
+        }
+
+        let bytes = buf.get(2..2 + n).ok_or_else(short)?;
+        (bytes.iter().fold(0, |len, &b| (len << 8) | b as usize), 2 + n)
+    };
+
+    let contents = buf.get(hdr..hdr + len).ok_or_else(short)?;
+
+    Ok((tag, contents, hdr + len))
+}
+
+/// Iterates over the elements within a DER constructed value
+fn der_elements(mut buf: &[u8]) -> Result<Vec<(u8, &[u8])>> {
+    let mut rval = vec![];
+
+    while !buf.is_empty() {
+        let (tag, contents, len) = der(buf)?;
+        rval.push((tag, contents));
+        buf = &buf[len..];
+    }
+
+    Ok(rval)
+}
+
+fn der_name(buf: &[u8]) -> Result<String> {
+    let mut rval = vec![];
+
+    for (_, set) in der_elements(buf)? {
+        for (_, atv) in der_elements(set)? {
+            let atv = der_elements(atv)?;
+
+            let (oid, value) = match atv.as_slice() {
+                [(0x06, oid), (_, value)] => (*oid, *value),
+                _ => bail!("malformed attribute in name"),
+            };
+
+            let name = match oid {
+                [0x55, 0x04, 0x03] => "CN".to_string(),
+                [0x55, 0x04, 0x05] => "serialNumber".to_string(),
+                [0x55, 0x04, 0x06] => "C".to_string(),
+                [0x55, 0x04, 0x0a] => "O".to_string(),
+                [0x55, 0x04, 0x0b] => "OU".to_string(),
+                _ => format!("{:02x?}", oid),
+            };
+
+            rval.push(format!("{}={}", name, String::from_utf8_lossy(value)));
+        }
+    }
+
+    Ok(rval.join(", "))
+}
+
+#[derive(Debug)]
+pub struct Certificate {
+    pub serial: Vec<u8>,
+    pub issuer: String,
+    pub subject: String,
+}
+
+///
+/// Decodes the interesting bits of a DER-encoded X.509 certificate.  This
+/// isn't a general-purpose parser:  it just walks the `TBSCertificate`
+/// far enough to find the serial number, issuer and subject.
+///
+pub fn decode_cert(buf: &[u8]) -> Result<Certificate> {
+    let (tag, cert, _) = der(buf)?;
+
+    if tag != 0x30 {
+        bail!("certificate is not a SEQUENCE");
+    }
+
+    let (tag, tbs, _) = der(cert)?;
+
+    if tag != 0x30 {
+        bail!("TBSCertificate is not a SEQUENCE");
+    }
+
+    let mut fields = der_elements(tbs)?.into_iter().peekable();
+
+    //
+    // The version is optional (and explicitly tagged).
+    //
+    fields.next_if(|(tag, _)| *tag == 0xa0);
+
+    let mut next =
+        |what: &str| fields.next().ok_or_else(|| anyhow!("missing {}", what));
+
+    let serial = next("serial number")?.1.to_vec();
+    next("signature algorithm")?;
+    let issuer = der_name(next("issuer")?.1)?;
+    next("validity")?;
+    let subject = der_name(next("subject")?.1)?;
+
+    Ok(Certificate { serial, issuer, subject })
+}
+
+/// Encodes a DER certificate as PEM
+pub fn pem(der: &[u8]) -> String {
+    const ALPHABET: &[u8] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = vec![];
+
+    for chunk in der.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize]);
+            } else {
+                encoded.push(b'=');
+            }
+        }
+    }
+
+    let mut rval = String::from("-----BEGIN CERTIFICATE-----\n");
+
+    for line in encoded.chunks(64) {
+        rval.push_str(&String::from_utf8_lossy(line));
+        rval.push('\n');
+    }
+
+    rval.push_str("-----END CERTIFICATE-----\n");
+    rval
+}
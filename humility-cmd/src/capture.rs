@@ -0,0 +1,292 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Capture of I2C transactions.  When capture is enabled (via the global
+//! `--capture-i2c` option), every I2C transaction performed via HIF is
+//! recorded -- along with its result -- and the transactions are written to
+//! a JSON file when the command completes.  A capture names its buses by
+//! controller and port name (rather than by port index) so that it can be
+//! replayed (via `humility i2c --replay`) against a different board.
+//!
+//! Transactions are recovered by following the stack of the HIF program
+//! that performed them; programs that contain control flow (e.g., the loop
+//! of `humility i2c --flash`) cannot be followed and are not captured.
+//!
+
+use crate::hiffy::HiffyFunctions;
+use anyhow::{bail, Result};
+use hif::*;
+use humility::hubris::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+
+/// The version of the capture file format
+pub const CAPTURE_VERSION: u32 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// A read, optionally preceded by a write of a register
+    Read,
+    /// A write, optionally preceded by a register
+    Write,
+    /// A write followed by a read after a repeated start
+    WriteRead,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The transaction succeeded, returning the specified bytes
+    Ok(Vec<u8>),
+    /// The transaction failed with the named error
+    Err(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Transaction {
+    pub operation: Operation,
+    pub controller: u8,
+    pub port: String,
+    pub mux: Option<u8>,
+    pub segment: Option<u8>,
+    pub address: u8,
+    pub register: Option<u8>,
+    /// Bytes written (for writes and write-then-reads)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub write: Vec<u8>,
+    /// Number of bytes read, if specified (for reads and write-then-reads)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbytes: Option<u8>,
+    pub outcome: Outcome,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Capture {
+    pub version: u32,
+    /// The name of the image on which the capture was made, if known
+    pub image: Option<String>,
+    pub transactions: Vec<Transaction>,
+}
+
+struct State {
+    image: Option<String>,
+    transactions: Vec<Transaction>,
+}
+
+lazy_static::lazy_static! {
+    static ref CAPTURE: Mutex<Option<State>> = Mutex::new(None);
+}
+
+/// Enables capture of I2C transactions.
+pub fn start() {
+    *CAPTURE.lock().unwrap() =
+        Some(State { image: None, transactions: vec![] });
+}
+
+/// Returns true if capture of I2C transactions is enabled.
+pub fn active() -> bool {
+    CAPTURE.lock().unwrap().is_some()
+}
+
+///
+/// Writes the captured transactions to the specified file, returning the
+/// number of transactions written.
+///
+pub fn save(filename: &str) -> Result<usize> {
+    let guard = CAPTURE.lock().unwrap();
+
+    let state = match guard.as_ref() {
+        Some(state) => state,
+        None => bail!("I2C capture is not enabled"),
+    };
+
+    let capture = Capture {
+        version: CAPTURE_VERSION,
+        image: state.image.clone(),
+        transactions: state.transactions.clone(),
+    };
+
+    fs::write(filename, serde_json::to_string_pretty(&capture)?)?;
+
+    Ok(capture.transactions.len())
+}
+
+/// Loads a capture from the specified file.
+pub fn load(filename: &str) -> Result<Capture> {
+    let capture: Capture =
+        serde_json::from_str(&fs::read_to_string(filename)?)?;
+
+    if capture.version != CAPTURE_VERSION {
+        bail!(
+            "{}: capture is version {}; expected version {}",
+            filename,
+            capture.version,
+            CAPTURE_VERSION
+        );
+    }
+
+    Ok(capture)
+}
+
+//
+// Given the (followed) stack at the time of a call to an I2C function,
+// decodes the arguments into a transaction, if we can.
+//
+fn decode(
+    hubris: &HubrisArchive,
+    name: &str,
+    stack: &[Option<u32>],
+    outcome: Outcome,
+) -> Option<Transaction> {
+    let byte = |v: &Option<u32>| v.map(|v| v as u8);
+
+    //
+    // Every I2C function has the same leading arguments: controller, port,
+    // mux, segment and address.  What follows depends on the function.
+    //
+    let (operation, nlead, register, write, nbytes) = match name {
+        "I2cRead" => {
+            let n = stack.len().checked_sub(2)?;
+            (Operation::Read, n, byte(&stack[n]), vec![], byte(&stack[n + 1]))
+        }
+        "I2cWrite" => {
+            let len = (*stack.last()?)? as usize;
+            let data = stack.len().checked_sub(len + 1)?;
+            let write = stack[data..stack.len() - 1]
+                .iter()
+                .map(byte)
+                .collect::<Option<Vec<_>>>()?;
+            let register = byte(stack.get(data.checked_sub(1)?)?);
+            (Operation::Write, data - 1, register, write, None)
+        }
+        "I2cWriteRead" => {
+            let nread = byte(stack.last()?);
+            let len = (*stack.get(stack.len().checked_sub(2)?)?)? as usize;
+            let data = stack.len().checked_sub(len + 2)?;
+            let write = stack[data..stack.len() - 2]
+                .iter()
+                .map(byte)
+                .collect::<Option<Vec<_>>>()?;
+            (Operation::WriteRead, data, None, write, nread)
+        }
+        _ => return None,
+    };
+
+    let lead = stack.get(nlead.checked_sub(5)?..nlead)?;
+    let controller = byte(&lead[0])?;
+    let index = byte(&lead[1])?;
+
+    let port = hubris
+        .manifest
+        .i2c_buses
+        .iter()
+        .find(|b| b.controller == controller && b.port.index == index)
+        .map_or_else(|| index.to_string(), |b| b.port.name.clone());
+
+    Some(Transaction {
+        operation,
+        controller,
+        port,
+        mux: byte(&lead[2]),
+        segment: byte(&lead[3]),
+        address: byte(&lead[4])?,
+        register,
+        write,
+        nbytes,
+        outcome,
+    })
+}
+
+///
+/// Records the I2C transactions performed by a HIF program, given the
+/// program and its results.
+///
+pub(crate) fn record(
+    hubris: &HubrisArchive,
+    functions: &HiffyFunctions,
+    ops: &[Op],
+    results: &[Result<Vec<u8>, u32>],
+) {
+    let mut guard = CAPTURE.lock().unwrap();
+
+    let state = match guard.as_mut() {
+        Some(state) => state,
+        None => return,
+    };
+
+    if state.image.is_none() {
+        state.image = hubris.manifest.name.clone();
+    }
+
+    let byid =
+        functions.0.values().map(|f| (f.id.0, f)).collect::<HashMap<_, _>>();
+
+    let is_i2c = |id: &TargetFunction| {
+        byid.get(&id.0).map_or(false, |f| f.name.starts_with("I2c"))
+    };
+
+    let mut stack: Vec<Option<u32>> = vec![];
+    let mut results = results.iter();
+
+    for (ndx, op) in ops.iter().enumerate() {
+        match op {
+            Op::Push(val) => stack.push(Some((*val).into())),
+            Op::Push16(val) => stack.push(Some((*val).into())),
+            Op::Push32(val) => stack.push(Some(*val)),
+            Op::PushNone => stack.push(None),
+            Op::Drop => {
+                stack.pop();
+            }
+            Op::DropN(n) => {
+                stack.truncate(stack.len().saturating_sub(*n as usize));
+            }
+            Op::Call(id) => {
+                let result = match results.next() {
+                    Some(result) => result,
+                    None => break,
+                };
+
+                let func = match byid.get(&id.0) {
+                    Some(func) if is_i2c(id) => func,
+                    _ => continue,
+                };
+
+                let outcome = match result {
+                    Ok(val) => Outcome::Ok(val.clone()),
+                    Err(code) => Outcome::Err(func.strerror(*code)),
+                };
+
+                match decode(hubris, &func.name, &stack, outcome) {
+                    Some(t) => state.transactions.push(t),
+                    None => {
+                        humility::msg!(
+                            "warning: could not capture {} call",
+                            func.name
+                        );
+                    }
+                }
+            }
+            Op::Done => break,
+            _ => {
+                let calls = ops[ndx..]
+                    .iter()
+                    .filter(|op| matches!(op, Op::Call(id) if is_i2c(id)))
+                    .count();
+
+                if calls != 0 {
+                    humility::msg!(
+                        "warning: I2C calls in HIF program with control \
+                        flow were not captured"
+                    );
+                }
+
+                break;
+            }
+        }
+    }
+}
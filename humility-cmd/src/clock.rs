@@ -0,0 +1,172 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Synchronization of the kernel's system time with host wall-clock time.
+//!
+//! We sample the kernel's system time (in ticks) against the host's clock,
+//! bracketing each read of the target by reads of the host clock; the
+//! midpoint of the bracket is taken as the host time of the sample, and half
+//! its width as the uncertainty.  From the samples, we fit a model of host
+//! time as an offset plus ticks times a tick period:  the period is fit by
+//! least squares once the samples span long enough to measure drift (and is
+//! otherwise taken to be nominal), and the offset is that of the sample
+//! with the least uncertainty.
+
+use crate::kernel::read_ticks;
+use anyhow::Result;
+use humility::core::Core;
+use humility::hubris::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The nominal period of a kernel tick, in seconds
+pub const NOMINAL_PERIOD: f64 = 0.001;
+
+/// The span of host time over which samples must be taken for us to
+/// measure drift, in seconds
+const DRIFT_SPAN: f64 = 10.0;
+
+/// The number of samples that we retain
+const MAX_SAMPLES: usize = 256;
+
+#[derive(Copy, Clone, Debug)]
+struct Sample {
+    ticks: u64,
+    host: f64,
+    uncertainty: f64,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ClockModel {
+    /// Host time at tick 0, in seconds since the Unix epoch
+    pub offset: f64,
+    /// Host time per tick, in seconds
+    pub period: f64,
+    /// Uncertainty of the offset, in seconds
+    pub uncertainty: f64,
+}
+
+impl ClockModel {
+    ///
+    /// Returns the drift of the target's clock relative to the host's, in
+    /// parts per million.
+    ///
+    pub fn drift(&self) -> f64 {
+        (self.period / NOMINAL_PERIOD - 1.0) * 1_000_000.0
+    }
+
+    pub fn wallclock(&self, ticks: u64) -> SystemTime {
+        let secs = self.offset + ticks as f64 * self.period;
+        UNIX_EPOCH + Duration::from_secs_f64(secs.max(0.0))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    samples: Vec<Sample>,
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+impl ClockSync {
+    ///
+    /// Synchronizes by taking the specified number of samples.
+    ///
+    pub fn sync(
+        hubris: &HubrisArchive,
+        core: &mut dyn Core,
+        samples: usize,
+    ) -> Result<Self> {
+        let mut sync = Self::default();
+
+        for _ in 0..samples {
+            sync.sample(hubris, core)?;
+        }
+
+        Ok(sync)
+    }
+
+    ///
+    /// Takes a sample, returning the system time.  A system time lower than
+    /// that of the last sample indicates that the target has reset, in which
+    /// case the samples of the previous boot are discarded.
+    ///
+    pub fn sample(
+        &mut self,
+        hubris: &HubrisArchive,
+        core: &mut dyn Core,
+    ) -> Result<u64> {
+        let before = now();
+        let ticks = read_ticks(hubris, core)?;
+        let after = now();
+
+        self.add(ticks, (before + after) / 2.0, (after - before) / 2.0);
+        Ok(ticks)
+    }
+
+    fn add(&mut self, ticks: u64, host: f64, uncertainty: f64) {
+        if let Some(last) = self.samples.last() {
+            if ticks < last.ticks {
+                self.samples.clear();
+            }
+        }
+
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.remove(0);
+        }
+
+        self.samples.push(Sample { ticks, host, uncertainty });
+    }
+
+    pub fn model(&self) -> Option<ClockModel> {
+        let first = self.samples.first()?;
+        let last = self.samples.last()?;
+
+        let period = if last.host - first.host >= DRIFT_SPAN {
+            let n = self.samples.len() as f64;
+            let mt =
+                self.samples.iter().map(|s| s.ticks as f64).sum::<f64>() / n;
+            let mh =
+                self.samples.iter().map(|s| s.host - first.host).sum::<f64>()
+                    / n;
+
+            let (mut cov, mut var) = (0.0, 0.0);
+
+            for s in &self.samples {
+                let dt = s.ticks as f64 - mt;
+                cov += dt * (s.host - first.host - mh);
+                var += dt * dt;
+            }
+
+            if var > 0.0 {
+                cov / var
+            } else {
+                NOMINAL_PERIOD
+            }
+        } else {
+            NOMINAL_PERIOD
+        };
+
+        let best = self.samples.iter().min_by(|a, b| {
+            a.uncertainty.partial_cmp(&b.uncertainty).unwrap()
+        })?;
+
+        Some(ClockModel {
+            offset: best.host - best.ticks as f64 * period,
+            period,
+            uncertainty: best.uncertainty,
+        })
+    }
+}
+
+///
+/// Formats a wall-clock time as RFC 3339, to the millisecond.
+///
+pub fn rfc3339(time: SystemTime) -> String {
+    humantime::format_rfc3339_millis(time).to_string()
+}
@@ -0,0 +1,156 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! The Humility configuration file, `humility.toml`.  This is
+//! `HUMILITY_CONFIG` if set; otherwise, it is the first `humility.toml`
+//! found in the current directory or any of its parents, falling back to
+//! `~/.config/humility/humility.toml`.
+//!
+//! In addition to targets (which are interpreted by Humility itself), the
+//! configuration file can contain named views for commands:  saved
+//! selections of what a command displays and how.  Views are tables of the
+//! form `[views.COMMAND.NAME]`, the contents of which are interpreted by the
+//! command.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+fn home() -> Option<PathBuf> {
+    let home = PathBuf::from(std::env::var_os("HOME")?);
+    Some(home.join(".config").join("humility").join("humility.toml"))
+}
+
+///
+/// Returns the path of the configuration file, if there is one.
+///
+pub fn path() -> Option<PathBuf> {
+    if let Some(config) = std::env::var_os("HUMILITY_CONFIG") {
+        return Some(PathBuf::from(config));
+    }
+
+    if let Ok(cwd) = std::env::current_dir() {
+        for dir in cwd.ancestors() {
+            let candidate = dir.join("humility.toml");
+
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+        }
+    }
+
+    home().filter(|candidate| candidate.is_file())
+}
+
+fn load(path: &Path) -> Result<toml::Value> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    toml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", path.display()))
+}
+
+fn lookup<'a>(
+    config: &'a toml::Value,
+    command: &str,
+    name: &str,
+) -> Option<&'a toml::Value> {
+    config.get("views")?.get(command)?.get(name)
+}
+
+///
+/// Loads the named view for the specified command.
+///
+pub fn view<T: DeserializeOwned>(command: &str, name: &str) -> Result<T> {
+    let path = path().ok_or_else(|| {
+        anyhow!("view \"{}\" specified, but no humility.toml found", name)
+    })?;
+
+    let config = load(&path)?;
+
+    let view = lookup(&config, command, name).ok_or_else(|| {
+        let known = config
+            .get("views")
+            .and_then(|views| views.get(command))
+            .and_then(|views| views.as_table())
+            .map(|views| views.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        anyhow!(
+            "view \"{}\" not found in {}; known views: {}",
+            name,
+            path.display(),
+            if known.is_empty() {
+                "none".to_string()
+            } else {
+                known.join(", ")
+            }
+        )
+    })?;
+
+    view.clone().try_into().with_context(|| {
+        format!("invalid view \"{}\" in {}", name, path.display())
+    })
+}
+
+///
+/// Saves the specified view for the specified command by appending it to
+/// the configuration file (which is created if there isn't one), returning
+/// the path of the file.  A view that already exists is not overwritten.
+///
+pub fn save_view<T: Serialize>(
+    command: &str,
+    name: &str,
+    view: &T,
+) -> Result<PathBuf> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+
+    if name.is_empty() || !name.chars().all(valid) {
+        bail!(
+            "invalid view name \"{}\"; names may contain only letters, \
+            numbers, '-' and '_'",
+            name
+        );
+    }
+
+    let path = match path() {
+        Some(path) => path,
+        None => {
+            let path = home().ok_or_else(|| anyhow!("HOME is not set"))?;
+
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).with_context(|| {
+                    format!("failed to create {}", dir.display())
+                })?;
+            }
+
+            path
+        }
+    };
+
+    if path.is_file() && lookup(&load(&path)?, command, name).is_some() {
+        bail!(
+            "view \"{}\" already exists in {}; remove it to save it anew",
+            name,
+            path.display()
+        );
+    }
+
+    let body = toml::to_string(view)?;
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+
+    write!(file, "\n[views.{}.{}]\n{}", command, name, body)?;
+
+    Ok(path)
+}
@@ -0,0 +1,125 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Discovery and decoding of event counters created via the `counters!`
+//! macro in the Hubris `counters` crate.
+//!
+//! The macro creates a static (named `__COUNTERS`, in the module in which it
+//! is invoked) containing a counter for each variant of the counted type;
+//! variants that themselves contain counted types have nested counters.  We
+//! find counters by name, and flatten them into their individual counts,
+//! named by their path through the structure (e.g., `Reset` or
+//! `Read.Timeout`).  Atomic wrappers are elided from the path.
+
+use crate::reflect::{self, Base, Value};
+use anyhow::Result;
+use humility::core::Core;
+use humility::hubris::*;
+use std::collections::BTreeMap;
+
+/// A counters variable, as found in the archive.
+#[derive(Copy, Clone, Debug)]
+pub struct CountersVariable<'a> {
+    pub name: &'a str,
+    pub task: &'a str,
+    pub variable: &'a HubrisVariable,
+}
+
+impl<'a> CountersVariable<'a> {
+    ///
+    /// Reads the counters, returning each count by its path.  As with ring
+    /// buffers, this does not halt the target.
+    ///
+    pub fn read(
+        &self,
+        hubris: &HubrisArchive,
+        core: &mut dyn Core,
+    ) -> Result<BTreeMap<String, u64>> {
+        let ty = hubris.lookup_type(self.variable.goff)?;
+        let mut buf: Vec<u8> = vec![0; self.variable.size];
+
+        core.read_8(self.variable.addr, buf.as_mut_slice())?;
+
+        let mut rval = BTreeMap::new();
+        leaves(
+            String::new(),
+            &reflect::load_value(hubris, &buf, ty, 0)?,
+            &mut rval,
+        );
+
+        Ok(rval)
+    }
+}
+
+fn child(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+fn leaves(path: String, val: &Value, rval: &mut BTreeMap<String, u64>) {
+    match val {
+        Value::Base(base) => {
+            let n = match *base {
+                Base::U8(n) => n as u64,
+                Base::U16(n) => n as u64,
+                Base::U32(n) => n as u64,
+                Base::U64(n) => n,
+                _ => return,
+            };
+
+            rval.insert(path, n);
+        }
+        Value::Struct(s) => {
+            //
+            // An atomic (and the `UnsafeCell` within it) is a wrapper around
+            // the count; we don't include its member in the path.
+            //
+            if s.name().starts_with("Atomic")
+                || s.name().starts_with("UnsafeCell")
+            {
+                if let Some((_, member)) = s.iter().next() {
+                    return leaves(path, member, rval);
+                }
+            }
+
+            for (name, member) in s.iter() {
+                leaves(child(&path, name), member, rval);
+            }
+        }
+        Value::Tuple(t) => {
+            for (ndx, member) in t.iter().enumerate() {
+                leaves(child(&path, &ndx.to_string()), member, rval);
+            }
+        }
+        Value::Array(a) => {
+            for (ndx, member) in a.iter().enumerate() {
+                leaves(format!("{}[{}]", path, ndx), member, rval);
+            }
+        }
+        Value::Enum(_) | Value::Ptr(_) => {}
+    }
+}
+
+///
+/// Finds all counters in the archive, sorted by task and name.
+///
+pub fn counters(hubris: &HubrisArchive) -> Result<Vec<CountersVariable>> {
+    let mut rval = vec![];
+
+    for (name, variable) in hubris.qualified_variables() {
+        if !name.ends_with("COUNTERS") {
+            continue;
+        }
+
+        let task = &hubris.lookup_module(HubrisTask::from(variable.goff))?.name;
+        rval.push(CountersVariable { name, task, variable });
+    }
+
+    rval.sort_by_key(|c| (c.task, c.name, c.variable.addr));
+
+    Ok(rval)
+}
@@ -0,0 +1,608 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Decoding of messages that use deferred formatting.  Rather than
+//! formatting a message on the target, a task sends the index of its format
+//! string (interned in the `.defmt` section of its ELF object; see
+//! [`HubrisArchive::lookup_deferred`]) followed by the values of its
+//! arguments, and the message is reconstructed here.  Each message is
+//! encoded as:
+//!
+//! - the index of its format string, as a little-endian `u16`;
+//! - if the task has a timestamp format, the arguments of the timestamp;
+//! - the arguments of the message, in the order of their positions.
+//!
+//! Integers and floats are little-endian at their natural size (`usize` and
+//! `isize` being 4 bytes); `bool` is a single byte; `str` and `[u8]` are
+//! preceded by their length as a LEB128-encoded integer; `[u8; N]` is `N`
+//! bytes; `istr` (an interned string) is a `u16` index; and a value of a type
+//! that has its own format (`{}` or `{=?}`) is encoded as a message itself.
+//!
+
+use anyhow::{anyhow, bail, Result};
+use humility::hubris::*;
+use std::fmt::Write;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Unsigned(usize),
+    Signed(usize),
+    F32,
+    F64,
+    Bool,
+    Str,
+    Bytes,
+    Array(usize),
+    Interned,
+    Format,
+}
+
+impl Kind {
+    fn parse(kind: &str) -> Result<Self> {
+        Ok(match kind {
+            "u8" => Kind::Unsigned(1),
+            "u16" => Kind::Unsigned(2),
+            "u32" | "usize" => Kind::Unsigned(4),
+            "u64" => Kind::Unsigned(8),
+            "u128" => Kind::Unsigned(16),
+            "i8" => Kind::Signed(1),
+            "i16" => Kind::Signed(2),
+            "i32" | "isize" => Kind::Signed(4),
+            "i64" => Kind::Signed(8),
+            "i128" => Kind::Signed(16),
+            "f32" => Kind::F32,
+            "f64" => Kind::F64,
+            "bool" => Kind::Bool,
+            "str" => Kind::Str,
+            "[u8]" => Kind::Bytes,
+            "istr" => Kind::Interned,
+            "?" | "" => Kind::Format,
+            _ => {
+                let array = kind
+                    .strip_prefix("[u8;")
+                    .and_then(|k| k.strip_suffix(']'))
+                    .and_then(|n| n.trim().parse::<usize>().ok());
+
+                match array {
+                    Some(n) => Kind::Array(n),
+                    None => bail!("unsupported type \"{}\"", kind),
+                }
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Param { position: usize, kind: Kind, hint: Option<String> },
+}
+
+///
+/// Parses a format string into its literal text and its parameters (e.g.,
+/// `{=u8}`, `{0=u32:x}` or `{}`).
+///
+fn parse(format: &str) -> Result<Vec<Segment>> {
+    let mut segments = vec![];
+    let mut literal = String::new();
+    let mut chars = format.chars().peekable();
+    let mut next = 0;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut param = String::new();
+
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => param.push(c),
+                        None => bail!("unterminated parameter in {}", format),
+                    }
+                }
+
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(literal.clone()));
+                    literal.clear();
+                }
+
+                let (param, hint) = match param.split_once(':') {
+                    Some((param, hint)) => (param, Some(hint.to_string())),
+                    None => (param.as_str(), None),
+                };
+
+                let (position, kind) = match param.split_once('=') {
+                    Some((position, kind)) => (position, kind),
+                    None => (param, ""),
+                };
+
+                let position = if position.is_empty() {
+                    next += 1;
+                    next - 1
+                } else {
+                    position.parse::<usize>().map_err(|_| {
+                        anyhow!("bad parameter position in {}", format)
+                    })?
+                };
+
+                segments.push(Segment::Param {
+                    position,
+                    kind: Kind::parse(kind)?,
+                    hint,
+                });
+            }
+            _ => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+
+    Ok(segments)
+}
+
+///
+/// The outcome of attempting to decode from a buffer that may not yet hold
+/// a complete message.
+///
+enum Decoded<T> {
+    Complete(T, usize),
+    Incomplete,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    offs: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let rval = self.buf.get(self.offs..self.offs + n)?;
+        self.offs += n;
+        Some(rval)
+    }
+
+    fn leb128(&mut self) -> Option<usize> {
+        let mut rval = 0usize;
+        let mut shift = 0;
+
+        loop {
+            let b = *self.take(1)?.first()?;
+            rval |= ((b & 0x7f) as usize) << shift;
+
+            if b & 0x80 == 0 {
+                return Some(rval);
+            }
+
+            shift += 7;
+        }
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+}
+
+fn integer(bytes: &[u8]) -> u128 {
+    let mut buf = [0u8; 16];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    u128::from_le_bytes(buf)
+}
+
+fn hinted(val: u128, hint: Option<&str>) -> String {
+    match hint {
+        Some("x") => format!("{:x}", val),
+        Some("#x") => format!("{:#x}", val),
+        Some("X") => format!("{:X}", val),
+        Some("#X") => format!("{:#X}", val),
+        Some("b") => format!("{:b}", val),
+        Some("#b") => format!("{:#b}", val),
+        _ => format!("{}", val),
+    }
+}
+
+///
+/// A decoded message.
+///
+#[derive(Clone, Debug)]
+pub struct DeferredMessage {
+    /// The tag of the format string (e.g., `info`, `error`, `println`)
+    pub tag: String,
+    /// The formatted timestamp, if the task has a timestamp format
+    pub timestamp: Option<String>,
+    /// The formatted message
+    pub message: String,
+}
+
+impl DeferredMessage {
+    ///
+    /// Returns the level of the message, if it is a log message.
+    ///
+    pub fn level(&self) -> Option<&str> {
+        match self.tag.as_str() {
+            "trace" | "debug" | "info" | "warn" | "error" => Some(&self.tag),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for DeferredMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(timestamp) = &self.timestamp {
+            write!(f, "{} ", timestamp)?;
+        }
+
+        if let Some(level) = self.level() {
+            write!(f, "{:<5} ", level.to_uppercase())?;
+        }
+
+        write!(f, "{}", self.message)
+    }
+}
+
+///
+/// Decodes a stream of messages from a single task.  Bytes are pushed as
+/// they arrive; complete messages are returned as they can be decoded.
+///
+pub struct DeferredDecoder<'a> {
+    strings: Box<dyn Fn(u32) -> Option<&'a HubrisDeferred> + 'a>,
+    timestamp: Option<&'a HubrisDeferred>,
+    buf: Vec<u8>,
+}
+
+impl<'a> DeferredDecoder<'a> {
+    pub fn new(hubris: &'a HubrisArchive, task: HubrisTask) -> Self {
+        Self {
+            strings: Box::new(move |index| hubris.lookup_deferred(task, index)),
+            timestamp: hubris.deferred_timestamp(task),
+            buf: vec![],
+        }
+    }
+
+    fn lookup(&self, index: u16) -> Result<&'a HubrisDeferred> {
+        (self.strings)(index as u32)
+            .ok_or_else(|| anyhow!("unknown format string index {}", index))
+    }
+
+    ///
+    /// Formats the arguments of the specified format string, read from the
+    /// reader.  Returns `None` if the reader runs out of bytes.
+    ///
+    fn format(&self, format: &str, r: &mut Reader) -> Result<Option<String>> {
+        let segments = parse(format)?;
+
+        //
+        // Each position is encoded once, in order of position, regardless
+        // of how many times (or in what order) it appears in the format.
+        //
+        let mut params: Vec<(usize, &Kind)> = segments
+            .iter()
+            .filter_map(|s| match s {
+                Segment::Param { position, kind, .. } => {
+                    Some((*position, kind))
+                }
+                Segment::Literal(_) => None,
+            })
+            .collect();
+
+        params.sort_by_key(|(position, _)| *position);
+        params.dedup_by_key(|(position, _)| *position);
+
+        let mut values = vec![];
+
+        for (position, kind) in params {
+            let value = match kind {
+                Kind::Unsigned(n) => match r.take(*n) {
+                    Some(bytes) => Value::Unsigned(integer(bytes)),
+                    None => return Ok(None),
+                },
+                Kind::Signed(n) => match r.take(*n) {
+                    Some(bytes) => {
+                        let shift = 128 - n * 8;
+                        let val = ((integer(bytes) << shift) as i128) >> shift;
+                        Value::Signed(val)
+                    }
+                    None => return Ok(None),
+                },
+                Kind::F32 => match r.take(4) {
+                    Some(b) => Value::Text(
+                        f32::from_le_bytes(b.try_into().unwrap()).to_string(),
+                    ),
+                    None => return Ok(None),
+                },
+                Kind::F64 => match r.take(8) {
+                    Some(b) => Value::Text(
+                        f64::from_le_bytes(b.try_into().unwrap()).to_string(),
+                    ),
+                    None => return Ok(None),
+                },
+                Kind::Bool => match r.take(1) {
+                    Some(b) => Value::Text((b[0] != 0).to_string()),
+                    None => return Ok(None),
+                },
+                Kind::Str | Kind::Bytes => {
+                    let bytes = match r.leb128().and_then(|n| r.take(n)) {
+                        Some(bytes) => bytes,
+                        None => return Ok(None),
+                    };
+
+                    if *kind == Kind::Str {
+                        Value::Text(String::from_utf8_lossy(bytes).to_string())
+                    } else {
+                        Value::Bytes(bytes.to_vec())
+                    }
+                }
+                Kind::Array(n) => match r.take(*n) {
+                    Some(bytes) => Value::Bytes(bytes.to_vec()),
+                    None => return Ok(None),
+                },
+                Kind::Interned => match r.u16() {
+                    Some(index) => {
+                        Value::Text(self.lookup(index)?.format.clone())
+                    }
+                    None => return Ok(None),
+                },
+                Kind::Format => {
+                    let nested = match r.u16() {
+                        Some(index) => self.lookup(index)?,
+                        None => return Ok(None),
+                    };
+
+                    match self.format(&nested.format, r)? {
+                        Some(text) => Value::Text(text),
+                        None => return Ok(None),
+                    }
+                }
+            };
+
+            values.push((position, value));
+        }
+
+        let mut rval = String::new();
+
+        for segment in &segments {
+            match segment {
+                Segment::Literal(text) => rval.push_str(text),
+                Segment::Param { position, hint, .. } => {
+                    let value = values
+                        .iter()
+                        .find(|(p, _)| p == position)
+                        .map(|(_, v)| v)
+                        .unwrap();
+
+                    let hint = hint.as_deref();
+
+                    match value {
+                        Value::Unsigned(v) => {
+                            rval.push_str(&hinted(*v, hint));
+                        }
+                        Value::Signed(v) if *v < 0 && hint.is_some() => {
+                            rval.push_str(&hinted(*v as u128, hint));
+                        }
+                        Value::Signed(v) => write!(rval, "{}", v)?,
+                        Value::Text(text) => rval.push_str(text),
+                        Value::Bytes(bytes) => {
+                            let bytes: Vec<_> = bytes
+                                .iter()
+                                .map(|b| hinted(*b as u128, hint))
+                                .collect();
+                            write!(rval, "[{}]", bytes.join(", "))?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Some(rval))
+    }
+
+    fn decode(&self) -> Result<Decoded<DeferredMessage>> {
+        let mut r = Reader { buf: &self.buf, offs: 0 };
+
+        let index = match r.u16() {
+            Some(index) => index,
+            None => return Ok(Decoded::Incomplete),
+        };
+
+        let deferred = self.lookup(index)?;
+
+        let timestamp = match self.timestamp {
+            Some(timestamp) => match self.format(&timestamp.format, &mut r)? {
+                Some(timestamp) => Some(timestamp),
+                None => return Ok(Decoded::Incomplete),
+            },
+            None => None,
+        };
+
+        match self.format(&deferred.format, &mut r)? {
+            Some(message) => Ok(Decoded::Complete(
+                DeferredMessage {
+                    tag: deferred.tag.clone(),
+                    timestamp,
+                    message,
+                },
+                r.offs,
+            )),
+            None => Ok(Decoded::Incomplete),
+        }
+    }
+
+    ///
+    /// Pushes bytes into the decoder, returning any messages (or errors)
+    /// that they complete.  On an error, a byte is discarded in an attempt
+    /// to resynchronize.
+    ///
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Result<DeferredMessage>> {
+        let mut rval = vec![];
+
+        self.buf.extend_from_slice(bytes);
+
+        while !self.buf.is_empty() {
+            match self.decode() {
+                Ok(Decoded::Complete(message, len)) => {
+                    self.buf.drain(..len);
+                    rval.push(Ok(message));
+                }
+                Ok(Decoded::Incomplete) => break,
+                Err(err) => {
+                    self.buf.remove(0);
+                    rval.push(Err(err));
+                }
+            }
+        }
+
+        rval
+    }
+}
+
+enum Value {
+    Unsigned(u128),
+    Signed(i128),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+///
+/// Determines the task whose messages are to be decoded:  the named task if
+/// one is specified, or the only task with interned format strings.
+///
+pub fn task(hubris: &HubrisArchive, name: Option<&str>) -> Result<HubrisTask> {
+    let tasks = hubris.deferred_tasks();
+
+    if let Some(name) = name {
+        let task = match hubris.lookup_task(name) {
+            Some(task) => *task,
+            None => bail!("unknown task \"{}\"", name),
+        };
+
+        if !tasks.contains(&task) {
+            bail!(
+                "task {} has no format strings for deferred formatting",
+                name
+            );
+        }
+
+        return Ok(task);
+    }
+
+    match tasks.len() {
+        0 => bail!("no task in archive has deferred format strings"),
+        1 => Ok(tasks[0]),
+        _ => bail!(
+            "multiple tasks have deferred format strings; must specify one"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deferred(tag: &str, format: &str) -> HubrisDeferred {
+        HubrisDeferred { tag: tag.to_string(), format: format.to_string() }
+    }
+
+    fn decoder(strings: &[HubrisDeferred]) -> DeferredDecoder<'_> {
+        DeferredDecoder {
+            strings: Box::new(move |index| strings.get(index as usize)),
+            timestamp: None,
+            buf: vec![],
+        }
+    }
+
+    fn decode(strings: &[HubrisDeferred], bytes: &[u8]) -> Vec<String> {
+        decoder(strings)
+            .push(bytes)
+            .into_iter()
+            .map(|m| m.unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_integers() {
+        let strings = [deferred(
+            "info",
+            "u8={=u8} u16={=u16:x} i8={=i8} i32={=i32} {=u64:#x} {=bool}",
+        )];
+
+        let mut bytes = vec![0, 0, 7, 0xef, 0xbe, 0xfe];
+        bytes.extend_from_slice(&(-100000i32).to_le_bytes());
+        bytes.extend_from_slice(&0x1234_5678_9abcu64.to_le_bytes());
+        bytes.push(1);
+
+        assert_eq!(
+            decode(&strings, &bytes),
+            ["INFO  u8=7 u16=beef i8=-2 i32=-100000 0x123456789abc true"]
+        );
+    }
+
+    #[test]
+    fn test_floats_and_bytes() {
+        let strings =
+            [deferred("println", "{=f32} {=f64} {=str} {=[u8]:x} {=[u8; 2]}")];
+
+        let mut bytes = vec![0, 0];
+        bytes.extend_from_slice(&1.5f32.to_le_bytes());
+        bytes.extend_from_slice(&(-0.25f64).to_le_bytes());
+        bytes.extend_from_slice(&[2, b'h', b'i', 2, 0xab, 0x01, 3, 4]);
+
+        assert_eq!(decode(&strings, &bytes), ["1.5 -0.25 hi [ab, 1] [3, 4]"]);
+    }
+
+    #[test]
+    fn test_interned_and_nested() {
+        let strings = [
+            deferred("warn", "{=istr}: {} ({1=?})"),
+            deferred("str", "sensor"),
+            deferred("fmt", "{=u8:#x} at {=u16}"),
+        ];
+
+        let bytes = [0, 0, 1, 0, 2, 0, 0x2a, 0x10, 0x00];
+
+        assert_eq!(
+            decode(&strings, &bytes),
+            ["WARN  sensor: 0x2a at 16 (0x2a at 16)"]
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let strings = [deferred("info", "{=u32} {=str}")];
+        let bytes = [0, 0, 1, 0, 0, 0, 3, b'a', b'b', b'c'];
+        let mut decoder = decoder(&strings);
+
+        for i in 0..bytes.len() - 1 {
+            assert!(decoder.push(&bytes[i..i + 1]).is_empty());
+        }
+
+        let messages = decoder.push(&bytes[bytes.len() - 1..]);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].as_ref().unwrap().message, "1 abc");
+
+        //
+        // An unknown index is an error, after which we resynchronize.
+        //
+        let messages = decoder.push(&[9, 0, 0, 2, 0, 0, 0, 0]);
+        assert!(messages[0].is_err());
+        assert_eq!(messages.last().unwrap().as_ref().unwrap().message, "2 ");
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(parse("{=u8").is_err());
+        assert!(parse("{=u7}").is_err());
+        assert!(parse("{x=u8}").is_err());
+        assert_eq!(parse("{{{=u8}}}").unwrap().len(), 3);
+    }
+}
@@ -308,3 +308,22 @@ pub struct StaticCell {
 pub struct UnsafeCell {
     pub value: Value,
 }
+
+/// Double of an entry in the kernel's event log.
+///
+/// The event is an enum whose variants (and their payloads) vary by kernel
+/// version, so we read it in as a generic `Value`.
+#[derive(Clone, Debug, Load)]
+pub struct KlogEntry {
+    pub timestamp: u64,
+    pub task: TaskId,
+    pub event: Value,
+}
+
+/// Double of the kernel's event log.  `count` is the number of events logged
+/// since boot; the most recent is in slot `(count - 1) % buffer.len()`.
+#[derive(Clone, Debug, Load)]
+pub struct Klog {
+    pub count: u32,
+    pub buffer: Vec<KlogEntry>,
+}
@@ -0,0 +1,831 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Access to I2C EEPROMs via HIF, and decoding of the formats (IPMI FRU
+//! information and Oxide barcodes) that we expect to find in them.
+
+use crate::hiffy::*;
+use crate::i2c::I2cArgs;
+use anyhow::{anyhow, bail, Result};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Geometry {
+    /// Size of the EEPROM, in bytes
+    pub size: usize,
+    /// Size of a write page, in bytes
+    pub page: usize,
+    /// Number of bytes of memory address
+    pub addr_bytes: usize,
+}
+
+///
+/// Parts that we know about.  Parts that use the low bits of the device
+/// address to address memory (e.g., the AT24C08) are not included.
+///
+pub const EEPROMS: &[(&str, Geometry)] = &[
+    ("at24c02", Geometry { size: 256, page: 8, addr_bytes: 1 }),
+    ("24aa02", Geometry { size: 256, page: 8, addr_bytes: 1 }),
+    ("at24csw080", Geometry { size: 1024, page: 16, addr_bytes: 2 }),
+    ("at24c32", Geometry { size: 4096, page: 32, addr_bytes: 2 }),
+    ("at24c64", Geometry { size: 8192, page: 32, addr_bytes: 2 }),
+    ("at24c128", Geometry { size: 16384, page: 64, addr_bytes: 2 }),
+    ("at24c256", Geometry { size: 32768, page: 64, addr_bytes: 2 }),
+];
+
+pub fn lookup_geometry(device: &str) -> Option<Geometry> {
+    EEPROMS.iter().find(|(name, _)| *name == device).map(|(_, g)| *g)
+}
+
+/// Largest page size that we can write in a single operation
+const MAX_PAGE: usize = 128;
+
+pub fn is_eeprom(device: &HubrisI2cDevice) -> bool {
+    lookup_geometry(&device.device).is_some()
+        || device.device.starts_with("at24")
+}
+
+///
+/// Determines the geometry of the specified EEPROM, with the size and page
+/// size (if specified) overriding what we know about the part.
+///
+pub fn geometry(
+    hargs: &I2cArgs,
+    size: Option<usize>,
+    page: Option<usize>,
+) -> Result<Geometry> {
+    let known = hargs.device.as_deref().and_then(lookup_geometry);
+
+    let size = match (size, known) {
+        (Some(size), _) => size,
+        (None, Some(g)) => g.size,
+        (None, None) => bail!("unknown EEPROM; size must be specified"),
+    };
+
+    let page = match (page, known) {
+        (Some(page), _) => page,
+        (None, Some(g)) => g.page,
+        (None, None) => bail!("unknown EEPROM; page size must be specified"),
+    };
+
+    if page == 0 || !page.is_power_of_two() || size % page != 0 {
+        bail!("page size of {} is invalid for size {}", page, size);
+    }
+
+    //
+    // A page (along with its address) is written in a single HIF call,
+    // the length of which must fit in a byte.
+    //
+    if page > MAX_PAGE {
+        bail!("page size of {} exceeds maximum of {}", page, MAX_PAGE);
+    }
+
+    let addr_bytes = match known {
+        Some(g) => g.addr_bytes,
+        None if size <= 256 => 1,
+        None => 2,
+    };
+
+    Ok(Geometry { size, page, addr_bytes })
+}
+
+pub struct Eeprom<'a> {
+    context: HiffyContext<'a>,
+    base: Vec<Op>,
+    geometry: Geometry,
+    funcs: &'a HiffyFunctions,
+    read: &'a HiffyFunction,
+    write: &'a HiffyFunction,
+    sleep: &'a HiffyFunction,
+}
+
+impl<'a> Eeprom<'a> {
+    pub fn new(
+        context: HiffyContext<'a>,
+        funcs: &'a HiffyFunctions,
+        hargs: &I2cArgs,
+        geometry: Geometry,
+    ) -> Result<Self> {
+        let address = match hargs.address {
+            Some(address) => address,
+            None => bail!("must specify an EEPROM device"),
+        };
+
+        let mut base =
+            vec![Op::Push(hargs.controller), Op::Push(hargs.port.index)];
+
+        if let Some(mux) = hargs.mux {
+            base.push(Op::Push(mux.0));
+            base.push(Op::Push(mux.1));
+        } else {
+            base.push(Op::PushNone);
+            base.push(Op::PushNone);
+        }
+
+        base.push(Op::Push(address));
+
+        Ok(Self {
+            context,
+            base,
+            geometry,
+            funcs,
+            read: funcs.get("I2cRead", 7)?,
+            write: funcs.get("I2cWrite", 8)?,
+            sleep: funcs.get("Sleep", 1)?,
+        })
+    }
+
+    ///
+    /// Pushes the memory address onto the stack as the register and (for
+    /// two-byte addressing) the first byte of payload, returning the number
+    /// of bytes of payload pushed.
+    ///
+    fn push_addr(&self, ops: &mut Vec<Op>, offset: usize) -> u8 {
+        if self.geometry.addr_bytes == 2 {
+            ops.push(Op::Push((offset >> 8) as u8));
+            ops.push(Op::Push(offset as u8));
+            1
+        } else {
+            ops.push(Op::Push(offset as u8));
+            0
+        }
+    }
+
+    pub fn read(&mut self, core: &mut dyn Core) -> Result<Vec<u8>> {
+        let chunk = 128;
+        let size = self.geometry.size;
+
+        //
+        // We perform as many reads per HIF program as will fit in our
+        // return stack (with some headroom for per-result overhead).
+        //
+        let per = (self.context.rstack_size() / (chunk * 2)).max(1);
+        let mut rval = vec![];
+
+        for start in (0..size).step_by(chunk * per) {
+            let mut ops = self.base.clone();
+            let end = (start + chunk * per).min(size);
+
+            for offset in (start..end).step_by(chunk) {
+                let len = chunk.min(end - offset);
+
+                //
+                // Set the address pointer with a write of the address,
+                // and then do a raw read from it.
+                //
+                let n = self.push_addr(&mut ops, offset);
+                ops.push(Op::Push(n));
+                ops.push(Op::Call(self.write.id));
+                ops.push(Op::DropN(n + 2));
+
+                ops.push(Op::PushNone);
+                ops.push(Op::Push(len as u8));
+                ops.push(Op::Call(self.read.id));
+                ops.push(Op::DropN(2));
+            }
+
+            ops.push(Op::Done);
+
+            let results = self.context.run(core, ops.as_slice(), None)?;
+
+            for (i, result) in results.iter().enumerate() {
+                let offset = start + (i / 2) * chunk;
+
+                match result {
+                    Ok(val) if i % 2 == 1 => rval.extend_from_slice(val),
+                    Ok(_) => {}
+                    Err(err) => {
+                        let func =
+                            if i % 2 == 0 { self.write } else { self.read };
+                        return Err(func.error(*err).context(format!(
+                            "failed to read at offset 0x{:x}",
+                            offset
+                        )));
+                    }
+                }
+            }
+        }
+
+        if rval.len() != size {
+            bail!("short read: expected {} bytes, found {}", size, rval.len());
+        }
+
+        Ok(rval)
+    }
+
+    ///
+    /// Writes the specified data at the specified offset, breaking the
+    /// write into page-aligned writes and sleeping after each to allow for
+    /// the EEPROM's write cycle.  Returns the number of pages written.
+    ///
+    pub fn write(
+        &mut self,
+        core: &mut dyn Core,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<usize> {
+        let bulk = self.funcs.get("I2cBulkWrite", 8)?;
+        let page = self.geometry.page;
+        let addr_bytes = self.geometry.addr_bytes;
+        let mut pages = vec![];
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let addr = offset + pos;
+            let len = (page - (addr % page)).min(data.len() - pos);
+            pages.push((addr, &data[pos..pos + len]));
+            pos += len;
+        }
+
+        //
+        // Each page is staged in the HIF data as its address followed by
+        // its payload, and then written with a bulk write.  We put as many
+        // pages in a HIF program as will fit in the data, in the program
+        // text (at a generous 20 bytes of text per page, with headroom for
+        // the preamble) and in the return stack.
+        //
+        let data_size = self.context.data_size();
+        let per = (self.context.text_size().saturating_sub(32) / 20)
+            .min(self.context.rstack_size() / 8)
+            .max(1);
+
+        let mut batches: Vec<Vec<(usize, &[u8])>> = vec![];
+        let mut staged = 0;
+
+        for &(addr, payload) in &pages {
+            let len = addr_bytes + payload.len();
+
+            if len > data_size {
+                bail!(
+                    "page size of {} exceeds HIF data size of {}",
+                    page,
+                    data_size
+                );
+            }
+
+            match batches.last_mut() {
+                Some(batch)
+                    if batch.len() < per && staged + len <= data_size =>
+                {
+                    batch.push((addr, payload));
+                    staged += len;
+                }
+                _ => {
+                    batches.push(vec![(addr, payload)]);
+                    staged = len;
+                }
+            }
+        }
+
+        for batch in &batches {
+            let mut ops = self.base.clone();
+            let mut buf = vec![];
+
+            //
+            // The memory address is staged with each page, so there is no
+            // register to write.
+            //
+            ops.push(Op::PushNone);
+
+            for (addr, payload) in batch {
+                let start = buf.len();
+
+                if addr_bytes == 2 {
+                    buf.push((addr >> 8) as u8);
+                }
+
+                buf.push(*addr as u8);
+                buf.extend_from_slice(payload);
+
+                ops.push(Op::Push32(start as u32));
+                ops.push(Op::Push16((buf.len() - start) as u16));
+                ops.push(Op::Call(bulk.id));
+                ops.push(Op::DropN(2));
+
+                ops.push(Op::Push(5));
+                ops.push(Op::Call(self.sleep.id));
+                ops.push(Op::Drop);
+            }
+
+            ops.push(Op::Done);
+
+            let results = self.context.run(core, ops.as_slice(), Some(&buf))?;
+
+            for (i, (addr, _)) in batch.iter().enumerate() {
+                if let Some(Err(err)) = results.get(i * 2) {
+                    return Err(bulk.error(*err).context(format!(
+                        "failed to write page at offset 0x{:x}",
+                        addr
+                    )));
+                }
+            }
+        }
+
+        Ok(pages.len())
+    }
+
+    ///
+    /// Writes the specified data at the specified offset, and then reads
+    /// back the EEPROM to verify it.  Returns the number of pages written.
+    ///
+    pub fn write_verified(
+        &mut self,
+        core: &mut dyn Core,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<usize> {
+        let pages = self.write(core, offset, data)?;
+        let readback = self.read(core)?;
+
+        if let Some(i) =
+            (0..data.len()).find(|&i| readback[offset + i] != data[i])
+        {
+            bail!(
+                "verification failed at offset 0x{:x}: \
+                wrote 0x{:02x}, read 0x{:02x}",
+                offset + i,
+                data[i],
+                readback[offset + i]
+            );
+        }
+
+        Ok(pages)
+    }
+}
+
+//
+// IPMI Platform Management FRU Information Storage Definition, v1.0
+//
+const FRU_END_OF_FIELDS: u8 = 0xc1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum FruAreaKind {
+    Chassis,
+    Board,
+    Product,
+}
+
+impl FruAreaKind {
+    fn name(&self) -> &'static str {
+        match self {
+            FruAreaKind::Chassis => "chassis",
+            FruAreaKind::Board => "board",
+            FruAreaKind::Product => "product",
+        }
+    }
+
+    /// Number of bytes following the version and length bytes that precede
+    /// the fields
+    fn preamble(&self) -> usize {
+        match self {
+            FruAreaKind::Chassis => 1,
+            FruAreaKind::Board => 4,
+            FruAreaKind::Product => 1,
+        }
+    }
+
+    fn fields(&self) -> &'static [&'static str] {
+        match self {
+            FruAreaKind::Chassis => &["part", "serial"],
+            FruAreaKind::Board => {
+                &["manufacturer", "product", "serial", "part", "file_id"]
+            }
+            FruAreaKind::Product => &[
+                "manufacturer",
+                "product",
+                "part",
+                "version",
+                "serial",
+                "asset_tag",
+                "file_id",
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FruField {
+    name: String,
+    typelen: u8,
+    data: Vec<u8>,
+}
+
+impl FruField {
+    fn value(&self) -> String {
+        match self.typelen >> 6 {
+            0b00 => format!("{:x?}", self.data),
+            0b01 => self
+                .data
+                .iter()
+                .flat_map(|b| [b >> 4, b & 0xf])
+                .map(|d| match d {
+                    0..=9 => (b'0' + d) as char,
+                    0xa => ' ',
+                    0xb => '-',
+                    0xc => '.',
+                    _ => '?',
+                })
+                .collect(),
+            0b10 => {
+                let mut s = String::new();
+
+                for chunk in self.data.chunks(3) {
+                    let mut bits = 0u32;
+
+                    for (i, &b) in chunk.iter().enumerate() {
+                        bits |= (b as u32) << (i * 8);
+                    }
+
+                    for i in 0..(chunk.len() * 8) / 6 {
+                        s.push(
+                            (((bits >> (i * 6)) & 0x3f) as u8 + 0x20) as char,
+                        );
+                    }
+                }
+
+                format!("{:?}", s)
+            }
+            _ => format!("{:?}", String::from_utf8_lossy(&self.data)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FruArea {
+    kind: FruAreaKind,
+    version: u8,
+    preamble: Vec<u8>,
+    fields: Vec<FruField>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Fru {
+    version: u8,
+    internal: Option<Vec<u8>>,
+    areas: Vec<FruArea>,
+    multirecord: Option<Vec<u8>>,
+    /// Number of bytes spanned by the FRU as parsed
+    span: usize,
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+impl FruArea {
+    fn parse(kind: FruAreaKind, buf: &[u8]) -> Result<Self> {
+        if buf.len() < 2 {
+            bail!("{} area is truncated", kind.name());
+        }
+
+        let len = buf[1] as usize * 8;
+
+        if len < 2 + kind.preamble() || len > buf.len() {
+            bail!("{} area has bad length {}", kind.name(), len);
+        }
+
+        let area = &buf[..len];
+
+        if checksum(area) != 0 {
+            bail!("{} area has bad checksum", kind.name());
+        }
+
+        let preamble = area[2..2 + kind.preamble()].to_vec();
+        let mut fields = vec![];
+        let mut pos = 2 + kind.preamble();
+        let names = kind.fields();
+
+        while pos < len - 1 && area[pos] != FRU_END_OF_FIELDS {
+            let typelen = area[pos];
+            let flen = (typelen & 0x3f) as usize;
+
+            if pos + 1 + flen > len - 1 {
+                bail!("{} area field at {} overruns area", kind.name(), pos);
+            }
+
+            let name = match names.get(fields.len()) {
+                Some(name) => name.to_string(),
+                None => format!("custom{}", fields.len() - names.len()),
+            };
+
+            fields.push(FruField {
+                name,
+                typelen,
+                data: area[pos + 1..pos + 1 + flen].to_vec(),
+            });
+
+            pos += 1 + flen;
+        }
+
+        Ok(Self { kind, version: area[0], preamble, fields })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut rval = vec![self.version, 0];
+        rval.extend_from_slice(&self.preamble);
+
+        for field in &self.fields {
+            rval.push(field.typelen);
+            rval.extend_from_slice(&field.data);
+        }
+
+        rval.push(FRU_END_OF_FIELDS);
+
+        //
+        // The area is padded out to a multiple of 8 bytes, including the
+        // checksum that concludes it.
+        //
+        while (rval.len() + 1) % 8 != 0 {
+            rval.push(0);
+        }
+
+        rval[1] = ((rval.len() + 1) / 8) as u8;
+        rval.push(0u8.wrapping_sub(checksum(&rval)));
+        rval
+    }
+
+    ///
+    /// Returns the manufacturing date of a board area as a string, if one
+    /// has been specified.
+    ///
+    fn mfg_date(&self) -> Option<String> {
+        if self.kind != FruAreaKind::Board {
+            return None;
+        }
+
+        let p = &self.preamble;
+        let minutes = u32::from_le_bytes([p[1], p[2], p[3], 0]);
+
+        if minutes == 0 {
+            return None;
+        }
+
+        //
+        // The date is in minutes since 1996-01-01 00:00, which is 9496 days
+        // after the Unix epoch.
+        //
+        let days = (minutes / 1440) as i64 + 9496;
+        let (y, m, d) = civil_from_days(days);
+
+        Some(format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}",
+            y,
+            m,
+            d,
+            (minutes % 1440) / 60,
+            minutes % 60
+        ))
+    }
+}
+
+///
+/// Converts days since the Unix epoch into a year, month and day (after
+/// Howard Hinnant's algorithm of the same name).
+///
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+
+    (y, m, d)
+}
+
+impl Fru {
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 8 || buf[0] != 0x01 || checksum(&buf[..8]) != 0 {
+            bail!("no valid IPMI FRU common header");
+        }
+
+        let offset = |ndx: usize| buf[ndx] as usize * 8;
+
+        //
+        // The internal use area has no length of its own; it extends to the
+        // next area.
+        //
+        let internal = if buf[1] != 0 {
+            let start = offset(1);
+            let end = (2..=5)
+                .map(offset)
+                .filter(|&o| o > start)
+                .min()
+                .unwrap_or(buf.len());
+
+            if start >= buf.len() || end > buf.len() {
+                bail!("internal use area is out of bounds");
+            }
+
+            Some(buf[start..end].to_vec())
+        } else {
+            None
+        };
+
+        let mut span = match internal {
+            Some(ref internal) => offset(1) + internal.len(),
+            None => 8,
+        };
+
+        let mut areas = vec![];
+
+        for (ndx, kind) in [
+            (2, FruAreaKind::Chassis),
+            (3, FruAreaKind::Board),
+            (4, FruAreaKind::Product),
+        ] {
+            if buf[ndx] != 0 {
+                let start = offset(ndx);
+
+                if start >= buf.len() {
+                    bail!("{} area offset is out of bounds", kind.name());
+                }
+
+                areas.push(FruArea::parse(kind, &buf[start..])?);
+                span = span.max(start + buf[start + 1] as usize * 8);
+            }
+        }
+
+        //
+        // The multirecord area consists of records with 5-byte headers,
+        // the last of which has the end-of-list bit set.
+        //
+        let multirecord = if buf[5] != 0 {
+            let start = offset(5);
+            let mut pos = start;
+
+            loop {
+                if pos + 5 > buf.len() {
+                    bail!("multirecord area is truncated");
+                }
+
+                let end_of_list = buf[pos + 1] & 0x80 != 0;
+                pos += 5 + buf[pos + 2] as usize;
+
+                if end_of_list {
+                    break;
+                }
+            }
+
+            let end = pos.min(buf.len());
+            span = span.max(end);
+
+            Some(buf[start..end].to_vec())
+        } else {
+            None
+        };
+
+        Ok(Self { version: buf[0], internal, areas, multirecord, span })
+    }
+
+    ///
+    /// Encodes the FRU, failing if the encoding would extend beyond the
+    /// bytes that the FRU originally spanned (and therefore overwrite
+    /// whatever follows it).
+    ///
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut header = vec![self.version, 0, 0, 0, 0, 0, 0];
+        let mut body = vec![];
+
+        let mut append = |ndx: usize, bytes: &[u8], body: &mut Vec<u8>| {
+            header[ndx] = ((8 + body.len()) / 8) as u8;
+            body.extend_from_slice(bytes);
+
+            while body.len() % 8 != 0 {
+                body.push(0);
+            }
+        };
+
+        if let Some(internal) = &self.internal {
+            append(1, internal, &mut body);
+        }
+
+        for area in &self.areas {
+            let ndx = match area.kind {
+                FruAreaKind::Chassis => 2,
+                FruAreaKind::Board => 3,
+                FruAreaKind::Product => 4,
+            };
+
+            append(ndx, &area.encode(), &mut body);
+        }
+
+        if let Some(multirecord) = &self.multirecord {
+            append(5, multirecord, &mut body);
+        }
+
+        header.push(0u8.wrapping_sub(checksum(&header)));
+        header.extend(body);
+
+        if header.len() > self.span {
+            bail!(
+                "encoded FRU ({} bytes) exceeds its original size of {} bytes",
+                header.len(),
+                self.span
+            );
+        }
+
+        Ok(header)
+    }
+
+    pub fn print(&self) {
+        println!("IPMI FRU (format version {}):", self.version);
+
+        for area in &self.areas {
+            let name = area.kind.name();
+
+            if let Some(date) = area.mfg_date() {
+                println!("    {:20} = {}", format!("{}.mfg_date", name), date);
+            }
+
+            for field in &area.fields {
+                let field_name = format!("{}.{}", name, field.name);
+                println!("    {:20} = {}", field_name, field.value());
+            }
+        }
+
+        if let Some(internal) = &self.internal {
+            println!("    {:20} = {} bytes", "internal", internal.len());
+        }
+
+        if let Some(multirecord) = &self.multirecord {
+            println!("    {:20} = {} bytes", "multirecord", multirecord.len());
+        }
+    }
+
+    fn lookup(&mut self, field: &str) -> Result<&mut FruField> {
+        let (area, name) = field.split_once('.').ok_or_else(|| {
+            anyhow!("field must be area.field, e.g. board.serial")
+        })?;
+
+        let area = self
+            .areas
+            .iter_mut()
+            .find(|a| a.kind.name() == area)
+            .ok_or_else(|| anyhow!("FRU has no {} area", area))?;
+
+        let kind = area.kind;
+
+        area.fields
+            .iter_mut()
+            .find(|f| f.name == name)
+            .ok_or_else(|| anyhow!("no field {} in {} area", name, kind.name()))
+    }
+
+    ///
+    /// Returns the raw contents of the specified field (e.g.,
+    /// `board.serial`), which will be empty if the field is unpopulated.
+    ///
+    pub fn get(&mut self, field: &str) -> Result<Vec<u8>> {
+        Ok(self.lookup(field)?.data.clone())
+    }
+
+    pub fn set(&mut self, field: &str, value: &str) -> Result<()> {
+        if value.len() > 63 {
+            bail!("value for {} is too long ({} bytes)", field, value.len());
+        }
+
+        let f = self.lookup(field)?;
+
+        f.typelen = 0xc0 | value.len() as u8;
+        f.data = value.as_bytes().to_vec();
+
+        Ok(())
+    }
+}
+
+///
+/// Finds any Oxide barcode strings (e.g., `0XV1:9130000019:006:BRM42220016`)
+/// in the EEPROM contents, returning their offsets and values.
+///
+pub fn barcodes(buf: &[u8]) -> Vec<(usize, String)> {
+    let mut rval = vec![];
+    let mut pos = 0;
+
+    while pos + 5 <= buf.len() {
+        let prefix = &buf[pos..pos + 5];
+
+        if prefix == b"0XV1:" || prefix == b"0XV2:" {
+            let end = buf[pos..]
+                .iter()
+                .position(|&c| !c.is_ascii_graphic())
+                .map_or(buf.len(), |p| pos + p);
+
+            rval.push((
+                pos,
+                String::from_utf8_lossy(&buf[pos..end]).to_string(),
+            ));
+            pos = end;
+        } else {
+            pos += 1;
+        }
+    }
+
+    rval
+}
@@ -0,0 +1,131 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Classification of errors.  Most errors are simply errors, but some
+//! classes of failure are of particular interest to automation (e.g., being
+//! unable to attach versus a device failing to respond), and these are
+//! classified with an [`ErrorKind`] that determines Humility's exit code.
+//! Classifying an error does not change how it is displayed.
+//!
+
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The command was invoked incorrectly
+    Usage,
+    /// We were unable to attach to the target or dump
+    Attach,
+    /// The target is not running the image in the archive
+    ArchiveMismatch,
+    /// The target is not in a state to be operated upon (e.g., it has not
+    /// booted, or the kernel has faulted)
+    TargetFault,
+    /// The HIF execution facility failed or timed out
+    Hiffy,
+    /// A device failed to respond to an operation
+    DeviceNak,
+}
+
+impl ErrorKind {
+    /// The exit code for an unclassified error
+    pub const OTHER: i32 = 1;
+
+    pub fn code(&self) -> i32 {
+        match self {
+            ErrorKind::Usage => 2,
+            ErrorKind::Attach => 3,
+            ErrorKind::ArchiveMismatch => 4,
+            ErrorKind::TargetFault => 5,
+            ErrorKind::Hiffy => 6,
+            ErrorKind::DeviceNak => 7,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Usage => "usage",
+            ErrorKind::Attach => "attach",
+            ErrorKind::ArchiveMismatch => "archive_mismatch",
+            ErrorKind::TargetFault => "target_fault",
+            ErrorKind::Hiffy => "hiffy",
+            ErrorKind::DeviceNak => "device_nak",
+        }
+    }
+
+    ///
+    /// Creates an error of this kind with the specified message, e.g.
+    /// `return Err(ErrorKind::Hiffy.error("operation timed out"))`.
+    ///
+    pub fn error<M>(self, msg: M) -> anyhow::Error
+    where
+        M: fmt::Display + fmt::Debug + Send + Sync + 'static,
+    {
+        self.classify(anyhow::Error::msg(msg))
+    }
+
+    /// Classifies an existing error as being of this kind.
+    pub fn classify(self, err: anyhow::Error) -> anyhow::Error {
+        anyhow::Error::new(Classified { kind: self, err })
+    }
+}
+
+///
+/// An error that has been classified.  This displays as the underlying error
+/// and has the same source, so it is invisible in the error's output.
+///
+#[derive(Debug)]
+struct Classified {
+    kind: ErrorKind,
+    err: anyhow::Error,
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.err)
+    }
+}
+
+impl std::error::Error for Classified {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.err.source()
+    }
+}
+
+/// Allows a `Result` to be classified in the manner of `anyhow::Context`.
+pub trait Classify<T> {
+    fn classify(self, kind: ErrorKind) -> anyhow::Result<T>;
+}
+
+impl<T, E> Classify<T> for Result<T, E>
+where
+    E: Into<anyhow::Error>,
+{
+    fn classify(self, kind: ErrorKind) -> anyhow::Result<T> {
+        self.map_err(|err| kind.classify(err.into()))
+    }
+}
+
+///
+/// Returns the kind of an error, if it has been classified (or is an
+/// argument parsing error).  If an error has been classified more than once,
+/// the outermost classification wins.
+///
+pub fn kind(err: &anyhow::Error) -> Option<ErrorKind> {
+    if let Some(classified) = err.downcast_ref::<Classified>() {
+        return Some(classified.kind);
+    }
+
+    if err.downcast_ref::<clap::Error>().is_some() {
+        return Some(ErrorKind::Usage);
+    }
+
+    None
+}
+
+/// Returns the exit code for an error.
+pub fn code(err: &anyhow::Error) -> i32 {
+    kind(err).map_or(ErrorKind::OTHER, |kind| kind.code())
+}
@@ -0,0 +1,626 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Evaluation of expressions over static variables in target memory.
+//!
+//! Expressions name variables (by their name or their fully qualified
+//! name), and may index them, access their members, dereference them and
+//! take their address, combining integers with the arithmetic and bitwise
+//! operators of Rust.  Evaluating an expression yields either a place in
+//! target memory (an address and a type) or an integer.
+
+use anyhow::{anyhow, bail, Result};
+use humility::core::Core;
+use humility::hubris::*;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Number(u64),
+    Ident(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 17] = [
+    "<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "!", ".", "[",
+    "]", "(", ")",
+];
+
+fn tokenize(expr: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut rest = expr.trim_start();
+
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+
+        let len = if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+
+            let number = parse_int::parse::<u64>(&rest[..len])
+                .map_err(|_| anyhow!("bad number \"{}\"", &rest[..len]))?;
+
+            tokens.push(Token::Number(number));
+            len
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            //
+            // Identifiers may be qualified with `::`.
+            //
+            let mut len = 0;
+
+            loop {
+                len += rest[len..]
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len() - len);
+
+                if rest[len..].starts_with("::") {
+                    len += 2;
+                } else {
+                    break;
+                }
+            }
+
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            len
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(*op));
+                    op.len()
+                }
+                None => bail!("unexpected character '{}'", c),
+            }
+        };
+
+        rest = rest[len..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+///
+/// The result of evaluating (part of) an expression: either a value in
+/// target memory of a known type, or an integer.
+///
+#[derive(Copy, Clone, Debug)]
+pub enum Eval {
+    Place { addr: u32, goff: HubrisGoff },
+    Int(u64),
+}
+
+///
+/// An evaluator of an expression over static variables.
+///
+pub struct Evaluator<'a> {
+    hubris: &'a HubrisArchive,
+    core: &'a mut dyn Core,
+    tokens: Vec<Token>,
+    pos: usize,
+    task: Option<HubrisTask>,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(
+        hubris: &'a HubrisArchive,
+        core: &'a mut dyn Core,
+        expr: &str,
+    ) -> Result<Self> {
+        Ok(Self { hubris, core, tokens: tokenize(expr)?, pos: 0, task: None })
+    }
+
+    ///
+    /// Evaluates the expression in its entirety.  The core should be halted.
+    ///
+    pub fn evaluate(&mut self) -> Result<Eval> {
+        let val = self.expr(0)?;
+
+        if let Some(token) = self.peek() {
+            bail!("unexpected {:?}", token);
+        }
+
+        Ok(val)
+    }
+
+    ///
+    /// The task that owns the first variable named by the expression, if
+    /// any.
+    ///
+    pub fn task(&self) -> Option<HubrisTask> {
+        self.task
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, op: &str) -> Result<()> {
+        match self.advance() {
+            Some(Token::Op(o)) if o == op => Ok(()),
+            Some(token) => bail!("expected '{}', found {:?}", op, token),
+            None => bail!("expected '{}' at end of expression", op),
+        }
+    }
+
+    pub fn read(&mut self, addr: u32, size: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; size];
+        self.core.read_8(addr, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn variable(&mut self, name: &str) -> Result<Eval> {
+        let variables: Vec<&HubrisVariable> =
+            match self.hubris.lookup_variables(name) {
+                Ok(variables) => variables.iter().collect(),
+                Err(_) => self
+                    .hubris
+                    .qualified_variables()
+                    .filter(|(n, _)| *n == name)
+                    .map(|(_, v)| v)
+                    .collect(),
+            };
+
+        match variables.len() {
+            0 => bail!("variable {} not found", name),
+            1 => {
+                let v = variables[0];
+                self.task.get_or_insert(HubrisTask::from(v.goff));
+                Ok(Eval::Place { addr: v.addr, goff: v.goff })
+            }
+            _ => bail!("{} is ambiguous; use its qualified name", name),
+        }
+    }
+
+    ///
+    /// Looks through the active variant of an enum, returning the place of
+    /// its payload.
+    ///
+    fn variant(&mut self, addr: u32, e: &HubrisEnum) -> Result<Eval> {
+        let buf = self.read(addr, e.size)?;
+        let variant = e.determine_variant(self.hubris, &buf)?;
+
+        match variant.goff {
+            Some(goff) => Ok(Eval::Place { addr, goff }),
+            None => bail!("{} is {}", e.name, variant.name),
+        }
+    }
+
+    ///
+    /// Dereferences a value, looking through the active variant of an enum
+    /// or a structure of a single member (e.g., `NonNull`) to find the
+    /// pointer.
+    ///
+    fn deref(&mut self, val: Eval) -> Result<Eval> {
+        let (addr, goff) = match val {
+            Eval::Int(addr) => {
+                let addr = addr as u32;
+                return Ok(Eval::Int(self.core.read_word_32(addr)? as u64));
+            }
+            Eval::Place { addr, goff } => (addr, goff),
+        };
+
+        match self.hubris.lookup_type(goff)? {
+            HubrisType::Ptr(_) => Ok(Eval::Place {
+                addr: self.core.read_word_32(addr)?,
+                goff: self.hubris.lookup_ptrtype(goff)?,
+            }),
+            HubrisType::Enum(e) => {
+                let payload = self.variant(addr, e)?;
+                self.deref(payload)
+            }
+            HubrisType::Struct(s) if s.members.len() == 1 => {
+                let m = &s.members[0];
+                let member =
+                    Eval::Place { addr: addr + m.offset as u32, goff: m.goff };
+
+                self.deref(member)
+            }
+            ty => bail!("cannot dereference {}", ty.name(self.hubris)?),
+        }
+    }
+
+    fn member(&mut self, val: Eval, name: &str) -> Result<Eval> {
+        let (addr, goff) = match val {
+            Eval::Int(_) => bail!("integers have no member {}", name),
+            Eval::Place { addr, goff } => (addr, goff),
+        };
+
+        match self.hubris.lookup_type(goff)? {
+            HubrisType::Struct(s) => {
+                //
+                // Tuple members are named by their index.
+                //
+                let tuple = format!("__{}", name);
+
+                match s
+                    .members
+                    .iter()
+                    .find(|m| m.name == name || m.name == tuple)
+                {
+                    Some(m) => Ok(Eval::Place {
+                        addr: addr + m.offset as u32,
+                        goff: m.goff,
+                    }),
+                    //
+                    // Look through a wrapper (e.g., `NonNull`) for the
+                    // member.
+                    //
+                    None if s.members.len() == 1 => {
+                        let m = &s.members[0];
+                        let inner = Eval::Place {
+                            addr: addr + m.offset as u32,
+                            goff: m.goff,
+                        };
+
+                        self.member(inner, name)
+                    }
+                    None => bail!("{} has no member {}", s.name, name),
+                }
+            }
+            HubrisType::Enum(e) => {
+                let payload = self.variant(addr, e)?;
+                self.member(payload, name)
+            }
+            HubrisType::Ptr(_) => {
+                let pointee = self.deref(val)?;
+                self.member(pointee, name)
+            }
+            ty => bail!("{} has no member {}", ty.name(self.hubris)?, name),
+        }
+    }
+
+    fn index(&mut self, val: Eval, ndx: u64) -> Result<Eval> {
+        let (addr, goff) = match val {
+            Eval::Int(_) => bail!("cannot index an integer"),
+            Eval::Place { addr, goff } => (addr, goff),
+        };
+
+        //
+        // Anything other than an array is indexed as a pointer.
+        //
+        let (base, elem, count) = match self.hubris.lookup_type(goff)? {
+            HubrisType::Array(a) => (addr, a.goff, Some(a.count)),
+            _ => match self.deref(val)? {
+                Eval::Place { addr, goff } => (addr, goff, None),
+                Eval::Int(_) => unreachable!(),
+            },
+        };
+
+        if let Some(count) = count {
+            if ndx >= count as u64 {
+                bail!("index {} out of bounds for array of {}", ndx, count);
+            }
+        }
+
+        let size = self.hubris.typesize(elem)?;
+
+        Ok(Eval::Place {
+            addr: base + (ndx as usize * size) as u32,
+            goff: elem,
+        })
+    }
+
+    ///
+    /// Converts a value to an integer, reading it from memory if needed.
+    ///
+    fn int(&mut self, val: Eval) -> Result<u64> {
+        let (addr, goff) = match val {
+            Eval::Int(val) => return Ok(val),
+            Eval::Place { addr, goff } => (addr, goff),
+        };
+
+        match self.hubris.lookup_type(goff)? {
+            HubrisType::Ptr(_) => Ok(self.core.read_word_32(addr)? as u64),
+            HubrisType::Base(b) => {
+                let buf = self.read(addr, b.size)?;
+                let mut bytes = [0u8; 8];
+
+                if b.size > 8 {
+                    bail!("{}-byte integers are not supported", b.size);
+                }
+
+                bytes[..b.size].copy_from_slice(&buf);
+                let val = u64::from_le_bytes(bytes);
+
+                match b.encoding {
+                    HubrisEncoding::Signed if b.size < 8 => {
+                        let shift = 64 - b.size * 8;
+                        Ok((((val << shift) as i64) >> shift) as u64)
+                    }
+                    HubrisEncoding::Float => {
+                        bail!("floating point values are not supported")
+                    }
+                    _ => Ok(val),
+                }
+            }
+            HubrisType::Struct(s) if s.members.len() == 1 => {
+                let m = &s.members[0];
+
+                self.int(Eval::Place {
+                    addr: addr + m.offset as u32,
+                    goff: m.goff,
+                })
+            }
+            ty => bail!("{} is not an integer", ty.name(self.hubris)?),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Eval> {
+        match self.advance() {
+            Some(Token::Number(n)) => Ok(Eval::Int(n)),
+            Some(Token::Ident(name)) => self.variable(&name),
+            Some(Token::Op("(")) => {
+                let val = self.expr(0)?;
+                self.expect(")")?;
+                Ok(val)
+            }
+            Some(token) => bail!("unexpected {:?}", token),
+            None => bail!("unexpected end of expression"),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Eval> {
+        let mut val = self.primary()?;
+
+        loop {
+            match self.peek() {
+                Some(Token::Op(".")) => {
+                    self.pos += 1;
+
+                    let name = match self.advance() {
+                        Some(Token::Ident(name)) => name,
+                        Some(Token::Number(n)) => n.to_string(),
+                        _ => bail!("expected member name after '.'"),
+                    };
+
+                    val = self.member(val, &name)?;
+                }
+                Some(Token::Op("[")) => {
+                    self.pos += 1;
+                    let ndx = self.expr(0)?;
+                    self.expect("]")?;
+
+                    let ndx = self.int(ndx)?;
+                    val = self.index(val, ndx)?;
+                }
+                _ => return Ok(val),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Eval> {
+        let op = match self.peek() {
+            Some(Token::Op(op)) if ["*", "&", "-", "~", "!"].contains(op) => {
+                *op
+            }
+            _ => return self.postfix(),
+        };
+
+        self.pos += 1;
+        let val = self.unary()?;
+
+        match op {
+            "*" => self.deref(val),
+            "&" => match val {
+                Eval::Place { addr, .. } => Ok(Eval::Int(addr as u64)),
+                Eval::Int(_) => bail!("cannot take the address of an integer"),
+            },
+            "-" => Ok(Eval::Int(self.int(val)?.wrapping_neg())),
+            _ => Ok(Eval::Int(!self.int(val)?)),
+        }
+    }
+
+    ///
+    /// Evaluates binary operators by precedence climbing, with operators of
+    /// at least the specified precedence.
+    ///
+    fn expr(&mut self, min: u32) -> Result<Eval> {
+        let precedence = |op: &str| match op {
+            "*" | "/" | "%" => Some(5),
+            "+" | "-" => Some(4),
+            "<<" | ">>" => Some(3),
+            "&" => Some(2),
+            "^" => Some(1),
+            "|" => Some(0),
+            _ => None,
+        };
+
+        let mut lhs = self.unary()?;
+
+        loop {
+            let (op, prec) = match self.peek() {
+                Some(Token::Op(op)) => match precedence(op) {
+                    Some(prec) if prec >= min => (*op, prec),
+                    _ => return Ok(lhs),
+                },
+                _ => return Ok(lhs),
+            };
+
+            self.pos += 1;
+
+            let rhs = self.expr(prec + 1)?;
+            let (l, r) = (self.int(lhs)?, self.int(rhs)?);
+
+            let val = match op {
+                "*" => l.wrapping_mul(r),
+                "/" | "%" if r == 0 => bail!("division by zero"),
+                "/" => l / r,
+                "%" => l % r,
+                "+" => l.wrapping_add(r),
+                "-" => l.wrapping_sub(r),
+                "<<" => l.checked_shl(r as u32).unwrap_or(0),
+                ">>" => l.checked_shr(r as u32).unwrap_or(0),
+                "&" => l & r,
+                "^" => l ^ r,
+                _ => l | r,
+            };
+
+            lhs = Eval::Int(val);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use humility::arch::ARMRegister;
+    use std::collections::HashMap;
+
+    ///
+    /// A core that is nothing but words of memory.
+    ///
+    struct Memory(HashMap<u32, u32>);
+
+    impl Core for Memory {
+        fn info(&self) -> (String, Option<String>) {
+            ("memory".to_string(), None)
+        }
+
+        fn read_word_32(&mut self, addr: u32) -> Result<u32> {
+            match self.0.get(&addr) {
+                Some(val) => Ok(*val),
+                None => bail!("no memory at 0x{:x}", addr),
+            }
+        }
+
+        fn read_8(&mut self, addr: u32, data: &mut [u8]) -> Result<()> {
+            for (i, byte) in data.iter_mut().enumerate() {
+                let addr = addr + i as u32;
+                let word = self.read_word_32(addr & !3)?;
+                *byte = word.to_le_bytes()[(addr & 3) as usize];
+            }
+
+            Ok(())
+        }
+
+        fn read_reg(&mut self, _reg: ARMRegister) -> Result<u32> {
+            bail!("no registers")
+        }
+
+        fn write_reg(&mut self, _reg: ARMRegister, _val: u32) -> Result<()> {
+            bail!("no registers")
+        }
+
+        fn init_swv(&mut self) -> Result<()> {
+            bail!("no SWV")
+        }
+
+        fn read_swv(&mut self) -> Result<Vec<u8>> {
+            bail!("no SWV")
+        }
+
+        fn write_word_32(&mut self, _addr: u32, _data: u32) -> Result<()> {
+            bail!("read-only")
+        }
+
+        fn write_8(&mut self, _addr: u32, _data: &[u8]) -> Result<()> {
+            bail!("read-only")
+        }
+
+        fn halt(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn run(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn step(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn eval(expr: &str) -> Result<u64> {
+        let hubris = HubrisArchive::new()?;
+        let mut core = Memory(
+            [(0x2000_0000, 0x2000_0010), (0x2000_0010, 42)]
+                .into_iter()
+                .collect(),
+        );
+
+        match Evaluator::new(&hubris, &mut core, expr)?.evaluate()? {
+            Eval::Int(val) => Ok(val),
+            Eval::Place { addr, .. } => bail!("place at 0x{:x}", addr),
+        }
+    }
+
+    fn error(expr: &str) -> String {
+        eval(expr).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let ident = |s: &str| Token::Ident(s.to_string());
+
+        assert_eq!(
+            tokenize(" task::TASK.state[0x10]<<2 ").unwrap(),
+            [
+                ident("task::TASK"),
+                Token::Op("."),
+                ident("state"),
+                Token::Op("["),
+                Token::Number(16),
+                Token::Op("]"),
+                Token::Op("<<"),
+                Token::Number(2),
+            ]
+        );
+
+        assert_eq!(
+            tokenize("*_x.0").unwrap(),
+            [Token::Op("*"), ident("_x"), Token::Op("."), Token::Number(0)]
+        );
+
+        assert!(tokenize("0x1g").is_err());
+        assert!(tokenize("1 $ 2").is_err());
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), 7);
+        assert_eq!(eval("(1 + 2) * 3").unwrap(), 9);
+        assert_eq!(eval("10 - 4 - 3").unwrap(), 3);
+        assert_eq!(eval("100 / 10 / 5 % 3").unwrap(), 2);
+        assert_eq!(eval("1 | 2 ^ 3 & 4 << 1").unwrap(), 3);
+        assert_eq!(eval("1 << 2 + 1").unwrap(), 8);
+        assert_eq!(eval("-1 + 2").unwrap(), 1);
+        assert_eq!(eval("~0 >> 60").unwrap(), 0xf);
+        assert_eq!(eval("-(2 * 3) * 2").unwrap(), (-12i64) as u64);
+        assert_eq!(eval("1 << 64").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_deref() {
+        assert_eq!(eval("*0x20000000").unwrap(), 0x2000_0010);
+        assert_eq!(eval("**0x20000000 + 1").unwrap(), 43);
+        assert_eq!(eval("*(0x20000000 + 0x10)").unwrap(), 42);
+        assert!(eval("*0x10").is_err());
+        assert_eq!(error("&1"), "cannot take the address of an integer");
+    }
+
+    #[test]
+    fn test_chains() {
+        assert_eq!(error("(1).0"), "integers have no member 0");
+        assert_eq!(error("1[2]"), "cannot index an integer");
+        assert_eq!(error("1.foo[2]"), "integers have no member foo");
+        assert_eq!(error("x.y[1]"), "variable x not found");
+        assert_eq!(error("1.["), "expected member name after '.'");
+        assert_eq!(error("1[2"), "expected ']' at end of expression");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error(""), "unexpected end of expression");
+        assert_eq!(error("1 +"), "unexpected end of expression");
+        assert_eq!(error("(1 + 2"), "expected ')' at end of expression");
+        assert_eq!(error("1 2"), "unexpected Number(2)");
+        assert_eq!(error("3 % (1 - 1)"), "division by zero");
+        assert_eq!(error(")"), "unexpected Op(\")\")");
+    }
+}
@@ -3,7 +3,9 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{
+    capture,
     doppel::StaticCell,
+    error::ErrorKind,
     idol,
     reflect::{self, Load, Value},
 };
@@ -42,9 +44,129 @@ pub struct HiffyContext<'a> {
     kicked: Option<Instant>,
     timeout: u32,
     state: State,
+    captured: Option<Vec<Op>>,
 }
 
-#[derive(Debug)]
+/// The class of a failure of a HIF function
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HiffyErrorKind {
+    /// The device did not respond (that is, it NACK'd its address)
+    NoDevice,
+    /// The device did not accept the register (or data) written to it
+    NoRegister,
+    /// The bus is locked, e.g. because arbitration was lost or a device is
+    /// holding the bus
+    BusLocked,
+    /// The bus was reset, or an error was detected on it
+    BusError,
+    /// A mux (or a segment on it) could not be found or selected
+    Mux,
+    /// The function was given an argument that is not valid on the target
+    BadArgument,
+    /// The operation is not supported by the device or controller
+    NotSupported,
+    /// A named error that we don't otherwise decode
+    Other,
+    /// An error code that the function doesn't define
+    Unknown,
+}
+
+//
+// The errors that we know how to decode, by name.  This covers the errors
+// of the I2C, SPI, QSPI and GPIO functions; errors not found here are
+// still named (if the function defines them), but are otherwise opaque.
+//
+const HIFFY_ERRORS: &[(&str, HiffyErrorKind, &str)] = &[
+    ("NoDevice", HiffyErrorKind::NoDevice, "device did not respond"),
+    ("NoRegister", HiffyErrorKind::NoRegister, "device NACK'd register"),
+    ("BusLocked", HiffyErrorKind::BusLocked, "bus locked"),
+    ("BusLockedMux", HiffyErrorKind::BusLocked, "bus locked by mux"),
+    ("ControllerLocked", HiffyErrorKind::BusLocked, "controller locked"),
+    ("ControllerBusy", HiffyErrorKind::BusLocked, "controller busy"),
+    ("BusReset", HiffyErrorKind::BusError, "bus reset"),
+    ("BusResetMux", HiffyErrorKind::BusError, "bus reset by mux"),
+    ("BusError", HiffyErrorKind::BusError, "bus error"),
+    ("MuxNotFound", HiffyErrorKind::Mux, "mux not found"),
+    ("MuxMissing", HiffyErrorKind::Mux, "mux not responding"),
+    ("BadMux", HiffyErrorKind::Mux, "invalid mux"),
+    ("BadSegment", HiffyErrorKind::Mux, "invalid mux segment"),
+    ("SegmentNotFound", HiffyErrorKind::Mux, "mux segment not found"),
+    ("SegmentDisconnected", HiffyErrorKind::Mux, "mux segment disconnected"),
+    ("BadController", HiffyErrorKind::BadArgument, "invalid controller"),
+    ("BadPort", HiffyErrorKind::BadArgument, "invalid port"),
+    ("BadDefaultPort", HiffyErrorKind::BadArgument, "invalid default port"),
+    ("ReservedAddress", HiffyErrorKind::BadArgument, "reserved address"),
+    ("BadArg", HiffyErrorKind::BadArgument, "invalid argument"),
+    ("BadDevice", HiffyErrorKind::BadArgument, "invalid device"),
+    ("TooMuchData", HiffyErrorKind::BadArgument, "too much data"),
+    (
+        "OperationNotSupported",
+        HiffyErrorKind::NotSupported,
+        "operation not supported",
+    ),
+];
+
+///
+/// A decoded failure of a HIF function.  This displays as a description of
+/// the failure followed by the name of the error, e.g. "device did not
+/// respond (NoDevice)".
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HiffyError {
+    pub function: String,
+    pub code: u32,
+    pub name: Option<String>,
+    pub kind: HiffyErrorKind,
+}
+
+impl HiffyError {
+    ///
+    /// Returns an error with the specified context (e.g., "failed to write
+    /// block 3"), classified as a device NAK if that's what this is.  The
+    /// context precedes the failure in the error's message.
+    ///
+    pub fn context<C>(self, context: C) -> anyhow::Error
+    where
+        C: std::fmt::Display,
+    {
+        let nak = matches!(
+            self.kind,
+            HiffyErrorKind::NoDevice | HiffyErrorKind::NoRegister
+        );
+
+        let err = anyhow!("{}: {}", context, self);
+
+        if nak {
+            ErrorKind::DeviceNak.classify(err)
+        } else {
+            err
+        }
+    }
+}
+
+impl std::fmt::Display for HiffyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match &self.name {
+            Some(name) => name,
+            None => {
+                return write!(
+                    f,
+                    "unknown {} error {}",
+                    self.function, self.code
+                );
+            }
+        };
+
+        match HIFFY_ERRORS.iter().find(|(n, _, _)| n == name) {
+            Some((_, _, desc)) => write!(f, "{} ({})", desc, name),
+            None => write!(f, "{}", name),
+        }
+    }
+}
+
+impl std::error::Error for HiffyError {}
+
+#[derive(Clone, Debug)]
 pub struct HiffyFunction {
     pub id: TargetFunction,
     pub name: String,
@@ -60,6 +182,26 @@ impl HiffyFunction {
         }
     }
 
+    ///
+    /// Decodes an error code returned by this function.  Unlike
+    /// [`HiffyFunction::strerror`] (which merely names the error, and is
+    /// suitable for tables), the resulting [`HiffyError`] describes the
+    /// failure and can be turned into a classified error.
+    ///
+    pub fn error(&self, code: u32) -> HiffyError {
+        let name = self.errmap.get(&code).cloned();
+
+        let kind = match &name {
+            Some(name) => HIFFY_ERRORS
+                .iter()
+                .find(|(n, _, _)| n == name)
+                .map_or(HiffyErrorKind::Other, |(_, kind, _)| *kind),
+            None => HiffyErrorKind::Unknown,
+        };
+
+        HiffyError { function: self.name.clone(), code, name, kind }
+    }
+
     pub fn argument_variants(
         &self,
         hubris: &HubrisArchive,
@@ -261,6 +403,7 @@ impl<'a> HiffyContext<'a> {
             kicked: None,
             timeout,
             state: State::Initialized,
+            captured: None,
         })
     }
 
@@ -268,6 +411,10 @@ impl<'a> HiffyContext<'a> {
         self.data.size
     }
 
+    pub fn text_size(&self) -> usize {
+        self.text.size
+    }
+
     pub fn functions(&mut self) -> Result<HiffyFunctions> {
         let hubris = self.hubris;
 
@@ -398,6 +545,214 @@ impl<'a> HiffyContext<'a> {
         Ok(())
     }
 
+    /// Like [Self::idol_call_ops], but for an operation that takes a single
+    /// read lease:  the first `len` bytes of the HIF data (as passed to
+    /// [Self::run]) are lent to the server.
+    pub fn idol_call_ops_write(
+        &self,
+        funcs: &HiffyFunctions,
+        op: &idol::IdolOperation,
+        payload: &[u8],
+        ops: &mut Vec<Op>,
+        len: u32,
+    ) -> Result<()> {
+        Self::check_leases(op, &[(true, false)])?;
+        let send = funcs.get("SendLeaseRead", 5)?;
+        self.idol_call_ops_leased(send, op, payload, ops, &[len])
+    }
+
+    /// Like [Self::idol_call_ops], but for an operation that takes a single
+    /// write lease:  the server writes into the first `len` bytes of the HIF
+    /// data, which can be retrieved with [Self::read_data] once the call
+    /// completes.
+    pub fn idol_call_ops_read(
+        &self,
+        funcs: &HiffyFunctions,
+        op: &idol::IdolOperation,
+        payload: &[u8],
+        ops: &mut Vec<Op>,
+        len: u32,
+    ) -> Result<()> {
+        Self::check_leases(op, &[(false, true)])?;
+        let send = funcs.get("SendLeaseWrite", 5)?;
+        self.idol_call_ops_leased(send, op, payload, ops, &[len])
+    }
+
+    /// Like [Self::idol_call_ops], but for an operation that takes a read
+    /// lease followed by a write lease:  the first `read` bytes of the HIF
+    /// data are lent to the server, and the server writes into the `write`
+    /// bytes that follow them.
+    pub fn idol_call_ops_read_write(
+        &self,
+        funcs: &HiffyFunctions,
+        op: &idol::IdolOperation,
+        payload: &[u8],
+        ops: &mut Vec<Op>,
+        read: u32,
+        write: u32,
+    ) -> Result<()> {
+        Self::check_leases(op, &[(true, false), (false, true)])?;
+        let send = funcs.get("SendLeaseReadWrite", 6)?;
+        self.idol_call_ops_leased(send, op, payload, ops, &[read, write])
+    }
+
+    ///
+    /// Calls an Idol operation, returning its (raw) reply or the name of its
+    /// error.  `leases` are the lengths of the operation's read and write
+    /// leases, if any:  the read lease is at the start of the HIF data, and
+    /// the write lease follows it (and can be retrieved with
+    /// [Self::read_data] once the call completes).  If `data` is specified,
+    /// it is written to the HIF data before the call; otherwise, the read
+    /// lease is assumed to have been staged.
+    ///
+    pub fn idol_call(
+        &mut self,
+        core: &mut dyn Core,
+        funcs: &HiffyFunctions,
+        op: &idol::IdolOperation,
+        args: &[(&str, idol::IdolArgument)],
+        leases: (Option<u32>, Option<u32>),
+        data: Option<&[u8]>,
+    ) -> Result<Result<Vec<u8>, String>> {
+        let payload = op.payload(args)?;
+        let mut ops = vec![];
+
+        match leases {
+            (None, None) => {
+                self.idol_call_ops(funcs, op, &payload, &mut ops)?
+            }
+            (Some(r), None) => {
+                self.idol_call_ops_write(funcs, op, &payload, &mut ops, r)?
+            }
+            (None, Some(w)) => {
+                self.idol_call_ops_read(funcs, op, &payload, &mut ops, w)?
+            }
+            (Some(r), Some(w)) => self.idol_call_ops_read_write(
+                funcs, op, &payload, &mut ops, r, w,
+            )?,
+        }
+
+        ops.push(Op::Done);
+
+        let results = self.run(core, ops.as_slice(), data)?;
+
+        Ok(match results.into_iter().next() {
+            Some(Ok(val)) => Ok(val),
+            Some(Err(e)) => {
+                match op.error.and_then(|err| err.lookup_variant(e as u64)) {
+                    Some(variant) => Err(variant.name.to_string()),
+                    None => Err(format!("Err(0x{:x})", e)),
+                }
+            }
+            None => bail!("{} returned no result", op.name.1),
+        })
+    }
+
+    fn check_leases(
+        op: &idol::IdolOperation,
+        expected: &[(bool, bool)],
+    ) -> Result<()> {
+        let leases = &op.operation.leases;
+
+        if leases.len() != expected.len()
+            || leases
+                .values()
+                .zip(expected.iter())
+                .any(|(l, &(read, write))| l.read != read || l.write != write)
+        {
+            bail!(
+                "{}.{} does not take the expected leases",
+                op.name.0,
+                op.name.1
+            );
+        }
+
+        Ok(())
+    }
+
+    fn idol_call_ops_leased(
+        &self,
+        send: &HiffyFunction,
+        op: &idol::IdolOperation,
+        payload: &[u8],
+        ops: &mut Vec<Op>,
+        leases: &[u32],
+    ) -> Result<()> {
+        let total: u32 = leases.iter().sum();
+
+        if total as usize > self.data.size {
+            bail!(
+                "lease size ({}) exceeds maximum data size ({})",
+                total,
+                self.data.size
+            );
+        }
+
+        let push = |val: u32| {
+            if val <= u8::MAX as u32 {
+                Op::Push(val as u8)
+            } else if val <= u16::MAX as u32 {
+                Op::Push16(val as u16)
+            } else {
+                Op::Push32(val as u32)
+            }
+        };
+
+        if let HubrisTask::Task(id) = op.task {
+            ops.push(push(id));
+        } else {
+            bail!("interface matches invalid task {:?}", op.task);
+        }
+
+        let size = u8::try_from(4 + leases.len() + payload.len())
+            .map_err(|_| anyhow!("payload size exceeds maximum size"))?;
+
+        ops.push(push(op.code as u32));
+
+        for byte in payload {
+            ops.push(Op::Push(*byte));
+        }
+
+        ops.push(push(payload.len() as u32));
+        ops.push(push(self.hubris.typesize(op.ok)? as u32));
+
+        for len in leases {
+            ops.push(push(*len));
+        }
+
+        ops.push(Op::Call(send.id));
+        ops.push(Op::DropN(size));
+
+        Ok(())
+    }
+
+    /// Reads `len` bytes of the HIF data at the specified offset -- e.g.,
+    /// after a call to an operation with a write lease has completed.
+    pub fn read_data(
+        &self,
+        core: &mut dyn Core,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        if offset + len > self.data.size {
+            bail!(
+                "read of {} bytes at offset {} exceeds data size ({})",
+                len,
+                offset,
+                self.data.size
+            );
+        }
+
+        let mut buf = vec![0u8; len];
+
+        core.op_start()?;
+        let rval = core.read_8(self.data.addr + offset as u32, &mut buf);
+        core.op_done()?;
+        rval?;
+
+        Ok(buf)
+    }
+
     /// Begins HIF execution.  This is non-blocking with respect to the HIF
     /// program, so you will need to poll [Self::done] to check for completion.
     pub fn start(
@@ -430,7 +785,9 @@ impl<'a> HiffyContext<'a> {
 
         if core.read_word_32(self.ready.addr)? != 1 {
             core.op_done()?;
-            bail!("HIF execution facility unavailable");
+            return Err(
+                ErrorKind::Hiffy.error("HIF execution facility unavailable")
+            );
         }
 
         let buf = &mut text.as_mut_slice();
@@ -443,6 +800,13 @@ impl<'a> HiffyContext<'a> {
 
         core.write_8(self.text.addr, &buf[0..])?;
 
+        //
+        // If we are capturing I2C transactions, we hang on to our program
+        // to be able to recover them when we get our results.
+        //
+        self.captured =
+            if capture::active() { Some(ops.to_vec()) } else { None };
+
         if let Some(data) = data {
             core.write_8(self.data.addr, data)?;
         }
@@ -493,7 +857,7 @@ impl<'a> HiffyContext<'a> {
 
         if let Some(kicked) = self.kicked {
             if kicked.elapsed().as_millis() > self.timeout.into() {
-                bail!("operation timed out");
+                return Err(ErrorKind::Hiffy.error("operation timed out"));
             }
         }
 
@@ -524,11 +888,10 @@ impl<'a> HiffyContext<'a> {
                         let hubris = self.hubris;
                         let f =
                             hubris.printfmt(&buf, self.failure.goff, &fmt)?;
-                        bail!("request failed: {}", f);
-                    }
-                    _ => {
-                        bail!("request failed");
+                        Err(ErrorKind::Hiffy
+                            .error(format!("request failed: {}", f)))
                     }
+                    _ => Err(ErrorKind::Hiffy.error("request failed")),
                 }
             } else {
                 Ok(false)
@@ -578,6 +941,12 @@ impl<'a> HiffyContext<'a> {
 
         self.state = State::ResultsConsumed;
 
+        if let Some(ops) = self.captured.take() {
+            if let Ok(functions) = self.functions() {
+                capture::record(self.hubris, &functions, &ops, &rvec);
+            }
+        }
+
         Ok(rvec)
     }
 
@@ -2,9 +2,61 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::hiffy::HiffyFunction;
 use anyhow::{bail, Context, Result};
+use hif::*;
 use humility::hubris::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::sync::Mutex;
+
+///
+/// An alias for an I2C bus, as defined for a target in `humility.toml`.  An
+/// alias names either a bus or a controller and port -- or, if it names
+/// neither, the bus of the same name as the alias -- along with the mux and
+/// segment to use when one is not explicitly given.
+///
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct I2cAlias {
+    pub bus: Option<String>,
+    pub controller: Option<u8>,
+    pub port: Option<String>,
+    pub mux: Option<String>,
+}
+
+impl I2cAlias {
+    pub fn validate(&self) -> Result<()> {
+        match (&self.bus, self.controller, &self.port) {
+            (Some(_), Some(_), _) => {
+                bail!("cannot specify both a bus and a controller")
+            }
+            (Some(_), _, Some(_)) => {
+                bail!("cannot specify both a bus and a port")
+            }
+            (None, None, Some(_)) => {
+                bail!("cannot specify a port without a controller")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref ALIASES: Mutex<BTreeMap<String, I2cAlias>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Sets the I2C bus aliases that apply to [`I2cArgs::parse`].
+pub fn set_aliases(aliases: BTreeMap<String, I2cAlias>) {
+    *ALIASES.lock().unwrap() = aliases;
+}
+
+/// Returns the alias of the specified name, if any.
+pub fn lookup_alias(name: &str) -> Option<I2cAlias> {
+    ALIASES.lock().unwrap().get(name).cloned()
+}
 
 pub struct I2cArgs<'a> {
     pub controller: u8,
@@ -32,6 +84,23 @@ impl fmt::Display for I2cArgs<'_> {
 }
 
 impl<'a> I2cArgs<'a> {
+    ///
+    /// Pushes the controller, port, mux and segment, which are the leading
+    /// arguments of every I2C HIF function.
+    ///
+    pub fn push_bus(&self, ops: &mut Vec<Op>) {
+        ops.push(Op::Push(self.controller));
+        ops.push(Op::Push(self.port.index));
+
+        if let Some((mux, segment)) = self.mux {
+            ops.push(Op::Push(mux));
+            ops.push(Op::Push(segment));
+        } else {
+            ops.push(Op::PushNone);
+            ops.push(Op::PushNone);
+        }
+    }
+
     pub fn from_device(device: &'a HubrisI2cDevice) -> Self {
         Self {
             controller: device.controller,
@@ -134,6 +203,39 @@ impl<'a> I2cArgs<'a> {
             bail!("no I2C buses found; is this an old Hubris image?");
         }
 
+        //
+        // If our bus is an alias, it determines our bus (or our controller
+        // and port) -- and our mux and segment, if we weren't given them.
+        //
+        let (bus, controller, port, mux) =
+            match bus.as_ref().and_then(|b| lookup_alias(b)) {
+                Some(alias) => {
+                    let name = bus.as_ref().unwrap();
+
+                    if controller.is_some() || port.is_some() {
+                        bail!(
+                            "cannot specify a controller or port with bus {}",
+                            name
+                        );
+                    }
+
+                    let bus = match (&alias.bus, alias.controller) {
+                        (None, None) => Some(name.clone()),
+                        (bus, _) => bus.clone(),
+                    };
+
+                    (
+                        bus,
+                        alias.controller,
+                        alias.port.clone(),
+                        mux.clone().or_else(|| alias.mux.clone()),
+                    )
+                }
+                None => (bus.clone(), controller, port.clone(), mux.clone()),
+            };
+
+        let (bus, port, mux) = (&bus, &port, &mux);
+
         //
         // If we were given a bus, that will guide us to our controller and
         // port
@@ -234,3 +336,280 @@ impl<'a> I2cArgs<'a> {
         Ok(Self { controller, port, mux, device, address, class })
     }
 }
+
+///
+/// SMBus-specific aspects of a transaction:  whether it is a block transfer
+/// (in which the data is preceded by a byte count) and whether it is
+/// protected by a Packet Error Code (PEC).
+///
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SmbusOptions {
+    pub block: bool,
+    pub pec: bool,
+}
+
+///
+/// Computes the SMBus Packet Error Code over the specified bytes, which is a
+/// CRC-8 with the polynomial x^8 + x^2 + x + 1.
+///
+pub fn smbus_pec(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+impl SmbusOptions {
+    ///
+    /// Returns the payload to be written to the specified register on the
+    /// specified device:  for a block write, the data is preceded by its
+    /// length; with PEC, it is followed by a PEC computed over the entire
+    /// transaction (including the address and register).
+    ///
+    pub fn write_payload(
+        &self,
+        address: u8,
+        register: Option<u8>,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut payload = vec![];
+
+        if self.block {
+            if data.len() > 255 {
+                bail!("block write of {} bytes is too large", data.len());
+            }
+
+            payload.push(data.len() as u8);
+        }
+
+        payload.extend_from_slice(data);
+
+        if self.pec {
+            let mut bytes = vec![address << 1];
+            bytes.extend(register);
+            bytes.extend_from_slice(&payload);
+            payload.push(smbus_pec(&bytes));
+        }
+
+        Ok(payload)
+    }
+
+    ///
+    /// Returns the number of bytes that must be read to get `nbytes` of data,
+    /// accounting for the byte count (for block reads) and the PEC.
+    ///
+    pub fn read_size(&self, nbytes: usize) -> usize {
+        nbytes + self.block as usize + self.pec as usize
+    }
+
+    ///
+    /// Checks the bytes read from the specified register on the specified
+    /// device, returning the data with any byte count and PEC removed.
+    ///
+    pub fn read_data(
+        &self,
+        address: u8,
+        register: Option<u8>,
+        raw: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut data = raw;
+
+        if self.pec {
+            let (pec, rest) = match data.split_last() {
+                Some((pec, rest)) => (*pec, rest),
+                None => bail!("short read: missing PEC"),
+            };
+
+            //
+            // For a read, the PEC covers the write of the register as well
+            // as the repeated start with the read address.
+            //
+            let mut bytes = vec![];
+
+            if let Some(register) = register {
+                bytes.push(address << 1);
+                bytes.push(register);
+            }
+
+            bytes.push((address << 1) | 1);
+            bytes.extend_from_slice(rest);
+
+            let expected = smbus_pec(&bytes);
+
+            if pec != expected {
+                bail!(
+                    "PEC mismatch: expected 0x{:02x}, found 0x{:02x}",
+                    expected,
+                    pec
+                );
+            }
+
+            data = rest;
+        }
+
+        if self.block {
+            let (count, rest) = match data.split_first() {
+                Some((count, rest)) => (*count as usize, rest),
+                None => bail!("short read: missing block count"),
+            };
+
+            if count != rest.len() {
+                bail!(
+                    "block count mismatch: count is {}, but read {} bytes",
+                    count,
+                    rest.len()
+                );
+            }
+
+            data = rest;
+        }
+
+        Ok(data.to_vec())
+    }
+}
+
+///
+/// Appends the operations for a write-then-read transaction via the
+/// `I2cWriteRead` HIF function:  the specified bytes are written, followed
+/// by a repeated start and a read of `nread` bytes.  This allows for devices
+/// that require more than one byte of register address (e.g., EEPROMs with a
+/// 16-bit pointer), and for devices that require an arbitrary write before a
+/// read without an intervening stop.  The bus (see [`I2cArgs::push_bus`])
+/// and device address must already be on the stack, and are left there.
+///
+pub fn write_read_ops(
+    func: &HiffyFunction,
+    write: &[u8],
+    nread: u8,
+    ops: &mut Vec<Op>,
+) -> Result<()> {
+    if write.is_empty() {
+        bail!("write-then-read requires at least one byte to write");
+    }
+
+    if write.len() > 32 {
+        bail!("write-then-read of {} bytes is too large", write.len());
+    }
+
+    for byte in write {
+        ops.push(Op::Push(*byte));
+    }
+
+    ops.push(Op::Push(write.len() as u8));
+    ops.push(Op::Push(nread));
+    ops.push(Op::Call(func.id));
+    ops.push(Op::DropN(write.len() as u8 + 2));
+
+    Ok(())
+}
+
+///
+/// Statistics of a single I2C transaction, as recorded by the target.
+///
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct I2cTransactionStats {
+    pub address: u8,
+    /// Number of times the transaction was retried
+    pub retries: u8,
+    /// Number of NACKs received
+    pub nacks: u8,
+    /// Number of times arbitration was lost
+    pub arblost: u8,
+    /// Time taken by the transaction (including retries), in microseconds
+    pub latency: u32,
+}
+
+///
+/// Statistics of the I2C transactions performed since they were last
+/// retrieved, as returned (and cleared) by the `I2cStats` HIF function.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct I2cStats {
+    /// Number of transactions that the target could not record
+    pub dropped: u32,
+    pub transactions: Vec<I2cTransactionStats>,
+}
+
+/// Size of an `I2cStats` header, and of each record that follows it
+const I2C_STATS_HEADER: usize = 4;
+const I2C_STATS_RECORD: usize = 8;
+
+impl I2cStats {
+    ///
+    /// Decodes the value returned by `I2cStats`:  the number of dropped
+    /// transactions (as a little-endian u32), followed by an 8-byte record
+    /// for each transaction consisting of the address, retries, NACKs and
+    /// arbitration losses (one byte each) and the latency in microseconds
+    /// (as a little-endian u32).
+    ///
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < I2C_STATS_HEADER
+            || (bytes.len() - I2C_STATS_HEADER) % I2C_STATS_RECORD != 0
+        {
+            bail!("malformed I2C statistics ({} bytes)", bytes.len());
+        }
+
+        let (header, records) = bytes.split_at(I2C_STATS_HEADER);
+        let word = |b: &[u8]| u32::from_le_bytes(b.try_into().unwrap());
+
+        Ok(Self {
+            dropped: word(header),
+            transactions: records
+                .chunks_exact(I2C_STATS_RECORD)
+                .map(|r| I2cTransactionStats {
+                    address: r[0],
+                    retries: r[1],
+                    nacks: r[2],
+                    arblost: r[3],
+                    latency: word(&r[4..]),
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smbus_pec() {
+        assert_eq!(smbus_pec(b"123456789"), 0xf4);
+
+        let smbus = SmbusOptions { block: true, pec: true };
+        let payload = smbus.write_payload(0x58, Some(0x21), &[0x12, 0x34]);
+        let pec = smbus_pec(&[0xb0, 0x21, 0x02, 0x12, 0x34]);
+        assert_eq!(payload.unwrap(), vec![0x02, 0x12, 0x34, pec]);
+
+        let pec = smbus_pec(&[0xb0, 0x21, 0xb1, 0x02, 0x12, 0x34]);
+        let data = smbus.read_data(0x58, Some(0x21), &[0x02, 0x12, 0x34, pec]);
+        assert_eq!(data.unwrap(), vec![0x12, 0x34]);
+
+        let data = smbus.read_data(0x58, Some(0x21), &[0x02, 0x12, 0x34, 0]);
+        assert!(data.is_err());
+    }
+
+    #[test]
+    fn test_i2c_stats() {
+        let bytes = [
+            2, 0, 0, 0, 0x48, 0, 0, 0, 0x90, 0, 0, 0, 0x49, 3, 1, 1, 0x10,
+            0x27, 0, 0,
+        ];
+
+        let stats = I2cStats::from_bytes(&bytes).unwrap();
+        assert_eq!(stats.dropped, 2);
+        assert_eq!(stats.transactions.len(), 2);
+        assert_eq!(stats.transactions[0].latency, 0x90);
+
+        let t = stats.transactions[1];
+        assert_eq!((t.address, t.retries, t.nacks, t.arblost), (0x49, 3, 1, 1));
+        assert_eq!(t.latency, 10_000);
+
+        assert!(I2cStats::from_bytes(&bytes[..7]).is_err());
+    }
+}
@@ -6,6 +6,8 @@ use ::idol::syntax::{Operation, RecvStrategy, Reply};
 use anyhow::{anyhow, bail, Context, Result};
 use humility::hubris::*;
 use indexmap::IndexMap;
+use std::collections::HashMap;
+use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct IdolOperation<'a> {
@@ -47,6 +49,20 @@ impl<'a> IdolOperation<'a> {
     }
 
     pub fn payload(&self, args: &[(&str, IdolArgument)]) -> Result<Vec<u8>> {
+        Ok(self.template(args)?.payload)
+    }
+
+    ///
+    /// Encodes a payload for the specified arguments as a template, in
+    /// which unsigned scalar arguments can be substituted (via
+    /// [`IdolPayload::set`]) without resolving any types.  This is intended
+    /// for commands that make the same call repeatedly with different
+    /// arguments.
+    ///
+    pub fn template(
+        &self,
+        args: &[(&str, IdolArgument)],
+    ) -> Result<IdolPayload> {
         let hubris = self.hubris;
         let module = hubris.lookup_module(self.task)?;
 
@@ -54,6 +70,7 @@ impl<'a> IdolOperation<'a> {
             args.iter().map(|arg| (arg.0, &arg.1)).collect();
 
         let mut payload = vec![0u8; self.args.size];
+        let mut scalars = HashMap::new();
 
         for arg in &self.operation.args {
             let val = map.remove(arg.0 as &str).ok_or_else(|| {
@@ -85,6 +102,15 @@ impl<'a> IdolOperation<'a> {
             if matches!(arg.1.recv, RecvStrategy::FromBytes) {
                 if ty != "bool" {
                     call_arg(hubris, member, val, &mut payload)?;
+
+                    if let HubrisType::Base(base) =
+                        hubris.lookup_type(member.goff)?
+                    {
+                        if base.encoding == HubrisEncoding::Unsigned {
+                            let slot = (member.offset, base.size);
+                            scalars.insert(arg.0.to_string(), slot);
+                        }
+                    }
                 } else {
                     let v = IdolArgument::String(match val {
                         IdolArgument::String("true") => "1",
@@ -125,7 +151,80 @@ impl<'a> IdolOperation<'a> {
             );
         }
 
-        Ok(payload)
+        Ok(IdolPayload { payload, scalars })
+    }
+}
+
+///
+/// A pre-encoded payload for an Idol operation, as returned by
+/// [`IdolOperation::template`].
+///
+#[derive(Clone, Debug)]
+pub struct IdolPayload {
+    payload: Vec<u8>,
+    scalars: HashMap<String, (usize, usize)>,
+}
+
+impl IdolPayload {
+    ///
+    /// Substitutes the value of an unsigned scalar argument.
+    ///
+    pub fn set(&mut self, name: &str, value: u64) -> Result<()> {
+        let (offset, size) = *self.scalars.get(name).ok_or_else(|| {
+            anyhow!("argument \"{}\" is not an unsigned scalar", name)
+        })?;
+
+        if size < 8 && value >> (size * 8) != 0 {
+            bail!("value of {} exceeds maximum for {}", value, name);
+        }
+
+        let dest = &mut self.payload[offset..offset + size];
+        dest.copy_from_slice(&value.to_le_bytes()[..size]);
+
+        Ok(())
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+///
+/// A cache of Idol operations, keyed by interface and operation (and the
+/// task, if specified), for commands that look up operations repeatedly.
+///
+#[derive(Debug)]
+pub struct IdolCache<'a> {
+    hubris: &'a HubrisArchive,
+    ops: HashMap<(String, String, Option<HubrisTask>), Rc<IdolOperation<'a>>>,
+}
+
+impl<'a> IdolCache<'a> {
+    pub fn new(hubris: &'a HubrisArchive) -> Self {
+        Self { hubris, ops: HashMap::new() }
+    }
+
+    pub fn get(
+        &mut self,
+        interface: &str,
+        operation: &str,
+        task: Option<&HubrisTask>,
+    ) -> Result<Rc<IdolOperation<'a>>> {
+        let key = (interface.to_string(), operation.to_string(), task.copied());
+
+        if let Some(op) = self.ops.get(&key) {
+            return Ok(op.clone());
+        }
+
+        let op = Rc::new(IdolOperation::new(
+            self.hubris,
+            interface,
+            operation,
+            task,
+        )?);
+        self.ops.insert(key, op.clone());
+
+        Ok(op)
     }
 }
 
@@ -0,0 +1,392 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Readback of Hubris kernel state.
+//!
+//! This module reads the kernel's task table out of target memory (be it a
+//! live target or a dump) and decodes it by way of the types in the archive.
+//! Decoding is done through the types in [`crate::doppel`], which allows us
+//! to tolerate differences between kernel versions.  Note that we do not
+//! halt the target here:  callers that want a consistent snapshot of a live
+//! system should halt the core before calling [`KernelState::read`] (and run
+//! it afterwards).
+
+use crate::doppel::{
+    FaultInfo, FaultSource, Task, TaskDesc, TaskId, TaskState, UsageError,
+};
+use crate::reflect::{self, Load};
+use anyhow::{bail, Context, Result};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// A task's timer, as found in its kernel task structure.
+#[derive(Copy, Clone, Debug)]
+pub struct KernelTimer {
+    /// Absolute deadline, in kernel ticks
+    pub deadline: u64,
+    /// Deadline relative to the system time at which the task table was
+    /// read; negative values denote a deadline that has passed
+    pub delta: i64,
+    /// Notification bits to be posted when the deadline is reached
+    pub to_post: u32,
+}
+
+/// A single entry in the kernel's task table.
+#[derive(Clone, Debug)]
+pub struct KernelTask {
+    /// Index of the task in the task table
+    pub index: u32,
+    /// Address of the task structure in target memory
+    pub addr: u32,
+    /// Name of the task, as determined by its entry point
+    pub name: String,
+    /// The raw, reflected task structure
+    pub value: reflect::Value,
+    /// The decoded task structure
+    pub task: Task,
+    /// The task's descriptor
+    pub desc: TaskDesc,
+    /// Saved R4 through R6, which are needed to interpret some states (e.g.,
+    /// the notification mask of a receiving task or a panic message)
+    pub saved: BTreeMap<ARMRegister, u32>,
+    /// Pairs of notification mask and IRQ number for the IRQs owned by this
+    /// task
+    pub irqs: Vec<(u32, u32)>,
+    /// The task's timer, if a deadline is set
+    pub timer: Option<KernelTimer>,
+}
+
+impl KernelTask {
+    pub fn is_faulted(&self) -> bool {
+        matches!(self.task.state, TaskState::Faulted { .. })
+    }
+
+    pub fn saved(&self, reg: ARMRegister) -> u32 {
+        *self.saved.get(&reg).unwrap_or(&0)
+    }
+}
+
+/// A snapshot of kernel state.
+#[derive(Clone, Debug)]
+pub struct KernelState {
+    /// System time, in ticks
+    pub ticks: u64,
+    /// Index of the currently running task, if it could be determined
+    pub current: Option<u32>,
+    pub tasks: Vec<KernelTask>,
+}
+
+impl KernelState {
+    pub fn read(hubris: &HubrisArchive, core: &mut dyn Core) -> Result<Self> {
+        let (base, task_count) = hubris.task_table(core)?;
+        let ticks = read_ticks(hubris, core)?;
+
+        let task_t = hubris.lookup_struct_byname("Task")?;
+        let save = task_t.lookup_member("save")?.offset;
+        let state = hubris.lookup_struct_byname("SavedState")?;
+        let r4 = save + state.lookup_member("r4")?.offset;
+
+        let cur =
+            core.read_word_32(hubris.lookup_symword("CURRENT_TASK_PTR")?)?;
+
+        //
+        // We read the entire task table at a go to get as consistent a
+        // snapshot as possible.
+        //
+        let mut taskblock = vec![0; task_t.size * task_count as usize];
+        core.read_8(base, &mut taskblock)
+            .context("failed to read task table")?;
+
+        let mut tasks = vec![];
+        let mut current = None;
+
+        for i in 0..task_count {
+            let addr = base + i * task_t.size as u32;
+            let offs = i as usize * task_t.size;
+
+            let value: reflect::Value =
+                reflect::load(hubris, &taskblock, task_t, offs)?;
+            let task: Task = Task::from_value(&value)
+                .with_context(|| format!("failed to decode task {}", i))?;
+
+            //
+            // Always load R4, R5 and R6, which are in the saved state in our
+            // task structure (and are needed to interpret state).
+            //
+            let mut saved = BTreeMap::new();
+
+            for (n, reg) in [ARMRegister::R4, ARMRegister::R5, ARMRegister::R6]
+                .iter()
+                .enumerate()
+            {
+                let o = offs + r4 + n * 4;
+                let v =
+                    u32::from_le_bytes(taskblock[o..o + 4].try_into().unwrap());
+                saved.insert(*reg, v);
+            }
+
+            let desc: TaskDesc = task.descriptor.load_from(hubris, core)?;
+            let name = hubris
+                .instr_mod(desc.entry_point)
+                .unwrap_or("<unknown>")
+                .to_string();
+
+            let irqs = hubris
+                .manifest
+                .task_irqs
+                .get(&name)
+                .cloned()
+                .unwrap_or_default();
+
+            let timer = task.timer.deadline.map(|deadline| KernelTimer {
+                deadline: deadline.0,
+                delta: deadline.0 as i64 - ticks as i64,
+                to_post: task.timer.to_post.0,
+            });
+
+            if addr == cur {
+                current = Some(i);
+            }
+
+            tasks.push(KernelTask {
+                index: i,
+                addr,
+                name,
+                value,
+                task,
+                desc,
+                saved,
+                irqs,
+                timer,
+            });
+        }
+
+        Ok(Self { ticks, current, tasks })
+    }
+
+    pub fn current_task(&self) -> Option<&KernelTask> {
+        self.current.map(|ndx| &self.tasks[ndx as usize])
+    }
+
+    pub fn lookup_task(&self, name: &str) -> Option<&KernelTask> {
+        self.tasks.iter().find(|t| t.name == name)
+    }
+
+    ///
+    /// Returns a map of IRQ number to the index of the owning task and the
+    /// notification mask that the IRQ is routed to.
+    ///
+    pub fn irq_owners(&self) -> BTreeMap<u32, (u32, u32)> {
+        let mut rval = BTreeMap::new();
+
+        for task in &self.tasks {
+            for (mask, irq) in &task.irqs {
+                rval.insert(*irq, (task.index, *mask));
+            }
+        }
+
+        rval
+    }
+
+    ///
+    /// Returns the tasks that have a timer set, sorted by deadline.
+    ///
+    pub fn timers(&self) -> Vec<(&KernelTask, KernelTimer)> {
+        let mut rval = self
+            .tasks
+            .iter()
+            .filter_map(|t| t.timer.map(|timer| (t, timer)))
+            .collect::<Vec<_>>();
+
+        rval.sort_by_key(|(_, timer)| timer.deadline);
+        rval
+    }
+
+    ///
+    /// Returns a count of tasks by scheduling state name (e.g. "Runnable",
+    /// "InRecv", "Faulted").
+    ///
+    pub fn state_counts(&self) -> HashMap<&'static str, usize> {
+        use crate::doppel::SchedState;
+
+        let mut rval = HashMap::new();
+
+        for task in &self.tasks {
+            let name = match task.task.state {
+                TaskState::Faulted { .. } => "Faulted",
+                TaskState::Healthy(SchedState::Stopped) => "Stopped",
+                TaskState::Healthy(SchedState::Runnable) => "Runnable",
+                TaskState::Healthy(SchedState::InSend(_)) => "InSend",
+                TaskState::Healthy(SchedState::InReply(_)) => "InReply",
+                TaskState::Healthy(SchedState::InRecv(_)) => "InRecv",
+            };
+
+            *rval.entry(name).or_insert(0) += 1;
+        }
+
+        rval
+    }
+}
+
+///
+/// Reads the kernel's notion of system time.  Depending on the vintage of the
+/// kernel, `TICKS` is either a 64-bit or 32-bit quantity.
+///
+pub fn read_ticks(hubris: &HubrisArchive, core: &mut dyn Core) -> Result<u64> {
+    let ticks = hubris.lookup_variable("TICKS")?;
+
+    match ticks.size {
+        8 => core.read_word_64(ticks.addr),
+        4 => Ok(core.read_word_32(ticks.addr)? as u64),
+        size => bail!("TICKS has unexpected size {}", size),
+    }
+}
+
+///
+/// A reset of the target, as detected by a [`Heartbeat`]:  the system time
+/// at the beat before the reset, and at the beat after it.
+///
+#[derive(Copy, Clone, Debug)]
+pub struct Reset {
+    pub before: u64,
+    pub after: u64,
+}
+
+impl std::fmt::Display for Reset {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "target reset (system time went from {} to {} ticks)",
+            self.before, self.after
+        )
+    }
+}
+
+///
+/// Detects that a target has reset while it is being polled.  System time
+/// only increases over the life of a boot, so system time that is lower
+/// than it was at the previous beat means that the target has reset.
+///
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    last: Option<u64>,
+}
+
+impl Heartbeat {
+    ///
+    /// Notes the system time, returning the reset if the target has reset
+    /// since the previous beat.
+    ///
+    pub fn beat(&mut self, ticks: u64) -> Option<Reset> {
+        let reset = match self.last {
+            Some(before) if ticks < before => {
+                Some(Reset { before, after: ticks })
+            }
+            _ => None,
+        };
+
+        self.last = Some(ticks);
+        reset
+    }
+
+    ///
+    /// Reads the system time and notes it as with [`Heartbeat::beat`].
+    ///
+    pub fn read(
+        &mut self,
+        hubris: &HubrisArchive,
+        core: &mut dyn Core,
+    ) -> Result<Option<Reset>> {
+        Ok(self.beat(read_ticks(hubris, core)?))
+    }
+}
+
+///
+/// Describes where a memory or bus fault was taken.
+///
+pub fn describe_source(source: FaultSource) -> &'static str {
+    match source {
+        FaultSource::User => "in task code",
+        FaultSource::Kernel => "in syscall",
+    }
+}
+
+fn describe_usage(e: UsageError) -> &'static str {
+    match e {
+        UsageError::BadSyscallNumber => "undefined syscall number",
+        UsageError::InvalidSlice => "sent malformed slice to kernel",
+        UsageError::TaskOutOfRange => "used bogus task index",
+        UsageError::IllegalTask => "illegal task operation",
+        UsageError::LeaseOutOfRange => "bad caller lease index",
+        UsageError::OffsetOutOfRange => "bad caller lease offset",
+        UsageError::NoIrq => "referred to undefined interrupt",
+        UsageError::BadKernelMessage => "sent nonsense IPC to kernel",
+    }
+}
+
+fn task_id(hubris: &HubrisArchive, id: TaskId) -> String {
+    match hubris.task_name(id.index()) {
+        Some(name) => format!("{}/gen{}", name, id.generation()),
+        None => format!("unknown#{}/gen{}", id.index(), id.generation()),
+    }
+}
+
+fn panic_message(core: &mut dyn Core, task: &KernelTask) -> Result<String> {
+    let base = task.saved(ARMRegister::R4);
+    let len = task.saved(ARMRegister::R5).min(255) as usize;
+    let mut buf = vec![0; len];
+
+    core.read_8(base, &mut buf)?;
+
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+///
+/// Describes a task's fault in a single line, including the message of a
+/// panic (which is read from the task's memory).
+///
+pub fn describe_fault(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    task: &KernelTask,
+    fault: FaultInfo,
+) -> String {
+    let addr = |address: Option<u32>| match address {
+        Some(addr) => format!("precise: 0x{:x}", addr),
+        None => "imprecise".to_string(),
+    };
+
+    match fault {
+        FaultInfo::MemoryAccess { address, source } => {
+            format!("mem fault ({}) {}", addr(address), describe_source(source))
+        }
+        FaultInfo::BusError { address, source } => {
+            format!("bus fault ({}) {}", addr(address), describe_source(source))
+        }
+        FaultInfo::StackOverflow { address } => {
+            format!("stack overflow; sp=0x{:x}", address)
+        }
+        FaultInfo::DivideByZero => "divide by zero".to_string(),
+        FaultInfo::IllegalText => "jump to non-executable mem".to_string(),
+        FaultInfo::IllegalInstruction => "illegal instruction".to_string(),
+        FaultInfo::InvalidOperation(bits) => {
+            format!("general fault, cfsr=0x{:x}", bits)
+        }
+        FaultInfo::SyscallUsage(e) => {
+            format!("in syscall: {}", describe_usage(e))
+        }
+        FaultInfo::Panic => match panic_message(core, task) {
+            Ok(msg) => format!("panic: {}", msg),
+            Err(_) => "panic with unreadable message".to_string(),
+        },
+        FaultInfo::Injected(id) => {
+            format!("killed by {}", task_id(hubris, id))
+        }
+        FaultInfo::FromServer(id, reason) => {
+            format!("reply fault from {}: {:?}", task_id(hubris, id), reason)
+        }
+    }
+}
@@ -0,0 +1,117 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Decoding of the Hubris kernel's event log.
+//!
+//! Kernels built with event logging record kernel-level events (e.g., task
+//! faults and IPC errors) in a ring buffer, `KLOG`, in kernel memory.  Each
+//! entry records the kernel time at which the event occurred, the task to
+//! which it pertains, and the event itself -- an enum that we symbolize by
+//! way of the types in the archive.  Kernels built without event logging
+//! have no `KLOG`, and have no events to decode.
+
+use crate::doppel::{Klog, StaticCell, TaskId};
+use crate::reflect::{self, Format, Load, Value};
+use anyhow::{Context, Result};
+use humility::core::Core;
+use humility::hubris::*;
+
+/// A kernel event, as read from the event log.
+#[derive(Clone, Debug)]
+pub struct KlogEvent {
+    /// Sequence number of the event since boot
+    pub seq: u32,
+    /// Kernel time at which the event occurred, in ticks
+    pub ticks: u64,
+    /// Task to which the event pertains
+    pub task: TaskId,
+    /// The event itself
+    pub event: Value,
+}
+
+impl KlogEvent {
+    ///
+    /// Returns the name of the event (that is, its variant).
+    ///
+    pub fn name(&self) -> &str {
+        match &self.event {
+            Value::Enum(e) => e.disc(),
+            _ => "",
+        }
+    }
+
+    ///
+    /// Describes the event symbolically, including its payload (if any).
+    ///
+    pub fn describe(&self, hubris: &HubrisArchive) -> Result<String> {
+        let fmt =
+            HubrisPrintFormat { hex: true, ..HubrisPrintFormat::default() };
+        let mut out = vec![];
+        self.event.format(hubris, fmt, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+}
+
+///
+/// Returns the kernel's event log, if the kernel has one.
+///
+pub fn klog(hubris: &HubrisArchive) -> Option<&HubrisVariable> {
+    hubris.lookup_variable("KLOG").ok()
+}
+
+///
+/// Reads the kernel's event log, returning the events that remain in it
+/// (oldest first) along with the total number of events logged since boot.
+/// Events with sequence numbers less than `after` are skipped.  Note that
+/// this does not halt the target; if a consistent view of a live target is
+/// desired, the caller should halt it first.
+///
+pub fn read(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    variable: &HubrisVariable,
+    after: u32,
+) -> Result<(Vec<KlogEvent>, u32)> {
+    let mut buf: Vec<u8> = vec![0; variable.size];
+    core.read_8(variable.addr, buf.as_mut_slice())
+        .context("failed to read kernel event log")?;
+
+    let value: Value =
+        reflect::load(hubris, &buf, hubris.lookup_type(variable.goff)?, 0)?;
+
+    //
+    // As with ring buffers, the log may or may not be in a StaticCell.
+    //
+    let klog = Klog::from_value(&value).or_else(|_e| {
+        let cell: StaticCell = StaticCell::from_value(&value)?;
+        Klog::from_value(&cell.cell.value)
+    })?;
+
+    let len = klog.buffer.len() as u32;
+
+    if len == 0 {
+        return Ok((vec![], klog.count));
+    }
+
+    //
+    // Only the most recent events remain in the buffer; anything older has
+    // been overwritten.
+    //
+    let first = klog.count.saturating_sub(len).max(after);
+
+    let events = (first..klog.count)
+        .map(|seq| {
+            let entry = &klog.buffer[(seq % len) as usize];
+
+            KlogEvent {
+                seq,
+                ticks: entry.timestamp,
+                task: entry.task,
+                event: entry.event.clone(),
+            }
+        })
+        .collect();
+
+    Ok((events, klog.count))
+}
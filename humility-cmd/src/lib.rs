@@ -2,19 +2,54 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//!
+//! The layer between Humility's commands and [`humility`] (that is,
+//! `humility-core`):  facilities for running HIF programs on a target
+//! ([`hiffy::HiffyContext`]), making Idol calls ([`idol::IdolOperation`]),
+//! loading Rust values out of target memory ([`reflect`]), and the like.
+//! These are usable as a library; they keep no global state and do not
+//! print.
+//!
+//! The remainder of this crate -- [`Args`], [`Command`], [`attach`] and the
+//! modules that render output for the user, like [`output`] and [`stack`]
+//! -- is the framework for Humility's command-line subcommands, and is
+//! subject to change along with them.
+//!
+
+pub mod alert;
+pub mod attest;
+pub mod capture;
+pub mod clock;
+pub mod config;
+pub mod counters;
+pub mod deferred;
 pub mod doppel;
+pub mod eeprom;
+pub mod error;
+pub mod eval;
 pub mod hiffy;
 pub mod i2c;
 pub mod idol;
 pub mod jefe;
+pub mod kernel;
+pub mod klog;
+pub mod output;
+pub mod pmgen;
+pub mod progress;
 pub mod reflect;
+pub mod retry;
+pub mod ringbuf;
 pub mod stack;
+pub mod style;
 pub mod test;
+pub mod transfer;
 
-use anyhow::{bail, Result};
+use anyhow::Result;
 use clap::{AppSettings, Parser};
+use error::{Classify, ErrorKind};
 use humility::core::Core;
 use humility::hubris::*;
+use std::io::{self, Write};
 
 #[derive(Parser)]
 #[clap(name = "humility", max_term_width = 80)]
@@ -40,6 +75,85 @@ pub struct Args {
     #[clap(long, short, env = "HUMILITY_DUMP")]
     pub dump: Option<String>,
 
+    /// named target (as defined in humility.toml)
+    #[clap(long, value_name = "name", env = "HUMILITY_TARGET")]
+    pub target: Option<String>,
+
+    /// run a read-only command against each target in a file of targets
+    #[clap(
+        long,
+        value_name = "file",
+        conflicts_with_all = &["target", "probe", "dump"]
+    )]
+    pub fleet: Option<String>,
+
+    /// maximum number of targets to run against at once with --fleet
+    #[clap(long, value_name = "n", default_value = "8", requires = "fleet")]
+    pub jobs: usize,
+
+    /// also run a read-only command against the named target as the root
+    /// of trust, merging the output of both onto one timeline
+    #[clap(
+        long,
+        value_name = "target",
+        conflicts_with_all = &["fleet", "dump", "capture-i2c"]
+    )]
+    pub rot: Option<String>,
+
+    /// output format for commands that emit tables
+    #[clap(
+        long,
+        short = 'F',
+        arg_enum,
+        default_value = "table",
+        env = "HUMILITY_FORMAT"
+    )]
+    pub format: output::OutputFormat,
+
+    /// report errors on stderr as JSON
+    #[clap(long)]
+    pub json_errors: bool,
+
+    /// when to color output
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "auto",
+        value_name = "when",
+        env = "HUMILITY_COLOR"
+    )]
+    pub color: style::ColorChoice,
+
+    /// use a mismatched archive with a dump, for tasks whose text matches
+    #[clap(long, requires = "dump")]
+    pub allow_mismatch: bool,
+
+    /// file of calibrations to apply to sensor values
+    #[clap(long, value_name = "file", env = "HUMILITY_CALIBRATION")]
+    pub calibration: Option<String>,
+
+    /// record the I2C transactions performed by the command to a file
+    #[clap(long, value_name = "file", conflicts_with = "fleet")]
+    pub capture_i2c: Option<String>,
+
+    /// suppress progress reporting
+    #[clap(long, short = 'q')]
+    pub quiet: bool,
+
+    /// bypass (and update) the cached output of archive-only commands
+    #[clap(long)]
+    pub refresh: bool,
+
+    /// format of progress reporting for long-running operations
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "bar",
+        value_name = "format",
+        env = "HUMILITY_PROGRESS"
+    )]
+    pub progress: progress::ProgressFormat,
+
     //
     // probe-rs requires the chip to be specified when creating a session,
     // even though it is only used for flashing (which we don't use probe-rs
@@ -122,14 +236,17 @@ pub fn attach_live(
     hubris: &HubrisArchive,
 ) -> Result<Box<dyn Core>> {
     if args.dump.is_some() {
-        bail!("must be run against a live system");
+        Err(ErrorKind::Usage.error("must be run against a live system"))
     } else {
         let probe = match &args.probe {
             Some(p) => p,
             None => "auto",
         };
 
-        humility::core::attach(probe, hubris)
+        let attached = humility::core::attach(probe, hubris)
+            .classify(ErrorKind::Attach)?;
+        humility::msg!("attached {}", attached.description);
+        Ok(attached.core)
     }
 }
 
@@ -138,12 +255,67 @@ pub fn attach_dump(
     hubris: &HubrisArchive,
 ) -> Result<Box<dyn Core>> {
     if let Some(dump) = &args.dump {
-        humility::core::attach_dump(dump, hubris)
+        let attached = humility::core::attach_dump(dump, hubris)
+            .classify(ErrorKind::Attach)?;
+        humility::msg!("attached {}", attached.description);
+        Ok(attached.core)
     } else {
-        bail!("must be run against a dump");
+        Err(ErrorKind::Usage.error("must be run against a dump"))
     }
 }
 
+///
+/// Validates that the archive matches the target.  If it doesn't, but the
+/// target is a dump and `--allow-mismatch` has been specified, we instead
+/// compare the text of each task and warn about those that differ:  their
+/// symbols (and anything derived from them) may be wrong.
+///
+fn validate_match(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+) -> Result<()> {
+    let err = match hubris.validate(core, HubrisValidate::ArchiveMatch) {
+        Ok(_) => return Ok(()),
+        Err(err) => err,
+    };
+
+    if !(args.allow_mismatch && core.is_dump()) {
+        return Err(ErrorKind::ArchiveMismatch.classify(err));
+    }
+
+    humility::msg!("warning: archive does not match dump: {}", err);
+
+    let mut mismatched = 0;
+
+    for check in hubris.check_text(core) {
+        if check.matches() {
+            continue;
+        }
+
+        mismatched += 1;
+
+        match check.target {
+            Ok(_) => humility::msg!(
+                "warning: text of {} differs from archive; \
+                its symbols are unreliable",
+                check.name
+            ),
+            Err(_) => humility::msg!(
+                "warning: text of {} is not in dump; \
+                its symbols cannot be verified",
+                check.name
+            ),
+        }
+    }
+
+    if mismatched == 0 {
+        humility::msg!("text of every task matches archive");
+    }
+
+    Ok(())
+}
+
 pub fn attach(
     hubris: &HubrisArchive,
     args: &Args,
@@ -165,19 +337,37 @@ pub fn attach(
 
     let core = c.as_mut();
 
+    //
+    // Validating that the target has booted also validates that it matches
+    // the archive; we check the match first to distinguish these failures.
+    //
     match validate {
         Validate::Booted => {
-            hubris.validate(core, HubrisValidate::Booted)?;
-        }
-        Validate::Match => {
-            hubris.validate(core, HubrisValidate::ArchiveMatch)?;
+            validate_match(hubris, core, args)?;
+            hubris
+                .validate(core, HubrisValidate::Booted)
+                .classify(ErrorKind::TargetFault)?;
         }
+        Validate::Match => validate_match(hubris, core, args)?,
         Validate::None => {}
     }
 
     (run)(hubris, core)
 }
 
+///
+/// Prompts the user to confirm an operation, returning true if they did.
+///
+pub fn confirm(prompt: &str) -> Result<bool> {
+    print!("{} [y/N] ", prompt);
+    io::stdout().flush()?;
+
+    let mut response = String::new();
+    io::stdin().read_line(&mut response)?;
+
+    Ok(matches!(response.trim(), "y" | "Y" | "yes"))
+}
+
 pub struct Dumper {
     /// Word size, in bytes
     pub size: usize,
@@ -0,0 +1,472 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Tabular output in a user-selectable format.
+//!
+//! Commands that emit rows of data describe their columns with [`Column`]
+//! and emit typed rows of [`Cell`] via a [`Table`], which renders them
+//! according to the [`OutputFormat`] specified with the global `--format`
+//! option: as an aligned, human-readable table (the default), as JSON (one
+//! object per row, with each column's name as a key), or as CSV (with a
+//! header row of column names).  Because rows are written as they are
+//! emitted, commands that produce output continuously can use a single
+//! table for their lifetime.  Cells can be styled by severity (see
+//! [`crate::style`]), which affects only their rendering in a table.
+//!
+
+use crate::style::Severity;
+use anyhow::{bail, Result};
+use clap::ArgEnum;
+use std::io::{self, Write};
+
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Table
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Right,
+}
+
+///
+/// A single value in a row.  Each variant is rendered in a manner suitable
+/// for humans in a table, and as its natural type in JSON.
+///
+#[derive(Clone, Debug)]
+pub enum Cell {
+    Str(String),
+    Unsigned(u64),
+    Signed(i64),
+    /// A floating point value, rendered in a table to two decimal places
+    Float(f64),
+    /// An integer rendered in a table as hex, zero-padded to the specified
+    /// number of digits
+    Hex(u64, usize),
+    /// A size in bytes, rendered in a table in KiB if at least 1 KiB
+    Size(u64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    /// An absent value, rendered as `-` in a table and `null` in JSON
+    None,
+    /// A value styled by severity when rendered in a table
+    Styled(Box<Cell>, Severity),
+}
+
+impl Cell {
+    ///
+    /// Styles the cell by severity when it is rendered in a table.
+    ///
+    pub fn styled(self, severity: Severity) -> Self {
+        match self {
+            Cell::Styled(cell, _) => Cell::Styled(cell, severity),
+            cell => Cell::Styled(Box::new(cell), severity),
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Cell::Str(s) => s.clone(),
+            Cell::Unsigned(v) => v.to_string(),
+            Cell::Signed(v) => v.to_string(),
+            Cell::Float(v) => format!("{:.2}", v),
+            Cell::Hex(v, width) => format!("0x{:0width$x}", v, width = width),
+            Cell::Size(v) if *v >= 1024 => format!("{}KiB", v >> 10),
+            Cell::Size(v) => v.to_string(),
+            Cell::Bool(b) => b.to_string(),
+            Cell::Bytes(b) => b
+                .iter()
+                .map(|b| format!("0x{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" "),
+            Cell::None => "-".to_string(),
+            Cell::Styled(cell, _) => cell.text(),
+        }
+    }
+
+    fn align(&self) -> Align {
+        match self {
+            Cell::Styled(cell, _) => cell.align(),
+            Cell::Unsigned(_)
+            | Cell::Signed(_)
+            | Cell::Float(_)
+            | Cell::Size(_) => Align::Right,
+            _ => Align::Left,
+        }
+    }
+
+    fn json(&self) -> serde_json::Value {
+        use serde_json::Value;
+
+        match self {
+            Cell::Str(s) => Value::from(s.as_str()),
+            Cell::Unsigned(v) | Cell::Hex(v, _) | Cell::Size(v) => {
+                Value::from(*v)
+            }
+            Cell::Signed(v) => Value::from(*v),
+            Cell::Float(v) => Value::from(*v),
+            Cell::Bool(b) => Value::from(*b),
+            Cell::Bytes(b) => Value::from(b.clone()),
+            Cell::None => Value::Null,
+            Cell::Styled(cell, _) => cell.json(),
+        }
+    }
+
+    fn csv(&self) -> String {
+        let text = match self {
+            Cell::Styled(cell, _) => return cell.csv(),
+            Cell::Size(v) => v.to_string(),
+            Cell::Float(v) => v.to_string(),
+            Cell::None => String::new(),
+            _ => self.text(),
+        };
+
+        if text.contains(&[',', '"', '\n'][..]) {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        } else {
+            text
+        }
+    }
+}
+
+impl From<&str> for Cell {
+    fn from(s: &str) -> Self {
+        Cell::Str(s.to_string())
+    }
+}
+
+impl From<String> for Cell {
+    fn from(s: String) -> Self {
+        Cell::Str(s)
+    }
+}
+
+impl<T: Into<Cell>> From<Option<T>> for Cell {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Cell::None, Into::into)
+    }
+}
+
+macro_rules! cell_from {
+    ($variant:ident, $as:ty, $($t:ty),*) => {
+        $(
+            impl From<$t> for Cell {
+                fn from(v: $t) -> Self {
+                    Cell::$variant(v as $as)
+                }
+            }
+        )*
+    };
+}
+
+cell_from!(Unsigned, u64, u8, u16, u32, usize);
+cell_from!(Signed, i64, i8, i16, i32);
+cell_from!(Float, f64, f32);
+
+impl From<u64> for Cell {
+    fn from(v: u64) -> Self {
+        Cell::Unsigned(v)
+    }
+}
+
+impl From<i64> for Cell {
+    fn from(v: i64) -> Self {
+        Cell::Signed(v)
+    }
+}
+
+impl From<f64> for Cell {
+    fn from(v: f64) -> Self {
+        Cell::Float(v)
+    }
+}
+
+///
+/// A column of a table.  The name of a column is used as its key in JSON and
+/// its name in the CSV header; by default, its heading in a table is its
+/// name in upper case.  In a table, each column is preceded by a separator
+/// (by default, a single space); in the header, the separator is rendered
+/// as spaces.
+///
+#[derive(Clone, Debug)]
+pub struct Column {
+    pub name: String,
+    pub heading: String,
+    pub width: usize,
+    pub align: Option<Align>,
+    pub separator: String,
+}
+
+impl Column {
+    pub fn new(name: &str, width: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            heading: name.to_uppercase(),
+            width,
+            align: None,
+            separator: " ".to_string(),
+        }
+    }
+
+    pub fn separator(mut self, separator: &str) -> Self {
+        self.separator = separator.to_string();
+        self
+    }
+
+    pub fn heading(mut self, heading: &str) -> Self {
+        self.heading = heading.to_string();
+        self
+    }
+
+    pub fn left(mut self) -> Self {
+        self.align = Some(Align::Left);
+        self
+    }
+
+    pub fn right(mut self) -> Self {
+        self.align = Some(Align::Right);
+        self
+    }
+}
+
+pub struct Table {
+    format: OutputFormat,
+    columns: Vec<Column>,
+    header: bool,
+}
+
+impl Table {
+    pub fn new(format: OutputFormat, columns: Vec<Column>) -> Self {
+        Self { format, columns, header: false }
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    fn pad(text: &str, width: usize, align: Align) -> String {
+        match align {
+            Align::Left => format!("{:<width$}", text, width = width),
+            Align::Right => format!("{:>width$}", text, width = width),
+        }
+    }
+
+    ///
+    /// Emits the header, if it hasn't already been emitted.  This is done
+    /// implicitly by the first call to [`Table::row`]; it need only be called
+    /// explicitly by consumers that wish to emit additional output
+    /// immediately after the header.
+    ///
+    pub fn header(&mut self) -> Result<()> {
+        if self.header {
+            return Ok(());
+        }
+
+        self.header = true;
+
+        let line = match self.format {
+            OutputFormat::Table => {
+                let mut line = String::new();
+
+                for (i, c) in self.columns.iter().enumerate() {
+                    if i > 0 {
+                        line.push_str(&" ".repeat(c.separator.chars().count()));
+                    }
+
+                    let align = c.align.unwrap_or(Align::Left);
+                    line.push_str(&Self::pad(&c.heading, c.width, align));
+                }
+
+                line
+            }
+            OutputFormat::Csv => self
+                .columns
+                .iter()
+                .map(|c| Cell::from(c.name.as_str()).csv())
+                .collect::<Vec<_>>()
+                .join(","),
+            OutputFormat::Json => return Ok(()),
+        };
+
+        writeln!(io::stdout(), "{}", line)?;
+
+        Ok(())
+    }
+
+    ///
+    /// Emits a note among the rows (e.g., to mark an event that bears on the
+    /// rows that follow it):  as a line of its own in a table, as a comment
+    /// in CSV, and as an object with a `note` field in JSON.
+    ///
+    pub fn note(&mut self, note: &str) -> Result<()> {
+        self.header()?;
+
+        let line = match self.format {
+            OutputFormat::Table => {
+                Severity::Warning.paint(&format!("*** {} ***", note))
+            }
+            OutputFormat::Csv => format!("# {}", note),
+            OutputFormat::Json => {
+                serde_json::json!({ "note": note }).to_string()
+            }
+        };
+
+        writeln!(io::stdout(), "{}", line)?;
+
+        Ok(())
+    }
+
+    ///
+    /// Renders a row in the table's format.
+    ///
+    fn render(&self, cells: &[Cell]) -> String {
+        match self.format {
+            OutputFormat::Table => {
+                let mut line = String::new();
+                let last = self.columns.len().saturating_sub(1);
+
+                for (i, (c, cell)) in
+                    self.columns.iter().zip(cells.iter()).enumerate()
+                {
+                    if i > 0 {
+                        line.push_str(&c.separator);
+                    }
+
+                    let align = c.align.unwrap_or_else(|| cell.align());
+
+                    //
+                    // A left-aligned value in the last column is not padded,
+                    // lest every row end in whitespace.
+                    //
+                    let width = match (i == last, align) {
+                        (true, Align::Left) => 0,
+                        _ => c.width,
+                    };
+
+                    let text = Self::pad(&cell.text(), width, align);
+
+                    line.push_str(&match cell {
+                        Cell::Styled(_, severity) => severity.paint(&text),
+                        _ => text,
+                    });
+                }
+
+                line
+            }
+            OutputFormat::Csv => {
+                cells.iter().map(Cell::csv).collect::<Vec<_>>().join(",")
+            }
+            OutputFormat::Json => {
+                //
+                // We construct each object ourselves (rather than using a
+                // map) to preserve the order of the columns.
+                //
+                let fields = self
+                    .columns
+                    .iter()
+                    .zip(cells.iter())
+                    .map(|(c, cell)| {
+                        format!(
+                            "{}:{}",
+                            serde_json::Value::from(c.name.as_str()),
+                            cell.json()
+                        )
+                    })
+                    .collect::<Vec<_>>();
+
+                format!("{{{}}}", fields.join(","))
+            }
+        }
+    }
+
+    pub fn row(&mut self, cells: Vec<Cell>) -> Result<()> {
+        if cells.len() != self.columns.len() {
+            bail!(
+                "row has {} cells, but table has {} columns",
+                cells.len(),
+                self.columns.len()
+            );
+        }
+
+        self.header()?;
+
+        let line = self.render(&cells);
+
+        writeln!(io::stdout(), "{}", line)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv() {
+        let table = Table::new(
+            OutputFormat::Csv,
+            vec![Column::new("name", 8), Column::new("value", 8)],
+        );
+
+        assert_eq!(table.render(&["plain".into(), 3u32.into()]), "plain,3");
+        assert_eq!(
+            table.render(&["a,b".into(), "say \"hi\"".into()]),
+            r#""a,b","say ""hi""""#
+        );
+        assert_eq!(Cell::from("two\nlines").csv(), "\"two\nlines\"");
+        assert_eq!(Cell::None.csv(), "");
+        assert_eq!(Cell::Size(2048).csv(), "2048");
+        assert_eq!(Cell::Float(1.5).csv(), "1.5");
+        assert_eq!(Cell::Bytes(vec![0x1, 0xab]).csv(), "0x01 0xab");
+        assert_eq!(Cell::from("x,y").styled(Severity::Error).csv(), "\"x,y\"");
+    }
+
+    #[test]
+    fn test_json() {
+        let table = Table::new(
+            OutputFormat::Json,
+            vec![
+                Column::new("zeta", 4),
+                Column::new("alpha", 4),
+                Column::new("bytes", 4),
+                Column::new("missing", 4),
+            ],
+        );
+
+        let cells = [
+            Cell::Hex(0x10, 4),
+            "a\"b".into(),
+            Cell::Bytes(vec![0, 0xff]),
+            Cell::None,
+        ];
+
+        assert_eq!(
+            table.render(&cells),
+            r#"{"zeta":16,"alpha":"a\"b","bytes":[0,255],"missing":null}"#
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        let table = Table::new(
+            OutputFormat::Table,
+            vec![Column::new("id", 4), Column::new("name", 8)],
+        );
+
+        assert_eq!(table.render(&[7u32.into(), "foo".into()]), "   7 foo");
+        assert_eq!(Cell::Bytes(vec![0xde, 0xad]).text(), "0xde 0xad");
+        assert_eq!(Cell::Size(4096).text(), "4KiB");
+    }
+}
@@ -0,0 +1,349 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Ingestion of vendor configuration exports for PMBus devices, and
+//! generation of Rust payload functions from them.
+//!
+//! A configuration is a sequence of [`Packet`]s, each of which is a write
+//! to either a PMBus command or (on Renesas devices) a DMA address.  A
+//! configuration is ingested from a vendor export ([`ingest_renesas`] for
+//! the Renesas Power Navigator text format; [`ingest`] for the simpler
+//! command/value exports of other vendors), and emitted via [`generate`] as
+//! a Rust function that iterates over the configuration's raw writes.  This
+//! module is independent of any particular device:  commands are resolved
+//! by the caller, typically via the `pmbus` crate.
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::io::{BufRead, Write};
+
+/// The destination of a configuration write
+#[derive(Clone, Debug)]
+pub enum Address {
+    /// A write to a DMA address (Renesas devices only)
+    Dma(u16),
+    /// A write to a PMBus command, with the command's name
+    Pmbus(u8, String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Packet {
+    pub address: Address,
+    pub payload: Vec<u8>,
+}
+
+///
+/// A configuration write in a canonical form for consumption by other
+/// tooling (e.g., when serialized as JSON or RON), with its payload in the
+/// order in which it is written.
+///
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Record {
+    Pmbus { code: u8, name: String, payload: Vec<u8> },
+    Dma { address: u16, payload: Vec<u8> },
+}
+
+impl From<&Packet> for Record {
+    fn from(packet: &Packet) -> Self {
+        let payload = packet.payload.clone();
+
+        match &packet.address {
+            Address::Pmbus(code, name) => {
+                Record::Pmbus { code: *code, name: name.clone(), payload }
+            }
+            Address::Dma(address) => Record::Dma { address: *address, payload },
+        }
+    }
+}
+
+/// The codes of the commands used for DMA writes on Renesas devices
+#[derive(Copy, Clone, Debug)]
+pub struct Dma {
+    /// The code of `DMAADDR`, which sets the DMA address
+    pub addr: u8,
+    /// The code of `DMAFIX`, which writes to the DMA address
+    pub fix: u8,
+}
+
+/// The width of the data written by a PMBus command
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Width {
+    /// A send byte, which has no data
+    None,
+    /// A fixed number of bytes, written little-endian
+    Fixed(usize),
+    /// A block, which is preceded by its byte count
+    Block,
+}
+
+fn hex(lineno: usize, val: &str, len: usize) -> Result<Vec<u8>> {
+    let bad = || anyhow::anyhow!("bad payload on line {}: {}", lineno, val);
+
+    let digits = val.strip_prefix("0x").ok_or_else(bad)?;
+
+    if digits.is_empty() || digits.len() > len * 2 {
+        return Err(bad());
+    }
+
+    let n = u64::from_str_radix(digits, 16).map_err(|_| bad())?;
+
+    Ok(n.to_le_bytes()[..len].to_vec())
+}
+
+///
+/// Ingests a Renesas Power Navigator text export, in which each line
+/// contains a payload and an address (preceded by `#`):  a single-byte
+/// address denotes a PMBus command (named via `name`), and a two-byte
+/// address a DMA address.
+///
+pub fn ingest_renesas(
+    reader: impl BufRead,
+    name: impl Fn(u8) -> Option<String>,
+) -> Result<Vec<Packet>> {
+    let mut packets = vec![];
+
+    for (ndx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let lineno = ndx + 1;
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let contents = line.split_whitespace().collect::<Vec<_>>();
+
+        if contents.len() != 4 || contents[2] != "#" {
+            bail!("malformed line {}", lineno);
+        }
+
+        let payload = contents[1];
+
+        if !payload.starts_with("0x") {
+            bail!("bad payload prefix on line {}: {}", lineno, payload);
+        }
+
+        let payload = match payload.len() {
+            4 => hex(lineno, payload, 1)?,
+            6 => hex(lineno, payload, 2)?,
+            10 => hex(lineno, payload, 4)?,
+            _ => {
+                bail!("badly sized payload on line {}: {}", lineno, payload);
+            }
+        };
+
+        let address = contents[3];
+
+        //
+        // This is lame, but the only way to differentiate PMBus writes
+        // (single-byte address) from DMA writes (dual-byte) is to look
+        // at length of the string:
+        //
+        if !address.starts_with("0x") {
+            bail!("bad address on line {}: {}", lineno, address);
+        }
+
+        let address = if address.len() > 4 {
+            match parse_int::parse::<u16>(address) {
+                Ok(dmaaddr) => Address::Dma(dmaaddr),
+                Err(_) => {
+                    bail!("bad DMA address on line {}: {}", lineno, address);
+                }
+            }
+        } else {
+            match parse_int::parse::<u8>(address) {
+                Ok(code) => match name(code) {
+                    Some(name) => Address::Pmbus(code, name),
+                    None => {
+                        bail!("unknown command on line {}: {}", lineno, address)
+                    }
+                },
+                Err(_) => {
+                    bail!("bad PMBus address on line {}: {}", lineno, address);
+                }
+            }
+        };
+
+        packets.push(Packet { address, payload });
+    }
+
+    //
+    // The export doesn't include the final write to 0xe7 that applies the
+    // configuration; we add it ourselves.
+    //
+    match name(0xe7) {
+        Some(name) => packets.push(Packet {
+            address: Address::Pmbus(0xe7, name),
+            payload: vec![1, 0],
+        }),
+        None => bail!("device has no command 0xe7"),
+    }
+
+    Ok(packets)
+}
+
+///
+/// Ingests a command/value export, in which each line names a command (by
+/// its name or its code) followed by the value to write, separated by
+/// whitespace, commas or `=`; lines that are empty or that begin with `#`,
+/// `;` or `//` are ignored.  Commands are resolved by `lookup`, which
+/// returns the code, name and width of a command.  Fixed-width values are
+/// written little-endian; block values are written in the order given.
+///
+pub fn ingest(
+    reader: impl BufRead,
+    lookup: impl Fn(&str) -> Option<(u8, String, Width)>,
+) -> Result<Vec<Packet>> {
+    let mut packets = vec![];
+
+    for (ndx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let lineno = ndx + 1;
+        let line = line.trim();
+
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with(';')
+            || line.starts_with("//")
+        {
+            continue;
+        }
+
+        let contents = line
+            .split(|c: char| c.is_whitespace() || c == ',' || c == '=')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+
+        if contents.len() > 2 {
+            bail!("malformed line {}", lineno);
+        }
+
+        let (code, name, width) = match lookup(contents[0]) {
+            Some(command) => command,
+            None => bail!(
+                "unknown or read-only command on line {}: {}",
+                lineno,
+                contents[0]
+            ),
+        };
+
+        let payload = match (width, contents.get(1)) {
+            (Width::None, None) => vec![],
+            (Width::None, Some(_)) => {
+                bail!("unexpected value for {} on line {}", name, lineno)
+            }
+            (_, None) => bail!("missing value for {} on line {}", name, lineno),
+            (Width::Fixed(len), Some(val)) => hex(lineno, val, len)?,
+            (Width::Block, Some(val)) => {
+                let digits = match val.strip_prefix("0x") {
+                    Some(digits) if digits.len() % 2 == 0 => digits,
+                    _ => bail!("bad block on line {}: {}", lineno, val),
+                };
+
+                let mut block = vec![(digits.len() / 2) as u8];
+
+                for i in (0..digits.len()).step_by(2) {
+                    match u8::from_str_radix(&digits[i..i + 2], 16) {
+                        Ok(byte) => block.push(byte),
+                        Err(_) => {
+                            bail!("bad block on line {}: {}", lineno, val)
+                        }
+                    }
+                }
+
+                block
+            }
+        };
+
+        packets.push(Packet { address: Address::Pmbus(code, name), payload });
+    }
+
+    Ok(packets)
+}
+
+///
+/// Generates a Rust function named `{name}_payload` that iterates over the
+/// raw writes of a configuration, with `doc` as its doc comment.  DMA writes
+/// require the codes of the DMA commands to be specified.
+///
+pub fn generate(
+    out: &mut impl Write,
+    name: &str,
+    doc: &[String],
+    packets: &[Packet],
+    dma: Option<Dma>,
+) -> Result<()> {
+    writeln!(
+        out,
+        r##"// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+///"##
+    )?;
+
+    for line in doc {
+        writeln!(out, "/// {}", line)?;
+    }
+
+    writeln!(
+        out,
+        r##"///
+#[rustfmt::skip]
+pub fn {}_payload<E>(
+    mut func: impl FnMut(&[u8]) -> Result<(), E>
+) -> Result<(), E> {{
+
+    const PAYLOAD: &[&[u8]] = &["##,
+        name,
+    )?;
+
+    for packet in packets {
+        match &packet.address {
+            Address::Dma(addr) => {
+                let dma = match dma {
+                    Some(dma) => dma,
+                    None => bail!("DMA write to 0x{:04x} without DMA", addr),
+                };
+
+                let p = addr.to_le_bytes();
+
+                writeln!(out, "        // DMAADDR = 0x{:04x}", addr)?;
+                writeln!(
+                    out,
+                    "        &[ 0x{:02x}, 0x{:02x}, 0x{:02x} ],\n",
+                    dma.addr, p[0], p[1]
+                )?;
+
+                writeln!(out, "        // DMAFIX = {:x?}", packet.payload)?;
+                write!(out, "        &[ 0x{:02x}, ", dma.fix)?;
+            }
+
+            Address::Pmbus(code, name) => {
+                writeln!(out, "        // {} = {:x?}", name, packet.payload)?;
+                write!(out, "        &[ 0x{:02x}, ", code)?;
+            }
+        }
+
+        for byte in &packet.payload {
+            write!(out, "0x{:02x}, ", byte)?;
+        }
+
+        writeln!(out, "],\n")?;
+    }
+
+    writeln!(
+        out,
+        r##"    ];
+
+    for chunk in PAYLOAD {{
+        func(chunk)?;
+    }}
+
+    Ok(())
+}}"##
+    )?;
+
+    Ok(())
+}
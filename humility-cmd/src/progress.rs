@@ -0,0 +1,272 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Progress reporting for long-running operations.
+//!
+//! A command reports the progress of an operation by creating a
+//! [`Progress`] (via [`Progress::new`] for a count of items, or
+//! [`Progress::bytes`] for a count of bytes) and updating its position;
+//! operations that consist of several phases can create a child for each
+//! phase with [`Progress::child`].  How progress is rendered is determined
+//! by the global `--progress` option (and `--quiet`):
+//!
+//! - As a progress bar (the default), for operations with a known total.
+//!   If standard error is not a terminal, progress is instead reported as a
+//!   line of plain text at each tenth of the operation, without any terminal
+//!   control codes.
+//!
+//! - As JSON, with each event emitted as an object on its own line on
+//!   standard error.  Every event has an `event` (one of `start`, `progress`
+//!   or `finish`) and an `operation`, which is the path of the operation
+//!   (e.g., `flash/erase` for a child of `flash`); `start` and `progress`
+//!   events have a `position` and a `total` (if known), and `finish` events
+//!   have the position and the elapsed time in milliseconds.
+//!
+//! - Not at all, with `--progress=none` or `--quiet`.
+//!
+
+use crate::Args;
+use clap::ArgEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::time::{Duration, Instant};
+
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProgressFormat {
+    Bar,
+    Json,
+    None,
+}
+
+impl Default for ProgressFormat {
+    fn default() -> Self {
+        ProgressFormat::Bar
+    }
+}
+
+//
+// How progress is actually rendered, having taken into account whether
+// standard error is a terminal.
+//
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Mode {
+    Bar,
+    Plain,
+    Json,
+    None,
+}
+
+//
+// The minimum interval between JSON progress events.
+//
+const JSON_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Progress {
+    mode: Mode,
+    operation: String,
+    name: String,
+    total: Option<u64>,
+    bytes: bool,
+    position: u64,
+    started: Instant,
+    reported: Instant,
+    tenths: u64,
+    bar: Option<ProgressBar>,
+    finished: bool,
+}
+
+impl Progress {
+    fn create(
+        mode: Mode,
+        operation: String,
+        name: &str,
+        total: Option<u64>,
+        bytes: bool,
+    ) -> Self {
+        let now = Instant::now();
+
+        let bar = match (mode, total) {
+            (Mode::Bar, Some(total)) => {
+                let bar = ProgressBar::new(total);
+
+                bar.set_style(ProgressStyle::default_bar().template(&format!(
+                    "humility: {} [{{bar:30}}] {}",
+                    name,
+                    if bytes { "{bytes}/{total_bytes}" } else { "{pos}/{len}" }
+                )));
+
+                Some(bar)
+            }
+            _ => None,
+        };
+
+        let progress = Self {
+            mode,
+            operation,
+            name: name.to_string(),
+            total,
+            bytes,
+            position: 0,
+            started: now,
+            reported: now,
+            tenths: 0,
+            bar,
+            finished: false,
+        };
+
+        match mode {
+            Mode::Json => progress.event("start", None),
+            Mode::Plain => humility::msg!("{}", name),
+            Mode::Bar if progress.bar.is_none() => humility::msg!("{}", name),
+            _ => {}
+        }
+
+        progress
+    }
+
+    fn start(args: &Args, name: &str, total: Option<u64>, bytes: bool) -> Self {
+        let mode = match (args.quiet, args.progress) {
+            (true, _) | (_, ProgressFormat::None) => Mode::None,
+            (_, ProgressFormat::Json) => Mode::Json,
+            (_, ProgressFormat::Bar) if atty::is(atty::Stream::Stderr) => {
+                Mode::Bar
+            }
+            (_, ProgressFormat::Bar) => Mode::Plain,
+        };
+
+        Self::create(mode, name.to_string(), name, total, bytes)
+    }
+
+    /// Starts an operation that consists of `total` items (if known).
+    pub fn new(args: &Args, name: &str, total: Option<u64>) -> Self {
+        Self::start(args, name, total, false)
+    }
+
+    /// Starts an operation that consists of `total` bytes.
+    pub fn bytes(args: &Args, name: &str, total: u64) -> Self {
+        Self::start(args, name, Some(total), true)
+    }
+
+    ///
+    /// Starts a phase of this operation, which consists of `total` items
+    /// (or bytes, if `bytes` is set).  While a child with a progress bar is
+    /// running, this operation's progress bar is not updated.
+    ///
+    pub fn child(&self, name: &str, total: Option<u64>, bytes: bool) -> Self {
+        let operation = format!("{}/{}", self.operation, name);
+        Self::create(self.mode, operation, name, total, bytes)
+    }
+
+    fn event(&self, event: &str, elapsed: Option<Duration>) {
+        let mut obj = serde_json::Map::new();
+
+        obj.insert("event".into(), event.into());
+        obj.insert("operation".into(), self.operation.as_str().into());
+        obj.insert("position".into(), self.position.into());
+
+        if let Some(total) = self.total {
+            obj.insert("total".into(), total.into());
+        }
+
+        if self.bytes {
+            obj.insert("units".into(), "bytes".into());
+        }
+
+        if let Some(elapsed) = elapsed {
+            obj.insert(
+                "elapsed_ms".into(),
+                (elapsed.as_millis() as u64).into(),
+            );
+        }
+
+        eprintln!("{}", serde_json::Value::Object(obj));
+    }
+
+    /// Sets the position of the operation.
+    pub fn set_position(&mut self, position: u64) {
+        self.position = position;
+
+        match self.mode {
+            Mode::Bar => {
+                if let Some(bar) = &self.bar {
+                    bar.set_position(position);
+                }
+            }
+            Mode::Json => {
+                if self.reported.elapsed() >= JSON_INTERVAL {
+                    self.event("progress", None);
+                    self.reported = Instant::now();
+                }
+            }
+            Mode::Plain => {
+                if let Some(total) = self.total.filter(|&t| t != 0) {
+                    let tenths = (position.min(total) * 10) / total;
+
+                    if tenths > self.tenths && tenths < 10 {
+                        humility::msg!(
+                            "{}: {}% ({}/{})",
+                            self.name,
+                            tenths * 10,
+                            position,
+                            total
+                        );
+                    }
+
+                    self.tenths = tenths;
+                }
+            }
+            Mode::None => {}
+        }
+    }
+
+    /// Advances the position of the operation.
+    pub fn inc(&mut self, delta: u64) {
+        self.set_position(self.position + delta);
+    }
+
+    /// Finishes the operation, clearing its progress bar.
+    pub fn finish(mut self) {
+        self.done();
+    }
+
+    fn done(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        self.finished = true;
+
+        match self.mode {
+            Mode::Bar => {
+                if let Some(bar) = &self.bar {
+                    bar.finish_and_clear();
+                }
+            }
+            Mode::Json => self.event("finish", Some(self.started.elapsed())),
+            Mode::Plain => {
+                humility::msg!(
+                    "{}: done in {:.1}s",
+                    self.name,
+                    self.started.elapsed().as_secs_f64()
+                );
+            }
+            Mode::None => {}
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        //
+        // If an operation is dropped without being finished (e.g., because
+        // of an error), we clear its progress bar -- but we don't report it
+        // as finished.
+        //
+        if !self.finished {
+            if let Some(bar) = &self.bar {
+                bar.finish_and_clear();
+            }
+        }
+    }
+}
@@ -0,0 +1,182 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Handling of transient errors in polling loops.  Commands that sample the
+//! target repeatedly (e.g., `sensors`, `fans` and `thermal`) take a policy
+//! via `--on-error` that determines what happens when a sample fails:
+//!
+//! - `fail`:  the command fails
+//!
+//! - `retry[:N]`:  the sample is retried immediately, up to `N` times
+//!   (defaulting to 3), after which the command fails
+//!
+//! - `backoff[:N]`:  the sample is retried up to `N` times (defaulting to
+//!   5), waiting 100 milliseconds before the first retry and doubling the
+//!   wait before each subsequent one (to a maximum of 5 seconds), after which
+//!   the command fails
+//!
+//! - `continue`:  the sample is marked as missed, and sampling continues
+//!
+//! The default policy is `retry:1`:  a single failure is retried, and the
+//! command fails only if the retry fails as well.
+//!
+
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+/// Wait before the first retry when backing off
+const BACKOFF_INITIAL: Duration = Duration::from_millis(100);
+
+/// Maximum wait between retries when backing off
+const BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RetryPolicy {
+    /// Fail on the first error
+    Fail,
+    /// Retry immediately, up to the specified number of times
+    Retry(u32),
+    /// Retry with exponential backoff, up to the specified number of times
+    Backoff(u32),
+    /// Mark the sample as missed and continue
+    Continue,
+}
+
+impl FromStr for RetryPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (policy, count) = match s.split_once(':') {
+            Some((policy, count)) => {
+                let count = parse_int::parse::<u32>(count).map_err(|_| {
+                    anyhow!("policy \"{}\" has invalid count \"{}\"", s, count)
+                })?;
+
+                (policy, Some(count))
+            }
+            None => (s, None),
+        };
+
+        match (policy, count) {
+            ("fail", None) => Ok(RetryPolicy::Fail),
+            ("continue", None) => Ok(RetryPolicy::Continue),
+            ("retry", count) => Ok(RetryPolicy::Retry(count.unwrap_or(3))),
+            ("backoff", count) => Ok(RetryPolicy::Backoff(count.unwrap_or(5))),
+            ("fail" | "continue", Some(_)) => {
+                bail!("policy \"{}\" does not take a count", policy)
+            }
+            _ => bail!(
+                "unknown policy \"{}\" (expected fail, retry[:N], \
+                backoff[:N] or continue)",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RetryPolicy::Fail => write!(f, "fail"),
+            RetryPolicy::Retry(n) => write!(f, "retry:{}", n),
+            RetryPolicy::Backoff(n) => write!(f, "backoff:{}", n),
+            RetryPolicy::Continue => write!(f, "continue"),
+        }
+    }
+}
+
+impl RetryPolicy {
+    ///
+    /// Takes a sample by calling the specified function, retrying it as the
+    /// policy dictates.  An outer error indicates that the command should
+    /// fail; an inner error indicates that the sample should be marked as
+    /// missed (which happens only with [`RetryPolicy::Continue`]).
+    ///
+    pub fn sample<T, F>(&self, what: &str, mut f: F) -> Result<Result<T>>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let retries = match self {
+            RetryPolicy::Retry(n) | RetryPolicy::Backoff(n) => *n,
+            RetryPolicy::Fail | RetryPolicy::Continue => 0,
+        };
+
+        let mut wait = BACKOFF_INITIAL;
+        let mut attempt = 0;
+
+        loop {
+            let err = match f() {
+                Ok(val) => return Ok(Ok(val)),
+                Err(err) => err,
+            };
+
+            if *self == RetryPolicy::Continue {
+                return Ok(Err(err));
+            }
+
+            if attempt == retries {
+                if retries == 0 {
+                    return Err(err);
+                }
+
+                return Err(err.context(format!(
+                    "{} failed after {} attempts",
+                    what,
+                    attempt + 1
+                )));
+            }
+
+            attempt += 1;
+            humility::msg!("{} failed ({}); retrying", what, err);
+
+            if let RetryPolicy::Backoff(_) = self {
+                thread::sleep(wait);
+                wait = (wait * 2).min(BACKOFF_MAX);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy() {
+        assert_eq!("fail".parse::<RetryPolicy>().unwrap(), RetryPolicy::Fail);
+        assert_eq!(
+            "retry".parse::<RetryPolicy>().unwrap(),
+            RetryPolicy::Retry(3)
+        );
+        assert_eq!(
+            "backoff:0x10".parse::<RetryPolicy>().unwrap(),
+            RetryPolicy::Backoff(16)
+        );
+        assert!("continue:2".parse::<RetryPolicy>().is_err());
+        assert!("retry:many".parse::<RetryPolicy>().is_err());
+        assert!("ignore".parse::<RetryPolicy>().is_err());
+
+        let mut failures = 2;
+        let mut flaky = || {
+            if failures > 0 {
+                failures -= 1;
+                bail!("transient failure");
+            }
+
+            Ok(())
+        };
+
+        assert!(RetryPolicy::Retry(1).sample("read", &mut flaky).is_err());
+        assert!(RetryPolicy::Retry(1).sample("read", &mut flaky).is_ok());
+
+        let outcome = RetryPolicy::Continue
+            .sample("read", || -> Result<()> { bail!("transient failure") });
+
+        assert!(matches!(outcome, Ok(Err(_))));
+    }
+}
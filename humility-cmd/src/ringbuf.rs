@@ -0,0 +1,143 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Discovery and decoding of ring buffers created via the `ringbuf!` macro
+//! in the Hubris `ringbuf` crate.
+//!
+//! Ring buffers are found by the shape of their type rather than by their
+//! name:  any variable that is a `Ringbuf` (or a `StaticCell` containing a
+//! `Ringbuf`) is considered to be a ring buffer, regardless of what it is
+//! called.  As a fallback, we also consider any variable ending in `RINGBUF`
+//! to be a ring buffer, even if we don't recognize its type.
+
+use crate::doppel::{Ringbuf, RingbufEntry, StaticCell};
+use crate::reflect::{self, Load, Value};
+use anyhow::Result;
+use humility::core::Core;
+use humility::hubris::*;
+
+/// A ring buffer variable, as found in the archive.
+#[derive(Copy, Clone, Debug)]
+pub struct RingbufVariable<'a> {
+    pub name: &'a str,
+    pub task: &'a str,
+    pub variable: &'a HubrisVariable,
+}
+
+impl<'a> RingbufVariable<'a> {
+    ///
+    /// Reads and decodes the ring buffer.  Note that this does not halt the
+    /// target; if a consistent view of a live target is desired, the caller
+    /// should halt it first.
+    ///
+    pub fn read(
+        &self,
+        hubris: &HubrisArchive,
+        core: &mut dyn Core,
+    ) -> Result<Ringbuf> {
+        let definition = hubris.lookup_struct(self.variable.goff)?;
+        let mut buf: Vec<u8> = vec![0; self.variable.size];
+
+        core.read_8(self.variable.addr, buf.as_mut_slice())?;
+
+        // There are two possible shapes of ringbufs, depending on the age of
+        // the firmware.
+        // - Raw Ringbuf that is not wrapped by anything.
+        // - Safe Ringbuf that is inside a StaticCell.
+        //
+        // Here we will attempt to handle them both -- first raw, then
+        // fallback.
+        let ringbuf_val: Value =
+            Value::Struct(reflect::load_struct(hubris, &buf, definition, 0)?);
+
+        Ringbuf::from_value(&ringbuf_val).or_else(|_e| {
+            let cell: StaticCell = StaticCell::from_value(&ringbuf_val)?;
+            Ringbuf::from_value(&cell.cell.value)
+        })
+    }
+}
+
+///
+/// Returns true if the specified struct has the shape of a ring buffer entry.
+///
+fn is_entry(s: &HubrisStruct) -> bool {
+    ["line", "generation", "count", "payload"]
+        .iter()
+        .all(|m| s.lookup_member(m).is_ok())
+}
+
+///
+/// Returns true if the specified type is a `Ringbuf` -- or a wrapper (like
+/// `StaticCell` or `UnsafeCell`) that contains one.
+///
+pub fn is_ringbuf(hubris: &HubrisArchive, goff: HubrisGoff) -> bool {
+    let mut goff = goff;
+
+    //
+    // We allow for a small number of wrapping layers; anything deeper than
+    // this is almost certainly not something that we want to be decoding.
+    //
+    for _ in 0..4 {
+        let s = match hubris.lookup_struct(goff) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+
+        if let (Ok(_), Ok(buffer)) =
+            (s.lookup_member("last"), s.lookup_member("buffer"))
+        {
+            return hubris
+                .lookup_array(buffer.goff)
+                .and_then(|array| hubris.lookup_struct(array.goff))
+                .map(is_entry)
+                .unwrap_or(false);
+        }
+
+        match s.members.as_slice() {
+            [m] if m.name == "cell" || m.name == "value" => goff = m.goff,
+            _ => return false,
+        }
+    }
+
+    false
+}
+
+///
+/// Finds all ring buffers in the archive, sorted by name.
+///
+pub fn ringbufs(hubris: &HubrisArchive) -> Result<Vec<RingbufVariable>> {
+    let mut rval = vec![];
+
+    for (name, variable) in hubris.qualified_variables() {
+        if !name.ends_with("RINGBUF") && !is_ringbuf(hubris, variable.goff) {
+            continue;
+        }
+
+        let task = &hubris.lookup_module(HubrisTask::from(variable.goff))?.name;
+        rval.push(RingbufVariable { name, task, variable });
+    }
+
+    rval.sort_by_key(|r| (r.name, r.variable.addr));
+
+    Ok(rval)
+}
+
+///
+/// Returns the valid entries in the ring buffer along with their slot index,
+/// oldest first.
+///
+pub fn entries(ringbuf: &Ringbuf) -> Vec<(usize, &RingbufEntry)> {
+    let ndx = match ringbuf.last {
+        Some(ndx) => ndx as usize,
+        None => return vec![],
+    };
+
+    let len = ringbuf.buffer.len();
+
+    (0..len)
+        .map(|i| (ndx + i + 1) % len)
+        .map(|slot| (slot, &ringbuf.buffer[slot]))
+        .filter(|(_, entry)| entry.generation != 0)
+        .collect()
+}
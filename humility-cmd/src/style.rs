@@ -0,0 +1,83 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Styling of terminal output by severity.
+//!
+//! Commands highlight output (e.g., errors, warnings, faulted tasks and
+//! changed values) by its [`Severity`] -- either directly, or by styling a
+//! table cell with [`crate::output::Cell::styled`], in which case the cell
+//! is styled only in a table (and not in JSON or CSV).  Whether color is
+//! used at all is determined by the global `--color` option:  with `auto`
+//! (the default), color is used only if standard output is a terminal and
+//! `NO_COLOR` is not set (see <https://no-color.org>).  Because this is
+//! enforced by overriding the `colored` crate, commands that use `colored`
+//! directly are subject to the same choice.
+//!
+
+use clap::ArgEnum;
+use colored::Colorize;
+
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// Nominal (e.g., a device that validated)
+    Ok,
+    /// Worthy of note, but not a problem
+    Info,
+    /// Possibly a problem (e.g., a value that could not be read)
+    Warning,
+    /// A problem (e.g., a faulted task)
+    Error,
+    /// A value that has changed from a previous value
+    Changed,
+}
+
+///
+/// Determines whether output is to be colored; this should be called once,
+/// before any output is emitted.
+///
+pub fn init(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::env::var_os("NO_COLOR").map_or(true, |v| v.is_empty())
+                && atty::is(atty::Stream::Stdout)
+        }
+    };
+
+    colored::control::set_override(enabled);
+}
+
+///
+/// Returns true if output is to be colored.
+///
+pub fn enabled() -> bool {
+    colored::control::SHOULD_COLORIZE.should_colorize()
+}
+
+impl Severity {
+    ///
+    /// Styles the specified text according to severity.  Note that the
+    /// result may contain escape sequences, so any padding must be applied
+    /// to the text before it is styled.
+    ///
+    pub fn paint(&self, text: &str) -> String {
+        match self {
+            Severity::Ok => text.green(),
+            Severity::Info => text.cyan(),
+            Severity::Warning => text.yellow(),
+            Severity::Error => text.red().bold(),
+            Severity::Changed => text.magenta(),
+        }
+        .to_string()
+    }
+}
@@ -0,0 +1,550 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A framed transfer channel over HIF, for moving large amounts of data to
+//! the target.
+//!
+//! Data is divided into frames, each of which is sized to fill as much of
+//! the HIF data as it can.  If the target's hiffy task provides an `Unpack`
+//! function, each frame is prefixed with a header that carries a sequence
+//! number, the length of the frame on the wire and once unpacked, and a
+//! CRC-32 of its unpacked contents; its payload is compressed (LZSS) when
+//! compression makes it smaller.  A frame is staged by writing it to the
+//! HIF data and calling `Unpack`, which validates the frame and leaves its
+//! unpacked contents at the start of the HIF data to be consumed by a
+//! subsequent HIF program; a frame that fails validation is resent.  If the
+//! target lacks `Unpack`, frames are instead sent as raw data along with the
+//! program that consumes them.
+//!
+//! The header is 16 bytes, with all fields little-endian:
+//!
+//! | offset | size | field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 2    | magic (`FRAME_MAGIC`)                         |
+//! | 2      | 2    | sequence number                               |
+//! | 4      | 1    | flags (`FRAME_COMPRESSED`)                    |
+//! | 5      | 3    | reserved                                      |
+//! | 8      | 2    | length of the payload that follows the header |
+//! | 10     | 2    | length of the payload once unpacked           |
+//! | 12     | 4    | CRC-32 (ISO HDLC) of the unpacked payload     |
+//!
+//! A compressed payload consists of groups of up to eight items, each group
+//! preceded by a flag byte in which bit *n* (from the LSB) is set if item
+//! *n* is a back-reference rather than a literal byte.  A back-reference is
+//! two bytes:  the low 8 bits of the distance less one, followed by the high
+//! 4 bits of the distance less one (in the high nibble) and the length less
+//! three (in the low nibble).
+
+use crate::hiffy::{HiffyContext, HiffyFunction, HiffyFunctions};
+use anyhow::{bail, Result};
+use hif::*;
+use humility::core::Core;
+use std::collections::HashMap;
+
+/// Magic number at the start of each frame header
+pub const FRAME_MAGIC: u16 = 0x4846;
+
+/// Flag indicating that a frame's payload is compressed
+pub const FRAME_COMPRESSED: u8 = 0x1;
+
+/// Size of a frame header
+pub const HEADER_SIZE: usize = 16;
+
+/// Maximum distance of a back-reference
+const WINDOW: usize = 4096;
+
+/// Minimum and maximum lengths of a back-reference
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 18;
+
+/// Number of candidate back-references considered at each position
+const CANDIDATES: usize = 32;
+
+/// Number of times a frame that fails validation is resent
+const RETRIES: usize = 3;
+
+const CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+///
+/// Compresses the specified data.
+///
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut chains: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    let mut pos = 0;
+    let mut flags = 0;
+    let mut nitems = 0;
+
+    while pos < data.len() {
+        if nitems % 8 == 0 {
+            flags = out.len();
+            out.push(0);
+        }
+
+        let mut best = (0, 0);
+
+        if pos + MIN_MATCH <= data.len() {
+            if let Some(chain) = chains.get(&data[pos..pos + MIN_MATCH]) {
+                let max = MAX_MATCH.min(data.len() - pos);
+
+                for &cand in chain.iter().rev().take(CANDIDATES) {
+                    if pos - cand > WINDOW {
+                        break;
+                    }
+
+                    let len = (0..max)
+                        .take_while(|&i| data[cand + i] == data[pos + i])
+                        .count();
+
+                    if len > best.1 {
+                        best = (pos - cand, len);
+
+                        if len == max {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let advance = if best.1 >= MIN_MATCH {
+            let (dist, len) = (best.0 - 1, best.1 - MIN_MATCH);
+            out[flags] |= 1 << (nitems % 8);
+            out.push((dist & 0xff) as u8);
+            out.push((((dist >> 8) << 4) | len) as u8);
+            best.1
+        } else {
+            out.push(data[pos]);
+            1
+        };
+
+        for p in pos..pos + advance {
+            if p + MIN_MATCH <= data.len() {
+                chains.entry(&data[p..p + MIN_MATCH]).or_default().push(p);
+            }
+        }
+
+        pos += advance;
+        nitems += 1;
+    }
+
+    out
+}
+
+///
+/// Decompresses the specified data, as compressed by [`compress`].
+///
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out: Vec<u8> = vec![];
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let flags = data[pos];
+        pos += 1;
+
+        for bit in 0..8 {
+            if pos >= data.len() {
+                break;
+            }
+
+            if flags & (1 << bit) == 0 {
+                out.push(data[pos]);
+                pos += 1;
+                continue;
+            }
+
+            if pos + 1 >= data.len() {
+                bail!("truncated back-reference at offset {}", pos);
+            }
+
+            let dist =
+                (data[pos] as usize | (data[pos + 1] as usize >> 4) << 8) + 1;
+            let len = (data[pos + 1] & 0xf) as usize + MIN_MATCH;
+
+            if dist > out.len() {
+                bail!("back-reference at offset {} is out of range", pos);
+            }
+
+            for _ in 0..len {
+                out.push(out[out.len() - dist]);
+            }
+
+            pos += 2;
+        }
+    }
+
+    Ok(out)
+}
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    /// Sequence number of the frame
+    pub seq: u16,
+    /// Offset of the frame's contents within the data being transferred
+    pub offset: usize,
+    /// Length of the frame's contents
+    pub len: usize,
+    /// The frame as sent to the target
+    wire: Vec<u8>,
+}
+
+impl Frame {
+    fn header(seq: u16, flags: u8, len: usize, contents: &[u8]) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(&FRAME_MAGIC.to_le_bytes());
+        header.extend_from_slice(&seq.to_le_bytes());
+        header.extend_from_slice(&[flags, 0, 0, 0]);
+        header.extend_from_slice(&(len as u16).to_le_bytes());
+        header.extend_from_slice(&(contents.len() as u16).to_le_bytes());
+        header.extend_from_slice(&CRC.checksum(contents).to_le_bytes());
+        header
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TransferStats {
+    /// Number of bytes transferred
+    pub bytes: usize,
+    /// Number of bytes sent to the target, including frame headers
+    pub sent: usize,
+    /// Number of frames sent
+    pub frames: usize,
+    /// Number of frames that were resent
+    pub retries: usize,
+}
+
+#[derive(Debug)]
+pub struct Transfer {
+    unpack: Option<HiffyFunction>,
+    compress: bool,
+    stats: TransferStats,
+}
+
+impl Transfer {
+    pub fn new(funcs: &HiffyFunctions) -> Self {
+        Self {
+            unpack: funcs.get("Unpack", 0).ok().cloned(),
+            compress: true,
+            stats: TransferStats::default(),
+        }
+    }
+
+    ///
+    /// Returns true if the target can unpack frames, in which case frames
+    /// are staged by [`Transfer::stage`] in advance of the program that
+    /// consumes them.
+    ///
+    pub fn framed(&self) -> bool {
+        self.unpack.is_some()
+    }
+
+    ///
+    /// Disables compression, even if the target can decompress frames.
+    ///
+    pub fn uncompressed(mut self) -> Self {
+        self.compress = false;
+        self
+    }
+
+    pub fn stats(&self) -> TransferStats {
+        self.stats
+    }
+
+    ///
+    /// Divides the specified data into frames no larger than the HIF data,
+    /// each of which has contents of at most `max` bytes; the contents of
+    /// every frame but the last is a multiple of `align` bytes.
+    ///
+    pub fn frames(
+        &self,
+        context: &HiffyContext,
+        data: &[u8],
+        max: usize,
+        align: usize,
+    ) -> Result<Vec<Frame>> {
+        let size = context.data_size();
+        let round = |len: usize| len - (len % align);
+
+        if !self.framed() {
+            let chunk = round(max.min(size));
+
+            if chunk == 0 {
+                bail!("HIF data ({} bytes) is too small", size);
+            }
+
+            return Ok(data
+                .chunks(chunk)
+                .enumerate()
+                .map(|(ndx, contents)| Frame {
+                    seq: ndx as u16,
+                    offset: ndx * chunk,
+                    len: contents.len(),
+                    wire: contents.to_vec(),
+                })
+                .collect());
+        }
+
+        let limit = round(max.min(size).min(u16::MAX as usize));
+        let raw = round(limit.min(size - HEADER_SIZE.min(size)));
+
+        if raw == 0 {
+            bail!("HIF data ({} bytes) is too small for framing", size);
+        }
+
+        let mut frames = vec![];
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let seq = frames.len() as u16;
+            let end = (offset + limit).min(data.len());
+            let contents = &data[offset..end];
+
+            let compressed = if self.compress {
+                let payload = compress(contents);
+
+                if decompress(&payload)? != contents {
+                    bail!("compression of frame {} failed to round-trip", seq);
+                }
+
+                Some(payload)
+            } else {
+                None
+            };
+
+            let (payload, flags, contents) = match compressed {
+                Some(payload)
+                    if payload.len() + HEADER_SIZE <= size
+                        && payload.len() < contents.len() =>
+                {
+                    (payload, FRAME_COMPRESSED, contents)
+                }
+                _ => {
+                    let contents = &data[offset..(offset + raw).min(end)];
+                    (contents.to_vec(), 0, contents)
+                }
+            };
+
+            let mut wire = Frame::header(seq, flags, payload.len(), contents);
+            wire.extend_from_slice(&payload);
+
+            frames.push(Frame { seq, offset, len: contents.len(), wire });
+            offset += contents.len();
+        }
+
+        Ok(frames)
+    }
+
+    ///
+    /// Stages a frame.  If the target can unpack frames, the frame is
+    /// written and unpacked, and `None` is returned:  the frame's contents
+    /// are at the start of the HIF data, and the consuming program should be
+    /// run without data.  Otherwise, the frame's contents are returned, to be
+    /// passed as data to the consuming program.
+    ///
+    pub fn stage<'f>(
+        &mut self,
+        context: &mut HiffyContext,
+        core: &mut dyn Core,
+        frame: &'f Frame,
+    ) -> Result<Option<&'f [u8]>> {
+        self.stats.bytes += frame.len;
+        self.stats.sent += frame.wire.len();
+        self.stats.frames += 1;
+
+        let unpack = match &self.unpack {
+            Some(unpack) => unpack,
+            None => return Ok(Some(frame.wire.as_slice())),
+        };
+
+        let ops = [Op::Call(unpack.id), Op::Done];
+        let mut attempt = 0;
+
+        loop {
+            let results =
+                context.run(core, &ops, Some(frame.wire.as_slice()))?;
+
+            let err = match results.get(0) {
+                Some(Ok(val)) if val.len() == 4 => {
+                    let len = u32::from_le_bytes(val[..].try_into().unwrap());
+
+                    if len as usize == frame.len {
+                        return Ok(None);
+                    }
+
+                    format!("unpacked {} bytes, expected {}", len, frame.len)
+                }
+                Some(Ok(val)) => format!("bad reply {:x?}", val),
+                Some(Err(code)) => unpack.strerror(*code),
+                None => "no result".to_string(),
+            };
+
+            attempt += 1;
+
+            if attempt > RETRIES {
+                bail!(
+                    "frame {} (offset {}) failed after {} attempts: {}",
+                    frame.seq,
+                    frame.offset,
+                    attempt,
+                    err
+                );
+            }
+
+            log::warn!("frame {} failed ({}); resending", frame.seq, err);
+            self.stats.retries += 1;
+            self.stats.sent += frame.wire.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Item {
+        Literal(u8),
+        Ref(usize, usize),
+    }
+
+    ///
+    /// Breaks compressed data into its literals and back-references.
+    ///
+    fn items(data: &[u8]) -> Vec<Item> {
+        let mut items = vec![];
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let flags = data[pos];
+            pos += 1;
+
+            for bit in 0..8 {
+                if pos >= data.len() {
+                    break;
+                }
+
+                if flags & (1 << bit) == 0 {
+                    items.push(Item::Literal(data[pos]));
+                    pos += 1;
+                } else {
+                    let (lo, hi) = (data[pos] as usize, data[pos + 1] as usize);
+                    let dist = (lo | (hi >> 4) << 8) + 1;
+                    items.push(Item::Ref(dist, (hi & 0xf) + MIN_MATCH));
+                    pos += 2;
+                }
+            }
+        }
+
+        items
+    }
+
+    fn roundtrip(data: &[u8]) -> Vec<Item> {
+        let compressed = compress(data);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+
+        let items = items(&compressed);
+
+        for item in &items {
+            if let Item::Ref(dist, len) = item {
+                assert!(*dist <= WINDOW && *len <= MAX_MATCH, "{:?}", item);
+            }
+        }
+
+        items
+    }
+
+    ///
+    /// Pseudo-random bytes with the high bit set, and so distinct from any
+    /// ASCII.
+    ///
+    fn filler(len: usize) -> Vec<u8> {
+        let mut x = 1u32;
+
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                0x80 | (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        assert!(compress(&[]).is_empty());
+        assert_eq!(roundtrip(b"a"), [Item::Literal(b'a')]);
+
+        let mut data = filler(10000);
+        data.extend_from_slice(&data[1000..3000].to_vec());
+        data.extend_from_slice(&[0; 500]);
+        roundtrip(&data);
+    }
+
+    #[test]
+    fn test_window() {
+        let pattern = b"ABCDEFGHIJKLMNOPQR";
+
+        //
+        // A repeat at exactly the maximum distance is a back-reference...
+        //
+        let mut data = pattern.to_vec();
+        data.extend(filler(WINDOW - pattern.len()));
+        data.extend_from_slice(pattern);
+
+        let items = roundtrip(&data);
+        assert_eq!(items.last(), Some(&Item::Ref(WINDOW, pattern.len())));
+
+        //
+        // ...but one byte further is not.
+        //
+        let mut data = pattern.to_vec();
+        data.extend(filler(WINDOW - pattern.len() + 1));
+        data.extend_from_slice(pattern);
+
+        let items = roundtrip(&data);
+        let tail = &items[items.len() - pattern.len()..];
+        assert!(tail.iter().zip(pattern).all(|(i, b)| *i == Item::Literal(*b)));
+    }
+
+    #[test]
+    fn test_max_match() {
+        let items = roundtrip(&[b'a'; 100]);
+
+        assert_eq!(items[0], Item::Literal(b'a'));
+        assert_eq!(items[1], Item::Ref(1, MAX_MATCH));
+        assert_eq!(items.len(), 1 + (99 + MAX_MATCH - 1) / MAX_MATCH);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let compressed = compress(b"abcabcabc");
+        assert_eq!(items(&compressed).last(), Some(&Item::Ref(3, 6)));
+
+        let truncated = &compressed[..compressed.len() - 1];
+        assert!(decompress(truncated).is_err());
+
+        //
+        // A back-reference to before the start of the data is an error.
+        //
+        assert!(decompress(&[0x1, 0x0, 0x0]).is_err());
+        assert!(decompress(&[0x2, b'a', 0x1, 0x0]).is_err());
+    }
+
+    #[test]
+    fn test_header() {
+        let header = Frame::header(0x1234, FRAME_COMPRESSED, 5, b"123456789");
+
+        assert_eq!(header.len(), HEADER_SIZE);
+        assert_eq!(
+            header,
+            [
+                0x46, 0x48, // magic
+                0x34, 0x12, // sequence number
+                0x01, 0x00, 0x00, 0x00, // flags and reserved
+                0x05, 0x00, // length on the wire
+                0x09, 0x00, // length unpacked
+                0x26, 0x39, 0xf4, 0xcb, // CRC-32 of "123456789"
+            ]
+        );
+    }
+}
@@ -13,10 +13,9 @@ use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt;
-use std::fs;
 use std::io::Read;
 use std::io::Write;
-use std::net::TcpStream;
+use std::net::{TcpStream, ToSocketAddrs};
 use std::str;
 use std::time::Duration;
 use std::time::Instant;
@@ -267,6 +266,41 @@ impl Core for ProbeCore {
     }
 }
 
+///
+/// The host on which debug servers (OpenOCD, JLink) are presumed to run if
+/// no other host is specified.
+///
+const DEBUG_SERVER_LOCALHOST: &str = "127.0.0.1";
+
+///
+/// Connects to a debug server.  A server on another host (e.g., a lab
+/// machine to which the probe is attached) is allowed more time to answer
+/// than one on the local machine.
+///
+fn debug_server_connect(host: &str, port: u16) -> Option<TcpStream> {
+    let timeout = if host == DEBUG_SERVER_LOCALHOST {
+        Duration::from_millis(100)
+    } else {
+        Duration::from_millis(2000)
+    };
+
+    (host, port)
+        .to_socket_addrs()
+        .ok()?
+        .find_map(|addr| TcpStream::connect_timeout(&addr, timeout).ok())
+}
+
+///
+/// Describes where a debug server is for the purposes of an error message.
+///
+fn debug_server_location(host: &str, port: u16) -> String {
+    if host == DEBUG_SERVER_LOCALHOST {
+        format!("port {}", port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
 const OPENOCD_COMMAND_DELIMITER: u8 = 0x1a;
 const OPENOCD_TRACE_DATA_BEGIN: &str = "type target_trace data ";
 const OPENOCD_TRACE_DATA_END: &str = "\r\n";
@@ -314,13 +348,14 @@ impl OpenOCDCore {
         }
     }
 
-    fn new() -> Result<OpenOCDCore> {
-        let addr = "127.0.0.1:6666".parse()?;
-        let timeout = Duration::from_millis(100);
-        let stream =
-            TcpStream::connect_timeout(&addr, timeout).map_err(|_| {
-                anyhow!("can't connect to OpenOCD on port 6666; is it running?")
-            })?;
+    fn new(host: &str) -> Result<OpenOCDCore> {
+        let port = 6666;
+        let stream = debug_server_connect(host, port).ok_or_else(|| {
+            anyhow!(
+                "can't connect to OpenOCD on {}; is it running?",
+                debug_server_location(host, port)
+            )
+        })?;
 
         Ok(Self { stream, swv: false, last_swv: None })
     }
@@ -729,24 +764,19 @@ impl GDBCore {
         }
     }
 
-    fn new(server: GDBServer) -> Result<GDBCore> {
+    fn new(server: GDBServer, host: &str) -> Result<GDBCore> {
         let port = match server {
             GDBServer::OpenOCD => 3333,
             GDBServer::JLink => 2331,
         };
 
-        let host = format!("127.0.0.1:{}", port);
-        let addr = host.parse()?;
-        let timeout = Duration::from_millis(100);
-
-        let stream =
-            TcpStream::connect_timeout(&addr, timeout).map_err(|_| {
-                anyhow!(
-                "can't connect to {} GDB server on \
-                    port {}; is it running?",
-                server, port
+        let stream = debug_server_connect(host, port).ok_or_else(|| {
+            anyhow!(
+                "can't connect to {} GDB server on {}; is it running?",
+                server,
+                debug_server_location(host, port)
             )
-            })?;
+        })?;
 
         //
         // Both the OpenOCD and JLink GDB servers stop the target upon
@@ -875,11 +905,8 @@ pub struct DumpCore {
 
 impl DumpCore {
     fn new(dump: &str, hubris: &HubrisArchive) -> Result<DumpCore> {
-        let mut file = fs::File::open(dump)?;
         let mut regions = BTreeMap::new();
-
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents)?;
+        let contents = read_dump(dump)?;
 
         let elf = Elf::parse(&contents).map_err(|e| {
             anyhow!("failed to parse {} as an ELF file: {}", dump, e)
@@ -1027,12 +1054,52 @@ impl Core for DumpCore {
     }
 }
 
+///
+/// A core to which we have attached, along with a description of how we
+/// attached to it (e.g., "via ST-Link V3") suitable for display.
+///
+pub struct Attached {
+    pub core: Box<dyn Core>,
+    pub description: String,
+}
+
+impl Attached {
+    fn new(core: Box<dyn Core>, description: String) -> Self {
+        Self { core, description }
+    }
+}
+
+///
+/// Attaches to a live target via the specified probe, which can be a probe
+/// type (`usb`, `ocd`, `ocdgdb`, `jlink` or `auto`), a USB probe index
+/// (e.g., `usb-1`), a USB probe serial number (e.g., `usb:0038001F3137`) or
+/// a VID:PID[:serial] selector.  The probe types that
+/// attach via a debug server (`ocd`, `ocdgdb` and `jlink`, as well as
+/// `auto`) can be suffixed with `@host` to use a debug server on another
+/// machine.
+///
 #[rustfmt::skip::macros(anyhow, bail)]
-pub fn attach(
-    mut probe: &str,
-    hubris: &HubrisArchive,
-) -> Result<Box<dyn Core>> {
+pub fn attach(mut probe: &str, hubris: &HubrisArchive) -> Result<Attached> {
     let mut index: Option<usize> = None;
+    let mut serial: Option<&str> = None;
+    let mut host = DEBUG_SERVER_LOCALHOST;
+
+    if let Some((kind, remote)) = probe.split_once('@') {
+        match kind {
+            "ocd" | "ocdgdb" | "jlink" | "auto" => {
+                probe = kind;
+                host = remote;
+            }
+            _ => {
+                bail!("probe type \"{}\" cannot be on another host", kind);
+            }
+        }
+    }
+
+    if let Some(s) = probe.strip_prefix("usb:") {
+        serial = Some(s);
+        probe = "usb";
+    }
 
     if probe.contains('-') {
         let str = probe.to_owned();
@@ -1063,6 +1130,19 @@ pub fn attach(
                 bail!("no debug probe found; is it plugged in?");
             }
 
+            if let Some(serial) = serial {
+                let found = probes
+                    .iter()
+                    .position(|p| p.serial_number.as_deref() == Some(serial));
+
+                match found {
+                    Some(found) => index = Some(found),
+                    None => {
+                        bail!("no USB probe with serial number {}", serial);
+                    }
+                }
+            }
+
             let (selected, res) = if let Some(index) = index {
                 if index < probes.len() {
                     (index, probes[index].open())
@@ -1100,29 +1180,35 @@ pub fn attach(
             let name = probe.get_name();
             let session = probe.attach(chip)?;
 
-            crate::msg!("attached via {}", name);
-
-            Ok(Box::new(ProbeCore::new(
+            let core = ProbeCore::new(
                 session,
                 probes[selected].identifier.clone(),
                 probes[selected].vendor_id,
                 probes[selected].product_id,
                 probes[selected].serial_number.clone(),
                 hubris.unhalted_reads(),
-            )))
+            );
+
+            Ok(Attached::new(Box::new(core), format!("via {}", name)))
         }
 
         "ocd" => {
-            let mut core = OpenOCDCore::new()?;
+            let mut core = OpenOCDCore::new(host)?;
             let version = core.sendcmd("version")?;
 
             if !version.contains("Open On-Chip Debugger") {
                 bail!("version string unrecognized: \"{}\"", version);
             }
 
-            crate::msg!("attached via OpenOCD");
+            Ok(Attached::new(Box::new(core), "via OpenOCD".to_string()))
+        }
+
+        "auto" if host != DEBUG_SERVER_LOCALHOST => {
+            if let Ok(probe) = attach(&format!("ocd@{}", host), hubris) {
+                return Ok(probe);
+            }
 
-            Ok(Box::new(core))
+            attach(&format!("jlink@{}", host), hubris)
         }
 
         "auto" => {
@@ -1138,17 +1224,18 @@ pub fn attach(
         }
 
         "ocdgdb" => {
-            let core = GDBCore::new(GDBServer::OpenOCD)?;
-            crate::msg!("attached via OpenOCD's GDB server");
+            let core = GDBCore::new(GDBServer::OpenOCD, host)?;
 
-            Ok(Box::new(core))
+            Ok(Attached::new(
+                Box::new(core),
+                "via OpenOCD's GDB server".to_string(),
+            ))
         }
 
         "jlink" => {
-            let core = GDBCore::new(GDBServer::JLink)?;
-            crate::msg!("attached via JLink");
+            let core = GDBCore::new(GDBServer::JLink, host)?;
 
-            Ok(Box::new(core))
+            Ok(Attached::new(Box::new(core), "via JLink".to_string()))
         }
 
         _ => match TryInto::<probe_rs::DebugProbeSelector>::try_into(probe) {
@@ -1163,27 +1250,28 @@ pub fn attach(
                 let name = probe.get_name();
                 let session = probe.attach(chip)?;
 
-                crate::msg!("attached to {} via {}", vidpid, name);
+                let description = format!("to {} via {}", vidpid, name);
 
-                Ok(Box::new(ProbeCore::new(
+                let core = ProbeCore::new(
                     session,
                     name,
                     vid,
                     pid,
                     serial,
                     hubris.unhalted_reads(),
-                )))
+                );
+
+                Ok(Attached::new(Box::new(core), description))
             }
             Err(_) => Err(anyhow!("unrecognized probe: {}", probe)),
         },
     }
 }
 
-pub fn attach_dump(
-    dump: &str,
-    hubris: &HubrisArchive,
-) -> Result<Box<dyn Core>> {
+///
+/// Attaches to a dump taken with [`HubrisArchive::dump`].
+///
+pub fn attach_dump(dump: &str, hubris: &HubrisArchive) -> Result<Attached> {
     let core = DumpCore::new(dump, hubris)?;
-    crate::msg!("attached to dump");
-    Ok(Box::new(core))
+    Ok(Attached::new(Box::new(core), "to dump".to_string()))
 }
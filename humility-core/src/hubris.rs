@@ -26,7 +26,7 @@ use gimli::UnwindSection;
 use goblin::elf::Elf;
 use idol::syntax::Interface;
 use multimap::MultiMap;
-use num_traits::FromPrimitive;
+use num_traits::{FromPrimitive, ToPrimitive};
 use rustc_demangle::demangle;
 use scroll::{IOwrite, Pwrite};
 
@@ -34,24 +34,36 @@ const OXIDE_NT_NAME: &str = "Oxide Computer Company";
 const OXIDE_NT_BASE: u32 = 0x1de << 20;
 const OXIDE_NT_HUBRIS_ARCHIVE: u32 = OXIDE_NT_BASE + 1;
 const OXIDE_NT_HUBRIS_REGISTERS: u32 = OXIDE_NT_BASE + 2;
+const OXIDE_NT_HUBRIS_TASK_REGISTERS: u32 = OXIDE_NT_BASE + 3;
+
+//
+// The magic that denotes a gzip-compressed dump.
+//
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
 const MAX_HUBRIS_VERSION: u32 = 2;
 
 #[derive(Default, Debug)]
 pub struct HubrisManifest {
-    version: Option<String>,
-    gitrev: Option<String>,
+    pub version: Option<String>,
+    pub gitrev: Option<String>,
     features: Vec<String>,
-    board: Option<String>,
+    pub board: Option<String>,
     pub name: Option<String>,
-    target: Option<String>,
+    pub target: Option<String>,
     task_features: HashMap<String, Vec<String>>,
     pub task_irqs: HashMap<String, Vec<(u32, u32)>>,
+    /// Peripherals used by each task, as tuples of name, address and size
+    pub task_peripherals: HashMap<String, Vec<(String, u32, u32)>>,
+    pub irq_names: BTreeMap<u32, String>,
     peripherals: BTreeMap<String, u32>,
     peripherals_byaddr: BTreeMap<u32, String>,
     pub i2c_devices: Vec<HubrisI2cDevice>,
     pub i2c_buses: Vec<HubrisI2cBus>,
     pub sensors: Vec<HubrisSensor>,
+    pub gpio_pins: Vec<HubrisGpioPin>,
+    pub net_sockets: Vec<HubrisNetSocket>,
+    pub spi_devices: Vec<HubrisSpiDevice>,
 }
 
 //
@@ -82,6 +94,7 @@ struct HubrisConfigKernel {
 struct HubrisConfigTask {
     features: Option<Vec<String>>,
     interrupts: Option<IndexMap<String, u32>>,
+    uses: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -150,9 +163,60 @@ struct HubrisConfigI2c {
     devices: Option<Vec<HubrisConfigI2cDevice>>,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+struct HubrisConfigGpioPin {
+    port: String,
+    pin: u8,
+    description: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct HubrisConfigGpio {
+    pins: Option<IndexMap<String, HubrisConfigGpioPin>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct HubrisConfigNetSocketOwner {
+    name: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct HubrisConfigNetSocketBuffer {
+    packets: usize,
+    bytes: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct HubrisConfigNetSocket {
+    kind: String,
+    owner: HubrisConfigNetSocketOwner,
+    port: u16,
+    tx: HubrisConfigNetSocketBuffer,
+    rx: HubrisConfigNetSocketBuffer,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct HubrisConfigNet {
+    sockets: Option<IndexMap<String, HubrisConfigNetSocket>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct HubrisConfigSpiDevice {
+    description: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct HubrisConfigSpi {
+    controller: u8,
+    devices: Option<IndexMap<String, HubrisConfigSpiDevice>>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct HubrisConfigConfig {
     i2c: Option<HubrisConfigI2c>,
+    gpio: Option<HubrisConfigGpio>,
+    net: Option<HubrisConfigNet>,
+    spi: Option<IndexMap<String, HubrisConfigSpi>>,
 }
 
 #[derive(Clone, Debug)]
@@ -191,6 +255,36 @@ pub struct HubrisI2cDevice {
     pub removable: bool,
 }
 
+#[derive(Clone, Debug)]
+pub struct HubrisGpioPin {
+    pub name: String,
+    pub port: String,
+    pub pin: u8,
+    pub description: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HubrisNetSocket {
+    pub name: String,
+    pub kind: String,
+    pub owner: String,
+    pub port: u16,
+    /// transmit queue depth, in packets and bytes
+    pub tx: (usize, usize),
+    /// receive queue depth, in packets and bytes
+    pub rx: (usize, usize),
+}
+
+#[derive(Clone, Debug)]
+pub struct HubrisSpiDevice {
+    pub name: String,
+    /// SPI controller (that is, the number of the SPI peripheral)
+    pub controller: u8,
+    /// Index of the device among those of its controller
+    pub index: u8,
+    pub description: Option<String>,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HubrisSensorKind {
     Temperature,
@@ -279,6 +373,9 @@ pub struct HubrisArchive {
     // Current registers (if a dump)
     registers: HashMap<ARMRegister, u32>,
 
+    // Task registers at the time of the dump: task index to registers
+    task_registers: HashMap<u32, BTreeMap<ARMRegister, u32>>,
+
     // Modules: text address to module
     modules: BTreeMap<u32, HubrisModule>,
 
@@ -303,6 +400,9 @@ pub struct HubrisArchive {
     // Inlined: address/nesting tuple to length/goff/origin tuple
     inlined: BTreeMap<(u32, isize), (u32, HubrisGoff, HubrisGoff)>,
 
+    // Deferred formatting: task to index to interned format string
+    deferred: HashMap<HubrisTask, BTreeMap<u32, HubrisDeferred>>,
+
     // Subprograms: goff to demangled name
     subprograms: HashMap<HubrisGoff, String>,
 
@@ -380,6 +480,7 @@ impl HubrisArchive {
             instrs: HashMap::new(),
             syscall_pushes: HashMap::new(),
             registers: HashMap::new(),
+            task_registers: HashMap::new(),
             modules: BTreeMap::new(),
             tasks: HashMap::new(),
             frames: HashMap::new(),
@@ -388,6 +489,7 @@ impl HubrisArchive {
             esyms: BTreeMap::new(),
             esyms_byname: MultiMap::new(),
             inlined: BTreeMap::new(),
+            deferred: HashMap::new(),
             subprograms: HashMap::new(),
             basetypes: HashMap::new(),
             basetypes_byname: HashMap::new(),
@@ -454,6 +556,24 @@ impl HubrisArchive {
         })
     }
 
+    ///
+    /// Looks up functions by name, returning the (demangled) name, address
+    /// and size of each function whose name is either the specified name or
+    /// ends with it as a path component (e.g., `main` matches
+    /// `task_ping::main`).  Because each task has its own text, the same
+    /// function can appear in more than one task.
+    ///
+    pub fn lookup_functions(&self, name: &str) -> Vec<(&str, u32, u32)> {
+        let suffix = format!("::{}", name);
+
+        self.esyms
+            .iter()
+            .filter(|(_, (sym, _))| sym == name || sym.ends_with(&suffix))
+            .filter(|(&addr, _)| self.instr_mod(addr).is_some())
+            .map(|(&addr, (sym, size))| (sym.as_str(), addr, *size))
+            .collect()
+    }
+
     pub fn instr_inlined(&self, pc: u32, base: u32) -> Vec<HubrisInlined> {
         let mut inlined: Vec<HubrisInlined> = vec![];
 
@@ -1785,6 +1905,33 @@ impl HubrisArchive {
         Ok(())
     }
 
+    fn load_deferred(&mut self, task: HubrisTask, name: &str, index: u32) {
+        #[derive(Deserialize)]
+        struct Interned {
+            tag: String,
+            data: String,
+        }
+
+        //
+        // Symbols that don't describe an interned string (e.g., the markers
+        // of the section's bounds) are skipped.
+        //
+        let interned: Interned = match serde_json::from_str(name) {
+            Ok(interned) => interned,
+            Err(_) => {
+                log::trace!("skipping deferred symbol {}", name);
+                return;
+            }
+        };
+
+        let tag = interned.tag.trim_start_matches("defmt_").to_string();
+
+        self.deferred
+            .entry(task)
+            .or_default()
+            .insert(index, HubrisDeferred { tag, format: interned.data });
+    }
+
     fn load_object(
         &mut self,
         object: &str,
@@ -1831,6 +1978,15 @@ impl HubrisArchive {
             .map(|(ndx, _)| ndx)
             .collect::<HashSet<_>>();
 
+        //
+        // Format strings interned for deferred formatting are in a section
+        // of their own, where each symbol's name describes the string and
+        // its value is the string's index.
+        //
+        let deferred = elf.section_headers.iter().position(|sh| {
+            matches!(elf.shdr_strtab.get(sh.sh_name), Some(Ok(".defmt")))
+        });
+
         let offset = textsec.sh_offset as u32;
         let size = textsec.sh_size as u32;
         let current = self.current;
@@ -1876,6 +2032,11 @@ impl HubrisArchive {
                 }
             }
 
+            if deferred == Some(sym.st_shndx) {
+                self.load_deferred(task, name, sym.st_value as u32);
+                continue;
+            }
+
             //
             // If this is a zero-sized symbol or not against an allocated
             // section (e.g., .idolatry), we don't want to keep track of it.
@@ -2022,6 +2183,9 @@ impl HubrisArchive {
                 heapbss,
                 task,
                 iface,
+                texthash: text_hash(
+                    &buffer[offset as usize..(offset + size) as usize],
+                ),
             },
         );
 
@@ -2181,6 +2345,55 @@ impl HubrisArchive {
         Ok(())
     }
 
+    fn load_gpio_config(&mut self, gpio: &HubrisConfigGpio) -> Result<()> {
+        if let Some(ref pins) = gpio.pins {
+            for (name, pin) in pins {
+                if pin.pin >= 16 {
+                    bail!("GPIO pin {}: invalid pin {}", name, pin.pin);
+                }
+
+                self.manifest.gpio_pins.push(HubrisGpioPin {
+                    name: name.clone(),
+                    port: pin.port.clone(),
+                    pin: pin.pin,
+                    description: pin.description.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn load_net_config(&mut self, net: &HubrisConfigNet) {
+        if let Some(ref sockets) = net.sockets {
+            for (name, socket) in sockets {
+                self.manifest.net_sockets.push(HubrisNetSocket {
+                    name: name.clone(),
+                    kind: socket.kind.clone(),
+                    owner: socket.owner.name.clone(),
+                    port: socket.port,
+                    tx: (socket.tx.packets, socket.tx.bytes),
+                    rx: (socket.rx.packets, socket.rx.bytes),
+                });
+            }
+        }
+    }
+
+    fn load_spi_config(&mut self, spi: &IndexMap<String, HubrisConfigSpi>) {
+        for config in spi.values() {
+            for (index, (name, device)) in
+                config.devices.iter().flatten().enumerate()
+            {
+                self.manifest.spi_devices.push(HubrisSpiDevice {
+                    name: name.clone(),
+                    controller: config.controller,
+                    index: index as u8,
+                    description: device.description.clone(),
+                });
+            }
+        }
+    }
+
     fn load_config(
         &mut self,
         config: &HubrisConfig,
@@ -2202,8 +2415,9 @@ impl HubrisArchive {
 
                 if let Some(ref interrupts) = p.interrupts {
                     for (interrupt, irq) in interrupts {
-                        named_interrupts
-                            .insert(format!("{}.{}", name, interrupt), *irq);
+                        let irqname = format!("{}.{}", name, interrupt);
+                        self.manifest.irq_names.insert(*irq, irqname.clone());
+                        named_interrupts.insert(irqname, *irq);
                     }
                 }
             }
@@ -2244,12 +2458,37 @@ impl HubrisArchive {
 
                 self.manifest.task_irqs.insert(name.clone(), task_irqs);
             }
+
+            if let (Some(uses), Some(peripherals)) = (&task.uses, peripherals) {
+                let used = uses
+                    .iter()
+                    .filter_map(|p| {
+                        peripherals.get(p).map(|periph| {
+                            (p.clone(), periph.address, periph.size)
+                        })
+                    })
+                    .collect();
+
+                self.manifest.task_peripherals.insert(name.clone(), used);
+            }
         }
 
         if let Some(ref config) = config.config {
             if let Some(ref i2c) = config.i2c {
                 self.load_i2c_config(i2c)?;
             }
+
+            if let Some(ref gpio) = config.gpio {
+                self.load_gpio_config(gpio)?;
+            }
+
+            if let Some(ref net) = config.net {
+                self.load_net_config(net);
+            }
+
+            if let Some(ref spi) = config.spi {
+                self.load_spi_config(spi);
+            }
         }
 
         Ok(())
@@ -2512,6 +2751,47 @@ impl HubrisArchive {
         Ok(())
     }
 
+    fn load_task_registers(&mut self, r: &[u8]) -> Result<()> {
+        let word = |offs: usize| -> Result<u32> {
+            match r.get(offs..offs + 4) {
+                Some(w) => Ok(u32::from_le_bytes(w.try_into().unwrap())),
+                None => bail!("task registers note truncated at {}", offs),
+            }
+        };
+
+        let mut offs = 0;
+
+        //
+        // The note consists of a series of records, each of which is a task
+        // index, a count of registers, and then that many pairs of register
+        // ID and value.
+        //
+        while offs < r.len() {
+            let task = word(offs)?;
+            let nregs = word(offs + 4)? as usize;
+            let mut regs = BTreeMap::new();
+
+            offs += 8;
+
+            for i in 0..nregs {
+                let id = word(offs + i * 8)?;
+                let val = word(offs + i * 8 + 4)?;
+
+                if let Some(reg) = ARMRegister::from_u32(id) {
+                    regs.insert(reg, val);
+                }
+            }
+
+            offs += nregs * 8;
+
+            if self.task_registers.insert(task, regs).is_some() {
+                bail!("duplicate registers for task {}", task);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn load_dump(
         &mut self,
         dumpfile: &str,
@@ -2520,7 +2800,7 @@ impl HubrisArchive {
         //
         // We expect the dump to be an ELF core dump.
         //
-        let contents = fs::read(dumpfile)?;
+        let contents = read_dump(dumpfile)?;
         let elf = Elf::parse(&contents).map_err(|e| {
             anyhow!("failed to parse {} as an ELF file: {}", dumpfile, e)
         })?;
@@ -2544,8 +2824,19 @@ impl HubrisArchive {
                             OXIDE_NT_HUBRIS_REGISTERS => {
                                 self.load_registers(note.desc)?;
                             }
+                            OXIDE_NT_HUBRIS_TASK_REGISTERS => {
+                                self.load_task_registers(note.desc)?;
+                            }
                             _ => {
-                                bail!("unrecognized note 0x{:x}", note.n_type);
+                                //
+                                // This may well be a dump from a future
+                                // Humility; we skip notes that we don't
+                                // understand rather than refuse the dump.
+                                //
+                                log::warn!(
+                                    "skipping unrecognized note 0x{:x}",
+                                    note.n_type
+                                );
                             }
                         }
                     }
@@ -2664,6 +2955,41 @@ impl HubrisArchive {
         }
     }
 
+    ///
+    /// Returns the tasks that have interned format strings for deferred
+    /// formatting.
+    ///
+    pub fn deferred_tasks(&self) -> Vec<HubrisTask> {
+        let mut tasks: Vec<_> = self.deferred.keys().copied().collect();
+        tasks.sort();
+        tasks
+    }
+
+    ///
+    /// Looks up a format string interned by the specified task for deferred
+    /// formatting by its index.
+    ///
+    pub fn lookup_deferred(
+        &self,
+        task: HubrisTask,
+        index: u32,
+    ) -> Option<&HubrisDeferred> {
+        self.deferred.get(&task).and_then(|d| d.get(&index))
+    }
+
+    ///
+    /// Looks up the format of the timestamp that precedes each of the
+    /// specified task's deferred messages, if it has one.
+    ///
+    pub fn deferred_timestamp(
+        &self,
+        task: HubrisTask,
+    ) -> Option<&HubrisDeferred> {
+        self.deferred
+            .get(&task)
+            .and_then(|d| d.values().find(|d| d.tag == "timestamp"))
+    }
+
     ///
     /// Looks up the specified symbol.  This is more of a convenience routine
     /// that turns an Option into a Result.
@@ -2879,6 +3205,41 @@ impl HubrisArchive {
         );
     }
 
+    ///
+    /// Compares the text of each module (the kernel and each task) in the
+    /// archive with the text on the target.  This allows for an archive
+    /// that does not match a target (e.g., a dump) to be used on a
+    /// best-effort basis for those tasks whose text is unchanged.  Note
+    /// that the text of a task can only be checked if the target (or dump)
+    /// includes it.
+    ///
+    pub fn check_text(
+        &self,
+        core: &mut dyn crate::core::Core,
+    ) -> Vec<HubrisTextCheck> {
+        let mut modules = self.modules.values().collect::<Vec<_>>();
+        modules.sort_by_key(|m| m.task);
+
+        modules
+            .iter()
+            .map(|module| {
+                let mut text = vec![0; module.textsize as usize];
+
+                let target = core
+                    .read_8(module.textbase, &mut text)
+                    .map(|_| text_hash(&text))
+                    .map_err(|e| e.to_string());
+
+                HubrisTextCheck {
+                    name: module.name.clone(),
+                    task: module.task,
+                    archive: module.texthash,
+                    target,
+                }
+            })
+            .collect()
+    }
+
     pub fn image_id_addr(&self) -> Option<u32> {
         self.imageid.as_ref().map(|i| i.0)
     }
@@ -3125,6 +3486,16 @@ impl HubrisArchive {
             }
         };
 
+        //
+        // If this is a dump that recorded task registers, we take them
+        // directly.
+        //
+        if core.is_dump() {
+            if let Some(regs) = self.task_registers.get(&ndx) {
+                return Ok(regs.clone());
+            }
+        }
+
         let task = self.lookup_struct_byname("Task")?;
         let save = task.lookup_member("save")?.offset as u32;
         let state = self.lookup_struct_byname("SavedState")?;
@@ -3771,15 +4142,39 @@ impl HubrisArchive {
         &self,
         core: &mut dyn crate::core::Core,
         dumpfile: Option<&str>,
+        options: &HubrisDumpOptions,
     ) -> Result<()> {
         use indicatif::{HumanBytes, HumanDuration};
         use indicatif::{ProgressBar, ProgressStyle};
         use std::io::Write;
 
         let regions = self.regions(core)?;
-        let nsegs = regions
+
+        //
+        // Our segments consist of every region that isn't device memory,
+        // along with any device memory that we have been explicitly asked to
+        // include.
+        //
+        let mut segs = regions
             .values()
-            .fold(0, |ttl, r| ttl + if !r.attr.device { 1 } else { 0 });
+            .filter(|r| !r.attr.device)
+            .map(|r| (r.base, r.size))
+            .collect::<Vec<_>>();
+
+        segs.extend(options.device.iter());
+        segs.sort_unstable();
+
+        for w in segs.windows(2) {
+            if w[0].0 + w[0].1 > w[1].0 {
+                bail!(
+                    "memory at 0x{:x} overlaps memory at 0x{:x}",
+                    w[0].0,
+                    w[1].0
+                );
+            }
+        }
+
+        let nsegs = segs.len();
 
         macro_rules! pad {
             ($size:expr) => {
@@ -3826,6 +4221,34 @@ impl HubrisArchive {
             n_type: OXIDE_NT_HUBRIS_ARCHIVE,
         });
 
+        //
+        // Record the registers of each task.  We don't want to fail the
+        // dump for a task whose registers we can't determine, so we just
+        // drop any such task on the floor.
+        //
+        let mut task_regs = vec![];
+
+        for i in 0..self.ntasks() as u32 {
+            match self.registers(core, HubrisTask::Task(i)) {
+                Ok(regs) => task_regs.push((i, regs)),
+                Err(e) => {
+                    crate::msg!(
+                        "failed to read registers for task {}: {}",
+                        i,
+                        e
+                    );
+                }
+            }
+        }
+
+        notes.push(goblin::elf::note::Nhdr32 {
+            n_namesz: (oxide.len() + 1) as u32,
+            n_descsz: task_regs
+                .iter()
+                .fold(0, |ttl, (_, regs)| ttl + 8 + regs.len() as u32 * 8),
+            n_type: OXIDE_NT_HUBRIS_TASK_REGISTERS,
+        });
+
         let mut header = goblin::elf::header::Header::new(ctx);
         header.e_machine = goblin::elf::header::EM_ARM;
         header.e_type = goblin::elf::header::ET_CORE;
@@ -3890,26 +4313,22 @@ impl HubrisArchive {
 
         let mut total = 0;
 
-        for (_, region) in regions.iter() {
-            if region.attr.device {
-                continue;
-            }
-
+        for (base, size) in segs.iter() {
             let seg_phdr = goblin::elf32::program_header::ProgramHeader {
                 p_type: goblin::elf::program_header::PT_LOAD,
                 p_flags: goblin::elf::program_header::PF_R,
                 p_offset: offset,
-                p_vaddr: region.base,
-                p_filesz: region.size,
-                p_memsz: region.size,
+                p_vaddr: *base,
+                p_filesz: *size,
+                p_memsz: *size,
                 ..Default::default()
             };
 
             bytes.pwrite_with(seg_phdr, 0, ctx.le)?;
             file.write_all(&bytes)?;
 
-            offset += region.size + pad!(region.size);
-            total += region.size;
+            offset += size + pad!(size);
+            total += size;
         }
         for note in &notes {
             //
@@ -3943,6 +4362,22 @@ impl HubrisArchive {
                 OXIDE_NT_HUBRIS_ARCHIVE => {
                     file.write_all(&self.archive)?;
                 }
+                OXIDE_NT_HUBRIS_TASK_REGISTERS => {
+                    let mut bytes = [0x0u8; 8];
+
+                    for (task, regs) in task_regs.iter() {
+                        bytes.pwrite_with(task, 0, ctx.le)?;
+                        bytes.pwrite_with(regs.len() as u32, 4, ctx.le)?;
+                        file.write_all(&bytes)?;
+
+                        for (reg, val) in regs.iter() {
+                            let id = reg.to_u32().unwrap();
+                            bytes.pwrite_with(id, 0, ctx.le)?;
+                            bytes.pwrite_with(val, 4, ctx.le)?;
+                            file.write_all(&bytes)?;
+                        }
+                    }
+                }
                 _ => {
                     panic!("unimplemented note");
                 }
@@ -3965,14 +4400,10 @@ impl HubrisArchive {
                 .template("humility: dumping [{bar:30}] {bytes}/{total_bytes}"),
         );
 
-        for (_, region) in regions.iter() {
-            if region.attr.device {
-                continue;
-            }
-
-            let mut remain = region.size as usize;
+        for (base, size) in segs.iter() {
+            let mut remain = *size as usize;
             let mut bytes = vec![0; 1024];
-            let mut addr = region.base;
+            let mut addr = *base;
 
             while remain > 0 {
                 let nbytes =
@@ -3986,12 +4417,24 @@ impl HubrisArchive {
                 bar.set_position(written as u64);
             }
 
-            let npad = pad!(region.size) as usize;
+            let npad = pad!(size) as usize;
             file.write_all(&pad[0..npad])?;
         }
 
         bar.finish_and_clear();
 
+        if options.compress {
+            use flate2::write::GzEncoder;
+
+            drop(file);
+
+            let contents = fs::read(&filename)?;
+            let file = fs::File::create(&filename)?;
+            let mut gz = GzEncoder::new(file, flate2::Compression::default());
+            gz.write_all(&contents)?;
+            gz.finish()?;
+        }
+
         crate::msg!(
             "dumped {} in {}",
             HumanBytes(written as u64),
@@ -4001,17 +4444,133 @@ impl HubrisArchive {
         Ok(())
     }
 
-    #[allow(clippy::print_literal)]
-    pub fn manifest(&self) -> Result<()> {
+    ///
+    /// Restores a dump taken of this archive to the (halted) core.  Only
+    /// writable memory is restored; memory that is read-only (e.g., text) or
+    /// that is device memory (e.g., peripherals explicitly included in the
+    /// dump) is skipped.
+    ///
+    pub fn restore(
+        &self,
+        core: &mut dyn crate::core::Core,
+        dumpfile: &str,
+    ) -> Result<()> {
+        use indicatif::{HumanBytes, HumanDuration};
+        use indicatif::{ProgressBar, ProgressStyle};
+
+        let contents = read_dump(dumpfile)?;
+        let elf = Elf::parse(&contents).map_err(|e| {
+            anyhow!("failed to parse {} as an ELF file: {}", dumpfile, e)
+        })?;
+
+        let mut archive = None;
+        let mut registers = None;
+
+        if let Some(notes) = elf.iter_note_headers(&contents) {
+            for note in notes {
+                let note = note.map_err(|e| anyhow!("bad note: {}", e))?;
+
+                if note.name != OXIDE_NT_NAME {
+                    continue;
+                }
+
+                match note.n_type {
+                    OXIDE_NT_HUBRIS_ARCHIVE => archive = Some(note.desc),
+                    OXIDE_NT_HUBRIS_REGISTERS => registers = Some(note.desc),
+                    _ => {}
+                }
+            }
+        }
+
+        match archive {
+            Some(archive) if archive == self.archive.as_slice() => {}
+            Some(_) => bail!("{} was taken of a different archive", dumpfile),
+            None => bail!("{} does not contain an archive", dumpfile),
+        }
+
+        let registers = match registers {
+            Some(registers) if registers.len() % 8 == 0 => registers,
+            Some(registers) => {
+                bail!("bad length {} in registers note", registers.len())
+            }
+            None => bail!("{} does not contain registers", dumpfile),
+        };
+
+        let regions = self.regions(core)?;
+
+        let writable = |base: u32, size: u32| {
+            regions.values().any(|r| {
+                r.attr.write
+                    && !r.attr.device
+                    && base >= r.base
+                    && base + size <= r.base + r.size
+            })
+        };
+
+        let segs = elf
+            .program_headers
+            .iter()
+            .filter(|h| h.p_type == goblin::elf::program_header::PT_LOAD)
+            .filter(|h| writable(h.p_vaddr as u32, h.p_filesz as u32))
+            .collect::<Vec<_>>();
+
+        let total = segs.iter().fold(0, |ttl, h| ttl + h.p_filesz);
+        let mut written = 0;
+
+        let started = Instant::now();
+        let bar = ProgressBar::new(total);
+        bar.set_style(
+            ProgressStyle::default_bar().template(
+                "humility: restoring [{bar:30}] {bytes}/{total_bytes}",
+            ),
+        );
+
+        for seg in segs {
+            let offset = seg.p_offset as usize;
+            let data = contents
+                .get(offset..offset + seg.p_filesz as usize)
+                .ok_or_else(|| {
+                    anyhow!("segment at 0x{:x} is truncated", seg.p_vaddr)
+                })?;
+
+            let mut addr = seg.p_vaddr as u32;
+
+            for chunk in data.chunks(1024) {
+                core.write_8(addr, chunk)?;
+                addr += chunk.len() as u32;
+                written += chunk.len() as u64;
+                bar.set_position(written);
+            }
+        }
+
+        bar.finish_and_clear();
+
+        for chunk in registers.chunks_exact(8) {
+            let id = u32::from_le_bytes(chunk[0..4].try_into().unwrap());
+            let val = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+
+            if let Some(reg) = ARMRegister::from_u32(id) {
+                core.write_reg(reg, val)
+                    .with_context(|| format!("failed to restore {}", reg))?;
+            }
+        }
+
+        crate::msg!(
+            "restored {} in {}",
+            HumanBytes(written),
+            HumanDuration(started.elapsed())
+        );
+
+        Ok(())
+    }
+
+    #[allow(clippy::write_literal)]
+    pub fn manifest(&self, out: &mut dyn std::io::Write) -> Result<()> {
         ensure!(
             !self.modules.is_empty(),
             "must specify a valid Hubris archive"
         );
 
-        let print = |what, val| {
-            println!("{:>12} => {}", what, val);
-        };
-
         let size = |task| {
             self.modules
                 .iter()
@@ -4020,23 +4579,28 @@ impl HubrisArchive {
                 .unwrap()
         };
 
-        print(
+        writeln!(
+            out,
+            "{:>12} => {}",
             "version",
             match &self.manifest.version {
                 Some(s) => s,
                 None => "<unknown>",
             },
-        );
+        )?;
 
-        print(
+        writeln!(
+            out,
+            "{:>12} => {}",
             "git rev",
             match &self.manifest.gitrev {
                 Some(s) => s,
                 None => "<unknown>",
             },
-        );
+        )?;
 
-        println!(
+        writeln!(
+            out,
             "{:>12} => {}",
             "image id",
             match &self.imageid {
@@ -4045,36 +4609,50 @@ impl HubrisArchive {
                 }
                 None => "<none>".to_string(),
             },
-        );
+        )?;
 
-        print(
+        writeln!(
+            out,
+            "{:>12} => {}",
             "board",
             match &self.manifest.board {
                 Some(s) => s,
                 None => "<unknown>",
             },
-        );
+        )?;
 
-        print(
+        writeln!(
+            out,
+            "{:>12} => {}",
             "target",
             match &self.manifest.target {
                 Some(s) => s,
                 None => "<unknown>",
             },
-        );
+        )?;
 
-        print("features", &self.manifest.features.join(", "));
+        writeln!(
+            out,
+            "{:>12} => {}",
+            "features",
+            &self.manifest.features.join(", ")
+        )?;
 
         let ttl = self.modules.iter().fold(0, |ttl, m| ttl + m.1.memsize);
 
-        println!("{:>12} => {}K", "total size", ttl / 1024);
-        println!(
+        writeln!(out, "{:>12} => {}K", "total size", ttl / 1024)?;
+        writeln!(
+            out,
             "{:>12} => {}K",
             "kernel size",
             size(HubrisTask::Kernel) / 1024
-        );
-        println!("{:>12} => {}", "tasks", self.modules.len() - 1);
-        println!("{:>18} {:18} {:>5} {}", "ID", "TASK", "SIZE", "FEATURES");
+        )?;
+        writeln!(out, "{:>12} => {}", "tasks", self.modules.len() - 1)?;
+        writeln!(
+            out,
+            "{:>18} {:18} {:>5} {}",
+            "ID", "TASK", "SIZE", "FEATURES"
+        )?;
 
         let mut id = 0;
 
@@ -4085,7 +4663,8 @@ impl HubrisArchive {
 
             let features = self.manifest.task_features.get(&module.name);
 
-            println!(
+            writeln!(
+                out,
                 "{:>18} {:18} {:>4.1}K {}",
                 id,
                 module.name,
@@ -4095,7 +4674,7 @@ impl HubrisArchive {
                 } else {
                     "".to_string()
                 }
-            );
+            )?;
 
             id += 1;
         }
@@ -4107,44 +4686,49 @@ impl HubrisArchive {
                 controllers.insert(bus.controller);
             }
 
-            println!(
+            writeln!(
+                out,
                 "{:>12} => {} controller{}, {} bus{}",
                 "i2c buses",
                 controllers.len(),
                 if controllers.len() != 1 { "s" } else { "" },
                 self.manifest.i2c_buses.len(),
                 if self.manifest.i2c_buses.len() != 1 { "es" } else { "" },
-            );
+            )?;
 
-            println!(
+            writeln!(
+                out,
                 "{:>17} {} {} {:13} {}",
                 "C", "PORT", "MODE", "NAME", "DESCRIPTION"
-            );
+            )?;
 
             for bus in &self.manifest.i2c_buses {
-                println!(
+                writeln!(
+                    out,
                     "{:>17} {:4} {:4} {:13} {}",
                     bus.controller,
                     bus.port.name,
                     if bus.target { "trgt" } else { "init" },
                     bus.name.as_ref().unwrap_or(&"-".to_string()),
                     bus.description.as_ref().unwrap_or(&"-".to_string()),
-                );
+                )?;
             }
         }
 
         if !self.manifest.i2c_devices.is_empty() {
-            println!(
+            writeln!(
+                out,
                 "{:>12} => {} device{}",
                 "i2c devices",
                 self.manifest.i2c_devices.len(),
                 if self.manifest.i2c_devices.len() != 1 { "s" } else { "" }
-            );
+            )?;
 
-            println!(
+            writeln!(
+                out,
                 "{:>17} {:2} {} {} {:13} {}",
                 "C", "P", "MUX", "ADDR", "DEVICE", "DESCRIPTION"
-            );
+            )?;
 
             for device in &self.manifest.i2c_devices {
                 let mux = match (device.mux, device.segment) {
@@ -4153,7 +4737,8 @@ impl HubrisArchive {
                     (_, _) => "?:?".to_string(),
                 };
 
-                println!(
+                writeln!(
+                    out,
                     "{:>17} {:2} {:3} 0x{:02x} {:13} {}",
                     device.controller,
                     device.port.name,
@@ -4161,14 +4746,105 @@ impl HubrisArchive {
                     device.address,
                     device.device,
                     device.description
-                );
+                )?;
+            }
+        }
+
+        if !self.manifest.gpio_pins.is_empty() {
+            writeln!(
+                out,
+                "{:>12} => {} pin{}",
+                "gpio pins",
+                self.manifest.gpio_pins.len(),
+                if self.manifest.gpio_pins.len() != 1 { "s" } else { "" }
+            )?;
+
+            writeln!(out, "{:>17} {:20} {}", "PIN", "NAME", "DESCRIPTION")?;
+
+            for pin in &self.manifest.gpio_pins {
+                writeln!(
+                    out,
+                    "{:>17} {:20} {}",
+                    format!("{}:{}", pin.port, pin.pin),
+                    pin.name,
+                    pin.description.as_ref().unwrap_or(&"-".to_string()),
+                )?;
+            }
+        }
+
+        if !self.manifest.net_sockets.is_empty() {
+            writeln!(
+                out,
+                "{:>12} => {} socket{}",
+                "net sockets",
+                self.manifest.net_sockets.len(),
+                if self.manifest.net_sockets.len() != 1 { "s" } else { "" }
+            )?;
+
+            writeln!(
+                out,
+                "{:>17} {:4} {:>5} {}",
+                "NAME", "KIND", "PORT", "OWNER"
+            )?;
+
+            for socket in &self.manifest.net_sockets {
+                writeln!(
+                    out,
+                    "{:>17} {:4} {:>5} {}",
+                    socket.name, socket.kind, socket.port, socket.owner
+                )?;
+            }
+        }
+
+        if !self.manifest.spi_devices.is_empty() {
+            writeln!(
+                out,
+                "{:>12} => {} device{}",
+                "spi devices",
+                self.manifest.spi_devices.len(),
+                if self.manifest.spi_devices.len() != 1 { "s" } else { "" }
+            )?;
+
+            writeln!(
+                out,
+                "{:>17} {:>3} {:20} {}",
+                "C", "DEV", "NAME", "DESCRIPTION"
+            )?;
+
+            for device in &self.manifest.spi_devices {
+                writeln!(
+                    out,
+                    "{:>17} {:>3} {:20} {}",
+                    device.controller,
+                    device.index,
+                    device.name,
+                    device.description.as_deref().unwrap_or("-"),
+                )?;
             }
         }
 
         Ok(())
     }
 
-    pub fn extract_file_to(&self, filename: &str, target: &Path) -> Result<()> {
+    ///
+    /// Returns the name and (uncompressed) size of each file in the archive,
+    /// in archive order.
+    ///
+    pub fn archive_files(&self) -> Result<Vec<(String, u64)>> {
+        let cursor = Cursor::new(self.archive.as_slice());
+        let mut archive = zip::ZipArchive::new(cursor)?;
+        let mut rval = vec![];
+
+        for i in 0..archive.len() {
+            let file = archive.by_index(i)?;
+            rval.push((file.name().to_string(), file.size()));
+        }
+
+        Ok(rval)
+    }
+
+    /// Returns the contents of the named file in the archive.
+    pub fn archive_file(&self, filename: &str) -> Result<Vec<u8>> {
         let cursor = Cursor::new(self.archive.as_slice());
         let mut archive = zip::ZipArchive::new(cursor)?;
         let mut file = archive
@@ -4177,9 +4853,42 @@ impl HubrisArchive {
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
 
+        Ok(buffer)
+    }
+
+    pub fn extract_file_to(&self, filename: &str, target: &Path) -> Result<()> {
+        let buffer = self.archive_file(filename)?;
         std::fs::write(target, &buffer).map_err(Into::into)
     }
 
+    ///
+    /// Returns the image to be written to auxiliary flash, if the archive
+    /// carries one.
+    ///
+    pub fn auxflash_data(&self) -> Result<Option<Vec<u8>>> {
+        let cursor = Cursor::new(self.archive.as_slice());
+        let mut archive = zip::ZipArchive::new(cursor)?;
+
+        let mut file = match archive.by_name("img/auxi.tlvc") {
+            Ok(file) => file,
+            Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+            Err(e) => bail!("failed to read auxiliary flash image: {}", e),
+        };
+
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+
+        Ok(Some(buffer))
+    }
+
+    ///
+    /// Returns the memory loaded for the specified task (that is, the loadable
+    /// segments of its ELF object), in address order.
+    ///
+    pub fn loaded_regions(&self, task: HubrisTask) -> Vec<&HubrisRegion> {
+        self.loaded.values().filter(|r| r.tasks.contains(&task)).collect()
+    }
+
     /// Copies the kernel and every task ELF file to the given directory.
     pub fn extract_elfs_to(&self, p: &Path) -> Result<()> {
         self.extract_file_to("elf/kernel", &p.join("kernel"))?;
@@ -4197,8 +4906,8 @@ impl HubrisArchive {
         Ok(())
     }
 
-    #[allow(clippy::print_literal)]
-    pub fn list_variables(&self) -> Result<()> {
+    #[allow(clippy::write_literal)]
+    pub fn list_variables(&self, out: &mut dyn std::io::Write) -> Result<()> {
         let mut variables = vec![];
 
         for (name, variable) in &self.variables {
@@ -4209,17 +4918,19 @@ impl HubrisArchive {
 
         variables.sort();
 
-        println!(
+        writeln!(
+            out,
             "{:18} {:<30} {:<10} {}",
             "MODULE", "VARIABLE", "ADDR", "SIZE"
-        );
+        )?;
 
         for v in variables {
             let task = &self.lookup_module(v.0)?.name;
-            println!(
+            writeln!(
+                out,
                 "{:18} {:<30} 0x{:08x} {:<}",
                 task, v.1, v.2.addr, v.2.size
-            );
+            )?;
         }
         Ok(())
     }
@@ -4272,6 +4983,10 @@ impl HubrisArchive {
         self.manifest.peripherals_byaddr.get(&addr)
     }
 
+    pub fn lookup_gpio_pin(&self, name: &str) -> Option<&HubrisGpioPin> {
+        self.manifest.gpio_pins.iter().find(|p| p.name == name)
+    }
+
     pub fn lookup_i2c_bus(&self, bus: &str) -> Result<&HubrisI2cBus> {
         self.manifest
             .i2c_buses
@@ -4463,6 +5178,16 @@ impl fmt::Display for HubrisGoff {
     }
 }
 
+///
+/// A format string interned by a task for deferred formatting, along with
+/// its tag (e.g., the level of a log message, or `timestamp`).
+///
+#[derive(Clone, Debug)]
+pub struct HubrisDeferred {
+    pub tag: String,
+    pub format: String,
+}
+
 #[derive(Clone, Debug)]
 pub struct HubrisSymbol {
     pub addr: u32,
@@ -4539,6 +5264,39 @@ pub struct HubrisVariable {
     pub size: usize,
 }
 
+///
+/// Options for [`HubrisArchive::dump`].
+///
+#[derive(Clone, Debug, Default)]
+pub struct HubrisDumpOptions {
+    /// Device memory (e.g., peripheral registers) to include in the dump, as
+    /// pairs of base address and size.  Note that reading device memory can
+    /// have side effects!
+    pub device: Vec<(u32, u32)>,
+    /// Compress the dump with gzip
+    pub compress: bool,
+}
+
+///
+/// Reads the contents of a dump, decompressing it if needed.
+///
+pub fn read_dump(dumpfile: &str) -> Result<Vec<u8>> {
+    let contents = fs::read(dumpfile)
+        .with_context(|| format!("failed to read dump {}", dumpfile))?;
+
+    if contents.starts_with(&GZIP_MAGIC) {
+        let mut gz = flate2::read::GzDecoder::new(contents.as_slice());
+        let mut decompressed = vec![];
+
+        gz.read_to_end(&mut decompressed)
+            .with_context(|| format!("failed to decompress {}", dumpfile))?;
+
+        Ok(decompressed)
+    } else {
+        Ok(contents)
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct HubrisArray {
     pub goff: HubrisGoff,
@@ -4891,6 +5649,8 @@ pub struct HubrisModule {
     pub memsize: u32,
     pub heapbss: (Option<u32>, Option<u32>),
     pub iface: Option<Interface>,
+    /// Hash of the module's text, as computed by [`text_hash`]
+    pub texthash: u64,
 }
 
 impl HubrisModule {
@@ -4983,6 +5743,35 @@ impl HubrisPrintFormat {
     }
 }
 
+///
+/// Computes the hash used to compare module text between an archive and a
+/// target:  a 64-bit FNV-1a hash.  This is not a cryptographic hash; it is
+/// used only to detect that text differs.
+///
+pub fn text_hash(text: &[u8]) -> u64 {
+    text.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// The result of comparing a module's text in the archive to the target.
+#[derive(Clone, Debug)]
+pub struct HubrisTextCheck {
+    pub name: String,
+    pub task: HubrisTask,
+    /// Hash of the text in the archive
+    pub archive: u64,
+    /// Hash of the text on the target, or the error encountered reading it
+    pub target: std::result::Result<u64, String>,
+}
+
+impl HubrisTextCheck {
+    /// Returns true if the text on the target matches the archive.
+    pub fn matches(&self) -> bool {
+        self.target.as_ref().map_or(false, |&t| t == self.archive)
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum HubrisValidate {
     ArchiveMatch,
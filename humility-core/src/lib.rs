@@ -2,6 +2,45 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//!
+//! The core of Humility:  parsing of Hubris archives and dumps, and
+//! attachment to a target via a debug probe.  This crate is usable as a
+//! library by programs other than Humility; it keeps no global state, and
+//! (with the exception of the [`msg!`] macro, which is for consumers'
+//! use) does not print.  Functionality that renders output, like
+//! [`hubris::HubrisArchive::manifest`], writes to a caller-supplied writer.
+//!
+//! The principal abstractions are:
+//!
+//! - [`hubris::HubrisArchive`], a loaded archive (or dump), which can be
+//!   queried for tasks, variables, types, the manifest and the like
+//! - [`core::Core`], a target (or dump) that can be read from and written
+//!   to, as returned by [`core::attach`] or [`core::attach_dump`]
+//!
+//! For example, to read a variable from a live target:
+//!
+//! ```no_run
+//! use humility::core;
+//! use humility::hubris::*;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let mut hubris = HubrisArchive::new()?;
+//! hubris.load("build-gimlet.zip", HubrisArchiveDoneness::Cook)?;
+//!
+//! let mut attached = core::attach("auto", &hubris)?;
+//! let core = attached.core.as_mut();
+//! hubris.validate(core, HubrisValidate::ArchiveMatch)?;
+//!
+//! let variable = hubris.lookup_variable("CLOCK_FREQ_KHZ")?;
+//! println!("{}", core.read_word_32(variable.addr)?);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! For interacting with Hubris tasks via HIF and Idol, see the
+//! `humility-cmd` crate.
+//!
+
 pub mod arch;
 pub mod core;
 pub mod hubris;
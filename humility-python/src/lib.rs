@@ -0,0 +1,467 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Python bindings for Humility, exposing Hubris archives ([`Archive`]) and
+//! the targets that run them ([`Target`]):  memory and variable access,
+//! Idol calls via HIF, and sensor readings.  See README.md for use.
+//!
+
+use anyhow::bail;
+use hif::*;
+use humility_cmd::hiffy::HiffyContext;
+use humility_cmd::idol::{IdolArgument, IdolOperation};
+use humility_cmd::reflect::{self, Base, Value};
+use humility_core::core::Core;
+use humility_core::hubris::*;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyTuple};
+
+create_exception!(humility, HumilityError, PyException);
+create_exception!(humility, IdolError, HumilityError);
+
+fn error(err: anyhow::Error) -> PyErr {
+    HumilityError::new_err(format!("{:?}", err))
+}
+
+/// A Hubris archive, loaded from an archive file or from a dump
+#[pyclass(unsendable)]
+struct Archive {
+    hubris: HubrisArchive,
+}
+
+impl Archive {
+    fn load(path: &str, dump: bool) -> PyResult<Self> {
+        let mut hubris = HubrisArchive::new().map_err(error)?;
+
+        if dump {
+            hubris.load_dump(path, HubrisArchiveDoneness::Cook)
+        } else {
+            hubris.load(path, HubrisArchiveDoneness::Cook)
+        }
+        .map_err(error)?;
+
+        Ok(Self { hubris })
+    }
+}
+
+#[pymethods]
+impl Archive {
+    #[new]
+    fn new(path: &str) -> PyResult<Self> {
+        Self::load(path, false)
+    }
+
+    /// Loads the archive contained in a dump
+    #[staticmethod]
+    fn from_dump(path: &str) -> PyResult<Self> {
+        Self::load(path, true)
+    }
+
+    /// The name of the image, if known
+    #[getter]
+    fn name(&self) -> Option<String> {
+        self.hubris.manifest.name.clone()
+    }
+
+    /// The names of the tasks, in task index order
+    fn tasks(&self) -> Vec<String> {
+        (0..self.hubris.ntasks())
+            .filter_map(|i| self.hubris.task_name(i))
+            .map(|name| name.to_string())
+            .collect()
+    }
+
+    /// The sensors, as a list of dicts with name, kind and device
+    fn sensors(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        self.hubris
+            .manifest
+            .sensors
+            .iter()
+            .map(|s| {
+                let dict = PyDict::new(py);
+                dict.set_item("name", &s.name)?;
+                dict.set_item("kind", s.kind.to_string())?;
+                dict.set_item("device", s.device)?;
+                Ok(dict.into())
+            })
+            .collect()
+    }
+
+    /// The I2C devices, as a list of dicts describing each
+    fn i2c_devices(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        self.hubris
+            .manifest
+            .i2c_devices
+            .iter()
+            .map(|d| {
+                let dict = PyDict::new(py);
+                dict.set_item("device", &d.device)?;
+                dict.set_item("name", &d.name)?;
+                dict.set_item("controller", d.controller)?;
+                dict.set_item("port", &d.port.name)?;
+                dict.set_item("mux", d.mux)?;
+                dict.set_item("segment", d.segment)?;
+                dict.set_item("address", d.address)?;
+                dict.set_item("description", &d.description)?;
+                Ok(dict.into())
+            })
+            .collect()
+    }
+
+    /// Returns the address and size of a variable
+    fn lookup_variable(&self, name: &str) -> PyResult<(u32, usize)> {
+        let v = self.hubris.lookup_variable(name).map_err(error)?;
+        Ok((v.addr, v.size))
+    }
+}
+
+///
+/// Converts a value loaded from the target into its natural Python
+/// equivalent:  structs become dicts, tuples become tuples, arrays become
+/// lists, enums without payloads become their variant name (and those with
+/// payloads a dict mapping the variant name to the payload), and `Option`s
+/// become either their contents or `None`.
+///
+fn value(py: Python, val: &Value) -> PyResult<PyObject> {
+    Ok(match val {
+        Value::Base(base) => match *base {
+            Base::I8(v) => v.into_py(py),
+            Base::I16(v) => v.into_py(py),
+            Base::I32(v) => v.into_py(py),
+            Base::I64(v) => v.into_py(py),
+            Base::I128(v) => v.into_py(py),
+            Base::U0 => py.None(),
+            Base::U8(v) => v.into_py(py),
+            Base::U16(v) => v.into_py(py),
+            Base::U32(v) => v.into_py(py),
+            Base::U64(v) => v.into_py(py),
+            Base::U128(v) => v.into_py(py),
+            Base::Bool(v) => v.into_py(py),
+            Base::F32(v) => v.into_py(py),
+            Base::F64(v) => v.into_py(py),
+        },
+        Value::Enum(e) => match (e.disc(), e.contents()) {
+            ("None", None) => py.None(),
+            ("Some", Some(c)) => value(py, c.as_1tuple().map_err(error)?)?,
+            (disc, None) => disc.into_py(py),
+            (disc, Some(c)) => {
+                let dict = PyDict::new(py);
+                dict.set_item(disc, value(py, c)?)?;
+                dict.into()
+            }
+        },
+        Value::Struct(s) => {
+            let dict = PyDict::new(py);
+
+            for (name, member) in s.iter() {
+                dict.set_item(name, value(py, member)?)?;
+            }
+
+            dict.into()
+        }
+        Value::Tuple(t) => {
+            let members =
+                t.iter().map(|v| value(py, v)).collect::<PyResult<Vec<_>>>()?;
+            PyTuple::new(py, members).into()
+        }
+        Value::Array(a) => a
+            .iter()
+            .map(|v| value(py, v))
+            .collect::<PyResult<Vec<_>>>()?
+            .into_py(py),
+        Value::Ptr(p) => p.addr().into_py(py),
+    })
+}
+
+/// An owned Idol argument
+enum Argument {
+    String(String),
+    Scalar(u64),
+}
+
+/// A target (or dump) running the image in an archive
+#[pyclass(unsendable)]
+struct Target {
+    archive: Py<Archive>,
+    core: Box<dyn Core>,
+    timeout: u32,
+}
+
+impl Target {
+    fn call_op(
+        &mut self,
+        hubris: &HubrisArchive,
+        op: &IdolOperation,
+        args: &[(&str, IdolArgument)],
+    ) -> anyhow::Result<Result<Vec<u8>, String>> {
+        let core = self.core.as_mut();
+        let mut context = HiffyContext::new(hubris, core, self.timeout)?;
+        let funcs = context.functions()?;
+        let payload = op.payload(args)?;
+
+        let mut ops = vec![];
+        context.idol_call_ops(&funcs, op, &payload, &mut ops)?;
+        ops.push(Op::Done);
+
+        let mut results = context.run(core, &ops, None)?;
+
+        if results.len() != 1 {
+            bail!("unexpected results length: {:?}", results);
+        }
+
+        Ok(results.remove(0).map_err(|code| {
+            match op.error.and_then(|e| e.lookup_variant(code as u64)) {
+                Some(variant) => variant.name.to_string(),
+                None => format!("0x{:x}", code),
+            }
+        }))
+    }
+
+    fn call_value(
+        &mut self,
+        py: Python,
+        hubris: &HubrisArchive,
+        op: &IdolOperation,
+        args: &[(&str, IdolArgument)],
+    ) -> PyResult<PyObject> {
+        match self.call_op(hubris, op, args).map_err(error)? {
+            Ok(buf) => {
+                let ty = hubris.lookup_type(op.ok).map_err(error)?;
+                let val =
+                    reflect::load_value(hubris, &buf, ty, 0).map_err(error)?;
+                value(py, &val)
+            }
+            Err(err) => Err(IdolError::new_err(format!(
+                "{}.{}() failed: {}",
+                op.name.0, op.name.1, err
+            ))),
+        }
+    }
+}
+
+#[pymethods]
+impl Target {
+    ///
+    /// Attaches to the target running the image in the specified archive via
+    /// the specified probe (as with `humility -p`), or to the specified dump.
+    /// When attaching to a live target, the image is validated to match the
+    /// archive.
+    ///
+    #[new]
+    #[args(probe = "\"auto\"", dump = "None", timeout = "5000")]
+    fn new(
+        py: Python,
+        archive: Py<Archive>,
+        probe: &str,
+        dump: Option<&str>,
+        timeout: u32,
+    ) -> PyResult<Self> {
+        let attached = {
+            let archive = archive.borrow(py);
+            let hubris = &archive.hubris;
+
+            let mut attached = match dump {
+                Some(dump) => humility_core::core::attach_dump(dump, hubris),
+                None => humility_core::core::attach(probe, hubris),
+            }
+            .map_err(error)?;
+
+            hubris
+                .validate(attached.core.as_mut(), HubrisValidate::ArchiveMatch)
+                .map_err(error)?;
+
+            attached
+        };
+
+        Ok(Self { archive, core: attached.core, timeout })
+    }
+
+    /// Reads the specified number of bytes from the specified address
+    fn read<'p>(
+        &mut self,
+        py: Python<'p>,
+        addr: u32,
+        nbytes: usize,
+    ) -> PyResult<&'p PyBytes> {
+        let mut buf = vec![0u8; nbytes];
+        self.core.read_8(addr, &mut buf).map_err(error)?;
+        Ok(PyBytes::new(py, &buf))
+    }
+
+    /// Writes the specified bytes to the specified address
+    fn write(&mut self, addr: u32, data: &[u8]) -> PyResult<()> {
+        self.core.write_8(addr, data).map_err(error)
+    }
+
+    /// Reads a 32-bit word from the specified address
+    fn read_word(&mut self, addr: u32) -> PyResult<u32> {
+        self.core.read_word_32(addr).map_err(error)
+    }
+
+    /// Writes a 32-bit word to the specified address
+    fn write_word(&mut self, addr: u32, val: u32) -> PyResult<()> {
+        self.core.write_word_32(addr, val).map_err(error)
+    }
+
+    /// Reads the contents of the named variable
+    fn read_variable<'p>(
+        &mut self,
+        py: Python<'p>,
+        name: &str,
+    ) -> PyResult<&'p PyBytes> {
+        let (addr, size) = self.archive.borrow(py).lookup_variable(name)?;
+        self.read(py, addr, size)
+    }
+
+    fn halt(&mut self) -> PyResult<()> {
+        self.core.halt().map_err(error)
+    }
+
+    fn run(&mut self) -> PyResult<()> {
+        self.core.run().map_err(error)
+    }
+
+    ///
+    /// Makes an Idol call, e.g. `target.call("Sensor", "get", {"id": 3})`,
+    /// returning the reply as a Python value.  Arguments are integers,
+    /// booleans or strings (which are interpreted as `humility hiffy`
+    /// interprets them); if the call returns an error, `IdolError` is raised
+    /// with the name of the error variant.
+    ///
+    #[args(args = "None", task = "None")]
+    fn call(
+        &mut self,
+        py: Python,
+        interface: &str,
+        operation: &str,
+        args: Option<&PyDict>,
+        task: Option<&str>,
+    ) -> PyResult<PyObject> {
+        let archive = self.archive.clone_ref(py);
+        let archive = archive.borrow(py);
+        let hubris = &archive.hubris;
+
+        let task = match task {
+            Some(task) => Some(hubris.lookup_task(task).ok_or_else(|| {
+                HumilityError::new_err(format!("unknown task \"{}\"", task))
+            })?),
+            None => None,
+        };
+
+        let op = IdolOperation::new(hubris, interface, operation, task)
+            .map_err(error)?;
+
+        //
+        // Our string arguments borrow from strings that we must own for the
+        // duration of the call.
+        //
+        let mut owned = vec![];
+
+        if let Some(args) = args {
+            for (name, val) in args.iter() {
+                let name: String = name.extract()?;
+
+                let val = if let Ok(val) = val.extract::<bool>() {
+                    Argument::Scalar(val as u64)
+                } else if let Ok(val) = val.extract::<u64>() {
+                    Argument::Scalar(val)
+                } else {
+                    Argument::String(val.extract()?)
+                };
+
+                owned.push((name, val));
+            }
+        }
+
+        let args = owned
+            .iter()
+            .map(|(name, val)| {
+                let arg = match val {
+                    Argument::String(s) => IdolArgument::String(s),
+                    Argument::Scalar(v) => IdolArgument::Scalar(*v),
+                };
+                (name.as_str(), arg)
+            })
+            .collect::<Vec<_>>();
+
+        self.call_value(py, hubris, &op, &args)
+    }
+
+    ///
+    /// Reads a sensor, specified by name or by index (as displayed by
+    /// `humility sensors -l`).  If a name matches more than one sensor, the
+    /// kind of sensor (e.g., "temp") must also be specified.
+    ///
+    #[args(kind = "None")]
+    fn read_sensor(
+        &mut self,
+        py: Python,
+        sensor: &PyAny,
+        kind: Option<&str>,
+    ) -> PyResult<PyObject> {
+        let archive = self.archive.clone_ref(py);
+        let archive = archive.borrow(py);
+        let hubris = &archive.hubris;
+        let sensors = &hubris.manifest.sensors;
+
+        let index = if let Ok(index) = sensor.extract::<usize>() {
+            if index >= sensors.len() {
+                return Err(HumilityError::new_err(format!(
+                    "sensor index {} out of range",
+                    index
+                )));
+            }
+
+            index
+        } else {
+            let name: String = sensor.extract()?;
+
+            let matches = sensors
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| s.name == name)
+                .filter(|(_, s)| kind.map_or(true, |k| s.kind.to_string() == k))
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+
+            match matches[..] {
+                [index] => index,
+                [] => {
+                    return Err(HumilityError::new_err(format!(
+                        "no sensor \"{}\"",
+                        name
+                    )));
+                }
+                _ => {
+                    return Err(HumilityError::new_err(format!(
+                        "sensor \"{}\" is ambiguous; specify a kind",
+                        name
+                    )));
+                }
+            }
+        };
+
+        let op =
+            IdolOperation::new(hubris, "Sensor", "get", None).map_err(error)?;
+
+        self.call_value(
+            py,
+            hubris,
+            &op,
+            &[("id", IdolArgument::Scalar(index as u64))],
+        )
+    }
+}
+
+#[pymodule]
+fn humility(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Archive>()?;
+    m.add_class::<Target>()?;
+    m.add("HumilityError", py.get_type::<HumilityError>())?;
+    m.add("IdolError", py.get_type::<IdolError>())?;
+    Ok(())
+}
@@ -0,0 +1,200 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Caching of the output of commands that consult only the archive.  Loading
+//! an archive dominates the run time of such commands, so when they are run
+//! repeatedly (e.g., from a script), their output can be cached on disk,
+//! keyed by the archive (or dump) -- as identified by a hash of its
+//! contents -- and by the command line.  On a subsequent
+//! invocation with the same archive and command line, the cached output is
+//! emitted without loading the archive at all.  `--refresh` bypasses the
+//! cache (and updates it).
+//!
+//! Caching is opt-in:  it is enabled only when `$HUMILITY_CACHE` is set to
+//! a (non-empty) directory.  Only standard output is cached (that is,
+//! messages on standard error are not replayed), and only for commands that
+//! succeed.  Entries are evicted a week after they are written.
+//!
+
+use anyhow::{Context, Result};
+use humility_cmd::{style, Args, Command};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::cmd;
+
+//
+// The commands whose output may be cached.  Commands that are not attached
+// are cached when run against an archive or a dump; commands that are
+// attached are cached only when run against a dump.
+//
+const CACHEABLE: &[&str] =
+    &["apptable", "manifest", "map", "readvar", "stackmargin"];
+
+//
+// The age at which cached output is evicted.
+//
+const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+fn directory() -> Option<PathBuf> {
+    match std::env::var_os("HUMILITY_CACHE") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => None,
+    }
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+///
+/// Returns the path of the cached output of the specified command, if it is
+/// cacheable.
+///
+fn path(
+    commands: &HashMap<&'static str, Command>,
+    args: &Args,
+    subargs: &[String],
+) -> Result<Option<PathBuf>> {
+    if !CACHEABLE.contains(&subargs[0].as_str()) {
+        return Ok(None);
+    }
+
+    let file = match commands.get(subargs[0].as_str()) {
+        Some(Command::Unattached { .. }) => {
+            args.archive.as_ref().or(args.dump.as_ref())
+        }
+        Some(Command::Attached { .. }) => args.dump.as_ref(),
+        _ => None,
+    };
+
+    let (file, dir) = match (file, directory()) {
+        (Some(file), Some(dir)) => (file, dir),
+        _ => return Ok(None),
+    };
+
+    //
+    // We identify the archive by its contents rather than by its path:  an
+    // archive that is rebuilt in place is a different archive, while a copy
+    // of an archive is the same one.  Hashing the archive is much cheaper
+    // than loading it.
+    //
+    let contents =
+        fs::read(file).with_context(|| format!("failed to read {}", file))?;
+
+    //
+    // Our key is everything that can affect the command's output:  the
+    // archive, our own version, the command line, and the global options
+    // that determine how output is formatted.
+    //
+    let mut key = Sha256::new();
+    key.update(Sha256::digest(&contents));
+    key.update(env!("CARGO_PKG_VERSION"));
+
+    for arg in subargs {
+        key.update([0]);
+        key.update(arg);
+    }
+
+    key.update(format!(
+        "\0{:?}\0{}\0{}",
+        args.format,
+        style::enabled(),
+        args.allow_mismatch
+    ));
+
+    Ok(Some(dir.join(hex(&key.finalize()))))
+}
+
+///
+/// Removes any cached output that has reached its maximum age.
+///
+fn evict(dir: &Path) -> Result<()> {
+    let now = SystemTime::now();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        let modified = entry.metadata()?.modified()?;
+
+        if now.duration_since(modified).unwrap_or_default() >= MAX_AGE {
+            log::trace!("evicting {}", entry.path().display());
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Runs a subcommand, emitting its cached output if it has any.
+///
+pub fn subcommand(
+    commands: &HashMap<&'static str, Command>,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    //
+    // A command that captures I2C transactions must actually run.
+    //
+    let path = if args.capture_i2c.is_some() {
+        None
+    } else {
+        path(commands, args, subargs)?
+    };
+
+    let path = match path {
+        Some(path) => path,
+        None => return cmd::subcommand(commands, args, subargs),
+    };
+
+    if !args.refresh {
+        if let Ok(output) = fs::read(&path) {
+            log::trace!("emitting cached output from {}", path.display());
+            std::io::stdout().write_all(&output)?;
+            return Ok(());
+        }
+    }
+
+    //
+    // Our output is buffered; flush it on either side of the redirection to
+    // capture precisely the command's output.
+    //
+    std::io::stdout().flush()?;
+
+    let mut redirect =
+        gag::BufferRedirect::stdout().context("failed to capture output")?;
+
+    let rval = cmd::subcommand(commands, args, subargs);
+    std::io::stdout().flush()?;
+
+    let mut output = vec![];
+    redirect.read_to_end(&mut output)?;
+    drop(redirect);
+
+    std::io::stdout().write_all(&output)?;
+
+    if rval.is_ok() {
+        let dir = path.parent().unwrap();
+
+        if let Err(err) =
+            fs::create_dir_all(dir).and_then(|_| fs::write(&path, &output))
+        {
+            log::warn!("failed to cache output to {}: {}", path.display(), err);
+        } else if let Err(err) = evict(dir) {
+            log::warn!("failed to evict from {}: {}", dir.display(), err);
+        }
+    }
+
+    rval
+}
@@ -4,11 +4,16 @@
 
 use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
+use clap::CommandFactory;
 use humility::hubris::*;
 use humility_cmd::Args;
 use humility_cmd::{Archive, Command};
 use std::collections::HashMap;
 
+use crate::completions;
+use crate::script;
+use crate::shell;
+
 //
 // Our build.rs creates cmds.rs, which looks at our workspace to assemble
 // the commands, and creates a function (`dcmds`) that we call to get
@@ -36,18 +41,64 @@ pub fn init(
         rval = rval.subcommand(subcmd.after_help(dcmd.docmsg));
     }
 
+    //
+    // The shell, scripts and completions are not commands in their own
+    // right (they need the other commands to be able to do anything), so we
+    // add them here.
+    //
+    rval = rval.subcommand(completions::CompletionsArgs::command());
+    rval = rval.subcommand(shell::ShellArgs::command());
+    rval = rval.subcommand(script::ScriptArgs::command());
+
     (cmds, rval)
 }
 
+///
+/// Creates a Hubris archive, loading it from the archive or dump specified
+/// on the command line (if any) as the command's disposition dictates.
+///
+pub fn load(
+    args: &Args,
+    archive: Archive,
+    doneness: HubrisArchiveDoneness,
+) -> Result<HubrisArchive> {
+    let mut hubris = HubrisArchive::new().context("failed to initialize")?;
+
+    if archive != Archive::Ignored {
+        if let Some(archive) = &args.archive {
+            hubris.load(archive, doneness).with_context(|| {
+                format!("failed to load archive \"{}\"", archive)
+            })?;
+        } else if let Some(dump) = &args.dump {
+            hubris
+                .load_dump(dump, doneness)
+                .with_context(|| format!("failed to load dump \"{}\"", dump))?;
+        }
+    }
+
+    if archive == Archive::Required
+        && doneness == HubrisArchiveDoneness::Cook
+        && !hubris.loaded()
+    {
+        bail!("must provide a Hubris archive or dump");
+    }
+
+    Ok(hubris)
+}
+
 pub fn subcommand(
     commands: &HashMap<&'static str, Command>,
     args: &Args,
     subargs: &[String],
 ) -> Result<()> {
-    if let Some(command) = commands.get(&subargs[0].as_str()) {
-        let mut hubris =
-            HubrisArchive::new().context("failed to initialize")?;
+    match subargs[0].as_str() {
+        "shell" => return shell::shell(commands, args, subargs),
+        "run-script" => return script::run(commands, args, subargs),
+        "completions" => return completions::completions(args, subargs),
+        _ => {}
+    }
 
+    if let Some(command) = commands.get(&subargs[0].as_str()) {
         let (archive, doneness) = match command {
             Command::Attached { archive, .. } => {
                 (*archive, HubrisArchiveDoneness::Cook)
@@ -60,24 +111,7 @@ pub fn subcommand(
             }
         };
 
-        if archive != Archive::Ignored {
-            if let Some(archive) = &args.archive {
-                hubris.load(archive, doneness).with_context(|| {
-                    format!("failed to load archive \"{}\"", archive)
-                })?;
-            } else if let Some(dump) = &args.dump {
-                hubris.load_dump(dump, doneness).with_context(|| {
-                    format!("failed to load dump \"{}\"", dump)
-                })?;
-            }
-        }
-
-        if archive == Archive::Required
-            && doneness == HubrisArchiveDoneness::Cook
-            && !hubris.loaded()
-        {
-            bail!("must provide a Hubris archive or dump");
-        }
+        let mut hubris = load(args, archive, doneness)?;
 
         match command {
             Command::Attached { run, attach, validate, .. } => {
@@ -0,0 +1,371 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! `humility completions` emits shell completions for Humility and all of
+//! its commands.  In addition to the static completions generated by clap,
+//! the completions for bash, zsh and fish complete the values of arguments
+//! that name tasks, sensors, rails, I2C devices and I2C buses by running
+//! `humility completions --names <kind>`, which lists the names of that kind
+//! in the archive (or dump) specified in the environment.
+//!
+
+use anyhow::{bail, Result};
+use clap::{ArgEnum, CommandFactory, Parser};
+use clap_complete::Shell;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args};
+use std::collections::BTreeSet;
+use std::io;
+
+use crate::cmd;
+
+/// generate shell completions
+#[derive(Parser, Debug)]
+#[clap(name = "completions")]
+pub struct CompletionsArgs {
+    /// list names of the specified kind from the archive
+    #[clap(
+        long,
+        arg_enum,
+        hide = true,
+        value_name = "kind",
+        conflicts_with = "shell"
+    )]
+    names: Option<Names>,
+
+    /// shell for which to generate completions
+    #[clap(arg_enum, required_unless_present = "names")]
+    shell: Option<Shell>,
+}
+
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum Names {
+    Tasks,
+    Sensors,
+    Rails,
+    Devices,
+    Buses,
+}
+
+impl Names {
+    fn name(&self) -> String {
+        self.to_possible_value().unwrap().get_name().to_string()
+    }
+
+    ///
+    /// Determines the kind of name that an argument takes, if any.  This is
+    /// necessarily heuristic, and is based on argument and value names.
+    ///
+    fn of(arg: &clap::Arg) -> Option<Names> {
+        let value = arg.get_value_names().and_then(|v| v.first().copied());
+
+        match (arg.get_name(), value) {
+            ("task", _) | (_, Some("task")) => Some(Names::Tasks),
+            (_, Some("sensor name")) => Some(Names::Sensors),
+            (_, Some("rail")) => Some(Names::Rails),
+            (_, Some("device")) => Some(Names::Devices),
+            (_, Some("bus")) => Some(Names::Buses),
+            _ => None,
+        }
+    }
+
+    fn list(&self, hubris: &HubrisArchive) -> BTreeSet<String> {
+        let manifest = &hubris.manifest;
+
+        match self {
+            Names::Tasks => (0..hubris.ntasks())
+                .filter_map(|i| hubris.task_name(i))
+                .map(|name| name.to_string())
+                .collect(),
+            Names::Sensors => {
+                manifest.sensors.iter().map(|s| s.name.clone()).collect()
+            }
+            Names::Rails => manifest
+                .i2c_devices
+                .iter()
+                .flat_map(|d| match &d.class {
+                    HubrisI2cDeviceClass::Pmbus { rails } => rails.clone(),
+                    _ => vec![],
+                })
+                .collect(),
+            Names::Devices => {
+                manifest.i2c_devices.iter().map(|d| d.device.clone()).collect()
+            }
+            Names::Buses => manifest
+                .i2c_buses
+                .iter()
+                .filter_map(|b| b.name.clone())
+                .collect(),
+        }
+    }
+}
+
+///
+/// An argument of a command whose value is a name that can be completed
+/// dynamically.
+///
+struct Dynamic {
+    command: String,
+    short: Option<char>,
+    long: Option<String>,
+    names: Names,
+}
+
+impl Dynamic {
+    fn find(clap: &clap::Command) -> Vec<Dynamic> {
+        let mut rval = vec![];
+
+        for sub in clap.get_subcommands() {
+            for arg in sub.get_arguments() {
+                if let Some(names) = Names::of(arg) {
+                    rval.push(Dynamic {
+                        command: sub.get_name().to_string(),
+                        short: arg.get_short(),
+                        long: arg.get_long().map(|l| l.to_string()),
+                        names,
+                    });
+                }
+            }
+        }
+
+        rval
+    }
+
+    fn positional(&self) -> bool {
+        self.short.is_none() && self.long.is_none()
+    }
+
+    fn flags(&self) -> Vec<String> {
+        let mut flags = vec![];
+
+        if let Some(short) = self.short {
+            flags.push(format!("-{}", short));
+        }
+
+        if let Some(long) = &self.long {
+            flags.push(format!("--{}", long));
+        }
+
+        flags
+    }
+}
+
+//
+// Global options that take a value, which must be skipped when looking for
+// the command in the words on the command line.
+//
+const GLOBAL_VALUES: &str = "-p|--probe|-a|--archive|-d|--dump|--target|\
+    --fleet|--jobs|--rot|-F|--format";
+
+///
+/// Returns the `case` arms (for bash and zsh) that determine the kind of name
+/// to complete:  first, those that match a command and the preceding option,
+/// and then those that match only a command (for positional arguments).
+///
+fn cases(dynamic: &[Dynamic]) -> (String, String) {
+    let mut cases = String::new();
+    let mut positional = String::new();
+
+    for d in dynamic {
+        if d.positional() {
+            positional += &format!(
+                "                {}) kind={} ;;\n",
+                d.command,
+                d.names.name()
+            );
+        } else {
+            let pattern = d
+                .flags()
+                .iter()
+                .map(|f| format!("\"{} {}\"", d.command, f))
+                .collect::<Vec<_>>()
+                .join("|");
+
+            cases += &format!(
+                "            {}) kind={} ;;\n",
+                pattern,
+                d.names.name()
+            );
+        }
+    }
+
+    (cases, positional)
+}
+
+fn bash(dynamic: &[Dynamic]) -> String {
+    let (cases, positional) = cases(dynamic);
+
+    format!(
+        r##"
+_humility_dynamic() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    local cmd="" kind="" skip=0 i
+
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        if [[ $skip == 1 ]]; then
+            skip=0
+            continue
+        fi
+
+        case "${{COMP_WORDS[i]}}" in
+            {globals}) skip=1 ;;
+            -*) ;;
+            *) cmd="${{COMP_WORDS[i]}}"; break ;;
+        esac
+    done
+
+    if [[ -n "$cmd" ]]; then
+        case "$cmd $prev" in
+{cases}        esac
+
+        if [[ -z "$kind" && "$prev" != -* && "$cur" != -* ]]; then
+            case "$cmd" in
+{positional}            esac
+        fi
+    fi
+
+    if [[ -n "$kind" ]]; then
+        COMPREPLY=($(compgen -W \
+            "$(humility completions --names $kind 2>/dev/null)" -- "$cur"))
+        return 0
+    fi
+
+    _humility "$@"
+}}
+
+complete -F _humility_dynamic -o bashdefault -o default humility
+"##,
+        globals = GLOBAL_VALUES,
+        cases = cases,
+        positional = positional,
+    )
+}
+
+fn zsh(dynamic: &[Dynamic]) -> String {
+    let (cases, positional) = cases(dynamic);
+
+    format!(
+        r##"
+_humility_dynamic() {{
+    local cmd="" kind="" skip=0 i
+    local prev="${{words[CURRENT-1]}}"
+
+    for ((i = 2; i < CURRENT; i++)); do
+        if [[ $skip == 1 ]]; then
+            skip=0
+            continue
+        fi
+
+        case "${{words[i]}}" in
+            {globals}) skip=1 ;;
+            -*) ;;
+            *) cmd="${{words[i]}}"; break ;;
+        esac
+    done
+
+    if [[ -n "$cmd" ]]; then
+        case "$cmd $prev" in
+{cases}        esac
+
+        local cur="${{words[CURRENT]}}"
+
+        if [[ -z "$kind" && "$prev" != -* && "$cur" != -* ]]; then
+            case "$cmd" in
+{positional}            esac
+        fi
+    fi
+
+    if [[ -n "$kind" ]]; then
+        compadd -- ${{(f)"$(humility completions --names $kind 2>/dev/null)"}}
+        return 0
+    fi
+
+    _humility "$@"
+}}
+
+compdef _humility_dynamic humility
+"##,
+        globals = GLOBAL_VALUES,
+        cases = cases,
+        positional = positional,
+    )
+}
+
+fn fish(dynamic: &[Dynamic]) -> String {
+    let mut rval = String::new();
+
+    for d in dynamic {
+        let mut line = format!(
+            "complete -c humility -n \"__fish_seen_subcommand_from {}\"",
+            d.command
+        );
+
+        if let Some(short) = d.short {
+            line += &format!(" -s {}", short);
+        }
+
+        if let Some(long) = &d.long {
+            line += &format!(" -l {}", long);
+        }
+
+        if !d.positional() {
+            line += " -r";
+        }
+
+        line += &format!(
+            " -f -a \"(humility completions --names {} 2>/dev/null)\"\n",
+            d.names.name()
+        );
+
+        rval += &line;
+    }
+
+    rval
+}
+
+pub fn completions(args: &Args, subargs: &[String]) -> Result<()> {
+    let subargs = CompletionsArgs::try_parse_from(subargs)?;
+
+    if let Some(names) = subargs.names {
+        //
+        // This is run by the shell as the user types; if there is no archive,
+        // we simply have no names to offer.
+        //
+        let hubris =
+            cmd::load(args, Archive::Optional, HubrisArchiveDoneness::Cook)?;
+
+        for name in names.list(&hubris) {
+            println!("{}", name);
+        }
+
+        return Ok(());
+    }
+
+    let shell = match subargs.shell {
+        Some(shell) => shell,
+        None => bail!("must specify a shell"),
+    };
+
+    //
+    // We need the full command tree, including all of our commands, to
+    // generate completions.
+    //
+    let (_, mut clap) = cmd::init(Args::command());
+    let bin = clap.get_name().to_string();
+
+    clap_complete::generate(shell, &mut clap, bin, &mut io::stdout());
+
+    let dynamic = Dynamic::find(&clap);
+
+    match shell {
+        Shell::Bash => print!("{}", bash(&dynamic)),
+        Shell::Zsh => print!("{}", zsh(&dynamic)),
+        Shell::Fish => print!("{}", fish(&dynamic)),
+        _ => {}
+    }
+
+    Ok(())
+}
@@ -0,0 +1,268 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Running a command against a fleet of targets.  The fleet is a file of
+//! named targets in the same form as `humility.toml`; the command is run
+//! against each of them (by running Humility with `--target`), with at most
+//! `--jobs` running at once.  Output is emitted once all targets have
+//! completed, keyed by target: in a table, each line of output is prefixed
+//! with the name of the target; in CSV, the target is the first column; and
+//! in JSON, each object has a `target` member.
+//!
+//! Only commands that do not modify the target can be run against a fleet.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use humility_cmd::error::ErrorKind;
+use humility_cmd::output::OutputFormat;
+use humility_cmd::{Args, Command};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use crate::target;
+
+//
+// The commands that can be run against a fleet.  Note that commands that
+// poll until interrupted (e.g., `tasks --spin` or `ringbuf --follow`) will
+// never complete.
+//
+pub const READ_ONLY: &[&str] = &[
+    "doctor",
+    "fault",
+    "irqs",
+    "manifest",
+    "map",
+    "net",
+    "probe",
+    "readmem",
+    "readvar",
+    "registers",
+    "ringbuf",
+    "sensors",
+    "spd",
+    "stackmargin",
+    "tasks",
+    "validate",
+];
+
+struct Outcome {
+    ok: bool,
+    stdout: String,
+    stderr: String,
+}
+
+struct Invocation {
+    exe: PathBuf,
+    fleet: String,
+    format: OutputFormat,
+    archive: Option<String>,
+    subargs: Vec<String>,
+}
+
+fn invoke(invocation: &Invocation, name: &str) -> Result<Outcome> {
+    let exe = &invocation.exe;
+
+    let format = match invocation.format {
+        OutputFormat::Table => "table",
+        OutputFormat::Json => "json",
+        OutputFormat::Csv => "csv",
+    };
+
+    let mut cmd = process::Command::new(exe);
+    cmd.env("HUMILITY_CONFIG", &invocation.fleet);
+    cmd.arg("--target").arg(name).arg("--format").arg(format);
+
+    if let Some(archive) = &invocation.archive {
+        cmd.arg("--archive").arg(archive);
+    }
+
+    let output = cmd
+        .args(&invocation.subargs)
+        .stdin(process::Stdio::null())
+        .output()
+        .with_context(|| format!("failed to run {}", exe.display()))?;
+
+    Ok(Outcome {
+        ok: output.status.success(),
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+    })
+}
+
+fn emit(format: OutputFormat, width: usize, name: &str, stdout: &str) {
+    let mut lines = stdout.lines();
+
+    match format {
+        OutputFormat::Table => {
+            for line in lines {
+                println!("{:width$} {}", name, line, width = width);
+            }
+        }
+        OutputFormat::Csv => {
+            //
+            // Each target emits its own header; we have already emitted a
+            // header (with the target column) for the first.
+            //
+            lines.next();
+
+            for line in lines {
+                println!("{},{}", name, line);
+            }
+        }
+        OutputFormat::Json => {
+            let target = serde_json::Value::from(name);
+
+            for line in lines {
+                match line.strip_prefix('{') {
+                    Some(rest) if rest.trim() == "}" => {
+                        println!("{{\"target\":{}}}", target)
+                    }
+                    Some(rest) => println!("{{\"target\":{},{}", target, rest),
+                    None => println!("{}", line),
+                }
+            }
+        }
+    }
+}
+
+///
+/// Runs the specified command against every target in the fleet.  As with
+/// `--target`, an archive given on the command line applies to every
+/// target.
+///
+pub fn run(
+    commands: &HashMap<&'static str, Command>,
+    args: &Args,
+    archive: Option<&str>,
+    subargs: &[String],
+) -> Result<()> {
+    let fleet = args.fleet.as_ref().unwrap();
+    let command = subargs[0].as_str();
+
+    if !commands.contains_key(command) || !READ_ONLY.contains(&command) {
+        return Err(ErrorKind::Usage.error(format!(
+            "{} cannot be run against a fleet; commands that can: {}",
+            command,
+            READ_ONLY.join(", ")
+        )));
+    }
+
+    if args.jobs == 0 {
+        return Err(ErrorKind::Usage.error("--jobs must be at least 1"));
+    }
+
+    let names = target::names(Path::new(fleet))?;
+
+    if names.is_empty() {
+        bail!("no targets found in {}", fleet);
+    }
+
+    let invocation = Arc::new(Invocation {
+        exe: std::env::current_exe()
+            .context("failed to determine path to humility")?,
+        fleet: fleet.clone(),
+        format: args.format,
+        archive: archive.map(|a| a.to_string()),
+        subargs: subargs.to_vec(),
+    });
+
+    //
+    // Each worker takes the next target from the queue until it is empty,
+    // sending each outcome back to us.
+    //
+    let queue = Arc::new(Mutex::new(names.clone().into_iter()));
+    let (tx, rx) = mpsc::channel();
+
+    let workers: Vec<_> = (0..args.jobs.min(names.len()))
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+            let invocation = Arc::clone(&invocation);
+            let tx = tx.clone();
+
+            thread::spawn(move || loop {
+                let name = match queue.lock().unwrap().next() {
+                    Some(name) => name,
+                    None => break,
+                };
+
+                let outcome = invoke(&invocation, &name);
+
+                if tx.send((name, outcome)).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect();
+
+    drop(tx);
+
+    let outcomes: BTreeMap<String, Result<Outcome>> = rx.iter().collect();
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    let width = names.iter().map(|n| n.len()).max().unwrap_or(0);
+
+    let mut header = false;
+    let mut failed = vec![];
+
+    for (name, outcome) in &outcomes {
+        let outcome = match outcome {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                failed.push((name, format!("{:?}", err)));
+                continue;
+            }
+        };
+
+        if args.format == OutputFormat::Csv && !header {
+            if let Some(first) = outcome.stdout.lines().next() {
+                println!("target,{}", first);
+                header = true;
+            }
+        }
+
+        emit(args.format, width, name, &outcome.stdout);
+
+        if !outcome.ok {
+            //
+            // Humility's summary of the error is the line that reports the
+            // failure, which may be followed by its causes.
+            //
+            let reason = outcome
+                .stderr
+                .lines()
+                .find(|l| l.starts_with("humility ") && l.contains(" failed"))
+                .or_else(|| outcome.stderr.lines().last())
+                .unwrap_or("failed")
+                .to_string();
+
+            failed.push((name, reason));
+        } else if args.verbose {
+            for line in outcome.stderr.lines() {
+                humility::msg!("{}: {}", name, line);
+            }
+        }
+    }
+
+    for (name, reason) in &failed {
+        humility::msg!("{}: {}", name, reason);
+    }
+
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "{} failed on {} of {} targets",
+            command,
+            failed.len(),
+            names.len()
+        ));
+    }
+
+    Ok(())
+}
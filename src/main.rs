@@ -2,13 +2,65 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use humility_cmd::capture;
+use humility_cmd::error::{self, ErrorKind};
+use humility_cmd::style::{self, Severity};
 use humility_cmd::{Args, Subcommand};
 
 use clap::CommandFactory;
 use clap::FromArgMatches;
 use clap::Parser;
 
+mod cache;
 mod cmd;
+mod completions;
+mod fleet;
+mod pair;
+mod script;
+mod shell;
+mod target;
+
+///
+/// Reports a fatal error and exits with the exit code that corresponds to
+/// its kind.
+///
+fn fail(args: &Args, command: Option<&str>, err: anyhow::Error) -> ! {
+    let kind = error::kind(&err);
+    let code = error::code(&err);
+
+    if args.json_errors {
+        let causes: Vec<_> =
+            err.chain().skip(1).map(|e| e.to_string()).collect();
+
+        let json = serde_json::json!({
+            "command": command,
+            "kind": kind.map_or("error", |k| k.name()),
+            "code": code,
+            "message": err.to_string(),
+            "causes": causes,
+        });
+
+        eprintln!("{}", json);
+    } else {
+        match command {
+            Some(command) => eprintln!(
+                "humility {} {}: {:?}",
+                command,
+                Severity::Error.paint("failed"),
+                err
+            ),
+            None => {
+                eprintln!(
+                    "humility {}: {:?}",
+                    Severity::Error.paint("failed"),
+                    err
+                )
+            }
+        }
+    }
+
+    std::process::exit(code);
+}
 
 fn main() {
     //
@@ -39,16 +91,29 @@ fn main() {
         println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"));
         std::process::exit(0);
     } else if args.cmd.is_none() {
-        eprintln!("humility failed: subcommand expected (--help to list)");
-        std::process::exit(1);
+        let err =
+            ErrorKind::Usage.error("subcommand expected (--help to list)");
+        fail(&args, None, err);
     }
 
+    style::init(args.color);
+
     let log_level = if args.verbose { "trace" } else { "warn" };
 
     let env = env_logger::Env::default().filter_or("RUST_LOG", log_level);
 
     env_logger::init_from_env(env);
 
+    //
+    // If a target has been named, its settings override those from the
+    // environment (but not those on the command line).
+    //
+    if let Some(name) = args.target.clone() {
+        if let Err(err) = target::apply(&name, &mut args, &m) {
+            fail(&args, None, err);
+        }
+    }
+
     //
     // Check to see if we have both a dump and an archive.  Because these
     // conflict with one another but because we allow both of them to be
@@ -60,17 +125,18 @@ fn main() {
         match (m.occurrences_of("dump") == 1, m.occurrences_of("archive") == 1)
         {
             (true, true) => {
-                log::error!("cannot specify both a dump and an archive");
-                std::process::exit(1);
+                let err = ErrorKind::Usage
+                    .error("cannot specify both a dump and an archive");
+                fail(&args, None, err);
             }
 
             (false, false) => {
-                log::error!(
+                let err = ErrorKind::Usage.error(
                     "both dump and archive have been set via environment \
                     variables; unset one of them, or use a command-line option \
-                    to override"
+                    to override",
                 );
-                std::process::exit(1);
+                fail(&args, None, err);
             }
 
             (true, false) => {
@@ -94,9 +160,57 @@ fn main() {
     //
     let Subcommand::Other(subargs) = args.cmd.as_ref().unwrap();
 
-    if let Err(err) = cmd::subcommand(&commands, &args, subargs) {
-        eprintln!("humility {} failed: {:?}", subargs[0], err);
-        std::process::exit(1);
+    //
+    // When running against a fleet, each target is run by a separate
+    // invocation of Humility; an archive on the command line applies to all
+    // of them.
+    //
+    if args.fleet.is_some() {
+        let archive = match m.occurrences_of("archive") {
+            0 => None,
+            _ => args.archive.as_deref(),
+        };
+
+        if let Err(err) = fleet::run(&commands, &args, archive, subargs) {
+            fail(&args, Some(&subargs[0]), err);
+        }
+
+        return;
+    }
+
+    //
+    // When running against both an SP and its RoT, each is likewise run by
+    // a separate invocation of Humility.
+    //
+    if args.rot.is_some() {
+        if let Err(err) = pair::run(&commands, &args, subargs) {
+            fail(&args, Some(&subargs[0]), err);
+        }
+
+        return;
+    }
+
+    if args.capture_i2c.is_some() {
+        capture::start();
+    }
+
+    let rval = cache::subcommand(&commands, &args, subargs);
+
+    //
+    // We save any I2C capture even if the command failed:  the transactions
+    // that led to a failure are often exactly those of interest.
+    //
+    if let Some(filename) = &args.capture_i2c {
+        match capture::save(filename) {
+            Ok(n) => {
+                humility::msg!("wrote {} I2C transactions to {}", n, filename)
+            }
+            Err(err) => fail(&args, Some(&subargs[0]), err),
+        }
+    }
+
+    if let Err(err) = rval {
+        fail(&args, Some(&subargs[0]), err);
     }
 }
 
@@ -0,0 +1,253 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Running a command against a service processor and its root of trust at
+//! once.  The RoT is a named target (see [`crate::target`]) given with
+//! `--rot`; the SP is whatever the rest of the command line specifies.  The
+//! command is run against each side concurrently (by running Humility once
+//! for each), and their output is merged onto a single timeline as it
+//! arrives:  each line is stamped with the time (in seconds since the
+//! command started) at which it was emitted and with the side that emitted
+//! it -- prefixed to each line of a table, as the first columns of CSV, or
+//! as `side` and `time` members of each JSON object.
+//!
+//! Only commands that do not modify the target can be run against both.
+//!
+
+use anyhow::{anyhow, Context, Result};
+use humility_cmd::error::ErrorKind;
+use humility_cmd::output::OutputFormat;
+use humility_cmd::{Args, Command};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use crate::fleet;
+
+//
+// In addition to the commands that can be run against a fleet, commands
+// that report on both sides of the SP/RoT boundary.
+//
+const CORRELATED: &[&str] = &["log", "versions"];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Side {
+    Sp,
+    Rot,
+}
+
+impl Side {
+    fn name(&self) -> &'static str {
+        match self {
+            Side::Sp => "sp",
+            Side::Rot => "rot",
+        }
+    }
+}
+
+enum Event {
+    Stdout(Side, f64, String),
+    Stderr(Side, String),
+}
+
+///
+/// Returns our own command line, less `--rot` and its value:  this is the
+/// command line for the SP.
+///
+fn sp_args() -> Vec<String> {
+    let mut rval = vec![];
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == "--rot" {
+            args.next();
+        } else if !arg.starts_with("--rot=") {
+            rval.push(arg);
+        }
+    }
+
+    rval
+}
+
+///
+/// Forwards each line read from the specified stream (either standard
+/// output or standard error) as an event.
+///
+fn forward<R: Read + Send + 'static>(
+    stream: R,
+    tx: mpsc::Sender<Event>,
+    start: Instant,
+    side: Side,
+    stdout: bool,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+
+            let event = if stdout {
+                Event::Stdout(side, start.elapsed().as_secs_f64(), line)
+            } else {
+                Event::Stderr(side, line)
+            };
+
+            if tx.send(event).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+fn emit(format: OutputFormat, side: Side, time: f64, line: &str) {
+    match format {
+        OutputFormat::Table => {
+            println!("{:>10.6} {:3} {}", time, side.name(), line);
+        }
+        OutputFormat::Csv => {
+            println!("{},{:.6},{}", side.name(), time, line);
+        }
+        OutputFormat::Json => {
+            let members = format!(
+                "\"side\":{},\"time\":{:.6}",
+                serde_json::Value::from(side.name()),
+                time
+            );
+
+            match line.strip_prefix('{') {
+                Some(rest) if rest.trim() == "}" => {
+                    println!("{{{}}}", members)
+                }
+                Some(rest) => println!("{{{},{}", members, rest),
+                None => println!("{}", line),
+            }
+        }
+    }
+}
+
+///
+/// Runs the specified command against both the SP and the RoT.
+///
+pub fn run(
+    commands: &HashMap<&'static str, Command>,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let rot = args.rot.as_ref().unwrap();
+    let command = subargs[0].as_str();
+
+    if !commands.contains_key(command)
+        || !(fleet::READ_ONLY.contains(&command)
+            || CORRELATED.contains(&command))
+    {
+        let mut allowed = fleet::READ_ONLY.to_vec();
+        allowed.extend_from_slice(CORRELATED);
+        allowed.sort_unstable();
+
+        return Err(ErrorKind::Usage.error(format!(
+            "{} cannot be run against both SP and RoT; commands that can: {}",
+            command,
+            allowed.join(", ")
+        )));
+    }
+
+    let exe = std::env::current_exe()
+        .context("failed to determine path to humility")?;
+
+    let format = match args.format {
+        OutputFormat::Table => "table",
+        OutputFormat::Json => "json",
+        OutputFormat::Csv => "csv",
+    };
+
+    let mut sp = process::Command::new(&exe);
+    sp.args(sp_args());
+
+    let mut rot_cmd = process::Command::new(&exe);
+    rot_cmd.arg("--target").arg(rot).arg("--format").arg(format);
+    rot_cmd.args(subargs);
+
+    let start = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    let mut children = vec![];
+    let mut forwarders = vec![];
+
+    for (side, mut cmd) in [(Side::Sp, sp), (Side::Rot, rot_cmd)] {
+        let mut child = cmd
+            .stdin(process::Stdio::null())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to run {}", exe.display()))?;
+
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+
+        forwarders.push(forward(stdout, tx.clone(), start, side, true));
+        forwarders.push(forward(stderr, tx.clone(), start, side, false));
+
+        children.push((side, child));
+    }
+
+    drop(tx);
+
+    //
+    // In CSV, each side emits its own header; we emit only the first (with
+    // our own columns prepended).
+    //
+    let mut header = [false, false];
+    let mut emitted = false;
+
+    for event in rx.iter() {
+        match event {
+            Event::Stdout(side, time, line) => {
+                if args.format == OutputFormat::Csv {
+                    let seen = &mut header[side as usize];
+
+                    if !*seen {
+                        *seen = true;
+
+                        if !emitted {
+                            println!("side,time,{}", line);
+                            emitted = true;
+                        }
+
+                        continue;
+                    }
+                }
+
+                emit(args.format, side, time, &line);
+            }
+            Event::Stderr(side, line) => {
+                let line = line.strip_prefix("humility: ").unwrap_or(&line);
+                humility::msg!("{}: {}", side.name(), line);
+            }
+        }
+    }
+
+    for forwarder in forwarders {
+        let _ = forwarder.join();
+    }
+
+    let mut failed = vec![];
+
+    for (side, mut child) in children {
+        let status = child.wait().context("failed to wait for humility")?;
+
+        if !status.success() {
+            failed.push(side.name());
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(anyhow!("{} failed on {}", command, failed.join(" and ")));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,405 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! `humility run-script` executes a file of Humility commands against a
+//! single attachment.  Each line is either a command (as it would be given
+//! to `humility shell`), a comment (starting with `#`), or one of the
+//! following directives:
+//!
+//! - `set NAME VALUE` sets a variable, which can then be referred to as
+//!   `${NAME}` anywhere on a subsequent line
+//! - `capture NAME` sets a variable to the output of the last command
+//! - `if CONDITION` ... [`else` ...] `end` executes lines conditionally,
+//!   where the condition is one of `ok` (the last command succeeded),
+//!   `failed` (the last command failed), `contains TEXT` (the output of the
+//!   last command contains the text), `A == B` or `A != B`, any of which can
+//!   be preceded with `not`
+//! - `echo TEXT` prints the text
+//! - `fail MESSAGE` stops the script with an error
+//! - `try COMMAND` executes a command, continuing if it fails
+//!
+//! A command that fails without `try` stops the script.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use crate::cmd;
+use crate::shell;
+
+/// execute a script of commands against a single attachment
+#[derive(Parser, Debug)]
+#[clap(name = "run-script")]
+pub struct ScriptArgs {
+    /// sets a variable
+    #[clap(long = "define", short = 'D', value_name = "name=value")]
+    define: Vec<String>,
+
+    /// write each command and its output to the specified file
+    #[clap(long, short, value_name = "file")]
+    output: Option<String>,
+
+    /// do not print command output
+    #[clap(long, short)]
+    quiet: bool,
+
+    /// script to execute
+    script: String,
+}
+
+#[derive(Debug)]
+enum Statement {
+    Set(String),
+    Capture(String),
+    If(String),
+    Else,
+    End,
+    Echo(String),
+    Fail(String),
+    Command { text: String, tolerate: bool },
+}
+
+struct Line {
+    lineno: usize,
+    statement: Statement,
+}
+
+fn parse(contents: &str) -> Result<Vec<Line>> {
+    let mut lines = vec![];
+    let mut depth = vec![];
+
+    for (ndx, raw) in contents.lines().enumerate() {
+        let lineno = ndx + 1;
+        let text = raw.trim();
+
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, rest) = match text.split_once(char::is_whitespace) {
+            Some((keyword, rest)) => (keyword, rest.trim().to_string()),
+            None => (text, String::new()),
+        };
+
+        let statement = match keyword {
+            "set" => Statement::Set(rest),
+            "capture" => Statement::Capture(rest),
+            "if" => {
+                depth.push(lineno);
+                Statement::If(rest)
+            }
+            "else" => {
+                if depth.is_empty() {
+                    bail!("line {}: \"else\" without \"if\"", lineno);
+                }
+                Statement::Else
+            }
+            "end" => {
+                if depth.pop().is_none() {
+                    bail!("line {}: \"end\" without \"if\"", lineno);
+                }
+                Statement::End
+            }
+            "echo" => Statement::Echo(rest),
+            "fail" => Statement::Fail(rest),
+            "try" => Statement::Command { text: rest, tolerate: true },
+            _ => Statement::Command { text: text.to_string(), tolerate: false },
+        };
+
+        match &statement {
+            Statement::Set(rest) | Statement::Capture(rest)
+                if rest.is_empty() =>
+            {
+                bail!(
+                    "line {}: \"{}\" requires a variable name",
+                    lineno,
+                    keyword
+                );
+            }
+            Statement::If(rest) | Statement::Command { text: rest, .. }
+                if rest.is_empty() =>
+            {
+                bail!("line {}: \"{}\" requires an argument", lineno, keyword);
+            }
+            _ => {}
+        }
+
+        lines.push(Line { lineno, statement });
+    }
+
+    if let Some(lineno) = depth.pop() {
+        bail!("line {}: \"if\" without \"end\"", lineno);
+    }
+
+    Ok(lines)
+}
+
+///
+/// Expands `${NAME}` references to variables.
+///
+fn expand(text: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let mut rval = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${") {
+        rval.push_str(&rest[..start]);
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated variable reference"))?;
+
+        let name = &rest[start + 2..start + end];
+
+        match vars.get(name) {
+            Some(val) => rval.push_str(val),
+            None => bail!("undefined variable \"{}\"", name),
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+
+    rval.push_str(rest);
+
+    Ok(rval)
+}
+
+struct State {
+    vars: HashMap<String, String>,
+    ok: bool,
+    output: String,
+}
+
+fn condition(cond: &str, state: &State) -> Result<bool> {
+    let words = shell_words::split(cond)?;
+
+    let (negate, words) = match words.split_first() {
+        Some((first, rest)) if first == "not" => (true, rest),
+        _ => (false, &words[..]),
+    };
+
+    let rval = match words {
+        [w] if w == "ok" => state.ok,
+        [w] if w == "failed" => !state.ok,
+        [w, text] if w == "contains" => state.output.contains(text.as_str()),
+        [lhs, op, rhs] if op == "==" => lhs == rhs,
+        [lhs, op, rhs] if op == "!=" => lhs != rhs,
+        _ => bail!("invalid condition \"{}\"", cond),
+    };
+
+    Ok(rval ^ negate)
+}
+
+///
+/// Executes a command, capturing its output.
+///
+fn command(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    commands: &HashMap<&'static str, Command>,
+    args: &Args,
+    words: &[String],
+) -> (Result<()>, String) {
+    let mut output = String::new();
+
+    //
+    // Our output is buffered; flush it on either side of the redirection to
+    // capture precisely the command's output.
+    //
+    let _ = std::io::stdout().flush();
+
+    let mut redirect = match gag::BufferRedirect::stdout() {
+        Ok(redirect) => redirect,
+        Err(err) => {
+            return (Err(err).context("failed to capture output"), output);
+        }
+    };
+
+    let rval = shell::execute(hubris, core, commands, args, words);
+    let _ = std::io::stdout().flush();
+
+    if let Err(err) = redirect.read_to_string(&mut output) {
+        return (Err(err).context("failed to read output"), output);
+    }
+
+    (rval, output)
+}
+
+fn execute(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    commands: &HashMap<&'static str, Command>,
+    args: &Args,
+    subargs: &ScriptArgs,
+    lines: &[Line],
+    state: &mut State,
+) -> Result<()> {
+    let mut transcript = match &subargs.output {
+        Some(output) => Some(
+            File::create(output)
+                .with_context(|| format!("failed to create {}", output))?,
+        ),
+        None => None,
+    };
+
+    //
+    // Our stack of conditionals:  for each, whether its condition was taken
+    // and whether we are executing its current branch.
+    //
+    let mut stack: Vec<(bool, bool)> = vec![];
+
+    for line in lines {
+        let active = stack.last().map_or(true, |&(_, active)| active);
+        let lineno = line.lineno;
+        let err = |e: anyhow::Error| e.context(format!("line {}", lineno));
+
+        match &line.statement {
+            Statement::If(cond) => {
+                let taken = active
+                    && condition(
+                        &expand(cond, &state.vars).map_err(err)?,
+                        state,
+                    )
+                    .map_err(err)?;
+                stack.push((taken, taken));
+                continue;
+            }
+            Statement::Else => {
+                let (taken, _) = stack.pop().unwrap();
+                let parent = stack.last().map_or(true, |&(_, active)| active);
+                stack.push((taken, parent && !taken));
+                continue;
+            }
+            Statement::End => {
+                stack.pop();
+                continue;
+            }
+            _ if !active => continue,
+            _ => {}
+        }
+
+        match &line.statement {
+            Statement::Set(rest) => {
+                let rest = expand(rest, &state.vars).map_err(err)?;
+
+                let (name, val) = match rest.split_once(char::is_whitespace) {
+                    Some((name, val)) => (name, val.trim()),
+                    None => (rest.as_str(), ""),
+                };
+
+                state.vars.insert(name.to_string(), val.to_string());
+            }
+            Statement::Capture(name) => {
+                state
+                    .vars
+                    .insert(name.clone(), state.output.trim().to_string());
+            }
+            Statement::Echo(text) => {
+                println!("{}", expand(text, &state.vars).map_err(err)?);
+            }
+            Statement::Fail(text) => {
+                bail!(
+                    "line {}: {}",
+                    lineno,
+                    expand(text, &state.vars).map_err(err)?
+                );
+            }
+            Statement::Command { text, tolerate } => {
+                let text = expand(text, &state.vars).map_err(err)?;
+                let words =
+                    shell_words::split(&text).map_err(|e| err(anyhow!(e)))?;
+
+                let (rval, output) =
+                    command(hubris, core, commands, args, &words);
+
+                if !subargs.quiet {
+                    print!("{}", output);
+                }
+
+                if let Some(transcript) = &mut transcript {
+                    writeln!(transcript, "% humility {}", text)?;
+                    write!(transcript, "{}", output)?;
+
+                    if let Err(e) = &rval {
+                        writeln!(
+                            transcript,
+                            "humility {} failed: {:?}",
+                            words[0], e
+                        )?;
+                    }
+                }
+
+                state.ok = rval.is_ok();
+                state.output = output;
+
+                match rval {
+                    Err(e) if !tolerate => {
+                        return Err(err(e.context(format!(
+                            "humility {} failed",
+                            words[0]
+                        ))));
+                    }
+                    Err(e) => {
+                        humility::msg!("humility {} failed: {:?}", words[0], e);
+                    }
+                    Ok(_) => {}
+                }
+            }
+            Statement::If(_) | Statement::Else | Statement::End => {
+                unreachable!()
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn run(
+    commands: &HashMap<&'static str, Command>,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = ScriptArgs::try_parse_from(subargs)?;
+
+    let contents = std::fs::read_to_string(&subargs.script)
+        .with_context(|| format!("failed to read {}", subargs.script))?;
+
+    //
+    // We parse the entire script before attaching, so that a malformed
+    // script fails before it has done anything to the target.
+    //
+    let lines = parse(&contents)
+        .with_context(|| format!("failed to parse {}", subargs.script))?;
+
+    let mut state =
+        State { vars: HashMap::new(), ok: true, output: String::new() };
+
+    for define in &subargs.define {
+        match define.split_once('=') {
+            Some((name, val)) => {
+                state.vars.insert(name.to_string(), val.to_string());
+            }
+            None => bail!("expected name=value, found \"{}\"", define),
+        }
+    }
+
+    let hubris =
+        cmd::load(args, Archive::Required, HubrisArchiveDoneness::Cook)?;
+
+    humility_cmd::attach(
+        &hubris,
+        args,
+        Attach::Any,
+        Validate::Match,
+        |hubris, core| {
+            execute(hubris, core, commands, args, &subargs, &lines, &mut state)
+        },
+    )
+}
@@ -0,0 +1,306 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! `humility shell` attaches to the target (or dump) once, and then
+//! accepts successive commands without re-attaching or re-loading the
+//! archive.  Lines are split as a shell would split them, and the first
+//! word names the command; the remaining words are that command's
+//! arguments, exactly as they would be given on the command line.  Command
+//! history is kept across invocations, and command names -- along with the
+//! names of tasks, sensors and I2C devices in the archive's manifest -- can
+//! be completed with tab.
+//!
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+
+use crate::cmd;
+
+/// interactively run commands against a single attachment
+#[derive(Parser, Debug)]
+#[clap(name = "shell")]
+pub struct ShellArgs {
+    /// file in which to keep command history
+    #[clap(long, short = 'H', value_name = "file", env = "HUMILITY_HISTORY")]
+    history: Option<String>,
+}
+
+struct ShellHelper {
+    commands: BTreeSet<String>,
+    names: BTreeSet<String>,
+}
+
+impl ShellHelper {
+    fn new(
+        hubris: &HubrisArchive,
+        commands: &HashMap<&'static str, Command>,
+    ) -> Self {
+        let mut names = BTreeSet::new();
+
+        for i in 0..hubris.ntasks() {
+            if let Some(name) = hubris.task_name(i) {
+                names.insert(name.to_string());
+            }
+        }
+
+        for sensor in &hubris.manifest.sensors {
+            names.insert(sensor.name.clone());
+        }
+
+        for device in &hubris.manifest.i2c_devices {
+            names.insert(device.device.clone());
+
+            if let Some(name) = &device.name {
+                names.insert(name.clone());
+            }
+        }
+
+        let mut commands: BTreeSet<String> =
+            commands.keys().map(|c| c.to_string()).collect();
+
+        for builtin in BUILTINS {
+            commands.insert(builtin.to_string());
+        }
+
+        Self { commands, names }
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        //
+        // Names can be specified as arguments to options (e.g. `-t name`) or
+        // as values (e.g. `--sensor=name` or a comma-separated list), so we
+        // complete the text after the last delimiter of any of these.
+        //
+        let start = line[..pos]
+            .rfind(|c: char| c.is_whitespace() || c == '=' || c == ',')
+            .map_or(0, |i| i + 1);
+
+        let word = &line[start..pos];
+
+        let candidates = if line[..start].trim().is_empty() {
+            &self.commands
+        } else {
+            &self.names
+        };
+
+        Ok((
+            start,
+            candidates
+                .iter()
+                .filter(|c| c.starts_with(word))
+                .cloned()
+                .collect(),
+        ))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+impl Validator for ShellHelper {}
+impl Helper for ShellHelper {}
+
+const BUILTINS: &[&str] = &["exit", "help", "quit"];
+
+fn history(subargs: &ShellArgs) -> Option<PathBuf> {
+    match &subargs.history {
+        Some(history) => Some(PathBuf::from(history)),
+        None => std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".humility_history")),
+    }
+}
+
+fn help(commands: &HashMap<&'static str, Command>) {
+    let mut names: Vec<_> = commands
+        .iter()
+        .filter_map(|(name, command)| match command {
+            Command::Attached { archive, .. }
+                if *archive != Archive::Ignored =>
+            {
+                Some(*name)
+            }
+            _ => None,
+        })
+        .collect();
+
+    names.sort_unstable();
+
+    println!("commands (\"<command> --help\" for usage):");
+
+    for chunk in names.chunks(6) {
+        let line: Vec<_> = chunk.iter().map(|n| format!("{:12}", n)).collect();
+        println!("    {}", line.join(" ").trim_end());
+    }
+
+    println!("\"exit\" or \"quit\" (or ^D) leaves the shell");
+}
+
+///
+/// Executes a single command (as split into words, with the command name
+/// first) against an existing attachment.
+///
+pub fn execute(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    commands: &HashMap<&'static str, Command>,
+    args: &Args,
+    words: &[String],
+) -> Result<()> {
+    let (attach, validate, run) =
+        match commands.get(words[0].as_str()) {
+            Some(Command::Attached {
+                archive, attach, validate, run, ..
+            }) if *archive != Archive::Ignored => (attach, validate, run),
+            Some(_) => {
+                bail!("{} cannot be run from the shell", words[0]);
+            }
+            None => {
+                bail!("command {} not found (\"help\" to list)", words[0]);
+            }
+        };
+
+    match (attach, args.dump.is_some()) {
+        (Attach::LiveOnly, true) => {
+            bail!("must be run against a live system");
+        }
+        (Attach::DumpOnly, false) => {
+            bail!("must be run against a dump");
+        }
+        _ => {}
+    }
+
+    //
+    // We validated that the archive matches when we attached; if the
+    // command additionally needs the system to be booted, we check that
+    // now, as it may not have been (or may have since been reset).
+    //
+    if let Validate::Booted = validate {
+        hubris.validate(core, HubrisValidate::Booted)?;
+    }
+
+    (run)(hubris, core, args, words)
+}
+
+fn repl(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    commands: &HashMap<&'static str, Command>,
+    args: &Args,
+    subargs: &ShellArgs,
+) -> Result<()> {
+    let mut editor = Editor::<ShellHelper>::new();
+    editor.set_helper(Some(ShellHelper::new(hubris, commands)));
+
+    let history = history(subargs);
+
+    if let Some(history) = &history {
+        //
+        // The history file won't exist the first time the shell is run.
+        //
+        let _ = editor.load_history(history);
+    }
+
+    let prompt = match &hubris.manifest.name {
+        Some(name) => format!("humility ({})> ", name),
+        None => "humility> ".to_string(),
+    };
+
+    loop {
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+
+        let words = match shell_words::split(&line) {
+            Ok(words) => words,
+            Err(err) => {
+                humility::msg!("{}", err);
+                continue;
+            }
+        };
+
+        if words.is_empty() {
+            continue;
+        }
+
+        editor.add_history_entry(line.as_str());
+
+        match words[0].as_str() {
+            "exit" | "quit" => break,
+            "help" => {
+                help(commands);
+                continue;
+            }
+            _ => {}
+        }
+
+        if let Err(err) = execute(hubris, core, commands, args, &words) {
+            //
+            // Argument errors (including requests for help) are already
+            // formatted for the user by clap.
+            //
+            match err.downcast_ref::<clap::Error>() {
+                Some(err) => {
+                    let _ = err.print();
+                }
+                None => eprintln!("humility {} failed: {:?}", words[0], err),
+            }
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(err) = editor.save_history(history) {
+            humility::msg!(
+                "failed to save history to {}: {}",
+                history.display(),
+                err
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn shell(
+    commands: &HashMap<&'static str, Command>,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = ShellArgs::try_parse_from(subargs)?;
+    let hubris =
+        cmd::load(args, Archive::Required, HubrisArchiveDoneness::Cook)?;
+
+    humility_cmd::attach(
+        &hubris,
+        args,
+        Attach::Any,
+        Validate::Match,
+        |hubris, core| repl(hubris, core, commands, args, &subargs),
+    )
+}
@@ -0,0 +1,247 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Named targets.  A `humility.toml` defines targets by name, each of which
+//! specifies how to attach to it (a probe, a probe's serial number, or the
+//! address of a machine running a debug server), the archive or dump to
+//! use, and any defaults for global options.  A target is selected with
+//! `--target` (or `HUMILITY_TARGET`), and its settings apply to any global
+//! options not given on the command line, overriding those set in the
+//! environment.
+//!
+//! A target can also define aliases for I2C buses (see
+//! [`humility_cmd::i2c::I2cAlias`]), which are then accepted by any command
+//! that takes a bus.
+//!
+//! The configuration file is located as described in
+//! [`humility_cmd::config`]; any views that it contains are interpreted by
+//! the commands that use them.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{ArgEnum, ArgMatches};
+use humility_cmd::config;
+use humility_cmd::error::ErrorKind;
+use humility_cmd::i2c::{self, I2cAlias};
+use humility_cmd::output::OutputFormat;
+use humility_cmd::Args;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    targets: BTreeMap<String, Target>,
+
+    /// Views, which are interpreted by commands
+    #[allow(dead_code)]
+    #[serde(default)]
+    views: BTreeMap<String, toml::value::Table>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Target {
+    /// Human-readable description, shown when listing targets
+    description: Option<String>,
+
+    /// Probe, as it would be specified with `--probe`
+    probe: Option<String>,
+
+    /// Serial number of a USB probe
+    serial: Option<String>,
+
+    /// Address of a machine running a debug server (OpenOCD or JLink)
+    address: Option<String>,
+
+    /// Hubris archive
+    archive: Option<String>,
+
+    /// Hubris dump
+    dump: Option<String>,
+
+    /// Output format for commands that emit tables
+    format: Option<String>,
+
+    /// Calibrations to apply to sensor values
+    calibration: Option<String>,
+
+    /// Aliases for I2C buses, by name
+    #[serde(default)]
+    i2c: BTreeMap<String, I2cAlias>,
+}
+
+impl Target {
+    ///
+    /// Determines the probe specification (as would be given to `--probe`)
+    /// for this target, if any.
+    ///
+    fn probe(&self) -> Result<Option<String>> {
+        match (&self.probe, &self.serial, &self.address) {
+            (_, Some(_), Some(_)) => {
+                bail!("cannot specify both a serial number and an address")
+            }
+            (Some(_), Some(_), _) => {
+                bail!("cannot specify both a probe and a serial number")
+            }
+            (None, Some(serial), None) => Ok(Some(format!("usb:{}", serial))),
+            (probe, None, Some(address)) => Ok(Some(format!(
+                "{}@{}",
+                probe.as_deref().unwrap_or("auto"),
+                address
+            ))),
+            (probe, None, None) => Ok(probe.clone()),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.archive.is_some() && self.dump.is_some() {
+            bail!("cannot specify both a dump and an archive");
+        }
+
+        if self.dump.is_some() && self.probe()?.is_some() {
+            bail!("cannot specify both a dump and a means of attaching");
+        }
+
+        if let Some(format) = &self.format {
+            OutputFormat::from_str(format, false)
+                .map_err(|_| anyhow!("invalid format \"{}\"", format))?;
+        }
+
+        for (name, alias) in &self.i2c {
+            alias
+                .validate()
+                .with_context(|| format!("invalid I2C bus alias {}", name))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn load(path: &Path) -> Result<Config> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    toml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", path.display()))
+}
+
+///
+/// Returns the names of the targets defined in the specified configuration
+/// file, having validated each of them.
+///
+pub fn names(path: &Path) -> Result<Vec<String>> {
+    let config = load(path)?;
+
+    for (name, target) in &config.targets {
+        target.validate().with_context(|| {
+            format!("invalid target \"{}\" in {}", name, path.display())
+        })?;
+    }
+
+    Ok(config.targets.into_keys().collect())
+}
+
+///
+/// Applies the named target to our arguments.  Settings from the target
+/// apply only to options that were not given on the command line, and
+/// override any that were set via the environment.
+///
+pub fn apply(name: &str, args: &mut Args, m: &ArgMatches) -> Result<()> {
+    let path = config::path().ok_or_else(|| {
+        ErrorKind::Usage.error(format!(
+            "target \"{}\" specified, but no humility.toml found",
+            name
+        ))
+    })?;
+
+    let config = load(&path)?;
+
+    let target = match config.targets.get(name) {
+        Some(target) => target,
+        None => {
+            let known: Vec<_> = config
+                .targets
+                .iter()
+                .map(|(name, target)| match &target.description {
+                    Some(description) => format!("{} ({})", name, description),
+                    None => name.clone(),
+                })
+                .collect();
+
+            return Err(ErrorKind::Usage.error(format!(
+                "target \"{}\" not found in {}; known targets: {}",
+                name,
+                path.display(),
+                if known.is_empty() {
+                    "none".to_string()
+                } else {
+                    known.join(", ")
+                }
+            )));
+        }
+    };
+
+    target.validate().with_context(|| {
+        format!("invalid target \"{}\" in {}", name, path.display())
+    })?;
+
+    let explicit = |option| m.occurrences_of(option) != 0;
+
+    if let Some(probe) = target.probe()? {
+        if !explicit("probe") {
+            args.probe = Some(probe);
+
+            if !explicit("dump") {
+                args.dump = None;
+            }
+        }
+    }
+
+    if let Some(archive) = &target.archive {
+        if !explicit("archive") {
+            args.archive = Some(archive.clone());
+
+            if !explicit("dump") {
+                args.dump = None;
+            }
+        }
+    }
+
+    if let Some(dump) = &target.dump {
+        if !explicit("dump") {
+            args.dump = Some(dump.clone());
+
+            if !explicit("archive") {
+                args.archive = None;
+            }
+
+            if !explicit("probe") {
+                args.probe = None;
+            }
+        }
+    }
+
+    if let Some(format) = &target.format {
+        if !explicit("format") {
+            //
+            // We validated the format above.
+            //
+            args.format = OutputFormat::from_str(format, false).unwrap();
+        }
+    }
+
+    if let Some(calibration) = &target.calibration {
+        if !explicit("calibration") {
+            args.calibration = Some(calibration.clone());
+        }
+    }
+
+    i2c::set_aliases(target.i2c.clone());
+
+    Ok(())
+}
@@ -43,6 +43,11 @@ fn make_tests() -> Result<()> {
         Test::basic("stackmargin"),
         Test::basic("tasks"),
         Test::witharg("tasks-slvr", "tasks", "-slvr"),
+        Test::basic("fault"),
+        Test::basic("counters"),
+        Test::basic("timers"),
+        Test::basic("ipc"),
+        Test::basic("elfcore"),
     ];
 
     let mut cores = vec![];
@@ -56,6 +61,20 @@ fn make_tests() -> Result<()> {
 
         if let Some(f) = path.file_name() {
             if let Some(s) = f.to_str() {
+                //
+                // humility elfcore writes its output alongside the dump (and
+                // won't overwrite it), so we remove any left by a prior run.
+                //
+                if s.contains(".elfcore") {
+                    if path.is_dir() {
+                        fs::remove_dir_all(&path)?;
+                    } else {
+                        fs::remove_file(&path)?;
+                    }
+
+                    continue;
+                }
+
                 let prefix = "hubris.core.";
 
                 if let Some(name) = s.strip_prefix(&prefix) {